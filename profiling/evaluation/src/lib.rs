@@ -23,6 +23,7 @@ pub use acc::get_frame_stats;
 
 mod helper;
 pub use helper::all_configurations;
+pub use helper::skip_to_fps;
 
 mod profile;
 pub use profile::Configuration;
@@ -0,0 +1,88 @@
+//! Streams frames to a server from an application that runs its own reactor
+//! and listeners, through an `Endpoint`.
+//!
+//! The application answers each connection on its status port with the
+//! session token and the bytes sent so far, and sends a 10 KB frame every
+//! 33 ms, skipping frames while the server reports congestion.
+//!
+//! ```text
+//! cargo run --example endpoint -- 127.0.0.1:8889 127.0.0.1:9000
+//! ```
+
+extern crate awstream;
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use awstream::AsDatum;
+use awstream::endpoint::{Endpoint, Feedback};
+use futures::{Future, Sink, Stream};
+use std::cell::Cell;
+use std::env;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Core, Interval};
+
+/// Frames skipped after a congestion report.
+const BACKOFF_FRAMES: usize = 10;
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let server: SocketAddr = args.next().unwrap_or_else(|| "127.0.0.1:8889".into()).parse().expect("bad server address");
+    let status: SocketAddr = args.next().unwrap_or_else(|| "127.0.0.1:9000".into()).parse().expect("bad status address");
+
+    // the application's reactor, which it uses for its own work as well
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+
+    let tcp = core.run(TcpStream::connect(&server, &handle)).expect("failed to connect");
+    let endpoint = match core.run(Endpoint::builder().connect(tcp, &handle)) {
+        Ok(endpoint) => endpoint,
+        Err(e) => panic!("failed to open a session: {}", e),
+    };
+    let token = endpoint.session_token();
+    let bytes = endpoint.bytes_sent();
+    println!("session {:x}", token);
+
+    // the application's own listener, on the same reactor
+    let listener = TcpListener::bind(&status, &handle).expect("failed to bind the status port");
+    let status = listener.incoming().for_each(move |(socket, _)| {
+        let line = format!("session {:x}, {} bytes sent\n", token, bytes.load(Ordering::Relaxed));
+        handle.spawn(tokio_io::io::write_all(socket, line).map(|_| ()).map_err(|_| ()));
+        Ok(())
+    });
+    core.handle().spawn(status.map_err(|e| eprintln!("status port failed: {}", e)));
+
+    // back off for a while on each congestion report
+    let (sink, feedback) = endpoint.split();
+    let backoff = Rc::new(Cell::new(0));
+    let congested = backoff.clone();
+    let feedback = feedback.for_each(move |f| {
+        if let Feedback::Congestion(report) = f {
+            println!("congested: {:.0} ms", report.latency());
+            congested.set(BACKOFF_FRAMES);
+        }
+        Ok(())
+    });
+    core.handle().spawn(feedback.map_err(|e| eprintln!("feedback failed: {}", e)));
+
+    let ticks = Interval::new(Duration::from_millis(33), &core.handle()).unwrap();
+    let frames = ticks
+        .take(300)
+        .zip(futures::stream::iter_ok::<_, io::Error>(0..))
+        .filter_map(move |(_, i)| match backoff.get() {
+            0 => Some(AsDatum::new(0, i, vec![0; 10 * 1024])),
+            n => {
+                backoff.set(n - 1);
+                None
+            }
+        });
+    match core.run(sink.send_all(frames)) {
+        Ok(_) => println!("done"),
+        Err(e) => eprintln!("stream failed: {}", e),
+    }
+}
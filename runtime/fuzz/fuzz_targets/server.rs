@@ -0,0 +1,68 @@
+//! Plays arbitrary client behavior against the server (see
+//! `awstream::harness`): it must neither panic, nor leak connections, nor
+//! grow its memory without bound.
+//!
+//! Run with `cargo fuzz run server` from `runtime/`.
+
+#![no_main]
+
+use awstream::harness::{self, Harness};
+use libfuzzer_sys::fuzz_target;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+/// Most the process may hold beyond what it held after the first run.
+const MEMORY_BOUND: usize = 64 * 1024 * 1024;
+
+/// Counts live heap bytes, including those of the server thread.
+struct Counting;
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let live = LIVE.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(live, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// The server, with the heap it held once warmed up.
+static HARNESS: OnceLock<Mutex<(Harness, usize)>> = OnceLock::new();
+
+fn harness() -> &'static Mutex<(Harness, usize)> {
+    HARNESS.get_or_init(|| {
+        let harness = Harness::start().expect("failed to start the server");
+        harness.run(&[]).expect("server unusable");
+        let baseline = LIVE.load(Ordering::Relaxed);
+        Mutex::new((harness, baseline))
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let harness = harness().lock().unwrap();
+    let (ref server, baseline) = *harness;
+    PEAK.store(LIVE.load(Ordering::Relaxed), Ordering::Relaxed);
+
+    if let Err(e) = server.run(&harness::parse(data)) {
+        panic!("server did not recover: {}", e);
+    }
+
+    let peak = PEAK.load(Ordering::Relaxed);
+    assert!(peak < baseline + MEMORY_BOUND, "peak heap {} bytes", peak);
+    let live = LIVE.load(Ordering::Relaxed);
+    assert!(live < baseline + MEMORY_BOUND, "heap grew to {} bytes", live);
+});
@@ -1,33 +1,271 @@
 //! Adapatation algorithm implementation (described as in Figure 6).
 
+use super::Bandwidth;
+use super::profile::SimpleProfile;
+
+/// Probe a bit more than the next level strictly needs.
+const PROBE_EXTRA: f64 = 1.05;
+
+/// Actions for adaptation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AdaptAction {
+    /// Adapts to a designated bandwidth.
+    ToRate(Bandwidth),
+
+    /// Decreases the adaptation level.
+    DecreaseDegradation,
+
+    /// Moves to a designated level (decided by the client's controller).
+    ToLevel(usize),
+
+    /// Starts probing with target bandwidth.
+    StartProbe(Bandwidth),
+
+    /// Increases probe pace.
+    IncreaseProbePace,
+
+    /// Stops the probing.
+    StopProbe,
+
+    /// Biases the accuracy of a level towards the quality reported by the
+    /// receiver's analytics, with (level, quality, weight).
+    ObserveAccuracy(usize, f64, f64),
+}
+
 /// Signal
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Signal {
     /// QueueCongest signal carries the outgoing rate and the estimated latency.
-    QueueCongest(f64, f64),
+    QueueCongest(Bandwidth, f64),
 
     /// Queue is empty, try to be aggressive.
     QueueEmpty,
 
     /// Congestion signal from the remote.
-    RemoteCongest(f64, f64),
+    RemoteCongest(Bandwidth, f64),
 
     /// Probe done
     ProbeDone,
+
+    /// The source cannot produce levels above this one in real time. Handled
+    /// by `decide` as a ceiling on the profile; policies never see it.
+    EncoderLimit(usize),
+
+    /// The device is overloaded or overheating (`true`), or has been healthy
+    /// for a while (`false`). Handled by `decide` as a ceiling below the
+    /// current level; policies never see it.
+    SystemLoad(bool),
+
+    /// The receiver's latency (the quantile held to the budget, in ms) has
+    /// exceeded the latency budget for a sustained window. Handled by
+    /// `decide` as a downgrade by one level, even if throughput looks fine;
+    /// policies never see it.
+    BudgetViolation(f64),
+
+    /// The source failed to switch to a level (`false`), or the level may be
+    /// retried (`true`). Handled by `decide` by skipping the level; policies
+    /// never see it.
+    LevelAvailable(usize, bool),
+
+    /// The memory budget of the client is under pressure, with this many
+    /// bytes in use (see `memory`). Handled by `decide` as a downgrade by one
+    /// level, so that less piles up; policies never see it.
+    MemoryPressure(usize),
+
+    /// The frames predicted to come next would queue for this long (ms)
+    /// beyond the prediction horizon (see `prediction`). Handled by `decide`
+    /// as a downgrade by one level, ahead of the burst; policies never see
+    /// it.
+    PredictedBurst(f64),
+
+    /// A live frame was dropped since it would have been sent this long (ms)
+    /// after capture, past the deadline (see `deadline`). Handled by `decide`
+    /// as a downgrade by one level; policies never see it.
+    DeadlineMiss(f64),
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Action decided by a policy in reaction to a `Signal`.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Action {
+    /// Keep everything as is.
     NoOp,
+
+    /// Move to the next (higher) level.
     AdvanceConfig,
 
     /// When the action is `AdjustConfig`, we inform the estimated outgoing rate
-    AdjustConfig(f64),
+    AdjustConfig(Bandwidth),
 
-    /// Start the probe with a target bandwidth (in kbps)
+    /// Start the probe towards the next level.
     StartProbe,
+
+    /// Probe more aggressively.
     IncreaseProbePace,
+
+    /// Stop the ongoing probe.
     StopProbe,
+
+    /// Move to a designated level, e.g., as chosen by an external agent.
+    ToLevel(usize),
+}
+
+/// A rate adaptation policy maps signals to actions. `Adaptation` is the
+/// default; alternatives can be compared offline with `replay_decisions`.
+pub trait Policy {
+    /// Reacts to `signal`. `max_config` tells if the profile is already at
+    /// its highest level.
+    fn transit(&mut self, signal: Signal, max_config: bool) -> Action;
+
+    /// Reacts to `signal` knowing the whole `profile` (e.g., the current
+    /// level). Defaults to `transit`.
+    fn transit_in(&mut self, signal: Signal, profile: &SimpleProfile) -> Action {
+        self.transit(signal, profile.is_max())
+    }
+}
+
+/// The outcome of feeding one signal to a policy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decision {
+    /// The input signal.
+    pub signal: Signal,
+
+    /// The action chosen by the policy.
+    pub action: Action,
+
+    /// The level after applying the action.
+    pub level: usize,
+
+    /// The command sent to the source, if any.
+    pub command: Option<AdaptAction>,
+}
+
+/// Runs `policy` on `signal`, applies the resulting action to `profile` and
+/// returns the command for the source. Both the live client and the offline
+/// replay go through this function so that they decide identically.
+pub fn decide<P: Policy + ?Sized>(
+    policy: &mut P,
+    profile: &mut SimpleProfile,
+    signal: Signal,
+) -> Decision {
+    if let Signal::EncoderLimit(max) = signal {
+        let level = profile.set_ceiling(Some(max));
+        info!("encoder limits levels to {}, now at {:?}", max, level);
+        return Decision {
+            signal,
+            action: Action::NoOp,
+            level: profile.current(),
+            command: level.map(AdaptAction::ToLevel),
+        };
+    }
+    if let Signal::SystemLoad(overloaded) = signal {
+        let level = if overloaded {
+            profile.throttle()
+        } else {
+            profile.relax();
+            None
+        };
+        info!("system overloaded: {}, now at {:?}", overloaded, level);
+        return Decision {
+            signal,
+            action: Action::NoOp,
+            level: profile.current(),
+            command: level.map(AdaptAction::ToLevel),
+        };
+    }
+    if let Signal::LevelAvailable(level, available) = signal {
+        let moved = if available {
+            profile.mark_available(level);
+            None
+        } else {
+            profile.mark_unavailable(level)
+        };
+        info!("level {} available: {}, now at {:?}", level, available, moved);
+        return Decision {
+            signal,
+            action: Action::NoOp,
+            level: profile.current(),
+            command: moved.map(AdaptAction::ToLevel),
+        };
+    }
+    if let Signal::BudgetViolation(latency) = signal {
+        let level = profile.decrease_level();
+        warn!("latency {:.1} ms over budget, now at {:?}", latency, level);
+        return Decision {
+            signal,
+            action: Action::NoOp,
+            level: profile.current(),
+            command: level.map(AdaptAction::ToLevel),
+        };
+    }
+    if let Signal::MemoryPressure(used) = signal {
+        let level = profile.decrease_level();
+        warn!("memory pressure at {} bytes, now at {:?}", used, level);
+        return Decision {
+            signal,
+            action: Action::NoOp,
+            level: profile.current(),
+            command: level.map(AdaptAction::ToLevel),
+        };
+    }
+    if let Signal::PredictedBurst(latency) = signal {
+        let level = profile.decrease_level();
+        info!("burst predicted to queue {:.1} ms, now at {:?}", latency, level);
+        return Decision {
+            signal,
+            action: Action::NoOp,
+            level: profile.current(),
+            command: level.map(AdaptAction::ToLevel),
+        };
+    }
+    if let Signal::DeadlineMiss(latency) = signal {
+        let level = profile.decrease_level();
+        warn!("frame would take {:.1} ms, past the deadline, now at {:?}", latency, level);
+        return Decision {
+            signal,
+            action: Action::NoOp,
+            level: profile.current(),
+            command: level.map(AdaptAction::ToLevel),
+        };
+    }
+    let action = policy.transit_in(signal, profile);
+    let command = match action {
+        Action::NoOp => None,
+        Action::AdjustConfig(rate) => {
+            let level = profile.adjust_level(rate);
+            info!("adjust config, level: {:?}, rate: {}", level, rate);
+            level.map(AdaptAction::ToLevel)
+        }
+        Action::AdvanceConfig => {
+            let level = profile.advance_level();
+            info!("advance config to {:?}", level);
+            level.map(AdaptAction::ToLevel)
+        }
+        Action::StartProbe => {
+            let delta = profile.next_rate_delta().expect("Must not at max config");
+            let target = delta * PROBE_EXTRA; // probe more space than needed
+            info!("start probing for {:?}", target);
+            Some(AdaptAction::StartProbe(target))
+        }
+        Action::IncreaseProbePace => {
+            info!("increase probe pace");
+            Some(AdaptAction::IncreaseProbePace)
+        }
+        Action::StopProbe => {
+            info!("stop probe pace");
+            Some(AdaptAction::StopProbe)
+        }
+        Action::ToLevel(level) => {
+            let level = profile.set_level(level);
+            info!("policy moves to level {:?}", level);
+            level.map(AdaptAction::ToLevel)
+        }
+    };
+    Decision {
+        signal,
+        action,
+        level: profile.current(),
+        command,
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -39,6 +277,7 @@ enum State {
     Probe,
 }
 
+/// The default policy: the state machine in Figure 6 of the paper.
 pub struct Adaptation {
     state: State,
     steady_count: usize,
@@ -62,6 +301,7 @@ impl Adaptation {
     /// Only start probing if we are steady enough (that is, enough Q_E).
     const STEADY_ENOUGH: usize = 3;
 
+    /// Transits the state machine with a new `signal`.
     pub fn transit(&mut self, signal: Signal, max_config: bool) -> Action {
         info!(
             "state: {:?}, signal: {:?}, max?: {}",
@@ -151,3 +391,9 @@ impl Adaptation {
         action
     }
 }
+
+impl Policy for Adaptation {
+    fn transit(&mut self, signal: Signal, max_config: bool) -> Action {
+        Adaptation::transit(self, signal, max_config)
+    }
+}
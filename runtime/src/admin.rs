@@ -0,0 +1,324 @@
+//! A small HTTP endpoint for operators, enabled with `admin_port`.
+//!
+//! It listens on localhost only (reach it through an SSH tunnel) and speaks
+//! just enough HTTP/1.1 for `curl`:
+//!
+//! - `GET /sessions` lists the sessions as CSV: level, goodput, throughput
+//!   and latency percentiles;
+//! - `GET /sessions/<token>/tradeoff` reports the time, latency and loss at
+//!   each level of a session as CSV, next to the rates of its profile;
+//! - `POST /sessions/<token>/cap?kbps=<n>` caps the bandwidth of a client
+//!   (without `kbps`, lifts the cap);
+//! - `POST /sessions/<token>/level?level=<n>&duration_ms=<n>` forces a level;
+//! - `POST /sessions/<token>/auto` hands the level back to adaptation;
+//! - `POST /sessions/<token>/close?reason=<r>` closes the connection of a
+//!   session, for `operator` (the default) or `migrating`.
+//!
+//! Directives reach the client through the session's feedback channel.
+
+use super::{AsDatum, Bandwidth, CloseReason, Directive};
+use super::session::SessionStore;
+use csv;
+use errors::*;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// How long a level is forced without `duration_ms`.
+const DEFAULT_FORCE_MS: u64 = 60 * 1000;
+
+/// How long a connection may stall reading its request or taking the
+/// response before it is dropped; requests are served one at a time.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The most read of a request (its line and headers).
+const MAX_REQUEST_BYTES: u64 = 16 * 1024;
+
+/// A session as listed by `GET /sessions`.
+#[derive(Serialize, Debug)]
+struct SessionRow {
+    token: String,
+    attached: bool,
+    level: Option<usize>,
+    goodput_kbps: f64,
+    throughput_kbps: f64,
+    latency_p50_ms: Option<f64>,
+    latency_p95_ms: Option<f64>,
+    latency_p99_ms: Option<f64>,
+    duplicates: usize,
+    digest_mismatches: usize,
+}
+
+/// A response to a request.
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    /// The status code.
+    pub status: u16,
+
+    /// `text/csv` for listings, `text/plain` otherwise.
+    pub content_type: &'static str,
+
+    /// The body.
+    pub body: String,
+}
+
+impl Response {
+    fn text(status: u16, body: &str) -> Response {
+        Response {
+            status,
+            content_type: "text/plain",
+            body: format!("{}\n", body),
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+/// Serves requests on `listener` from a background thread, one request per
+/// connection.
+pub fn serve<A: Clone + Send + 'static>(listener: TcpListener, sessions: SessionStore<A>) -> Result<SocketAddr> {
+    let addr = listener.local_addr()?;
+    info!("admin endpoint on http://{}", addr);
+    thread::Builder::new()
+        .name("awstream-admin".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let handled = stream.map_err(Error::from).and_then(|s| handle(s, &sessions));
+                if let Err(e) = handled {
+                    warn!("admin request failed: {}", e);
+                }
+            }
+        })?;
+    Ok(addr)
+}
+
+fn handle<A: Clone>(stream: TcpStream, sessions: &SessionStore<A>) -> Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_REQUEST_BYTES));
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // the headers (and any body) are not needed
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+    let mut parts = request.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => route(sessions, method, target),
+        _ => Response::text(400, "malformed request"),
+    };
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.reason(),
+        response.content_type,
+        response.body.len(),
+        response.body
+    )?;
+    Ok(())
+}
+
+/// Answers `method` on `target` (path and query).
+pub fn route<A: Clone>(sessions: &SessionStore<A>, method: &str, target: &str) -> Response {
+    let (path, query) = match target.find('?') {
+        Some(i) => (&target[..i], &target[i + 1..]),
+        None => (target, ""),
+    };
+    let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+    match (method, segments.as_slice()) {
+        ("GET", ["sessions"]) => match list(sessions) {
+            Ok(body) => Response {
+                status: 200,
+                content_type: "text/csv",
+                body,
+            },
+            Err(e) => Response::text(500, &e.to_string()),
+        },
+        ("GET", ["sessions", token, "tradeoff"]) => {
+            let token = match u64::from_str_radix(token, 16) {
+                Ok(token) => token,
+                Err(_) => return Response::text(400, "malformed session token"),
+            };
+            match tradeoff(sessions, token) {
+                Ok(Some(body)) => Response {
+                    status: 200,
+                    content_type: "text/csv",
+                    body,
+                },
+                Ok(None) => Response::text(404, "unknown session"),
+                Err(e) => Response::text(500, &e.to_string()),
+            }
+        }
+        ("POST", ["sessions", token, action]) => {
+            let token = match u64::from_str_radix(token, 16) {
+                Ok(token) => token,
+                Err(_) => return Response::text(400, "malformed session token"),
+            };
+            if *action == "close" {
+                return close(sessions, token, query);
+            }
+            let directive = match directive(action, query) {
+                Ok(directive) => directive,
+                Err(response) => return response,
+            };
+            let sent = AsDatum::directive(&directive).and_then(|d| sessions.send_feedback(token, d));
+            match sent {
+                Ok(true) => Response::text(200, &format!("sent {:?}", directive)),
+                Ok(false) => Response::text(404, "unknown session"),
+                Err(e) => Response::text(500, &e.to_string()),
+            }
+        }
+        (_, ["sessions"]) | (_, ["sessions", _, _]) => Response::text(405, "method not allowed"),
+        _ => Response::text(404, "not found"),
+    }
+}
+
+fn list<A: Clone>(sessions: &SessionStore<A>) -> Result<String> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    let mut all = sessions.list()?;
+    all.sort_by_key(|(s, _)| s.token);
+    for (session, attached) in all {
+        let stats = &session.stats;
+        writer.serialize(SessionRow {
+            token: format!("{:x}", session.token),
+            attached,
+            level: stats.level(),
+            goodput_kbps: session.goodput.rate()?.kbps(),
+            throughput_kbps: session.throughput.rate()?.kbps(),
+            latency_p50_ms: stats.latency_percentile(50.0),
+            latency_p95_ms: stats.latency_percentile(95.0),
+            latency_p99_ms: stats.latency_percentile(99.0),
+            duplicates: stats.duplicates(),
+            digest_mismatches: stats.digest_mismatches(),
+        })?;
+    }
+    let bytes = writer.into_inner().map_err(|e| e.into_error())?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn tradeoff<A: Clone>(sessions: &SessionStore<A>, token: u64) -> Result<Option<String>> {
+    match sessions.list()?.into_iter().find(|(s, _)| s.token == token) {
+        Some((session, _)) => Ok(Some(session.stats.tradeoff().to_csv()?)),
+        None => Ok(None),
+    }
+}
+
+/// The value of parameter `name` in `query`.
+fn param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|kv| {
+            let mut kv = kv.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if k == name => Some(v),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+/// Closes the connection of session `token` for the reason in `query`.
+fn close<A>(sessions: &SessionStore<A>, token: u64, query: &str) -> Response {
+    let reason = match param(query, "reason").map(str::parse) {
+        None => CloseReason::Operator,
+        Some(Ok(reason @ CloseReason::Operator)) |
+        Some(Ok(reason @ CloseReason::Migrating)) => reason,
+        Some(_) => return Response::text(400, "malformed reason"),
+    };
+    match sessions.close(token, reason) {
+        Ok(true) => Response::text(200, &format!("closing for {}", reason)),
+        Ok(false) => Response::text(404, "no connection for the session"),
+        Err(e) => Response::text(500, &e.to_string()),
+    }
+}
+
+/// Parses the directive of `action` with the parameters of `query`.
+fn directive(action: &str, query: &str) -> ::std::result::Result<Directive, Response> {
+    let param = |name: &str| param(query, name);
+    let bad = |name: &str| Response::text(400, &format!("malformed {}", name));
+    match action {
+        "cap" => match param("kbps") {
+            Some(kbps) => match kbps.parse::<f64>() {
+                Ok(kbps) if kbps >= 0.0 => {
+                    Ok(Directive::BandwidthCap(Some(Bandwidth::from_kbps(kbps))))
+                }
+                _ => Err(bad("kbps")),
+            },
+            None => Ok(Directive::BandwidthCap(None)),
+        },
+        "level" => {
+            let level = param("level").and_then(|l| l.parse().ok()).ok_or_else(|| bad("level"))?;
+            let duration_ms = match param("duration_ms") {
+                Some(ms) => ms.parse().map_err(|_| bad("duration_ms"))?,
+                None => DEFAULT_FORCE_MS,
+            };
+            Ok(Directive::ForceLevel(level, duration_ms))
+        }
+        "auto" => Ok(Directive::ResumeAuto),
+        _ => Err(Response::text(404, "not found")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route() {
+        let sessions = SessionStore::new();
+        let (session, _) = sessions.open(None, ()).unwrap();
+        session.stats.add_frame(2, 100, 80.0);
+        let token = format!("{:x}", session.token);
+
+        let listing = route(&sessions, "GET", "/sessions");
+        assert_eq!(listing.status, 200);
+        assert!(listing.body.starts_with("token,attached,level,goodput_kbps"));
+        assert!(listing.body.contains(&format!("{},true,2,", token)), "{}", listing.body);
+
+        let tradeoff = route(&sessions, "GET", &format!("/sessions/{}/tradeoff", token));
+        assert_eq!(tradeoff.status, 200);
+        assert!(tradeoff.body.starts_with("level,time_ms,frames,lost"), "{}", tradeoff.body);
+        assert!(tradeoff.body.contains("\n2,0.0,1,0,"), "{}", tradeoff.body);
+        assert_eq!(route(&sessions, "GET", "/sessions/0/tradeoff").status, 404);
+
+        let cap = route(&sessions, "POST", &format!("/sessions/{}/cap?kbps=500", token));
+        assert_eq!(cap.status, 200);
+        let level = route(&sessions, "POST", &format!("/sessions/{}/level?level=1", token));
+        assert_eq!(level.status, 200);
+        let outbox = session.outbox.lock().unwrap().clone();
+        let sent = outbox.iter().map(|d| Directive::from_mem(&d.mem).unwrap()).collect::<Vec<_>>();
+        assert_eq!(
+            sent,
+            vec![
+                Directive::BandwidthCap(Some(Bandwidth::from_kbps(500.0))),
+                Directive::ForceLevel(1, DEFAULT_FORCE_MS),
+            ]
+        );
+
+        assert_eq!(route(&sessions, "POST", "/sessions/0/auto").status, 404);
+
+        let (closer, closed) = ::futures::sync::mpsc::unbounded();
+        *session.closer.lock().unwrap() = Some(::std::sync::Arc::new(closer));
+        let close = |reason| format!("/sessions/{}/close?reason={}", token, reason);
+        assert_eq!(route(&sessions, "POST", &close("migrating")).status, 200);
+        assert_eq!(route(&sessions, "POST", &close("idle")).status, 400);
+        *session.closer.lock().unwrap() = None;
+        let closed = ::futures::Stream::wait(closed).map(|r| r.unwrap()).collect::<Vec<_>>();
+        assert_eq!(closed, vec![CloseReason::Migrating]);
+        assert_eq!(route(&sessions, "POST", &format!("/sessions/{}/cap?kbps=x", token)).status, 400);
+        assert_eq!(route(&sessions, "DELETE", "/sessions").status, 405);
+        assert_eq!(route(&sessions, "GET", "/metrics").status, 404);
+    }
+}
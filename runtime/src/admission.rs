@@ -0,0 +1,207 @@
+//! Admission of sessions within the server's ingest capacity.
+//!
+//! A server configured with an aggregate `ingest_budget_kbps` admits a
+//! session only if the level the client starts at fits within what the
+//! sessions already admitted leave. The client sends its request (its
+//! starting level and the rates of its profile) along with `Hello`; the
+//! server replies, ahead of `Welcome`, with an `Admission` datum:
+//!
+//! ```text
+//! Admitted        admitted at most at the requested level
+//! Capped(level)   admitted at most at `level`, the highest that fits
+//! Rejected        not even the lowest level fits; the server hangs up
+//! ```
+//!
+//! An admitted client stays within the rate of the level it was granted,
+//! whatever other caps are set or lifted (see `Client`), so that it can't
+//! climb above its grant as bandwidth allows. Each session holds its
+//! grant until it disconnects. Sessions of clients that send no request are
+//! admitted without counting against the budget.
+
+use super::Bandwidth;
+use errors::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// What a client asks to be admitted at.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AdmissionRequest {
+    /// The level the client starts at.
+    pub level: usize,
+
+    /// The rate of each level of the client's profile.
+    pub rates: Vec<Bandwidth>,
+}
+
+impl AdmissionRequest {
+    /// The rate of `level`, if the profile has it.
+    pub fn rate(&self, level: usize) -> Option<Bandwidth> {
+        self.rates.get(level).cloned()
+    }
+}
+
+/// The server's answer to an `AdmissionRequest`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    /// Admitted at the requested level.
+    Admitted,
+
+    /// Admitted at most at this level, the highest that fits.
+    Capped(usize),
+
+    /// No level fits.
+    Rejected,
+}
+
+/// The sessions admitted within a budget. Clones share the sessions.
+#[derive(Debug, Clone)]
+pub struct AdmissionControl {
+    budget: Bandwidth,
+    granted: Arc<Mutex<HashMap<u64, Bandwidth>>>,
+}
+
+impl AdmissionControl {
+    /// Admits sessions within `budget` in aggregate.
+    pub fn new(budget: Bandwidth) -> AdmissionControl {
+        AdmissionControl {
+            budget,
+            granted: Arc::default(),
+        }
+    }
+
+    /// What the admitted sessions leave of the budget.
+    pub fn remaining(&self) -> Result<Bandwidth> {
+        let granted = self.granted.lock()?;
+        Ok(self.budget - granted.values().fold(Bandwidth::from_bps(0.0), |sum, &r| sum + r))
+    }
+
+    /// Decides on the request of `session` and, unless rejected, holds the
+    /// rate granted until `release`. A resumed session's previous grant is
+    /// given back first.
+    pub fn admit(&self, session: u64, request: &AdmissionRequest) -> Result<Admission> {
+        let mut granted = self.granted.lock()?;
+        granted.remove(&session);
+        let used = granted.values().fold(Bandwidth::from_bps(0.0), |sum, &r| sum + r);
+        let remaining = self.budget - used;
+        let level = request.level.min(request.rates.len().saturating_sub(1));
+        let fits = (0..=level).rev().find(|&l| request.rate(l).is_some_and(|r| r <= remaining));
+        let admission = match fits {
+            Some(l) if l == request.level => Admission::Admitted,
+            Some(l) => Admission::Capped(l),
+            None => Admission::Rejected,
+        };
+        if let Some(l) = fits {
+            granted.insert(session, request.rates[l]);
+        }
+        info!("session {:x}: {:?} with {} of {} left", session, admission, remaining, self.budget);
+        Ok(admission)
+    }
+
+    /// Gives back the grant of `session`.
+    pub fn release(&self, session: u64) -> Result<()> {
+        self.granted.lock()?.remove(&session);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admit_within_budget() {
+        let control = AdmissionControl::new(Bandwidth::from_kbps(3000.0));
+        let request = |level| AdmissionRequest {
+            level,
+            rates: [300.0, 800.0, 1500.0].iter().map(|&r| Bandwidth::from_kbps(r)).collect(),
+        };
+        assert_eq!(control.admit(1, &request(2)).unwrap(), Admission::Admitted);
+        assert_eq!(control.admit(2, &request(2)).unwrap(), Admission::Admitted);
+        assert_eq!(control.remaining().unwrap(), Bandwidth::from_kbps(0.0));
+        assert_eq!(control.admit(3, &request(0)).unwrap(), Admission::Rejected);
+
+        // a session resuming at a lower level gives back the difference
+        assert_eq!(control.admit(2, &request(1)).unwrap(), Admission::Admitted);
+        assert_eq!(control.admit(3, &request(2)).unwrap(), Admission::Capped(0));
+        assert_eq!(control.remaining().unwrap(), Bandwidth::from_kbps(400.0));
+
+        control.release(1).unwrap();
+        assert_eq!(control.admit(4, &request(2)).unwrap(), Admission::Admitted);
+        // a level beyond the profile asks for its top level
+        control.release(4).unwrap();
+        assert_eq!(control.admit(5, &request(7)).unwrap(), Admission::Capped(2));
+    }
+
+    #[cfg(all(feature = "client", feature = "server"))]
+    #[test]
+    fn test_handshake_replies() {
+        use bytes::BytesMut;
+        use catalog::ClientIdentity;
+        use demo::loopback_setting;
+        use futures::Stream;
+        use server::Server;
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+        use std::sync::mpsc;
+        use std::thread;
+        use tokio_core::reactor::Core;
+        use tokio_io::codec::{Decoder, Encoder};
+        use {AsCodec, AsDatum, AsDatumType};
+
+        let (port_tx, port_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut core = Core::new().unwrap();
+            let mut setting = loopback_setting(0);
+            setting.ingest_budget_kbps = Some(1200.0);
+            let server = Server::bind(setting, &core.handle()).unwrap();
+            port_tx.send(server.local_addr()).unwrap();
+            core.run(server.incoming_events().for_each(|_| Ok(()))).unwrap();
+        });
+        let addr = port_rx.recv().unwrap();
+        let identity = ClientIdentity {
+            client_id: Some("cam".into()),
+            ..ClientIdentity::default()
+        };
+        let request = AdmissionRequest {
+            level: 1,
+            rates: vec![Bandwidth::from_kbps(300.0), Bandwidth::from_kbps(800.0)],
+        };
+        let hello = AsDatum::hello_requesting(None, &identity, &request).unwrap();
+        assert_eq!(hello.client_identity().unwrap(), identity);
+        assert_eq!(hello.admission_request().unwrap(), Some(request));
+
+        // the replies of the server until it waits for frames or hangs up
+        let replies = |conn: &mut TcpStream| {
+            let mut codec = AsCodec::default();
+            let mut buf = BytesMut::new();
+            codec.encode(hello.clone(), &mut buf).unwrap();
+            conn.write_all(&buf).unwrap();
+            let (mut buf, mut answer) = (BytesMut::new(), None);
+            let mut chunk = [0; 1024];
+            loop {
+                while let Some(datum) = codec.decode(&mut buf).unwrap() {
+                    match datum.datum_type() {
+                        AsDatumType::Welcome(_) => return (answer, true),
+                        _ => answer = datum.admission_answer().unwrap(),
+                    }
+                }
+                match conn.read(&mut chunk).unwrap() {
+                    0 => return (answer, false),
+                    n => buf.extend_from_slice(&chunk[..n]),
+                }
+            }
+        };
+        let mut first = TcpStream::connect(addr).unwrap();
+        assert_eq!(replies(&mut first), (Some(Admission::Admitted), true));
+        let mut second = TcpStream::connect(addr).unwrap();
+        assert_eq!(replies(&mut second), (Some(Admission::Capped(0)), true));
+        let mut third = TcpStream::connect(addr).unwrap();
+        assert_eq!(replies(&mut third), (Some(Admission::Rejected), false));
+
+        // the first session leaving makes room
+        drop(first);
+        thread::sleep(::std::time::Duration::from_millis(200));
+        let mut fourth = TcpStream::connect(addr).unwrap();
+        assert_eq!(replies(&mut fourth), (Some(Admission::Admitted), true));
+    }
+}
@@ -1,3 +1,4 @@
+use super::Bandwidth;
 use super::errors::*;
 use super::evaluation::{self, FrameStat, f1, precision, recall};
 use super::profile::Profile;
@@ -8,7 +9,7 @@ use std::vec::Vec;
 
 #[derive(Clone)]
 pub struct VideoAnalytics {
-    inner: Arc<Mutex<Inner>>,
+    inner: Option<Arc<Mutex<Inner>>>,
 }
 
 struct Inner {
@@ -29,23 +30,40 @@ impl VideoAnalytics {
         let frame_stats: Vec<FrameStat> = FrameStat::from_csv(stat);
         let profile: Profile<VideoConfig> = Profile::new(profile);
         let inner = Inner {
-            frame_stats: frame_stats,
-            profile: profile,
+            frame_stats,
+            profile,
             logs: Vec::new(),
         };
 
-        VideoAnalytics { inner: Arc::new(Mutex::new(inner)) }
+        VideoAnalytics { inner: Some(Arc::new(Mutex::new(inner))) }
+    }
+
+    /// Analytics that ignore frames, for streams without ground truth.
+    pub fn disabled() -> VideoAnalytics {
+        VideoAnalytics { inner: None }
     }
 
     pub fn add(&mut self, frame_num: usize, level: usize) -> Result<()> {
-        let mut m = self.inner.lock()?;
-        (*m).logs.push((frame_num, level));
+        if let Some(ref inner) = self.inner {
+            inner.lock()?.logs.push((frame_num, level));
+        }
         Ok(())
     }
 
+    /// The rates of the profile's levels (none if disabled).
+    pub fn rates(&self) -> Result<Vec<Bandwidth>> {
+        match self.inner {
+            Some(ref inner) => Ok(inner.lock()?.profile.iter().map(|r| r.bandwidth).collect()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// The F1 score of the frames added since the last call (0 if disabled).
     pub fn accuracy(&self) -> Result<f64> {
-        let mut m = self.inner.lock()?;
-        Ok((*m).accuracy())
+        match self.inner {
+            Some(ref inner) => Ok(inner.lock()?.accuracy()),
+            None => Ok(0.0),
+        }
     }
 }
 
@@ -0,0 +1,359 @@
+//! A source of Opus audio, adapting the encoder bitrate and frame size.
+//!
+//! Each level of an audio profile is an `AudioConfig`. `AudioSource` takes
+//! 16-bit PCM at 48 kHz, either from a capture callback (through an
+//! `AudioSender`) or from a raw PCM file, and encodes a frame of the
+//! current level every frame interval with an `OpusEncode` backend. Opus
+//! encodes a frame in well under its duration, so encoding happens on the
+//! reactor thread.
+
+use super::{Adapt, AsDatum, Bandwidth, Capabilities, CapabilityCheck};
+use super::config::{ConfigDelta, Configurable, Demand};
+use super::decision::{SharedClock, SystemClock};
+use super::profile::{Profile, SimpleProfile};
+use super::source::Source;
+use super::ticker::Ticker;
+use errors::*;
+use futures::{Async, Poll, Stream};
+use futures::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Sample rate of the PCM input (Hz).
+pub const SAMPLE_RATE: usize = 48_000;
+
+/// Frame durations Opus supports in whole milliseconds.
+const FRAME_MS: [u64; 5] = [5, 10, 20, 40, 60];
+
+/// Bitrates Opus supports (kbps).
+const MIN_BITRATE: usize = 6;
+const MAX_BITRATE: usize = 510;
+
+/// A level of an audio profile.
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
+pub struct AudioConfig {
+    /// Target bitrate of the encoder (kbps).
+    pub bitrate_kbps: usize,
+
+    /// Duration of a frame (ms).
+    pub frame_ms: u64,
+}
+
+profile_schema!(AudioConfig { bitrate_kbps: usize, frame_ms: u64 });
+
+impl fmt::Display for AudioConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}kbps/{}ms", self.bitrate_kbps, self.frame_ms)
+    }
+}
+
+impl Configurable for AudioConfig {
+    fn validate(&self) -> Result<()> {
+        if self.bitrate_kbps < MIN_BITRATE || self.bitrate_kbps > MAX_BITRATE {
+            bail!(ErrorKind::InvalidConfig(format!(
+                "bitrate {} kbps outside {}..{}",
+                self.bitrate_kbps,
+                MIN_BITRATE,
+                MAX_BITRATE
+            )));
+        }
+        if !FRAME_MS.contains(&self.frame_ms) {
+            bail!(ErrorKind::InvalidConfig(
+                format!("frame of {} ms is not one of {:?}", self.frame_ms, FRAME_MS),
+            ));
+        }
+        Ok(())
+    }
+
+    fn apply_delta(&self, prev: &Self) -> ConfigDelta {
+        let mut delta = ConfigDelta::new();
+        delta.push("bitrate_kbps", prev.bitrate_kbps, self.bitrate_kbps);
+        delta.push("frame_ms", prev.frame_ms, self.frame_ms);
+        delta
+    }
+
+    fn demand(&self) -> Demand {
+        Demand {
+            format: Some("opus".into()),
+            ..Demand::default()
+        }
+    }
+}
+
+/// An Opus encoder.
+pub trait OpusEncode {
+    /// Encodes one frame of interleaved `pcm` (exactly `frame_ms` of audio)
+    /// at the bitrate of `config`.
+    fn encode(&mut self, pcm: &[i16], config: AudioConfig) -> Result<Vec<u8>>;
+}
+
+/// Feeds captured PCM into an `AudioSource`, e.g., from the callback of an
+/// audio device. The source ends once every sender is dropped.
+#[derive(Debug, Clone)]
+pub struct AudioSender {
+    tx: UnboundedSender<Vec<i16>>,
+}
+
+impl AudioSender {
+    /// Pushes interleaved samples. Fails if the source is gone.
+    pub fn push(&self, pcm: &[i16]) -> Result<()> {
+        self.tx.unbounded_send(pcm.to_vec()).map_err(|_| ErrorKind::SourceData.into())
+    }
+}
+
+enum AudioInput {
+    Capture(UnboundedReceiver<Vec<i16>>),
+    File(BufReader<File>),
+}
+
+/// `AudioSource` encodes a frame every frame interval of the current level.
+/// With capture input, a frame waits for its samples; a short last frame
+/// is padded with silence.
+pub struct AudioSource<E> {
+    profile: Profile<AudioConfig>,
+    encoder: E,
+    channels: usize,
+    input: AudioInput,
+    buffer: Vec<i16>,
+    ended: bool,
+    clock: SharedClock,
+    ticker: Ticker,
+    frame_ms: u64,
+    /// A tick has passed and its frame is not out yet.
+    due: bool,
+    frames: usize,
+}
+
+impl<E: OpusEncode> AudioSource<E> {
+    /// Creates a source encoding captured audio of `channels` channels, and
+    /// the sender to push it with.
+    pub fn capture(profile: Profile<AudioConfig>, encoder: E, channels: usize) -> (AudioSource<E>, AudioSender) {
+        let (tx, rx) = unbounded();
+        let source = AudioSource::with_input(profile, encoder, channels, AudioInput::Capture(rx));
+        (source, AudioSender { tx })
+    }
+
+    /// Creates a source encoding a file of raw PCM (16-bit little-endian,
+    /// interleaved `channels`), paced as if captured live.
+    pub fn file<P: AsRef<Path>>(
+        path: P,
+        profile: Profile<AudioConfig>,
+        encoder: E,
+        channels: usize,
+    ) -> Result<AudioSource<E>> {
+        let file = BufReader::new(File::open(path)?);
+        Ok(AudioSource::with_input(profile, encoder, channels, AudioInput::File(file)))
+    }
+
+    fn with_input(profile: Profile<AudioConfig>, encoder: E, channels: usize, input: AudioInput) -> AudioSource<E> {
+        let clock: SharedClock = Arc::new(SystemClock::new());
+        let frame_ms = profile.current_config().frame_ms;
+        AudioSource {
+            ticker: Ticker::new(clock.clone(), Duration::from_millis(frame_ms)),
+            clock,
+            frame_ms,
+            profile,
+            encoder,
+            channels,
+            input,
+            buffer: Vec::new(),
+            ended: false,
+            due: false,
+            frames: 0,
+        }
+    }
+
+    /// Paces frames by `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: SharedClock) -> AudioSource<E> {
+        self.ticker = Ticker::new(clock.clone(), Duration::from_millis(self.frame_ms));
+        self.clock = clock;
+        self
+    }
+
+    /// Restarts pacing if the level changed the frame duration.
+    fn retime(&mut self) {
+        let frame_ms = self.profile.current_config().frame_ms;
+        if frame_ms != self.frame_ms {
+            self.frame_ms = frame_ms;
+            self.ticker = Ticker::new(self.clock.clone(), Duration::from_millis(frame_ms));
+        }
+    }
+
+    /// Samples in a frame of the current level.
+    fn frame_samples(&self) -> usize {
+        SAMPLE_RATE * self.frame_ms as usize / 1000 * self.channels
+    }
+
+    /// Buffers input until a frame is complete or the input ends.
+    fn fill(&mut self, samples: usize) -> Poll<(), Error> {
+        while self.buffer.len() < samples && !self.ended {
+            match self.input {
+                AudioInput::Capture(ref mut rx) => match rx.poll() {
+                    Ok(Async::Ready(Some(pcm))) => self.buffer.extend(pcm),
+                    Ok(Async::Ready(None)) | Err(()) => self.ended = true,
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                },
+                AudioInput::File(ref mut file) => {
+                    // short reads would split samples; read up to the frame
+                    let want = 2 * (samples - self.buffer.len());
+                    let mut bytes = Vec::with_capacity(want);
+                    file.by_ref().take(want as u64).read_to_end(&mut bytes)?;
+                    if bytes.len() < want {
+                        self.ended = true;
+                    }
+                    let pcm = bytes.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]]));
+                    self.buffer.extend(pcm);
+                }
+            }
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<E: OpusEncode> Adapt for AudioSource<E> {
+    fn adapt(&mut self, bandwidth: Bandwidth) {
+        self.profile.adjust_config(bandwidth);
+        self.retime();
+    }
+
+    fn dec_degradation(&mut self) {
+        self.profile.advance_config();
+        self.retime();
+    }
+
+    fn set_level(&mut self, level: usize) {
+        self.profile.set_config(level);
+        self.retime();
+    }
+
+    fn period_in_ms(&self) -> u64 {
+        self.frame_ms
+    }
+
+    fn current_level(&self) -> usize {
+        self.profile.current_level()
+    }
+
+    fn simple_profile(&self) -> SimpleProfile {
+        self.profile.simplify()
+    }
+
+    fn observe_accuracy(&mut self, level: usize, quality: f64, weight: f64) {
+        self.profile.observe_accuracy(level, quality, weight);
+    }
+
+    fn restrict(&mut self, caps: &Capabilities, check: CapabilityCheck) -> Result<Vec<usize>> {
+        let masked = self.profile.restrict(caps, check)?;
+        self.retime();
+        Ok(masked)
+    }
+}
+
+impl<E: OpusEncode> Source for AudioSource<E> {
+    fn poll_frame(&mut self) -> Poll<Option<AsDatum>, Error> {
+        if !self.due {
+            if try_ready!(self.ticker.poll()).is_none() {
+                return Ok(Async::Ready(None));
+            }
+            self.due = true;
+        }
+        let samples = self.frame_samples();
+        try_ready!(self.fill(samples));
+        if self.buffer.is_empty() {
+            return Ok(Async::Ready(None));
+        }
+        self.due = false;
+        let mut pcm = self.buffer.drain(..samples.min(self.buffer.len())).collect::<Vec<_>>();
+        pcm.resize(samples, 0);
+        let config = self.profile.current_config();
+        let mem = self.encoder.encode(&pcm, config)?;
+        self.frames += 1;
+        Ok(Async::Ready(Some(AsDatum::new(self.profile.current_level(), self.frames, mem))))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            formats: Some(vec!["opus".into()]),
+            ..Capabilities::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {AsDatumType, Record};
+    use decision::ManualClock;
+    use futures::{future, Future};
+
+    /// Produces frames of exactly the target bitrate.
+    struct ConstantBitrate;
+
+    impl OpusEncode for ConstantBitrate {
+        fn encode(&mut self, pcm: &[i16], config: AudioConfig) -> Result<Vec<u8>> {
+            assert_eq!(pcm.len(), SAMPLE_RATE * config.frame_ms as usize / 1000);
+            Ok(vec![0; config.bitrate_kbps * config.frame_ms as usize / 8])
+        }
+    }
+
+    fn poll<E: OpusEncode>(source: &mut AudioSource<E>) -> Async<Option<AsDatum>> {
+        future::lazy(|| Ok::<_, ()>(source.poll_frame().unwrap())).wait().unwrap()
+    }
+
+    #[test]
+    fn test_audio_source() {
+        let profile = Profile::_with_vec(vec![
+            Record::new(
+                Bandwidth::from_kbps(12.0),
+                AudioConfig {
+                    bitrate_kbps: 12,
+                    frame_ms: 60,
+                },
+                0.6,
+            ),
+            Record::new(
+                Bandwidth::from_kbps(64.0),
+                AudioConfig {
+                    bitrate_kbps: 64,
+                    frame_ms: 20,
+                },
+                1.0,
+            ),
+        ]);
+        let clock = ManualClock::new(0);
+        let (source, sender) = AudioSource::capture(profile, ConstantBitrate, 1);
+        let mut source = source.with_clock(Arc::new(clock.clone()));
+        assert_eq!(source.period_in_ms(), 60);
+
+        // a frame is out once its interval passed and its samples arrived
+        sender.push(&[1; 2880]).unwrap();
+        assert_eq!(poll(&mut source), Async::NotReady);
+        clock.advance(60);
+        let frame = match poll(&mut source) {
+            Async::Ready(Some(frame)) => frame,
+            other => panic!("expected a frame, got {:?}", other),
+        };
+        assert_eq!(frame.datum_type(), AsDatumType::Live(0, 1));
+        assert_eq!(frame.mem.len(), 90);
+
+        // the top level paces at 20 ms
+        source.set_level(1);
+        assert_eq!(source.period_in_ms(), 20);
+        clock.advance(20);
+        assert_eq!(poll(&mut source), Async::NotReady);
+        sender.push(&[1; 500]).unwrap();
+        drop(sender);
+        // the short last frame is padded
+        match poll(&mut source) {
+            Async::Ready(Some(frame)) => assert_eq!(frame.mem.len(), 160),
+            other => panic!("expected a frame, got {:?}", other),
+        }
+        clock.advance(20);
+        assert_eq!(poll(&mut source), Async::Ready(None));
+    }
+}
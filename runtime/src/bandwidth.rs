@@ -0,0 +1,170 @@
+//! A bandwidth with its unit.
+//!
+//! Profiles are written in kbps, byte counters count bytes and traces are
+//! often in Mbps; bare `f64`s made it easy to mix them up. `Bandwidth` holds
+//! bits per second and is only built and read through explicit units. It is
+//! (de)serialized as a number of kbps, the unit of profile files.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::ops::{Add, Div, Mul, Sub};
+
+/// A bandwidth, stored in bits per second.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+pub struct Bandwidth(f64);
+
+impl Bandwidth {
+    /// No bandwidth.
+    pub const ZERO: Bandwidth = Bandwidth(0.0);
+
+    /// From bits per second.
+    pub fn from_bps(bps: f64) -> Bandwidth {
+        Bandwidth(bps)
+    }
+
+    /// From kilobits per second.
+    pub fn from_kbps(kbps: f64) -> Bandwidth {
+        Bandwidth(kbps * 1e3)
+    }
+
+    /// From megabits per second.
+    pub fn from_mbps(mbps: f64) -> Bandwidth {
+        Bandwidth(mbps * 1e6)
+    }
+
+    /// From `bytes` transferred in `ms` milliseconds.
+    pub fn from_bytes_per_ms(bytes: f64, ms: f64) -> Bandwidth {
+        Bandwidth(bytes * 8.0 * 1e3 / ms)
+    }
+
+    /// In bits per second.
+    pub fn bps(self) -> f64 {
+        self.0
+    }
+
+    /// In kilobits per second.
+    pub fn kbps(self) -> f64 {
+        self.0 / 1e3
+    }
+
+    /// In megabits per second.
+    pub fn mbps(self) -> f64 {
+        self.0 / 1e6
+    }
+
+    /// In bytes per second.
+    pub fn bytes_per_sec(self) -> f64 {
+        self.0 / 8.0
+    }
+
+    /// How long (ms) `bytes` take at this bandwidth (infinite at zero).
+    pub fn transfer_ms(self, bytes: usize) -> f64 {
+        bytes as f64 * 8.0 * 1e3 / self.0
+    }
+
+    /// The smaller of two bandwidths.
+    pub fn min(self, other: Bandwidth) -> Bandwidth {
+        Bandwidth(self.0.min(other.0))
+    }
+
+    /// The larger of two bandwidths.
+    pub fn max(self, other: Bandwidth) -> Bandwidth {
+        Bandwidth(self.0.max(other.0))
+    }
+}
+
+impl fmt::Display for Bandwidth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bps = self.0.abs();
+        if bps >= 1e6 {
+            write!(f, "{:.2} Mbps", self.mbps())
+        } else if bps >= 1e3 {
+            write!(f, "{:.1} kbps", self.kbps())
+        } else {
+            write!(f, "{:.0} bps", self.0)
+        }
+    }
+}
+
+impl Add for Bandwidth {
+    type Output = Bandwidth;
+
+    fn add(self, other: Bandwidth) -> Bandwidth {
+        Bandwidth(self.0 + other.0)
+    }
+}
+
+impl Sub for Bandwidth {
+    type Output = Bandwidth;
+
+    fn sub(self, other: Bandwidth) -> Bandwidth {
+        Bandwidth(self.0 - other.0)
+    }
+}
+
+impl Mul<f64> for Bandwidth {
+    type Output = Bandwidth;
+
+    fn mul(self, factor: f64) -> Bandwidth {
+        Bandwidth(self.0 * factor)
+    }
+}
+
+impl Div<f64> for Bandwidth {
+    type Output = Bandwidth;
+
+    fn div(self, divisor: f64) -> Bandwidth {
+        Bandwidth(self.0 / divisor)
+    }
+}
+
+/// The ratio of two bandwidths.
+impl Div for Bandwidth {
+    type Output = f64;
+
+    fn div(self, other: Bandwidth) -> f64 {
+        self.0 / other.0
+    }
+}
+
+impl Serialize for Bandwidth {
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_f64(self.kbps())
+    }
+}
+
+impl<'de> Deserialize<'de> for Bandwidth {
+    fn deserialize<D>(deserializer: D) -> ::std::result::Result<Bandwidth, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        f64::deserialize(deserializer).map(Bandwidth::from_kbps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bincode;
+
+    #[test]
+    fn test_bandwidth_units() {
+        let b = Bandwidth::from_kbps(1500.0);
+        assert_eq!(b, Bandwidth::from_mbps(1.5));
+        assert_eq!(b.bps(), 1.5e6);
+        assert_eq!(b.bytes_per_sec(), 187_500.0);
+        // 1250 bytes in 10 ms
+        assert_eq!(Bandwidth::from_bytes_per_ms(1250.0, 10.0), Bandwidth::from_kbps(1000.0));
+        assert_eq!(Bandwidth::from_kbps(1000.0).transfer_ms(1250), 10.0);
+        assert_eq!(format!("{}", b), "1.50 Mbps");
+        assert_eq!(format!("{}", Bandwidth::from_kbps(64.0)), "64.0 kbps");
+        assert_eq!(format!("{}", Bandwidth::from_bps(800.0)), "800 bps");
+        assert_eq!(b * 2.0 - b, b);
+        assert_eq!(b / Bandwidth::from_kbps(500.0), 3.0);
+
+        // stored in kbps
+        let mem = bincode::serialize(&b, bincode::Infinite).unwrap();
+        assert_eq!(bincode::deserialize::<f64>(&mem).unwrap(), 1500.0);
+        assert_eq!(bincode::deserialize::<Bandwidth>(&mem).unwrap(), b);
+    }
+}
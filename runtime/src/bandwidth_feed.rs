@@ -0,0 +1,100 @@
+//! The client's bandwidth estimates, for any number of consumers.
+//!
+//! Every `MONITOR_INTERVAL`, the monitor turns the bytes produced and sent
+//! into an estimate of the sending rate and of the queueing delay. Besides
+//! the controller, the application (a UI, metrics) may want them. Rather
+//! than read the byte counters the monitor resets, each consumer subscribes
+//! to a `BandwidthFeed` and receives every sample from then on as a
+//! `Stream`, or reads the latest one, like a watch channel.
+
+use super::Bandwidth;
+use futures::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// An estimate of the monitor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BandwidthSample {
+    /// When it was taken (ms of the client's clock).
+    pub time_ms: u64,
+
+    /// The estimated sending rate.
+    pub rate: Bandwidth,
+
+    /// The estimated queueing delay (ms).
+    pub queue_delay_ms: f64,
+
+    /// The estimated bandwidth of the path, once the send queue backlogged
+    /// (see `QueueEstimator::bandwidth`).
+    pub bandwidth: Option<Bandwidth>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    latest: Option<BandwidthSample>,
+    subscribers: Vec<UnboundedSender<BandwidthSample>>,
+}
+
+/// Publishes samples to its subscribers. Clones share the subscribers.
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthFeed {
+    state: Arc<Mutex<State>>,
+}
+
+impl BandwidthFeed {
+    /// Creates a feed without subscribers.
+    pub fn new() -> BandwidthFeed {
+        BandwidthFeed::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("bandwidth feed poisoned")
+    }
+
+    /// Delivers the samples from now on. Dropping the receiver unsubscribes.
+    pub fn subscribe(&self) -> UnboundedReceiver<BandwidthSample> {
+        let (tx, rx) = unbounded();
+        self.lock().subscribers.push(tx);
+        rx
+    }
+
+    /// The last sample, if any.
+    pub fn latest(&self) -> Option<BandwidthSample> {
+        self.lock().latest
+    }
+
+    /// Delivers `sample` to every subscriber still listening.
+    pub fn publish(&self, sample: BandwidthSample) {
+        let mut state = self.lock();
+        state.latest = Some(sample);
+        state.subscribers.retain(|tx| tx.unbounded_send(sample).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Stream;
+
+    #[test]
+    fn test_every_subscriber_sees_every_sample() {
+        let feed = BandwidthFeed::new();
+        let sample = |t| BandwidthSample {
+            time_ms: t,
+            rate: Bandwidth::from_kbps(t as f64),
+            queue_delay_ms: 0.0,
+            bandwidth: None,
+        };
+        assert_eq!(feed.latest(), None);
+        let (ui, metrics) = (feed.subscribe(), feed.clone().subscribe());
+        feed.publish(sample(100));
+        let late = feed.subscribe();
+        feed.publish(sample(200));
+        assert_eq!(feed.latest(), Some(sample(200)));
+
+        drop(feed);
+        let times = |rx: UnboundedReceiver<BandwidthSample>| rx.wait().map(|s| s.unwrap().time_ms).collect::<Vec<_>>();
+        assert_eq!(times(ui), vec![100, 200]);
+        assert_eq!(times(metrics), vec![100, 200]);
+        assert_eq!(times(late), vec![200]);
+    }
+}
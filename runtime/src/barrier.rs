@@ -0,0 +1,225 @@
+//! Barriers between configurations on the send path.
+//!
+//! When the source switches levels, the driver emits a `Barrier` datum ahead
+//! of the first frame of the new level. `Drain` makes the barrier hold on the
+//! way to the socket: frames of the old level still queued before it are
+//! either flushed or dropped (`BarrierPolicy`), and stale frames surfacing
+//! after it (backfill, redundant padding) are discarded, so the receiver
+//! never sees frames of two configurations interleaved.
+//!
+//! Only dropping takes the ready backlog ahead, to find the barrier behind
+//! it; flushing pulls one datum at a time, leaving the send queue (CoDel,
+//! the scheduler's control reserve) in charge of what leaves when.
+
+use super::{AsDatum, AsDatumType};
+use super::memory::Account;
+use futures::{Async, Poll, Stream};
+use std::collections::VecDeque;
+
+/// What happens to frames of the old level queued before a barrier.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BarrierPolicy {
+    /// Send them before the barrier.
+    Flush,
+
+    /// Discard them; the new level starts right away.
+    Drop,
+}
+
+/// Returns the level of a datum carrying a frame.
+fn frame_level(datum: &AsDatum) -> Option<usize> {
+    match datum.datum_type() {
+        AsDatumType::Live(level, _) |
+        AsDatumType::Reference(level, _) |
+        AsDatumType::Backfill(level, _) |
+        AsDatumType::Redundant(level, _) => Some(level),
+        _ => None,
+    }
+}
+
+/// Enforces the barriers of a stream of datums.
+pub struct Drain<S> {
+    inner: S,
+    policy: BarrierPolicy,
+    queued: VecDeque<AsDatum>,
+    /// The level of the last barrier sent.
+    level: Option<usize>,
+    done: bool,
+    account: Option<Account>,
+}
+
+impl<S: Stream<Item = AsDatum>> Drain<S> {
+    /// Enforces the barriers of `inner` according to `policy`.
+    pub fn new(inner: S, policy: BarrierPolicy) -> Drain<S> {
+        Drain {
+            inner,
+            policy,
+            queued: VecDeque::new(),
+            level: None,
+            done: false,
+            account: None,
+        }
+    }
+
+    /// Holds the datums taken ahead (only `BarrierPolicy::Drop` takes any)
+    /// within a memory budget: frames that don't fit, or arrive under
+    /// pressure, are dropped.
+    pub fn set_budget(&mut self, account: Account) {
+        self.account = Some(account);
+    }
+
+    /// Queues `datum` unless it is a frame the budget refuses.
+    fn admit(&mut self, datum: AsDatum) {
+        if let Some(ref account) = self.account {
+            if frame_level(&datum).is_none() {
+                account.charge(datum.net_len());
+            } else if !account.try_reserve(datum.net_len()) {
+                debug!("no memory to queue {}", datum);
+                return;
+            }
+        }
+        self.queued.push_back(datum);
+    }
+
+    /// Gives back the memory of datums leaving the queue.
+    fn release(&self, bytes: usize) {
+        if let Some(ref account) = self.account {
+            account.release(bytes);
+        }
+    }
+
+    /// Drops the frames queued ahead of the barrier just queued.
+    fn drop_before_barrier(&mut self) {
+        let before = self.queued.len();
+        let barrier = self.queued.pop_back().expect("barrier queued");
+        let mut freed = 0;
+        self.queued.retain(|d| {
+            let keep = frame_level(d).is_none();
+            if !keep {
+                freed += d.net_len();
+            }
+            keep
+        });
+        self.release(freed);
+        let dropped = before - 1 - self.queued.len();
+        if dropped > 0 {
+            debug!("dropped {} frames before {}", dropped, barrier);
+        }
+        self.queued.push_back(barrier);
+    }
+
+    /// Takes in what is ready, so that a barrier can overtake the backlog.
+    fn read_ahead(&mut self) -> Result<(), S::Error> {
+        while !self.done {
+            match self.inner.poll()? {
+                Async::Ready(Some(datum)) => {
+                    let barrier = matches!(datum.datum_type(), AsDatumType::Barrier(_));
+                    self.admit(datum);
+                    if barrier {
+                        self.drop_before_barrier();
+                    }
+                }
+                Async::Ready(None) => self.done = true,
+                Async::NotReady => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `datum` is a frame of a level before the last barrier sent,
+    /// and records the level of a barrier.
+    fn is_stale(&mut self, datum: &AsDatum) -> bool {
+        match (datum.datum_type(), frame_level(datum), self.level) {
+            (AsDatumType::Barrier(level), _, _) => {
+                self.level = Some(level);
+                false
+            }
+            (_, Some(level), Some(current)) => level != current,
+            _ => false,
+        }
+    }
+}
+
+impl<S: Stream<Item = AsDatum>> Stream for Drain<S> {
+    type Item = AsDatum;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<AsDatum>, S::Error> {
+        loop {
+            let datum = match self.policy {
+                BarrierPolicy::Drop => {
+                    self.read_ahead()?;
+                    match self.queued.pop_front() {
+                        Some(datum) => {
+                            self.release(datum.net_len());
+                            datum
+                        }
+                        None if self.done => return Ok(Async::Ready(None)),
+                        None => return Ok(Async::NotReady),
+                    }
+                }
+                // the backlog goes out anyway: take one datum at a time, so
+                // that the queues before keep deciding what leaves when
+                BarrierPolicy::Flush => match try_ready!(self.inner.poll()) {
+                    Some(datum) => datum,
+                    None => return Ok(Async::Ready(None)),
+                },
+            };
+            if self.is_stale(&datum) {
+                trace!("dropped stale {}", datum);
+                continue;
+            }
+            return Ok(Async::Ready(Some(datum)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{Future, future};
+    use futures::stream;
+
+    fn drain(policy: BarrierPolicy) -> Vec<AsDatumType> {
+        let datums = vec![
+            AsDatum::new(0, 1, vec![]),
+            AsDatum::latency_probe(),
+            AsDatum::new(0, 2, vec![]),
+            AsDatum::barrier(1),
+            AsDatum::new(1, 3, vec![]),
+            AsDatum::new(0, 1, vec![]).into_redundant(),
+            AsDatum::new(1, 4, vec![]),
+        ];
+        let mut drain = Drain::new(stream::iter_ok::<_, ()>(datums), policy);
+        let sent = future::lazy(|| stream::poll_fn(|| drain.poll()).collect()).wait();
+        sent.unwrap().iter().map(AsDatum::datum_type).collect()
+    }
+
+    #[test]
+    fn test_barrier_drain() {
+        let stale_removed = vec![
+            AsDatumType::Barrier(1),
+            AsDatumType::Live(1, 3),
+            AsDatumType::Live(1, 4),
+        ];
+        let mut flushed = vec![AsDatumType::Live(0, 1), AsDatumType::LatencyProbe, AsDatumType::Live(0, 2)];
+        flushed.extend(stale_removed.iter().cloned());
+        assert_eq!(drain(BarrierPolicy::Flush), flushed);
+
+        let mut dropped = vec![AsDatumType::LatencyProbe];
+        dropped.extend(stale_removed);
+        assert_eq!(drain(BarrierPolicy::Drop), dropped);
+    }
+
+    #[test]
+    fn test_flush_takes_nothing_ahead() {
+        use std::cell::Cell;
+        let taken = Cell::new(0);
+        let datums = (1..5).map(|i| AsDatum::new(0, i, vec![]));
+        let inner = stream::iter_ok::<_, ()>(datums).inspect(|_| taken.set(taken.get() + 1));
+        let mut drain = Drain::new(inner, BarrierPolicy::Flush);
+        future::lazy(|| drain.poll()).wait().unwrap();
+        assert_eq!(taken.get(), 1);
+    }
+}
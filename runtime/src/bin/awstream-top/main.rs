@@ -0,0 +1,94 @@
+//! A terminal dashboard of a running client
+//!
+//! Connects to the stats port of a client (`stats_port` in its setting) and
+//! redraws the current level, throughput, queueing delay, drops and RTT on
+//! every snapshot. Run it on the edge box over SSH, or through a tunnel:
+//!
+//! ```text
+//! awstream-top 127.0.0.1:9200
+//! ```
+
+extern crate awstream;
+extern crate chrono;
+
+use awstream::stats::{self, StatsSnapshot};
+use chrono::{TimeZone, Utc};
+use std::collections::VecDeque;
+use std::env;
+use std::io::{self, Write};
+use std::process;
+
+/// Throughput samples in the sparkline.
+const HISTORY: usize = 60;
+
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+fn sparkline(values: &VecDeque<f64>) -> String {
+    let max = values.iter().cloned().fold(0.0, f64::max);
+    values
+        .iter()
+        .map(|&v| {
+            let i = if max > 0.0 { (v / max * 7.0).round() as usize } else { 0 };
+            BARS[i.min(7)]
+        })
+        .collect()
+}
+
+fn or_dash<T: ToString>(v: Option<T>) -> String {
+    v.map_or("-".into(), |v| v.to_string())
+}
+
+fn draw<W: Write>(out: &mut W, addr: &str, s: &StatsSnapshot, history: &VecDeque<f64>) -> io::Result<()> {
+    let time = Utc.timestamp_millis_opt(s.time_ms)
+        .single()
+        .map_or("-".into(), |t| t.format("%H:%M:%S").to_string());
+    // clears the screen and moves to the top-left corner
+    write!(out, "\x1b[2J\x1b[H")?;
+    writeln!(out, "awstream-top  {}  {}", addr, time)?;
+    writeln!(out)?;
+    writeln!(out, "level        {}", or_dash(s.level))?;
+    writeln!(out, "throughput   {:.1} kbps", s.throughput_kbps)?;
+    writeln!(out, "             {}", sparkline(history))?;
+    writeln!(out, "queue delay  {:.1} ms", s.queue_delay_ms)?;
+    writeln!(out, "drops        {}", s.drops)?;
+    writeln!(out, "rtt          {} ms", or_dash(s.rtt_ms.map(|r| format!("{:.1}", r))))?;
+    out.flush()
+}
+
+pub fn main() {
+    let addr = match env::args().nth(1) {
+        Some(addr) => addr,
+        None => {
+            eprintln!("usage: awstream-top <host:port>");
+            process::exit(2);
+        }
+    };
+    let snapshots = match stats::watch(addr.as_str()) {
+        Ok(snapshots) => snapshots,
+        Err(e) => {
+            eprintln!("failed to connect to {}: {}", addr, e);
+            process::exit(1);
+        }
+    };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut history = VecDeque::with_capacity(HISTORY);
+    for snapshot in snapshots {
+        let snapshot = match snapshot {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("lost {}: {}", addr, e);
+                process::exit(1);
+            }
+        };
+        if history.len() == HISTORY {
+            history.pop_front();
+        }
+        history.push_back(snapshot.throughput_kbps);
+        if draw(&mut out, &addr, &snapshot, &history).is_err() {
+            return;
+        }
+    }
+    eprintln!("{} closed the connection", addr);
+}
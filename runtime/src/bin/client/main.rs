@@ -20,7 +20,7 @@ pub fn main() {
         let t = chrono::Utc::now();
         format!(
             "{} {}:{}: {}",
-            t.format("%Y-%m-%d %H:%M:%S%.3f").to_string(),
+            t.format("%Y-%m-%d %H:%M:%S%.3f"),
             record.level(),
             record.location().module_path(),
             record.args()
@@ -34,6 +34,7 @@ pub fn main() {
     }
 
     builder.init().unwrap();
+    postmortem::install();
 
     // Client runs
     let setting = Setting::init("Setting.toml").unwrap();
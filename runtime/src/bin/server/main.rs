@@ -11,7 +11,7 @@ pub fn main() {
         let t = chrono::Utc::now();
         format!(
             "{} {}",
-            t.format("%Y-%m-%d %H:%M:%S%.3f").to_string(),
+            t.format("%Y-%m-%d %H:%M:%S%.3f"),
             record.args()
         )
     };
@@ -23,6 +23,7 @@ pub fn main() {
     }
 
     builder.init().unwrap();
+    postmortem::install();
 
     let setting = Setting::init("Setting.toml").unwrap();
     server::server(setting);
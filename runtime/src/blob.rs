@@ -0,0 +1,300 @@
+//! Out-of-band payloads.
+//!
+//! Some profiles only need metadata at their low levels: the full payload is
+//! worth keeping, but not worth streaming under congestion. `Offloader` wraps
+//! a source and, for the chosen levels, uploads the payload of each frame to
+//! a `BlobStore` (on a thread pool) and streams a `Reference` datum instead,
+//! carrying the blob's key and a small summary (e.g., a thumbnail). Receivers
+//! fetch the payload from the store when they need it.
+
+use super::{Adapt, AsDatum, AsDatumType, Bandwidth, Capabilities, CapabilityCheck, Hint, StreamInfo};
+use super::profile::SimpleProfile;
+use super::source::Source;
+use bincode;
+use errors::*;
+use futures::{Async, Future, Poll};
+use futures_cpupool::{CpuFuture, CpuPool};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Storage of payloads, e.g., a local directory or an object store (S3).
+pub trait BlobStore: Send + Sync {
+    /// Stores `data` and returns its key.
+    fn put(&self, data: &[u8]) -> Result<String>;
+
+    /// Fetches the data stored under `key`.
+    fn get(&self, key: &str) -> Result<Vec<u8>>;
+}
+
+/// A `BlobStore` in a local directory, one file per blob named by the
+/// SHA-256 digest of its content, so that distinct payloads never share a
+/// file.
+#[derive(Debug, Clone)]
+pub struct LocalStore {
+    dir: PathBuf,
+}
+
+impl LocalStore {
+    /// Stores blobs in `dir`, creating it if needed.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Result<LocalStore> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(LocalStore { dir })
+    }
+}
+
+impl BlobStore for LocalStore {
+    fn put(&self, data: &[u8]) -> Result<String> {
+        let key: String = Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect();
+        let path = self.dir.join(&key);
+        if !path.exists() {
+            fs::write(path, data)?;
+        }
+        Ok(key)
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>> {
+        if key.contains('/') || key.contains("..") {
+            bail!(ErrorKind::InvalidConfig(format!("bad blob key {}", key)));
+        }
+        Ok(fs::read(self.dir.join(key))?)
+    }
+}
+
+/// What a `Reference` datum carries in place of the payload.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlobRef {
+    /// The key in the store.
+    pub key: String,
+
+    /// The size of the payload (bytes).
+    pub len: usize,
+
+    /// A summary of the payload, e.g., a thumbnail or metadata.
+    pub summary: Vec<u8>,
+}
+
+impl BlobRef {
+    /// Creates the datum referring to this blob for frame `frame_num` at
+    /// `level`.
+    pub fn into_datum(self, level: usize, frame_num: usize) -> Result<AsDatum> {
+        let mem = bincode::serialize(&self, bincode::Infinite)?;
+        Ok(AsDatum::with_type(AsDatumType::Reference(level, frame_num), mem))
+    }
+
+    /// Reads the reference carried by `datum`, if it is a `Reference`.
+    pub fn from_datum(datum: &AsDatum) -> Result<Option<BlobRef>> {
+        match datum.datum_type() {
+            AsDatumType::Reference(..) => Ok(Some(bincode::deserialize(&datum.mem)?)),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Computes the summary streamed in place of a payload.
+pub type Summarize = Arc<dyn Fn(&[u8]) -> Vec<u8> + Send + Sync>;
+
+/// `Offloader` uploads the payloads of the frames at some levels to a store,
+/// and streams references to them instead. Frames at other levels pass
+/// through.
+pub struct Offloader<S> {
+    inner: S,
+    store: Arc<dyn BlobStore>,
+    levels: Vec<usize>,
+    summarize: Summarize,
+    pool: CpuPool,
+    pending: Option<CpuFuture<AsDatum, Error>>,
+}
+
+impl<S: Source> Offloader<S> {
+    /// Wraps `inner`, offloading the frames at `levels` to `store` on `pool`.
+    /// References carry no summary unless set with `with_summary`.
+    pub fn new(inner: S, store: Arc<dyn BlobStore>, levels: Vec<usize>, pool: CpuPool) -> Offloader<S> {
+        Offloader {
+            inner,
+            store,
+            levels,
+            summarize: Arc::new(|_| Vec::new()),
+            pool,
+            pending: None,
+        }
+    }
+
+    /// Summarizes each offloaded payload with `summarize`.
+    pub fn with_summary(mut self, summarize: Summarize) -> Offloader<S> {
+        self.summarize = summarize;
+        self
+    }
+
+    fn upload(&self, frame: AsDatum, level: usize, frame_num: usize) -> CpuFuture<AsDatum, Error> {
+        let store = self.store.clone();
+        let summarize = self.summarize.clone();
+        self.pool.spawn_fn(move || {
+            let blob = BlobRef {
+                key: store.put(&frame.mem)?,
+                len: frame.mem.len(),
+                summary: summarize(&frame.mem),
+            };
+            let mut datum = blob.into_datum(level, frame_num)?;
+            if let Some(a) = frame.annotation() {
+                datum = datum.with_annotation(a);
+            }
+            Ok(datum)
+        })
+    }
+}
+
+impl<S: Source> Adapt for Offloader<S> {
+    fn adapt(&mut self, bandwidth: Bandwidth) {
+        self.inner.adapt(bandwidth)
+    }
+
+    fn dec_degradation(&mut self) {
+        self.inner.dec_degradation()
+    }
+
+    fn set_level(&mut self, level: usize) {
+        self.inner.set_level(level)
+    }
+
+    fn try_set_level(&mut self, level: usize) -> Result<()> {
+        self.inner.try_set_level(level)
+    }
+
+    fn period_in_ms(&self) -> u64 {
+        self.inner.period_in_ms()
+    }
+
+    fn current_level(&self) -> usize {
+        self.inner.current_level()
+    }
+
+    fn simple_profile(&self) -> SimpleProfile {
+        self.inner.simple_profile()
+    }
+
+    fn observe_accuracy(&mut self, level: usize, quality: f64, weight: f64) {
+        self.inner.observe_accuracy(level, quality, weight)
+    }
+
+    fn frame_rate(&self, level: usize) -> Option<f64> {
+        self.inner.frame_rate(level)
+    }
+
+    fn restrict(&mut self, caps: &Capabilities, check: CapabilityCheck) -> Result<Vec<usize>> {
+        self.inner.restrict(caps, check)
+    }
+
+    fn replace_profile(&mut self, csv: &str) -> Result<()> {
+        self.inner.replace_profile(csv)
+    }
+
+    fn stream_info(&self) -> Option<StreamInfo> {
+        self.inner.stream_info()
+    }
+}
+
+impl<S: Source> Source for Offloader<S> {
+    fn poll_frame(&mut self) -> Poll<Option<AsDatum>, Error> {
+        if self.pending.is_none() {
+            let frame = match try_ready!(self.inner.poll_frame()) {
+                Some(f) => f,
+                None => return Ok(Async::Ready(None)),
+            };
+            match frame.datum_type() {
+                AsDatumType::Live(level, frame_num) if self.levels.contains(&level) => {
+                    self.pending = Some(self.upload(frame, level, frame_num));
+                }
+                _ => return Ok(Async::Ready(Some(frame))),
+            }
+        }
+        let datum = match self.pending.as_mut() {
+            Some(p) => try_ready!(p.poll()),
+            None => unreachable!(),
+        };
+        self.pending = None;
+        Ok(Async::Ready(Some(datum)))
+    }
+
+    fn encoder_limit(&self) -> Option<usize> {
+        self.inner.encoder_limit()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn on_hint(&mut self, hint: &Hint) {
+        self.inner.on_hint(hint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use profile::{Profile, Record};
+    use std::env;
+
+    /// Emits a 1000-byte frame per poll at its level.
+    struct Camera {
+        level: usize,
+        frame: usize,
+    }
+
+    impl Adapt for Camera {
+        fn adapt(&mut self, _bandwidth: Bandwidth) {}
+        fn dec_degradation(&mut self) {}
+        fn set_level(&mut self, level: usize) {
+            self.level = level;
+        }
+        fn period_in_ms(&self) -> u64 {
+            10
+        }
+        fn current_level(&self) -> usize {
+            self.level
+        }
+        fn simple_profile(&self) -> SimpleProfile {
+            Profile::_with_vec(vec![
+                Record::new(Bandwidth::from_kbps(1.0), (), 0.0),
+                Record::new(Bandwidth::from_kbps(2.0), (), 0.0),
+            ]).simplify()
+        }
+    }
+
+    impl Source for Camera {
+        fn poll_frame(&mut self) -> Poll<Option<AsDatum>, Error> {
+            self.frame += 1;
+            let mem = (0..1000).map(|i| (i + self.frame) as u8).collect();
+            Ok(Async::Ready(Some(AsDatum::new(self.level, self.frame, mem))))
+        }
+    }
+
+    #[test]
+    fn test_offload_low_levels() {
+        let dir = env::temp_dir().join(format!("awstream-blob-{}", ::std::process::id()));
+        let store = Arc::new(LocalStore::new(&dir).unwrap());
+        let camera = Camera { level: 0, frame: 0 };
+        let mut offloader = Offloader::new(camera, store.clone(), vec![0], CpuPool::new(1))
+            .with_summary(Arc::new(|mem: &[u8]| mem[..4].to_vec()));
+
+        let reference = future::poll_fn(|| offloader.poll_frame()).wait().unwrap().unwrap();
+        assert_eq!(reference.datum_type(), AsDatumType::Reference(0, 1));
+        assert!(reference.len() < 200);
+        let blob = BlobRef::from_datum(&reference).unwrap().unwrap();
+        assert_eq!(blob.len, 1000);
+        assert_eq!(blob.key.len(), 64);
+        assert_eq!(store.put(b"").unwrap(), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(blob.summary, vec![1, 2, 3, 4]);
+        let payload = store.get(&blob.key).unwrap();
+        assert_eq!(payload, (0..1000).map(|i| (i + 1) as u8).collect::<Vec<_>>());
+
+        offloader.set_level(1);
+        let live = future::poll_fn(|| offloader.poll_frame()).wait().unwrap().unwrap();
+        assert_eq!(live.datum_type(), AsDatumType::Live(1, 2));
+        assert_eq!(BlobRef::from_datum(&live).unwrap(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,3 +1,4 @@
+use super::Bandwidth;
 use errors::*;
 use std::sync::{Arc, Mutex};
 use std::vec::Vec;
@@ -10,33 +11,33 @@ pub struct BwMonitor {
 #[derive(Debug)]
 struct Inner {
     sample: usize,
-    rate: f64,
+    rate: Bandwidth,
 }
 
 impl BwMonitor {
     pub fn new() -> BwMonitor {
         let inner = Inner {
             sample: 0,
-            rate: 0.0,
+            rate: Bandwidth::ZERO,
         };
         BwMonitor { inner: Arc::new(Mutex::new(inner)) }
     }
 
     pub fn add(&mut self, sample: usize) -> Result<()> {
         let mut m = self.inner.lock()?;
-        (*m).sample += sample;
+        m.sample += sample;
         Ok(())
     }
 
-    pub fn rate(&self) -> Result<f64> {
+    pub fn rate(&self) -> Result<Bandwidth> {
         let m = self.inner.lock()?;
-        Ok((*m).rate)
+        Ok(m.rate)
     }
 
     pub fn update(&mut self, time_in_ms: usize) -> Result<()> {
         let mut m = self.inner.lock()?;
-        (*m).rate = ((*m).sample as f64) * 8.0 / (time_in_ms as f64);
-        (*m).sample = 0;
+        m.rate = Bandwidth::from_bytes_per_ms(m.sample as f64, time_in_ms as f64);
+        m.sample = 0;
         Ok(())
     }
 }
@@ -63,7 +64,7 @@ impl LatencyMonitor {
 
     pub fn add(&mut self, sample: f64) -> Result<()> {
         let mut m = self.inner.lock()?;
-        (*m).sample.push(sample);
+        m.sample.push(sample);
         Ok(())
     }
 
@@ -74,8 +75,8 @@ impl LatencyMonitor {
 
     pub fn update(&mut self) -> Result<()> {
         let mut m = self.inner.lock()?;
-        (*m).rate = (*m).sample.iter().sum::<f64>() / (*m).sample.len() as f64;
-        (*m).sample.clear();
+        m.rate = m.sample.iter().sum::<f64>() / m.sample.len() as f64;
+        m.sample.clear();
         Ok(())
     }
 }
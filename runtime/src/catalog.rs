@@ -0,0 +1,140 @@
+//! Profiles hosted by the server and handed to clients at handshake.
+//!
+//! A client introduces itself in `Hello` with a `ClientIdentity`. If the
+//! server has a `ProfileCatalog`, it looks up the profile for that identity
+//! and sends it along with `Welcome`, so a fleet is updated by replacing
+//! files on the server. The catalog is a directory:
+//!
+//! ```text
+//! <dir>/clients/<client_id>.csv   (for one client)
+//! <dir>/streams/<stream>.csv      (for every client of a stream type)
+//! ```
+//!
+//! A profile's detached signature (`<name>.csv.sig`, see `signature`), if
+//! any, is sent along.
+//!
+//! Files are read at every handshake; clients pick up a new profile when
+//! they next connect.
+
+use super::signature::signature_path;
+use errors::*;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// Who a client is, as sent in its `Hello`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientIdentity {
+    /// Identifies the device, e.g., its hostname.
+    pub client_id: Option<String>,
+
+    /// The kind of stream it sends, e.g., `traffic-camera`.
+    pub stream: Option<String>,
+
+    /// The group of streams of the client whose frames are time-aligned
+    /// (see `grouping`), e.g., the cameras of a rig.
+    pub group: Option<String>,
+}
+
+impl ClientIdentity {
+    /// Returns true if the client didn't introduce itself.
+    pub fn is_empty(&self) -> bool {
+        self.client_id.is_none() && self.stream.is_none() && self.group.is_none()
+    }
+}
+
+/// A profile as handed over by the server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HostedProfile {
+    /// What the profile is for, e.g., `clients/cam-7` or `streams/traffic`;
+    /// its signature covers it (see `signature`).
+    pub name: String,
+
+    /// The contents of the profile file.
+    pub csv: String,
+
+    /// The signature over `name` and `csv` (`<version> <hex>`), if signed.
+    pub signature: Option<String>,
+}
+
+/// A directory of profiles, by client and by stream type.
+#[derive(Debug, Clone)]
+pub struct ProfileCatalog {
+    dir: PathBuf,
+}
+
+impl ProfileCatalog {
+    /// Serves the profiles under `dir`.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> ProfileCatalog {
+        ProfileCatalog { dir: dir.into() }
+    }
+
+    /// Returns the profile for `identity`: the client's own if any, else the
+    /// one of its stream type.
+    pub fn lookup(&self, identity: &ClientIdentity) -> Result<Option<HostedProfile>> {
+        let candidates = [
+            ("clients", identity.client_id.as_ref()),
+            ("streams", identity.stream.as_ref()),
+        ];
+        for &(kind, name) in &candidates {
+            let name = match name {
+                Some(name) => name,
+                None => continue,
+            };
+            if !is_safe_name(name) {
+                bail!(ErrorKind::InvalidConfig(format!("bad profile name {:?}", name)));
+            }
+            let path = self.dir.join(kind).join(format!("{}.csv", name));
+            match fs::read_to_string(&path) {
+                Ok(csv) => {
+                    let signature = fs::read_to_string(signature_path(&path)).ok();
+                    let name = format!("{}/{}", kind, name);
+                    return Ok(Some(HostedProfile { name, csv, signature }));
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Names come from the network; they must not leave the catalog.
+fn is_safe_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') &&
+        name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_catalog_lookup() {
+        let dir = env::temp_dir().join(format!("awstream-catalog-{}", ::std::process::id()));
+        fs::create_dir_all(dir.join("clients")).unwrap();
+        fs::create_dir_all(dir.join("streams")).unwrap();
+        fs::write(dir.join("clients/cam-7.csv"), "100,1,0.5\n").unwrap();
+        fs::write(dir.join("streams/traffic.csv"), "200,1,0.9\n").unwrap();
+        fs::write(dir.join("streams/traffic.csv.sig"), "3 00ff").unwrap();
+        let catalog = ProfileCatalog::new(&dir);
+
+        let identity = |client_id: Option<&str>, stream: Option<&str>| ClientIdentity {
+            client_id: client_id.map(String::from),
+            stream: stream.map(String::from),
+            group: None,
+        };
+        let own = catalog.lookup(&identity(Some("cam-7"), Some("traffic"))).unwrap();
+        let own = own.map(|p| (p.name, p.csv, p.signature));
+        assert_eq!(own, Some(("clients/cam-7".into(), "100,1,0.5\n".into(), None)));
+        let shared = catalog.lookup(&identity(Some("cam-8"), Some("traffic"))).unwrap();
+        let shared = shared.map(|p| (p.name, p.csv, p.signature));
+        let signature = Some("3 00ff".into());
+        assert_eq!(shared, Some(("streams/traffic".into(), "200,1,0.9\n".into(), signature)));
+        assert_eq!(catalog.lookup(&ClientIdentity::default()).unwrap(), None);
+        assert!(catalog.lookup(&identity(Some("../streams/traffic"), None)).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,144 +2,1526 @@
 //! event loop (`tokio_core::Core`). The loop selects the next available event
 //! and reacts accordingly.
 
-use super::{Adapt, AdaptAction, AsCodec, ReceiverReport};
-use super::adaptation::{Action, Adaptation, Signal};
+use super::{AdaptAction, AsCodec, AsDatum, AsDatumType, Bandwidth, CloseReason, Directive, Hint,
+            QualityReport, ReceiverReport};
+use super::adaptation::{self, Adaptation, Policy, Signal};
+use super::admission::AdmissionRequest;
+use super::bandwidth_feed::BandwidthFeed;
+use super::barrier::Drain;
+use super::blob::{LocalStore, Offloader};
+use super::catalog::{ClientIdentity, HostedProfile};
+use super::codel::CoDelQueue;
+use super::deadline::DeadlineQueue;
+use super::collector::{self, Shipment};
+use super::congestion::LatencyBudget;
 use super::controller::Monitor;
+use super::decision::{SharedClock, SystemClock};
+use super::delta::DeltaEncoder;
+use super::dictionary::Compressor;
+use super::endpoint::{self, Feedback, Welcomed};
+use super::happy_eyeballs::{self, DEFAULT_STAGGER};
+use super::heartbeat::{HeartbeatPolicy, Heartbeats};
+use super::drop_policy::DropPolicy;
+use super::estimator::{Estimator, ExponentialSmooth, Quantile};
+use super::memory::{Component, MemoryBudget};
 use super::errors::*;
+use super::external::ExternalPolicy;
+use super::integrity::Sealer;
+use super::postmortem::{self, Registration};
+use super::prediction::FramePredictor;
 use super::profile::SimpleProfile;
+use super::proxy::{self, ProxyConfig, Target};
+use super::replay::Recorder;
+use super::send_queue;
 use super::setting::Setting;
-use super::socket::{FramedRead, Socket};
-use super::source::TimerSource;
+use super::signature::{ProfileVerifier, ProfileVersions};
+use super::socket::{self, FramedRead, Socket, SocketHooks};
+use super::source::{self, Cancellation, NaturalBursts, PaddingPolicy, Paced, RecentFrames,
+                    Source, Transition, ZeroPadding};
+use super::spool::{Scheduler, Spool};
+use super::stats::{self, ClientStats, StatsLog};
+use super::system::{Limits, SystemMonitor};
+use super::tcp_info::TcpInfoProbe;
+use super::ticker::Ticker;
+use super::uplink::{self, Uplink, UplinkMonitor};
 use super::video::VideoSource;
-use futures::{Future, Sink, Stream};
+use super::warm_start::{LastKnownGood, WarmStart};
+use super::watchdog::{Progress, Watchdog, WatchdogEvent};
+use super::wrr::{Inputs, SubStream, WeightedRoundRobin};
+use futures::{Async, Future, Sink, Stream, future, stream};
 
-use futures::sync::mpsc::UnboundedSender;
+use chrono::Utc;
+use futures::sync::mpsc::{Sender, UnboundedReceiver, UnboundedSender, unbounded};
+use futures::sync::oneshot;
 use futures_cpupool::CpuPool;
-use std::net::SocketAddr;
+use std::collections::VecDeque;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use tokio_core::net::TcpStream;
-use tokio_core::reactor::Core;
+use tokio_core::reactor::{Core, Handle};
 use tokio_io::AsyncRead;
+#[allow(deprecated)]
+use tokio_io::codec::FramedWrite;
+use tokio_io::io::ReadHalf;
 
-const PROBE_EXTRA: f64 = 1.05;
+/// How long `run` browses for a server when `server` is `auto`.
+/// Frames kept for backfill (10 seconds of video).
+const SPOOL_CAPACITY: usize = 300;
 
-fn connect(server: &str, port: u16, core: &mut Core) -> Result<TcpStream> {
-    let handle = core.handle();
-    let ip = server.parse().unwrap();
-    let address = SocketAddr::new(ip, port);
+/// Delivery samples (one per monitor interval) behind a quantile estimate.
+const QUANTILE_WINDOW: usize = 30;
+
+/// How often the client pings the server over the control connection (and
+/// sends heartbeats on the data connection, with `control_reserve`).
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the transport state is sampled (with `tcp-info`).
+const TCP_INFO_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the watchdog checks the pipeline for progress.
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+
+#[cfg(feature = "mdns")]
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+fn server_addresses(server: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    #[cfg(feature = "mdns")]
+    {
+        if server == "auto" {
+            let found = Client::discover(DISCOVERY_TIMEOUT)?;
+            if found.is_empty() {
+                bail!(ErrorKind::Discovery("no server found".into()));
+            }
+            return Ok(found);
+        }
+    }
+    happy_eyeballs::resolve(server, port)
+}
+
+/// Checks that `hosted` is signed and meant for this client, by id or by
+/// stream type; returns its version.
+fn verify_hosted(
+    verifier: &ProfileVerifier,
+    identity: &ClientIdentity,
+    hosted: &HostedProfile,
+) -> Result<u64> {
+    let ours = |kind: &str, name: &Option<String>| {
+        name.as_ref().is_some_and(|n| hosted.name == format!("{}/{}", kind, n))
+    };
+    if !ours("clients", &identity.client_id) && !ours("streams", &identity.stream) {
+        bail!(ErrorKind::BadSignature(format!("{} is not meant for this client", hosted.name)));
+    }
+    let signature = hosted.signature.as_deref();
+    let version = verifier.verify(&hosted.name, hosted.csv.as_bytes(), signature)?;
+    debug!("{} v{} verified", hosted.name, version);
+    Ok(version)
+}
+
+/// Returns true if `server` names a host rather than an address.
+fn is_host_name(server: &str) -> bool {
+    server != "auto" && server.parse::<IpAddr>().is_err()
+}
+
+/// Connects to the first reachable address of `server`, or through `proxy`,
+/// which resolves host names itself. Resolves to the connection and the
+/// address it reached (the proxy's, if the name doesn't resolve here).
+fn connect(
+    server: &str,
+    port: u16,
+    proxy: Option<&ProxyConfig>,
+    stagger: Duration,
+    handle: &Handle,
+) -> Box<dyn Future<Item = (TcpStream, SocketAddr), Error = Error>> {
+    match proxy {
+        Some(proxy) if is_host_name(server) => {
+            let target = Target::Host(server.to_string(), port);
+            let resolved = happy_eyeballs::resolve(server, port).ok().map(|addresses| addresses[0]);
+            Box::new(proxy::connect(proxy, target, handle).and_then(move |tcp| {
+                let address = match resolved {
+                    Some(address) => address,
+                    None => tcp.peer_addr()?,
+                };
+                Ok((tcp, address))
+            }))
+        }
+        Some(proxy) => {
+            let address = match server_addresses(server, port) {
+                Ok(addresses) => addresses[0],
+                Err(e) => return Box::new(future::err(e)),
+            };
+            Box::new(proxy::connect(proxy, address.into(), handle).map(move |tcp| (tcp, address)))
+        }
+        None => match server_addresses(server, port) {
+            Ok(addresses) => Box::new(happy_eyeballs::connect(addresses, stagger, handle)),
+            Err(e) => Box::new(future::err(e)),
+        },
+    }
+}
+
+/// A thread-safe handle to query the level of a running client and to
+/// override adaptation, e.g., for a "max quality" burst requested by a user.
+#[derive(Clone)]
+pub struct LevelControl {
+    inner: Arc<Mutex<LevelState>>,
+    clock: SharedClock,
+}
+
+#[derive(Default)]
+struct LevelState {
+    current: Option<usize>,
+    forced: Option<(usize, u64)>,
+    cap: Option<Bandwidth>,
+    app_cap: Option<Bandwidth>,
+    admitted: Option<Bandwidth>,
+    wake: Option<UnboundedSender<()>>,
+}
+
+impl Default for LevelControl {
+    fn default() -> LevelControl {
+        LevelControl::with_clock(Arc::new(SystemClock::new()))
+    }
+}
+
+impl LevelControl {
+    /// Creates a control whose forced levels expire on `clock`.
+    pub fn with_clock(clock: SharedClock) -> LevelControl {
+        LevelControl {
+            inner: Arc::default(),
+            clock,
+        }
+    }
+
+    /// The level the controller last chose, once streaming.
+    pub fn current_level(&self) -> Option<usize> {
+        self.inner.lock().expect("level control poisoned").current
+    }
+
+    /// Holds `level` for `duration`, ignoring adaptation. Ceilings (encoder
+    /// or system limits) still apply.
+    pub fn force_level(&self, level: usize, duration: Duration) {
+        let mut state = self.inner.lock().expect("level control poisoned");
+        let ms = duration.as_millis().min(u64::MAX as u128) as u64;
+        state.forced = Some((level, self.clock.now_ms().saturating_add(ms)));
+        state.wake();
+    }
+
+    /// Ends a `force_level` early and hands the level back to adaptation.
+    pub fn resume_auto(&self) {
+        let mut state = self.inner.lock().expect("level control poisoned");
+        state.forced = None;
+        state.wake();
+    }
+
+    /// Restricts the levels to those fitting within `cap` regardless of the
+    /// estimated bandwidth, e.g., when the user turns on a data saver, or
+    /// lifts the restriction with `None`. An operator's cap still applies.
+    pub fn set_bandwidth_cap(&self, cap: Option<Bandwidth>) {
+        let mut state = self.inner.lock().expect("level control poisoned");
+        state.app_cap = cap;
+        state.wake();
+    }
+
+    /// The forced level, if any and not expired.
+    fn forced(&self) -> Option<usize> {
+        let mut state = self.inner.lock().expect("level control poisoned");
+        match state.forced {
+            Some((level, until)) if self.clock.now_ms() < until => Some(level),
+            Some(_) => {
+                info!("forced level expired, resuming adaptation");
+                state.forced = None;
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// The tightest of the caps of the operator, the application and the
+    /// server's admission, if any.
+    fn cap(&self) -> Option<Bandwidth> {
+        let state = self.inner.lock().expect("level control poisoned");
+        [state.cap, state.app_cap, state.admitted]
+            .iter()
+            .flatten()
+            .fold(None, |tightest: Option<Bandwidth>, &c| Some(tightest.map_or(c, |t| t.min(c))))
+    }
+
+    fn set_cap(&self, cap: Option<Bandwidth>) {
+        self.inner.lock().expect("level control poisoned").cap = cap;
+    }
+
+    /// Holds the session within the rate the server granted it, which other
+    /// caps can't lift.
+    fn set_admitted(&self, grant: Option<Bandwidth>) {
+        self.inner.lock().expect("level control poisoned").admitted = grant;
+    }
+
+    fn set_current(&self, level: usize) {
+        self.inner.lock().expect("level control poisoned").current = Some(level);
+    }
+
+    /// Returns the wake-ups of a new run, sent whenever the override changes.
+    fn attach(&self) -> UnboundedReceiver<()> {
+        let (tx, rx) = unbounded();
+        self.inner.lock().expect("level control poisoned").wake = Some(tx);
+        rx
+    }
+}
+
+impl LevelState {
+    fn wake(&mut self) {
+        if let Some(ref tx) = self.wake {
+            // the run may have ended
+            let _ = tx.unbounded_send(());
+        }
+    }
+}
 
-    let work = TcpStream::connect(&address, &handle);
-    let tcp = core.run(work)?;
-    // tcp.set_nodelay(true).expect("failed to set TCP NODELAY");
-    // tcp.set_send_buffer_size(64 * 1_024).expect("failed to set send buffer");
-    Ok(tcp)
+/// What the control plane reacts to.
+enum Input {
+    Signal(Signal),
+    Report(ReceiverReport),
+    Quality(QualityReport),
+    Hint(Hint),
+    Directive(Directive),
+    Override,
+    Downlink(AsDatum),
+    Closed(CloseReason),
+    PeerClosed,
+    WatchdogCheck,
+    Uplink(Uplink),
+}
+
+/// The session of a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionInfo {
+    /// The resumption token of the session.
+    pub token: u64,
+
+    /// The server address the client connected to, among those its name
+    /// resolved to.
+    pub server: SocketAddr,
+}
+
+/// The client side of the runtime.
+pub struct Client {
+    setting: Arc<Setting>,
+    token: Arc<Mutex<Option<u64>>>,
+    server: Arc<Mutex<Option<SocketAddr>>>,
+    spool: Spool,
+    levels: LevelControl,
+    hooks: Option<Arc<dyn SocketHooks>>,
+    downlink: Option<UnboundedSender<AsDatum>>,
+    watchdog_events: Option<UnboundedSender<WatchdogEvent>>,
+    stats: ClientStats,
+    stats_served: bool,
+    stats_shipment: Option<Shipment>,
+    stats_log: Option<StatsLog>,
+    feed: BandwidthFeed,
+    memory: Option<MemoryBudget>,
+    drop_policy: Arc<Mutex<Box<dyn DropPolicy>>>,
+    sub_streams: Vec<SubStream>,
+    profile_versions: ProfileVersions,
+    _postmortem: Option<Registration>,
+}
+
+impl Client {
+    /// Creates a client from `setting`.
+    pub fn new(setting: Setting) -> Client {
+        Client::with_clock(setting, Arc::new(SystemClock::new()))
+    }
+
+    /// Creates a client from `setting` whose timers (pacing, monitoring,
+    /// probes and pings) and level overrides run on `clock`.
+    pub fn with_clock(setting: Setting, clock: SharedClock) -> Client {
+        let capacity = if setting.backfill_stride.is_some() {
+            SPOOL_CAPACITY
+        } else {
+            0
+        };
+        let stats = ClientStats::new();
+        let postmortem = setting.stats_snapshot.clone().map(|path| {
+            let stats = stats.clone();
+            postmortem::register(move || match stats.write_snapshot(&path) {
+                Ok(true) => {}
+                Ok(false) => warn!("stats busy, no snapshot written"),
+                Err(e) => warn!("failed to write stats snapshot: {}", e),
+            })
+        });
+        let memory = setting.memory_budget_mb.map(|mb| MemoryBudget::new(mb * 1024 * 1024));
+        let spool = match memory {
+            Some(ref budget) => Spool::with_budget(capacity, budget.account(Component::Spool)),
+            None => Spool::new(capacity),
+        };
+        let drop_policy = setting.drop_policy.unwrap_or_default().into_policy();
+        Client {
+            drop_policy: Arc::new(Mutex::new(drop_policy)),
+            setting: Arc::new(setting),
+            token: Arc::new(Mutex::new(None)),
+            server: Arc::new(Mutex::new(None)),
+            spool,
+            levels: LevelControl::with_clock(clock),
+            hooks: None,
+            downlink: None,
+            watchdog_events: None,
+            stats,
+            stats_served: false,
+            stats_shipment: None,
+            stats_log: None,
+            feed: BandwidthFeed::new(),
+            memory,
+            sub_streams: Vec::new(),
+            profile_versions: ProfileVersions::default(),
+            _postmortem: postmortem,
+        }
+    }
+
+    /// The verifier of profiles, if `profile_keys` is set. It remembers the
+    /// versions applied across runs.
+    fn profile_verifier(&self) -> Result<Option<ProfileVerifier>> {
+        match self.setting.profile_keys {
+            Some(ref keys) => Ok(Some(ProfileVerifier::new(keys, self.profile_versions.clone())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Browses the local network for advertised servers during `timeout`.
+    #[cfg(feature = "mdns")]
+    pub fn discover(timeout: Duration) -> Result<Vec<SocketAddr>> {
+        ::discovery::discover(timeout)
+    }
+
+    /// The resumption token of the current session, once the server has
+    /// welcomed the client.
+    pub fn session_token(&self) -> Option<u64> {
+        *self.token.lock().expect("session token poisoned")
+    }
+
+    /// The current session and the server address its connection reached,
+    /// once the server has welcomed the client.
+    pub fn session_info(&self) -> Option<SessionInfo> {
+        let token = self.session_token()?;
+        let server = (*self.server.lock().expect("server address poisoned"))?;
+        Some(SessionInfo { token, server })
+    }
+
+    /// Frames kept for backfill (empty unless `backfill_stride` is set).
+    /// Applications that keep capturing while disconnected can push their
+    /// frames here.
+    pub fn spool(&self) -> Spool {
+        self.spool.clone()
+    }
+
+    /// A handle to query and override the level from other threads.
+    pub fn level_control(&self) -> LevelControl {
+        self.levels.clone()
+    }
+
+    /// The level the controller last chose, once streaming.
+    pub fn current_level(&self) -> Option<usize> {
+        self.levels.current_level()
+    }
+
+    /// Holds `level` for `duration`, ignoring adaptation.
+    pub fn force_level(&self, level: usize, duration: Duration) {
+        self.levels.force_level(level, duration)
+    }
+
+    /// Hands the level back to adaptation.
+    pub fn resume_auto(&self) {
+        self.levels.resume_auto()
+    }
+
+    /// Caps the bandwidth of the selectable levels (see
+    /// `LevelControl::set_bandwidth_cap`).
+    pub fn set_bandwidth_cap(&self, cap: Option<Bandwidth>) {
+        self.levels.set_bandwidth_cap(cap)
+    }
+
+    /// The live statistics of the client (see `stats`).
+    pub fn stats(&self) -> ClientStats {
+        self.stats.clone()
+    }
+
+    /// The bandwidth estimates of the monitor, across runs: subscribe for a
+    /// stream of them, or read the latest (see `bandwidth_feed`).
+    pub fn bandwidth_feed(&self) -> BandwidthFeed {
+        self.feed.clone()
+    }
+
+    /// The memory budget of the client's buffers, if `memory_budget_mb` is
+    /// set, e.g., to read the usage of each component.
+    pub fn memory_budget(&self) -> Option<MemoryBudget> {
+        self.memory.clone()
+    }
+
+    /// Makes room in the send queue with `policy` instead of the one of
+    /// `drop_policy` in the setting.
+    pub fn set_drop_policy<P: DropPolicy + 'static>(&mut self, policy: P) {
+        self.drop_policy = Arc::new(Mutex::new(Box::new(policy)));
+    }
+
+    /// Instruments the data connection of the next runs with `hooks`.
+    pub fn set_socket_hooks(&mut self, hooks: Arc<dyn SocketHooks>) {
+        self.hooks = Some(hooks);
+    }
+
+    /// Delivers the frames the server streams back (see
+    /// `Server::set_downlink`) to `tx` in the next runs.
+    pub fn set_downlink(&mut self, tx: UnboundedSender<AsDatum>) {
+        self.downlink = Some(tx);
+    }
+
+    /// Sends the payloads given to the returned sender alongside the
+    /// source's frames in this and the next runs, as a sub-stream sharing the
+    /// connection by `weight` (see `wrr`). The sender waits while the client
+    /// is behind.
+    pub fn add_sub_stream(&mut self, weight: u32) -> Sender<Vec<u8>> {
+        let (sub, tx) = SubStream::new(self.sub_streams.len() as u32 + 1, weight);
+        self.sub_streams.push(sub);
+        tx
+    }
+
+    /// Reports the pipelines the watchdog finds wedged to `tx` in the next
+    /// runs (see `watchdog`).
+    pub fn set_watchdog_events(&mut self, tx: UnboundedSender<WatchdogEvent>) {
+        self.watchdog_events = Some(tx);
+    }
+
+    /// Streams the video of `source_path` until the connection ends.
+    /// Running again reconnects and resumes the session on the server.
+    pub fn run(&mut self) -> Result<()> {
+        let setting = &self.setting;
+        let verified = match self.profile_verifier()? {
+            Some(verifier) => {
+                let (name, version) = verifier.verify_file(&setting.profile_path)?;
+                Some((verifier, name, version))
+            }
+            None => None,
+        };
+        let (source_path, profile_path) = (setting.source_path.clone(), setting.profile_path.clone());
+        let clock = self.levels.clock.clone();
+        let video_source = move || {
+            let video_source = VideoSource::new(source_path.clone(), profile_path.clone());
+            // the source loaded the profile
+            if let Some((ref verifier, ref name, version)) = verified {
+                verifier.record(name, version)?;
+            }
+            Ok(Paced::with_clock(video_source, clock.clone()))
+        };
+        self.supervise(video_source, Cancellation::new())
+    }
+
+    /// Streams the frames of `source` until it ends (e.g., when `cancel`
+    /// fires) and all have been sent, or until the connection ends.
+    pub fn stream<S: Source + 'static>(&mut self, source: S, cancel: Cancellation) -> Result<()> {
+        self.start_stats()?;
+        run_client(self, source, cancel)
+    }
+
+    /// Like `stream`, over `tcp`, already connected to the server, with the
+    /// whole pipeline (the source, adaptation and the control plane) driven
+    /// on `handle`, e.g., the reactor of an application running its own
+    /// listeners (see `endpoint`). The future resolves as `stream` returns.
+    pub fn stream_on<S: Source + 'static>(
+        &mut self,
+        source: S,
+        cancel: Cancellation,
+        tcp: TcpStream,
+        handle: &Handle,
+    ) -> Box<dyn Future<Item = (), Error = Error>> {
+        let started = self.start_stats().and_then(|_| {
+            let address = tcp.peer_addr()?;
+            start_run(self, source, cancel, tcp, address, handle)
+        });
+        Box::new(future::result(started).flatten())
+    }
+
+    /// Serves, ships and logs the statistics as the setting says, once.
+    fn start_stats(&mut self) -> Result<()> {
+        if let (Some(port), false) = (self.setting.stats_port, self.stats_served) {
+            stats::serve(self.stats.clone(), ("127.0.0.1", port))?;
+            self.stats_served = true;
+        }
+        if let (Some(config), None) = (self.setting.stats_collector.as_ref(), self.stats_shipment.as_ref()) {
+            let identity = ClientIdentity {
+                client_id: self.setting.client_id.clone(),
+                stream: self.setting.stream_type.clone(),
+                group: self.setting.stream_group.clone(),
+            };
+            self.stats_shipment = Some(collector::ship(self.stats.clone(), config.clone(), identity)?);
+        }
+        if let (Some(path), None) = (self.setting.stats_log.as_ref(), self.stats_log.as_ref()) {
+            let rotation = self.setting.stats_log_rotation;
+            self.stats_log = Some(stats::log(self.stats.clone(), path, rotation)?);
+        }
+        Ok(())
+    }
+
+    /// Like `stream`, with the sources `factory` builds: when the watchdog
+    /// finds the pipeline wedged, or the uplink changes to one with another
+    /// profile, a new source is built and the client reconnects (see
+    /// `watchdog` and `uplink`).
+    pub fn supervise<S, F>(&mut self, mut factory: F, cancel: Cancellation) -> Result<()>
+    where
+        S: Source + 'static,
+        F: FnMut() -> Result<S>,
+    {
+        loop {
+            match self.stream(factory()?, cancel.child()) {
+                Err(Error(ErrorKind::Wedged(stage), _)) if !cancel.is_cancelled() => {
+                    warn!("rebuilding the pipeline, the {} was wedged", stage);
+                }
+                Err(Error(ErrorKind::UplinkChanged(uplink), _)) if !cancel.is_cancelled() => {
+                    info!("rebuilding the pipeline with the profile for {}", uplink);
+                }
+                result => return result,
+            }
+        }
+    }
 }
 
 /// Run client
 pub fn run(setting: Setting) -> Result<()> {
-    let pool = CpuPool::new_num_cpus();
+    Client::new(setting).run()
+}
+
+fn run_client<S: Source + 'static>(client: &Client, source: S, cancel: Cancellation) -> Result<()> {
+    let setting = &client.setting;
 
     // Setting up the reactor core
-    let mut core = Core::new().unwrap();
+    let mut core = Core::new()?;
+    let handle = core.handle();
 
-    // Creates the TCP connection (this is synchronous!)
-    let tcp = connect(&setting.server, setting.port, &mut core)?;
-    info!("conected to server: {}:{}", setting.server, setting.port);
+    // Creates the TCP connection
+    let stagger = setting.connect_stagger_ms.map_or(DEFAULT_STAGGER, Duration::from_millis);
+    let connecting = connect(&setting.server, setting.port, setting.proxy.as_ref(), stagger, &handle);
+    let (tcp, address) = core.run(connecting)?;
+    info!("conected to server: {}", address);
+    core.run(start_run(client, source, cancel, tcp, address, &handle)?)
+}
 
-    let video_source = VideoSource::new(setting.source_path, setting.profile_path);
-    let mut profile = video_source.simple_profile();
+/// Sets up a run of `client` streaming `source` over `tcp`, connected to
+/// `address`. The returned future drives the whole pipeline (the source,
+/// adaptation and the control plane) on `handle`, and resolves as
+/// `Client::stream` returns.
+fn start_run<S: Source + 'static>(
+    client: &Client,
+    mut source: S,
+    cancel: Cancellation,
+    tcp: TcpStream,
+    address: SocketAddr,
+    handle: &Handle,
+) -> Result<Box<dyn Future<Item = (), Error = Error>>> {
+    let setting = client.setting.clone();
+    let (token, spool, levels) = (client.token.clone(), client.spool.clone(), client.levels.clone());
+    let downlink = client.downlink.clone();
+    let watchdog_events = client.watchdog_events.clone();
+    let stats = client.stats.clone();
+    let memory = client.memory.clone();
+    let clock = levels.clock.clone();
+    let (drop_policy, sub_streams, feed) = (client.drop_policy.clone(), client.sub_streams.clone(), client.feed.clone());
+    let verifier = client.profile_verifier()?;
+    let pool = CpuPool::new_num_cpus();
+    let handle = handle.clone();
+    *client.server.lock()? = Some(address);
 
-    /////////////////////////////////////////////////////////////////
-    //
-    // Data Plane
-    //
-    /////////////////////////////////////////////////////////////////
+    // stream with what is set for the type of the uplink, if any
+    let uplinks = setting.uplinks.clone().unwrap_or_default();
+    let uplink = setting.uplinks.as_ref().and_then(|_| uplink::detect(address.ip()));
+    let link = uplinks.get(uplink).cloned().unwrap_or_default();
+    if let Some(uplink) = uplink {
+        info!("streaming over {}", uplink);
+    }
+    if let Some(ref path) = link.profile_path {
+        let verified = match verifier {
+            Some(ref verifier) => Some(verifier.verify_file(path)?),
+            None => None,
+        };
+        source.replace_profile(&fs::read_to_string(path)?)?;
+        if let (Some(verifier), Some((name, version))) = (verifier.as_ref(), verified) {
+            verifier.record(&name, version)?;
+        }
+    }
 
-    // 1. Creates source
-    let handle = core.handle();
-    let (src_ctrl, src_data, src_stat) = TimerSource::spawn(video_source, handle);
-
-    // 2. Creates sink (socket)
-    let (tcp_read, tcp_write) = tcp.split();
-    let (socket, out_bytes) = Socket::new(tcp_write);
-
-    // 3. Forward all source data to socket
-    let s = src_data.map_err(|_| Error::from_kind(ErrorKind::SourceData));
-    let socket_work = socket.send_all(s).map(|_| ()).map_err(|_| ());
-
-    let data_plane = pool.spawn(socket_work);
-    core.handle().spawn(data_plane);
-
-    //////////////////////////////////////////////////////////////////
-    //
-    //  Control Plane
-    //
-    //////////////////////////////////////////////////////////////////
-    let mut adaptation = Adaptation::default();
-
-    let remote = FramedRead::new(tcp_read, AsCodec::default())
-        .map(|as_datum| {
-            let errmsg = "failed to parse mem into report";
-            let report = ReceiverReport::from_mem(&as_datum.mem).expect(&errmsg);
-            Signal::RemoteCongest(report.throughput, report.latency)
-        })
-        .map_err(|_| Error::from_kind(ErrorKind::RemotePeer));
-
-    let (src_tx, src_rx) = src_ctrl;
-    let monitor = Monitor::new(src_stat, out_bytes).skip(1);
-    let probing = src_rx.map_err(|_| Error::from_kind(ErrorKind::RemotePeer));
-
-    let control_plane = monitor
-        .select(probing)
-        .select(remote)
-        .for_each(move |signal| {
-            core_adapt(signal, &mut adaptation, &mut profile, src_tx.clone());
+    // Creates the sink (socket) and opens (or resumes) the session, which
+    // carries the profile hosted by the server, if any
+    if setting.coalesce_us.is_some() {
+        tcp.set_nodelay(true)?;
+    }
+    if let Some(probe) = TcpInfoProbe::new(&tcp) {
+        let stats = stats.clone();
+        let sampling = Ticker::new(clock.clone(), TCP_INFO_INTERVAL).for_each(move |_| {
+            if let Some(info) = probe.read() {
+                info!(
+                    "tcp cwnd {}\tsrtt {:.1} ms\trttvar {:.1} ms\tretransmits {}",
+                    info.cwnd,
+                    info.srtt_ms,
+                    info.rttvar_ms,
+                    info.retransmits
+                );
+                stats.set_rtt(info.srtt_ms);
+            }
+            Ok(())
+        });
+        handle.spawn(sampling.map_err(|_| ()));
+    }
+    let (tcp_read, tcp_write) = socket::split(tcp);
+    let format = setting.static_wire_format.unwrap_or_default();
+    format.validate()?;
+    let (mut socket, out_bytes) = Socket::new(tcp_write, format);
+    socket.set_hooks(client.hooks.clone());
+    socket.set_frame_limits(setting.frame_limits.unwrap_or_default());
+    let identity = ClientIdentity {
+        client_id: setting.client_id.clone(),
+        stream: setting.stream_type.clone(),
+        group: setting.stream_group.clone(),
+    };
+    // rather than climbing from the bottom again, start at the level the
+    // last run held steadily
+    let rates = source.simple_profile().rates().to_vec();
+    let record = setting.warm_start_path.as_ref().and_then(LastKnownGood::load);
+    if let Some(level) = record.and_then(|r| r.start_level(&rates)) {
+        info!("starting at level {}, the last known good", level);
+        source.set_level(level);
+    }
+    // the server admits the session at the level the source starts at, or
+    // caps it to what its ingest capacity leaves
+    let request = AdmissionRequest {
+        level: source.current_level(),
+        rates,
+    };
+    let resumed = *token.lock()?;
+    let handshake = endpoint::handshake(socket, tcp_read, resumed, &identity, Some(&request), format);
+    let run = handshake.and_then(move |welcomed| -> Result<Box<dyn Future<Item = (), Error = Error>>> {
+        let Welcomed {
+            mut socket,
+            mut remote,
+            session,
+            hosted,
+            granted,
+        } = welcomed;
+        *token.lock()? = Some(session);
+        levels.set_admitted(granted.and_then(|level| request.rate(level)));
+        if let Some(ref budget) = memory {
+            remote.set_budget(budget.account(Component::Decode));
+        }
+        if let Some(hosted) = hosted {
+            let verified = match verifier {
+                Some(ref verifier) => verify_hosted(verifier, &identity, &hosted).map(Some),
+                None => Ok(None),
+            };
+            // a version is recorded only once the source took the profile
+            let applied = verified.and_then(|version| {
+                source.replace_profile(&hosted.csv)?;
+                match (verifier.as_ref(), version) {
+                    (Some(verifier), Some(version)) => verifier.record(&hosted.name, version),
+                    _ => Ok(()),
+                }
+            });
+            match applied {
+                Ok(()) => info!("using the profile hosted by the server"),
+                Err(e) => warn!("kept the local profile over the hosted one: {}", e),
+            }
+        }
+        socket.set_coalescing(setting.coalesce_us.map(Duration::from_micros));
+        socket.set_counter_mode(setting.counter_mode.unwrap_or_default());
+
+        // levels the source can't produce would only fail once switched to
+        let check = setting.capability_check.unwrap_or_default();
+        let masked = source.restrict(&source.capabilities(), check)?;
+        if !masked.is_empty() {
+            warn!("masked levels {:?} beyond the source's capabilities", masked);
+        }
+        let mut profile = source.simple_profile();
+        // start within the caps, e.g., the one the server admitted the session at
+        if let Some(level) = profile.set_bandwidth_cap(levels.cap()) {
+            source.set_level(level);
+        }
+        if let Some(level) = profile.set_link_ceiling(link.max_level) {
+            source.set_level(level);
+        }
+        let mut warm_start = setting
+            .warm_start_path
+            .as_ref()
+            .map(|path| WarmStart::new(path, profile.num_levels()));
+
+        /////////////////////////////////////////////////////////////////
+        //
+        // Data Plane
+        //
+        /////////////////////////////////////////////////////////////////
+
+        // 1. Creates source
+        let transition = Transition::from_step_ms(setting.transition_step_ms);
+        let padding: Box<dyn PaddingPolicy> = match setting.padding_frames {
+            Some(n) => Box::new(RecentFrames::new(n)),
+            None => Box::new(ZeroPadding),
+        };
+        let padding: Box<dyn PaddingPolicy> = match setting.burst_probing {
+            Some(true) => Box::new(NaturalBursts::new(padding)),
+            _ => padding,
+        };
+        let (hint_tx, hint_rx) = unbounded();
+        let barriers = setting.barrier.is_some();
+        let frame_rate = setting.frame_rate_enforcement;
+        let (src_ctrl, src_data, src_stat) = match setting.blob_dir {
+            Some(ref dir) => {
+                let store = Arc::new(LocalStore::new(dir.as_str())?);
+                let levels = setting.blob_levels.clone().unwrap_or_default();
+                let offloader = Offloader::new(source, store, levels, pool.clone());
+                let c = cancel.clone();
+                source::spawn(offloader, &handle, transition, c, padding, clock.clone(), hint_rx, barriers, frame_rate)
+            }
+            None => {
+                let c = cancel.clone();
+                source::spawn(source, &handle, transition, c, padding, clock.clone(), hint_rx, barriers, frame_rate)
+            }
+        };
+
+        // 2. Forward all source data to socket, backfilling the previous outage;
+        //    frames the socket can no longer take are spooled
+        let backfill = match setting.backfill_stride {
+            Some(stride) => spool.take(stride),
+            None => VecDeque::new(),
+        };
+        //    within the send queue's capacity and the memory budget, if any
+        let queued = memory.as_ref().map(|budget| budget.account(Component::SendQueue));
+        let capacity = setting.send_queue_kb.map(|kb| kb * 1024);
+        let policy = drop_policy.clone();
+        let (live_tx, mut live_rx) = send_queue::channel(capacity, queued, policy, clock.clone());
+        let (produced, dropped) = (src_stat.clone(), stats.clone());
+        //    with live frames sealed with a digest of their content, delta
+        //    coded, then compressed once a dictionary is trained; what is sent
+        //    counts as produced instead of the frames' original bytes
+        let sealer = match setting.frame_integrity {
+            Some(ref config) => Some(Sealer::new(config)?),
+            None => None,
+        };
+        let mut compressor = setting.compression.and_then(Compressor::new);
+        let mut delta = setting.delta.map(DeltaEncoder::new);
+        //    frames lost after coding make the next ones full
+        let delta_lost = delta.as_ref().map(DeltaEncoder::loss_flag);
+        //    counting the datums the watchdog sees pass
+        let progress = Progress::default();
+        let (generated, sent) = (progress.clone(), progress.clone());
+        //    and the frames the predictor learns from
+        let predictor = setting.frame_prediction.map(|_| Arc::new(Mutex::new(FramePredictor::new())));
+        let (learning, learning_clock) = (predictor.clone(), clock.clone());
+        //    along with the datums of the application's sub-streams
+        let subs = sub_streams
+            .iter()
+            .map(|sub| Box::new(sub.datums(src_stat.clone())) as Box<_>)
+            .collect();
+        let spooler = Inputs::new(src_data, subs).for_each(move |datum| {
+            generated.add_produced(&datum);
+            if let (Some(predictor), AsDatumType::Live(level, _)) = (learning.as_ref(), datum.datum_type()) {
+                if let Ok(mut predictor) = predictor.lock() {
+                    predictor.observe(level, datum.mem.len(), learning_clock.now_ms());
+                }
+            }
+            let before = datum.net_len();
+            let datum = match sealer {
+                Some(ref sealer) => sealer.seal(datum),
+                None => datum,
+            };
+            let datums = match delta {
+                Some(ref mut delta) => delta.encode(datum),
+                None => vec![datum],
+            };
+            let datums = datums
+                .into_iter()
+                .flat_map(|datum| match compressor {
+                    Some(ref mut compressor) => compressor.compress(datum),
+                    None => vec![datum],
+                })
+                .collect::<Vec<_>>();
+            let after = datums.iter().map(AsDatum::net_len).sum::<usize>();
+            if after != before {
+                let recount = |p: usize| Some((p + after).saturating_sub(before));
+                let _ = produced.fetch_update(Ordering::SeqCst, Ordering::SeqCst, recount);
+            }
+            for datum in datums {
+                match live_tx.send(datum) {
+                    Ok(victims) => {
+                        for victim in victims {
+                            debug!("no room to queue {}", victim);
+                            let len = victim.net_len();
+                            let uncount = |p: usize| Some(p.saturating_sub(len));
+                            let _ = produced.fetch_update(Ordering::SeqCst, Ordering::SeqCst, uncount);
+                            dropped.add_drop();
+                            if let Some(ref mut delta) = delta {
+                                delta.resync();
+                            }
+                        }
+                    }
+                    // the next connection has a dictionary (and bases) of its own
+                    Err(ref datum) if datum.datum_type() == AsDatumType::Dictionary => {}
+                    Err(datum) => {
+                        let datum = match compressor {
+                            Some(ref mut compressor) => compressor.restore(datum),
+                            None => datum,
+                        };
+                        match delta {
+                            Some(ref mut delta) => spool.push(delta.restore(datum)),
+                            None => spool.push(datum),
+                        }
+                    }
+                }
+            }
             Ok(())
-        })
-        .map_err(|_| Error::from_kind(ErrorKind::ControlPlane));
+        });
+        handle.spawn(spooler.map_err(|_| ()));
+        // once the source ended (or the server closed the connection),
+        // `send_all` flushes and closes the socket
+        let finished = Arc::new(AtomicBool::new(false));
+        let done = finished.clone();
+        // frames waiting too long are dropped before reaching the socket
+        let (drop_tx, drop_rx) = unbounded();
+        //    sharing the link with the application's sub-streams by weight
+        let wrr = WeightedRoundRobin::new(setting.multiplex.unwrap_or_default());
+        live_rx.multiplex(wrr.with_sub_streams(&sub_streams));
+        //    those that waited in the send queue too long
+        let live = CoDelQueue::new(live_rx, setting.codel, drop_tx.clone(), src_stat.clone());
+        //    heartbeats go ahead of queued frames within their reserve
+        let mut scheduler = Scheduler::new(live, backfill);
+        //    spaced by how stable the link is
+        let heartbeat = match setting.heartbeat {
+            Some(config) => HeartbeatPolicy::new(config),
+            None => HeartbeatPolicy::fixed(PING_INTERVAL),
+        };
+        if let Some(reserve) = setting.control_reserve {
+            let (control_tx, control_rx) = unbounded();
+            let heartbeats = Heartbeats::new(clock.clone(), heartbeat.clone())
+                .map(|_| AsDatum::latency_probe())
+                .map_err(|_| ())
+                .forward(control_tx.sink_map_err(|_| ()));
+            handle.spawn(heartbeats.map(|_| ()));
+            scheduler.set_control(control_rx, reserve);
+        }
+        let queue: Box<dyn Stream<Item = AsDatum, Error = ()> + Send> = match setting.barrier {
+            Some(policy) => {
+                let mut drain = Drain::new(scheduler, policy);
+                if let Some(ref budget) = memory {
+                    drain.set_budget(budget.account(Component::Reorder));
+                }
+                Box::new(drain)
+            }
+            None => Box::new(scheduler),
+        };
+        //    and frames that would reach the socket past the deadline, if bounded
+        let deadline = setting.latency_deadline;
+        //    and once the stream ended, a `Close` telling the server so (dropped
+        //    if the connection is dead already)
+        let farewell = stream::once(AsDatum::close(CloseReason::Finished).map_err(|_| ()));
+        let s = DeadlineQueue::new(queue, deadline, drop_tx, src_stat.clone(), feed.clone())
+            .chain(farewell)
+            .chain(stream::poll_fn(move || {
+                done.store(true, Ordering::SeqCst);
+                Ok(Async::Ready(None))
+            }))
+            .inspect(move |datum| sent.add_sent(datum))
+            .map_err(|_| Error::from_kind(ErrorKind::SourceData));
+        let poison = socket.poison();
+        let socket_work = socket.send_all(s).map(|_| ());
+
+        let data_plane = pool.spawn(socket_work);
+
+        //////////////////////////////////////////////////////////////////
+        //
+        //  Control Plane
+        //
+        //////////////////////////////////////////////////////////////////
+        let mut adaptation: Box<dyn Policy + Send> = match setting.external_policy {
+            Some(ref config) => Box::new(ExternalPolicy::from_config(config)?),
+            None => Box::new(Adaptation::default()),
+        };
 
-    let control_plane = pool.spawn(control_plane);
-    core.run(control_plane)?;
+        // Feedback arrives on the control connection once attached, and on the
+        // data connection otherwise; it goes to the host the data connection
+        // reached (by name through a proxy).
+        let control_host = match setting.proxy {
+            Some(_) if is_host_name(&setting.server) => setting.server.clone(),
+            _ => address.ip().to_string(),
+        };
+        // reached through the reactor, then read along with the data connection
+        let control: Box<dyn Stream<Item = AsDatum, Error = Error> + Send> = match setting.control_port {
+            Some(port) => {
+                let heartbeats = Heartbeats::new(clock.clone(), heartbeat.clone());
+                let opening = open_control(&control_host, port, setting.proxy.as_ref(), session, heartbeats, &handle);
+                let (opened_tx, opened_rx) = oneshot::channel();
+                handle.spawn(opening.then(move |opened| opened_tx.send(opened).map_err(|_| ())));
+                let opened = opened_rx
+                    .map_err(|_| Error::from_kind(ErrorKind::ControlPlane))
+                    .and_then(|opened| opened);
+                Box::new(opened.flatten_stream())
+            }
+            None => Box::new(stream::empty()),
+        };
+        let control = control.filter_map(feedback);
+        // the server closing the data connection ends the run
+        let remote = remote
+            .filter_map(feedback)
+            .chain(stream::once(Ok(Input::PeerClosed)))
+            .select(control)
+            .map_err(|_| Error::from_kind(ErrorKind::RemotePeer));
 
-    Ok(())
+        let mut recorder = match setting.record_path {
+            Some(ref path) => Some(Recorder::create(path)?),
+            None => None,
+        };
+
+        let (src_tx, src_rx) = src_ctrl;
+        let estimator: Box<dyn Estimator> = match setting.throughput_quantile {
+            Some(q) => Box::new(Quantile::new(q, QUANTILE_WINDOW)),
+            None => Box::new(ExponentialSmooth::new(0.5)),
+        };
+        let mut monitor = Monitor::new(src_stat, out_bytes, estimator, clock.clone());
+        monitor.set_stats(stats.clone());
+        monitor.set_feed(feed.clone());
+        monitor.set_heartbeat(heartbeat.clone());
+        if let (Some(predictor), Some(config)) = (predictor, setting.frame_prediction) {
+            monitor.set_predictor(predictor, config.horizon_ms);
+        }
+        let monitor = monitor.skip(1);
+        let probing = src_rx.map_err(|_| Error::from_kind(ErrorKind::RemotePeer));
+        let dropped = stats.clone();
+        let drops = drop_rx
+            .inspect(move |_| {
+                dropped.add_drop();
+                if let Some(ref lost) = delta_lost {
+                    lost.store(true, Ordering::SeqCst);
+                }
+            })
+            .map_err(|_| Error::from_kind(ErrorKind::ControlPlane));
+        let limits = Limits {
+            cpu: setting.cpu_limit,
+            temp_c: setting.thermal_limit_c,
+        };
+        let system = if limits.cpu.is_some() || limits.temp_c.is_some() {
+            Some(SystemMonitor::new(limits, profile.num_levels()))
+        } else {
+            None
+        };
+
+        // memory pressure steps down a level, so that less piles up
+        let pressure = stream::iter_ok::<_, ()>(memory.as_ref().map(MemoryBudget::subscribe))
+            .flatten()
+            .map(|p| Signal::MemoryPressure(p.used))
+            .map_err(|_| Error::from_kind(ErrorKind::ControlPlane));
+
+        let overrides = levels
+            .attach()
+            .map(|_| Input::Override)
+            .map_err(|_| Error::from_kind(ErrorKind::ControlPlane));
+
+        let mut watchdog = setting
+            .watchdog
+            .map(|config| Watchdog::new(config, progress, clock.clone()));
+        let checks = stream::iter_ok::<_, Error>(watchdog.as_ref().map(|_| Ticker::new(clock.clone(), WATCHDOG_INTERVAL)))
+            .flatten()
+            .map(|_| Input::WatchdogCheck);
+        let wedged = Arc::new(Mutex::new(None));
+
+        let relinks = stream::iter_ok::<_, Error>(
+            setting
+                .uplinks
+                .as_ref()
+                .map(|_| UplinkMonitor::new(address.ip(), uplink, clock.clone())),
+        ).flatten()
+            .map(Input::Uplink);
+        let relinked = Arc::new(Mutex::new(None));
+
+        let accuracy_feedback = setting.accuracy_feedback;
+        let mut budget = setting.latency_budget.map(LatencyBudget::new);
+        let peer_closed = Arc::new(AtomicBool::new(false));
+        let close_reason = Arc::new(Mutex::new(None));
+        let on_close = (peer_closed.clone(), cancel.clone(), close_reason.clone());
+        let on_wedged = wedged.clone();
+        let on_relinked = relinked.clone();
+        let control_plane = monitor
+            .select(probing)
+            .select(drops)
+            .select(stream::iter_ok::<_, Error>(system).flatten())
+            .select(pressure)
+            .map(Input::Signal)
+            .select(remote)
+            .select(overrides)
+            .select(checks)
+            .select(relinks)
+            .for_each(move |input| {
+                let forced = levels.forced();
+                // a server slow to handle frames still reports its latency, but
+                // degrading won't help it
+                let (input, adapt) = match input {
+                    Input::Report(report) => {
+                        let signal = Signal::RemoteCongest(report.throughput(), report.latency());
+                        if report.receiver_limited() {
+                            debug!("server is slow to handle frames, not congested: {:?}", report);
+                        }
+                        (Input::Signal(signal), !report.receiver_limited())
+                    }
+                    input => (input, true),
+                };
+                match input {
+                    Input::Signal(signal) => {
+                        if let Signal::RemoteCongest(_, latency) = signal {
+                            heartbeat.observe_delay(latency);
+                            if let Some(ref mut watchdog) = watchdog {
+                                watchdog.observe_congestion();
+                            }
+                        }
+                        let signal = match budget {
+                            Some(ref mut budget) => budgeted(signal, budget, clock.now_ms()),
+                            None => signal,
+                        };
+                        if let Some(ref mut r) = recorder {
+                            r.record(signal)?;
+                        }
+                        if forced.is_none() && adapt {
+                            core_adapt(signal, &mut *adaptation, &mut profile, src_tx.clone());
+                        }
+                    }
+                    // turned into a signal above
+                    Input::Report(_) => unreachable!(),
+                    Input::Quality(report) => {
+                        debug!("analytics quality {:?}", report);
+                        if let Some(weight) = accuracy_feedback {
+                            let (level, quality) = (report.level, report.quality);
+                            block_send(src_tx.clone(), AdaptAction::ObserveAccuracy(level, quality, weight));
+                        }
+                    }
+                    Input::Hint(hint) => {
+                        debug!("receiver hint {:?}", hint);
+                        // the source may have ended
+                        let _ = hint_tx.unbounded_send(hint);
+                    }
+                    Input::Directive(directive) => {
+                        info!("operator directive {:?}", directive);
+                        match directive {
+                            Directive::BandwidthCap(cap) => levels.set_cap(cap),
+                            Directive::ForceLevel(level, ms) => {
+                                levels.force_level(level, Duration::from_millis(ms))
+                            }
+                            Directive::ResumeAuto => levels.resume_auto(),
+                        }
+                    }
+                    // adaptation resumes with the next signal
+                    Input::Override => {}
+                    Input::Downlink(datum) => {
+                        if let Some(ref tx) = downlink {
+                            // the application may have stopped listening
+                            let _ = tx.unbounded_send(datum);
+                        }
+                    }
+                    Input::Closed(reason) => {
+                        warn!("server is closing the connection for {}", reason);
+                        stats.set_close_reason(reason);
+                        *on_close.2.lock()? = Some(reason);
+                    }
+                    // the server closing after us is the normal end
+                    Input::PeerClosed if finished.load(Ordering::SeqCst) => {}
+                    Input::PeerClosed => {
+                        warn!("server closed the connection");
+                        on_close.0.store(true, Ordering::SeqCst);
+                        poison.poison();
+                        on_close.1.cancel();
+                    }
+                    Input::WatchdogCheck => {
+                        if let Some(event) = watchdog.as_mut().and_then(Watchdog::check) {
+                            error!("the {} made no progress for {} ms, restarting", event.stage, event.stalled_ms);
+                            stats.add_restart();
+                            if let Some(ref tx) = watchdog_events {
+                                // the application may have stopped listening
+                                let _ = tx.unbounded_send(event);
+                            }
+                            *on_wedged.lock()? = Some(event.stage);
+                            poison.poison();
+                            on_close.1.cancel();
+                            bail!(ErrorKind::Wedged(event.stage));
+                        }
+                    }
+                    Input::Uplink(uplink) => {
+                        info!("the route to the server moved to {}", uplink);
+                        let next = uplinks.get(Some(uplink)).cloned().unwrap_or_default();
+                        // another profile needs another source
+                        if next.profile_path != link.profile_path {
+                            *on_relinked.lock()? = Some(uplink);
+                            poison.poison();
+                            on_close.1.cancel();
+                            bail!(ErrorKind::UplinkChanged(uplink));
+                        }
+                        if let Some(l) = profile.set_link_ceiling(next.max_level) {
+                            block_send(src_tx.clone(), AdaptAction::ToLevel(l));
+                        }
+                    }
+                }
+                if let Some(l) = profile.set_bandwidth_cap(levels.cap()) {
+                    block_send(src_tx.clone(), AdaptAction::ToLevel(l));
+                }
+                if let Some(level) = forced {
+                    if let Some(l) = profile.set_level(level) {
+                        block_send(src_tx.clone(), AdaptAction::ToLevel(l));
+                    }
+                }
+                levels.set_current(profile.current());
+                stats.set_level(profile.current());
+                if let Some(ref mut warm_start) = warm_start {
+                    let throughput = Bandwidth::from_kbps(stats.snapshot().throughput_kbps);
+                    warm_start.observe(profile.current(), throughput, clock.now_ms());
+                }
+                Ok(())
+            })
+            .map_err(|_| Error::from_kind(ErrorKind::ControlPlane));
+
+        // the run ends with either plane: the data plane once the source
+        // ended and everything was sent
+        let control_plane = pool.spawn(control_plane);
+        let run = control_plane.select(data_plane).map(|_| ()).map_err(|(e, _)| e);
+        let run = run.then(move |result| {
+            // the pool's workers stop once it is dropped, leaving both planes
+            // behind: it lives as long as the run
+            drop(pool);
+            cancel.cancel();
+            if let Some(stage) = *wedged.lock()? {
+                bail!(ErrorKind::Wedged(stage));
+            }
+            if let Some(uplink) = *relinked.lock()? {
+                bail!(ErrorKind::UplinkChanged(uplink));
+            }
+            result?;
+
+            if peer_closed.load(Ordering::SeqCst) {
+                match *close_reason.lock()? {
+                    Some(reason) => bail!(ErrorKind::Closed(reason)),
+                    None => bail!(ErrorKind::PeerClosed),
+                }
+            }
+            Ok(())
+        });
+        Ok(Box::new(run))
+    });
+    Ok(Box::new(run.flatten()))
+}
+
+/// Opens the control connection of `session` and keeps pinging the server
+/// over it, on `handle`. Resolves to the feedback read from the connection.
+fn open_control(
+    host: &str,
+    port: u16,
+    proxy: Option<&ProxyConfig>,
+    session: u64,
+    heartbeats: Heartbeats,
+    handle: &Handle,
+) -> Box<dyn Future<Item = FramedRead<ReadHalf<TcpStream>, AsCodec>, Error = Error>> {
+    let connecting = connect(host, port, proxy, DEFAULT_STAGGER, handle);
+    let handle = handle.clone();
+    let opening = connecting.and_then(move |(tcp, address)| {
+        tcp.set_nodelay(true)?;
+        info!("control connection to {}", address);
+        let (tcp_read, tcp_write) = tcp.split();
+
+        let pings = heartbeats.map(|_| AsDatum::latency_probe());
+        let attach = stream::once(Ok(AsDatum::control(session)));
+        #[allow(deprecated)]
+        let transport_write = FramedWrite::new(tcp_write, AsCodec::default());
+        let pinger = transport_write
+            .send_all(attach.chain(pings))
+            .map(|_| ())
+            .map_err(|e| warn!("control connection closed: {}", e));
+        handle.spawn(pinger);
+
+        Ok(FramedRead::new(tcp_read, AsCodec::default()))
+    });
+    Box::new(opening)
+}
+
+/// Turns a datum from the server into an input of the control plane.
+fn feedback(as_datum: AsDatum) -> Option<Input> {
+    if as_datum.datum_type() == AsDatumType::LatencyProbe {
+        // our ping, echoed over the control connection
+        let rtt = Utc::now().signed_duration_since(as_datum.ts);
+        debug!("control rtt {} us", rtt.num_microseconds().unwrap_or(i64::MAX));
+        return None;
+    }
+    match Feedback::from_datum(as_datum)? {
+        Feedback::Congestion(report) => Some(Input::Report(report)),
+        Feedback::Quality(report) => Some(Input::Quality(report)),
+        Feedback::Hint(hint) => Some(Input::Hint(hint)),
+        Feedback::Directive(directive) => Some(Input::Directive(directive)),
+        Feedback::Downlink(datum) => Some(Input::Downlink(datum)),
+        Feedback::Closed(reason) => Some(Input::Closed(reason)),
+    }
+}
+
+/// Counts the latency `signal` carries, if any, toward `budget`. A violation
+/// takes the place of the signal, so that one report downgrades only once.
+fn budgeted(signal: Signal, budget: &mut LatencyBudget, now_ms: u64) -> Signal {
+    let latency = match signal {
+        Signal::QueueCongest(_, latency) | Signal::RemoteCongest(_, latency) => latency,
+        _ => return signal,
+    };
+    match budget.on_latency(latency, now_ms) {
+        Some(quantile) => Signal::BudgetViolation(quantile),
+        None => signal,
+    }
 }
 
 fn block_send<T>(tx: UnboundedSender<T>, item: T) {
     let errmsg = "failed to control source";
-    tx.send(item).wait().expect(&errmsg);
+    tx.send(item).wait().expect(errmsg);
 }
 
-fn core_adapt(
+fn core_adapt<P: Policy + ?Sized>(
     signal: Signal,
-    adaptation: &mut Adaptation,
+    policy: &mut P,
     profile: &mut SimpleProfile,
     src_ctrl: UnboundedSender<AdaptAction>,
 ) {
-    let action = adaptation.transit(signal, profile.is_max());
-    match action {
-        Action::NoOp => {}
-        Action::AdjustConfig(rate) => {
-            let level = profile.adjust_level(rate);
-            block_send(src_ctrl, AdaptAction::ToRate(rate));
-            info!("adjust config, level: {:?}, rate: {}", level, rate);
-        }
-        Action::AdvanceConfig => {
-            let level = profile.advance_level();
-            block_send(src_ctrl, AdaptAction::DecreaseDegradation);
-            info!("advance config to {:?}", level);
-        }
-        Action::StartProbe => {
-            let delta = profile.next_rate_delta().expect("Must not at max config");
-            let target = PROBE_EXTRA * delta; // probe more space than needed
-            block_send(src_ctrl, AdaptAction::StartProbe(target));
-            info!("start probing for {:?}", target);
-        }
-        Action::IncreaseProbePace => {
-            block_send(src_ctrl, AdaptAction::IncreaseProbePace);
-            info!("increase probe pace");
-        }
-        Action::StopProbe => {
-            block_send(src_ctrl, AdaptAction::StopProbe);
-            info!("stop probe pace");
+    let decision = adaptation::decide(policy, profile, signal);
+    if let Some(command) = decision.command {
+        block_send(src_ctrl, command);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{Adapt, AsCodec, Experiment, Profile, Record};
+    use decision::ManualClock;
+    use bytes::BytesMut;
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener};
+    use std::thread;
+    use tokio_io::codec::Encoder;
+    use toml;
+
+    /// 100 bytes every 10 ms at a single level.
+    struct Ticker(usize);
+
+    impl Adapt for Ticker {
+        fn adapt(&mut self, _bandwidth: Bandwidth) {}
+        fn dec_degradation(&mut self) {}
+        fn set_level(&mut self, _level: usize) {}
+        fn period_in_ms(&self) -> u64 {
+            10
+        }
+        fn current_level(&self) -> usize {
+            0
+        }
+        fn simple_profile(&self) -> SimpleProfile {
+            Profile::_with_vec(vec![Record::new(Bandwidth::from_kbps(80.0), (), 1.0)]).simplify()
+        }
+    }
+
+    impl Experiment for Ticker {
+        fn next_datum(&mut self) -> (usize, usize) {
+            self.0 += 1;
+            (100, self.0)
+        }
+    }
+
+    #[test]
+    fn test_peer_half_close() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut peer, _) = listener.accept().unwrap();
+            let mut welcome = BytesMut::new();
+            AsCodec::default().encode(AsDatum::welcome(7), &mut welcome).unwrap();
+            peer.write_all(&welcome).unwrap();
+            thread::sleep(Duration::from_millis(200));
+            peer.shutdown(Shutdown::Write).unwrap();
+            // the client flushes and closes its side too
+            let mut rest = Vec::new();
+            peer.read_to_end(&mut rest).unwrap();
+            assert!(!rest.is_empty());
+        });
+
+        let setting = format!(
+            "server = \"127.0.0.1\"\nport = {}\nprofile_path = \"\"\n\
+             source_path = \"\"\nstat_path = \"\"\n",
+            port
+        );
+        let mut client = Client::new(toml::from_str(&setting).unwrap());
+        let result = client.stream(Paced::new(Ticker(0)), Cancellation::new());
+        match result {
+            Err(Error(ErrorKind::PeerClosed, _)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(client.session_token(), Some(7));
+        let info = client.session_info().unwrap();
+        assert_eq!((info.token, info.server.port()), (7, port));
+        server.join().unwrap();
+    }
+
+    /// `Ticker`, ending after a number of frames.
+    struct Brief(usize);
+
+    impl Adapt for Brief {
+        fn adapt(&mut self, _bandwidth: Bandwidth) {}
+        fn dec_degradation(&mut self) {}
+        fn set_level(&mut self, _level: usize) {}
+        fn period_in_ms(&self) -> u64 {
+            10
         }
+        fn current_level(&self) -> usize {
+            0
+        }
+        fn simple_profile(&self) -> SimpleProfile {
+            Ticker(0).simple_profile()
+        }
+    }
+
+    impl Source for Brief {
+        fn poll_frame(&mut self) -> ::futures::Poll<Option<AsDatum>, Error> {
+            if self.0 == 0 {
+                return Ok(::futures::Async::Ready(None));
+            }
+            self.0 -= 1;
+            Ok(::futures::Async::Ready(Some(AsDatum::new(0, 10 - self.0, vec![0; 100]))))
+        }
+    }
+
+    #[test]
+    fn test_server_closing_after_the_end() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut peer, _) = listener.accept().unwrap();
+            let mut welcome = BytesMut::new();
+            AsCodec::default().encode(AsDatum::welcome(7), &mut welcome).unwrap();
+            peer.write_all(&welcome).unwrap();
+            // everything the client sent, then the server closes too
+            let mut sent = Vec::new();
+            peer.read_to_end(&mut sent).unwrap();
+        });
+
+        let setting = format!(
+            "server = \"127.0.0.1\"\nport = {}\nprofile_path = \"\"\n\
+             source_path = \"\"\nstat_path = \"\"\n",
+            port
+        );
+        let mut client = Client::new(toml::from_str(&setting).unwrap());
+        // the normal end, not `PeerClosed`
+        client.stream(Brief(10), Cancellation::new()).unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_stream_on_the_applications_reactor() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut peer, _) = listener.accept().unwrap();
+            let mut welcome = BytesMut::new();
+            AsCodec::default().encode(AsDatum::welcome(7), &mut welcome).unwrap();
+            peer.write_all(&welcome).unwrap();
+            let mut sent = Vec::new();
+            peer.read_to_end(&mut sent).unwrap();
+            assert!(!sent.is_empty());
+        });
+
+        let setting = format!(
+            "server = \"127.0.0.1\"\nport = {}\nprofile_path = \"\"\n\
+             source_path = \"\"\nstat_path = \"\"\n",
+            port
+        );
+        let mut client = Client::new(toml::from_str(&setting).unwrap());
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        // the application's own listener keeps running on the same reactor
+        let own = ::tokio_core::net::TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        handle.spawn(own.incoming().for_each(|_| Ok(())).map_err(|_| ()));
+        let address = SocketAddr::from(([127, 0, 0, 1], port));
+        let tcp = core.run(TcpStream::connect(&address, &handle)).unwrap();
+        let run = client.stream_on(Brief(10), Cancellation::new(), tcp, &handle);
+        core.run(run).unwrap();
+        assert_eq!(client.session_info().map(|info| info.token), Some(7));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_level_control_override() {
+        let levels = LevelControl::default();
+        let wake = levels.attach();
+        assert_eq!(levels.current_level(), None);
+
+        levels.force_level(3, Duration::from_secs(60));
+        assert_eq!(levels.forced(), Some(3));
+        levels.resume_auto();
+        assert_eq!(levels.forced(), None);
+
+        levels.force_level(3, Duration::from_secs(0));
+        assert_eq!(levels.forced(), None);
+        // however long, e.g., `duration_ms` from the admin endpoint
+        levels.force_level(3, Duration::from_millis(u64::MAX));
+        assert_eq!(levels.forced(), Some(3));
+        levels.resume_auto();
+
+        // overrides expire on the control's clock
+        let clock = ManualClock::new(0);
+        let timed = LevelControl::with_clock(Arc::new(clock.clone()));
+        timed.force_level(2, Duration::from_secs(10));
+        clock.advance(9999);
+        assert_eq!(timed.forced(), Some(2));
+        clock.advance(1);
+        assert_eq!(timed.forced(), None);
+
+        drop(levels);
+        assert_eq!(wake.wait().count(), 5);
+    }
+
+    #[test]
+    fn test_bandwidth_cap() {
+        let levels = LevelControl::default();
+        let wake = levels.attach();
+        levels.set_bandwidth_cap(Some(Bandwidth::from_kbps(500.0)));
+        assert_eq!(levels.cap(), Some(Bandwidth::from_kbps(500.0)));
+
+        // the tighter of the operator's and the application's caps holds
+        levels.set_cap(Some(Bandwidth::from_kbps(300.0)));
+        assert_eq!(levels.cap(), Some(Bandwidth::from_kbps(300.0)));
+        levels.set_cap(Some(Bandwidth::from_kbps(800.0)));
+        assert_eq!(levels.cap(), Some(Bandwidth::from_kbps(500.0)));
+        levels.set_bandwidth_cap(None);
+        assert_eq!(levels.cap(), Some(Bandwidth::from_kbps(800.0)));
+
+        // the grant of the server's admission holds whatever the other caps
+        levels.set_admitted(Some(Bandwidth::from_kbps(600.0)));
+        levels.set_cap(None);
+        assert_eq!(levels.cap(), Some(Bandwidth::from_kbps(600.0)));
+        let records = [300.0, 500.0, 900.0].iter().map(|&r| Record::new(Bandwidth::from_kbps(r), (), 1.0));
+        let mut profile = Profile::_with_vec(records.collect()).simplify();
+        profile.set_level(2);
+        assert_eq!(profile.set_bandwidth_cap(levels.cap()), Some(1));
+        // however much bandwidth there is
+        assert_eq!(profile.adjust_level(Bandwidth::from_kbps(5000.0)), None);
+        assert_eq!(profile.current(), 1);
+
+        drop(levels);
+        assert_eq!(wake.wait().count(), 2);
+    }
+
+    #[test]
+    fn test_budget_counts_every_latency_once() {
+        use congestion::BudgetConfig;
+
+        let mut budget = LatencyBudget::new(BudgetConfig {
+            budget_ms: 200.0,
+            quantile: 0.5,
+            window_ms: 1000,
+        });
+        let rate = Bandwidth::from_kbps(500.0);
+        // the local queue's latency counts as well as the receiver's
+        let queued = Signal::QueueCongest(rate, 400.0);
+        assert_eq!(budgeted(queued, &mut budget, 0), queued);
+        assert_eq!(budgeted(Signal::QueueEmpty, &mut budget, 500), Signal::QueueEmpty);
+        // a violation replaces the report, rather than downgrading twice
+        let report = Signal::RemoteCongest(rate, 300.0);
+        assert_eq!(budgeted(report, &mut budget, 1000), Signal::BudgetViolation(400.0));
+        assert_eq!(budgeted(report, &mut budget, 1100), report);
     }
 }
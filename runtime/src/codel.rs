@@ -0,0 +1,286 @@
+//! Bounded queueing delay on the send path (CoDel-style).
+//!
+//! Adaptation keeps the queue short only as long as the bandwidth estimate
+//! is right. `CoDelQueue` bounds the delay regardless: it watches how long
+//! live frames waited in the send queue, and once they have waited longer
+//! than a target for a whole interval, drops frames from the head of the
+//! queue (more often the longer it lasts) and signals congestion to the
+//! controller.
+
+use super::{AsDatum, AsDatumType, Bandwidth};
+use super::adaptation::Signal;
+use super::decision::SharedClock;
+use super::send_queue::QueueReceiver;
+use futures::{Async, Poll, Stream};
+use futures::sync::mpsc::UnboundedSender;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// When to drop frames.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct CoDelConfig {
+    /// Acceptable queueing delay of a frame (ms).
+    pub target_ms: u64,
+
+    /// How long the delay may stay above the target before dropping (ms).
+    pub interval_ms: u64,
+}
+
+impl Default for CoDelConfig {
+    fn default() -> Self {
+        CoDelConfig {
+            target_ms: 100,
+            interval_ms: 1000,
+        }
+    }
+}
+
+/// The drop decision, fed with the delay of each frame leaving the queue.
+#[derive(Debug, Clone)]
+pub struct CoDel {
+    config: CoDelConfig,
+    first_above: Option<u64>,
+    dropping: bool,
+    count: u32,
+    drop_next: u64,
+}
+
+impl CoDel {
+    /// Creates the state of an empty queue.
+    pub fn new(config: CoDelConfig) -> CoDel {
+        CoDel {
+            config,
+            first_above: None,
+            dropping: false,
+            count: 0,
+            drop_next: 0,
+        }
+    }
+
+    /// Whether the frame leaving the queue at `now_ms` after waiting
+    /// `sojourn_ms` should be dropped.
+    pub fn should_drop(&mut self, now_ms: u64, sojourn_ms: u64) -> bool {
+        if sojourn_ms < self.config.target_ms {
+            self.first_above = None;
+            self.dropping = false;
+            self.count = 0;
+            return false;
+        }
+        match self.first_above {
+            None => {
+                self.first_above = Some(now_ms + self.config.interval_ms);
+                return false;
+            }
+            Some(t) if now_ms < t => return false,
+            Some(_) => {}
+        }
+        if self.dropping && now_ms < self.drop_next {
+            return false;
+        }
+        self.dropping = true;
+        self.count += 1;
+        // drops get closer as 1/sqrt(count), as in CoDel
+        let gap = self.config.interval_ms as f64 / f64::from(self.count).sqrt();
+        self.drop_next = now_ms + gap as u64;
+        true
+    }
+}
+
+/// The shortest window the drain rate is measured over (ms), so that the
+/// first datums of a window do not inflate it.
+const MIN_WINDOW_MS: u64 = 100;
+
+/// Applies `CoDel` to the live frames leaving a send queue; other datums
+/// pass through.
+pub struct CoDelQueue {
+    inner: QueueReceiver,
+    codel: Option<CoDel>,
+    interval_ms: u64,
+    clock: SharedClock,
+    signals: UnboundedSender<Signal>,
+    produced: Arc<AtomicUsize>,
+    window_start: u64,
+    window_bytes: usize,
+}
+
+impl CoDelQueue {
+    /// Wraps `inner`, timing frames on its clock from when they were queued
+    /// and reporting drops to `signals`; without `config`, all datums pass
+    /// through. The bytes of dropped frames are taken back from `produced`,
+    /// so that the monitor does not count them as queued.
+    pub fn new(
+        inner: QueueReceiver,
+        config: Option<CoDelConfig>,
+        signals: UnboundedSender<Signal>,
+        produced: Arc<AtomicUsize>,
+    ) -> CoDelQueue {
+        CoDelQueue {
+            clock: inner.clock(),
+            inner,
+            codel: config.map(CoDel::new),
+            interval_ms: config.unwrap_or_default().interval_ms,
+            signals,
+            produced,
+            window_start: 0,
+            window_bytes: 0,
+        }
+    }
+
+    /// The rate at which datums left the queue in the current window.
+    fn drain_rate(&mut self, now_ms: u64, bytes: usize) -> Bandwidth {
+        if now_ms >= self.window_start + self.interval_ms {
+            self.window_start = now_ms;
+            self.window_bytes = 0;
+        }
+        self.window_bytes += bytes;
+        let elapsed = (now_ms - self.window_start).max(MIN_WINDOW_MS);
+        Bandwidth::from_bytes_per_ms(self.window_bytes as f64, elapsed as f64)
+    }
+
+    fn drop_frame(&mut self, datum: &AsDatum, rate: Bandwidth, sojourn_ms: u64) {
+        let len = datum.net_len();
+        warn!("dropping {} after {} ms in queue", datum, sojourn_ms);
+        // the monitor may have collected the bytes already; then they stay
+        let _ = self.produced.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |p| {
+            Some(p.saturating_sub(len))
+        });
+        let _ = self.signals.unbounded_send(Signal::QueueCongest(rate, sojourn_ms as f64));
+    }
+}
+
+impl Stream for CoDelQueue {
+    type Item = AsDatum;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<AsDatum>, ()> {
+        loop {
+            let (datum, since) = match self.inner.poll_timed() {
+                Async::Ready(Some(timed)) => timed,
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+            if self.codel.is_none() {
+                return Ok(Async::Ready(Some(datum)));
+            }
+            if let AsDatumType::Live(..) = datum.datum_type() {
+                let now_ms = self.clock.now_ms();
+                let sojourn_ms = now_ms.saturating_sub(since);
+                let rate = self.drain_rate(now_ms, datum.net_len());
+                let drop = self.codel
+                    .as_mut()
+                    .is_some_and(|c| c.should_drop(now_ms, sojourn_ms));
+                if drop {
+                    self.drop_frame(&datum, rate, sojourn_ms);
+                    continue;
+                }
+            }
+            return Ok(Async::Ready(Some(datum)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use decision::ManualClock;
+    use drop_policy::DropPolicyKind;
+    use futures::{executor, Future};
+    use futures::sync::mpsc::{unbounded, UnboundedReceiver};
+    use send_queue;
+    use std::sync::Mutex;
+
+    fn queue(
+        clock: &ManualClock,
+    ) -> (send_queue::QueueSender, CoDelQueue, UnboundedReceiver<Signal>) {
+        let policy = Arc::new(Mutex::new(DropPolicyKind::TailDrop.into_policy()));
+        let (tx, rx) = send_queue::channel(None, None, policy, Arc::new(clock.clone()));
+        let config = CoDelConfig {
+            target_ms: 100,
+            interval_ms: 1000,
+        };
+        let (signals, drops) = unbounded();
+        let produced = Arc::new(AtomicUsize::new(0));
+        (tx, CoDelQueue::new(rx, Some(config), signals, produced), drops)
+    }
+
+    /// A frame captured long before it was queued.
+    fn stale_frame(frame_num: usize) -> AsDatum {
+        let mut datum = AsDatum::new(0, frame_num, vec![0; 1000]);
+        datum.ts -= Duration::seconds(10);
+        datum
+    }
+
+    #[test]
+    fn test_codel_queue_times_from_enqueue() {
+        let clock = ManualClock::new(0);
+        let (tx, codel, drops) = queue(&clock);
+        let mut codel = executor::spawn(codel);
+
+        // however old, a frame that did not wait is not late
+        for i in 0..150 {
+            tx.send(stale_frame(i)).unwrap();
+            clock.advance(10);
+            let datum = codel.wait_stream().unwrap().unwrap();
+            assert_eq!(datum.datum_type(), AsDatumType::Live(0, i));
+        }
+
+        // a standing queue is dropped from
+        let mut next = 150;
+        for _ in 0..10 {
+            tx.send(stale_frame(next)).unwrap();
+            tx.send(stale_frame(next + 1)).unwrap();
+            next += 2;
+            clock.advance(500);
+            codel.wait_stream().unwrap().unwrap();
+        }
+        drop(codel);
+        let drops = drops.collect().wait().unwrap();
+        assert!(!drops.is_empty());
+        for signal in drops {
+            match signal {
+                Signal::QueueCongest(_, sojourn_ms) => assert!(sojourn_ms >= 500.0),
+                s => panic!("unexpected {:?}", s),
+            }
+        }
+    }
+
+    #[test]
+    fn test_drain_rate_has_a_minimum_window() {
+        let clock = ManualClock::new(0);
+        let (_tx, mut codel, _) = queue(&clock);
+        // right after the window starts, one frame is not a burst of bandwidth
+        let rate = codel.drain_rate(5000, 1000);
+        assert_eq!(rate, Bandwidth::from_bytes_per_ms(1000.0, MIN_WINDOW_MS as f64));
+        let rate = codel.drain_rate(5000 + 2 * MIN_WINDOW_MS, 1000);
+        assert_eq!(rate, Bandwidth::from_bytes_per_ms(2000.0, 2.0 * MIN_WINDOW_MS as f64));
+    }
+
+    #[test]
+    fn test_codel_drops_after_interval() {
+        let config = CoDelConfig {
+            target_ms: 100,
+            interval_ms: 1000,
+        };
+        let mut codel = CoDel::new(config);
+        // a short spike is tolerated
+        assert!(!codel.should_drop(0, 500));
+        assert!(!codel.should_drop(900, 500));
+        assert!(!codel.should_drop(950, 50));
+        assert!(!codel.should_drop(1500, 500));
+
+        // a standing queue is not
+        let drops = (0..100)
+            .map(|i| 1500 + 33 * i)
+            .filter(|&t| codel.should_drop(t, 500))
+            .collect::<Vec<_>>();
+        assert_eq!(drops[0], 2500 + 33 - 1000 % 33);
+        assert!(drops.len() >= 3);
+        // and drops get more frequent
+        let gaps = drops.windows(2).map(|w| w[1] - w[0]).collect::<Vec<_>>();
+        assert!(gaps.windows(2).all(|g| g[1] <= g[0]), "{:?}", gaps);
+
+        assert!(!codel.should_drop(5000, 10));
+    }
+}
@@ -0,0 +1,261 @@
+//! Statistics of a fleet of clients, shipped to a collector.
+//!
+//! `stats_port` serves a client's statistics to viewers on the device itself;
+//! aggregating how a fleet adapts would take a metrics agent on each device.
+//! With `stats_collector` set, the client opens a secondary, low-rate stream
+//! to a collector instead, over the same protocol as its frames:
+//!
+//! ```text
+//! Hello (the client's identity), then a Stats datum every interval_ms
+//! ```
+//!
+//! The stream is independent of the data connection: it survives outages,
+//! reconnecting every interval until the collector is back, and snapshots
+//! missed in between are not resent. A `Collector` receives the statistics of
+//! every client streaming to it.
+
+use super::{AsCodec, AsDatum, AsDatumType, WireFormat};
+use bytes::BytesMut;
+use catalog::ClientIdentity;
+use errors::*;
+use stats::{ClientStats, StatsSnapshot};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+use tokio_io::codec::{Decoder, Encoder};
+
+/// Where and how often a client ships its statistics.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CollectorConfig {
+    /// The collector, as `host:port`.
+    pub address: String,
+
+    /// How often (ms) a snapshot is sent.
+    #[serde(default = "default_interval_ms")]
+    pub interval_ms: u64,
+}
+
+fn default_interval_ms() -> u64 {
+    10_000
+}
+
+/// Stops shipping statistics when dropped.
+pub struct Shipment {
+    stopped: Arc<AtomicBool>,
+}
+
+impl Drop for Shipment {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Ships the snapshots of `stats` to the collector of `config`, introducing
+/// the client as `identity`, from a background thread.
+pub fn ship(stats: ClientStats, config: CollectorConfig, identity: ClientIdentity) -> Result<Shipment> {
+    if config.interval_ms == 0 {
+        bail!(ErrorKind::InvalidConfig("the stats collector needs an interval_ms above 0".into()));
+    }
+    let interval = Duration::from_millis(config.interval_ms);
+    info!("shipping stats to {} every {:?}", config.address, interval);
+    let stopped = Arc::new(AtomicBool::new(false));
+    let stop = stopped.clone();
+    thread::spawn(move || {
+        while !stop.load(Ordering::SeqCst) {
+            match send_all(&stats, &config.address, &identity, interval, &stop) {
+                Ok(()) => {}
+                Err(e) => debug!("stats collector {} unreachable: {}", config.address, e),
+            }
+            thread::sleep(interval);
+        }
+    });
+    Ok(Shipment { stopped })
+}
+
+/// Sends snapshots to `address` until the connection fails or `stop` is set.
+fn send_all(
+    stats: &ClientStats,
+    address: &str,
+    identity: &ClientIdentity,
+    interval: Duration,
+    stop: &AtomicBool,
+) -> Result<()> {
+    let addr = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| Error::from_kind(ErrorKind::InvalidConfig(format!("no address for {}", address))))?;
+    let mut stream = TcpStream::connect_timeout(&addr, interval)?;
+    stream.set_nodelay(true)?;
+    let mut codec = AsCodec::new(WireFormat::default());
+    let mut buf = BytesMut::new();
+    codec.encode(AsDatum::hello_as(None, identity)?, &mut buf)?;
+    while !stop.load(Ordering::SeqCst) {
+        codec.encode(AsDatum::stats(&stats.snapshot())?, &mut buf)?;
+        stream.write_all(&buf)?;
+        buf.clear();
+        thread::sleep(interval);
+    }
+    Ok(())
+}
+
+/// A client's statistics, as received by a collector.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollectedStats {
+    /// The client's address.
+    pub peer: SocketAddr,
+
+    /// Who the client said it is.
+    pub identity: ClientIdentity,
+
+    /// The statistics.
+    pub snapshot: StatsSnapshot,
+}
+
+/// Receives the statistics clients ship, from a background thread (and one
+/// per client). Iterating blocks until the next snapshot.
+pub struct Collector {
+    local: SocketAddr,
+    rx: Receiver<CollectedStats>,
+}
+
+impl Collector {
+    /// Listens for clients on `addr`.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Collector> {
+        let listener = TcpListener::bind(addr)?;
+        let local = listener.local_addr()?;
+        info!("collecting stats on {}", local);
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                match client {
+                    Ok(client) => {
+                        let tx = tx.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = receive(client, &tx) {
+                                debug!("stats stream ended: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => warn!("failed to accept stats stream: {}", e),
+                }
+            }
+        });
+        Ok(Collector { local, rx })
+    }
+
+    /// The address listened on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local
+    }
+}
+
+impl Iterator for Collector {
+    type Item = CollectedStats;
+
+    fn next(&mut self) -> Option<CollectedStats> {
+        self.rx.recv().ok()
+    }
+}
+
+/// Forwards the snapshots of `client` until it leaves.
+fn receive(mut client: TcpStream, tx: &Sender<CollectedStats>) -> Result<()> {
+    let peer = client.peer_addr()?;
+    let mut codec = AsCodec::new(WireFormat::default());
+    let mut buf = BytesMut::new();
+    let mut chunk = [0; 4096];
+    let mut identity = None;
+    loop {
+        while let Some(datum) = codec.decode(&mut buf)? {
+            match (datum.datum_type(), identity.clone()) {
+                (AsDatumType::Hello(_), _) => identity = Some(datum.client_identity()?),
+                (AsDatumType::Stats, Some(identity)) => {
+                    if let Some(snapshot) = datum.stats_snapshot()? {
+                        let stats = CollectedStats {
+                            peer,
+                            identity,
+                            snapshot,
+                        };
+                        if tx.send(stats).is_err() {
+                            // the collector was dropped
+                            return Ok(());
+                        }
+                    }
+                }
+                (t, _) => bail!(ErrorKind::InvalidConfig(format!("unexpected {:?} from {}", t, peer))),
+            }
+        }
+        let n = client.read(&mut chunk)?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ships_stats_to_collector() {
+        let mut collector = Collector::bind("127.0.0.1:0").unwrap();
+        let stats = ClientStats::new();
+        stats.set_level(3);
+        stats.add_drop();
+        let config = CollectorConfig {
+            address: collector.local_addr().to_string(),
+            interval_ms: 50,
+        };
+        let identity = ClientIdentity {
+            client_id: Some("camera-7".into()),
+            ..ClientIdentity::default()
+        };
+        let shipment = ship(stats.clone(), config, identity.clone()).unwrap();
+
+        let first = collector.next().unwrap();
+        assert_eq!(first.identity, identity);
+        assert_eq!((first.snapshot.level, first.snapshot.drops), (Some(3), 1));
+        stats.set_level(1);
+        let later = collector.find(|s| s.snapshot.level == Some(1)).unwrap();
+        assert!(later.snapshot.time_ms >= first.snapshot.time_ms);
+        assert_eq!(later.peer, first.peer);
+        drop(shipment);
+    }
+
+    #[test]
+    fn test_stops_shipping_when_dropped() {
+        let collector = Collector::bind("127.0.0.1:0").unwrap();
+        let config = CollectorConfig {
+            address: collector.local_addr().to_string(),
+            interval_ms: 20,
+        };
+        let shipment = ship(ClientStats::new(), config, ClientIdentity::default()).unwrap();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for stats in collector {
+                if tx.send(stats).is_err() {
+                    return;
+                }
+            }
+        });
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        drop(shipment);
+        // at most the snapshot in flight arrives after the drop
+        thread::sleep(Duration::from_millis(100));
+        while rx.try_recv().is_ok() {}
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+
+    #[test]
+    fn test_rejects_zero_interval() {
+        let config = CollectorConfig {
+            address: "127.0.0.1:9".into(),
+            interval_ms: 0,
+        };
+        assert!(ship(ClientStats::new(), config, ClientIdentity::default()).is_err());
+    }
+}
@@ -0,0 +1,186 @@
+//! Bitrate composition: how the bytes of a connection split between
+//! keyframes, delta frames, frame metadata, probe padding and control
+//! traffic.
+//!
+//! Level downgrades are not always caused by congestion: a burst of
+//! keyframes (e.g., scene changes) inflates the queue just as well. The
+//! composition per interval tells the two apart. Frames carry no keyframe
+//! flag, so a live frame counts as a keyframe when it is much larger than
+//! the recent frames of its level.
+
+use super::{AsDatum, AsDatumType};
+use super::prediction::KEY_FACTOR;
+use std::fmt;
+
+/// Weight of a new frame in the average frame size of its level.
+const SIZE_ALPHA: f64 = 1.0 / 16.0;
+
+/// What the bytes of a datum are spent on.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    /// Payload of a frame much larger than its level's average.
+    Key,
+
+    /// Payload of any other frame.
+    Delta,
+
+    /// Padding of probes (dummy and redundant datums).
+    Padding,
+
+    /// Handshakes, pings and feedback.
+    Control,
+}
+
+/// Bytes per kind over an interval. The metadata (length, type, timestamp,
+/// annotation) of frames is counted apart from their payload.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct Composition {
+    /// Payload bytes of keyframes.
+    pub key: usize,
+
+    /// Payload bytes of delta frames.
+    pub delta: usize,
+
+    /// Metadata bytes of frames.
+    pub metadata: usize,
+
+    /// Bytes of probe padding.
+    pub padding: usize,
+
+    /// Bytes of control datums.
+    pub control: usize,
+}
+
+impl Composition {
+    /// All bytes counted.
+    pub fn total(&self) -> usize {
+        self.key + self.delta + self.metadata + self.padding + self.control
+    }
+
+    /// The fraction of frame payload spent on keyframes, if any frames.
+    pub fn key_share(&self) -> Option<f64> {
+        match self.key + self.delta {
+            0 => None,
+            n => Some(self.key as f64 / n as f64),
+        }
+    }
+}
+
+impl fmt::Display for Composition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = self.total().max(1) as f64;
+        let pct = |n: usize| 100.0 * n as f64 / total;
+        write!(
+            f,
+            "key {:.0}% delta {:.0}% metadata {:.0}% padding {:.0}% control {:.0}%",
+            pct(self.key),
+            pct(self.delta),
+            pct(self.metadata),
+            pct(self.padding),
+            pct(self.control)
+        )
+    }
+}
+
+/// Classifies datums and accumulates the composition of an interval.
+#[derive(Debug, Default)]
+pub struct CompositionTracker {
+    /// Average payload size per level, once seen.
+    sizes: Vec<Option<f64>>,
+    current: Composition,
+}
+
+impl CompositionTracker {
+    /// Creates a tracker with no history.
+    pub fn new() -> CompositionTracker {
+        CompositionTracker::default()
+    }
+
+    /// Counts `datum`, whose length (see `AsDatum::len`) was `wire_len` on
+    /// the wire, less than restored if compressed or delta coded; returns
+    /// what its payload was spent on.
+    pub fn observe(&mut self, datum: &AsDatum, wire_len: usize) -> FrameKind {
+        match datum.datum_type() {
+            AsDatumType::Live(level, _) | AsDatumType::Backfill(level, _) => {
+                // the metadata goes as is, only the payload is coded
+                let metadata = (datum.len() - datum.mem.len()).min(wire_len);
+                let payload = wire_len - metadata;
+                self.current.metadata += metadata;
+                let kind = self.classify(level, payload);
+                match kind {
+                    FrameKind::Key => self.current.key += payload,
+                    _ => self.current.delta += payload,
+                }
+                kind
+            }
+            AsDatumType::Reference(..) => {
+                // the payload went to the blob store
+                self.current.metadata += wire_len;
+                FrameKind::Delta
+            }
+            AsDatumType::Dummy | AsDatumType::Redundant(..) => {
+                self.current.padding += wire_len;
+                FrameKind::Padding
+            }
+            _ => {
+                self.current.control += wire_len;
+                FrameKind::Control
+            }
+        }
+    }
+
+    /// Returns the composition since the last call and starts a new interval.
+    pub fn take(&mut self) -> Composition {
+        ::std::mem::take(&mut self.current)
+    }
+
+    fn classify(&mut self, level: usize, bytes: usize) -> FrameKind {
+        if self.sizes.len() <= level {
+            self.sizes.resize(level + 1, None);
+        }
+        let bytes = bytes as f64;
+        let kind = match self.sizes[level] {
+            Some(avg) if bytes > KEY_FACTOR * avg => FrameKind::Key,
+            _ => FrameKind::Delta,
+        };
+        let avg = self.sizes[level].map_or(bytes, |avg| avg + SIZE_ALPHA * (bytes - avg));
+        self.sizes[level] = Some(avg);
+        kind
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_composition() {
+        let mut tracker = CompositionTracker::new();
+        let mut observe = |datum: AsDatum| {
+            let len = datum.len();
+            tracker.observe(&datum, len)
+        };
+        for i in 0..10 {
+            assert_eq!(observe(AsDatum::new(0, i, vec![0; 100])), FrameKind::Delta);
+        }
+        assert_eq!(observe(AsDatum::new(0, 10, vec![0; 1000])), FrameKind::Key);
+        // levels are tracked apart
+        assert_eq!(observe(AsDatum::new(1, 11, vec![0; 1000])), FrameKind::Delta);
+        assert_eq!(observe(AsDatum::latency_probe()), FrameKind::Control);
+
+        let c = tracker.take();
+        assert_eq!(c.key, 1000);
+        assert_eq!(c.delta, 2000);
+        assert!((c.key_share().unwrap() - 1000.0 / 3000.0).abs() < 0.01);
+        assert!(c.control > 0);
+        assert_eq!(c.padding, 0);
+        assert_eq!(tracker.take(), Composition::default());
+
+        // a compressed frame counts the bytes it took on the wire
+        let restored = AsDatum::new(1, 12, vec![0; 1000]);
+        let metadata = restored.len() - 1000;
+        assert_eq!(tracker.observe(&restored, metadata + 150), FrameKind::Delta);
+        let c = tracker.take();
+        assert_eq!((c.delta, c.metadata), (150, metadata));
+    }
+}
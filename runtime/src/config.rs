@@ -0,0 +1,189 @@
+//! The `Configurable` trait that profile configurations implement.
+//!
+//! A profile is parameterized by a configuration type `C`. Requiring `C` to be
+//! `Configurable` lets the runtime reject invalid profiles when loading them
+//! and log human-readable diffs (e.g., "width 1920→1280") on level changes.
+//! A configuration also states what it `demand`s from the source, so that
+//! levels a source cannot produce are caught at startup (see `Capabilities`).
+
+use errors::*;
+use std::fmt;
+
+/// A single knob whose value differs between two configurations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldChange {
+    /// Name of the knob.
+    pub name: &'static str,
+
+    /// Value in the previous configuration.
+    pub from: String,
+
+    /// Value in the new configuration.
+    pub to: String,
+}
+
+/// The difference between two configurations, as a list of changed knobs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigDelta {
+    changes: Vec<FieldChange>,
+}
+
+impl ConfigDelta {
+    /// Creates an empty delta.
+    pub fn new() -> ConfigDelta {
+        ConfigDelta::default()
+    }
+
+    /// Records the change of knob `name`. Values that are equal are skipped,
+    /// so implementors can push every field unconditionally.
+    pub fn push<T: fmt::Display + PartialEq>(&mut self, name: &'static str, from: T, to: T) {
+        if from != to {
+            self.changes.push(FieldChange {
+                name,
+                from: from.to_string(),
+                to: to.to_string(),
+            });
+        }
+    }
+
+    /// Returns true if the two configurations are identical.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Returns the list of changed knobs.
+    pub fn changes(&self) -> &[FieldChange] {
+        &self.changes
+    }
+}
+
+impl fmt::Display for ConfigDelta {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.changes.is_empty() {
+            return write!(f, "no change");
+        }
+        for (i, c) in self.changes.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} {}→{}", c.name, c.from, c.to)?;
+        }
+        Ok(())
+    }
+}
+
+/// A configuration that a profile can hold.
+pub trait Configurable {
+    /// Checks that the configuration is achievable. Returns
+    /// `ErrorKind::InvalidConfig` with a reason if not.
+    fn validate(&self) -> Result<()>;
+
+    /// Describes what changes when moving from `prev` to `self`.
+    fn apply_delta(&self, prev: &Self) -> ConfigDelta;
+
+    /// What the source must support to produce this configuration. Unknown
+    /// by default, which any source satisfies.
+    fn demand(&self) -> Demand {
+        Demand::default()
+    }
+}
+
+/// What a configuration requires from the source; `None` means anything.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Demand {
+    /// Frame width (pixels).
+    pub width: Option<usize>,
+
+    /// Frame height (pixels).
+    pub height: Option<usize>,
+
+    /// Frames per second.
+    pub fps: Option<f64>,
+
+    /// Encoding format, e.g., "h264".
+    pub format: Option<String>,
+}
+
+/// The envelope of configurations a source can produce; `None` means no
+/// limit. The default is unlimited.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Capabilities {
+    /// Largest frame width (pixels).
+    pub max_width: Option<usize>,
+
+    /// Largest frame height (pixels).
+    pub max_height: Option<usize>,
+
+    /// Highest frame rate.
+    pub max_fps: Option<f64>,
+
+    /// Supported encoding formats.
+    pub formats: Option<Vec<String>>,
+}
+
+impl Capabilities {
+    /// Checks that `demand` is within the envelope. Returns
+    /// `ErrorKind::InvalidConfig` with the first violation if not.
+    pub fn admit(&self, demand: &Demand) -> Result<()> {
+        fn above<T: PartialOrd + fmt::Display>(name: &str, v: Option<T>, max: Option<T>) -> Result<()> {
+            match (v, max) {
+                (Some(v), Some(max)) if v > max => {
+                    bail!(ErrorKind::InvalidConfig(format!("{} {} exceeds {}", name, v, max)))
+                }
+                _ => Ok(()),
+            }
+        }
+        above("width", demand.width, self.max_width)?;
+        above("height", demand.height, self.max_height)?;
+        above("fps", demand.fps, self.max_fps)?;
+        if let (Some(f), Some(formats)) = (demand.format.as_ref(), self.formats.as_ref()) {
+            if !formats.contains(f) {
+                bail!(ErrorKind::InvalidConfig(format!("format {} is not supported", f)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What to do at startup with levels beyond the source's capabilities.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CapabilityCheck {
+    /// Fail to start.
+    #[default]
+    Reject,
+
+    /// Remove them from the profile.
+    Mask,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities_admit() {
+        let caps = Capabilities {
+            max_width: Some(1280),
+            max_fps: Some(30.0),
+            formats: Some(vec!["h264".into()]),
+            ..Capabilities::default()
+        };
+        let mut demand = Demand {
+            width: Some(1280),
+            height: Some(2160),
+            fps: Some(30.0),
+            format: Some("h264".into()),
+        };
+        assert!(caps.admit(&demand).is_ok());
+        assert!(Capabilities::default().admit(&demand).is_ok());
+        assert!(caps.admit(&Demand::default()).is_ok());
+
+        demand.fps = Some(60.0);
+        assert!(caps.admit(&demand).is_err());
+        demand.fps = None;
+        demand.format = Some("vp8".into());
+        assert!(caps.admit(&demand).is_err());
+    }
+}
@@ -0,0 +1,219 @@
+//! Congestion signals derived from delay samples.
+//!
+//! Buffer occupancy misses congestion when kernel buffers are large: the
+//! queue builds up in the network, not in the sender. `DelayGradient` instead
+//! watches the trend of the one-way delay (LEDBAT/BBR-style): it smooths the
+//! samples with an EWMA, and tracks their slope with a scalar Kalman filter.
+//! `LatencyBudget` watches the level of the delay instead, against a target
+//! end-to-end latency.
+
+use std::collections::VecDeque;
+
+/// A source of congestion evidence fed with delay samples.
+pub trait CongestionSignal: Send {
+    /// Adds a delay sample (ms) observed at `at_ms` (see `decision::Clock`).
+    fn on_delay(&mut self, delay_ms: f64, at_ms: u64);
+
+    /// How congested the path looks: 0 for not at all, 1 and above for
+    /// congested.
+    fn level(&self) -> f64;
+}
+
+/// Weight of the history when smoothing delay samples.
+const SMOOTHING: f64 = 0.9;
+
+/// Variance of the slope's drift between two samples ((ms/s)^2).
+const PROCESS_NOISE: f64 = 1.0;
+
+/// Variance of a measured slope ((ms/s)^2); slopes between close samples
+/// amplify jitter.
+const MEASUREMENT_NOISE: f64 = 3600.0;
+
+/// A delay growing by this much per second counts as congestion.
+const GRADIENT_THRESHOLD: f64 = 20.0;
+
+/// Detects congestion from a rising one-way delay.
+#[derive(Debug, Clone)]
+pub struct DelayGradient {
+    smoothed: Option<(f64, u64)>,
+    gradient: f64,
+    variance: f64,
+}
+
+impl Default for DelayGradient {
+    fn default() -> DelayGradient {
+        DelayGradient::new()
+    }
+}
+
+impl DelayGradient {
+    /// Creates a detector with no history.
+    pub fn new() -> DelayGradient {
+        DelayGradient {
+            smoothed: None,
+            gradient: 0.0,
+            variance: MEASUREMENT_NOISE,
+        }
+    }
+
+    /// The estimated trend of the delay, in ms per second.
+    pub fn gradient(&self) -> f64 {
+        self.gradient
+    }
+}
+
+impl CongestionSignal for DelayGradient {
+    fn on_delay(&mut self, delay_ms: f64, at_ms: u64) {
+        let (prev, since) = match self.smoothed {
+            Some(s) => s,
+            None => {
+                self.smoothed = Some((delay_ms, at_ms));
+                return;
+            }
+        };
+        if at_ms <= since {
+            return;
+        }
+        let dt = (at_ms - since) as f64 / 1000.0;
+        let smoothed = SMOOTHING * prev + (1.0 - SMOOTHING) * delay_ms;
+        let measured = (smoothed - prev) / dt;
+        self.smoothed = Some((smoothed, at_ms));
+
+        // random-walk model of the slope
+        self.variance += PROCESS_NOISE;
+        let gain = self.variance / (self.variance + MEASUREMENT_NOISE);
+        self.gradient += gain * (measured - self.gradient);
+        self.variance *= 1.0 - gain;
+    }
+
+    fn level(&self) -> f64 {
+        (self.gradient / GRADIENT_THRESHOLD).max(0.0)
+    }
+}
+
+/// A target end-to-end latency.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct BudgetConfig {
+    /// The latency budget (ms).
+    pub budget_ms: f64,
+
+    /// The quantile of the latency held to the budget (0 to 1).
+    pub quantile: f64,
+
+    /// How long the quantile, over this window, must exceed the budget before
+    /// it counts as violated (ms).
+    pub window_ms: u64,
+}
+
+impl Default for BudgetConfig {
+    fn default() -> Self {
+        BudgetConfig {
+            budget_ms: 500.0,
+            quantile: 0.95,
+            window_ms: 2000,
+        }
+    }
+}
+
+/// Detects sustained violations of a latency budget.
+#[derive(Debug, Clone)]
+pub struct LatencyBudget {
+    config: BudgetConfig,
+    samples: VecDeque<(u64, f64)>,
+    above_since: Option<u64>,
+}
+
+impl LatencyBudget {
+    /// Creates a detector with no samples.
+    pub fn new(config: BudgetConfig) -> LatencyBudget {
+        LatencyBudget {
+            config,
+            samples: VecDeque::new(),
+            above_since: None,
+        }
+    }
+
+    /// The quantile of the latency samples within the window, if any.
+    pub fn quantile(&self) -> Option<f64> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let mut sorted = self.samples.iter().map(|&(_, l)| l).collect::<Vec<_>>();
+        sorted.sort_by(|a, b| a.partial_cmp(b).expect("latency is NaN"));
+        let rank = (self.config.quantile.clamp(0.0, 1.0) * (sorted.len() - 1) as f64).round();
+        Some(sorted[rank as usize])
+    }
+
+    /// Adds a latency sample (ms) observed at `at_ms`. Returns the quantile
+    /// once it has exceeded the budget for a whole window; the detector then
+    /// starts over, so that violations are reported at most once per window.
+    pub fn on_latency(&mut self, latency_ms: f64, at_ms: u64) -> Option<f64> {
+        if latency_ms.is_nan() {
+            return None;
+        }
+        let start = at_ms.saturating_sub(self.config.window_ms);
+        while self.samples.front().is_some_and(|&(t, _)| t < start) {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((at_ms, latency_ms));
+
+        let quantile = self.quantile()?;
+        if quantile <= self.config.budget_ms {
+            self.above_since = None;
+            return None;
+        }
+        let since = *self.above_since.get_or_insert(at_ms);
+        if at_ms - since < self.config.window_ms {
+            return None;
+        }
+        self.above_since = None;
+        self.samples.clear();
+        Some(quantile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed<F: Fn(u64) -> f64>(detector: &mut DelayGradient, n: u64, delay: F) -> f64 {
+        for i in 0..n {
+            detector.on_delay(delay(i), 33 * i);
+        }
+        detector.level()
+    }
+
+    #[test]
+    fn test_delay_gradient() {
+        // jitter around a flat delay
+        let flat = feed(&mut DelayGradient::new(), 300, |i| 50.0 + (i % 3) as f64 * 4.0);
+        assert!(flat < 0.5, "flat delay looks congested: {}", flat);
+
+        // the delay grows by 2 ms per frame, i.e., 60 ms/s
+        let rising = feed(&mut DelayGradient::new(), 300, |i| 50.0 + 2.0 * i as f64);
+        assert!(rising > 1.0, "rising delay not detected: {}", rising);
+    }
+
+    #[test]
+    fn test_latency_budget() {
+        let mut budget = LatencyBudget::new(BudgetConfig {
+            budget_ms: 200.0,
+            quantile: 0.9,
+            window_ms: 1000,
+        });
+        // rare spikes stay within the budget
+        for i in 0..100 {
+            let latency = if i % 20 == 0 { 900.0 } else { 100.0 };
+            assert_eq!(budget.on_latency(latency, 100 * i), None);
+        }
+
+        // a sustained rise is reported after a window, then once per window
+        let violations = (100..150)
+            .filter_map(|i| budget.on_latency(300.0, 100 * i).map(|q| (i, q)))
+            .collect::<Vec<_>>();
+        assert_eq!(violations[0].1, 300.0);
+        assert!(violations[0].0 > 100 && violations[0].0 <= 112, "{:?}", violations);
+        assert!(violations.windows(2).all(|w| w[1].0 - w[0].0 >= 10));
+    }
+}
@@ -0,0 +1,149 @@
+//! Flow control on the lag of the downstream consumer.
+//!
+//! The server reports congestion on the network path only; when the sink the
+//! frames go to (a Kafka topic, a file) falls behind, frames pile up past the
+//! server while the network looks fine. A `LagProbe` set with
+//! `Server::set_lag_probe` tells how far (ms) the consumer of a session is
+//! behind, and its lag feeds back into the loop, weighted against the
+//! network's signals (`consumer_lag`):
+//!
+//! ```text
+//! pressure = weight * lag_ms / target_ms
+//! pressure >= 1   feedback is sent, at the rate the network carried divided
+//!                 by the pressure, and never marked as receiver limited
+//! ```
+//!
+//! so that senders slow down to what the end-to-end bottleneck drains.
+
+use super::Bandwidth;
+use middleware::ConnectionInfo;
+use std::sync::Arc;
+
+/// How often (ms) a probe is read, at most.
+const PROBE_INTERVAL_MS: u64 = 1000;
+
+/// Tells how far the downstream consumer of a session is behind.
+pub trait LagProbe: Send + Sync {
+    /// How long (ms) the frames of `conn` wait for the consumer, if known.
+    fn lag_ms(&self, conn: &ConnectionInfo) -> Option<f64>;
+}
+
+impl<F> LagProbe for F
+where
+    F: Fn(&ConnectionInfo) -> Option<f64> + Send + Sync,
+{
+    fn lag_ms(&self, conn: &ConnectionInfo) -> Option<f64> {
+        self(conn)
+    }
+}
+
+/// How the consumer's lag weighs against the network's signals.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct ConsumerLagConfig {
+    /// The lag (ms) the consumer may run behind.
+    pub target_ms: f64,
+
+    /// The weight of the lag: 0 ignores it, 1 slows down once the lag
+    /// reaches the target, higher earlier.
+    pub weight: f64,
+}
+
+impl Default for ConsumerLagConfig {
+    fn default() -> Self {
+        ConsumerLagConfig {
+            target_ms: 1000.0,
+            weight: 1.0,
+        }
+    }
+}
+
+/// The lag of a connection's consumer, read as frames arrive.
+pub(crate) struct LagSignal {
+    probe: Arc<dyn LagProbe>,
+    conn: Arc<ConnectionInfo>,
+    config: ConsumerLagConfig,
+    lag_ms: f64,
+    read_ms: Option<u64>,
+}
+
+impl LagSignal {
+    pub fn new(probe: Arc<dyn LagProbe>, conn: Arc<ConnectionInfo>, config: ConsumerLagConfig) -> LagSignal {
+        LagSignal {
+            probe,
+            conn,
+            config,
+            lag_ms: 0.0,
+            read_ms: None,
+        }
+    }
+
+    /// Reads the probe at `at_ms` if it is due. An unknown lag counts as
+    /// none.
+    pub fn update(&mut self, at_ms: u64) {
+        if self.read_ms.is_some_and(|read| at_ms < read + PROBE_INTERVAL_MS) {
+            return;
+        }
+        self.read_ms = Some(at_ms);
+        let lag_ms = self.probe.lag_ms(&self.conn).filter(|lag| lag.is_finite()).unwrap_or(0.0);
+        let pressure = |lag| self.config.weight * lag / self.config.target_ms;
+        if (pressure(lag_ms) >= 1.0) != (pressure(self.lag_ms) >= 1.0) {
+            info!("consumer of client {} is {:.0} ms behind", self.conn.addr, lag_ms);
+        }
+        self.lag_ms = lag_ms.max(0.0);
+    }
+
+    /// How much the consumer holds the session back: 1 and above to slow
+    /// down.
+    pub fn pressure(&self) -> f64 {
+        if self.config.target_ms <= 0.0 {
+            return 0.0;
+        }
+        self.config.weight * self.lag_ms / self.config.target_ms
+    }
+
+    /// The share of `rate` the consumer keeps up with.
+    pub fn limit(&self, rate: Bandwidth) -> Bandwidth {
+        let pressure = self.pressure();
+        if pressure > 1.0 { rate / pressure } else { rate }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use catalog::ClientIdentity;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_lag_weighs_against_network() {
+        let conn = Arc::new(ConnectionInfo {
+            addr: "127.0.0.1:8888".parse().unwrap(),
+            session: 1,
+            identity: ClientIdentity::default(),
+        });
+        let lag = Arc::new(Mutex::new(Some(500.0)));
+        let reading = lag.clone();
+        let probe = Arc::new(move |_: &ConnectionInfo| *reading.lock().unwrap());
+        let config = ConsumerLagConfig {
+            target_ms: 1000.0,
+            weight: 2.0,
+        };
+        let mut signal = LagSignal::new(probe, conn, config);
+        signal.update(0);
+        assert_eq!(signal.pressure(), 1.0);
+        assert_eq!(signal.limit(Bandwidth::from_kbps(1000.0)), Bandwidth::from_kbps(1000.0));
+
+        // read again only once due
+        *lag.lock().unwrap() = Some(2000.0);
+        signal.update(999);
+        assert_eq!(signal.pressure(), 1.0);
+        signal.update(1000);
+        assert_eq!(signal.pressure(), 4.0);
+        assert_eq!(signal.limit(Bandwidth::from_kbps(1000.0)), Bandwidth::from_kbps(250.0));
+
+        *lag.lock().unwrap() = None;
+        signal.update(2000);
+        assert_eq!(signal.pressure(), 0.0);
+    }
+}
@@ -1,17 +1,20 @@
 use adaptation::Signal;
+use bandwidth_feed::{BandwidthFeed, BandwidthSample};
 use errors::*;
+use heartbeat::HeartbeatPolicy;
+use prediction::FramePredictor;
 use futures::{Async, Poll, Stream};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
-use tokio_timer::{self, Interval};
-use utils::ExponentialSmooth;
-
-const ALPHA_RATE: f64 = 0.9;
+use decision::{MONITOR_INTERVAL, QueueEstimator, SharedClock};
+use estimator::Estimator;
+use stats::ClientStats;
+use ticker::Ticker;
 
 pub struct Monitor {
     /// Fires to estimate outgoing bandwidth and expected latency
-    timer: Interval,
+    timer: Ticker,
 
     /// My Reference to the data being generated.
     produced_bytes: Arc<AtomicUsize>,
@@ -19,41 +22,89 @@ pub struct Monitor {
     /// My Reference to the data being consumed.
     consumed_bytes: Arc<AtomicUsize>,
 
-    /// The estimated consumption rate.
-    rate: ExponentialSmooth,
-
-    /// Queued bytes.
-    queued: usize,
-
-    /// Empty counts.
-    empty_count: usize,
+    /// Turns produced and consumed bytes into signals.
+    queue: QueueEstimator,
 
     /// Remembers if timer has fired or not. We delay `react_to_timer` to avoid
     /// the race with `socket`.
     timer_fired: bool,
-}
 
-/// QUEUE_EMPTY_REQUIRED * MONITOR_INTERVAL => 1 seconds for each Q_E
-const QUEUE_EMPTY_REQUIRED: usize = 20;
+    /// Receives the rate and queueing delay of every interval, if set.
+    stats: Option<ClientStats>,
+
+    /// Publishes the estimates of every interval, if set.
+    feed: Option<BandwidthFeed>,
+
+    /// When the timer last fired (ms).
+    tick_ms: u64,
+
+    /// Spaces the heartbeats by the variation of the rate, if set.
+    heartbeat: Option<HeartbeatPolicy>,
 
-const MONITOR_INTERVAL: u64 = 100;
+    /// Predicts the bytes of the frames due within a horizon (ms), if set.
+    predictor: Option<(Arc<Mutex<FramePredictor>>, u64)>,
+
+    /// When a burst was last predicted (ms).
+    burst_ms: Option<u64>,
+}
 
 impl Monitor {
-    pub fn new(producer: Arc<AtomicUsize>, consumer: Arc<AtomicUsize>) -> Self {
-        let timer = tokio_timer::wheel()
-            .tick_duration(Duration::from_millis(50))
-            .build()
-            .interval(Duration::from_millis(MONITOR_INTERVAL));
+    /// Creates a monitor estimating the consumption rate with `rate`,
+    /// every `MONITOR_INTERVAL` of `clock`.
+    pub fn new(
+        producer: Arc<AtomicUsize>,
+        consumer: Arc<AtomicUsize>,
+        rate: Box<dyn Estimator>,
+        clock: SharedClock,
+    ) -> Self {
+        let timer = Ticker::new(clock, Duration::from_millis(MONITOR_INTERVAL));
 
         Monitor {
-            timer: timer,
+            timer,
             produced_bytes: producer,
             consumed_bytes: consumer,
-            rate: ExponentialSmooth::new(0.5),
-            queued: 0,
-            empty_count: 0,
+            queue: QueueEstimator::new(rate),
             timer_fired: false,
+            stats: None,
+            feed: None,
+            tick_ms: 0,
+            heartbeat: None,
+            predictor: None,
+            burst_ms: None,
+        }
+    }
+
+    /// Also records the rate and queueing delay of every interval in `stats`.
+    pub fn set_stats(&mut self, stats: ClientStats) {
+        self.stats = Some(stats);
+    }
+
+    /// Also publishes the estimates of every interval to `feed`.
+    pub fn set_feed(&mut self, feed: BandwidthFeed) {
+        self.feed = Some(feed);
+    }
+
+    /// Also feeds the rate of every interval to `heartbeat`.
+    pub fn set_heartbeat(&mut self, heartbeat: HeartbeatPolicy) {
+        self.heartbeat = Some(heartbeat);
+    }
+
+    /// Also raises `PredictedBurst` when the frames `predictor` expects
+    /// within `horizon_ms` would not drain in time (see `prediction`).
+    pub fn set_predictor(&mut self, predictor: Arc<Mutex<FramePredictor>>, horizon_ms: u64) {
+        self.predictor = Some((predictor, horizon_ms));
+    }
+
+    fn anticipate(&mut self) -> Option<Signal> {
+        let (ref predictor, horizon_ms) = *self.predictor.as_ref()?;
+        // one downgrade per burst
+        if self.burst_ms.is_some_and(|at| self.tick_ms < at + horizon_ms) {
+            return None;
         }
+        let upcoming = predictor.lock().ok()?.predict(horizon_ms);
+        let burst = self.queue.anticipate(upcoming, horizon_ms)?;
+        self.burst_ms = Some(self.tick_ms);
+        Some(burst)
     }
 
     fn react_to_timer(&mut self) -> Option<Signal> {
@@ -62,31 +113,25 @@ impl Monitor {
         // timer fired, we check the produced and consumed bytes
         let produced = self.produced_bytes.swap(0, Ordering::SeqCst);
         let consumed = self.consumed_bytes.swap(0, Ordering::SeqCst);
-
-        self.queued = self.queued + produced - consumed;
-        self.rate.add(consumed as f64);
-
-        // self.rate tracks the amount of bytes sent over the last
-        // MONITOR_INTERVAL (in ms). The division results in kbps.
-        let rate = self.rate.val() * 8.0 / (MONITOR_INTERVAL as f64);
-        let latency = self.queued as f64 * 8.0 / rate; // queued is bytes
-        info!(
-            "queued: {:?} kbytes, rate: {:.1} kbps, latency: {:.1} ms",
-            self.queued / 1000,
-            rate,
-            latency
-        );
-        if latency > 1.0 {
-            self.empty_count = 0;
-            return Some(Signal::QueueCongest(ALPHA_RATE * rate, latency));
-        } else {
-            self.empty_count += 1;
-            if self.empty_count > QUEUE_EMPTY_REQUIRED {
-                self.empty_count = 0;
-                return Some(Signal::QueueEmpty);
-            }
+        let signal = self.queue.update(produced, consumed);
+        if let Some(ref stats) = self.stats {
+            stats.set_queue(self.queue.rate().kbps(), self.queue.delay_ms());
+        }
+        if let Some(ref heartbeat) = self.heartbeat {
+            heartbeat.observe_rate(self.queue.rate().kbps());
+        }
+        if let Some(ref feed) = self.feed {
+            feed.publish(BandwidthSample {
+                time_ms: self.tick_ms,
+                rate: self.queue.rate(),
+                queue_delay_ms: self.queue.delay_ms(),
+                bandwidth: self.queue.bandwidth(),
+            });
+        }
+        match signal {
+            None | Some(Signal::QueueEmpty) => self.anticipate().or(signal),
+            signal => signal,
         }
-        return None;
     }
 }
 
@@ -95,30 +140,25 @@ impl Stream for Monitor {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        // We use a loop here to filter items: if `react_to_timer` returns
-        // `None`, the loop will continue and `try_ready` will return
-        // `Ok(Async::NotReady). In this way, not every timer tick will trigger
-        // a monitor event. This follows the implementation of
-        // `futures::Stream::filter`.
-        loop {
-            if self.timer_fired {
-                self.timer_fired = false;
-                match self.react_to_timer() {
-                    Some(s) => return Ok(Async::Ready(Some(s))),
-                    None => {}
-                }
+        // We delay `react_to_timer` to the next poll: if it returns `None`,
+        // `try_ready` on the timer will return `Ok(Async::NotReady)`. In this
+        // way, not every timer tick will trigger a monitor event. This follows
+        // the implementation of `futures::Stream::filter`.
+        if self.timer_fired {
+            self.timer_fired = false;
+            if let Some(s) = self.react_to_timer() {
+                return Ok(Async::Ready(Some(s)));
             }
-            match try_ready!(self.timer.poll()) {
-                Some(_t) => {
-                    self.timer_fired = true;
-                    let task = ::futures::task::current();
-                    task.notify();
-                    return Ok(Async::NotReady);
-                }
-                None => {
-                    return Ok(Async::Ready(None));
-                }
+        }
+        match try_ready!(self.timer.poll()) {
+            Some(t) => {
+                self.tick_ms = t;
+                self.timer_fired = true;
+                let task = ::futures::task::current();
+                task.notify();
+                Ok(Async::NotReady)
             }
+            None => Ok(Async::Ready(None)),
         }
     }
 }
@@ -0,0 +1,196 @@
+//! Bounded-latency mode: dropping over queueing.
+//!
+//! Adaptation and `codel` keep the send queue short on average, but a frame
+//! may still reach the socket long after it was captured, which is useless
+//! for interactive streams (e.g., teleoperation) whose frames expire. With a
+//! `DeadlineConfig`, the client bounds the age of what it sends instead:
+//!
+//! ```text
+//! age at the head of the queue + bytes / bandwidth > deadline_ms  =>  drop
+//! ```
+//!
+//! where the bandwidth is the monitor's estimate of the path
+//! (`BandwidthSample::bandwidth`); until the send queue first backlogged,
+//! there is none and only the age counts. Every live frame leaving the queue
+//! is checked, so a stale backlog is flushed at once rather than sent late.
+//! The first miss within a deadline downgrades a level right away
+//! (`Signal::DeadlineMiss`); misses after it report congestion at the
+//! estimated bandwidth, as `codel` does. Backfill, padding and control
+//! datums are not checked: they are late, or small, by design.
+//!
+//! The bound is best effort: it is checked as frames leave the client's
+//! queue, so bytes already in the socket's buffers, retransmissions and a
+//! bandwidth dropping faster than the estimate follows may still deliver a
+//! frame past its deadline.
+
+use super::{AsDatum, AsDatumType, Bandwidth};
+use super::adaptation::Signal;
+use super::bandwidth_feed::BandwidthFeed;
+use super::decision::{Clock, SystemClock};
+use chrono::Utc;
+use futures::{Async, Poll, Stream};
+use futures::sync::mpsc::UnboundedSender;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The latency bound of the client.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadlineConfig {
+    /// How long (ms) after capture a frame may still be sent.
+    pub deadline_ms: u64,
+}
+
+/// Drops the live frames of `inner` that would miss their deadline; other
+/// datums pass through.
+pub struct DeadlineQueue<S> {
+    inner: S,
+    deadline_ms: Option<u64>,
+    clock: SystemClock,
+    signals: UnboundedSender<Signal>,
+    produced: Arc<AtomicUsize>,
+    feed: BandwidthFeed,
+    last_miss: Option<u64>,
+}
+
+impl<S: Stream<Item = AsDatum>> DeadlineQueue<S> {
+    /// Wraps `inner`, reporting drops to `signals`; without `config`, all
+    /// datums pass through. The bytes of dropped frames are taken back from
+    /// `produced`, so that the monitor does not count them as queued. The
+    /// bandwidth is the latest estimate published to `feed`.
+    pub fn new(
+        inner: S,
+        config: Option<DeadlineConfig>,
+        signals: UnboundedSender<Signal>,
+        produced: Arc<AtomicUsize>,
+        feed: BandwidthFeed,
+    ) -> DeadlineQueue<S> {
+        DeadlineQueue {
+            inner,
+            deadline_ms: config.map(|c| c.deadline_ms),
+            clock: SystemClock::new(),
+            signals,
+            produced,
+            feed,
+            last_miss: None,
+        }
+    }
+
+    /// The estimated bandwidth of the path, if any yet.
+    fn bandwidth(&self) -> Option<Bandwidth> {
+        self.feed.latest().and_then(|sample| sample.bandwidth)
+    }
+
+    /// How long (ms) `datum` will have waited once sent at the estimated
+    /// bandwidth.
+    fn latency_ms(&self, datum: &AsDatum) -> u64 {
+        let age = Utc::now().signed_duration_since(datum.ts).num_milliseconds().max(0) as u64;
+        let sending = match self.bandwidth() {
+            Some(bw) if bw.bytes_per_sec() > 0.0 => {
+                datum.net_len() as f64 * 1000.0 / bw.bytes_per_sec()
+            }
+            _ => 0.0,
+        };
+        age + sending as u64
+    }
+
+    fn drop_frame(&mut self, datum: &AsDatum, now_ms: u64, deadline_ms: u64, latency_ms: u64) {
+        let len = datum.net_len();
+        warn!("dropping {}, which would arrive {} ms after capture", datum, latency_ms);
+        // the monitor may have collected the bytes already; then they stay
+        let _ = self.produced.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |p| {
+            Some(p.saturating_sub(len))
+        });
+        let signal = match self.last_miss {
+            Some(at) if now_ms < at + deadline_ms => {
+                Signal::QueueCongest(self.bandwidth().unwrap_or(Bandwidth::ZERO), latency_ms as f64)
+            }
+            _ => {
+                self.last_miss = Some(now_ms);
+                Signal::DeadlineMiss(latency_ms as f64)
+            }
+        };
+        let _ = self.signals.unbounded_send(signal);
+    }
+}
+
+impl<S: Stream<Item = AsDatum>> Stream for DeadlineQueue<S> {
+    type Item = AsDatum;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<AsDatum>, S::Error> {
+        loop {
+            let datum = match try_ready!(self.inner.poll()) {
+                Some(d) => d,
+                None => return Ok(Async::Ready(None)),
+            };
+            let deadline_ms = match self.deadline_ms {
+                Some(d) => d,
+                None => return Ok(Async::Ready(Some(datum))),
+            };
+            let now_ms = self.clock.now_ms();
+            if let AsDatumType::Live(..) | AsDatumType::Reference(..) = datum.datum_type() {
+                let latency_ms = self.latency_ms(&datum);
+                if latency_ms > deadline_ms {
+                    self.drop_frame(&datum, now_ms, deadline_ms, latency_ms);
+                    continue;
+                }
+            }
+            return Ok(Async::Ready(Some(datum)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bandwidth_feed::BandwidthSample;
+    use chrono::Duration;
+    use futures::{Future, stream};
+    use futures::sync::mpsc::unbounded;
+
+    #[test]
+    fn test_drops_frames_past_deadline() {
+        let aged = |frame_num, age_ms| {
+            let mut datum = AsDatum::new(1, frame_num, vec![0; 100]);
+            datum.ts = Utc::now() - Duration::milliseconds(age_ms);
+            datum
+        };
+        let datums = vec![
+            aged(0, 10),
+            aged(1, 500),
+            aged(2, 400),
+            AsDatum::new(1, 3, vec![0; 100]).into_backfill(),
+            aged(4, 20),
+            // young, but another 100 ms to send at 1 kB/s
+            aged(5, 150),
+        ];
+        let (tx, rx) = unbounded();
+        let produced = Arc::new(AtomicUsize::new(1000));
+        let feed = BandwidthFeed::new();
+        let bandwidth = Bandwidth::from_bytes_per_ms(1.0, 1.0);
+        feed.publish(BandwidthSample {
+            time_ms: 0,
+            rate: Bandwidth::from_kbps(1.0),
+            queue_delay_ms: 0.0,
+            bandwidth: Some(bandwidth),
+        });
+        let config = DeadlineConfig { deadline_ms: 200 };
+        let datums = stream::iter_ok::<_, ()>(datums);
+        let sent = DeadlineQueue::new(datums, Some(config), tx, produced.clone(), feed)
+            .map(|d| d.datum_type())
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(
+            sent,
+            vec![AsDatumType::Live(1, 0), AsDatumType::Backfill(1, 3), AsDatumType::Live(1, 4)]
+        );
+        assert!(produced.load(Ordering::SeqCst) < 1000);
+
+        // one downgrade for the stale backlog, congestion for the rest
+        let signals = rx.take(3).collect().wait().unwrap();
+        assert!(matches!(signals[0], Signal::DeadlineMiss(l) if l >= 500.0));
+        assert!(matches!(signals[1], Signal::QueueCongest(bw, l) if bw == bandwidth && l >= 400.0));
+        assert!(matches!(signals[2], Signal::QueueCongest(_, l) if l >= 250.0));
+    }
+}
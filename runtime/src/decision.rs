@@ -0,0 +1,299 @@
+//! The decision logic, free of the async stack.
+//!
+//! Everything needed to turn traffic measurements into levels lives in
+//! modules that don't touch the async stack: the profile walk
+//! (`SimpleProfile`), the policies (`decide`, `Adaptation`), the rate
+//! estimators, the queue model and the delay-based congestion detectors.
+//! None of them use chrono, tokio or futures, or read the time themselves:
+//! time is passed in as milliseconds from a `Clock`. This keeps the logic
+//! easy to drive step by step, e.g., from property tests or the simulations
+//! of `experiments`.
+//!
+//! They still need `std`, and profiles are parsed with serde and csv, so
+//! this is a module of the crate rather than a `no_std` crate of its own.
+//! The async stack sits behind the `runtime` feature, which every other
+//! feature implies: `cargo build --no-default-features` builds just these
+//! modules, so a dependency creeping in fails to compile.
+
+use super::Bandwidth;
+use super::adaptation::Signal;
+use super::estimator::Estimator;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+pub use super::adaptation::{decide, Action, Adaptation, Decision, Policy};
+pub use super::congestion::{BudgetConfig, CongestionSignal, DelayGradient, LatencyBudget};
+pub use super::estimator::{ExponentialSmooth, Quantile};
+pub use super::prediction::FramePredictor;
+pub use super::profile::SimpleProfile;
+
+/// A source of time, in milliseconds since an arbitrary epoch.
+pub trait Clock {
+    /// The current time (ms). Never decreases.
+    fn now_ms(&self) -> u64;
+}
+
+/// A clock shared by the components of a runtime.
+pub type SharedClock = Arc<dyn Clock + Send + Sync>;
+
+/// The monotonic system clock, counting from its creation.
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock {
+    epoch: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> SystemClock {
+        SystemClock::new()
+    }
+}
+
+impl SystemClock {
+    /// Creates a clock starting at 0 now.
+    pub fn new() -> SystemClock {
+        SystemClock { epoch: Instant::now() }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        let elapsed = self.epoch.elapsed();
+        elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis())
+    }
+}
+
+/// A clock that only moves when told to, for tests and simulations.
+/// Clones share the time: a test keeps one to advance the clock of the
+/// components it handed the others to.
+#[derive(Debug, Default, Clone)]
+pub struct ManualClock {
+    now: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    /// Creates a clock at `now_ms`.
+    pub fn new(now_ms: u64) -> ManualClock {
+        ManualClock { now: Arc::new(AtomicU64::new(now_ms)) }
+    }
+
+    /// Moves the clock forward by `ms`.
+    pub fn advance(&self, ms: u64) {
+        self.now.fetch_add(ms, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
+/// Fraction of the estimated rate reported with `QueueCongest`.
+const ALPHA_RATE: f64 = 0.9;
+
+/// The weight of the history in the bandwidth estimate.
+const BANDWIDTH_HISTORY: f64 = 0.5;
+
+/// QUEUE_EMPTY_REQUIRED * MONITOR_INTERVAL => 1 seconds for each Q_E
+const QUEUE_EMPTY_REQUIRED: usize = 20;
+
+/// The period (ms) of the queue measurements.
+pub const MONITOR_INTERVAL: u64 = 100;
+
+/// The queue model behind the client's monitor: turns the bytes produced and
+/// consumed during each `MONITOR_INTERVAL` into congestion signals. Also
+/// drives the simulated queue of `experiments`.
+pub struct QueueEstimator {
+    /// The estimated consumption rate.
+    rate: Box<dyn Estimator>,
+
+    /// Queued bytes.
+    queued: usize,
+
+    /// Bytes consumed per interval while the queue stayed backlogged, i.e.,
+    /// while the path rather than the source limited the rate (smoothed).
+    bandwidth: Option<f64>,
+
+    /// Empty counts.
+    empty_count: usize,
+}
+
+impl QueueEstimator {
+    /// Creates an empty queue whose drain rate is estimated with `rate`.
+    pub fn new(rate: Box<dyn Estimator>) -> Self {
+        QueueEstimator {
+            rate,
+            queued: 0,
+            bandwidth: None,
+            empty_count: 0,
+        }
+    }
+
+    /// Accounts for one interval, returning the signal it raises, if any.
+    pub fn update(&mut self, produced: usize, consumed: usize) -> Option<Signal> {
+        // bytes sent outside the source (e.g., the handshake) are consumed
+        // without having been produced
+        self.queued = (self.queued + produced).saturating_sub(consumed);
+        self.rate.add(consumed as f64);
+        if self.queued > 0 {
+            let sample = consumed as f64;
+            self.bandwidth = Some(match self.bandwidth {
+                Some(b) => b * BANDWIDTH_HISTORY + sample * (1.0 - BANDWIDTH_HISTORY),
+                None => sample,
+            });
+        }
+
+        let rate = self.rate();
+        let latency = self.delay_ms();
+        info!(
+            "queued: {:?} kbytes, rate: {}, latency: {:.1} ms",
+            self.queued / 1000,
+            rate,
+            latency
+        );
+        if latency > 1.0 {
+            self.empty_count = 0;
+            return Some(Signal::QueueCongest(rate * ALPHA_RATE, latency));
+        } else {
+            self.empty_count += 1;
+            if self.empty_count > QUEUE_EMPTY_REQUIRED {
+                self.empty_count = 0;
+                return Some(Signal::QueueEmpty);
+            }
+        }
+        None
+    }
+
+    /// Raises `PredictedBurst` if `upcoming` bytes, due within `horizon_ms`,
+    /// would not drain in time along with the queued ones at the estimated
+    /// bandwidth. Without an estimate (the queue never backlogged), the path
+    /// may well carry the burst, and nothing is raised.
+    pub fn anticipate(&self, upcoming: usize, horizon_ms: u64) -> Option<Signal> {
+        let rate = self.bandwidth()?.bytes_per_sec();
+        if rate <= 0.0 {
+            return None;
+        }
+        let excess = (self.queued + upcoming) as f64 - rate * horizon_ms as f64 / 1000.0;
+        if excess > 0.0 {
+            Some(Signal::PredictedBurst(excess * 1000.0 / rate))
+        } else {
+            None
+        }
+    }
+
+    /// The estimated consumption rate.
+    pub fn rate(&self) -> Bandwidth {
+        // self.rate tracks the amount of bytes sent over the last
+        // MONITOR_INTERVAL (in ms)
+        Bandwidth::from_bytes_per_ms(self.rate.estimate(), MONITOR_INTERVAL as f64)
+    }
+
+    /// The estimated bandwidth of the path, once the queue backlogged. What
+    /// is sent while the source limits the rate only bounds it from below,
+    /// so the rate of such intervals doesn't count, unless higher.
+    pub fn bandwidth(&self) -> Option<Bandwidth> {
+        let backlogged = Bandwidth::from_bytes_per_ms(self.bandwidth?, MONITOR_INTERVAL as f64);
+        Some(backlogged.max(self.rate()))
+    }
+
+    /// The time (ms) the queued bytes take to drain at the estimated rate.
+    pub fn delay_ms(&self) -> f64 {
+        self.queued as f64 * 1000.0 / self.rate().bytes_per_sec() // queued is bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use AdaptAction;
+    use profile::{Profile, Record};
+
+    /// A deterministic pseudo-random sequence (xorshift).
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        fn signal(&mut self, levels: usize) -> Signal {
+            let rate = Bandwidth::from_kbps(self.below(5000) as f64);
+            let latency = self.below(2000) as f64;
+            match self.below(10) {
+                0 => Signal::QueueCongest(rate, latency),
+                1 => Signal::QueueEmpty,
+                2 => Signal::RemoteCongest(rate, latency),
+                3 => Signal::ProbeDone,
+                4 => Signal::EncoderLimit(self.below(levels as u64) as usize),
+                5 => Signal::BudgetViolation(latency),
+                6 => Signal::MemoryPressure(self.below(1 << 20) as usize),
+                7 => Signal::PredictedBurst(latency),
+                8 => Signal::DeadlineMiss(latency),
+                _ => Signal::SystemLoad(self.below(2) == 0),
+            }
+        }
+    }
+
+    #[test]
+    fn test_decisions_stay_in_profile() {
+        let records = (0..5)
+            .map(|i| {
+                let rate = Bandwidth::from_kbps(100.0 * (1 << i) as f64);
+                Record::new(rate, (), 0.5 + 0.1 * i as f64)
+            })
+            .collect();
+        let levels = Profile::_with_vec(records).simplify();
+        for seed in 1..50u64 {
+            let mut rng = Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+            let mut profile = levels.clone();
+            let mut policy = Adaptation::default();
+            let mut probing = false;
+            for _ in 0..1000 {
+                // like the source, only reports the end of a running probe
+                let signal = match rng.signal(levels.num_levels()) {
+                    Signal::ProbeDone if !probing => Signal::QueueEmpty,
+                    s => s,
+                };
+                let decision = decide(&mut policy, &mut profile, signal);
+                probing = match (signal, decision.command) {
+                    (Signal::ProbeDone, _) | (_, Some(AdaptAction::StopProbe)) => false,
+                    (_, Some(AdaptAction::StartProbe(_))) => true,
+                    _ => probing,
+                };
+                assert!(decision.level < levels.num_levels(), "{:?}", decision);
+                assert_eq!(decision.level, profile.current());
+                match decision.command {
+                    Some(AdaptAction::ToLevel(l)) => assert_eq!(l, decision.level),
+                    Some(AdaptAction::StartProbe(target)) => {
+                        assert!(target > Bandwidth::ZERO, "{:?}", decision)
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new(5);
+        let mut detector = DelayGradient::new();
+        for i in 0..300 {
+            detector.on_delay(50.0 + 2.0 * i as f64, clock.now_ms());
+            clock.advance(33);
+        }
+        assert_eq!(clock.now_ms(), 5 + 300 * 33);
+        let shared = clock.clone();
+        clock.advance(1);
+        assert_eq!(shared.now_ms(), 5 + 300 * 33 + 1);
+        assert!(detector.level() > 1.0);
+        assert!(SystemClock::new().now_ms() < 1000);
+    }
+}
@@ -0,0 +1,339 @@
+//! Delta coding of consecutive live frames.
+//!
+//! Slowly-changing payloads (matrices, telemetry) differ in a few bytes from
+//! one frame to the next. With `delta` set, the client announces it with a
+//! `Delta` datum ahead of the first live frame of each connection, and from
+//! then on prefixes the payload of every live frame with a tag:
+//!
+//! ```text
+//! 0, payload                         a full frame
+//! 1, base, len, (skip, n, bytes)*    the changes from frame `base` of the
+//!                                    same level, in big-endian u32s
+//! ```
+//!
+//! The server restores the payloads, right after decompressing them, from
+//! the last frame it restored at each level. A frame whose base it doesn't
+//! have (the base was dropped on the way) is discarded, and so is every
+//! frame coded against it. To recover, the client sends a full frame after
+//! each frame it knows was lost (dropped from the send queue or by CoDel),
+//! at each level switch, and every `full_every` frames anyway.
+//!
+//! Only live frames and the datums of sub-streams are coded, the latter
+//! against the last datum of the same sub-stream; backfill and redundant
+//! copies are sent as produced. The `Delta` datum is new, so both ends need
+//! to support it.
+
+use super::{AsDatum, AsDatumType};
+use byteorder::{BigEndian, ByteOrder};
+use errors::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const FULL: u8 = 0;
+const CHANGES: u8 = 1;
+
+/// Unchanged bytes a run of changes may span, rather than being split.
+const MAX_GAP: usize = 8;
+
+/// What a datum is coded against the last of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Key {
+    Level(usize),
+    Sub(u32),
+}
+
+impl Key {
+    /// The key and number of a datum that is coded.
+    fn of(datum: &AsDatum) -> Option<(Key, usize)> {
+        match datum.datum_type() {
+            AsDatumType::Live(level, frame_num) => Some((Key::Level(level), frame_num)),
+            AsDatumType::Sub(stream, seq) => Some((Key::Sub(stream), seq)),
+            _ => None,
+        }
+    }
+
+    /// Returns true if coding a datum of this key restarts `other`: a level
+    /// switch restarts the other levels.
+    fn restarts(self, other: Key) -> bool {
+        matches!((self, other), (Key::Level(a), Key::Level(b)) if a != b)
+    }
+}
+
+/// When to send full frames.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct DeltaConfig {
+    /// A full frame is sent at least once every this many frames of a level.
+    pub full_every: usize,
+}
+
+impl Default for DeltaConfig {
+    fn default() -> Self {
+        DeltaConfig { full_every: 30 }
+    }
+}
+
+/// The client end.
+#[derive(Debug)]
+pub struct DeltaEncoder {
+    config: DeltaConfig,
+    announced: bool,
+    /// The last frame of each level (or sub-stream): its number, payload,
+    /// and the frames coded against a base since the last full one.
+    bases: HashMap<Key, (usize, Vec<u8>, usize)>,
+    lost: Arc<AtomicBool>,
+    decoder: DeltaDecoder,
+}
+
+impl DeltaEncoder {
+    /// Creates an encoder that announces itself with the next live frame.
+    pub fn new(config: DeltaConfig) -> DeltaEncoder {
+        DeltaEncoder {
+            config,
+            announced: false,
+            bases: HashMap::new(),
+            lost: Arc::default(),
+            decoder: DeltaDecoder::default(),
+        }
+    }
+
+    /// A flag to raise when a frame sent is lost, so that the next frames
+    /// are full.
+    pub fn loss_flag(&self) -> Arc<AtomicBool> {
+        self.lost.clone()
+    }
+
+    /// Makes the next frame of each level a full frame.
+    pub fn resync(&mut self) {
+        self.bases.clear();
+    }
+
+    /// Returns the datums to send for `datum`, in order: `datum`, coded,
+    /// preceded by the `Delta` datum the first time.
+    pub fn encode(&mut self, mut datum: AsDatum) -> Vec<AsDatum> {
+        let (key, frame_num) = match Key::of(&datum) {
+            Some(coded) => coded,
+            None => return vec![datum],
+        };
+        if self.lost.swap(false, Ordering::SeqCst) {
+            debug!("sending full frames after a loss");
+            self.resync();
+        }
+        let payload = ::std::mem::take(&mut datum.mem);
+        datum.mem = match self.bases.get(&key) {
+            Some(&(base, ref prev, coded)) if coded + 1 < self.config.full_every => {
+                let mut mem = vec![CHANGES];
+                push_u32(&mut mem, base);
+                push_u32(&mut mem, payload.len());
+                changes(prev, &payload, &mut mem);
+                mem
+            }
+            _ => [&[FULL][..], &payload].concat(),
+        };
+        let coded = match self.bases.get(&key) {
+            Some(&(_, _, coded)) if datum.mem[0] == CHANGES => coded + 1,
+            _ => 0,
+        };
+        self.bases.retain(|&k, _| !key.restarts(k));
+        self.bases.insert(key, (frame_num, payload, coded));
+        datum.update_len();
+        let _ = self.decoder.restore(datum.clone());
+        if self.announced {
+            vec![datum]
+        } else {
+            self.announced = true;
+            vec![AsDatum::delta(), datum]
+        }
+    }
+
+    /// Restores the payload of a datum returned by `encode`, e.g., to spool
+    /// it for another connection.
+    pub fn restore(&mut self, datum: AsDatum) -> AsDatum {
+        match Key::of(&datum) {
+            Some((key, frame_num)) => match self.decoder.last.get(&key) {
+                Some(&(n, ref payload)) if n == frame_num => {
+                    let mut datum = datum;
+                    datum.mem = payload.clone();
+                    datum.update_len();
+                    datum
+                }
+                _ => datum,
+            },
+            None => datum,
+        }
+    }
+}
+
+/// The server end: restores the live frames of a connection once its
+/// `Delta` datum arrived.
+#[derive(Debug, Default)]
+pub struct DeltaDecoder {
+    enabled: bool,
+    /// The last frame restored at each level (or sub-stream).
+    last: HashMap<Key, (usize, Vec<u8>)>,
+}
+
+impl DeltaDecoder {
+    /// Turns on with a `Delta` datum, and restores the payload of live
+    /// frames once on. Returns `None` for a frame whose base is missing.
+    pub fn decode(&mut self, datum: AsDatum) -> Result<Option<AsDatum>> {
+        if let AsDatumType::Delta = datum.datum_type() {
+            self.enabled = true;
+            return Ok(Some(datum));
+        }
+        if !self.enabled {
+            return Ok(Some(datum));
+        }
+        self.restore(datum).chain_err(|| ErrorKind::DecodeError)
+    }
+
+    fn restore(&mut self, mut datum: AsDatum) -> Result<Option<AsDatum>> {
+        let (key, frame_num) = match Key::of(&datum) {
+            Some(coded) => coded,
+            None => return Ok(Some(datum)),
+        };
+        let payload = match datum.mem.first() {
+            Some(&FULL) => datum.mem[1..].to_vec(),
+            Some(&CHANGES) => {
+                let base = read_u32(&datum.mem, 1)?;
+                match self.last.get(&key) {
+                    Some(&(n, ref prev)) if n == base => apply(prev, &datum.mem[5..])?,
+                    _ => {
+                        debug!("discarding {}, coded against missing frame {}", datum, base);
+                        self.last.remove(&key);
+                        return Ok(None);
+                    }
+                }
+            }
+            _ => bail!(ErrorKind::InvalidConfig(format!("bad delta tag in {}", datum))),
+        };
+        self.last.retain(|&k, _| !key.restarts(k));
+        self.last.insert(key, (frame_num, payload.clone()));
+        datum.mem = payload;
+        datum.update_len();
+        Ok(Some(datum))
+    }
+}
+
+fn push_u32(mem: &mut Vec<u8>, n: usize) {
+    let mut buf = [0; 4];
+    BigEndian::write_u32(&mut buf, n as u32);
+    mem.extend_from_slice(&buf);
+}
+
+fn read_u32(mem: &[u8], at: usize) -> Result<usize> {
+    match mem.get(at..at + 4) {
+        Some(field) => Ok(BigEndian::read_u32(field) as usize),
+        None => bail!(ErrorKind::InvalidConfig("truncated delta".into())),
+    }
+}
+
+/// Appends the length of `next`, then its runs of bytes that differ from
+/// `prev` (bytes beyond `prev` all differ).
+fn changes(prev: &[u8], next: &[u8], mem: &mut Vec<u8>) {
+    let differs = |i: usize| prev.get(i) != Some(&next[i]);
+    let mut i = 0;
+    let mut unchanged = 0;
+    while i < next.len() {
+        if !differs(i) {
+            i += 1;
+            unchanged += 1;
+            continue;
+        }
+        let start = i;
+        let mut end = i;
+        while i < next.len() && i - end <= MAX_GAP {
+            if differs(i) {
+                end = i + 1;
+            }
+            i += 1;
+        }
+        push_u32(mem, unchanged);
+        push_u32(mem, end - start);
+        mem.extend_from_slice(&next[start..end]);
+        i = end;
+        unchanged = 0;
+    }
+}
+
+/// Applies the runs of `changes` (after the length) to `prev`.
+fn apply(prev: &[u8], changes: &[u8]) -> Result<Vec<u8>> {
+    let len = read_u32(changes, 0)?;
+    // every byte is either unchanged from `prev` or carried in `changes`
+    if len > prev.len() + changes.len() {
+        bail!(ErrorKind::InvalidConfig("delta beyond its frame".into()));
+    }
+    let mut next = Vec::with_capacity(len);
+    let mut at = 4;
+    while at < changes.len() {
+        let (skip, n) = (read_u32(changes, at)?, read_u32(changes, at + 4)?);
+        at += 8;
+        let from = next.len();
+        match (prev.get(from..from + skip), changes.get(at..at + n)) {
+            (Some(unchanged), Some(changed)) => {
+                next.extend_from_slice(unchanged);
+                next.extend_from_slice(changed);
+            }
+            _ => bail!(ErrorKind::InvalidConfig("delta beyond its frame".into())),
+        }
+        at += n;
+    }
+    match prev.get(next.len()..len) {
+        Some(rest) => next.extend_from_slice(rest),
+        None if next.len() == len => {}
+        None => bail!(ErrorKind::InvalidConfig("delta beyond its frame".into())),
+    }
+    Ok(next)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta_round_trip_and_recovery() {
+        let config = DeltaConfig { full_every: 4 };
+        let mut encoder = DeltaEncoder::new(config);
+        let mut decoder = DeltaDecoder::default();
+        let payload = |i: usize| {
+            let mut p = vec![7u8; 1000];
+            p[i] = i as u8;
+            p.truncate(1000 - i);
+            p
+        };
+        let mut sent = Vec::new();
+        for i in 0..10 {
+            sent.extend(encoder.encode(AsDatum::new(0, i, payload(i))));
+        }
+        assert_eq!(sent[0].datum_type(), AsDatumType::Delta);
+        // full frames at 0, 4 and 8; small ones in between
+        let sizes = sent[1..].iter().map(|d| d.mem.len()).collect::<Vec<_>>();
+        assert!(sizes.iter().enumerate().all(|(i, &s)| (s > 900) == (i % 4 == 0)), "{:?}", sizes);
+        assert_eq!(encoder.restore(sent[10].clone()).mem, payload(9));
+
+        // frame 5 is lost: 6 and 7 are discarded until the full frame 8
+        let restored = sent
+            .into_iter()
+            .filter(|d| d.datum_type() != AsDatumType::Live(0, 5))
+            .filter_map(|d| decoder.decode(d).unwrap())
+            .filter(|d| d.datum_type() != AsDatumType::Delta)
+            .map(|d| match d.datum_type() {
+                AsDatumType::Live(_, i) => (i, d.mem == payload(i)),
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(restored, vec![(0, true), (1, true), (2, true), (3, true), (4, true), (8, true), (9, true)]);
+
+        // a loss reported by the pipeline makes the next frame full
+        encoder.loss_flag().store(true, Ordering::SeqCst);
+        let next = encoder.encode(AsDatum::new(0, 10, payload(10)));
+        assert_eq!(next[0].mem[0], FULL);
+    }
+
+    #[test]
+    fn test_apply_rejects_an_impossible_length() {
+        // 4 GiB claimed from three bytes and no changes
+        assert!(apply(&[1, 2, 3], &[0xff; 4]).is_err());
+    }
+}
@@ -0,0 +1,264 @@
+//! Client and server in one process.
+//!
+//! `run_loopback` starts a server on a background thread, streams `source`
+//! to it over localhost for a while, and reports what the server received.
+//! It is the shortest way to see the runtime adapt, and a smoke test for
+//! sources and profiles:
+//!
+//! ```text
+//! let report = run_loopback(&profile, Paced::new(my_source), Duration::from_secs(10))?;
+//! println!("{}", report);
+//! ```
+
+use super::{AsDatumType, Cancellation, Profile, Setting, Source};
+use super::client::Client;
+use super::server::{Server, ServerEvent};
+use errors::*;
+use futures::{Future, Stream};
+use futures::sync::oneshot;
+use std::fmt;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tokio_core::reactor::Core;
+use toml;
+
+/// How long to wait for the server to drain the connection after the client
+/// is done.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What the server received during a loopback run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunReport {
+    /// Frames received.
+    pub frames: usize,
+
+    /// Bytes of frames received.
+    pub bytes: usize,
+
+    /// Average sender-to-receiver latency (ms).
+    pub mean_latency_ms: f64,
+
+    /// Highest latency (ms).
+    pub max_latency_ms: f64,
+
+    /// Frames received at each level.
+    pub levels: Vec<usize>,
+
+    /// Average accuracy of the frames received, according to the profile.
+    pub accuracy: f64,
+}
+
+impl RunReport {
+    fn new(num_levels: usize) -> RunReport {
+        RunReport {
+            frames: 0,
+            bytes: 0,
+            mean_latency_ms: 0.0,
+            max_latency_ms: 0.0,
+            levels: vec![0; num_levels],
+            accuracy: 0.0,
+        }
+    }
+
+    fn add<C>(&mut self, profile: &Profile<C>, level: usize, bytes: usize, latency_ms: f64) {
+        let n = self.frames as f64;
+        self.frames += 1;
+        self.bytes += bytes;
+        self.mean_latency_ms = (self.mean_latency_ms * n + latency_ms) / (n + 1.0);
+        self.max_latency_ms = self.max_latency_ms.max(latency_ms);
+        if let Some(record) = profile.records().get(level) {
+            self.levels[level] += 1;
+            self.accuracy = (self.accuracy * n + record.accuracy) / (n + 1.0);
+        }
+    }
+}
+
+impl fmt::Display for RunReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} frames ({} bytes), latency {:.1} ms (max {:.1}), accuracy {:.4}, levels {:?}",
+            self.frames,
+            self.bytes,
+            self.mean_latency_ms,
+            self.max_latency_ms,
+            self.accuracy,
+            self.levels
+        )
+    }
+}
+
+pub(crate) fn loopback_setting(port: u16) -> Setting {
+    let setting = format!(
+        "server = \"127.0.0.1\"\n\
+         port = {}\n\
+         profile_path = \"\"\n\
+         source_path = \"\"\n\
+         stat_path = \"\"\n\
+         analytics = false\n",
+        port
+    );
+    toml::from_str(&setting).expect("invalid loopback setting")
+}
+
+/// Streams `source` to an in-process server over localhost for `duration`,
+/// and reports the frames received. `profile` is the profile of `source`,
+/// used to score the levels received.
+pub fn run_loopback<C, S>(profile: &Profile<C>, source: S, duration: Duration) -> Result<RunReport>
+where
+    S: Source + 'static,
+{
+    let (port_tx, port_rx) = mpsc::channel();
+    let (event_tx, event_rx) = mpsc::channel();
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    let server = thread::spawn(move || -> Result<()> {
+        let mut core = Core::new()?;
+        let server = Server::bind(loopback_setting(0), &core.handle())?;
+        let _ = port_tx.send(server.local_addr().port());
+        let events = server.incoming_events().for_each(move |event| {
+            let _ = event_tx.send(event);
+            Ok(())
+        });
+        // ends when stopped; the events never end by themselves
+        let _ = core.run(events.select2(stop_rx));
+        Ok(())
+    });
+    let port = match port_rx.recv() {
+        Ok(port) => port,
+        Err(_) => {
+            return match server.join() {
+                Ok(Err(e)) => Err(e),
+                _ => bail!(ErrorKind::RemotePeer),
+            }
+        }
+    };
+
+    let cancel = Cancellation::new();
+    let timeout = cancel.clone();
+    thread::spawn(move || {
+        thread::sleep(duration);
+        timeout.cancel();
+    });
+    let result = Client::new(loopback_setting(port)).stream(source, cancel);
+
+    let mut report = RunReport::new(profile.len());
+    if result.is_ok() {
+        while let Ok(event) = event_rx.recv_timeout(DRAIN_TIMEOUT) {
+            match event {
+                ServerEvent::Frame { latency_ms, datum, .. } => match datum.datum_type() {
+                    AsDatumType::Live(level, _) | AsDatumType::Reference(level, _) => {
+                        report.add(profile, level, datum.net_len(), latency_ms)
+                    }
+                    _ => {}
+                },
+                ServerEvent::Disconnected { .. } => break,
+                _ => {}
+            }
+        }
+    }
+    let _ = stop_tx.send(());
+    let _ = server.join();
+    result.map(|_| report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {Adapt, AsDatum, Bandwidth, Experiment, Paced, Record, SimpleProfile};
+    use futures::sync::mpsc::unbounded;
+
+    /// A source of 10 frames per second of the size of its level.
+    struct Synthetic {
+        profile: SimpleProfile,
+        kbps: Vec<f64>,
+        frame: usize,
+    }
+
+    impl Adapt for Synthetic {
+        fn adapt(&mut self, bandwidth: Bandwidth) {
+            self.profile.adjust_level(bandwidth);
+        }
+        fn dec_degradation(&mut self) {
+            self.profile.advance_level();
+        }
+        fn set_level(&mut self, level: usize) {
+            self.profile.set_level(level);
+        }
+        fn period_in_ms(&self) -> u64 {
+            100
+        }
+        fn current_level(&self) -> usize {
+            self.profile.current()
+        }
+        fn simple_profile(&self) -> SimpleProfile {
+            self.profile.clone()
+        }
+    }
+
+    impl Experiment for Synthetic {
+        fn next_datum(&mut self) -> (usize, usize) {
+            self.frame += 1;
+            let kbps = self.kbps[self.profile.current()];
+            ((kbps * 100.0 / 8.0) as usize, self.frame)
+        }
+    }
+
+    fn synthetic() -> (Profile<usize>, Synthetic) {
+        let records = (0..3)
+            .map(|i| {
+                let rate = Bandwidth::from_kbps(100.0 * (i + 1) as f64);
+                Record::new(rate, i, 0.6 + 0.1 * i as f64)
+            })
+            .collect();
+        let profile = Profile::_with_vec(records);
+        let source = Synthetic {
+            profile: profile.simplify(),
+            kbps: profile.iter().map(|r| r.bandwidth.kbps()).collect(),
+            frame: 0,
+        };
+        (profile, source)
+    }
+
+    #[test]
+    fn test_loopback() {
+        let (profile, source) = synthetic();
+        let report = run_loopback(&profile, Paced::new(source), Duration::from_millis(1500)).unwrap();
+        assert!(report.frames >= 10, "{}", report);
+        assert_eq!(report.levels.iter().sum::<usize>(), report.frames);
+        assert!(report.accuracy > 0.59, "{}", report);
+    }
+
+    #[test]
+    fn test_downlink() {
+        let (port_tx, port_rx) = mpsc::channel();
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let server = thread::spawn(move || {
+            let mut core = Core::new().unwrap();
+            let mut server = Server::bind(loopback_setting(0), &core.handle()).unwrap();
+            server.set_downlink(|_| Some(Box::new(Paced::new(synthetic().1)) as Box<dyn Source>));
+            port_tx.send(server.local_addr().port()).unwrap();
+            let events = server.incoming_events().for_each(|_| Ok(()));
+            let _ = core.run(events.select2(stop_rx));
+        });
+
+        let mut client = Client::new(loopback_setting(port_rx.recv().unwrap()));
+        let (tx, rx) = unbounded::<AsDatum>();
+        client.set_downlink(tx);
+        let cancel = Cancellation::new();
+        let timeout = cancel.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(1000));
+            timeout.cancel();
+        });
+        client.stream(Paced::new(synthetic().1), cancel).unwrap();
+        drop(client);
+        let _ = stop_tx.send(());
+        server.join().unwrap();
+
+        let received = rx.wait().collect::<::std::result::Result<Vec<_>, _>>().unwrap();
+        assert!(received.len() >= 3, "{} frames streamed back", received.len());
+        let live = |d: &AsDatum| matches!(d.datum_type(), AsDatumType::Live(..));
+        assert!(received.iter().all(live));
+    }
+}
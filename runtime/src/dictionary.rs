@@ -0,0 +1,387 @@
+//! Dictionary compression of live frames.
+//!
+//! Structured payloads (e.g., sensor telemetry) repeat most of their bytes
+//! from one frame to the next, but each frame is too small to compress well
+//! on its own. With `compression` set, the client trains a zstd dictionary on
+//! the first frames of each connection, sends it to the server in a
+//! `Dictionary` datum, and compresses the payload of every later live frame
+//! with it. The server installs the dictionary and restores the payloads
+//! before anything else sees them.
+//!
+//! Only live frames and the datums of sub-streams are compressed, so that
+//! the server can tell from the datum type alone (the wire format need not
+//! carry `FrameFlags`); backfill and redundant copies are sent as produced.
+//! Should compressing a frame fail, the client sends an empty `Dictionary`,
+//! which uninstalls the server's, and sends the rest uncompressed. Restored
+//! payloads are bounded by the server's `FrameLimits` and memory budget.
+//! Requires the `compression` feature on both ends; without it, the client
+//! sends frames uncompressed and the server rejects compressed ones as
+//! malformed.
+
+#[cfg(feature = "compression")]
+use super::FrameFlags;
+use super::{AsDatum, AsDatumType, FrameLimits};
+use errors::*;
+use super::memory::Account;
+
+/// Largest payload a compressed frame may restore to.
+#[cfg(feature = "compression")]
+const MAX_PAYLOAD: usize = 64 << 20;
+
+/// When and how to compress.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct CompressionConfig {
+    /// Live frames sent uncompressed and trained on.
+    pub training_frames: usize,
+
+    /// Largest dictionary (KB).
+    pub dictionary_kb: usize,
+
+    /// zstd compression level (1 to 22).
+    pub level: i32,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            training_frames: 100,
+            dictionary_kb: 16,
+            level: 3,
+        }
+    }
+}
+
+/// Returns true if `datum` is compressed once a dictionary is in use.
+fn is_compressed(datum: &AsDatum) -> bool {
+    matches!(datum.datum_type(), AsDatumType::Live(..) | AsDatumType::Sub(..))
+}
+
+/// The client end: trains, then compresses.
+pub struct Compressor {
+    #[cfg(feature = "compression")]
+    config: CompressionConfig,
+    #[cfg(feature = "compression")]
+    state: State,
+}
+
+#[cfg(feature = "compression")]
+enum State {
+    /// The payloads of the live frames so far.
+    Training(Vec<Vec<u8>>),
+    Trained(Dictionary),
+    Failed,
+}
+
+#[cfg(feature = "compression")]
+struct Dictionary {
+    compressor: ::zstd::bulk::Compressor<'static>,
+    decompressor: Decompressor,
+}
+
+impl ::std::fmt::Debug for Compressor {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.write_str("Compressor")
+    }
+}
+
+#[cfg(feature = "compression")]
+impl Compressor {
+    /// Creates a compressor that starts training with the next live frame.
+    pub fn new(config: CompressionConfig) -> Option<Compressor> {
+        Some(Compressor {
+            config,
+            state: State::Training(Vec::with_capacity(config.training_frames)),
+        })
+    }
+
+    fn train(&self, samples: &[Vec<u8>]) -> Result<(Dictionary, AsDatum)> {
+        let dict = ::zstd::dict::from_samples(samples, self.config.dictionary_kb * 1024)?;
+        let compressor = ::zstd::bulk::Compressor::with_dictionary(self.config.level, &dict)?;
+        let mut decompressor = Decompressor::default();
+        let datum = AsDatum::dictionary(dict);
+        decompressor.install(&datum)?;
+        Ok((Dictionary { compressor, decompressor }, datum))
+    }
+
+    /// Returns the datums to send for `datum`, in order: `datum`, compressed
+    /// once trained, preceded by the dictionary when training completes. If
+    /// training or compression fails, frames are sent uncompressed.
+    pub fn compress(&mut self, mut datum: AsDatum) -> Vec<AsDatum> {
+        if !is_compressed(&datum) {
+            return vec![datum];
+        }
+        let samples = match self.state {
+            State::Trained(ref mut trained) => match trained.compressor.compress(&datum.mem) {
+                Ok(mem) => {
+                    datum.mem = mem;
+                    datum.update_len();
+                    datum.flags |= FrameFlags::COMPRESSED;
+                    return vec![datum];
+                }
+                Err(e) => {
+                    warn!("sending frames uncompressed, failed to compress {}: {}", datum, e);
+                    // an empty dictionary uninstalls the server's
+                    self.state = State::Failed;
+                    return vec![AsDatum::dictionary(Vec::new()), datum];
+                }
+            },
+            State::Training(ref mut samples) if samples.len() < self.config.training_frames => {
+                samples.push(datum.mem.clone());
+                return vec![datum];
+            }
+            State::Training(ref mut samples) => ::std::mem::take(samples),
+            State::Failed => return vec![datum],
+        };
+        match self.train(&samples) {
+            Ok((trained, dictionary)) => {
+                let (bytes, frames) = (dictionary.mem.len(), samples.len());
+                info!("trained a {} B dictionary on {} frames", bytes, frames);
+                self.state = State::Trained(trained);
+                let mut datums = vec![dictionary];
+                datums.extend(self.compress(datum));
+                datums
+            }
+            Err(e) => {
+                warn!("sending frames uncompressed, failed to train a dictionary: {}", e);
+                self.state = State::Failed;
+                vec![datum]
+            }
+        }
+    }
+
+    /// Restores the payload of a datum returned by `compress`, e.g., to
+    /// spool it for another connection.
+    pub fn restore(&mut self, datum: AsDatum) -> AsDatum {
+        match self.state {
+            State::Trained(ref mut trained) => {
+                trained.decompressor.decompress(datum.clone()).unwrap_or(datum)
+            }
+            _ => datum,
+        }
+    }
+}
+
+#[cfg(not(feature = "compression"))]
+impl Compressor {
+    /// Without the `compression` feature, frames are sent uncompressed.
+    pub fn new(_: CompressionConfig) -> Option<Compressor> {
+        warn!("`compression` is set but the `compression` feature is disabled");
+        None
+    }
+
+    /// Returns `datum`.
+    pub fn compress(&mut self, datum: AsDatum) -> Vec<AsDatum> {
+        vec![datum]
+    }
+
+    /// Returns `datum`.
+    pub fn restore(&mut self, datum: AsDatum) -> AsDatum {
+        datum
+    }
+}
+
+/// The server end: restores the live frames of a connection once its
+/// `Dictionary` arrived.
+pub struct Decompressor {
+    #[cfg(feature = "compression")]
+    decompressor: Option<::zstd::bulk::Decompressor<'static>>,
+    #[cfg(not(feature = "compression"))]
+    installed: bool,
+    #[cfg(feature = "compression")]
+    limits: FrameLimits,
+    #[cfg(feature = "compression")]
+    account: Option<Account>,
+}
+
+impl Default for Decompressor {
+    fn default() -> Self {
+        Decompressor::new(FrameLimits::default(), None)
+    }
+}
+
+impl ::std::fmt::Debug for Decompressor {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.write_str("Decompressor")
+    }
+}
+
+impl Decompressor {
+    /// Creates a decompressor restoring payloads within `limits`, charged
+    /// to `account` (e.g., the `Decode` component of a memory budget) while
+    /// they are restored.
+    pub fn new(limits: FrameLimits, account: Option<Account>) -> Decompressor {
+        #[cfg(not(feature = "compression"))]
+        let _ = (limits, account);
+        Decompressor {
+            #[cfg(feature = "compression")]
+            decompressor: None,
+            #[cfg(not(feature = "compression"))]
+            installed: false,
+            #[cfg(feature = "compression")]
+            limits,
+            #[cfg(feature = "compression")]
+            account,
+        }
+    }
+
+    /// Installs the dictionary of a `Dictionary` datum, or restores the
+    /// payload of a compressed frame. Other datums are returned as is.
+    pub fn decode(&mut self, datum: AsDatum) -> Result<AsDatum> {
+        if let AsDatumType::Dictionary = datum.datum_type() {
+            self.install(&datum).chain_err(|| ErrorKind::DecodeError)?;
+            return Ok(datum);
+        }
+        self.decompress(datum).chain_err(|| ErrorKind::DecodeError)
+    }
+}
+
+#[cfg(feature = "compression")]
+impl Decompressor {
+    fn install(&mut self, datum: &AsDatum) -> Result<()> {
+        self.decompressor = if datum.mem.is_empty() {
+            None
+        } else {
+            Some(::zstd::bulk::Decompressor::with_dictionary(&datum.mem)?)
+        };
+        Ok(())
+    }
+
+    fn decompress(&mut self, mut datum: AsDatum) -> Result<AsDatum> {
+        let decompressor = match self.decompressor {
+            Some(ref mut d) if is_compressed(&datum) => d,
+            _ => return Ok(datum),
+        };
+        if !datum.mem.is_empty() {
+            // the size is in the zstd frame header
+            let max = self.limits.max_payload.map_or(MAX_PAYLOAD, |max| max.min(MAX_PAYLOAD));
+            let size = match ::zstd::zstd_safe::get_frame_content_size(&datum.mem) {
+                Ok(Some(size)) if size <= max as u64 => size as usize,
+                Ok(Some(size)) => bail!(ErrorKind::PayloadTooLarge(size as usize, max)),
+                _ => {
+                    let reason = "no content size in a compressed frame";
+                    bail!(ErrorKind::InvalidConfig(reason.into()))
+                }
+            };
+            match self.account {
+                Some(ref account) if !account.try_reserve(size) => {
+                    bail!(ErrorKind::OverMemoryBudget(size))
+                }
+                _ => {}
+            }
+            let restored = decompressor.decompress(&datum.mem, size);
+            if let Some(ref account) = self.account {
+                account.release(size);
+            }
+            datum.mem = restored?;
+        }
+        datum.update_len();
+        datum.flags.remove(FrameFlags::COMPRESSED);
+        self.limits.check(&datum)?;
+        Ok(datum)
+    }
+}
+
+#[cfg(not(feature = "compression"))]
+impl Decompressor {
+    fn install(&mut self, datum: &AsDatum) -> Result<()> {
+        self.installed = !datum.mem.is_empty();
+        if self.installed {
+            bail!(ErrorKind::InvalidConfig("the `compression` feature is disabled".into()))
+        }
+        Ok(())
+    }
+
+    fn decompress(&mut self, datum: AsDatum) -> Result<AsDatum> {
+        if self.installed && is_compressed(&datum) {
+            bail!(ErrorKind::InvalidConfig("the `compression` feature is disabled".into()))
+        }
+        Ok(datum)
+    }
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod tests {
+    use super::*;
+    use memory::{Component, MemoryBudget};
+
+    #[test]
+    fn test_train_and_compress() {
+        let config = CompressionConfig {
+            training_frames: 50,
+            ..CompressionConfig::default()
+        };
+        let mut compressor = Compressor::new(config).unwrap();
+        let reading = |i: usize| {
+            let json = format!(
+                concat!(
+                    r#"{{"sensor":"imu-{}","seq":{},"accel":[0.0{},9.81,0.1],"#,
+                    r#""gyro":[0.0,0.0{},0.0],"status":"ok"}}"#
+                ),
+                i % 4,
+                i,
+                i % 10,
+                i % 7
+            );
+            AsDatum::new(0, i, json.into_bytes())
+        };
+
+        let originals = (0..100).map(reading).collect::<Vec<_>>();
+        let mut sent = Vec::new();
+        for original in &originals {
+            sent.extend(compressor.compress(original.clone()));
+        }
+        sent.push(AsDatum::barrier(1));
+        let types = sent.iter().map(AsDatum::datum_type).collect::<Vec<_>>();
+        assert_eq!(
+            types[49..52],
+            [AsDatumType::Live(0, 49), AsDatumType::Dictionary, AsDatumType::Live(0, 50)]
+        );
+
+        // trained frames are much smaller
+        let (before, after) = (reading(99).mem.len(), sent[100].mem.len());
+        assert!(after * 2 < before, "{} vs {}", after, before);
+        assert!(sent[100].flags().contains(FrameFlags::COMPRESSED));
+        assert_eq!(compressor.restore(sent[100].clone()).mem, reading(99).mem);
+
+        let (dictionary, compressed) = (sent[50].clone(), sent[100].clone());
+        let mut decompressor = Decompressor::default();
+        let received = sent
+            .into_iter()
+            .map(|d| decompressor.decode(d).unwrap())
+            .collect::<Vec<_>>();
+        for (i, original) in originals.iter().enumerate() {
+            let datum = &received[if i < 50 { i } else { i + 1 }];
+            assert_eq!(datum.mem, original.mem);
+            // the length of the datum sent, stamped with its time
+            assert_eq!(datum.len(), original.len());
+        }
+        assert_eq!(received.last().unwrap().datum_type(), AsDatumType::Barrier(1));
+
+        // garbage after a dictionary is malformed
+        match decompressor.decode(AsDatum::new(0, 100, vec![1, 2, 3])) {
+            Err(Error(ErrorKind::DecodeError, _)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+
+        // restored payloads stay within the limits and the memory budget
+        let limits = FrameLimits {
+            max_payload: Some(16),
+            ..FrameLimits::default()
+        };
+        let mut limited = Decompressor::new(limits, None);
+        limited.decode(dictionary.clone()).unwrap();
+        assert!(limited.decode(compressed.clone()).is_err());
+        let budget = MemoryBudget::new(16);
+        let account = budget.account(Component::Decode);
+        let mut tight = Decompressor::new(FrameLimits::default(), Some(account));
+        tight.decode(dictionary).unwrap();
+        assert!(tight.decode(compressed).is_err());
+        assert!(budget.refused(Component::Decode) > 0);
+
+        // an empty dictionary uninstalls it
+        decompressor.decode(AsDatum::dictionary(Vec::new())).unwrap();
+        let plain = decompressor.decode(reading(7)).unwrap();
+        assert_eq!(plain.mem, reading(7).mem);
+    }
+}
@@ -0,0 +1,74 @@
+//! Zero-configuration discovery on the local network (behind the `mdns`
+//! feature).
+//!
+//! The server advertises an `_awstream._tcp` DNS-SD service over mDNS, and
+//! clients browse for it, so lab and demo deployments don't need hard-coded
+//! addresses.
+
+use errors::*;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// The DNS-SD service type of AWStream servers.
+pub const SERVICE_TYPE: &str = "_awstream._tcp.local.";
+
+fn discovery<E: ::std::fmt::Display>(e: E) -> Error {
+    Error::from_kind(ErrorKind::Discovery(e.to_string()))
+}
+
+/// An active advertisement; the service is withdrawn when dropped.
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+/// Advertises a server named `name` listening on `port`.
+pub fn advertise(name: &str, port: u16) -> Result<Advertisement> {
+    let daemon = ServiceDaemon::new().map_err(discovery)?;
+    let host = format!("{}.local.", name);
+    let info = ServiceInfo::new(SERVICE_TYPE, name, &host, "", port, None)
+        .map_err(discovery)?
+        .enable_addr_auto();
+    let fullname = info.get_fullname().to_string();
+    daemon.register(info).map_err(discovery)?;
+    info!("advertising {} on port {}", fullname, port);
+    Ok(Advertisement { daemon, fullname })
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        let _ = self.daemon.unregister(&self.fullname);
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// Browses for servers during `timeout` and returns their addresses, in the
+/// order they were resolved.
+pub fn discover(timeout: Duration) -> Result<Vec<SocketAddr>> {
+    let daemon = ServiceDaemon::new().map_err(discovery)?;
+    let events = daemon.browse(SERVICE_TYPE).map_err(discovery)?;
+    let deadline = Instant::now() + timeout;
+    let mut found = Vec::new();
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        match events.recv_timeout(deadline - now) {
+            Ok(ServiceEvent::ServiceResolved(info)) => {
+                for ip in info.get_addresses() {
+                    let addr = SocketAddr::new(*ip, info.get_port());
+                    if !found.contains(&addr) {
+                        debug!("discovered {} at {}", info.get_fullname(), addr);
+                        found.push(addr);
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    let _ = daemon.shutdown();
+    Ok(found)
+}
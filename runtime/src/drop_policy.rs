@@ -0,0 +1,151 @@
+//! Which frames the send queue drops when it runs out of room.
+//!
+//! A `DropPolicy` chooses victims among the frames of a `SendQueue` (see
+//! `send_queue`). The built-ins are selected with `drop_policy` in the
+//! setting, or any policy with `Client::set_drop_policy`.
+
+use super::{AsDatum, AsDatumType, FrameFlags};
+use super::prediction::KEY_FACTOR;
+use super::send_queue::{FrameId, SendQueue};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Chooses the frames to drop from a send queue.
+pub trait DropPolicy: Send {
+    /// Returns frames of `queue` (see `SendQueue::frames`) whose bytes add up
+    /// to at least `needed_bytes`, if it can. The newest frame is the one
+    /// that was just queued; if the victims fall short, it is dropped too.
+    fn select_victims(&mut self, queue: &SendQueue, needed_bytes: usize) -> Vec<FrameId>;
+}
+
+impl fmt::Debug for dyn DropPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("DropPolicy")
+    }
+}
+
+/// Takes frames in order until they add up to `needed`.
+fn take_until<'a, I: Iterator<Item = (FrameId, &'a AsDatum)>>(frames: I, needed: usize) -> Vec<FrameId> {
+    let mut freed = 0;
+    frames
+        .take_while(|&(_, d)| {
+            let more = freed < needed;
+            freed += d.net_len();
+            more
+        })
+        .map(|(id, _)| id)
+        .collect()
+}
+
+/// Drops the newest frames, starting with the one just queued.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TailDrop;
+
+impl DropPolicy for TailDrop {
+    fn select_victims(&mut self, queue: &SendQueue, needed_bytes: usize) -> Vec<FrameId> {
+        take_until(queue.frames().rev(), needed_bytes)
+    }
+}
+
+/// Drops the oldest frames, which are the most stale.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OldestFirst;
+
+impl DropPolicy for OldestFirst {
+    fn select_victims(&mut self, queue: &SendQueue, needed_bytes: usize) -> Vec<FrameId> {
+        take_until(queue.frames(), needed_bytes)
+    }
+}
+
+/// Drops padding first, then backfill, then live frames, oldest first
+/// within each.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LowestPriorityFirst;
+
+impl LowestPriorityFirst {
+    fn priority(datum: &AsDatum) -> u8 {
+        let flags = datum.flags();
+        if flags.contains(FrameFlags::PADDED) {
+            0
+        } else if flags.contains(FrameFlags::DROPPABLE) {
+            1
+        } else {
+            2
+        }
+    }
+}
+
+impl DropPolicy for LowestPriorityFirst {
+    fn select_victims(&mut self, queue: &SendQueue, needed_bytes: usize) -> Vec<FrameId> {
+        let mut frames = queue.frames().collect::<Vec<_>>();
+        frames.sort_by_key(|&(_, d)| Self::priority(d));
+        take_until(frames.into_iter(), needed_bytes)
+    }
+}
+
+/// Drops delta frames before keyframes, oldest first, since frames after a
+/// lost keyframe can't be decoded. As in `composition`, a frame is a
+/// keyframe if much larger than the other frames of its level queued.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KeyframePreserving;
+
+impl KeyframePreserving {
+    fn level(datum: &AsDatum) -> Option<usize> {
+        match datum.datum_type() {
+            AsDatumType::Live(level, _) | AsDatumType::Reference(level, _) => Some(level),
+            _ => None,
+        }
+    }
+}
+
+impl DropPolicy for KeyframePreserving {
+    fn select_victims(&mut self, queue: &SendQueue, needed_bytes: usize) -> Vec<FrameId> {
+        let mut sizes: HashMap<usize, (usize, usize)> = HashMap::new();
+        for (_, datum) in queue.frames() {
+            if let Some(level) = Self::level(datum) {
+                let entry = sizes.entry(level).or_default();
+                entry.0 += datum.len();
+                entry.1 += 1;
+            }
+        }
+        let is_key = |datum: &AsDatum| match Self::level(datum).map(|l| sizes[&l]) {
+            Some((total, count)) if count > 1 => {
+                let others = (total - datum.len()) as f64 / (count - 1) as f64;
+                datum.len() as f64 > KEY_FACTOR * others
+            }
+            _ => false,
+        };
+        let (key, delta): (Vec<_>, Vec<_>) = queue.frames().partition(|&(_, d)| is_key(d));
+        take_until(delta.into_iter().chain(key), needed_bytes)
+    }
+}
+
+/// The built-in drop policies.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DropPolicyKind {
+    /// `TailDrop`, the default.
+    #[default]
+    TailDrop,
+
+    /// `OldestFirst`.
+    OldestFirst,
+
+    /// `LowestPriorityFirst`.
+    LowestPriorityFirst,
+
+    /// `KeyframePreserving`.
+    KeyframePreserving,
+}
+
+impl DropPolicyKind {
+    /// Creates the policy.
+    pub fn into_policy(self) -> Box<dyn DropPolicy> {
+        match self {
+            DropPolicyKind::TailDrop => Box::new(TailDrop),
+            DropPolicyKind::OldestFirst => Box::new(OldestFirst),
+            DropPolicyKind::LowestPriorityFirst => Box::new(LowestPriorityFirst),
+            DropPolicyKind::KeyframePreserving => Box::new(KeyframePreserving),
+        }
+    }
+}
@@ -0,0 +1,354 @@
+//! The data connection of a client, on the application's reactor.
+//!
+//! `Client` owns its event loop, connects by itself and blocks until the
+//! stream ends. Applications that already run a reactor (and their own
+//! listeners) can instead hand an `Endpoint` a connected `TcpStream` and
+//! their `Handle`: it opens the session without blocking, sends datums
+//! through a `Sink` and yields the server's `Feedback` as a `Stream`.
+//!
+//! ```text
+//! let tcp = TcpStream::connect(&addr, &handle);
+//! let work = tcp.from_err().and_then(|tcp| Endpoint::builder().connect(tcp, &handle));
+//! let (frames, feedback) = core.run(work)?.split();
+//! ```
+//!
+//! `examples/endpoint.rs` streams frames this way next to a listener of the
+//! application. Adaptation is then left to the application: `Feedback`
+//! carries the server's congestion reports, and `bytes_sent` the throughput.
+//! To run the whole client pipeline instead (driving a `Source`, adapting
+//! its level and attaching the control connection) on the same reactor,
+//! hand the connection to `Client::stream_on`:
+//!
+//! ```text
+//! let work = tcp.from_err().and_then(|tcp| client.stream_on(source, cancel, tcp, &handle));
+//! core.run(work)?;
+//! ```
+
+use super::{AsCodec, AsDatum, AsDatumType, CloseReason, Directive, Hint, QualityReport,
+            ReceiverReport, WireFormat};
+use super::admission::{Admission, AdmissionRequest};
+use super::catalog::{ClientIdentity, HostedProfile};
+use super::socket::{self, FramedRead, Socket, SocketHandle, TcpHalf};
+use errors::*;
+use futures::{Future, Sink, Stream, future};
+use futures::future::Loop;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+
+/// Datums buffered on their way to the socket before `Sink` pushes back.
+const SEND_CAPACITY: usize = 64;
+
+/// The data connection from the server.
+pub(crate) type Remote = FramedRead<TcpHalf, AsCodec>;
+
+/// What the server sends back to a client.
+#[derive(Debug, Clone)]
+pub enum Feedback {
+    /// The path to the server is congested.
+    Congestion(ReceiverReport),
+
+    /// The quality of the analytics of a frame or a level.
+    Quality(QualityReport),
+
+    /// What the analytics are interested in.
+    Hint(Hint),
+
+    /// An operator's directive.
+    Directive(Directive),
+
+    /// A frame the server streams back.
+    Downlink(AsDatum),
+
+    /// The server is closing the connection on purpose.
+    Closed(CloseReason),
+}
+
+impl Feedback {
+    /// Decodes `datum`; other datums, and malformed ones, are `None`.
+    pub fn from_datum(datum: AsDatum) -> Option<Feedback> {
+        let decoded = match datum.datum_type() {
+            AsDatumType::ReceiverCongest => ReceiverReport::from_mem(&datum.mem).map(Feedback::Congestion),
+            AsDatumType::Quality => QualityReport::from_mem(&datum.mem).map(Feedback::Quality),
+            AsDatumType::Hint => Hint::from_mem(&datum.mem).map(Feedback::Hint),
+            AsDatumType::Directive => Directive::from_mem(&datum.mem).map(Feedback::Directive),
+            AsDatumType::Close => CloseReason::from_mem(&datum.mem).map(Feedback::Closed),
+            AsDatumType::Live(..) | AsDatumType::Reference(..) => Ok(Feedback::Downlink(datum)),
+            _ => return None,
+        };
+        match decoded {
+            Ok(feedback) => Some(feedback),
+            Err(e) => {
+                warn!("malformed feedback: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// What the server replied to a `Hello`.
+pub(crate) struct Welcomed {
+    /// The socket `Hello` was sent on.
+    pub socket: Socket,
+    /// The remaining data connection.
+    pub remote: Remote,
+    /// The session token.
+    pub session: u64,
+    /// The profile hosted by the server, if any.
+    pub hosted: Option<HostedProfile>,
+    /// The highest level the session was admitted at, if the server holds
+    /// sessions within a budget.
+    pub granted: Option<usize>,
+}
+
+/// Sends `Hello` as `identity`, asking to be admitted as `request` says if
+/// set, and waits for the server's `Welcome`. Fails with `AdmissionRejected`
+/// if the server turns the session down.
+pub(crate) fn handshake(
+    socket: Socket,
+    tcp_read: TcpHalf,
+    token: Option<u64>,
+    identity: &ClientIdentity,
+    request: Option<&AdmissionRequest>,
+    format: WireFormat,
+) -> Box<dyn Future<Item = Welcomed, Error = Error>> {
+    let hello = match request {
+        Some(request) => AsDatum::hello_requesting(token, identity, request),
+        None => AsDatum::hello_as(token, identity),
+    };
+    let hello = match hello {
+        Ok(hello) => hello,
+        Err(e) => return Box::new(::futures::future::err(e)),
+    };
+    // an `Admission` precedes `Welcome` if the server judged the request
+    let requested = request.map(|r| r.level);
+    let remote = FramedRead::new(tcp_read, AsCodec::new(format));
+    let welcome = future::loop_fn((remote, None), move |(remote, granted)| {
+        remote.into_future().map_err(|(e, _)| e).and_then(move |(first, remote)| {
+            let datum = match first {
+                Some(datum) => datum,
+                None => bail!(ErrorKind::RemotePeer),
+            };
+            match datum.datum_type() {
+                AsDatumType::Welcome(session) => {
+                    info!("session {:x}", session);
+                    Ok(Loop::Break((remote, session, datum.hosted_profile()?, granted)))
+                }
+                AsDatumType::Admission => match datum.admission_answer()? {
+                    Some(Admission::Capped(level)) => {
+                        warn!("the server admitted the session up to level {}", level);
+                        Ok(Loop::Continue((remote, Some(level))))
+                    }
+                    Some(Admission::Admitted) => Ok(Loop::Continue((remote, requested))),
+                    Some(Admission::Rejected) => bail!(ErrorKind::AdmissionRejected),
+                    None => Ok(Loop::Continue((remote, granted))),
+                },
+                _ => bail!(ErrorKind::RemotePeer),
+            }
+        })
+    });
+    let work = socket.send(hello).join(welcome).map(|(socket, (remote, session, hosted, granted))| {
+        Welcomed {
+            socket,
+            remote,
+            session,
+            hosted,
+            granted,
+        }
+    });
+    Box::new(work)
+}
+
+/// Configures an `Endpoint`.
+#[derive(Debug, Clone, Default)]
+pub struct EndpointBuilder {
+    token: Option<u64>,
+    identity: ClientIdentity,
+    format: WireFormat,
+}
+
+impl EndpointBuilder {
+    /// Resumes the session of `token` (see `Endpoint::session_token`).
+    pub fn resume(mut self, token: u64) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    /// Introduces the client to the server as `identity`.
+    pub fn identity(mut self, identity: ClientIdentity) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    /// Frames datums with `format`, the server's `static_wire_format`: the
+    /// layout is not negotiated (see `wire`).
+    pub fn static_wire_format(mut self, format: WireFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Opens the session over `tcp`, already connected to the server. The
+    /// socket is driven on `handle` until the `Endpoint`'s sink is dropped.
+    pub fn connect(self, tcp: TcpStream, handle: &Handle) -> Box<dyn Future<Item = Endpoint, Error = Error>> {
+        if let Err(e) = self.format.validate() {
+            return Box::new(::futures::future::err(e));
+        }
+        let (tcp_read, tcp_write) = socket::split(tcp);
+        let (socket, bytes) = Socket::new(tcp_write, self.format);
+        let handle = handle.clone();
+        let welcome = handshake(socket, tcp_read, self.token, &self.identity, None, self.format);
+        let work = welcome.map(move |welcomed| {
+            let (sink, shared) = SocketHandle::new(welcomed.socket, SEND_CAPACITY);
+            handle.spawn(shared.map_err(|e| debug!("endpoint closed: {}", e)));
+            Endpoint {
+                session: welcomed.session,
+                hosted: welcomed.hosted,
+                bytes,
+                sink,
+                remote: welcomed.remote,
+            }
+        });
+        Box::new(work)
+    }
+}
+
+/// An open session over a connection the application established.
+pub struct Endpoint {
+    session: u64,
+    hosted: Option<HostedProfile>,
+    bytes: Arc<AtomicUsize>,
+    sink: SocketHandle,
+    remote: Remote,
+}
+
+impl Endpoint {
+    /// Configures an endpoint.
+    pub fn builder() -> EndpointBuilder {
+        EndpointBuilder::default()
+    }
+
+    /// The resumption token of the session.
+    pub fn session_token(&self) -> u64 {
+        self.session
+    }
+
+    /// The profile the server hosts for the client, if any.
+    pub fn hosted_profile(&self) -> Option<&HostedProfile> {
+        self.hosted.as_ref()
+    }
+
+    /// A counter of the bytes written to the connection, e.g., to estimate
+    /// the throughput.
+    pub fn bytes_sent(&self) -> Arc<AtomicUsize> {
+        self.bytes.clone()
+    }
+
+    /// Splits into a sink of datums (its clones share the connection fairly,
+    /// see `SocketHandle`) and the feedback of the server, which ends when
+    /// the server closes the connection.
+    pub fn split(self) -> (SocketHandle, Box<dyn Stream<Item = Feedback, Error = Error>>) {
+        let feedback = self.remote.filter_map(Feedback::from_datum);
+        (self.sink, Box::new(feedback))
+    }
+
+    /// Closes the connection on purpose, telling the server why (see
+    /// `SocketHandle::close` once split).
+    pub fn close(self, reason: CloseReason) -> Box<dyn Future<Item = (), Error = Error>> {
+        self.sink.close(reason)
+    }
+}
+
+impl ::std::fmt::Debug for Endpoint {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("Endpoint")
+            .field("session", &self.session)
+            .field("bytes_sent", &self.bytes.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+    use demo::loopback_setting;
+    use futures::stream;
+    use futures::sync::oneshot;
+    use server::{Server, ServerEvent};
+    use std::sync::mpsc;
+    use std::thread;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn test_endpoint_on_application_reactor() {
+        let (port_tx, port_rx) = mpsc::channel();
+        let (frames_tx, frames_rx) = oneshot::channel();
+        let server = thread::spawn(move || {
+            let mut core = Core::new().unwrap();
+            let server = Server::bind(loopback_setting(0), &core.handle()).unwrap();
+            port_tx.send(server.local_addr()).unwrap();
+            let frames = server
+                .incoming_events()
+                .take_while(|e| Ok(!matches!(*e, ServerEvent::Disconnected { .. })))
+                .filter(|e| matches!(*e, ServerEvent::Frame { .. }))
+                .collect();
+            let _ = frames_tx.send(core.run(frames).unwrap().len());
+        });
+
+        // the application's own reactor and connection
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let addr = port_rx.recv().unwrap();
+        let tcp = core.run(TcpStream::connect(&addr, &handle)).unwrap();
+        let endpoint = core.run(Endpoint::builder().connect(tcp, &handle)).unwrap();
+        assert!(endpoint.hosted_profile().is_none());
+        let bytes = endpoint.bytes_sent();
+        let (sink, _feedback) = endpoint.split();
+        let frames = stream::iter_ok::<_, Error>((0..5).map(|i| AsDatum::new(0, i, vec![0; 100])));
+        // dropping the sink closes the connection
+        let _ = core.run(sink.send_all(frames)).unwrap();
+        assert_eq!(core.run(frames_rx).unwrap(), 5);
+        assert!(bytes.load(Ordering::SeqCst) > 500);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_close_with_reason() {
+        let (port_tx, port_rx) = mpsc::channel();
+        let (closed_tx, closed_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut core = Core::new().unwrap();
+            let mut setting = loopback_setting(0);
+            setting.idle_timeout_ms = Some(200);
+            let server = Server::bind(setting, &core.handle()).unwrap();
+            let stats = server.stats();
+            port_tx.send(server.local_addr()).unwrap();
+            let events = server.incoming_events().for_each(|e| {
+                if let ServerEvent::Disconnected { reason, .. } = e {
+                    closed_tx.send((reason, reason.map(|r| stats.closed(r)))).unwrap();
+                }
+                Ok(())
+            });
+            core.run(events).unwrap();
+        });
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let addr = port_rx.recv().unwrap();
+        let tcp = core.run(TcpStream::connect(&addr, &handle)).unwrap();
+        let endpoint = core.run(Endpoint::builder().connect(tcp, &handle)).unwrap();
+        let (_sink, feedback) = endpoint.split();
+        // nothing is sent: the server says why it hangs up
+        let feedback = core.run(feedback.collect()).unwrap();
+        let last = feedback.last();
+        assert!(matches!(last, Some(&Feedback::Closed(CloseReason::Idle))), "{:?}", feedback);
+        assert_eq!(closed_rx.recv().unwrap(), (Some(CloseReason::Idle), Some(1)));
+
+        // and the application tells the server why it hangs up
+        let tcp = core.run(TcpStream::connect(&addr, &handle)).unwrap();
+        let endpoint = core.run(Endpoint::builder().connect(tcp, &handle)).unwrap();
+        let (sink, feedback) = endpoint.split();
+        core.run(sink.close(CloseReason::Finished).and_then(|_| feedback.collect())).unwrap();
+        assert_eq!(closed_rx.recv().unwrap(), (Some(CloseReason::Finished), Some(1)));
+    }
+}
@@ -1,44 +1,143 @@
 //! Error types for AWStream.
+#![allow(deprecated)]
 
-/// Creates the Error, ErrorKind, ResultExt, and Result types
+// Creates the Error, ErrorKind, ResultExt, and Result types
 error_chain!{
     errors {
+        /// The source failed to produce data.
         SourceData {
             description("error in generating source data")
         }
+        /// Reports from the peer couldn't be received.
         RemotePeer {
             description("error in receiving reports from peer")
         }
+        /// The control plane failed.
         ControlPlane {
             description("error in control plane")
         }
+        /// The data plane failed.
         DataPlane {
             description("error in data plane communication")
         }
+        /// A reply couldn't be sent to the client.
         ReplyChannel {
             description("error in replying to client")
         }
+        /// Data couldn't be encoded.
         EncodeError {
             description("error in encoding the data")
         }
+        /// Data couldn't be decoded.
         DecodeError {
             description("error in decoding the data")
         }
+        /// A lock was poisoned by a panicking thread.
         SyncPoisonError(t: String) {
         }
+        /// Tables composed into a profile disagree.
+        ProfileConflict(reason: String) {
+            description("conflicting entries when composing a profile")
+            display("conflicting profile entries: {}", reason)
+        }
+        /// Tables composed into a profile lack entries.
+        ProfileIncomplete(reason: String) {
+            description("missing entries when composing a profile")
+            display("incomplete profile: {}", reason)
+        }
+        /// A profile file doesn't match the columns of its config.
+        ProfileSchema(reason: String) {
+            description("a profile file doesn't match the layout of its config")
+            display("profile doesn't match the schema: {}", reason)
+        }
+        /// A configuration or profile is invalid.
+        InvalidConfig(reason: String) {
+            description("invalid configuration")
+            display("invalid configuration: {}", reason)
+        }
+        /// The media backend of a source failed.
+        SourceBackend(reason: String) {
+            description("error in the media backend of a source")
+            display("source backend error: {}", reason)
+        }
+        /// Too many malformed frames arrived within the tolerance window.
+        TooManyDecodeErrors(n: usize) {
+            description("too many malformed frames")
+            display("{} malformed frames within the tolerance window", n)
+        }
+        /// Frame metadata is over the limit.
+        MetadataTooLarge(size: usize, limit: usize) {
+            description("frame metadata over the limit")
+            display("frame metadata of {} bytes exceeds the limit of {}", size, limit)
+        }
+        /// A frame payload is over the limit.
+        PayloadTooLarge(size: usize, limit: usize) {
+            description("frame payload over the limit")
+            display("frame payload of {} bytes exceeds the limit of {}", size, limit)
+        }
+        /// The memory budget has no room left.
+        OverMemoryBudget(size: usize) {
+            description("the memory budget has no room left")
+            display("no room in the memory budget for {} bytes", size)
+        }
+        /// The peer closed the connection.
+        PeerClosed {
+            description("the peer closed the connection")
+        }
+        /// The peer closed the connection on purpose.
+        #[cfg(feature = "runtime")]
+        Closed(reason: ::CloseReason) {
+            description("the peer closed the connection on purpose")
+            display("the peer closed the connection: {}", reason)
+        }
+        /// A stage of the pipeline made no progress.
+        #[cfg(feature = "runtime")]
+        Wedged(stage: ::watchdog::Stage) {
+            description("a stage of the pipeline made no progress")
+            display("the {} made no progress", stage)
+        }
+        /// The route to the server moved to another type of uplink.
+        #[cfg(feature = "runtime")]
+        UplinkChanged(uplink: ::uplink::Uplink) {
+            description("the route to the server moved to another type of uplink")
+            display("the uplink changed to {}", uplink)
+        }
+        /// Local service discovery failed.
+        Discovery(reason: String) {
+            description("error in local service discovery")
+            display("discovery error: {}", reason)
+        }
+        /// A packet capture is malformed.
+        Capture(reason: String) {
+            description("malformed packet capture")
+            display("malformed packet capture: {}", reason)
+        }
+        /// A profile signature was rejected.
+        BadSignature(reason: String) {
+            description("profile signature rejected")
+            display("profile signature rejected: {}", reason)
+        }
+        /// The server has no capacity left for the session.
+        AdmissionRejected {
+            description("the server has no capacity left for the session")
+        }
+        /// The proxy failed to connect.
+        Proxy(reason: String) {
+            description("the proxy failed to connect")
+            display("proxy error: {}", reason)
+        }
     }
 
     foreign_links {
-        Io(::std::io::Error);
-        Timer(::tokio_timer::TimerError);
-        Bincode(::bincode::Error);
+        Io(::std::io::Error) #[doc = "An I/O error."];
+        Timer(::tokio_timer::TimerError) #[doc = "A timer error."] #[cfg(feature = "runtime")];
+        Bincode(::bincode::Error) #[doc = "A bincode (de)serialization error."];
+        Csv(::csv::Error) #[doc = "A CSV error."];
     }
 }
 
 impl<T> From<::std::sync::PoisonError<T>> for Error {
     fn from(err: ::std::sync::PoisonError<T>) -> Self {
-        use std::error::Error;
-
-        Self::from_kind(ErrorKind::SyncPoisonError(err.description().to_string()))
+        Self::from_kind(ErrorKind::SyncPoisonError(err.to_string()))
     }
 }
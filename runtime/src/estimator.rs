@@ -0,0 +1,117 @@
+//! Estimators of the outgoing rate, fed one delivery sample per monitor
+//! interval. The mean-based `ExponentialSmooth` reacts quickly but is
+//! optimistic under bursty cross traffic; `Quantile` trades some reactivity
+//! for robustness.
+
+use std::collections::VecDeque;
+
+/// Estimates a rate from a series of samples.
+pub trait Estimator: Send {
+    /// Adds a sample.
+    fn add(&mut self, sample: f64);
+
+    /// The current estimate (0 before any sample).
+    fn estimate(&self) -> f64;
+}
+
+/// Exponentially weighted moving average; `alpha` is the weight of the
+/// history.
+pub struct ExponentialSmooth {
+    val: f64,
+    alpha: f64,
+}
+
+impl ExponentialSmooth {
+    /// Creates an average weighting history by `alpha` (0 to 1).
+    pub fn new(alpha: f64) -> Self {
+        ExponentialSmooth {
+            val: 0.0,
+            alpha,
+        }
+    }
+
+    /// Adds a sample.
+    pub fn add(&mut self, sample: f64) {
+        self.val = self.val * self.alpha + sample * (1.0 - self.alpha);
+    }
+
+    /// The current average.
+    pub fn val(&self) -> f64 {
+        self.val
+    }
+}
+
+impl Estimator for ExponentialSmooth {
+    fn add(&mut self, sample: f64) {
+        ExponentialSmooth::add(self, sample)
+    }
+
+    fn estimate(&self) -> f64 {
+        self.val()
+    }
+}
+
+/// The `q`-th quantile (0 to 1) of the last `window` samples, e.g., the 25th
+/// percentile of per-interval delivery rates.
+pub struct Quantile {
+    samples: VecDeque<f64>,
+    window: usize,
+    q: f64,
+}
+
+impl Quantile {
+    /// Creates an estimator of the `q`-th quantile over `window` samples.
+    pub fn new(q: f64, window: usize) -> Quantile {
+        assert!((0.0..=1.0).contains(&q), "quantile out of range: {}", q);
+        assert!(window > 0);
+        Quantile {
+            samples: VecDeque::with_capacity(window),
+            window,
+            q,
+        }
+    }
+}
+
+impl Estimator for Quantile {
+    fn add(&mut self, sample: f64) {
+        if self.samples.len() == self.window {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    fn estimate(&self) -> f64 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+        let mut sorted = self.samples.iter().cloned().collect::<Vec<_>>();
+        sorted.sort_by(|a, b| a.partial_cmp(b).expect("failed to compare samples"));
+        // nearest rank
+        let rank = (self.q * (sorted.len() - 1) as f64).round() as usize;
+        sorted[rank]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantile_ignores_bursts() {
+        let mut mean = ExponentialSmooth::new(0.5);
+        let mut q = Quantile::new(0.25, 8);
+        assert_eq!(q.estimate(), 0.0);
+        for &s in &[100.0, 100.0, 900.0, 100.0, 1000.0, 100.0, 800.0, 100.0] {
+            Estimator::add(&mut mean, s);
+            q.add(s);
+        }
+        assert_eq!(q.estimate(), 100.0);
+        assert!(mean.estimate() > 300.0);
+
+        // old samples leave the window
+        for _ in 0..8 {
+            q.add(50.0);
+        }
+        assert_eq!(q.estimate(), 50.0);
+    }
+}
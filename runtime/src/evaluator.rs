@@ -0,0 +1,145 @@
+//! Accuracy of the analytics, estimated online by the server.
+//!
+//! Profiles carry the accuracy of each level as measured offline, which
+//! drifts as the scene changes. An `AccuracyEvaluator` set with
+//! `Server::set_evaluator` estimates it on the frames delivered instead (one
+//! in `evaluation_sample_every` of each session, `SAMPLE_EVERY` by default),
+//! on a pool of threads off the reactor: each estimate is logged and sent
+//! back to the client as a `QualityReport`, which moves the accuracy of its
+//! profile with `accuracy_feedback` set.
+
+use super::{AsDatum, QualityReport};
+use futures_cpupool::{CpuFuture, CpuPool};
+use middleware::ConnectionInfo;
+use std::cmp;
+use std::sync::Arc;
+
+/// Estimates the accuracy of the analytics on delivered frames.
+pub trait AccuracyEvaluator: Send + Sync {
+    /// The accuracy (between 0 and 1) of the analytics on `frame`, delivered
+    /// at `level`. `None` leaves the frame out, e.g., without ground truth.
+    fn evaluate(&self, conn: &ConnectionInfo, level: usize, frame: &AsDatum) -> Option<f64>;
+}
+
+impl<F> AccuracyEvaluator for F
+where
+    F: Fn(&ConnectionInfo, usize, &AsDatum) -> Option<f64> + Send + Sync,
+{
+    fn evaluate(&self, conn: &ConnectionInfo, level: usize, frame: &AsDatum) -> Option<f64> {
+        self(conn, level, frame)
+    }
+}
+
+/// How many frames are delivered per frame evaluated, by default: about one
+/// a second at 30 fps.
+pub const SAMPLE_EVERY: usize = 30;
+
+/// Runs an evaluator on `pool`, on one in `every` frames of a connection.
+pub(crate) struct Sampler {
+    evaluator: Arc<dyn AccuracyEvaluator>,
+    pool: CpuPool,
+    every: usize,
+    seen: usize,
+}
+
+impl Sampler {
+    pub fn new(evaluator: Arc<dyn AccuracyEvaluator>, pool: CpuPool, every: usize) -> Sampler {
+        Sampler {
+            evaluator,
+            pool,
+            every: cmp::max(every, 1),
+            seen: 0,
+        }
+    }
+
+    /// Evaluates `frame` if it is sampled, starting with the first one; the
+    /// estimate is ready once the pool ran the evaluator.
+    pub fn sample(
+        &mut self,
+        conn: &ConnectionInfo,
+        level: usize,
+        frame_num: usize,
+        frame: &AsDatum,
+    ) -> Option<CpuFuture<Option<QualityReport>, ()>> {
+        let sampled = self.seen.is_multiple_of(self.every);
+        self.seen += 1;
+        if !sampled {
+            return None;
+        }
+        let (evaluator, conn, frame) = (self.evaluator.clone(), conn.clone(), frame.clone());
+        Some(self.pool.spawn_fn(move || Ok(estimate(&*evaluator, &conn, level, frame_num, &frame))))
+    }
+}
+
+fn estimate(
+    evaluator: &dyn AccuracyEvaluator,
+    conn: &ConnectionInfo,
+    level: usize,
+    frame_num: usize,
+    frame: &AsDatum,
+) -> Option<QualityReport> {
+    let quality = evaluator.evaluate(conn, level, frame)?;
+    if !quality.is_finite() {
+        warn!("evaluator estimated accuracy {} for frame {}", quality, frame_num);
+        return None;
+    }
+    Some(QualityReport {
+        level,
+        frame_num: Some(frame_num),
+        quality: quality.clamp(0.0, 1.0),
+    })
+}
+
+#[cfg(all(test, feature = "client"))]
+mod tests {
+    use super::*;
+    use demo::loopback_setting;
+    use endpoint::{Endpoint, Feedback};
+    use errors::*;
+    use futures::{Sink, Stream, stream};
+    use server::Server;
+    use std::sync::mpsc;
+    use std::thread;
+    use tokio_core::net::TcpStream;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn test_estimates_reach_client() {
+        let (port_tx, port_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut core = Core::new().unwrap();
+            let mut setting = loopback_setting(0);
+            setting.evaluation_sample_every = Some(2);
+            let mut server = Server::bind(setting, &core.handle()).unwrap();
+            server.set_evaluator(|_: &ConnectionInfo, level: usize, _: &AsDatum| {
+                Some(level as f64 - 0.5)
+            });
+            port_tx.send(server.local_addr()).unwrap();
+            core.run(server.incoming_events().for_each(|_| Ok(()))).unwrap();
+        });
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let addr = port_rx.recv().unwrap();
+        let tcp = core.run(TcpStream::connect(&addr, &handle)).unwrap();
+        let endpoint = core.run(Endpoint::builder().connect(tcp, &handle)).unwrap();
+        let (sink, feedback) = endpoint.split();
+        let frames = (1..6).map(|i| AsDatum::new(i % 3, i, vec![0; 100]));
+        let frames = stream::iter_ok::<_, Error>(frames);
+        let sink = core.run(sink.send_all(frames)).unwrap();
+        let reports = feedback.filter_map(|f| match f {
+            Feedback::Quality(report) => Some(report),
+            _ => None,
+        });
+        let reports = core.run(reports.take(2).collect()).unwrap();
+        // frames 1 (level 1) and 3 (level 0, clamped) are sampled
+        // (evaluated on a pool, so in any order)
+        let mut estimates = reports
+            .iter()
+            .map(|r| (r.frame_num, r.level, r.quality))
+            .collect::<Vec<_>>();
+        estimates.sort_by_key(|e| e.0);
+        assert_eq!(estimates, vec![(Some(1), 1, 0.5), (Some(3), 0, 0.0)]);
+        drop(sink);
+    }
+}
@@ -0,0 +1,220 @@
+//! The experiment log records every frame delivered to the server as a CSV
+//! row, including the accuracy annotation attached by the source. Together
+//! with the bandwidth it makes delivered accuracy (not only delivered
+//! bitrate) computable per run.
+
+use csv;
+use errors::*;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, TryLockError};
+use super::Annotation;
+use super::rotation::{RotatingCsv, RotationPeriod};
+use super::tcp_info::TcpInfo;
+
+/// A frame delivered to the server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FrameEntry {
+    /// Receive time (ms since unix epoch).
+    pub time_ms: i64,
+
+    /// The client that sent the frame.
+    pub client: String,
+
+    /// The level the frame was produced at.
+    pub level: usize,
+
+    /// The frame number.
+    pub frame_num: usize,
+
+    /// Size on the wire (bytes).
+    pub bytes: usize,
+
+    /// Sender-to-receiver latency (ms).
+    pub latency_ms: f64,
+
+    /// Ground-truth accuracy attached by the source.
+    pub ground_truth: Option<f64>,
+
+    /// Confidence attached by the source.
+    pub confidence: Option<f64>,
+
+    /// Congestion window of the connection (segments), with `tcp-info`.
+    #[serde(default)]
+    pub cwnd: Option<u32>,
+
+    /// Smoothed RTT of the connection (ms), with `tcp-info`.
+    #[serde(default)]
+    pub srtt_ms: Option<f64>,
+
+    /// Retransmitted segments of the connection so far, with `tcp-info`.
+    #[serde(default)]
+    pub retransmits: Option<u32>,
+
+    /// Whether the frame was taken for a keyframe (see `composition`).
+    #[serde(default)]
+    pub key: Option<bool>,
+}
+
+impl FrameEntry {
+    /// Fills the annotation columns.
+    pub fn annotate(&mut self, annotation: Option<Annotation>) {
+        match annotation {
+            Some(Annotation::GroundTruth(a)) => self.ground_truth = Some(a),
+            Some(Annotation::Confidence(c)) => self.confidence = Some(c),
+            None => {}
+        }
+    }
+
+    /// Fills the transport columns from the last `TCP_INFO` sample.
+    pub fn transport(&mut self, info: Option<TcpInfo>) {
+        if let Some(info) = info {
+            self.cwnd = Some(info.cwnd);
+            self.srtt_ms = Some(info.srtt_ms);
+            self.retransmits = Some(info.retransmits);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Mean {
+    sum: f64,
+    count: usize,
+}
+
+impl Mean {
+    fn add(&mut self, v: Option<f64>) {
+        if let Some(v) = v {
+            self.sum += v;
+            self.count += 1;
+        }
+    }
+
+    fn get(&self) -> Option<f64> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.sum / self.count as f64)
+        }
+    }
+}
+
+struct Inner {
+    writer: Option<csv::Writer<Box<dyn Write + Send>>>,
+    rotation: Option<RotatingCsv>,
+    ground_truth: Mean,
+    confidence: Mean,
+}
+
+/// A shared handle to the experiment log. Clones write to the same file.
+#[derive(Clone)]
+pub struct ExperimentLog {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl ExperimentLog {
+    /// Creates (or truncates) the log at `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<ExperimentLog> {
+        let file = File::create(path)?;
+        Ok(ExperimentLog::from_writer(Box::new(file)))
+    }
+
+    /// Creates a log split into a file per `period` next to `path` (see
+    /// `rotation`). Frames go to the file of the epoch they were received in.
+    pub fn rotating<P: AsRef<Path>>(path: P, period: RotationPeriod) -> Result<ExperimentLog> {
+        let log = ExperimentLog::with_writer(None);
+        log.inner.lock()?.rotation = Some(RotatingCsv::new(path, period));
+        Ok(log)
+    }
+
+    /// Creates a log writing into `w`.
+    pub fn from_writer(w: Box<dyn Write + Send>) -> ExperimentLog {
+        ExperimentLog::with_writer(Some(csv::Writer::from_writer(w)))
+    }
+
+    /// Creates a log that writes nothing but still tracks delivered accuracy.
+    pub fn disabled() -> ExperimentLog {
+        ExperimentLog::with_writer(None)
+    }
+
+    fn with_writer(writer: Option<csv::Writer<Box<dyn Write + Send>>>) -> ExperimentLog {
+        let inner = Inner {
+            writer,
+            rotation: None,
+            ground_truth: Mean::default(),
+            confidence: Mean::default(),
+        };
+        ExperimentLog { inner: Arc::new(Mutex::new(inner)) }
+    }
+
+    /// Appends a delivered frame.
+    pub fn record(&self, entry: &FrameEntry) -> Result<()> {
+        let mut m = self.inner.lock()?;
+        m.ground_truth.add(entry.ground_truth);
+        m.confidence.add(entry.confidence);
+        if let Some(ref mut rotation) = m.rotation {
+            rotation.writer(entry.time_ms)?.serialize(entry)?;
+        }
+        if let Some(ref mut w) = m.writer {
+            w.serialize(entry)?;
+        }
+        Ok(())
+    }
+
+    /// Flushes buffered rows to the underlying file, completing the file of
+    /// a rotated log if its epoch is over at `now_ms` (ms since unix epoch,
+    /// on the clock frames are stamped with).
+    pub fn flush(&self, now_ms: i64) -> Result<()> {
+        let mut m = self.inner.lock()?;
+        if let Some(ref mut rotation) = m.rotation {
+            rotation.roll(now_ms)?;
+            rotation.flush()?;
+        }
+        if let Some(ref mut w) = m.writer {
+            w.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes buffered rows without waiting for a writer, e.g., from a
+    /// panic hook. Returns false if the log was busy.
+    pub fn try_flush(&self) -> Result<bool> {
+        let mut m = match self.inner.try_lock() {
+            Ok(m) => m,
+            Err(TryLockError::Poisoned(p)) => p.into_inner(),
+            Err(TryLockError::WouldBlock) => return Ok(false),
+        };
+        if let Some(ref mut rotation) = m.rotation {
+            rotation.flush()?;
+        }
+        if let Some(ref mut w) = m.writer {
+            w.flush()?;
+        }
+        Ok(true)
+    }
+
+    /// Mean ground-truth accuracy over delivered frames that carry one.
+    pub fn delivered_accuracy(&self) -> Result<Option<f64>> {
+        Ok(self.inner.lock()?.ground_truth.get())
+    }
+
+    /// Mean confidence over delivered frames that carry one.
+    pub fn mean_confidence(&self) -> Result<Option<f64>> {
+        Ok(self.inner.lock()?.confidence.get())
+    }
+}
+
+/// Reads back a log written by `ExperimentLog`.
+pub fn read_entries<P: AsRef<Path>>(path: P) -> Result<Vec<FrameEntry>> {
+    read_from(File::open(path)?)
+}
+
+fn read_from<R: io::Read>(r: R) -> Result<Vec<FrameEntry>> {
+    let mut rdr = csv::Reader::from_reader(r);
+    let mut entries = Vec::new();
+    for entry in rdr.deserialize() {
+        entries.push(entry?);
+    }
+    Ok(entries)
+}
@@ -0,0 +1,272 @@
+//! A/B experiments of adaptation policies in simulation.
+//!
+//! `compare` runs two policies over the same bandwidth trace and profile. The
+//! simulation is closed-loop: each `MONITOR_INTERVAL`, the current level (and
+//! probe) produces bytes, the link drains what the trace allows, and the
+//! resulting queue is turned into signals by the same model as the client's
+//! monitor, so that a policy sees the consequences of its own decisions.
+//! Both runs tick on the same clock, so their events align one-to-one.
+
+use super::{AdaptAction, Policy, Profile, Signal};
+use super::adaptation;
+use super::decision::{MONITOR_INTERVAL, QueueEstimator};
+use super::estimator::ExponentialSmooth;
+use super::source::ProbeTracker;
+use csv;
+use errors::*;
+use std::cmp;
+use std::fmt;
+use std::path::Path;
+
+/// Data that waits longer than this (ms) counts as dropped.
+const DEADLINE_MS: f64 = 1000.0;
+
+/// Link capacity over time.
+#[derive(Debug, Clone)]
+pub struct Trace {
+    step_ms: u64,
+    kbps: Vec<f64>,
+}
+
+impl Trace {
+    /// Creates a trace with one capacity (kbps) per `step_ms`.
+    pub fn new(step_ms: u64, kbps: Vec<f64>) -> Trace {
+        assert!(step_ms > 0);
+        Trace { step_ms, kbps }
+    }
+
+    /// Loads a headerless CSV file with one capacity (kbps) per row and
+    /// `step_ms` between rows.
+    pub fn load<P: AsRef<Path>>(path: P, step_ms: u64) -> Result<Trace> {
+        let mut rdr = csv::ReaderBuilder::new().has_headers(false).from_path(path)?;
+        let mut kbps = Vec::new();
+        for row in rdr.deserialize() {
+            let (k,): (f64,) = row?;
+            kbps.push(k);
+        }
+        Ok(Trace::new(step_ms, kbps))
+    }
+
+    /// The length of the trace (ms).
+    pub fn duration_ms(&self) -> u64 {
+        self.step_ms * self.kbps.len() as u64
+    }
+
+    /// The capacity (kbps) at `t_ms`.
+    pub fn at(&self, t_ms: u64) -> f64 {
+        let i = cmp::min((t_ms / self.step_ms) as usize, self.kbps.len().saturating_sub(1));
+        self.kbps.get(i).cloned().unwrap_or(0.0)
+    }
+}
+
+/// What happened during one simulation step.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Event {
+    /// Time since the start (ms).
+    pub t_ms: u64,
+
+    /// The level streamed.
+    pub level: usize,
+
+    /// Queueing delay of the data produced in this step (ms).
+    pub latency_ms: f64,
+
+    /// Accuracy of the level.
+    pub accuracy: f64,
+
+    /// Whether the data missed the deadline.
+    pub dropped: bool,
+}
+
+/// Aggregates of a run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    /// Average level.
+    pub mean_level: f64,
+
+    /// Average accuracy of what arrived in time (dropped steps count as 0).
+    pub delivered_accuracy: f64,
+
+    /// 99th percentile of the latency (ms).
+    pub p99_latency_ms: f64,
+
+    /// Steps whose data was dropped.
+    pub drops: usize,
+}
+
+impl Summary {
+    /// Summarizes `events`.
+    pub fn of(events: &[Event]) -> Summary {
+        if events.is_empty() {
+            return Summary {
+                mean_level: 0.0,
+                delivered_accuracy: 0.0,
+                p99_latency_ms: 0.0,
+                drops: 0,
+            };
+        }
+        let n = events.len() as f64;
+        let mut latencies = events.iter().map(|e| e.latency_ms).collect::<Vec<_>>();
+        latencies.sort_by(|a, b| a.partial_cmp(b).expect("failed to compare latency"));
+        let p99 = latencies[((latencies.len() - 1) as f64 * 0.99).round() as usize];
+        Summary {
+            mean_level: events.iter().map(|e| e.level as f64).sum::<f64>() / n,
+            delivered_accuracy: events
+                .iter()
+                .filter(|e| !e.dropped)
+                .map(|e| e.accuracy)
+                .sum::<f64>() / n,
+            p99_latency_ms: p99,
+            drops: events.iter().filter(|e| e.dropped).count(),
+        }
+    }
+}
+
+/// Runs `policy` over `trace`, starting from the lowest level of `profile`.
+pub fn simulate<C, P: Policy + ?Sized>(trace: &Trace, profile: &Profile<C>, policy: &mut P) -> Vec<Event> {
+    let records = profile.records();
+    let mut simple = profile.simplify();
+    simple.set_level(0);
+    let mut queue = QueueEstimator::new(Box::new(ExponentialSmooth::new(0.5)));
+    let mut prober = ProbeTracker::new(MONITOR_INTERVAL);
+    let bytes = |kbps: f64| (kbps * MONITOR_INTERVAL as f64 / 8.0) as usize;
+
+    let mut backlog = 0;
+    let mut pending = Vec::new();
+    let mut events = Vec::new();
+    for step in 0..trace.duration_ms() / MONITOR_INTERVAL {
+        let t_ms = step * MONITOR_INTERVAL;
+        let capacity_kbps = trace.at(t_ms);
+        let level = simple.current();
+        let produced = bytes(records[level].bandwidth.kbps()) + prober.next().unwrap_or(0);
+        let consumed = cmp::min(backlog + produced, bytes(capacity_kbps));
+        backlog = backlog + produced - consumed;
+        let latency_ms = if capacity_kbps > 0.0 {
+            backlog as f64 * 8.0 / capacity_kbps
+        } else {
+            f64::INFINITY
+        };
+        events.push(Event {
+            t_ms,
+            level,
+            latency_ms,
+            accuracy: records[level].accuracy,
+            dropped: latency_ms > DEADLINE_MS,
+        });
+
+        let signals = pending
+            .drain(..)
+            .chain(queue.update(produced, consumed))
+            .collect::<Vec<Signal>>();
+        for signal in signals {
+            match adaptation::decide(policy, &mut simple, signal).command {
+                Some(AdaptAction::StartProbe(target)) => prober.start_probe(target),
+                Some(AdaptAction::IncreaseProbePace) if !prober.inc_pace() => {
+                    pending.push(Signal::ProbeDone)
+                }
+                Some(AdaptAction::IncreaseProbePace) => {}
+                Some(_) => prober.stop_probe(),
+                None => {}
+            }
+        }
+    }
+    events
+}
+
+/// The outcome of an A/B run.
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    /// Summary of policy A.
+    pub a: Summary,
+
+    /// Summary of policy B.
+    pub b: Summary,
+
+    /// The events of both runs, step by step.
+    pub aligned: Vec<(Event, Event)>,
+}
+
+impl Comparison {
+    /// The steps where the policies chose different levels.
+    pub fn divergences(&self) -> Vec<(Event, Event)> {
+        self.aligned
+            .iter()
+            .filter(|&&(a, b)| a.level != b.level)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Runs policies `a` and `b` over the same `trace` and `profile`.
+pub fn compare<C, A, B>(trace: &Trace, profile: &Profile<C>, a: &mut A, b: &mut B) -> Comparison
+where
+    A: Policy + ?Sized,
+    B: Policy + ?Sized,
+{
+    let events_a = simulate(trace, profile, a);
+    let events_b = simulate(trace, profile, b);
+    Comparison {
+        a: Summary::of(&events_a),
+        b: Summary::of(&events_b),
+        aligned: events_a.into_iter().zip(events_b).collect(),
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<20}{:>12}{:>12}", "", "A", "B")?;
+        writeln!(f, "{:<20}{:>12.2}{:>12.2}", "mean level", self.a.mean_level, self.b.mean_level)?;
+        writeln!(
+            f,
+            "{:<20}{:>12.4}{:>12.4}",
+            "delivered accuracy",
+            self.a.delivered_accuracy,
+            self.b.delivered_accuracy
+        )?;
+        writeln!(
+            f,
+            "{:<20}{:>12.1}{:>12.1}",
+            "p99 latency (ms)",
+            self.a.p99_latency_ms,
+            self.b.p99_latency_ms
+        )?;
+        write!(f, "{:<20}{:>12}{:>12}", "drops", self.a.drops, self.b.drops)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use adaptation::{Action, Adaptation};
+    use Bandwidth;
+    use profile::Record;
+
+    /// Never moves.
+    struct Stay;
+
+    impl Policy for Stay {
+        fn transit(&mut self, _signal: Signal, _max_config: bool) -> Action {
+            Action::NoOp
+        }
+    }
+
+    #[test]
+    fn test_compare_policies() {
+        let records = (0..4)
+            .map(|i| {
+                let rate = Bandwidth::from_kbps(100.0 * (1 << i) as f64);
+                Record::new(rate, i, 0.5 + 0.1 * i as f64)
+            })
+            .collect();
+        let profile = Profile::_with_vec(records);
+        let trace = Trace::new(1000, vec![1000.0; 60]);
+
+        let cmp = compare(&trace, &profile, &mut Adaptation::default(), &mut Stay);
+        assert_eq!(cmp.aligned.len(), 600);
+        assert_eq!(cmp.b.mean_level, 0.0);
+        assert!(cmp.a.mean_level > 1.0, "{}", cmp);
+        assert!(cmp.a.delivered_accuracy > cmp.b.delivered_accuracy, "{}", cmp);
+        assert!(!cmp.divergences().is_empty());
+        assert_eq!(cmp.b.drops, 0);
+    }
+}
@@ -0,0 +1,382 @@
+//! Adaptation decided by an external process, e.g., a reinforcement-learning
+//! agent in Python, without linking it into the runtime.
+//!
+//! For every signal, `ExternalPolicy` writes an observation as one line of
+//! JSON (wrapped here) and reads back one line with the decision:
+//!
+//! ```text
+//! > {"signal":"queue_congest","rate_kbps":812.5,"latency_ms":40.2,
+//!    "level":2,"num_levels":5,"max":false}
+//! < {"level":1}
+//! ```
+//!
+//! The reply `{"level":null}` (or `{}`) keeps the current level. The agent is
+//! either a process spawned with its stdin/stdout as the channel, or a server
+//! listening on a Unix socket. A thread of its own talks to the agent, so that
+//! the reactor waits no longer than `AGENT_TIMEOUT`: a late decision leaves
+//! the signal to the built-in `Adaptation` (and is discarded once it
+//! arrives), and if the agent stops answering, `Adaptation` takes over.
+
+use super::adaptation::{Action, Adaptation, Policy, Signal};
+use super::profile::SimpleProfile;
+use errors::*;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, channel};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long the agent may take to decide.
+pub const AGENT_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// How to reach the agent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExternalPolicyConfig {
+    /// Connect to an agent listening on this Unix socket.
+    Socket(PathBuf),
+
+    /// Spawn this command (program and arguments) and talk over its
+    /// stdin/stdout.
+    Command(Vec<String>),
+}
+
+/// What the agent answered to an observation.
+#[derive(Debug, PartialEq)]
+enum Answer {
+    /// The level it chose, if any.
+    Decided(Option<usize>),
+
+    /// Nothing within `AGENT_TIMEOUT`.
+    Late,
+}
+
+/// A `Policy` delegating decisions to an agent over a line-based channel.
+pub struct ExternalPolicy {
+    observations: Sender<String>,
+    replies: Receiver<Result<String>>,
+    /// Replies still due for observations that timed out, to be discarded.
+    stale: usize,
+    child: Option<Child>,
+    fallback: Adaptation,
+    /// The channel failed; the fallback decides from now on.
+    broken: bool,
+}
+
+impl ExternalPolicy {
+    /// Talks to an agent reading observations from `writer` and answering
+    /// on `reader`.
+    pub fn new<R, W>(reader: R, writer: W) -> ExternalPolicy
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let (observations, requests) = channel::<String>();
+        let (answers, replies) = channel();
+        let mut reader = BufReader::new(reader);
+        let mut writer = writer;
+        thread::spawn(move || {
+            for observation in requests {
+                let reply = exchange(&mut reader, &mut writer, &observation);
+                let failed = reply.is_err();
+                if answers.send(reply).is_err() || failed {
+                    return;
+                }
+            }
+        });
+        ExternalPolicy {
+            observations,
+            replies,
+            stale: 0,
+            child: None,
+            fallback: Adaptation::default(),
+            broken: false,
+        }
+    }
+
+    /// Spawns `command` (program and arguments) as the agent.
+    pub fn spawn(command: &[String]) -> Result<ExternalPolicy> {
+        let (program, args) = match command.split_first() {
+            Some(split) => split,
+            None => bail!(ErrorKind::InvalidConfig("empty agent command".into())),
+        };
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let (stdin, stdout) = match (child.stdin.take(), child.stdout.take()) {
+            (Some(stdin), Some(stdout)) => (stdin, stdout),
+            _ => bail!(ErrorKind::ControlPlane),
+        };
+        let mut policy = ExternalPolicy::new(stdout, stdin);
+        policy.child = Some(child);
+        Ok(policy)
+    }
+
+    /// Connects to an agent listening on the Unix socket at `path`.
+    #[cfg(unix)]
+    pub fn connect<P: AsRef<::std::path::Path>>(path: P) -> Result<ExternalPolicy> {
+        use std::os::unix::net::UnixStream;
+
+        let stream = UnixStream::connect(path)?;
+        Ok(ExternalPolicy::new(stream.try_clone()?, stream))
+    }
+
+    /// Reaches the agent as configured.
+    pub fn from_config(config: &ExternalPolicyConfig) -> Result<ExternalPolicy> {
+        match *config {
+            #[cfg(unix)]
+            ExternalPolicyConfig::Socket(ref path) => ExternalPolicy::connect(path),
+            #[cfg(not(unix))]
+            ExternalPolicyConfig::Socket(_) => {
+                bail!(ErrorKind::InvalidConfig("unix sockets are not supported here".into()))
+            }
+            ExternalPolicyConfig::Command(ref command) => ExternalPolicy::spawn(command),
+        }
+    }
+
+    /// Sends the observation and waits up to `AGENT_TIMEOUT` for the level
+    /// the agent chose, if any. Being late is no error: that would capture a
+    /// backtrace, and keep the reactor waiting past the timeout.
+    fn ask(&mut self, observation: String) -> Result<Answer> {
+        if self.observations.send(observation).is_err() {
+            bail!(ErrorKind::PeerClosed);
+        }
+        let deadline = Instant::now() + AGENT_TIMEOUT;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.replies.recv_timeout(left) {
+                Ok(reply) if self.stale > 0 => {
+                    self.stale -= 1;
+                    reply?;
+                }
+                Ok(reply) => return parse_decision(&reply?).map(Answer::Decided),
+                Err(RecvTimeoutError::Timeout) => {
+                    self.stale += 1;
+                    return Ok(Answer::Late);
+                }
+                Err(RecvTimeoutError::Disconnected) => bail!(ErrorKind::PeerClosed),
+            }
+        }
+    }
+
+    fn decide(
+        &mut self,
+        signal: Signal,
+        level: Option<usize>,
+        num_levels: Option<usize>,
+        max: bool,
+    ) -> Action {
+        if self.broken {
+            return self.fallback.transit(signal, max);
+        }
+        match self.ask(observation(signal, level, num_levels, max)) {
+            Ok(Answer::Decided(Some(level))) => Action::ToLevel(level),
+            Ok(Answer::Decided(None)) => Action::NoOp,
+            Ok(Answer::Late) => {
+                warn!("no decision from the agent in time, falling back for {:?}", signal);
+                self.fallback.transit(signal, max)
+            }
+            Err(e) => {
+                match *e.kind() {
+                    ErrorKind::DecodeError => {
+                        warn!("malformed decision from the agent, falling back for {:?}", signal)
+                    }
+                    _ => {
+                        warn!("agent unreachable ({}), falling back to the built-in policy", e);
+                        self.broken = true;
+                    }
+                }
+                self.fallback.transit(signal, max)
+            }
+        }
+    }
+}
+
+impl Policy for ExternalPolicy {
+    fn transit(&mut self, signal: Signal, max_config: bool) -> Action {
+        self.decide(signal, None, None, max_config)
+    }
+
+    fn transit_in(&mut self, signal: Signal, profile: &SimpleProfile) -> Action {
+        self.decide(signal, Some(profile.current()), Some(profile.num_levels()), profile.is_max())
+    }
+}
+
+impl Drop for ExternalPolicy {
+    fn drop(&mut self) {
+        if let Some(ref mut child) = self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Writes `observation` to the agent and reads its reply.
+fn exchange<R, W>(reader: &mut R, writer: &mut W, observation: &str) -> Result<String>
+where
+    R: BufRead,
+    W: Write,
+{
+    writeln!(writer, "{}", observation)?;
+    writer.flush()?;
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        bail!(ErrorKind::PeerClosed);
+    }
+    Ok(line)
+}
+
+/// The observation of `signal` as a line of JSON.
+fn observation(
+    signal: Signal,
+    level: Option<usize>,
+    num_levels: Option<usize>,
+    max: bool,
+) -> String {
+    let (name, rate, latency) = match signal {
+        Signal::QueueCongest(r, l) => ("queue_congest", Some(r.kbps()), Some(l)),
+        Signal::QueueEmpty => ("queue_empty", None, None),
+        Signal::RemoteCongest(r, l) => ("remote_congest", Some(r.kbps()), Some(l)),
+        Signal::ProbeDone => ("probe_done", None, None),
+        Signal::EncoderLimit(_) => ("encoder_limit", None, None),
+        Signal::SystemLoad(_) => ("system_load", None, None),
+        Signal::BudgetViolation(l) => ("budget_violation", None, Some(l)),
+        Signal::LevelAvailable(..) => ("level_available", None, None),
+        Signal::MemoryPressure(_) => ("memory_pressure", None, None),
+        Signal::PredictedBurst(l) => ("predicted_burst", None, Some(l)),
+        Signal::DeadlineMiss(l) => ("deadline_miss", None, Some(l)),
+    };
+    fn json<T: ::std::fmt::Display>(v: Option<T>) -> String {
+        v.map_or("null".into(), |v| v.to_string())
+    }
+    format!(
+        concat!(
+            "{{\"signal\":\"{}\",\"rate_kbps\":{},\"latency_ms\":{},",
+            "\"level\":{},\"num_levels\":{},\"max\":{}}}"
+        ),
+        name,
+        json(rate.filter(|r| r.is_finite())),
+        json(latency.filter(|l| l.is_finite())),
+        json(level),
+        json(num_levels),
+        max
+    )
+}
+
+/// Parses `{"level":<n>}` (or `null`/`{}` for no change).
+fn parse_decision(line: &str) -> Result<Option<usize>> {
+    let line = line.trim();
+    if line == "{}" || line == "null" {
+        return Ok(None);
+    }
+    let value = match line.find("\"level\"") {
+        Some(i) => line[i + "\"level\"".len()..].trim_start(),
+        None => bail!(ErrorKind::DecodeError),
+    };
+    let value = match value.strip_prefix(':') {
+        Some(v) => v.trim_start(),
+        None => bail!(ErrorKind::DecodeError),
+    };
+    if value.starts_with("null") {
+        return Ok(None);
+    }
+    let digits = value.chars().take_while(char::is_ascii_digit).collect::<String>();
+    // levels are integers: `2.7` or `2e1` is not one
+    let rest = value[digits.len()..].trim_start();
+    if !(rest.is_empty() || rest.starts_with(',') || rest.starts_with('}')) {
+        bail!(ErrorKind::DecodeError);
+    }
+    match digits.parse() {
+        Ok(level) => Ok(Some(level)),
+        Err(_) => bail!(ErrorKind::DecodeError),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {AdaptAction, Bandwidth, Profile, Record};
+    use adaptation::decide;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    /// A writer whose bytes the test can read back.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> ::std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_external_policy() {
+        let records = (0..4)
+            .map(|i| Record::new(Bandwidth::from_kbps(100.0 * (i + 1) as f64), i, 0.7))
+            .collect();
+        let mut profile = Profile::_with_vec(records).simplify();
+        let replies = "{\"level\": 2}\n{\"level\":null}\nnonsense\n";
+        let sent = Shared::default();
+        let mut policy = ExternalPolicy::new(Cursor::new(replies), sent.clone());
+
+        let congest = Signal::QueueCongest(Bandwidth::from_kbps(250.0), 30.0);
+        let d = decide(&mut policy, &mut profile, congest);
+        assert_eq!((d.action, d.command), (Action::ToLevel(2), Some(AdaptAction::ToLevel(2))));
+        let d = decide(&mut policy, &mut profile, Signal::QueueEmpty);
+        assert_eq!((d.action, d.level), (Action::NoOp, 2));
+        // malformed replies and a closed channel fall back to `Adaptation`
+        decide(&mut policy, &mut profile, Signal::QueueEmpty);
+        decide(&mut policy, &mut profile, Signal::QueueEmpty);
+        assert!(policy.broken);
+
+        let sent = String::from_utf8(sent.0.lock().unwrap().clone()).unwrap();
+        let first = sent.lines().next().unwrap();
+        assert_eq!(
+            first,
+            concat!(
+                "{\"signal\":\"queue_congest\",\"rate_kbps\":250,\"latency_ms\":30,",
+                "\"level\":0,\"num_levels\":4,\"max\":false}"
+            )
+        );
+        assert_eq!(sent.lines().count(), 4);
+
+        assert!(parse_decision("{\"level\": 2.7}").is_err());
+        assert!(parse_decision("{\"level\":2e1}").is_err());
+        assert_eq!(parse_decision("{\"level\":3 , \"why\":\"x\"}").unwrap(), Some(3));
+    }
+
+    /// Replies the agent only gives after `delay`.
+    struct Slow(Cursor<&'static str>, Duration);
+
+    impl Read for Slow {
+        fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+            thread::sleep(self.1);
+            self.0.read(buf)
+        }
+    }
+
+    #[test]
+    fn test_slow_agent_falls_back() {
+        let records = (0..4).map(|i| Record::new(Bandwidth::from_kbps(100.0), i, 0.7)).collect();
+        let mut profile = Profile::_with_vec(records).simplify();
+        let late = Slow(Cursor::new("{\"level\":3}\n{\"level\":1}\n"), AGENT_TIMEOUT * 2);
+        let mut policy = ExternalPolicy::new(late, Shared::default());
+
+        let congest = Signal::QueueCongest(Bandwidth::from_kbps(50.0), 30.0);
+        let start = Instant::now();
+        let d = decide(&mut policy, &mut profile, congest);
+        assert!(start.elapsed() < AGENT_TIMEOUT * 2);
+        assert_ne!(d.action, Action::ToLevel(3));
+        assert!(!policy.broken);
+        // the late reply to the first observation is not taken for the second
+        thread::sleep(AGENT_TIMEOUT * 2);
+        assert_eq!(policy.ask("{}".into()).unwrap(), Answer::Decided(Some(1)));
+    }
+}
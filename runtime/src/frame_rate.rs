@@ -0,0 +1,265 @@
+//! Enforcing the frame rate of each level.
+//!
+//! A level may specify a frame rate (`Demand::fps`), which sources are
+//! trusted to honor. A camera stuck at its native rate, or an encoder that
+//! ignores a reconfiguration, produces more frames than the profile budgeted
+//! for, and the level then costs more bandwidth than its profile says. With
+//! `frame_rate_enforcement` set, the source driver passes at most one live
+//! frame per period of the current level's rate, and either discards the
+//! excess (`drop`) or holds the latest excess frame until the next period
+//! (`delay`). Either way, a source over-producing is reported:
+//!
+//! ```text
+//! level 2 is configured for 10 fps but the source produced 29.8 fps (99 excess frames)
+//! ```
+
+use super::{AsDatum, AsDatumType};
+use decision::SharedClock;
+use errors::*;
+use futures::{Async, Future, Poll};
+use std::cmp;
+use std::time::Duration;
+use ticker::shared_timer;
+use tokio_timer::{Sleep, Timer};
+
+/// How early (share of the period) a frame may arrive, to absorb jitter.
+const SLACK: f64 = 0.1;
+
+/// How often (ms) over-producing is reported.
+const REPORT_INTERVAL_MS: u64 = 5000;
+
+/// What to do with the frames beyond the rate of a level.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FrameRateEnforcement {
+    /// Discard them.
+    Drop,
+
+    /// Hold the latest one until the next period (discarding the others).
+    Delay,
+}
+
+/// Frames offered and passed at a level since the last report.
+#[derive(Debug)]
+struct Window {
+    start_ms: u64,
+    level: usize,
+    fps: f64,
+    offered: usize,
+    excess: usize,
+}
+
+/// Passes live frames at the rate of their level.
+pub struct FrameRateGate {
+    mode: FrameRateEnforcement,
+    clock: SharedClock,
+    /// The earliest time (ms) of the next frame.
+    next_ms: Option<u64>,
+    /// The frame held, and the period (ms) of its level.
+    held: Option<(AsDatum, f64)>,
+    timer: Timer,
+    sleep: Option<Sleep>,
+    window: Option<Window>,
+    excess: usize,
+}
+
+impl ::std::fmt::Debug for FrameRateGate {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("FrameRateGate")
+            .field("mode", &self.mode)
+            .field("next_ms", &self.next_ms)
+            .field("excess", &self.excess)
+            .finish()
+    }
+}
+
+impl FrameRateGate {
+    /// Enforces the rates frames are offered with using `mode`, timing with
+    /// `clock`.
+    pub fn new(mode: FrameRateEnforcement, clock: SharedClock) -> FrameRateGate {
+        FrameRateGate {
+            mode,
+            clock,
+            next_ms: None,
+            held: None,
+            timer: shared_timer(),
+            sleep: None,
+            window: None,
+            excess: 0,
+        }
+    }
+
+    /// The frames discarded so far.
+    pub fn excess(&self) -> usize {
+        self.excess
+    }
+
+    /// Offers a frame of a level whose rate is `fps`, returning it if it may
+    /// go now. Frames other than live ones, and those of levels without a
+    /// rate, always go. The rate is taken with each frame, as the profile
+    /// may be replaced while the source runs.
+    pub fn offer(&mut self, frame: AsDatum, fps: Option<f64>) -> Option<AsDatum> {
+        let level = match frame.datum_type() {
+            AsDatumType::Live(level, _) | AsDatumType::Reference(level, _) => level,
+            _ => return Some(frame),
+        };
+        let period = match fps {
+            Some(fps) if fps > 0.0 => 1000.0 / fps,
+            _ => return Some(frame),
+        };
+        let now = self.clock.now_ms();
+        self.observe(level, 1000.0 / period, now);
+        let due = self.next_ms.is_none_or(|next| now as f64 + SLACK * period >= next as f64);
+        if due && self.held.is_none() {
+            self.pass(period, now);
+            return Some(frame);
+        }
+        if let Some(ref mut window) = self.window {
+            window.excess += 1;
+        }
+        let discarded = match self.mode {
+            FrameRateEnforcement::Drop => Some(frame),
+            FrameRateEnforcement::Delay => self.held.replace((frame, period)).map(|(held, _)| held),
+        };
+        if let Some(discarded) = discarded {
+            trace!("discarding {} beyond {:.1} fps", discarded, 1000.0 / period);
+            self.excess += 1;
+        }
+        None
+    }
+
+    /// Moves to the next period after passing a frame at `now`.
+    fn pass(&mut self, period: f64, now: u64) {
+        // late frames don't earn a burst
+        let from = cmp::max(self.next_ms.unwrap_or(now), now.saturating_sub(period as u64));
+        self.next_ms = Some(from + period.round() as u64);
+    }
+
+    /// Yields the held frame once its period has come.
+    pub fn poll_held(&mut self) -> Poll<Option<AsDatum>, Error> {
+        loop {
+            let period = match self.held {
+                Some((_, period)) => period,
+                None => return Ok(Async::Ready(None)),
+            };
+            let now = self.clock.now_ms();
+            let next = self.next_ms.unwrap_or(now);
+            if now as f64 + SLACK * period >= next as f64 {
+                self.sleep = None;
+                self.pass(period, now);
+                return Ok(Async::Ready(self.take_held()));
+            }
+            let timer = &self.timer;
+            let sleep = self.sleep
+                .get_or_insert_with(|| timer.sleep(Duration::from_millis(next - now)));
+            if sleep.poll()?.is_not_ready() {
+                return Ok(Async::NotReady);
+            }
+            // woken up; the clock may still be behind the timer
+            self.sleep = None;
+        }
+    }
+
+    /// Takes the held frame regardless of its period, e.g., once the source
+    /// ended.
+    pub fn take_held(&mut self) -> Option<AsDatum> {
+        self.held.take().map(|(frame, _)| frame)
+    }
+
+    /// Counts a frame offered at `level` of `fps`, reporting the last window
+    /// when it is over or the level (or its rate) changed.
+    fn observe(&mut self, level: usize, fps: f64, now: u64) {
+        let over = match self.window {
+            Some(ref w) => {
+                let elapsed = now.saturating_sub(w.start_ms);
+                let over = level != w.level || fps != w.fps || elapsed >= REPORT_INTERVAL_MS;
+                if over && w.excess > 0 && elapsed > 0 {
+                    warn!(
+                        "level {} is configured for {:.1} fps but the source produced {:.1} fps ({} excess frames)",
+                        w.level,
+                        w.fps,
+                        w.offered as f64 * 1000.0 / elapsed as f64,
+                        w.excess
+                    );
+                }
+                over
+            }
+            None => true,
+        };
+        if over {
+            self.window = Some(Window {
+                start_ms: now,
+                level,
+                fps,
+                offered: 0,
+                excess: 0,
+            });
+        }
+        if let Some(ref mut window) = self.window {
+            window.offered += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use decision::ManualClock;
+    use futures::future;
+    use std::sync::Arc;
+
+    /// Polls within a task, as the driver does.
+    fn poll_held(gate: &mut FrameRateGate) -> Async<Option<AsDatum>> {
+        future::lazy(|| Ok::<_, Error>(gate.poll_held())).wait().unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_enforce_level_frame_rate() {
+        let clock = Arc::new(ManualClock::new(0));
+        let rates = [Some(10.0), None];
+        let mut gate = FrameRateGate::new(FrameRateEnforcement::Drop, clock.clone());
+        // a source at 40 fps configured for 10: one frame in four goes
+        let mut passed = Vec::new();
+        for i in 0..40 {
+            passed.extend(gate.offer(AsDatum::new(0, i, vec![]), rates[0]).map(|_| i));
+            clock.advance(25);
+        }
+        assert_eq!(passed, (0..40).step_by(4).collect::<Vec<_>>());
+        assert_eq!(gate.excess(), 30);
+        // levels without a rate and other datums always go
+        assert!(gate.offer(AsDatum::new(1, 40, vec![]), rates[1]).is_some());
+        assert!(gate.offer(AsDatum::latency_probe(), rates[0]).is_some());
+
+        let mut gate = FrameRateGate::new(FrameRateEnforcement::Delay, clock.clone());
+        assert!(gate.offer(AsDatum::new(0, 0, vec![]), rates[0]).is_some());
+        clock.advance(20);
+        assert!(gate.offer(AsDatum::new(0, 1, vec![]), rates[0]).is_none());
+        clock.advance(20);
+        assert!(gate.offer(AsDatum::new(0, 2, vec![]), rates[0]).is_none());
+        assert!(poll_held(&mut gate).is_not_ready());
+        // the latest excess frame goes at the next period
+        clock.advance(60);
+        match poll_held(&mut gate) {
+            Async::Ready(Some(frame)) => assert_eq!(frame.datum_type(), AsDatumType::Live(0, 2)),
+            r => panic!("unexpected {:?}", r.map(|f| f.map(|f| f.datum_type()))),
+        }
+        assert_eq!(gate.excess(), 1);
+        assert!(gate.offer(AsDatum::new(0, 3, vec![]), rates[0]).is_none());
+        assert_eq!(gate.take_held().map(|f| f.datum_type()), Some(AsDatumType::Live(0, 3)));
+    }
+
+    #[test]
+    fn test_follows_a_replaced_rate() {
+        let clock = Arc::new(ManualClock::new(0));
+        let mut gate = FrameRateGate::new(FrameRateEnforcement::Drop, clock.clone());
+        let mut offer = |i, fps| {
+            let passed = gate.offer(AsDatum::new(0, i, vec![]), Some(fps)).is_some();
+            clock.advance(50);
+            passed
+        };
+        // 20 fps at 10, then the profile raises level 0 to 20
+        let before = (0..8).filter(|&i| offer(i, 10.0)).count();
+        let after = (8..16).filter(|&i| offer(i, 20.0)).count();
+        assert_eq!((before, after), (4, 8));
+    }
+}
@@ -0,0 +1,338 @@
+//! Time-aligned frames across the streams of a group.
+//!
+//! A client with several cameras opens one connection per camera and names
+//! the same `group` in each `Hello` (see `ClientIdentity`). Their frames are
+//! timestamped by the one clock of the client, so frames captured together
+//! carry timestamps within a few milliseconds of each other. `StreamGroups`
+//! buffers the live frames of each member and hands out `FrameBundle`s: one
+//! frame per member, all captured within the sync tolerance, for multi-view
+//! analytics.
+//!
+//! Names are chosen by clients, so a group belongs to the session that
+//! opened it: later streams join it only from the same host and under the
+//! same `client_id`. Buffered frames are charged to the `Group` component of
+//! the memory budget, if any, and each group has a lock of its own.
+//!
+//! A frame that can't be matched (its peers were dropped or arrived too far
+//! apart, or the budget had no room for it) is discarded once a later frame
+//! of every member has arrived.
+
+use super::AsDatum;
+use super::memory::Account;
+use chrono::{DateTime, Utc};
+use errors::*;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Frames buffered per member; older ones are discarded.
+const MAX_PENDING: usize = 64;
+
+/// Identifies a group: the session that opened it and the name it gave.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct GroupKey {
+    /// The token of the session that opened the group.
+    pub owner: u64,
+
+    /// The name of the group.
+    pub group: String,
+}
+
+/// What a stream asks to join: the group its client named.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GroupRequest {
+    /// The host the stream connected from.
+    pub host: IpAddr,
+
+    /// The client's `client_id`, if it sent one.
+    pub client_id: Option<String>,
+
+    /// The name of the group.
+    pub group: String,
+}
+
+/// Frames of the members of a group captured at the same time.
+#[derive(Debug, Clone)]
+pub struct FrameBundle {
+    /// The group.
+    pub key: GroupKey,
+
+    /// The capture time of the earliest frame.
+    pub ts: DateTime<Utc>,
+
+    /// One frame per member, by session token.
+    pub frames: BTreeMap<u64, AsDatum>,
+}
+
+type Bundle = (DateTime<Utc>, BTreeMap<u64, AsDatum>);
+
+#[derive(Debug, Default)]
+struct Group {
+    members: BTreeMap<u64, VecDeque<AsDatum>>,
+    unmatched: usize,
+    account: Option<Account>,
+}
+
+impl Group {
+    /// Gives back the memory of frames leaving the group.
+    fn forget<'a, I: IntoIterator<Item = &'a AsDatum>>(&self, frames: I) {
+        if let Some(ref account) = self.account {
+            account.release(frames.into_iter().map(AsDatum::net_len).sum());
+        }
+    }
+
+    fn push(&mut self, session: u64, datum: &AsDatum) {
+        if let Some(ref account) = self.account {
+            if !account.try_reserve(datum.net_len()) {
+                debug!("no memory to bundle {}", datum);
+                self.unmatched += 1;
+                return;
+            }
+        }
+        let queue = self.members.entry(session).or_default();
+        let oldest = if queue.len() == MAX_PENDING {
+            queue.pop_front()
+        } else {
+            None
+        };
+        queue.push_back(datum.clone());
+        if let Some(ref oldest) = oldest {
+            self.unmatched += 1;
+            self.forget(Some(oldest));
+        }
+    }
+
+    /// Takes the next bundle, discarding frames that can't be matched.
+    fn next_bundle(&mut self, tolerance_ms: i64) -> Option<Bundle> {
+        if self.members.len() < 2 {
+            return None;
+        }
+        loop {
+            let heads = self.members
+                .values()
+                .map(|q| q.front().map(|d| d.ts))
+                .collect::<Option<Vec<_>>>()?;
+            let (first, last) = (*heads.iter().min()?, *heads.iter().max()?);
+            if last.signed_duration_since(first).num_milliseconds() <= tolerance_ms {
+                let frames = self.members
+                    .iter_mut()
+                    .filter_map(|(&token, q)| q.pop_front().map(|d| (token, d)))
+                    .collect::<BTreeMap<_, _>>();
+                self.forget(frames.values());
+                return Some((first, frames));
+            }
+            // frames too far before the latest head have no peers
+            let too_old = |d: &AsDatum| {
+                last.signed_duration_since(d.ts).num_milliseconds() > tolerance_ms
+            };
+            let mut stale = Vec::new();
+            for queue in self.members.values_mut() {
+                while queue.front().is_some_and(&too_old) {
+                    stale.extend(queue.pop_front());
+                }
+            }
+            self.unmatched += stale.len();
+            self.forget(&stale);
+        }
+    }
+}
+
+type Shared = Arc<Mutex<Group>>;
+
+#[derive(Debug, Default)]
+struct Inner {
+    groups: HashMap<GroupKey, Shared>,
+    owners: HashMap<GroupRequest, GroupKey>,
+    membership: HashMap<u64, (GroupKey, Shared)>,
+}
+
+/// The stream groups of a server. Clones share the groups.
+#[derive(Debug, Clone)]
+pub struct StreamGroups {
+    inner: Arc<RwLock<Inner>>,
+    tolerance_ms: u64,
+    account: Option<Account>,
+}
+
+impl StreamGroups {
+    /// Bundles frames captured within `tolerance_ms` of each other.
+    pub fn new(tolerance_ms: u64) -> StreamGroups {
+        StreamGroups {
+            inner: Arc::default(),
+            tolerance_ms,
+            account: None,
+        }
+    }
+
+    /// Charges buffered frames to a memory budget; frames it has no room
+    /// for are discarded.
+    pub fn with_budget(mut self, account: Account) -> StreamGroups {
+        self.account = Some(account);
+        self
+    }
+
+    /// Adds the stream of `session` to the group `request` names, which
+    /// `session` opens unless another session of the same client did.
+    pub fn join(&self, request: GroupRequest, session: u64) -> Result<GroupKey> {
+        let mut inner = self.inner.write()?;
+        let key = inner
+            .owners
+            .entry(request.clone())
+            .or_insert_with(|| GroupKey {
+                owner: session,
+                group: request.group.clone(),
+            })
+            .clone();
+        let account = self.account.clone();
+        let group = inner
+            .groups
+            .entry(key.clone())
+            .or_insert_with(|| {
+                Arc::new(Mutex::new(Group {
+                    account,
+                    ..Group::default()
+                }))
+            })
+            .clone();
+        group.lock()?.members.entry(session).or_default();
+        info!("session {:x} joined group {:?} as {:?}", session, request, key);
+        inner.membership.insert(session, (key.clone(), group));
+        Ok(key)
+    }
+
+    /// Removes the stream of `session` from its group, with its frames.
+    pub fn leave(&self, session: u64) -> Result<()> {
+        let mut inner = self.inner.write()?;
+        if let Some((key, group)) = inner.membership.remove(&session) {
+            let mut group = group.lock()?;
+            if let Some(frames) = group.members.remove(&session) {
+                group.forget(&frames);
+            }
+            if group.members.is_empty() {
+                inner.groups.remove(&key);
+                inner.owners.retain(|_, k| *k != key);
+            }
+        }
+        Ok(())
+    }
+
+    /// Buffers a live frame of `session`, if it is in a group.
+    pub fn add(&self, session: u64, datum: &AsDatum) -> Result<()> {
+        let group = match self.inner.read()?.membership.get(&session) {
+            Some((_, group)) => group.clone(),
+            None => return Ok(()),
+        };
+        group.lock()?.push(session, datum);
+        Ok(())
+    }
+
+    /// The groups with at least one member.
+    pub fn keys(&self) -> Result<Vec<GroupKey>> {
+        let mut keys = self.inner.read()?.groups.keys().cloned().collect::<Vec<_>>();
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn group(&self, key: &GroupKey) -> Result<Option<Shared>> {
+        Ok(self.inner.read()?.groups.get(key).cloned())
+    }
+
+    /// The sessions of the group of `key`.
+    pub fn members(&self, key: &GroupKey) -> Result<Vec<u64>> {
+        match self.group(key)? {
+            Some(group) => Ok(group.lock()?.members.keys().cloned().collect()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Frames of the group of `key` discarded for lack of peers.
+    pub fn unmatched(&self, key: &GroupKey) -> Result<usize> {
+        match self.group(key)? {
+            Some(group) => Ok(group.lock()?.unmatched),
+            None => Ok(0),
+        }
+    }
+
+    /// Takes the next bundle of the group of `key`, if every member has a
+    /// frame to match (groups of one have none).
+    pub fn next_bundle(&self, key: &GroupKey) -> Result<Option<FrameBundle>> {
+        let group = match self.group(key)? {
+            Some(group) => group,
+            None => return Ok(None),
+        };
+        let bundle = group.lock()?.next_bundle(self.tolerance_ms as i64);
+        Ok(bundle.map(|(ts, frames)| FrameBundle {
+            key: key.clone(),
+            ts,
+            frames,
+        }))
+    }
+
+    /// Iterates over the bundles of the group of `key` available now.
+    pub fn bundles<'a>(&'a self, key: &'a GroupKey) -> impl Iterator<Item = FrameBundle> + 'a {
+        ::std::iter::from_fn(move || self.next_bundle(key).ok().and_then(|b| b))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use memory::{Component, MemoryBudget};
+
+    #[test]
+    fn test_bundles_within_tolerance() {
+        let budget = MemoryBudget::new(1 << 20);
+        let groups = StreamGroups::new(10).with_budget(budget.account(Component::Group));
+        let request = GroupRequest {
+            host: [10, 0, 0, 1].into(),
+            client_id: Some("rig".into()),
+            group: "cams".into(),
+        };
+        let key = groups.join(request.clone(), 1).unwrap();
+        assert_eq!(groups.join(request.clone(), 2).unwrap(), key);
+        // the same name from another host is another group
+        let elsewhere = GroupRequest {
+            host: [10, 0, 0, 2].into(),
+            ..request
+        };
+        let other = groups.join(elsewhere, 4).unwrap();
+        assert_eq!(other.owner, 4);
+        groups.leave(4).unwrap();
+        let t0 = Utc::now();
+        let frame = |frame_num, ms| {
+            let mut datum = AsDatum::new(0, frame_num, vec![0; 100]);
+            datum.ts = t0 + Duration::milliseconds(ms);
+            datum
+        };
+        // camera 1 at 0, 33, 66 ms; camera 2 lost its first frame and lags 5 ms
+        for (i, ms) in [0, 33, 66].iter().enumerate() {
+            groups.add(1, &frame(i, *ms)).unwrap();
+        }
+        for (i, ms) in [38, 71].iter().enumerate() {
+            groups.add(2, &frame(i + 1, *ms)).unwrap();
+        }
+        // not in a group
+        groups.add(3, &frame(0, 0)).unwrap();
+
+        let bundles = groups.bundles(&key).collect::<Vec<_>>();
+        let nums = bundles
+            .iter()
+            .map(|b| b.frames.values().map(|d| d.datum_type()).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let live = ::AsDatumType::Live;
+        assert_eq!(nums, vec![vec![live(0, 1), live(0, 1)], vec![live(0, 2), live(0, 2)]]);
+        assert_eq!(bundles[0].ts, t0 + Duration::milliseconds(33));
+        assert_eq!(groups.unmatched(&key).unwrap(), 1);
+        assert_eq!(budget.used_by(Component::Group), 0);
+
+        groups.leave(2).unwrap();
+        assert_eq!(groups.members(&key).unwrap(), vec![1]);
+        groups.add(1, &frame(3, 99)).unwrap();
+        assert!(groups.next_bundle(&key).unwrap().is_none());
+        assert!(budget.used_by(Component::Group) > 0);
+        groups.leave(1).unwrap();
+        assert!(groups.keys().unwrap().is_empty());
+        assert_eq!(budget.used_by(Component::Group), 0);
+    }
+}
@@ -0,0 +1,318 @@
+//! A GStreamer-backed source (behind the `gst` feature).
+//!
+//! `GstSource` runs a pipeline ending in an `appsink` and streams each
+//! encoded buffer as a frame. Level changes are mapped to pipeline updates by
+//! `PipelineConfig`, e.g., the encoder's bitrate or the caps of a capsfilter
+//! after `videoscale`/`videorate`, so a real camera can drive the runtime.
+//!
+//! A pipeline for `VideoConfig` looks like:
+//!
+//! ```text
+//! v4l2src ! videoscale ! videorate ! capsfilter name=awstream_caps
+//!         ! x264enc name=awstream_enc tune=zerolatency ! appsink name=sink
+//! ```
+
+use super::video::VideoConfig;
+
+/// Name of the capsfilter element that `VideoConfig` updates.
+pub const CAPS_ELEMENT: &str = "awstream_caps";
+
+/// Name of the encoder element that `VideoConfig` updates.
+pub const ENCODER_ELEMENT: &str = "awstream_enc";
+
+/// A change applied to a named element of the pipeline.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PipelineUpdate {
+    /// Sets `property` of `element` from its string representation.
+    Property {
+        /// Name of the element in the pipeline.
+        element: String,
+
+        /// Property to set.
+        property: String,
+
+        /// Value, parsed by GStreamer according to the property type.
+        value: String,
+    },
+
+    /// Replaces the caps of the capsfilter `element`.
+    Caps {
+        /// Name of the capsfilter in the pipeline.
+        element: String,
+
+        /// Caps in GStreamer's string format.
+        caps: String,
+    },
+}
+
+/// A configuration that can be expressed as pipeline updates.
+pub trait PipelineConfig {
+    /// Returns the updates that put the pipeline in this configuration.
+    fn pipeline_updates(&self) -> Vec<PipelineUpdate>;
+}
+
+impl PipelineConfig for VideoConfig {
+    /// Width goes to the caps (height follows the aspect ratio), keeping one
+    /// frame in `skip + 1` lowers the frame rate, and quant is the encoder
+    /// quantizer.
+    fn pipeline_updates(&self) -> Vec<PipelineUpdate> {
+        vec![
+            PipelineUpdate::Caps {
+                element: CAPS_ELEMENT.into(),
+                caps: format!(
+                    "video/x-raw,width={},framerate={}/{}",
+                    self.width,
+                    VideoConfig::FPS,
+                    self.skip + 1
+                ),
+            },
+            PipelineUpdate::Property {
+                element: ENCODER_ELEMENT.into(),
+                property: "quantizer".into(),
+                value: self.quant.to_string(),
+            },
+        ]
+    }
+}
+
+#[cfg(feature = "gst")]
+pub use self::imp::GstSource;
+
+#[cfg(feature = "gst")]
+mod imp {
+    use super::{PipelineConfig, PipelineUpdate};
+    use super::super::{Adapt, AsDatum, Bandwidth, Capabilities, CapabilityCheck};
+    use super::super::config::Configurable;
+    use super::super::profile::{Profile, SimpleProfile};
+    use super::super::source::Source;
+    use errors::*;
+    use futures::{Async, Poll, Stream};
+    use futures::sync::mpsc::{UnboundedReceiver, unbounded};
+    use gst;
+    use gst::prelude::*;
+    use gst_app;
+    use std::fmt::Debug;
+    use std::str::FromStr;
+
+    fn backend<E: ::std::fmt::Display>(e: E) -> Error {
+        Error::from_kind(ErrorKind::SourceBackend(e.to_string()))
+    }
+
+    /// A source pulling encoded buffers from a GStreamer `appsink`.
+    pub struct GstSource<C> {
+        pipeline: gst::Pipeline,
+        frames: UnboundedReceiver<Vec<u8>>,
+        profile: Profile<C>,
+        frame_num: usize,
+        period: u64,
+        capabilities: Capabilities,
+    }
+
+    impl<C: PipelineConfig + Configurable + Clone + Debug> GstSource<C> {
+        /// Launches the pipeline described by `description`, whose sink is
+        /// the `appsink` named `sink`, in the first config of `profile`.
+        /// `period_in_ms` is the nominal frame interval (used for probing).
+        pub fn new(
+            description: &str,
+            sink: &str,
+            profile: Profile<C>,
+            period_in_ms: u64,
+        ) -> Result<GstSource<C>> {
+            gst::init().map_err(backend)?;
+            let pipeline = gst::parse::launch(description)
+                .map_err(backend)?
+                .downcast::<gst::Pipeline>()
+                .map_err(|_| backend("description is not a pipeline"))?;
+            let appsink = pipeline
+                .by_name(sink)
+                .ok_or_else(|| backend(format!("no element named {}", sink)))?
+                .downcast::<gst_app::AppSink>()
+                .map_err(|_| backend(format!("{} is not an appsink", sink)))?;
+
+            // The callback runs on a streaming thread; hand buffers over to
+            // the reactor through a channel so `poll_frame` never blocks.
+            let (tx, rx) = unbounded();
+            appsink.set_callbacks(
+                gst_app::AppSinkCallbacks::builder()
+                    .new_sample(move |appsink| {
+                        let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                        let buffer = sample.buffer().ok_or(gst::FlowError::Error)?;
+                        let map = buffer.map_readable().map_err(|_| gst::FlowError::Error)?;
+                        tx.unbounded_send(map.as_slice().to_vec()).map_err(
+                            |_| gst::FlowError::Flushing,
+                        )?;
+                        Ok(gst::FlowSuccess::Ok)
+                    })
+                    .build(),
+            );
+
+            let source = GstSource {
+                pipeline,
+                frames: rx,
+                profile,
+                frame_num: 0,
+                period: period_in_ms,
+                capabilities: Capabilities::default(),
+            };
+            source.apply(&source.profile.init_config())?;
+            source.pipeline.set_state(gst::State::Playing).map_err(
+                backend,
+            )?;
+            Ok(source)
+        }
+
+        /// Advertises what the camera behind the pipeline can capture.
+        pub fn with_capabilities(mut self, capabilities: Capabilities) -> GstSource<C> {
+            self.capabilities = capabilities;
+            self
+        }
+
+        fn apply(&self, config: &C) -> Result<()> {
+            for update in config.pipeline_updates() {
+                match update {
+                    PipelineUpdate::Property {
+                        element,
+                        property,
+                        value,
+                    } => {
+                        let e = self.element(&element)?;
+                        if e.find_property(&property).is_none() {
+                            bail!(backend(format!("{} has no property {}", element, property)));
+                        }
+                        e.set_property_from_str(&property, &value);
+                    }
+                    PipelineUpdate::Caps { element, caps } => {
+                        let caps = gst::Caps::from_str(&caps).map_err(backend)?;
+                        self.element(&element)?.set_property("caps", &caps);
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        fn element(&self, name: &str) -> Result<gst::Element> {
+            self.pipeline.by_name(name).ok_or_else(|| {
+                backend(format!("no element named {}", name))
+            })
+        }
+
+        fn apply_or_log(&self, config: &C) {
+            if let Err(e) = self.apply(config) {
+                error!("failed to reconfigure pipeline to {:?}: {}", config, e);
+            }
+        }
+    }
+
+    impl<C: PipelineConfig + Configurable + Clone + Debug> Adapt for GstSource<C> {
+        fn adapt(&mut self, bandwidth: Bandwidth) {
+            if let Some(r) = self.profile.adjust_config(bandwidth) {
+                self.apply_or_log(&r.config);
+            }
+        }
+
+        fn dec_degradation(&mut self) {
+            if let Some(r) = self.profile.advance_config() {
+                self.apply_or_log(&r.config);
+            }
+        }
+
+        fn set_level(&mut self, level: usize) {
+            if let Err(e) = self.try_set_level(level) {
+                error!("failed to switch to level {}: {}", level, e);
+            }
+        }
+
+        fn try_set_level(&mut self, level: usize) -> Result<()> {
+            let prev = self.profile.current_level();
+            if let Some(r) = self.profile.set_config(level) {
+                if let Err(e) = self.apply(&r.config) {
+                    // the pipeline still runs the previous config
+                    self.profile.set_config(prev);
+                    return Err(e);
+                }
+            }
+            Ok(())
+        }
+
+        fn period_in_ms(&self) -> u64 {
+            self.period
+        }
+
+        fn current_level(&self) -> usize {
+            self.profile.current_level()
+        }
+
+        fn simple_profile(&self) -> SimpleProfile {
+            self.profile.simplify()
+        }
+
+        fn observe_accuracy(&mut self, level: usize, quality: f64, weight: f64) {
+            self.profile.observe_accuracy(level, quality, weight);
+        }
+
+        fn frame_rate(&self, level: usize) -> Option<f64> {
+            self.profile.frame_rate(level)
+        }
+
+        fn restrict(&mut self, caps: &Capabilities, check: CapabilityCheck) -> Result<Vec<usize>> {
+            let current = self.profile.current_level();
+            let masked = self.profile.restrict(caps, check)?;
+            if masked.contains(&current) {
+                self.apply(&self.profile.current_record().config)?;
+            }
+            Ok(masked)
+        }
+    }
+
+    impl<C: PipelineConfig + Configurable + Clone + Debug> Source for GstSource<C> {
+        fn poll_frame(&mut self) -> Poll<Option<AsDatum>, Error> {
+            let buffer = match self.frames.poll() {
+                Ok(Async::Ready(Some(b))) => b,
+                Ok(Async::Ready(None)) | Err(_) => return Ok(Async::Ready(None)),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+            };
+            self.frame_num += 1;
+            let level = self.profile.current_level();
+            Ok(Async::Ready(Some(AsDatum::new(level, self.frame_num, buffer))))
+        }
+        fn capabilities(&self) -> Capabilities {
+            self.capabilities.clone()
+        }
+    }
+
+    impl<C> Drop for GstSource<C> {
+        fn drop(&mut self) {
+            let _ = self.pipeline.set_state(gst::State::Null);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_video_config_updates() {
+        let c = VideoConfig {
+            width: 640,
+            skip: 5,
+            quant: 30,
+        };
+        let updates = c.pipeline_updates();
+        assert_eq!(
+            updates[0],
+            PipelineUpdate::Caps {
+                element: CAPS_ELEMENT.into(),
+                caps: "video/x-raw,width=640,framerate=30/6".into(),
+            }
+        );
+        assert_eq!(
+            updates[1],
+            PipelineUpdate::Property {
+                element: ENCODER_ELEMENT.into(),
+                property: "quantizer".into(),
+                value: "30".into(),
+            }
+        );
+    }
+}
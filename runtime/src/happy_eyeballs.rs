@@ -0,0 +1,171 @@
+//! Connecting to the first reachable address of a server ("happy eyeballs").
+//!
+//! A server name may resolve to several addresses, typically IPv6 and IPv4.
+//! Trying them one after the other stalls for the full OS timeout (minutes)
+//! whenever the first one is unreachable, e.g., on a network with broken
+//! IPv6. Instead, `connect` races them as RFC 8305 suggests: addresses are
+//! interleaved by family, each attempt starts `stagger` after the previous
+//! one (or as soon as it fails), and the first connection established wins.
+//! The others are dropped, which closes them.
+
+use errors::*;
+use futures::{Async, Future, Poll};
+use std::collections::VecDeque;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use tokio_core::net::{TcpStream, TcpStreamNew};
+use tokio_core::reactor::{Handle, Timeout};
+
+/// The delay between attempts RFC 8305 recommends.
+pub const DEFAULT_STAGGER: Duration = Duration::from_millis(250);
+
+/// Resolves `host:port`, with the addresses interleaved by family (the
+/// family resolved first leads).
+pub fn resolve(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    let addrs = (host, port)
+        .to_socket_addrs()
+        .chain_err(|| ErrorKind::InvalidConfig(format!("bad server address {:?}", host)))?
+        .collect::<Vec<_>>();
+    if addrs.is_empty() {
+        bail!(ErrorKind::InvalidConfig(format!("{:?} resolves to no address", host)));
+    }
+    Ok(interleave(addrs))
+}
+
+/// Alternates between the families of `addrs`, keeping their order within
+/// each family.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs[0].is_ipv6();
+    let (mut lead, mut other): (VecDeque<_>, VecDeque<_>) =
+        addrs.into_iter().partition(|a| a.is_ipv6() == first_v6);
+    let mut interleaved = Vec::with_capacity(lead.len() + other.len());
+    while let Some(addr) = lead.pop_front() {
+        interleaved.push(addr);
+        interleaved.extend(other.pop_front());
+    }
+    interleaved.extend(other);
+    interleaved
+}
+
+/// Races connections to `addrs`, yielding the first established and its
+/// address.
+pub fn connect(addrs: Vec<SocketAddr>, stagger: Duration, handle: &Handle) -> Connect {
+    Connect {
+        queued: addrs.into(),
+        attempts: Vec::new(),
+        stagger,
+        timeout: Timeout::new(stagger, handle).ok(),
+        handle: handle.clone(),
+        last_error: None,
+    }
+}
+
+/// The future returned by `connect`.
+pub struct Connect {
+    queued: VecDeque<SocketAddr>,
+    attempts: Vec<(SocketAddr, TcpStreamNew)>,
+    stagger: Duration,
+    timeout: Option<Timeout>,
+    handle: Handle,
+    last_error: Option<io::Error>,
+}
+
+impl ::std::fmt::Debug for Connect {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        let attempts = self.attempts.iter().map(|&(addr, _)| addr).collect::<Vec<_>>();
+        f.debug_struct("Connect")
+            .field("queued", &self.queued)
+            .field("attempts", &attempts)
+            .finish()
+    }
+}
+
+impl Connect {
+    /// Starts the next attempt, if any, and restarts the stagger.
+    fn start_next(&mut self) {
+        if let Some(addr) = self.queued.pop_front() {
+            debug!("connecting to {}", addr);
+            self.attempts.push((addr, TcpStream::connect(&addr, &self.handle)));
+            if let Some(ref mut timeout) = self.timeout {
+                timeout.reset(Instant::now() + self.stagger);
+            }
+        }
+    }
+}
+
+impl Future for Connect {
+    type Item = (TcpStream, SocketAddr);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(TcpStream, SocketAddr), Error> {
+        if self.attempts.is_empty() {
+            self.start_next();
+        }
+        loop {
+            let mut failed = false;
+            let mut i = 0;
+            while i < self.attempts.len() {
+                match self.attempts[i].1.poll() {
+                    Ok(Async::Ready(tcp)) => return Ok(Async::Ready((tcp, self.attempts[i].0))),
+                    Ok(Async::NotReady) => i += 1,
+                    Err(e) => {
+                        let (addr, _) = self.attempts.remove(i);
+                        warn!("failed to connect to {}: {}", addr, e);
+                        self.last_error = Some(e);
+                        failed = true;
+                    }
+                }
+            }
+            let due = match self.timeout {
+                Some(ref mut timeout) => timeout.poll()?.is_ready(),
+                None => false,
+            };
+            if self.queued.is_empty() {
+                if self.attempts.is_empty() {
+                    let e = self.last_error.take().unwrap_or_else(|| io::ErrorKind::NotFound.into());
+                    return Err(e.into());
+                }
+                return Ok(Async::NotReady);
+            }
+            if !failed && !due {
+                return Ok(Async::NotReady);
+            }
+            // the new attempt (and the restarted timeout) must be polled
+            self.start_next();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn test_races_past_unreachable_addresses() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        let addrs = vec![addr("[::1]:1"), addr("[::2]:1"), addr("10.0.0.1:1"), addr("[::3]:1")];
+        assert_eq!(interleave(addrs), vec![addr("[::1]:1"), addr("10.0.0.1:1"), addr("[::2]:1"), addr("[::3]:1")]);
+
+        let mut core = Core::new().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap();
+        // refused at once, then blackholed (TEST-NET-1 doesn't route)
+        let refused = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let blackholed = addr("192.0.2.1:8889");
+
+        let start = Instant::now();
+        let work = connect(vec![refused, blackholed, server], Duration::from_millis(100), &core.handle());
+        let (_, chosen) = core.run(work).unwrap();
+        assert_eq!(chosen, server);
+        let elapsed = start.elapsed();
+        assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+
+        match core.run(connect(vec![refused], DEFAULT_STAGGER, &core.handle())) {
+            Err(Error(ErrorKind::Io(ref e), _)) if e.kind() == io::ErrorKind::ConnectionRefused => {}
+            r => panic!("unexpected {:?}", r.map(|(_, addr)| addr)),
+        }
+    }
+}
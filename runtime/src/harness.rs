@@ -0,0 +1,389 @@
+//! A state-machine driver of the server, for fuzzing the ingest (see the
+//! `server` target under `fuzz/`).
+//!
+//! `parse` turns arbitrary bytes into client steps: handshakes, data and
+//! control frames, malformed and oversized frames, raw garbage and abrupt
+//! disconnects, over several connections at once. `Harness` plays them
+//! against an in-process server, then checks that the server survived: every
+//! connection is released and a fresh client still gets welcomed.
+
+use super::{AsCodec, AsDatum, AsDatumType, FrameFlags, Hint, QualityReport, Setting, WireFormat};
+use super::server::{Server, ServerStats};
+use bytes::BytesMut;
+use errors::*;
+use futures::{Future, Stream};
+use futures::sync::oneshot;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::Core;
+use tokio_io::codec::{Decoder, Encoder};
+use toml;
+
+/// Connections open at once.
+pub const MAX_CONNECTIONS: usize = 4;
+
+/// Steps taken from one input.
+pub const MAX_STEPS: usize = 64;
+
+/// Largest payload or garbage a step sends.
+const MAX_PAYLOAD: usize = 4096;
+
+/// How long a step waits on the server.
+const STEP_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// How long the server may take to release connections and welcome again.
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// One action of a client, on connection `conn` (modulo `MAX_CONNECTIONS`).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    /// Opens a data connection, closing the previous one in its slot.
+    Connect(usize),
+
+    /// Opens a control connection for the session welcomed on connection
+    /// `session`, or for a made-up token.
+    Control(Option<usize>, u64),
+
+    /// Sends `Hello`, resuming the session welcomed earlier if `resume`.
+    Hello(usize, bool),
+
+    /// Sends a live frame (level, frame number, payload size) marked as
+    /// `kind` (live, backfill, redundant or a barrier).
+    Frame(usize, u8, usize, usize, usize),
+
+    /// Sends a latency or bandwidth probe.
+    Probe(usize, usize),
+
+    /// Sends a quality report or a hint, which clients don't send.
+    Feedback(usize, bool),
+
+    /// Sends a well-framed datum with a garbage body.
+    Malformed(usize, Vec<u8>),
+
+    /// Sends a header announcing a frame of the given length.
+    Header(usize, u64),
+
+    /// Sends raw bytes.
+    Garbage(usize, Vec<u8>),
+
+    /// Closes the connection abruptly.
+    Close(usize),
+
+    /// Stops sending on the connection.
+    HalfClose(usize),
+}
+
+/// Reads fields from the input, with zeros once exhausted.
+struct Cursor<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    fn u8(&mut self) -> u8 {
+        match self.data.split_first() {
+            Some((&b, rest)) => {
+                self.data = rest;
+                b
+            }
+            None => 0,
+        }
+    }
+
+    fn u16(&mut self) -> usize {
+        (usize::from(self.u8()) << 8) | usize::from(self.u8())
+    }
+
+    fn u64(&mut self) -> u64 {
+        (0..8).fold(0, |v, _| (v << 8) | u64::from(self.u8()))
+    }
+
+    fn bytes(&mut self) -> Vec<u8> {
+        let len = self.u16() % MAX_PAYLOAD;
+        let len = len.min(self.data.len());
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        bytes.to_vec()
+    }
+}
+
+/// Decodes `data` into at most `MAX_STEPS` steps.
+pub fn parse(data: &[u8]) -> Vec<Step> {
+    let mut c = Cursor { data };
+    let mut steps = Vec::new();
+    while !c.data.is_empty() && steps.len() < MAX_STEPS {
+        let op = c.u8();
+        let conn = usize::from(op >> 4) % MAX_CONNECTIONS;
+        let step = match op % 11 {
+            0 => Step::Connect(conn),
+            1 => {
+                let session = c.u8();
+                let session = if session & 0x80 == 0 {
+                    Some(usize::from(session) % MAX_CONNECTIONS)
+                } else {
+                    None
+                };
+                Step::Control(session, c.u64())
+            }
+            2 => Step::Hello(conn, c.u8() & 1 == 1),
+            3 => Step::Frame(conn, c.u8(), usize::from(c.u8()), c.u16(), c.u16() % MAX_PAYLOAD),
+            4 => Step::Probe(conn, c.u16() % MAX_PAYLOAD),
+            5 => Step::Feedback(conn, c.u8() & 1 == 1),
+            6 => Step::Malformed(conn, c.bytes()),
+            7 => Step::Header(conn, c.u64()),
+            8 => Step::Garbage(conn, c.bytes()),
+            9 => Step::Close(conn),
+            _ => Step::HalfClose(conn),
+        };
+        steps.push(step);
+    }
+    steps
+}
+
+fn encode(datum: AsDatum) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    match AsCodec::default().encode(datum, &mut buf) {
+        Ok(()) => buf.to_vec(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// A connection of the harness, with the session it was welcomed to.
+#[derive(Default)]
+struct Slot {
+    stream: Option<TcpStream>,
+    session: Option<u64>,
+}
+
+/// An in-process server to play steps against.
+pub struct Harness {
+    addr: SocketAddr,
+    control: SocketAddr,
+    stats: ServerStats,
+    stop: Option<oneshot::Sender<()>>,
+    server: Option<thread::JoinHandle<()>>,
+}
+
+impl Harness {
+    /// Starts a server on localhost with a control port.
+    pub fn start() -> Result<Harness> {
+        let setting: Setting = toml::from_str(
+            "server = \"127.0.0.1\"\nport = 0\ncontrol_port = 0\nprofile_path = \"\"\n\
+             source_path = \"\"\nstat_path = \"\"\nanalytics = false\n",
+        ).map_err(|e| ErrorKind::InvalidConfig(e.to_string()))?;
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let server = thread::spawn(move || {
+            let mut core = Core::new().expect("failed to create reactor");
+            let server = Server::bind(setting, &core.handle()).expect("failed to bind");
+            let control = server.control_addr().expect("no control port");
+            let _ = ready_tx.send((server.local_addr(), control, server.stats()));
+            let events = server.incoming_events().for_each(|_| Ok(()));
+            let _ = core.run(events.select2(stop_rx));
+        });
+        let (addr, control, stats) = ready_rx.recv().map_err(|_| ErrorKind::RemotePeer)?;
+        let loopback = |a: SocketAddr| SocketAddr::new([127, 0, 0, 1].into(), a.port());
+        Ok(Harness {
+            addr: loopback(addr),
+            control: loopback(control),
+            stats,
+            stop: Some(stop_tx),
+            server: Some(server),
+        })
+    }
+
+    /// Plays `steps`, closes every connection and checks that the server
+    /// recovered.
+    pub fn run(&self, steps: &[Step]) -> Result<()> {
+        let mut slots = (0..MAX_CONNECTIONS).map(|_| Slot::default()).collect::<Vec<_>>();
+        let mut controls = Vec::new();
+        for step in steps {
+            // the server may close a connection at any step; that's fine
+            let _ = self.play(step, &mut slots, &mut controls);
+        }
+        drop(slots);
+        drop(controls);
+        self.settle()
+    }
+
+    fn play(&self, step: &Step, slots: &mut [Slot], controls: &mut Vec<TcpStream>) -> Result<()> {
+        let send = |slots: &mut [Slot], conn: usize, bytes: &[u8]| -> Result<()> {
+            match slots[conn].stream {
+                Some(ref mut s) => Ok(s.write_all(bytes)?),
+                None => Ok(()),
+            }
+        };
+        match *step {
+            Step::Connect(conn) => {
+                slots[conn] = Slot {
+                    stream: Some(self.connect(self.addr)?),
+                    session: slots[conn].session,
+                };
+            }
+            Step::Control(session, token) => {
+                let token = session.and_then(|s| slots[s].session).unwrap_or(token);
+                let mut stream = self.connect(self.control)?;
+                stream.write_all(&encode(AsDatum::control(token)))?;
+                controls.push(stream);
+                if controls.len() > MAX_CONNECTIONS {
+                    controls.remove(0);
+                }
+            }
+            Step::Hello(conn, resume) => {
+                let token = if resume { slots[conn].session } else { None };
+                send(slots, conn, &encode(AsDatum::hello(token)))?;
+                if let Some(ref mut s) = slots[conn].stream {
+                    if let Some(session) = read_welcome(s) {
+                        slots[conn].session = Some(session);
+                    }
+                }
+            }
+            Step::Frame(conn, kind, level, frame_num, len) => {
+                let frame = AsDatum::new(level, frame_num, vec![0; len]);
+                let datum = match kind % 4 {
+                    0 => frame,
+                    1 => frame.into_backfill(),
+                    2 => frame.into_redundant(),
+                    _ => AsDatum::barrier(level),
+                };
+                send(slots, conn, &encode(datum))?;
+            }
+            Step::Probe(conn, len) => {
+                let datum = if len == 0 {
+                    AsDatum::latency_probe()
+                } else {
+                    AsDatum::bw_probe(len)
+                };
+                send(slots, conn, &encode(datum))?;
+            }
+            Step::Feedback(conn, quality) => {
+                let datum = if quality {
+                    AsDatum::quality(QualityReport {
+                        level: 0,
+                        frame_num: None,
+                        quality: 0.5,
+                    })?
+                } else {
+                    AsDatum::hint(&Hint::default())?
+                };
+                send(slots, conn, &encode(datum))?;
+            }
+            Step::Malformed(conn, ref body) => {
+                let format = WireFormat::default();
+                let mut header = vec![0; format.header_len()];
+                format.write_header(body.len() as u64, FrameFlags::empty(), &mut header)?;
+                header.extend_from_slice(body);
+                send(slots, conn, &header)?;
+            }
+            Step::Header(conn, len) => {
+                let format = WireFormat::default();
+                let mut header = vec![0; format.header_len()];
+                format.write_header(len.min(format.max_len()), FrameFlags::empty(), &mut header)?;
+                send(slots, conn, &header)?;
+            }
+            Step::Garbage(conn, ref bytes) => send(slots, conn, bytes)?,
+            Step::Close(conn) => {
+                if let Some(s) = slots[conn].stream.take() {
+                    let _ = s.shutdown(Shutdown::Both);
+                }
+            }
+            Step::HalfClose(conn) => {
+                if let Some(ref s) = slots[conn].stream {
+                    s.shutdown(Shutdown::Write)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn connect(&self, addr: SocketAddr) -> Result<TcpStream> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(STEP_TIMEOUT))?;
+        stream.set_write_timeout(Some(STEP_TIMEOUT))?;
+        Ok(stream)
+    }
+
+    /// Waits until the server released every connection and welcomes a new
+    /// client.
+    fn settle(&self) -> Result<()> {
+        let deadline = Instant::now() + SETTLE_TIMEOUT;
+        while self.stats.active() > 0 {
+            if Instant::now() > deadline {
+                bail!(ErrorKind::InvalidConfig(
+                    format!("{} connections never released", self.stats.active()),
+                ));
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        let mut stream = TcpStream::connect(self.addr)?;
+        stream.set_read_timeout(Some(SETTLE_TIMEOUT))?;
+        stream.write_all(&encode(AsDatum::hello(None)))?;
+        match read_welcome(&mut stream) {
+            Some(_) => Ok(()),
+            None => bail!(ErrorKind::RemotePeer),
+        }
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(server) = self.server.take() {
+            let _ = server.join();
+        }
+    }
+}
+
+/// Reads the server's `Welcome`, if it comes before the read timeout.
+fn read_welcome(stream: &mut TcpStream) -> Option<u64> {
+    let mut codec = AsCodec::default();
+    let mut buf = BytesMut::new();
+    let mut chunk = [0; 256];
+    loop {
+        if let Ok(Some(datum)) = codec.decode(&mut buf) {
+            return match datum.datum_type() {
+                AsDatumType::Welcome(token) => Some(token),
+                _ => None,
+            };
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_harness_survives_hostile_clients() {
+        let harness = Harness::start().unwrap();
+        let steps = vec![
+            Step::Connect(0),
+            Step::Hello(0, false),
+            Step::Frame(0, 0, 1, 1, 100),
+            Step::Control(Some(0), 0),
+            Step::Connect(1),
+            Step::Hello(1, true),
+            Step::Frame(1, 3, 2, 2, 0),
+            Step::Header(0, u64::MAX),
+            Step::Connect(2),
+            Step::Malformed(2, vec![0xff; 64]),
+            Step::Connect(3),
+            Step::Garbage(3, b"GET / HTTP/1.1\r\n\r\n".to_vec()),
+            Step::HalfClose(1),
+            Step::Close(0),
+        ];
+        harness.run(&steps).unwrap();
+        // arbitrary bytes, including an empty input
+        harness.run(&parse(&[0x00, 0x02, 0x01, 0x13, 0x07, 0xff, 0xff, 0x08, 0x00, 0x04, 1, 2, 3, 4])).unwrap();
+        harness.run(&parse(&[])).unwrap();
+        assert!(harness.stats.connections() >= 6);
+    }
+}
@@ -0,0 +1,229 @@
+//! Heartbeats spaced by how stable the link is.
+//!
+//! The client pings the server every second over its control connection,
+//! and sends heartbeats within `control_reserve`. On a volatile link, a
+//! second is slow to notice a stall; on a stable metered one, it's overhead
+//! for nothing. With `heartbeat` set, the controller spaces them between
+//! `min_ms` and `max_ms` by the variation of the last `window` estimates of
+//! the sending rate and of the delay reported by the server:
+//!
+//! ```text
+//! cv = max(stddev / mean of the rates, stddev / mean of the delays)
+//! interval = max_ms - (max_ms - min_ms) * min(cv / 0.5, 1)
+//! ```
+//!
+//! Until `window` estimates of either came in, heartbeats go every `min_ms`.
+
+use decision::SharedClock;
+use errors::*;
+use futures::{Poll, Stream};
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use ticker::Ticker;
+
+/// The variation at which heartbeats go every `min_ms`.
+const UNSTABLE_CV: f64 = 0.5;
+
+/// The bounds of the heartbeat interval.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct HeartbeatConfig {
+    /// The interval (ms) on an unstable link.
+    pub min_ms: u64,
+
+    /// The interval (ms) on a stable link.
+    pub max_ms: u64,
+
+    /// How many estimates the variation is taken over.
+    pub window: usize,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            min_ms: 250,
+            max_ms: 5000,
+            window: 10,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    config: HeartbeatConfig,
+    rates: VecDeque<f64>,
+    delays: VecDeque<f64>,
+    interval_ms: u64,
+}
+
+/// Chooses the heartbeat interval. Clones share the estimates.
+#[derive(Debug, Clone)]
+pub struct HeartbeatPolicy {
+    state: Arc<Mutex<State>>,
+}
+
+impl HeartbeatPolicy {
+    /// Spaces heartbeats within the bounds of `config`.
+    pub fn new(config: HeartbeatConfig) -> HeartbeatPolicy {
+        let config = HeartbeatConfig {
+            min_ms: cmp::max(cmp::min(config.min_ms, config.max_ms), 1),
+            window: cmp::max(config.window, 2),
+            ..config
+        };
+        HeartbeatPolicy {
+            state: Arc::new(Mutex::new(State {
+                config,
+                rates: VecDeque::new(),
+                delays: VecDeque::new(),
+                interval_ms: config.min_ms,
+            })),
+        }
+    }
+
+    /// Sends heartbeats every `period`, whatever the link.
+    pub fn fixed(period: Duration) -> HeartbeatPolicy {
+        let ms = period.as_millis() as u64;
+        HeartbeatPolicy::new(HeartbeatConfig {
+            min_ms: ms,
+            max_ms: ms,
+            ..HeartbeatConfig::default()
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("heartbeat policy poisoned")
+    }
+
+    /// The interval to send heartbeats at.
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.lock().interval_ms)
+    }
+
+    /// Takes an estimate of the sending rate (kbps) into account.
+    pub fn observe_rate(&self, kbps: f64) {
+        let state = &mut *self.lock();
+        push(&mut state.rates, kbps, state.config.window);
+        state.update();
+    }
+
+    /// Takes a delay (ms) reported by the server into account.
+    pub fn observe_delay(&self, ms: f64) {
+        let state = &mut *self.lock();
+        push(&mut state.delays, ms, state.config.window);
+        state.update();
+    }
+}
+
+impl State {
+    fn update(&mut self) {
+        let window = self.config.window;
+        let cv = [&self.rates, &self.delays]
+            .iter()
+            .filter(|samples| samples.len() == window)
+            .map(|samples| variation(samples))
+            .fold(None, |max: Option<f64>, cv| Some(max.map_or(cv, |m| m.max(cv))));
+        let (min, max) = (self.config.min_ms, self.config.max_ms);
+        let interval_ms = match cv {
+            Some(cv) => max - ((max - min) as f64 * (cv / UNSTABLE_CV).min(1.0)) as u64,
+            None => min,
+        };
+        if interval_ms != self.interval_ms {
+            trace!("heartbeats every {} ms (variation {:?})", interval_ms, cv);
+            self.interval_ms = interval_ms;
+        }
+    }
+}
+
+fn push(samples: &mut VecDeque<f64>, sample: f64, window: usize) {
+    if !sample.is_finite() {
+        return;
+    }
+    samples.push_back(sample);
+    while samples.len() > window {
+        samples.pop_front();
+    }
+}
+
+/// The coefficient of variation of `samples` (0 if their mean is).
+fn variation(samples: &VecDeque<f64>) -> f64 {
+    let n = samples.len() as f64;
+    let mean = samples.iter().sum::<f64>() / n;
+    if mean <= 0.0 {
+        return 0.0;
+    }
+    let var = samples.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / n;
+    var.sqrt() / mean
+}
+
+/// Ticks at the interval of a `HeartbeatPolicy`, following its changes.
+pub struct Heartbeats {
+    ticker: Ticker,
+    policy: HeartbeatPolicy,
+    interval: Duration,
+}
+
+impl Heartbeats {
+    /// Ticks at the interval of `policy`, on `clock`.
+    pub fn new(clock: SharedClock, policy: HeartbeatPolicy) -> Heartbeats {
+        let interval = policy.interval();
+        Heartbeats {
+            ticker: Ticker::new(clock, interval),
+            policy,
+            interval,
+        }
+    }
+}
+
+impl Stream for Heartbeats {
+    type Item = u64;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<u64>, Error> {
+        let interval = self.policy.interval();
+        if interval != self.interval {
+            self.ticker.set_period(interval);
+            self.interval = interval;
+        }
+        self.ticker.poll()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use decision::ManualClock;
+    use futures::{Async, Future, future};
+
+    #[test]
+    fn test_interval_follows_link_stability() {
+        let config = HeartbeatConfig {
+            min_ms: 200,
+            max_ms: 2000,
+            window: 4,
+        };
+        let policy = HeartbeatPolicy::new(config);
+        assert_eq!(policy.interval(), Duration::from_millis(200));
+        for _ in 0..4 {
+            policy.observe_rate(1000.0);
+        }
+        assert_eq!(policy.interval(), Duration::from_millis(2000));
+
+        let clock = ManualClock::new(0);
+        let mut heartbeats = Heartbeats::new(Arc::new(clock.clone()), policy.clone());
+        let mut poll = || future::lazy(|| Ok::<_, ()>(heartbeats.poll().unwrap())).wait().unwrap();
+        assert_eq!(poll(), Async::NotReady);
+        clock.advance(2000);
+        assert_eq!(poll(), Async::Ready(Some(2000)));
+
+        // the delays swing: back to fast detection from the last heartbeat
+        for delay in &[20.0, 200.0, 20.0, 200.0] {
+            policy.observe_delay(*delay);
+        }
+        assert_eq!(policy.interval(), Duration::from_millis(200));
+        clock.advance(200);
+        assert_eq!(poll(), Async::Ready(Some(2200)));
+        assert_eq!(poll(), Async::NotReady);
+    }
+}
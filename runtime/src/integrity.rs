@@ -0,0 +1,236 @@
+//! End-to-end integrity of frames, for audits.
+//!
+//! With `frame_integrity` set, the client seals each live frame with a
+//! `FrameDigest` of its content: level, frame number, capture time and
+//! payload (for the datums of sub-streams, stream id and sequence number
+//! instead, the id with its top bit set). The server verifies the digest of
+//! every sealed frame it receives and counts mismatches per session (see
+//! `SessionStats::digest_mismatches`), so that an audit can show that footage
+//! reached it as it left the camera.
+//!
+//! Without a key, the digest is a plain SHA-512 (truncated to 32 bytes): it
+//! catches corruption, but a relay altering a frame could recompute it. With
+//! a `key` shared by both ends, it is an HMAC, which no relay can forge.
+//! Frames are sealed before compression (see `dictionary`) and verified
+//! after it. The digest follows the serialized datum on the wire, so unsealed
+//! frames are unchanged and receivers that don't know digests ignore them. A
+//! server with `frame_integrity` set counts unsealed frames as mismatches, so
+//! that stripping the digest doesn't get a frame past it.
+
+use super::{AsDatum, AsDatumType};
+use errors::*;
+use hmac::{Hmac, Mac};
+use sha2::Sha512;
+use sha2::digest::Update;
+use std::fmt;
+use subtle::ConstantTimeEq;
+
+type HmacSha512 = Hmac<Sha512>;
+
+/// Tells the stream id of a sub-stream datum apart from a level.
+const SUB_STREAM: u64 = 1 << 63;
+
+/// Sealing and verification of frames.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct IntegrityConfig {
+    /// The HMAC key (hex) shared by the client and the server, if any.
+    pub key: Option<String>,
+}
+
+/// The digest a sealed frame carries.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct FrameDigest([u8; 32]);
+
+impl FrameDigest {
+    /// Its size on the wire.
+    pub(crate) const LEN: usize = 32;
+
+    /// Reads a digest of `LEN` bytes.
+    pub(crate) fn from_slice(bytes: &[u8]) -> FrameDigest {
+        let mut digest = [0; Self::LEN];
+        digest.copy_from_slice(bytes);
+        FrameDigest(digest)
+    }
+
+    /// The bytes of the digest.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for FrameDigest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// What verifying a frame found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// The frame carries no digest.
+    Unsealed,
+
+    /// The digest matches the content.
+    Intact,
+
+    /// The content changed since the frame was sealed.
+    Mismatch,
+}
+
+/// Seals and verifies frames, with or without a key.
+#[derive(Clone)]
+pub struct Sealer {
+    mac: Option<HmacSha512>,
+    required: bool,
+}
+
+impl fmt::Debug for Sealer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sealer")
+            .field("keyed", &self.mac.is_some())
+            .field("required", &self.required)
+            .finish()
+    }
+}
+
+impl Sealer {
+    /// Creates a sealer as configured.
+    pub fn new(config: &IntegrityConfig) -> Result<Sealer> {
+        let mac = match config.key {
+            Some(ref hex) => Some(
+                HmacSha512::new_from_slice(&from_hex(hex)?)
+                    .map_err(|_| ErrorKind::InvalidConfig("bad integrity key".into()))?,
+            ),
+            None => None,
+        };
+        Ok(Sealer { mac, required: false })
+    }
+
+    /// Verifies unsealed frames as mismatches from now on.
+    pub fn require_seals(self) -> Sealer {
+        Sealer { required: true, ..self }
+    }
+
+    /// Computes the digest of a frame (`None` for other datums).
+    pub fn digest(&self, datum: &AsDatum) -> Option<FrameDigest> {
+        let (level, frame_num) = match datum.datum_type() {
+            AsDatumType::Live(level, frame_num) |
+            AsDatumType::Backfill(level, frame_num) |
+            AsDatumType::Redundant(level, frame_num) |
+            AsDatumType::Reference(level, frame_num) => (level as u64, frame_num),
+            AsDatumType::Sub(stream, seq) => (u64::from(stream) | SUB_STREAM, seq),
+            _ => return None,
+        };
+        let content = |hasher: &mut dyn Update| {
+            hasher.update(&level.to_be_bytes());
+            hasher.update(&(frame_num as u64).to_be_bytes());
+            hasher.update(&datum.ts.timestamp().to_be_bytes());
+            hasher.update(&datum.ts.timestamp_subsec_nanos().to_be_bytes());
+            hasher.update(&datum.mem);
+        };
+        let hash = match self.mac {
+            Some(ref mac) => {
+                let mut mac = mac.clone();
+                content(&mut mac);
+                mac.finalize().into_bytes()
+            }
+            None => {
+                let mut hasher = Sha512::default();
+                content(&mut hasher);
+                sha2::Digest::finalize(hasher)
+            }
+        };
+        Some(FrameDigest::from_slice(&hash[..FrameDigest::LEN]))
+    }
+
+    /// Seals a frame with its digest; other datums are returned as is.
+    pub fn seal(&self, datum: AsDatum) -> AsDatum {
+        match self.digest(&datum) {
+            Some(digest) => datum.with_digest(digest),
+            None => datum,
+        }
+    }
+
+    /// Verifies the digest of a frame, if sealed (or if seals are
+    /// required). Digests are compared in constant time.
+    pub fn verify(&self, datum: &AsDatum) -> Verdict {
+        match (datum.digest(), self.digest(datum)) {
+            // not a frame
+            (_, None) => Verdict::Unsealed,
+            (None, Some(_)) if self.required => Verdict::Mismatch,
+            (None, Some(_)) => Verdict::Unsealed,
+            (Some(digest), Some(expected)) => {
+                if bool::from(digest.as_bytes().ct_eq(expected.as_bytes())) {
+                    Verdict::Intact
+                } else {
+                    Verdict::Mismatch
+                }
+            }
+        }
+    }
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    let hex = hex.trim();
+    if hex.is_empty() || !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        bail!(ErrorKind::InvalidConfig("bad integrity key".into()));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16).chain_err(|| ErrorKind::InvalidConfig("bad integrity key".into()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use AsCodec;
+    use bytes::BytesMut;
+    use tokio_io::codec::{Decoder, Encoder};
+
+    #[test]
+    fn test_seal_and_verify() {
+        let plain = Sealer::new(&IntegrityConfig::default()).unwrap();
+        let keyed = Sealer::new(&IntegrityConfig { key: Some("0b".repeat(20)) }).unwrap();
+
+        let frame = AsDatum::new(1, 7, vec![42; 1000]);
+        assert_eq!(plain.verify(&frame), Verdict::Unsealed);
+        assert!(plain.digest(&AsDatum::barrier(1)).is_none());
+        // a stripped digest is a mismatch where seals are required
+        let strict = keyed.clone().require_seals();
+        assert_eq!(strict.verify(&frame), Verdict::Mismatch);
+        assert_eq!(strict.verify(&AsDatum::barrier(1)), Verdict::Unsealed);
+
+        let sealed = keyed.seal(frame.clone());
+        assert_eq!(keyed.verify(&sealed), Verdict::Intact);
+        assert_eq!(strict.verify(&sealed), Verdict::Intact);
+        // backfill keeps the frame's identity
+        assert_eq!(keyed.verify(&sealed.clone().into_backfill()), Verdict::Intact);
+        assert_eq!(plain.verify(&sealed), Verdict::Mismatch);
+
+        // the digest travels after the datum
+        let mut codec = AsCodec::default();
+        let mut buf = BytesMut::new();
+        codec.encode(sealed.clone(), &mut buf).unwrap();
+        assert_eq!(buf.len(), frame.net_len() + FrameDigest::LEN);
+        let received = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(received.digest(), sealed.digest());
+        assert_eq!(keyed.verify(&received), Verdict::Intact);
+
+        // altered in transit
+        let mut altered = sealed.clone();
+        altered.mem[500] ^= 1;
+        assert_eq!(keyed.verify(&altered), Verdict::Mismatch);
+        let mut moved = sealed;
+        moved.t = AsDatumType::Live(1, 8);
+        assert_eq!(keyed.verify(&moved), Verdict::Mismatch);
+
+        assert!(Sealer::new(&IntegrityConfig { key: Some("xyz".into()) }).is_err());
+    }
+}
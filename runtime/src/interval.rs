@@ -16,8 +16,8 @@ pub fn new(timer: Timer, duration: Duration) -> (Interval, Sender<()>) {
     let (tx, rx) = oneshot::channel();
     let interval = Interval {
         sleep: timer.sleep(duration),
-        duration: duration,
-        rx: rx,
+        duration,
+        rx,
     };
     (interval, tx)
 }
@@ -32,7 +32,7 @@ impl Stream for Interval {
             return Ok(Async::Ready(None));
         }
 
-        let _ = try_ready!(self.sleep.poll());
+        try_ready!(self.sleep.poll());
         // Reset the timeout
         self.sleep = self.sleep.timer().sleep(self.duration);
         Ok(Async::Ready(Some(())))
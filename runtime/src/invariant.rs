@@ -0,0 +1,59 @@
+//! Runtime checks of protocol invariants.
+//!
+//! Some properties hold across modules rather than within one: the source
+//! numbers live frames in increasing order and only at the levels of its
+//! profile and the send queue accounts for exactly the bytes it holds. A
+//! regression breaking one of them tends to surface far away (a stalled
+//! session, a skewed rate), if at all. What comes from the wire is no
+//! invariant: `AsCodec` rejects implausible timestamps
+//! (`is_sane_timestamp`) as decode errors.
+//!
+//! With the `strict` feature, `strict_assert!` panics as soon as such an
+//! invariant breaks, with the context needed to trace it; CI and canary
+//! deployments enable it. Without the feature, the checks (and the work of
+//! computing their conditions) compile to nothing.
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+
+/// Panics with `context` if `cond` is false, with the `strict` feature only.
+/// `cond` isn't evaluated otherwise.
+macro_rules! strict_assert {
+    ($cond:expr, $($context:tt)+) => {
+        if cfg!(feature = "strict") && !$cond {
+            panic!("invariant `{}` violated: {}", stringify!($cond), format_args!($($context)+));
+        }
+    };
+}
+
+/// How far ahead of the local clock a peer's clock may plausibly be.
+const MAX_CLOCK_AHEAD_HOURS: i64 = 24;
+
+/// Returns true if `ts` is a plausible capture time: after 2000 and not
+/// further ahead of the local clock than any sane skew.
+pub(crate) fn is_sane_timestamp(ts: &DateTime<Utc>) -> bool {
+    let epoch = Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap();
+    *ts >= epoch && *ts <= Utc::now() + Duration::hours(MAX_CLOCK_AHEAD_HOURS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strict_assert() {
+        assert!(is_sane_timestamp(&Utc::now()));
+        assert!(!is_sane_timestamp(&Utc.timestamp_opt(0, 0).unwrap()));
+        assert!(!is_sane_timestamp(&(Utc::now() + Duration::days(30))));
+
+        strict_assert!(1 + 1 == 2, "arithmetic");
+        let violated = ::std::panic::catch_unwind(|| strict_assert!(1 + 1 == 3, "frame {} at level {}", 7, 2));
+        match violated {
+            Err(e) if cfg!(feature = "strict") => {
+                let msg = e.downcast_ref::<String>().map(String::as_str).or(e.downcast_ref::<&str>().copied());
+                assert_eq!(msg.unwrap(), "invariant `1 + 1 == 3` violated: frame 7 at level 2");
+            }
+            Ok(()) if cfg!(not(feature = "strict")) => {}
+            r => panic!("unexpected {:?}", r.is_ok()),
+        }
+    }
+}
@@ -6,27 +6,56 @@
 //! Key data structures are prefixed with `As`.
 #![recursion_limit = "1024"]
 #![deny(missing_docs)]
+// helpers shared by the client and the server go unused in partial builds
+#![cfg_attr(not(all(feature = "client", feature = "server")), allow(dead_code))]
 
 extern crate toml;
 extern crate bincode;
+#[cfg_attr(feature = "runtime", macro_use)]
+extern crate bitflags;
 extern crate byteorder;
 extern crate bytes;
+#[cfg(feature = "runtime")]
 extern crate chrono;
 extern crate csv;
+#[cfg(feature = "signing")]
+extern crate ed25519_dalek;
 #[macro_use]
 extern crate error_chain;
+#[cfg(feature = "server")]
 extern crate evaluation;
+#[cfg(feature = "runtime")]
 #[macro_use]
 extern crate futures;
+#[cfg(feature = "runtime")]
 extern crate futures_cpupool;
+extern crate hmac;
+#[cfg(feature = "ffmpeg")]
+extern crate ffmpeg_next as ffmpeg;
+#[cfg(feature = "gst")]
+extern crate gstreamer as gst;
+#[cfg(feature = "gst")]
+extern crate gstreamer_app as gst_app;
+#[cfg(feature = "tcp-info")]
+extern crate libc;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "mdns")]
+extern crate mdns_sd;
+#[macro_use]
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate sha2;
+extern crate subtle;
+#[cfg(feature = "runtime")]
 extern crate tokio_core;
+#[cfg(feature = "runtime")]
 extern crate tokio_io;
+#[cfg(feature = "runtime")]
 extern crate tokio_timer;
+#[cfg(feature = "compression")]
+extern crate zstd;
 
 /// A convenience macro for working with `io::Result<T>` from the `Read` and
 /// `Write` traits.
@@ -46,57 +75,209 @@ macro_rules! try_nb {
 }
 
 // mod online;
+// first, for the configs declaring their `profile_schema!`
+#[macro_use]
+pub mod schema;
 mod adaptation;
+#[cfg(feature = "server")]
+pub mod admin;
+#[cfg(feature = "runtime")]
+pub mod admission;
+#[cfg(feature = "server")]
 mod analytics;
+#[cfg(feature = "runtime")]
+pub mod audio;
+pub mod bandwidth;
+#[cfg(feature = "runtime")]
+pub mod bandwidth_feed;
+#[cfg(feature = "runtime")]
+pub mod barrier;
+#[cfg(feature = "client")]
+pub mod blob;
+#[cfg(feature = "server")]
 mod bw_monitor;
+#[cfg(feature = "runtime")]
+pub mod catalog;
+#[cfg(feature = "runtime")]
+pub mod codel;
+#[cfg(feature = "runtime")]
+pub mod collector;
+mod config;
+#[cfg(feature = "runtime")]
+pub mod composition;
+pub mod congestion;
+#[cfg(feature = "server")]
+pub mod consumer_lag;
+#[cfg(any(feature = "client", feature = "server"))]
 mod controller;
+#[cfg(feature = "runtime")]
+pub mod deadline;
+pub mod decision;
+#[cfg(feature = "runtime")]
+pub mod delta;
+#[cfg(all(feature = "client", feature = "server"))]
+pub mod demo;
+#[cfg(feature = "runtime")]
+pub mod dictionary;
+#[cfg(feature = "mdns")]
+pub mod discovery;
+#[cfg(feature = "runtime")]
+pub mod drop_policy;
+#[cfg(feature = "client")]
+pub mod endpoint;
 mod errors;
+pub mod estimator;
+#[cfg(feature = "server")]
+pub mod evaluator;
+#[cfg(any(feature = "server", feature = "tools"))]
+pub mod experiment_log;
+#[cfg(feature = "tools")]
+pub mod experiments;
+#[cfg(feature = "runtime")]
+pub mod external;
+#[cfg(feature = "runtime")]
+pub mod frame_rate;
+#[cfg(feature = "server")]
+pub mod grouping;
+#[cfg(feature = "runtime")]
+pub mod gst_source;
+#[cfg(feature = "client")]
+pub mod happy_eyeballs;
+#[cfg(feature = "server")]
+pub mod harness;
+#[cfg(feature = "runtime")]
+pub mod heartbeat;
+#[cfg(feature = "runtime")]
+pub mod integrity;
+#[cfg(feature = "server")]
 mod interval;
+#[cfg(feature = "runtime")]
+#[macro_use]
+mod invariant;
+#[cfg(feature = "runtime")]
+pub mod mapper;
+#[cfg(feature = "runtime")]
+pub mod memory;
+#[cfg(feature = "server")]
+pub mod middleware;
+#[cfg(feature = "tools")]
+pub mod pcap;
+#[cfg(feature = "runtime")]
+pub mod postmortem;
+pub mod prediction;
 mod profile;
+#[cfg(feature = "runtime")]
+pub mod proxy;
+#[cfg(feature = "runtime")]
 mod queue;
+#[cfg(feature = "server")]
+pub mod registry;
+#[cfg(feature = "runtime")]
+pub mod replay;
+#[cfg(feature = "runtime")]
+pub mod rotation;
+#[cfg(feature = "runtime")]
+pub mod send_queue;
+#[cfg(feature = "runtime")]
+pub mod sensor;
+#[cfg(feature = "runtime")]
+pub mod signature;
+#[cfg(feature = "tools")]
+pub mod report;
+#[cfg(feature = "server")]
+mod session;
+#[cfg(feature = "runtime")]
 mod setting;
+#[cfg(feature = "runtime")]
 mod socket;
+#[cfg(feature = "runtime")]
 mod source;
+#[cfg(feature = "client")]
+pub mod spool;
+#[cfg(feature = "runtime")]
+pub mod stats;
+#[cfg(feature = "client")]
+mod system;
+#[cfg(feature = "runtime")]
+pub mod tcp_info;
+#[cfg(feature = "runtime")]
+pub mod ticker;
+#[cfg(feature = "runtime")]
+pub mod tolerance;
+#[cfg(feature = "runtime")]
+pub mod transcode;
+#[cfg(feature = "server")]
+pub mod tradeoff;
+#[cfg(feature = "runtime")]
+pub mod uplink;
+#[cfg(feature = "server")]
 mod utils;
+#[cfg(feature = "runtime")]
 mod video;
+#[cfg(feature = "client")]
+pub mod warm_start;
+#[cfg(feature = "runtime")]
+pub mod watchdog;
+#[cfg(feature = "runtime")]
+pub mod wire;
+#[cfg(feature = "runtime")]
+pub mod wrr;
+#[cfg(feature = "client")]
 pub mod client;
+#[cfg(feature = "server")]
 pub mod server;
 
-use byteorder::{BigEndian, ReadBytesExt};
+#[cfg(feature = "runtime")]
 use bytes::{BufMut, BytesMut};
-use errors::*;
-use profile::SimpleProfile;
+#[cfg(feature = "runtime")]
+use admission::{Admission, AdmissionRequest};
+#[cfg(feature = "runtime")]
+use catalog::{ClientIdentity, HostedProfile};
+#[cfg(feature = "runtime")]
+use integrity::FrameDigest;
+#[cfg(feature = "runtime")]
+use stats::StatsSnapshot;
+pub use adaptation::{Action, AdaptAction, Adaptation, Decision, Policy, Signal};
+pub use bandwidth::Bandwidth;
+pub use config::{Capabilities, CapabilityCheck, ConfigDelta, Configurable, Demand, FieldChange};
+pub use errors::{Error, ErrorKind, Result, ResultExt};
+pub use profile::{Profile, ProfileBuilder, Record, SimpleProfile};
+#[cfg(feature = "runtime")]
 pub use setting::Setting;
+#[cfg(feature = "runtime")]
+pub use socket::{CounterMode, FramedRead, ReadLoad, Remaining, SharedSocket, Socket, SocketHandle, SocketHooks};
+#[cfg(feature = "runtime")]
+pub use source::{BlockingSource, Cancellation, NaturalBursts, Paced, PaddingPolicy, RecentFrames,
+                 Source, ZeroPadding};
+#[cfg(feature = "runtime")]
+pub use wire::{FrameFlags, FrameLimits, WireFormat};
+#[cfg(feature = "runtime")]
 use std::io::{self, Cursor};
+#[cfg(feature = "runtime")]
 use std::mem;
+#[cfg(feature = "runtime")]
 use tokio_io::codec::{Decoder, Encoder};
 
-/// Actions for adaptation.
-pub enum AdaptAction {
-    /// Adapts to a designated bandwidth in kbps.
-    ToRate(f64),
-
-    /// Decreases the adaptation level.
-    DecreaseDegradation,
-
-    /// Starts probing with target bandwidth in kbps.
-    StartProbe(f64),
-
-    /// Increases probe pace.
-    IncreaseProbePace,
-
-    /// Stops the probing.
-    StopProbe,
-}
-
 /// The core trait that a struct should react by changing levels.
+#[cfg(feature = "runtime")]
 pub trait Adapt {
     /// Adapts to a bandwidth constraint.
-    fn adapt(&mut self, bandwidth: f64);
+    fn adapt(&mut self, bandwidth: Bandwidth);
 
     /// Decreases the current degradation level.
     fn dec_degradation(&mut self);
 
+    /// Moves to a designated level.
+    fn set_level(&mut self, level: usize);
+
+    /// Moves to a designated level, failing if the source cannot switch to
+    /// it right now (e.g., the camera rejects the mode). The level must then
+    /// stay unchanged. Sources that cannot fail just `set_level`.
+    fn try_set_level(&mut self, level: usize) -> Result<()> {
+        self.set_level(level);
+        Ok(())
+    }
+
     /// Period
     fn period_in_ms(&self) -> u64;
 
@@ -105,89 +286,394 @@ pub trait Adapt {
 
     /// Return a simple profile
     fn simple_profile(&self) -> SimpleProfile;
+
+    /// Moves the accuracy of `level` towards `quality`, as measured by the
+    /// receiver's analytics, by `weight` (between 0 and 1). Sources without
+    /// a profile ignore it.
+    fn observe_accuracy(&mut self, _level: usize, _quality: f64, _weight: f64) {}
+
+    /// The frame rate `level` specifies (see `Demand::fps`), enforced with
+    /// `Setting::frame_rate_enforcement`. Sources without a profile specify
+    /// none.
+    fn frame_rate(&self, _level: usize) -> Option<f64> {
+        None
+    }
+
+    /// Checks every level of the profile against `caps` (see
+    /// `Profile::restrict`), returning the masked levels. Sources without a
+    /// profile accept anything.
+    fn restrict(&mut self, _caps: &Capabilities, _check: CapabilityCheck) -> Result<Vec<usize>> {
+        Ok(Vec::new())
+    }
+
+    /// Replaces the profile with `csv` (e.g., hosted by the server), whose
+    /// rows are in the source's config format. Sources without a replaceable
+    /// profile fail.
+    fn replace_profile(&mut self, _csv: &str) -> Result<()> {
+        bail!(ErrorKind::InvalidConfig("the source has no replaceable profile".into()))
+    }
+
+    /// What the stream is, announced to the server before the first frame
+    /// and whenever it changes (e.g., with the level). Checked with every
+    /// frame, so it should be cheap. Unknown by default.
+    fn stream_info(&self) -> Option<StreamInfo> {
+        None
+    }
 }
 
 /// For experiment
+#[cfg(feature = "runtime")]
 pub trait Experiment {
     /// Return the size of next datum and its index.
     fn next_datum(&mut self) -> (usize, usize);
+
+    /// Returns the annotation of the datum with index `frame_num` (as returned
+    /// by the last `next_datum`), if the source knows it.
+    fn annotation(&self, _frame_num: usize) -> Option<Annotation> {
+        None
+    }
 }
 
 #[derive(Debug)]
+#[cfg(feature = "runtime")]
 enum CodecState {
     Len,
-    Payload { len: u64 },
+    Payload { len: u64, flags: FrameFlags },
 }
 
+#[cfg(feature = "runtime")]
 impl Default for AsCodec {
     fn default() -> Self {
-        AsCodec { state: CodecState::Len }
+        AsCodec::new(WireFormat::default())
     }
 }
 
 #[derive(Debug)]
 /// A wrapping codec to use Tokio.
+#[cfg(feature = "runtime")]
 pub struct AsCodec {
     state: CodecState,
+    format: WireFormat,
+    limits: FrameLimits,
 }
 
+#[cfg(feature = "runtime")]
+impl AsCodec {
+    /// Creates a codec framing datums with `format`, which should be valid
+    /// (see `WireFormat::validate`).
+    pub fn new(format: WireFormat) -> AsCodec {
+        debug_assert!(format.validate().is_ok());
+        AsCodec {
+            state: CodecState::Len,
+            format,
+            limits: FrameLimits::default(),
+        }
+    }
+
+    /// Fails to encode datums beyond `limits`, and to decode frames longer
+    /// than they allow (see `FrameLimits::max_frame_len`).
+    pub fn set_limits(&mut self, limits: FrameLimits) {
+        self.limits = limits;
+    }
+}
+
+#[allow(clippy::len_without_is_empty)]
+#[cfg(feature = "runtime")]
 impl AsDatum {
-    /// Creates a new `AsDatum` object for live data.
-    pub fn new(level: usize, frame_num: usize, data: Vec<u8>) -> AsDatum {
-        let now = chrono::Utc::now();
+    /// Creates a datum of type `t` carrying `mem`, stamped with current time.
+    fn with_type(t: AsDatumType, mem: Vec<u8>) -> AsDatum {
         let mut d = AsDatum {
-            t: AsDatumType::Live(level, frame_num),
-            ts: now,
-            mem: data,
+            t,
+            ts: chrono::Utc::now(),
+            mem,
+            annotation: None,
+            seq: None,
+            digest: None,
+            flags: FrameFlags::empty(),
             len: 0,
         };
         d.update_len();
         d
     }
 
+    /// Creates a new `AsDatum` object for live data.
+    pub fn new(level: usize, frame_num: usize, data: Vec<u8>) -> AsDatum {
+        AsDatum::with_type(AsDatumType::Live(level, frame_num), data)
+    }
+
     /// Creates a new `AsDatum` object for probing.
     pub fn bw_probe(size: usize) -> AsDatum {
-        let now = chrono::Utc::now();
-        let mut d = AsDatum {
-            t: AsDatumType::Dummy,
-            ts: now,
-            mem: vec![0; size],
-            len: 0,
-        };
-        d.update_len();
-        d
+        AsDatum::with_type(AsDatumType::Dummy, vec![0; size])
     }
 
     /// Creates a new `AsDatum` object for probing RTT.
     pub fn latency_probe() -> AsDatum {
-        let now = chrono::Utc::now();
-        let mut d = AsDatum {
-            t: AsDatumType::LatencyProbe,
-            ts: now,
-            mem: vec![0; 0],
-            len: 0,
-        };
-        d.update_len();
-        d
+        AsDatum::with_type(AsDatumType::LatencyProbe, vec![0; 0])
     }
 
     /// Creates a new `AsDatum` object for acknowledgement.
     pub fn ack(rr: ReceiverReport) -> Result<AsDatum> {
-        let now = chrono::Utc::now();
         let mem = rr.to_mem()?;
-        let mut d = AsDatum {
-            t: AsDatumType::ReceiverCongest,
-            ts: now,
-            mem: mem,
-            len: 0,
-        };
-        d.update_len();
-        Ok(d)
+        Ok(AsDatum::with_type(AsDatumType::ReceiverCongest, mem))
+    }
+
+    /// Creates a new `AsDatum` object carrying the receiver's analytics
+    /// quality.
+    pub fn quality(report: QualityReport) -> Result<AsDatum> {
+        let mem = bincode::serialize(&report, bincode::Infinite)?;
+        Ok(AsDatum::with_type(AsDatumType::Quality, mem))
+    }
+
+    /// Creates a new `AsDatum` object carrying the receiver's regions or
+    /// classes of interest.
+    pub fn hint(hint: &Hint) -> Result<AsDatum> {
+        let mem = bincode::serialize(hint, bincode::Infinite)?;
+        Ok(AsDatum::with_type(AsDatumType::Hint, mem))
+    }
+
+    /// Creates a new `AsDatum` object describing the stream.
+    pub fn stream_info(info: &StreamInfo) -> Result<AsDatum> {
+        let mem = bincode::serialize(info, bincode::Infinite)?;
+        Ok(AsDatum::with_type(AsDatumType::StreamInfo, mem))
+    }
+
+    /// Creates the datum `seq` of the sub-stream `stream`.
+    pub fn sub_stream(stream: u32, seq: usize, data: Vec<u8>) -> AsDatum {
+        AsDatum::with_type(AsDatumType::Sub(stream, seq), data)
+    }
+
+    /// Creates a new `AsDatum` object carrying an operator's `Directive`.
+    pub fn directive(directive: &Directive) -> Result<AsDatum> {
+        let mem = bincode::serialize(directive, bincode::Infinite)?;
+        Ok(AsDatum::with_type(AsDatumType::Directive, mem))
+    }
+
+    /// Creates the last datum of a connection closed on purpose, carrying
+    /// why.
+    pub fn close(reason: CloseReason) -> Result<AsDatum> {
+        let mem = bincode::serialize(&reason, bincode::Infinite)?;
+        Ok(AsDatum::with_type(AsDatumType::Close, mem))
+    }
+
+    /// Creates the handshake datum of a client, with the resumption token of
+    /// a previous session if any.
+    pub fn hello(token: Option<u64>) -> AsDatum {
+        AsDatum::with_type(AsDatumType::Hello(token), Vec::new())
+    }
+
+    /// Creates the handshake datum of a client introducing itself as
+    /// `identity` (see `catalog`).
+    pub fn hello_as(token: Option<u64>, identity: &ClientIdentity) -> Result<AsDatum> {
+        if identity.is_empty() {
+            return Ok(AsDatum::hello(token));
+        }
+        let mem = bincode::serialize(identity, bincode::Infinite)?;
+        Ok(AsDatum::with_type(AsDatumType::Hello(token), mem))
+    }
+
+    /// Creates the first datum of a connection as `hello_as`, asking to be
+    /// admitted as `request` says.
+    pub fn hello_requesting(
+        token: Option<u64>,
+        identity: &ClientIdentity,
+        request: &AdmissionRequest,
+    ) -> Result<AsDatum> {
+        // the request follows the identity, which older servers read alone
+        let mut mem = bincode::serialize(identity, bincode::Infinite)?;
+        mem.extend(bincode::serialize(request, bincode::Infinite)?);
+        Ok(AsDatum::with_type(AsDatumType::Hello(token), mem))
+    }
+
+    /// Returns the admission request of a `Hello`, if any.
+    pub fn admission_request(&self) -> Result<Option<AdmissionRequest>> {
+        if let AsDatumType::Hello(_) = self.t {
+            let mut cursor = Cursor::new(&self.mem);
+            let identity: bincode::Result<ClientIdentity> =
+                bincode::deserialize_from(&mut cursor, bincode::Infinite);
+            if identity.is_ok() && (cursor.position() as usize) < self.mem.len() {
+                return Ok(Some(bincode::deserialize_from(&mut cursor, bincode::Infinite)?));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns who the client of a `Hello` is (empty if it didn't say).
+    pub fn client_identity(&self) -> Result<ClientIdentity> {
+        match self.t {
+            AsDatumType::Hello(_) if !self.mem.is_empty() => match bincode::deserialize(&self.mem) {
+                Ok(identity) => Ok(identity),
+                Err(_) => {
+                    // clients predating stream groups
+                    let (client_id, stream) = bincode::deserialize(&self.mem)?;
+                    Ok(ClientIdentity {
+                        client_id,
+                        stream,
+                        group: None,
+                    })
+                }
+            },
+            _ => Ok(ClientIdentity::default()),
+        }
+    }
+
+    /// Creates the server's reply to `hello`.
+    pub fn welcome(token: u64) -> AsDatum {
+        AsDatum::with_type(AsDatumType::Welcome(token), Vec::new())
+    }
+
+    /// Creates the server's reply to `hello`, handing over `profile`.
+    pub fn welcome_with_profile(token: u64, profile: &HostedProfile) -> Result<AsDatum> {
+        let mem = bincode::serialize(profile, bincode::Infinite)?;
+        Ok(AsDatum::with_type(AsDatumType::Welcome(token), mem))
+    }
+
+    /// Returns the profile carried by a `Welcome`, if any.
+    pub fn hosted_profile(&self) -> Result<Option<HostedProfile>> {
+        match self.t {
+            AsDatumType::Welcome(_) if !self.mem.is_empty() => Ok(Some(bincode::deserialize(&self.mem)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Creates the server's answer to an admission request.
+    pub fn admission(admission: Admission) -> Result<AsDatum> {
+        let mem = bincode::serialize(&admission, bincode::Infinite)?;
+        Ok(AsDatum::with_type(AsDatumType::Admission, mem))
+    }
+
+    /// Returns the answer carried by an `Admission` datum, if it is one.
+    pub fn admission_answer(&self) -> Result<Option<Admission>> {
+        match self.t {
+            AsDatumType::Admission => Ok(Some(bincode::deserialize(&self.mem)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Creates a datum carrying a client's statistics.
+    pub fn stats(snapshot: &StatsSnapshot) -> Result<AsDatum> {
+        let mem = bincode::serialize(snapshot, bincode::Infinite)?;
+        Ok(AsDatum::with_type(AsDatumType::Stats, mem))
+    }
+
+    /// Returns the statistics carried by a `Stats` datum, if it is one.
+    pub fn stats_snapshot(&self) -> Result<Option<StatsSnapshot>> {
+        match self.t {
+            AsDatumType::Stats => Ok(Some(bincode::deserialize(&self.mem)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Creates the first datum of a control connection for session `token`.
+    pub fn control(token: u64) -> AsDatum {
+        AsDatum::with_type(AsDatumType::Control(token), Vec::new())
+    }
+
+    /// Creates the compression dictionary of the live frames that follow
+    /// (see `dictionary`).
+    pub fn dictionary(dict: Vec<u8>) -> AsDatum {
+        AsDatum::with_type(AsDatumType::Dictionary, dict)
+    }
+
+    /// Announces that the payloads of the live frames that follow are delta
+    /// coded (see `delta`).
+    pub fn delta() -> AsDatum {
+        AsDatum::with_type(AsDatumType::Delta, Vec::new())
+    }
+
+    /// Creates a barrier ahead of the first frame of `level`.
+    pub fn barrier(level: usize) -> AsDatum {
+        AsDatum::with_type(AsDatumType::Barrier(level), Vec::new())
+    }
+
+    /// Marks a live frame as backfill, i.e., sent late after an outage.
+    /// Other datums are returned as is.
+    pub fn into_backfill(mut self) -> AsDatum {
+        if let AsDatumType::Live(level, frame_num) = self.t {
+            self.t = AsDatumType::Backfill(level, frame_num);
+            self.update_len();
+        }
+        self
+    }
+
+    /// Marks a live frame as a redundant copy, sent again as probe padding.
+    /// Other datums are returned as is.
+    pub fn into_redundant(mut self) -> AsDatum {
+        if let AsDatumType::Live(level, frame_num) = self.t {
+            self.t = AsDatumType::Redundant(level, frame_num);
+            self.update_len();
+        }
+        self
+    }
+
+    /// Attaches an accuracy annotation (e.g., ground truth or the source's
+    /// confidence) that is carried to the server's experiment log.
+    pub fn with_annotation(mut self, annotation: Annotation) -> AsDatum {
+        self.annotation = Some(annotation);
+        self.update_len();
+        self
+    }
+
+    /// Returns the annotation attached by the source, if any.
+    pub fn annotation(&self) -> Option<Annotation> {
+        self.annotation
+    }
+
+    /// Numbers a live frame in the order it is sent (see `seq`).
+    pub fn with_seq(mut self, seq: usize) -> AsDatum {
+        self.seq = Some(seq);
+        self.update_len();
+        self
+    }
+
+    /// Returns the sender's count of live frames sent before this one, if
+    /// numbered.
+    pub fn seq(&self) -> Option<usize> {
+        self.seq
+    }
+
+    /// Attaches the digest of the frame's content (see `integrity`).
+    pub fn with_digest(mut self, digest: FrameDigest) -> AsDatum {
+        self.digest = Some(digest);
+        self.update_len();
+        self
+    }
+
+    /// Returns the digest the frame was sealed with, if any.
+    pub fn digest(&self) -> Option<FrameDigest> {
+        self.digest
+    }
+
+    /// Marks the frame with `flags` (e.g., `FrameFlags::COMPRESSED` after
+    /// compressing its payload), in addition to those implied by its type.
+    pub fn with_flags(mut self, flags: FrameFlags) -> AsDatum {
+        self.flags |= flags;
+        self
+    }
+
+    /// The flags of the frame: those implied by its type and those it was
+    /// marked with (or received with).
+    pub fn flags(&self) -> FrameFlags {
+        let mut flags = FrameFlags::of(self.datum_type()) | self.flags;
+        flags.set(FrameFlags::TRAILER, self.trailer().is_some());
+        flags
+    }
+
+    /// What travels after the serialized datum, if anything.
+    fn trailer(&self) -> Option<Trailer> {
+        match (self.annotation, self.seq) {
+            (None, None) => None,
+            (annotation, seq) => Some(Trailer { annotation, seq }),
+        }
     }
 
     fn update_len(&mut self) {
-        // effective length includes the encoding of the length itself.
         self.len = bincode::serialized_size(self);
+        if let Some(ref trailer) = self.trailer() {
+            self.len += bincode::serialized_size(trailer);
+        }
+        if self.digest.is_some() {
+            self.len += FrameDigest::LEN as u64;
+        }
     }
 
     /// Returns the effective length (in bytes) for network transmission.
@@ -196,6 +682,11 @@ impl AsDatum {
         self.len as usize + mem::size_of::<u64>()
     }
 
+    /// Returns the serialized size of everything but the payload bytes.
+    pub fn metadata_len(&self) -> usize {
+        self.len as usize - self.mem.len()
+    }
+
     /// Returns the datum type.
     pub fn datum_type(&self) -> AsDatumType {
         self.t
@@ -207,6 +698,7 @@ impl AsDatum {
     }
 }
 
+#[cfg(feature = "runtime")]
 impl ::std::fmt::Display for AsDatum {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         match self.t {
@@ -219,16 +711,42 @@ impl ::std::fmt::Display for AsDatum {
                     .field("len", &self.len())
                     .finish()
             }
+            AsDatumType::Backfill(level, frame_num) => {
+                write!(f, "backfill level {} frame {}: {}", level, frame_num, self.len)
+            }
+            AsDatumType::Redundant(level, frame_num) => {
+                write!(f, "redundant level {} frame {}: {}", level, frame_num, self.len)
+            }
+            AsDatumType::Reference(level, frame_num) => {
+                write!(f, "reference level {} frame {}: {}", level, frame_num, self.len)
+            }
             AsDatumType::Raw => write!(f, "raw data: {}", self.len),
             AsDatumType::Dummy => write!(f, "probe data: {}", self.len),
             AsDatumType::LatencyProbe => write!(f, "probe latency"),
             AsDatumType::ReceiverCongest => write!(f, "receiver congest"),
+            AsDatumType::Hello(token) => write!(f, "hello {:?}", token),
+            AsDatumType::Welcome(token) => write!(f, "welcome {}", token),
+            AsDatumType::Control(token) => write!(f, "control {}", token),
+            AsDatumType::Quality => write!(f, "quality report"),
+            AsDatumType::Hint => write!(f, "hint"),
+            AsDatumType::Directive => write!(f, "directive"),
+            AsDatumType::Barrier(level) => write!(f, "barrier to level {}", level),
+            AsDatumType::Dictionary => write!(f, "dictionary: {}", self.len),
+            AsDatumType::Admission => write!(f, "admission"),
+            AsDatumType::Delta => write!(f, "delta"),
+            AsDatumType::Close => write!(f, "close"),
+            AsDatumType::Stats => write!(f, "stats"),
+            AsDatumType::StreamInfo => write!(f, "stream info"),
+            AsDatumType::Sub(stream, seq) => {
+                write!(f, "sub-stream {} datum {}: {}", stream, seq, self.len)
+            }
         }
     }
 }
 
 /// Datum type.
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg(feature = "runtime")]
 pub enum AsDatumType {
     /// Actual live data (meaningful), with (level, frame_num)
     Live(usize, usize),
@@ -244,41 +762,382 @@ pub enum AsDatumType {
 
     /// Signals that the receiver detects congestion.
     ReceiverCongest,
+
+    /// First datum of a connection: opens a session, or resumes the session
+    /// of the given token.
+    Hello(Option<u64>),
+
+    /// The server's reply to `Hello`, carrying the session's resumption token.
+    Welcome(u64),
+
+    /// First datum of a control connection: attaches it to the session of
+    /// the given token, so that feedback bypasses the data connection.
+    Control(u64),
+
+    /// A live frame held back during an outage and sent after reconnecting,
+    /// with (level, frame_num).
+    Backfill(usize, usize),
+
+    /// A copy of a recently sent live frame, carried as probe padding, with
+    /// (level, frame_num).
+    Redundant(usize, usize),
+
+    /// A live frame whose payload was uploaded to a blob store, carrying a
+    /// `blob::BlobRef` instead, with (level, frame_num).
+    Reference(usize, usize),
+
+    /// The quality of the analytics at the receiver, carrying a
+    /// `QualityReport`.
+    Quality,
+
+    /// What the receiver is interested in, carrying a `Hint`.
+    Hint,
+
+    /// An operator's instruction to the client, carrying a `Directive`.
+    Directive,
+
+    /// Separates the frames of two configurations: every frame after it is
+    /// of the given level.
+    Barrier(usize),
+
+    /// The zstd dictionary the payloads of later live frames are
+    /// compressed with.
+    Dictionary,
+
+    /// The server's answer to an admission request it can't grant as is
+    /// (see `admission`).
+    Admission,
+
+    /// The payloads of later live frames are delta coded (see `delta`).
+    Delta,
+
+    /// The last datum of a connection closed on purpose, carrying a
+    /// `CloseReason`.
+    Close,
+
+    /// A client's statistics, carrying a `stats::StatsSnapshot` (see
+    /// `collector`).
+    Stats,
+
+    /// What the stream is, carrying a `StreamInfo`: sent before the first
+    /// frame and whenever it changes.
+    StreamInfo,
+
+    /// A datum of one of the application's sub-streams (see `wrr`), with
+    /// (stream id, sequence number).
+    Sub(u32, usize),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-/// Statistics report from the receiver side.
+/// Live frames numbered up to this start a stream, about the first second of
+/// a 30 fps source.
+#[cfg(feature = "runtime")]
+const RESTART_WITHIN: usize = 30;
+
+/// Returns true if live frame `frame_num`, following frame `last`, restarts
+/// the numbering of the stream (e.g., `VideoSource` looping over its input)
+/// rather than going back to an earlier frame: it jumps from beyond the
+/// first `RESTART_WITHIN` frames back into them.
+#[cfg(feature = "runtime")]
+pub(crate) fn restarts_numbering(last: usize, frame_num: usize) -> bool {
+    frame_num <= RESTART_WITHIN && last > RESTART_WITHIN
+}
+
+/// Per-frame accuracy annotation attached by the source, so that the server
+/// can compute delivered accuracy rather than only delivered bitrate.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[cfg(feature = "runtime")]
+pub enum Annotation {
+    /// Ground-truth accuracy of this frame at its level (e.g., F1 score
+    /// against labels).
+    GroundTruth(f64),
+
+    /// The source's confidence (between 0 and 1) in the frame's analytics.
+    Confidence(f64),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+/// Statistics report from the receiver side. On the wire, `receiver_limited`
+/// follows the fields of the first version, which older peers read alone.
+#[cfg(feature = "runtime")]
 pub struct ReceiverReport {
     latency: f64,
-    goodput: f64,
-    throughput: f64,
+    goodput: Bandwidth,
+    throughput: Bandwidth,
+    receiver_limited: bool,
 }
 
+#[cfg(feature = "runtime")]
 impl ReceiverReport {
     /// Creates
-    pub fn new(latency: f64, goodput: f64, throughput: f64) -> Self {
+    pub fn new(latency: f64, goodput: Bandwidth, throughput: Bandwidth) -> Self {
         ReceiverReport {
-            latency: latency,
-            goodput: goodput,
-            throughput: throughput,
+            latency,
+            goodput,
+            throughput,
+            receiver_limited: false,
         }
     }
 
+    /// Marks whether the receiving application, not the network, is the
+    /// bottleneck.
+    pub fn with_receiver_limited(mut self, limited: bool) -> Self {
+        self.receiver_limited = limited;
+        self
+    }
+
+    /// Returns true if the backpressure comes from the receiving
+    /// application rather than the network, so degrading won't help.
+    pub fn receiver_limited(&self) -> bool {
+        self.receiver_limited
+    }
+
+    /// Latency (ms) of the frame that triggered the report.
+    pub fn latency(&self) -> f64 {
+        self.latency
+    }
+
+    /// Goodput measured by the receiver.
+    pub fn goodput(&self) -> Bandwidth {
+        self.goodput
+    }
+
+    /// Throughput measured by the receiver.
+    pub fn throughput(&self) -> Bandwidth {
+        self.throughput
+    }
+
     /// Decode from memory
-    pub fn from_mem(mem: &Vec<u8>) -> Result<ReceiverReport> {
-        let report = bincode::deserialize(&mem[..])?;
+    pub fn from_mem(mem: &[u8]) -> Result<ReceiverReport> {
+        let mut cursor = Cursor::new(mem);
+        let (latency, goodput, throughput) =
+            bincode::deserialize_from(&mut cursor, bincode::Infinite)?;
+        let mut report = ReceiverReport::new(latency, goodput, throughput);
+        // absent from the reports of older servers
+        if (cursor.position() as usize) < mem.len() {
+            report.receiver_limited = bincode::deserialize_from(&mut cursor, bincode::Infinite)?;
+        }
         Ok(report)
     }
 
     /// Encode into memory
     pub fn to_mem(&self) -> Result<Vec<u8>> {
-        let mem = bincode::serialize(&self, bincode::Infinite)?;
+        let first = (self.latency, self.goodput, self.throughput);
+        let mut mem = bincode::serialize(&first, bincode::Infinite)?;
+        mem.extend(bincode::serialize(&self.receiver_limited, bincode::Infinite)?);
         Ok(mem)
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+/// Quality of the analytics at the receiver (e.g., detector confidence), for
+/// one frame or for a window of frames at a level. Sent by the server
+/// application through `server::QualityFeedback`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[cfg(feature = "runtime")]
+pub struct QualityReport {
+    /// The level of the frames analyzed.
+    pub level: usize,
+
+    /// The frame analyzed, or `None` for a window of frames.
+    pub frame_num: Option<usize>,
+
+    /// The quality, between 0 and 1.
+    pub quality: f64,
+}
+
+#[cfg(feature = "runtime")]
+impl QualityReport {
+    /// Decodes the report carried by a `Quality` datum, rejecting a quality
+    /// that is not between 0 and 1.
+    pub fn from_mem(mem: &[u8]) -> Result<QualityReport> {
+        let report: QualityReport = bincode::deserialize(mem)?;
+        if !(0.0..=1.0).contains(&report.quality) {
+            bail!(ErrorKind::DecodeError);
+        }
+        Ok(report)
+    }
+}
+
+/// A rectangle of the frame, in fractions (between 0 and 1) of its width and
+/// height from the top-left corner.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[cfg(feature = "runtime")]
+pub struct Region {
+    /// Left edge.
+    pub x: f64,
+
+    /// Top edge.
+    pub y: f64,
+
+    /// Width.
+    pub width: f64,
+
+    /// Height.
+    pub height: f64,
+}
+
+/// What the receiver's analytics are interested in, so that the source can
+/// crop or spend its bits accordingly. Sent by the server application
+/// through `server::QualityFeedback` and delivered to `Source::on_hint`;
+/// empty lists mean no preference.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[cfg(feature = "runtime")]
+pub struct Hint {
+    /// Regions of interest.
+    pub regions: Vec<Region>,
+
+    /// Object classes of interest (e.g., "person").
+    pub classes: Vec<String>,
+}
+
+#[cfg(feature = "runtime")]
+impl Hint {
+    /// Decodes the hint carried by a `Hint` datum.
+    pub fn from_mem(mem: &[u8]) -> Result<Hint> {
+        Ok(bincode::deserialize(mem)?)
+    }
+}
+
+/// What a stream is, announced in-band by the client (see
+/// `Source::stream_info`) so that the server's handlers and whatever archives
+/// the frames can tell without out-of-band configuration. Unknown fields are
+/// left unset.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[cfg(feature = "runtime")]
+pub struct StreamInfo {
+    /// The codec of the payloads, e.g., `h264`.
+    pub codec: Option<String>,
+
+    /// The resolution at the current level, in the domain of the stream,
+    /// e.g., `640w@15fps` for video or `100ms` windows for a sensor.
+    pub resolution: Option<String>,
+
+    /// The units of the values carried, e.g., `celsius`.
+    pub units: Option<String>,
+
+    /// The kind of sensor producing the stream, e.g., `thermal-camera`.
+    pub sensor: Option<String>,
+
+    /// Identifies the profile the levels come from.
+    pub profile_id: Option<String>,
+}
+
+#[cfg(feature = "runtime")]
+impl StreamInfo {
+    /// Decodes the description carried by a `StreamInfo` datum.
+    pub fn from_mem(mem: &[u8]) -> Result<StreamInfo> {
+        Ok(bincode::deserialize(mem)?)
+    }
+}
+
+/// An instruction pushed to a client by an operator (e.g., through the
+/// server's admin endpoint), taking precedence over adaptation.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[cfg(feature = "runtime")]
+pub enum Directive {
+    /// Keeps the levels within this bandwidth, or lifts the cap.
+    BandwidthCap(Option<Bandwidth>),
+
+    /// Holds a level for a duration (ms), like `Client::force_level`.
+    ForceLevel(usize, u64),
+
+    /// Ends a forced level, like `Client::resume_auto`.
+    ResumeAuto,
+}
+
+#[cfg(feature = "runtime")]
+impl Directive {
+    /// Decodes the directive carried by a `Directive` datum.
+    pub fn from_mem(mem: &[u8]) -> Result<Directive> {
+        Ok(bincode::deserialize(mem)?)
+    }
+}
+
+/// Why a connection was closed on purpose. The end closing it sends a
+/// `Close` datum last, so that the other end can tell it from a connection
+/// that dropped. The server closes connections:
+///
+/// ```text
+/// operator        through the admin endpoint (POST /sessions/<token>/close)
+/// idle            after `idle_timeout_ms` without a datum
+/// auth_failure    when a middleware turns the client down
+/// protocol_error  after too many malformed frames
+/// migrating       to move the client to another instance (?reason=migrating)
+/// ```
+///
+/// and the client with `finished` once its stream ended. Applications on an
+/// `Endpoint` may close with a reason too (`Endpoint::close`). Old peers
+/// don't know the `Close` datum, so both ends need to support it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+#[cfg(feature = "runtime")]
+pub enum CloseReason {
+    /// An operator closed it.
+    Operator,
+
+    /// Nothing arrived for too long.
+    Idle,
+
+    /// The peer was not allowed in.
+    AuthFailure,
+
+    /// The peer broke the protocol.
+    ProtocolError,
+
+    /// The session moves to another server instance.
+    Migrating,
+
+    /// The stream ended.
+    Finished,
+}
+
+#[cfg(feature = "runtime")]
+impl CloseReason {
+    /// All reasons.
+    pub const ALL: [CloseReason; 6] = [
+        CloseReason::Operator,
+        CloseReason::Idle,
+        CloseReason::AuthFailure,
+        CloseReason::ProtocolError,
+        CloseReason::Migrating,
+        CloseReason::Finished,
+    ];
+
+    /// Decodes the reason carried by a `Close` datum.
+    pub fn from_mem(mem: &[u8]) -> Result<CloseReason> {
+        Ok(bincode::deserialize(mem)?)
+    }
+}
+
+#[cfg(feature = "runtime")]
+impl ::std::fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        let name = match *self {
+            CloseReason::Operator => "operator",
+            CloseReason::Idle => "idle",
+            CloseReason::AuthFailure => "auth_failure",
+            CloseReason::ProtocolError => "protocol_error",
+            CloseReason::Migrating => "migrating",
+            CloseReason::Finished => "finished",
+        };
+        f.write_str(name)
+    }
+}
+
+#[cfg(feature = "runtime")]
+impl ::std::str::FromStr for CloseReason {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<CloseReason> {
+        match CloseReason::ALL.iter().find(|r| r.to_string() == s) {
+            Some(&reason) => Ok(reason),
+            None => bail!(ErrorKind::InvalidConfig(format!("unknown close reason {}", s))),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 /// `AsDatum` is the core data object for streaming over the network.
+#[cfg(feature = "runtime")]
 pub struct AsDatum {
     /// The type of this datum.
     t: AsDatumType,
@@ -291,6 +1150,29 @@ pub struct AsDatum {
     /// Timestamp associated with the sender. We use unix time at UTC.
     ts: chrono::DateTime<chrono::Utc>,
 
+    /// Optional per-frame accuracy annotation. Travels in the trailer (see
+    /// `Trailer`).
+    #[serde(skip)]
+    annotation: Option<Annotation>,
+
+    /// The sender's count of live frames sent before this one, so that the
+    /// receiver tells frames lost on the way from frames the source skipped
+    /// (which leave gaps in the frame numbers). Travels in the trailer (see
+    /// `Trailer`).
+    #[serde(skip)]
+    seq: Option<usize>,
+
+    /// Optional digest of the frame's content, for integrity audits. Travels
+    /// after the serialized datum (see `integrity`).
+    #[serde(skip)]
+    digest: Option<FrameDigest>,
+
+    /// Flags beyond those implied by the type, e.g., set by a transform of
+    /// the payload, or as received. Travels in the frame header (see
+    /// `WireFormat::flags`).
+    #[serde(skip)]
+    flags: FrameFlags,
+
     /// The size of serialized version of this data structure (except this
     /// field). We use this field as a cache to avoid repeated call for
     /// serialization.
@@ -298,6 +1180,17 @@ pub struct AsDatum {
     len: u64,
 }
 
+/// What follows the serialized datum (ahead of its digest, if sealed) when
+/// the frame is flagged `FrameFlags::TRAILER`. Receivers unaware of it, e.g.,
+/// older peers and the device, read the datum alone.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[cfg(feature = "runtime")]
+struct Trailer {
+    annotation: Option<Annotation>,
+    seq: Option<usize>,
+}
+
+#[cfg(feature = "runtime")]
 impl Decoder for AsCodec {
     type Item = AsDatum;
     type Error = Error;
@@ -306,15 +1199,25 @@ impl Decoder for AsCodec {
         // trace!("Decode: {:?}", buf);
         loop {
             match self.state {
-                CodecState::Len if buf.len() < mem::size_of::<u64>() => {
-                    trace!("--> Buf len is {}; waiting for 8 to parse len.", buf.len());
+                CodecState::Len if buf.len() < self.format.header_len() => {
+                    trace!(
+                        "--> Buf len is {}; waiting for {} to parse len.",
+                        buf.len(),
+                        self.format.header_len()
+                    );
                     return Ok(None);
                 }
                 CodecState::Len => {
-                    let mut len_buf = buf.split_to(mem::size_of::<u64>());
-                    let len = Cursor::new(&mut len_buf).read_u64::<BigEndian>()?;
+                    let len_buf = buf.split_to(self.format.header_len());
+                    let len = self.format.read_header(&len_buf);
+                    let flags = self.format.read_flags(&len_buf);
                     trace!("--> Parsed len = {} from {:?}", len, len_buf);
-                    self.state = CodecState::Payload { len: len };
+                    // the frame isn't buffered: the stream can't go on past it
+                    let max = self.limits.max_frame_len();
+                    if len > max {
+                        bail!(ErrorKind::PayloadTooLarge(len as usize, max as usize));
+                    }
+                    self.state = CodecState::Payload { len, flags };
                 }
                 CodecState::Payload { len, .. } if buf.len() < len as usize => {
                     trace!(
@@ -324,15 +1227,39 @@ impl Decoder for AsCodec {
                     );
                     return Ok(None);
                 }
-                CodecState::Payload { len } => {
+                CodecState::Payload { len, flags } => {
                     let payload = buf.split_to(len as usize);
                     self.state = CodecState::Len;
-                    let mut datum: AsDatum =
-                        bincode::deserialize_from(&mut Cursor::new(payload), bincode::Infinite)
-                            .map_err(|deserialize_err| {
-                                io::Error::new(io::ErrorKind::Other, deserialize_err)
-                            })?;
+                    // the frame is consumed either way, so that decoding can
+                    // resume with the next one
+                    let mut cursor = Cursor::new(payload);
+                    let mut datum: AsDatum = bincode::deserialize_from(&mut cursor, bincode::Infinite)
+                        .chain_err(|| ErrorKind::DecodeError)?;
+                    // without a flags byte, anything after the datum but a
+                    // digest alone is a trailer
+                    let rest = cursor.get_ref().len() - cursor.position() as usize;
+                    let trailed = if self.format.flags {
+                        flags.contains(FrameFlags::TRAILER)
+                    } else {
+                        rest != 0 && rest != FrameDigest::LEN
+                    };
+                    if trailed {
+                        let trailer: Trailer = bincode::deserialize_from(&mut cursor, bincode::Infinite)
+                            .chain_err(|| ErrorKind::DecodeError)?;
+                        datum.annotation = trailer.annotation;
+                        datum.seq = trailer.seq;
+                    }
+                    let rest = &cursor.get_ref()[cursor.position() as usize..];
+                    if rest.len() == FrameDigest::LEN {
+                        datum.digest = Some(FrameDigest::from_slice(rest));
+                    }
                     datum.len = len;
+                    datum.flags = flags - FrameFlags::TRAILER;
+                    // a peer's broken clock would skew latencies and rates
+                    if !invariant::is_sane_timestamp(&datum.ts) {
+                        let cause = format!("{} ({} bytes) stamped {}", datum, len, datum.ts);
+                        return Err(Error::from(cause)).chain_err(|| ErrorKind::DecodeError);
+                    }
                     return Ok(Some(datum));
                 }
             }
@@ -340,30 +1267,52 @@ impl Decoder for AsCodec {
     }
 }
 
+#[cfg(feature = "runtime")]
+impl socket::Remaining for AsCodec {
+    fn remaining(&self, buffered: usize) -> usize {
+        match self.state {
+            CodecState::Len => self.format.header_len().saturating_sub(buffered),
+            CodecState::Payload { len, .. } => (len as usize).saturating_sub(buffered),
+        }
+    }
+}
+
+#[cfg(feature = "runtime")]
 impl Encoder for AsCodec {
     type Item = AsDatum;
     type Error = Error;
 
     fn encode(&mut self, d: AsDatum, buf: &mut BytesMut) -> Result<()> {
+        self.limits.check(&d)?;
         let payload_size = d.len;
-        let message_size = mem::size_of::<u64>() + payload_size as usize;
-        buf.reserve(message_size);
+        let mut header = [0; 9];
+        let header = &mut header[..self.format.header_len()];
+        self.format.write_header(payload_size, d.flags(), header)?;
+        buf.reserve(header.len() + payload_size as usize);
 
         // First write payload size
-        buf.put_u64::<BigEndian>(payload_size);
+        buf.put_slice(header);
         bincode::serialize_into(&mut buf.writer(), &d, bincode::Infinite)
             .map_err(|serialize_err| {
-                io::Error::new(io::ErrorKind::Other, serialize_err)
+                io::Error::other(serialize_err)
             })?;
+        if let Some(trailer) = d.trailer() {
+            bincode::serialize_into(&mut buf.writer(), &trailer, bincode::Infinite)
+                .map_err(io::Error::other)?;
+        }
+        if let Some(digest) = d.digest {
+            buf.put_slice(digest.as_bytes());
+        }
 
         // trace!("Encoded buffer: {:?}", buf);
         Ok(())
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "runtime"))]
 mod tests {
     use super::*;
+    use chrono::{TimeZone, Utc};
     #[test]
     fn encode_decode_works() {
         let d = AsDatum::new(0, 0, String::from("Hello").into_bytes());
@@ -380,4 +1329,126 @@ mod tests {
         let decoded = codec.decode(&mut buf);
         assert_eq!(decoded.unwrap().unwrap(), expected);
     }
+
+    #[test]
+    fn decoding_resumes_after_malformed_frame() {
+        let mut buf = bytes::BytesMut::new();
+        buf.put_u64_be(3);
+        buf.put_slice(&[0xff; 3]);
+        let mut codec = AsCodec::default();
+        // and after a frame stamped before any plausible capture
+        let mut stale = AsDatum::new(0, 0, vec![]);
+        stale.ts = Utc.timestamp_opt(0, 0).unwrap();
+        stale.update_len();
+        codec.encode(stale, &mut buf).unwrap();
+        codec.encode(AsDatum::new(0, 1, vec![]), &mut buf).unwrap();
+
+        for _ in 0..2 {
+            match codec.decode(&mut buf) {
+                Err(Error(ErrorKind::DecodeError, _)) => {}
+                r => panic!("unexpected {:?}", r),
+            }
+        }
+        let datum = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(datum.datum_type(), AsDatumType::Live(0, 1));
+    }
+
+    #[test]
+    fn quality_report_round_trip() {
+        let report = QualityReport {
+            level: 2,
+            frame_num: None,
+            quality: 0.75,
+        };
+        let mut buf = bytes::BytesMut::new();
+        let mut codec = AsCodec::default();
+        codec.encode(AsDatum::quality(report).unwrap(), &mut buf).unwrap();
+
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded.datum_type(), AsDatumType::Quality);
+        assert_eq!(QualityReport::from_mem(&decoded.mem).unwrap(), report);
+
+        // a quality the server could not have measured
+        for &quality in &[f64::NAN, -0.1, 1.5] {
+            let datum = AsDatum::quality(QualityReport { quality, ..report }).unwrap();
+            assert!(QualityReport::from_mem(&datum.mem).is_err());
+        }
+    }
+
+    #[test]
+    fn hint_round_trip() {
+        let hint = Hint {
+            regions: vec![Region { x: 0.25, y: 0.0, width: 0.5, height: 0.5 }],
+            classes: vec!["person".into()],
+        };
+        let mut buf = bytes::BytesMut::new();
+        let mut codec = AsCodec::default();
+        codec.encode(AsDatum::hint(&hint).unwrap(), &mut buf).unwrap();
+
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded.datum_type(), AsDatumType::Hint);
+        assert_eq!(Hint::from_mem(&decoded.mem).unwrap(), hint);
+    }
+
+    #[test]
+    fn annotation_survives_encoding() {
+        let plain = AsDatum::new(1, 7, vec![0; 16]);
+        let d = plain.clone().with_annotation(Annotation::GroundTruth(0.8));
+        let expected_len = d.net_len();
+        let mut buf = bytes::BytesMut::new();
+        let mut codec = AsCodec::default();
+        codec.encode(d, &mut buf).unwrap();
+        assert_eq!(buf.len(), expected_len);
+
+        // it travels in the trailer too
+        let mut old = bytes::BytesMut::new();
+        codec.encode(plain, &mut old).unwrap();
+        assert_eq!(buf[8..old.len()], old[8..]);
+
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded.annotation(), Some(Annotation::GroundTruth(0.8)));
+    }
+
+    #[test]
+    fn seq_travels_in_the_trailer() {
+        let plain = AsDatum::new(1, 7, vec![0; 16]);
+        let d = plain.clone().with_seq(41);
+        let expected_len = d.net_len();
+        let mut buf = bytes::BytesMut::new();
+        let mut codec = AsCodec::default();
+        codec.encode(d, &mut buf).unwrap();
+        assert_eq!(buf.len(), expected_len);
+
+        // the datum is laid out as without, for peers unaware of the trailer
+        let mut old = bytes::BytesMut::new();
+        codec.encode(plain, &mut old).unwrap();
+        assert_eq!(buf[8..old.len()], old[8..]);
+
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded.seq(), Some(41));
+        assert_eq!(codec.decode(&mut old).unwrap().unwrap().seq(), None);
+    }
+
+    #[test]
+    fn receiver_report_reads_the_first_version() {
+        let (goodput, throughput) = (Bandwidth::from_kbps(800.0), Bandwidth::from_kbps(900.0));
+        let report = ReceiverReport::new(12.5, goodput, throughput);
+        let limited = report.with_receiver_limited(true);
+        assert_eq!(ReceiverReport::from_mem(&limited.to_mem().unwrap()).unwrap(), limited);
+
+        // what servers sent before `receiver_limited`
+        let first = (12.5, goodput, throughput);
+        let old = bincode::serialize(&first, bincode::Infinite).unwrap();
+        assert_eq!(ReceiverReport::from_mem(&old).unwrap(), report);
+        assert_eq!(limited.to_mem().unwrap()[..old.len()], old[..]);
+    }
+
+    #[test]
+    fn looping_restarts_numbering() {
+        // `VideoSource` goes back to its first frame
+        assert!(restarts_numbering(1800, 1));
+        // a replay is no restart
+        assert!(!restarts_numbering(1800, 1795));
+        assert!(!restarts_numbering(12, 3));
+    }
 }
@@ -0,0 +1,182 @@
+//! Mapping profile configs to the settings of external encoders.
+//!
+//! A profile row says what to produce (width, frame rate, quantizer, and the
+//! bandwidth it takes); an encoder API wants its own options. A
+//! `ConfigMapper` translates a record into `EncoderSettings` (libavcodec
+//! option names and values), with adapters for common encoders:
+//!
+//! ```text
+//! X264   crf from the quantizer, maxrate/bufsize from the bandwidth
+//! Vp9    crf rescaled to 0..63, the bandwidth as the (constrained) bitrate
+//! Jpeg   a fixed qscale (2..31) from the quantizer; no rate control
+//! ```
+//!
+//! The adapters take the width and frame rate from `Configurable::demand`,
+//! and the quantizer from `Quantized`.
+
+use bandwidth::Bandwidth;
+use config::Configurable;
+use profile::Record;
+use video::VideoConfig;
+
+/// Largest quantizer of H.264, the scale of `Quantized`.
+const MAX_QUANT: usize = 51;
+
+/// A config with an encoder quality, as an H.264 quantizer.
+pub trait Quantized {
+    /// The quantizer, from 0 (best) to 51.
+    fn quant(&self) -> usize;
+}
+
+impl Quantized for VideoConfig {
+    fn quant(&self) -> usize {
+        self.quant
+    }
+}
+
+/// The settings of an encoder for one level.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EncoderSettings {
+    /// The width to scale frames to, if the config sets one.
+    pub width: Option<usize>,
+
+    /// The frame rate to encode at, if the config sets one.
+    pub fps: Option<f64>,
+
+    /// Options of the encoder, by their libavcodec names.
+    pub options: Vec<(String, String)>,
+}
+
+impl EncoderSettings {
+    /// Sets the option `name` to `value`.
+    pub fn set<V: ToString>(&mut self, name: &str, value: V) {
+        self.options.push((name.into(), value.to_string()));
+    }
+
+    /// The value of the option `name`, if set.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.options.iter().rev().find(|o| o.0 == name).map(|o| o.1.as_str())
+    }
+}
+
+/// Translates profile records into the settings of an encoder.
+pub trait ConfigMapper<C> {
+    /// The settings that encode at the level of `record`.
+    fn map(&self, record: &Record<C>) -> EncoderSettings;
+}
+
+fn frame_settings<C: Configurable>(config: &C) -> EncoderSettings {
+    let demand = config.demand();
+    EncoderSettings {
+        width: demand.width,
+        fps: demand.fps,
+        options: Vec::new(),
+    }
+}
+
+fn kbits(bandwidth: Bandwidth) -> String {
+    format!("{}k", bandwidth.kbps().round() as u64)
+}
+
+/// libx264 in constant rate factor mode, capped at the bandwidth of the
+/// level.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct X264 {
+    /// The speed preset, e.g., `ultrafast`.
+    pub preset: String,
+
+    /// How long (ms) bursts above the bandwidth may last.
+    pub buffer_ms: u64,
+}
+
+impl Default for X264 {
+    fn default() -> Self {
+        X264 {
+            preset: "ultrafast".into(),
+            buffer_ms: 1000,
+        }
+    }
+}
+
+impl<C: Configurable + Quantized> ConfigMapper<C> for X264 {
+    fn map(&self, record: &Record<C>) -> EncoderSettings {
+        let mut settings = frame_settings(&record.config);
+        settings.set("preset", &self.preset);
+        settings.set("tune", "zerolatency");
+        settings.set("crf", record.config.quant().min(MAX_QUANT));
+        settings.set("maxrate", kbits(record.bandwidth));
+        settings.set("bufsize", kbits(record.bandwidth * (self.buffer_ms as f64 / 1000.0)));
+        settings
+    }
+}
+
+/// libvpx-vp9 in constrained quality mode, at the bandwidth of the level.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vp9 {
+    /// The speed (`cpu-used`), from 0 (slowest) to 8.
+    pub speed: u8,
+}
+
+impl Default for Vp9 {
+    fn default() -> Self {
+        Vp9 { speed: 8 }
+    }
+}
+
+impl<C: Configurable + Quantized> ConfigMapper<C> for Vp9 {
+    fn map(&self, record: &Record<C>) -> EncoderSettings {
+        let mut settings = frame_settings(&record.config);
+        settings.set("deadline", "realtime");
+        settings.set("cpu-used", self.speed);
+        settings.set("crf", record.config.quant().min(MAX_QUANT) * 63 / MAX_QUANT);
+        settings.set("b", kbits(record.bandwidth));
+        settings
+    }
+}
+
+/// mjpeg at a fixed quality: each frame is coded on its own, so the
+/// bandwidth of the level follows from the quantizer and frame rate alone.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Jpeg;
+
+impl<C: Configurable + Quantized> ConfigMapper<C> for Jpeg {
+    fn map(&self, record: &Record<C>) -> EncoderSettings {
+        let mut settings = frame_settings(&record.config);
+        let qscale = 2 + record.config.quant().min(MAX_QUANT) * 29 / MAX_QUANT;
+        settings.set("qmin", qscale);
+        settings.set("qmax", qscale);
+        settings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_maps_level_to_encoders() {
+        let config = VideoConfig {
+            width: 640,
+            skip: 15,
+            quant: 51,
+        };
+        let record = Record::new(Bandwidth::from_kbps(800.0), config, 0.7);
+
+        let x264 = X264 {
+            buffer_ms: 500,
+            ..X264::default()
+        }.map(&record);
+        // one frame in 16
+        assert_eq!((x264.width, x264.fps), (Some(640), Some(1.875)));
+        assert_eq!(x264.get("crf"), Some("51"));
+        assert_eq!(x264.get("maxrate"), Some("800k"));
+        assert_eq!(x264.get("bufsize"), Some("400k"));
+
+        let vp9 = Vp9::default().map(&record);
+        assert_eq!((vp9.get("crf"), vp9.get("b"), vp9.get("cpu-used")), (Some("63"), Some("800k"), Some("8")));
+
+        let best = Record::new(Bandwidth::from_kbps(800.0), VideoConfig { quant: 0, ..config }, 0.9);
+        let jpeg = |r: &Record<VideoConfig>| Jpeg.map(r).get("qmax").map(str::to_string);
+        assert_eq!((jpeg(&best), jpeg(&record)), (Some("2".into()), Some("31".into())));
+    }
+}
@@ -0,0 +1,326 @@
+//! A bound on the memory the buffers of the pipeline hold together.
+//!
+//! Under long congestion, frames pile up in the send queue, the backfill
+//! spool, the barrier's reorder buffer and the read buffers of connections
+//! until a device with little RAM runs out of memory. A `MemoryBudget` bounds
+//! them as a whole: each buffer draws from it through an `Account` of its
+//! `Component`, which gives back whatever it still holds when dropped.
+//!
+//! Once usage crosses `HIGH_WATERMARK` of the limit, subscribers get a
+//! `MemoryPressure` event and buffers drop frames aggressively (new frames
+//! are refused, the spool sheds its oldest) until usage falls below
+//! `LOW_WATERMARK`. Frames that would exceed the limit are always refused.
+
+use futures::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Fraction of the limit above which the budget is under pressure.
+pub const HIGH_WATERMARK: f64 = 0.8;
+
+/// Fraction of the limit below which the pressure is relieved.
+pub const LOW_WATERMARK: f64 = 0.5;
+
+/// The buffers drawing from a budget.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Component {
+    /// Datums on their way from the source to the socket.
+    SendQueue,
+
+    /// Undelivered frames kept for backfill.
+    Spool,
+
+    /// Datums taken ahead so that barriers overtake them (see `barrier`).
+    Reorder,
+
+    /// Bytes received but not decoded yet.
+    Decode,
+
+    /// Frames waiting for their peers in a stream group (see `grouping`).
+    Group,
+}
+
+impl Component {
+    /// Every component.
+    pub const ALL: [Component; 5] = [
+        Component::SendQueue,
+        Component::Spool,
+        Component::Reorder,
+        Component::Decode,
+        Component::Group,
+    ];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Usage crossed the high watermark.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryPressure {
+    /// The component whose allocation crossed it.
+    pub component: Component,
+
+    /// Bytes in use across components.
+    pub used: usize,
+
+    /// The limit of the budget.
+    pub limit: usize,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    used: [usize; 5],
+    refused: [usize; 5],
+    pressure: bool,
+    subscribers: Vec<UnboundedSender<MemoryPressure>>,
+}
+
+impl State {
+    fn total(&self) -> usize {
+        self.used.iter().sum()
+    }
+}
+
+/// A memory limit shared by buffers. Clones draw from the same budget.
+#[derive(Debug, Clone)]
+pub struct MemoryBudget {
+    inner: Arc<Mutex<State>>,
+    limit: usize,
+}
+
+impl MemoryBudget {
+    /// Creates a budget of `limit` bytes.
+    pub fn new(limit: usize) -> MemoryBudget {
+        MemoryBudget {
+            inner: Arc::default(),
+            limit,
+        }
+    }
+
+    /// The limit in bytes.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Bytes in use across components.
+    pub fn used(&self) -> usize {
+        self.lock().total()
+    }
+
+    /// Bytes in use by `component`.
+    pub fn used_by(&self, component: Component) -> usize {
+        self.lock().used[component.index()]
+    }
+
+    /// Bytes `component` was refused since the budget was created.
+    pub fn refused(&self, component: Component) -> usize {
+        self.lock().refused[component.index()]
+    }
+
+    /// Returns true between crossing the high and the low watermark.
+    pub fn under_pressure(&self) -> bool {
+        self.lock().pressure
+    }
+
+    /// Delivers the `MemoryPressure` events from now on.
+    pub fn subscribe(&self) -> UnboundedReceiver<MemoryPressure> {
+        let (tx, rx) = unbounded();
+        self.lock().subscribers.push(tx);
+        rx
+    }
+
+    /// Opens an account for a buffer of `component`.
+    pub fn account(&self, component: Component) -> Account {
+        Account {
+            inner: Arc::new(Held {
+                budget: self.clone(),
+                component,
+                bytes: Mutex::new(0),
+            }),
+        }
+    }
+
+    /// Draws `bytes` unless that exceeds the limit (or, with `strict`, the
+    /// budget is under pressure).
+    fn reserve(&self, component: Component, bytes: usize, strict: bool) -> bool {
+        let mut state = self.lock();
+        let total = state.total();
+        if total + bytes > self.limit || (strict && state.pressure) {
+            state.refused[component.index()] += bytes;
+            self.check(&mut state, component, total + bytes);
+            return false;
+        }
+        state.used[component.index()] += bytes;
+        self.check(&mut state, component, total + bytes);
+        true
+    }
+
+    /// Returns true if `reserve` would draw `bytes` once `freed` bytes are
+    /// given back.
+    fn could_reserve(&self, bytes: usize, freed: usize) -> bool {
+        let state = self.lock();
+        let after = state.total().saturating_sub(freed);
+        let relieved = !state.pressure || (after as f64) < self.limit as f64 * LOW_WATERMARK;
+        after + bytes <= self.limit && relieved
+    }
+
+    /// Draws `bytes` already allocated, even beyond the limit.
+    fn charge(&self, component: Component, bytes: usize) {
+        let mut state = self.lock();
+        state.used[component.index()] += bytes;
+        let total = state.total();
+        self.check(&mut state, component, total);
+    }
+
+    fn release(&self, component: Component, bytes: usize) {
+        let mut state = self.lock();
+        let used = &mut state.used[component.index()];
+        *used = used.saturating_sub(bytes);
+        let total = state.total();
+        if state.pressure && (total as f64) < self.limit as f64 * LOW_WATERMARK {
+            info!("memory pressure relieved at {} of {} bytes", total, self.limit);
+            state.pressure = false;
+        }
+    }
+
+    /// Raises the pressure if `demand` crossed the high watermark.
+    fn check(&self, state: &mut State, component: Component, demand: usize) {
+        if state.pressure || (demand as f64) < self.limit as f64 * HIGH_WATERMARK {
+            return;
+        }
+        state.pressure = true;
+        let event = MemoryPressure {
+            component,
+            used: state.total(),
+            limit: self.limit,
+        };
+        warn!("memory pressure: {:?}", event);
+        state.subscribers.retain(|tx| tx.unbounded_send(event).is_ok());
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.inner.lock().expect("memory budget poisoned")
+    }
+}
+
+#[derive(Debug)]
+struct Held {
+    budget: MemoryBudget,
+    component: Component,
+    bytes: Mutex<usize>,
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        let bytes = *self.bytes.get_mut().unwrap_or_else(|p| p.into_inner());
+        self.budget.release(self.component, bytes);
+    }
+}
+
+/// What one buffer holds of a budget. Clones share the account (e.g., the
+/// two ends of a channel); the last one gives back what is still held.
+#[derive(Debug, Clone)]
+pub struct Account {
+    inner: Arc<Held>,
+}
+
+impl Account {
+    /// Draws `bytes` for a frame the buffer may drop: refused above the
+    /// limit and while under pressure.
+    pub fn try_reserve(&self, bytes: usize) -> bool {
+        let mut held = self.held();
+        if !self.inner.budget.reserve(self.inner.component, bytes, true) {
+            return false;
+        }
+        *held += bytes;
+        true
+    }
+
+    /// Returns true if `try_reserve(bytes)` would succeed once the buffer
+    /// gave back all it holds, i.e., if shedding its frames can make room.
+    pub fn could_reserve(&self, bytes: usize) -> bool {
+        let held = *self.held();
+        self.inner.budget.could_reserve(bytes, held)
+    }
+
+    /// Draws `bytes` the buffer must keep (e.g., control datums).
+    pub fn charge(&self, bytes: usize) {
+        *self.held() += bytes;
+        self.inner.budget.charge(self.inner.component, bytes);
+    }
+
+    /// Gives back `bytes`.
+    pub fn release(&self, bytes: usize) {
+        let mut held = self.held();
+        let bytes = bytes.min(*held);
+        *held -= bytes;
+        self.inner.budget.release(self.inner.component, bytes);
+    }
+
+    /// Holds exactly `bytes`, e.g., the capacity of a buffer that grew or
+    /// shrank.
+    pub fn resize(&self, bytes: usize) {
+        let mut held = self.held();
+        if bytes > *held {
+            self.inner.budget.charge(self.inner.component, bytes - *held);
+        } else {
+            self.inner.budget.release(self.inner.component, *held - bytes);
+        }
+        *held = bytes;
+    }
+
+    /// Returns true while the budget is under pressure.
+    pub fn under_pressure(&self) -> bool {
+        self.inner.budget.under_pressure()
+    }
+
+    fn held(&self) -> MutexGuard<'_, usize> {
+        self.inner.bytes.lock().expect("memory account poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Stream;
+
+    #[test]
+    fn test_budget_accounting_and_pressure() {
+        let budget = MemoryBudget::new(1000);
+        let mut events = budget.subscribe().wait();
+        let queue = budget.account(Component::SendQueue);
+        let spool = budget.account(Component::Spool);
+
+        assert!(queue.try_reserve(500));
+        assert!(spool.try_reserve(200));
+        assert!(!budget.under_pressure());
+        assert_eq!((budget.used_by(Component::SendQueue), budget.used()), (500, 700));
+
+        // crossing the high watermark raises the pressure once
+        assert!(spool.try_reserve(100));
+        assert!(budget.under_pressure());
+        let event = events.next().unwrap().unwrap();
+        assert_eq!((event.component, event.used, event.limit), (Component::Spool, 800, 1000));
+
+        // frames are refused, but what must be kept is charged
+        assert!(!queue.try_reserve(10));
+        queue.charge(10);
+        assert_eq!((budget.used(), budget.refused(Component::SendQueue)), (810, 10));
+
+        queue.release(300);
+        assert!(budget.under_pressure());
+        // dropping an account gives back what it held
+        drop(spool);
+        assert_eq!(budget.used(), 210);
+        assert!(!budget.under_pressure());
+        assert!(queue.try_reserve(10));
+
+        let decode = budget.account(Component::Decode);
+        decode.resize(2000);
+        assert!(budget.under_pressure());
+        decode.resize(100);
+        assert_eq!(budget.used_by(Component::Decode), 100);
+        assert!(!queue.try_reserve(790));
+    }
+}
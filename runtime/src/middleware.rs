@@ -0,0 +1,239 @@
+//! Custom stages in the server's per-connection pipeline.
+//!
+//! Each connection goes through the same stages, which deployments may need
+//! to extend (route tenants, meter usage) without touching `server`:
+//!
+//! ```text
+//! handshake (identity)              on_connect: may turn the client down
+//!           (session, admission)
+//! decode (decompression, integrity) on_datum: may rewrite or drop datums
+//! dedup, sequence checks, feedback
+//! events (ServerEvent::Frame)       on_frame: sees each frame delivered
+//!        (ServerEvent::StreamInfo)  on_stream_info: sees what the stream is
+//! disconnect                        on_disconnect
+//! ```
+//!
+//! Layers added with `Server::add_middleware` run in the order they were
+//! added at each stage; a datum dropped by one layer doesn't reach the next.
+
+use super::{AsDatum, StreamInfo};
+use catalog::ClientIdentity;
+use errors::*;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// A client asking to connect, as `on_connect` sees it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectRequest {
+    /// The client.
+    pub addr: SocketAddr,
+
+    /// The session the client asks to resume, if any.
+    pub resuming: Option<u64>,
+
+    /// Who the client said it is in `Hello`.
+    pub identity: ClientIdentity,
+}
+
+/// The connection a stage runs for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionInfo {
+    /// The client.
+    pub addr: SocketAddr,
+
+    /// The session token.
+    pub session: u64,
+
+    /// Who the client said it is in `Hello`.
+    pub identity: ClientIdentity,
+}
+
+/// A custom stage. Every method defaults to passing things through.
+pub trait Middleware: Send + Sync {
+    /// Called once the client introduced itself, before its session is
+    /// opened or admitted. An error turns the client down and is reported as
+    /// `ServerEvent::Error`.
+    fn on_connect(&self, _request: &ConnectRequest) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called with each datum decoded, before duplicates are dropped.
+    /// Returns the datum to go on with, if any. An error ends the connection.
+    fn on_datum(&self, _conn: &ConnectionInfo, datum: AsDatum) -> Result<Option<AsDatum>> {
+        Ok(Some(datum))
+    }
+
+    /// Called with each frame delivered, with its latency (ms), before it is
+    /// emitted as `ServerEvent::Frame`.
+    fn on_frame(&self, _conn: &ConnectionInfo, _frame: &AsDatum, _latency_ms: f64) {}
+
+    /// Called when the client announces what the stream is, at its start
+    /// and whenever it changes, before it is emitted as
+    /// `ServerEvent::StreamInfo`.
+    fn on_stream_info(&self, _conn: &ConnectionInfo, _info: &StreamInfo) {}
+
+    /// Called once the connection ended.
+    fn on_disconnect(&self, _conn: &ConnectionInfo) {}
+}
+
+/// The layers of a server, in order.
+#[derive(Clone, Default)]
+pub struct Layers {
+    layers: Vec<Arc<dyn Middleware>>,
+}
+
+impl fmt::Debug for Layers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Layers({})", self.layers.len())
+    }
+}
+
+impl Layers {
+    /// Appends `layer`.
+    pub fn push<M: Middleware + 'static>(&mut self, layer: M) {
+        self.layers.push(Arc::new(layer));
+    }
+
+    /// Runs `on_connect` of every layer, up to the first error.
+    pub fn on_connect(&self, request: &ConnectRequest) -> Result<()> {
+        self.layers.iter().try_for_each(|l| l.on_connect(request))
+    }
+
+    /// Runs `datum` through `on_datum` of every layer, until one drops it.
+    pub fn on_datum(&self, conn: &ConnectionInfo, datum: AsDatum) -> Result<Option<AsDatum>> {
+        let mut datum = datum;
+        for layer in &self.layers {
+            match layer.on_datum(conn, datum)? {
+                Some(d) => datum = d,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(datum))
+    }
+
+    /// Runs `on_frame` of every layer.
+    pub fn on_frame(&self, conn: &ConnectionInfo, frame: &AsDatum, latency_ms: f64) {
+        for layer in &self.layers {
+            layer.on_frame(conn, frame, latency_ms);
+        }
+    }
+
+    /// Runs `on_stream_info` of every layer.
+    pub fn on_stream_info(&self, conn: &ConnectionInfo, info: &StreamInfo) {
+        for layer in &self.layers {
+            layer.on_stream_info(conn, info);
+        }
+    }
+
+    /// Runs `on_disconnect` of every layer.
+    pub fn on_disconnect(&self, conn: &ConnectionInfo) {
+        for layer in &self.layers {
+            layer.on_disconnect(conn);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "client"))]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+    use demo::loopback_setting;
+    use futures::Stream;
+    use server::{Server, ServerEvent};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::Mutex;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use tokio_core::reactor::Core;
+    use tokio_io::codec::{Decoder, Encoder};
+    use {AsCodec, AsDatumType, CloseReason};
+
+    /// Turns down unknown clients, drops level 0 and meters the rest.
+    #[derive(Default)]
+    struct Metering {
+        bytes: Arc<Mutex<Vec<(String, usize)>>>,
+    }
+
+    impl Middleware for Metering {
+        fn on_connect(&self, request: &ConnectRequest) -> Result<()> {
+            match request.identity.client_id {
+                Some(_) => Ok(()),
+                None => bail!("unknown tenant"),
+            }
+        }
+
+        fn on_datum(&self, _conn: &ConnectionInfo, datum: AsDatum) -> Result<Option<AsDatum>> {
+            match datum.datum_type() {
+                AsDatumType::Live(0, _) => Ok(None),
+                _ => Ok(Some(datum)),
+            }
+        }
+
+        fn on_frame(&self, conn: &ConnectionInfo, frame: &AsDatum, _latency_ms: f64) {
+            let tenant = conn.identity.client_id.clone().unwrap();
+            self.bytes.lock().unwrap().push((tenant, frame.net_len()));
+        }
+    }
+
+    #[test]
+    fn test_stages_run_in_order() {
+        let metering = Metering::default();
+        let bytes = metering.bytes.clone();
+        let (port_tx, port_rx) = mpsc::channel();
+        let (event_tx, event_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut core = Core::new().unwrap();
+            let mut server = Server::bind(loopback_setting(0), &core.handle()).unwrap();
+            server.add_middleware(metering);
+            port_tx.send(server.local_addr()).unwrap();
+            let events = server.incoming_events().for_each(|e| {
+                match e {
+                    ServerEvent::Frame { datum, .. } => {
+                        event_tx.send(Some(datum.datum_type())).unwrap()
+                    }
+                    ServerEvent::Error { .. } => event_tx.send(None).unwrap(),
+                    _ => {}
+                }
+                Ok(())
+            });
+            core.run(events).unwrap();
+        });
+        let addr = port_rx.recv().unwrap();
+        let send = |conn: &mut TcpStream, datums: Vec<AsDatum>| {
+            let (mut codec, mut buf) = (AsCodec::default(), BytesMut::new());
+            for datum in datums {
+                codec.encode(datum, &mut buf).unwrap();
+            }
+            conn.write_all(&buf).unwrap();
+        };
+
+        let mut anonymous = TcpStream::connect(addr).unwrap();
+        send(&mut anonymous, vec![AsDatum::hello(None)]);
+        assert_eq!(event_rx.recv_timeout(Duration::from_secs(5)).unwrap(), None);
+        // the client is told why before the connection closes
+        let mut closing = Vec::new();
+        anonymous.read_to_end(&mut closing).unwrap();
+        let close = AsCodec::default().decode(&mut BytesMut::from(closing)).unwrap().unwrap();
+        assert_eq!(CloseReason::from_mem(&close.mem).unwrap(), CloseReason::AuthFailure);
+
+        let identity = ClientIdentity {
+            client_id: Some("tenant-a".into()),
+            ..ClientIdentity::default()
+        };
+        let mut tenant = TcpStream::connect(addr).unwrap();
+        let frames = vec![
+            AsDatum::hello_as(None, &identity).unwrap(),
+            AsDatum::new(0, 1, vec![0; 100]),
+            AsDatum::new(1, 2, vec![0; 200]),
+        ];
+        let metered = frames[2].net_len();
+        send(&mut tenant, frames);
+        // level 0 was dropped before it reached the handler
+        let delivered = event_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(delivered, Some(AsDatumType::Live(1, 2)));
+        assert_eq!(*bytes.lock().unwrap(), vec![("tenant-a".to_string(), metered)]);
+    }
+}
@@ -0,0 +1,475 @@
+//! Offline extraction of bandwidth and latency from a packet capture.
+//!
+//! `deliveries` reads a pcap file of a previous session (e.g., from `tcpdump
+//! -w` at the server), reassembles the TCP streams towards the server's port
+//! and decodes them into datums, each stamped with the capture time of the
+//! packet that completed it. From these, `bandwidth_trace` rebuilds the
+//! delivered rate over time (in the format of `experiments::Trace::load`, to
+//! replay the session with the shaper), and `level_stats` the rate and
+//! latency of each level, to refine the bandwidths of a profile.
+//!
+//! Captures of Ethernet, Linux cooked (SLL) and raw IPv4 links are
+//! supported. Latencies compare the capture clock with the sender's clock,
+//! so they are only meaningful if the two are synchronized.
+
+use super::{AsCodec, AsDatumType, WireFormat};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use bytes::BytesMut;
+use csv;
+use errors::*;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use tokio_io::codec::Decoder;
+
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const PROTO_TCP: u8 = 6;
+const TCP_SYN: u8 = 0x02;
+
+/// The largest snapshot length taken from a capture (that of `tcpdump`).
+const MAX_SNAPLEN: u32 = 262_144;
+
+/// Segments held ahead of a hole in a stream; beyond, the capture missed a
+/// segment and the stream is given up.
+const MAX_PENDING: usize = 4096;
+
+/// A datum decoded from the capture.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delivery {
+    /// The client that sent it.
+    pub client: SocketAddrV4,
+
+    /// Capture time of its last byte (ms since unix epoch).
+    pub at_ms: i64,
+
+    /// Its type.
+    pub datum_type: AsDatumType,
+
+    /// Size on the wire (bytes).
+    pub bytes: usize,
+
+    /// From the sender's timestamp to `at_ms` (ms).
+    pub latency_ms: f64,
+}
+
+/// Rate and latency of one level.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LevelStats {
+    /// The level.
+    pub level: usize,
+
+    /// Number of frames.
+    pub frames: usize,
+
+    /// Delivered rate while the level was in use (kbps).
+    pub kbps: f64,
+
+    /// Mean delivery latency (ms).
+    pub latency_ms: f64,
+}
+
+fn malformed<S: Into<String>>(reason: S) -> Error {
+    Error::from_kind(ErrorKind::Capture(reason.into()))
+}
+
+/// The byte order and time resolution of a pcap file.
+struct Header {
+    big_endian: bool,
+    nanos: bool,
+    linktype: u32,
+}
+
+impl Header {
+    fn u32(&self, b: &[u8]) -> u32 {
+        if self.big_endian {
+            BigEndian::read_u32(b)
+        } else {
+            LittleEndian::read_u32(b)
+        }
+    }
+}
+
+/// A TCP segment towards the server.
+struct Segment<'a> {
+    client: SocketAddrV4,
+    seq: u32,
+    syn: bool,
+    payload: &'a [u8],
+}
+
+/// Extracts the TCP segment from a captured IPv4 packet, if it goes to `port`.
+fn segment(ip: &[u8], port: u16) -> Option<Segment<'_>> {
+    if ip.len() < 20 || ip[0] >> 4 != 4 || ip[9] != PROTO_TCP {
+        return None;
+    }
+    let ihl = usize::from(ip[0] & 0x0f) * 4;
+    let total = ::std::cmp::min(usize::from(BigEndian::read_u16(&ip[2..4])), ip.len());
+    let tcp = ip.get(ihl..total)?;
+    if tcp.len() < 20 || BigEndian::read_u16(&tcp[2..4]) != port {
+        return None;
+    }
+    let offset = usize::from(tcp[12] >> 4) * 4;
+    let src = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+    Some(Segment {
+        client: SocketAddrV4::new(src, BigEndian::read_u16(&tcp[0..2])),
+        seq: BigEndian::read_u32(&tcp[4..8]),
+        syn: tcp[13] & TCP_SYN != 0,
+        payload: tcp.get(offset..)?,
+    })
+}
+
+/// The IPv4 packet of a captured frame, if any.
+fn ipv4(linktype: u32, frame: &[u8]) -> Option<&[u8]> {
+    match linktype {
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            let mut ethertype = BigEndian::read_u16(frame.get(offset..offset + 2)?);
+            if ethertype == ETHERTYPE_VLAN {
+                offset += 4;
+                ethertype = BigEndian::read_u16(frame.get(offset..offset + 2)?);
+            }
+            if ethertype == ETHERTYPE_IPV4 {
+                frame.get(offset + 2..)
+            } else {
+                None
+            }
+        }
+        LINKTYPE_LINUX_SLL if BigEndian::read_u16(frame.get(14..16)?) == ETHERTYPE_IPV4 => {
+            frame.get(16..)
+        }
+        LINKTYPE_RAW => Some(frame),
+        _ => None,
+    }
+}
+
+/// One client's stream, reassembled in sequence order. Offsets from the
+/// start of the stream are 64-bit, as sequence numbers wrap every 4 GiB.
+struct Flow {
+    base: Option<u32>,
+    delivered: u64,
+    pending: BTreeMap<u64, Vec<u8>>,
+    buffer: BytesMut,
+    codec: AsCodec,
+    broken: bool,
+}
+
+impl Flow {
+    fn new(format: WireFormat) -> Flow {
+        Flow {
+            base: None,
+            delivered: 0,
+            pending: BTreeMap::new(),
+            buffer: BytesMut::new(),
+            codec: AsCodec::new(format),
+            broken: false,
+        }
+    }
+
+    /// The offset of `seq` in the stream: the one within 2 GiB of what was
+    /// delivered, or `None` if before the start.
+    fn offset(&self, seq: u32, base: u32) -> Option<u64> {
+        let ahead = seq.wrapping_sub(base).wrapping_sub(self.delivered as u32) as i32;
+        let offset = self.delivered as i64 + i64::from(ahead);
+        if offset < 0 {
+            None
+        } else {
+            Some(offset as u64)
+        }
+    }
+
+    /// Adds a segment; returns true if new bytes became contiguous. Gives
+    /// up on the stream (`broken`) if too many segments wait for a hole.
+    fn add(&mut self, seg: &Segment<'_>) -> bool {
+        // data starts after the SYN, or at the first segment captured
+        let first = if seg.syn { seg.seq.wrapping_add(1) } else { seg.seq };
+        let base = *self.base.get_or_insert(first);
+        if seg.payload.is_empty() {
+            return false;
+        }
+        let rel = match self.offset(seg.seq, base) {
+            Some(rel) => rel,
+            None => return false,
+        };
+        self.pending.entry(rel).or_insert_with(|| seg.payload.to_vec());
+        let mut progressed = false;
+        while let Some((&rel, _)) = self.pending.iter().next() {
+            if rel > self.delivered {
+                break;
+            }
+            let data = self.pending.remove(&rel).expect("first entry");
+            let end = rel + data.len() as u64;
+            if end > self.delivered {
+                // skip what retransmissions repeat
+                self.buffer.extend_from_slice(&data[(self.delivered - rel) as usize..]);
+                self.delivered = end;
+                progressed = true;
+            }
+        }
+        if self.pending.len() > MAX_PENDING {
+            warn!("{} segments wait for one missing from the capture", self.pending.len());
+            self.pending.clear();
+            self.broken = true;
+        }
+        progressed
+    }
+}
+
+/// Reads a pcap capture and decodes the datums sent to `port`, framed with
+/// `format`. A stream that can't be decoded (e.g., because the capture
+/// started mid-session) is skipped from there on.
+pub fn deliveries<R: Read>(mut r: R, port: u16, format: WireFormat) -> Result<Vec<Delivery>> {
+    let mut global = [0u8; 24];
+    r.read_exact(&mut global)?;
+    let (big_endian, nanos) = match LittleEndian::read_u32(&global[0..4]) {
+        0xa1b2_c3d4 => (false, false),
+        0xa1b2_3c4d => (false, true),
+        0xd4c3_b2a1 => (true, false),
+        0x4d3c_b2a1 => (true, true),
+        magic => return Err(malformed(format!("bad magic {:#x}", magic))),
+    };
+    let mut header = Header {
+        big_endian,
+        nanos,
+        linktype: 0,
+    };
+    header.linktype = header.u32(&global[20..24]) & 0x0fff_ffff;
+    let snaplen = match header.u32(&global[16..20]) {
+        0 => MAX_SNAPLEN,
+        n => ::std::cmp::min(n, MAX_SNAPLEN),
+    };
+
+    let mut flows: HashMap<SocketAddrV4, Flow> = HashMap::new();
+    let mut out = Vec::new();
+    let mut record = [0u8; 16];
+    loop {
+        match r.read_exact(&mut record) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let secs = i64::from(header.u32(&record[0..4]));
+        let frac = i64::from(header.u32(&record[4..8]));
+        let at_ms = secs * 1000 + if header.nanos { frac / 1_000_000 } else { frac / 1000 };
+        // a record can't be longer than the snapshot; skip what is beyond
+        let caplen = header.u32(&record[8..12]);
+        let mut frame = vec![0; ::std::cmp::min(caplen, snaplen) as usize];
+        r.read_exact(&mut frame).map_err(|_| malformed("truncated record"))?;
+        if caplen > snaplen {
+            let beyond = u64::from(caplen - snaplen);
+            if io::copy(&mut (&mut r).take(beyond), &mut io::sink())? < beyond {
+                return Err(malformed("truncated record"));
+            }
+        }
+
+        let seg = match ipv4(header.linktype, &frame).and_then(|ip| segment(ip, port)) {
+            Some(s) => s,
+            None => continue,
+        };
+        let flow = flows.entry(seg.client).or_insert_with(|| Flow::new(format));
+        if flow.broken || !flow.add(&seg) {
+            continue;
+        }
+        loop {
+            match flow.codec.decode(&mut flow.buffer) {
+                Ok(Some(datum)) => out.push(Delivery {
+                    client: seg.client,
+                    at_ms,
+                    datum_type: datum.datum_type(),
+                    bytes: datum.net_len(),
+                    latency_ms: (at_ms - datum.ts.timestamp_millis()) as f64,
+                }),
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("cannot decode the stream of {}: {}", seg.client, e);
+                    flow.broken = true;
+                    break;
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// The delivered rate (kbps) of all clients per `step_ms`, from the first
+/// delivery on.
+pub fn bandwidth_trace(deliveries: &[Delivery], step_ms: u64) -> Vec<f64> {
+    let step_ms = ::std::cmp::max(step_ms, 1) as i64;
+    let start = match deliveries.iter().map(|d| d.at_ms).min() {
+        Some(t) => t,
+        None => return Vec::new(),
+    };
+    let mut bytes = Vec::new();
+    for d in deliveries {
+        let i = ((d.at_ms - start) / step_ms) as usize;
+        if bytes.len() <= i {
+            bytes.resize(i + 1, 0);
+        }
+        bytes[i] += d.bytes;
+    }
+    bytes.into_iter().map(|b| b as f64 * 8.0 / step_ms as f64).collect()
+}
+
+/// The rate and latency of each level. A level is in use from one of its
+/// frames to the next frame of the same client; everything the client
+/// delivers meanwhile (e.g., probes) counts towards the level.
+pub fn level_stats(deliveries: &[Delivery]) -> Vec<LevelStats> {
+    // level => (frames, bytes, ms in use, latency sum)
+    let mut levels: BTreeMap<usize, (usize, usize, i64, f64)> = BTreeMap::new();
+    let mut current: HashMap<SocketAddrV4, (usize, i64)> = HashMap::new();
+    for d in deliveries {
+        if let Some(&(level, since)) = current.get(&d.client) {
+            let stats = levels.entry(level).or_default();
+            stats.1 += d.bytes;
+            stats.2 += d.at_ms - since;
+        }
+        if let AsDatumType::Live(level, _) = d.datum_type {
+            let stats = levels.entry(level).or_default();
+            stats.0 += 1;
+            stats.3 += d.latency_ms;
+            current.insert(d.client, (level, d.at_ms));
+        } else if let Some(c) = current.get_mut(&d.client) {
+            c.1 = d.at_ms;
+        }
+    }
+    levels
+        .into_iter()
+        .filter(|&(_, (frames, _, _, _))| frames > 0)
+        .map(|(level, (frames, bytes, ms, latency))| LevelStats {
+            level,
+            frames,
+            kbps: bytes as f64 * 8.0 / ::std::cmp::max(ms, 1) as f64,
+            latency_ms: latency / frames as f64,
+        })
+        .collect()
+}
+
+/// Writes `trace` as one rate per row, without header.
+pub fn write_trace<W: io::Write>(trace: &[f64], w: W) -> Result<()> {
+    let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(w);
+    for kbps in trace {
+        writer.serialize((kbps,))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes `stats` as CSV with a header.
+pub fn write_levels<W: io::Write>(stats: &[LevelStats], w: W) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(w);
+    for s in stats {
+        writer.serialize(s)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use AsDatum;
+    use tokio_io::codec::Encoder;
+
+    /// A little-endian pcap of raw IPv4 packets.
+    struct Capture(Vec<u8>);
+
+    impl Capture {
+        fn new() -> Capture {
+            let mut buf = vec![0; 24];
+            LittleEndian::write_u32(&mut buf[0..4], 0xa1b2_c3d4);
+            LittleEndian::write_u32(&mut buf[16..20], 65535);
+            LittleEndian::write_u32(&mut buf[20..24], LINKTYPE_RAW);
+            Capture(buf)
+        }
+
+        fn packet(&mut self, at_ms: i64, src_port: u16, seq: u32, payload: &[u8]) {
+            let mut ip = vec![0; 40];
+            ip[0] = 0x45;
+            BigEndian::write_u16(&mut ip[2..4], (40 + payload.len()) as u16);
+            ip[9] = PROTO_TCP;
+            ip[12..16].copy_from_slice(&[10, 0, 0, 1]);
+            BigEndian::write_u16(&mut ip[20..22], src_port);
+            BigEndian::write_u16(&mut ip[22..24], 8889);
+            BigEndian::write_u32(&mut ip[24..28], seq);
+            ip[32] = 5 << 4;
+            ip.extend_from_slice(payload);
+
+            let mut record = vec![0; 16];
+            LittleEndian::write_u32(&mut record[0..4], (at_ms / 1000) as u32);
+            LittleEndian::write_u32(&mut record[4..8], (at_ms % 1000 * 1000) as u32);
+            LittleEndian::write_u32(&mut record[8..12], ip.len() as u32);
+            LittleEndian::write_u32(&mut record[12..16], ip.len() as u32);
+            self.0.extend(record);
+            self.0.extend(ip);
+        }
+    }
+
+    #[test]
+    fn test_reassembles_and_decodes() {
+        let mut stream = BytesMut::new();
+        let mut codec = AsCodec::default();
+        for (level, frame_num) in [(0, 1), (0, 2), (1, 3), (1, 4)].iter() {
+            codec.encode(AsDatum::new(*level, *frame_num, vec![7; 500]), &mut stream).unwrap();
+        }
+        let now = ::chrono::Utc::now().timestamp_millis();
+
+        // 500-byte segments, the second one retransmitted after the third
+        let mut capture = Capture::new();
+        let chunks = stream.chunks(500).collect::<Vec<_>>();
+        let mut order = vec![0, 2, 1];
+        order.extend(1..chunks.len());
+        for (i, &c) in order.iter().enumerate() {
+            capture.packet(now + 100 * i as i64, 40000, 1000 + 500 * c as u32, chunks[c]);
+        }
+        capture.packet(now, 40001, 0, b"not a datum");
+
+        let found = deliveries(&capture.0[..], 8889, WireFormat::default()).unwrap();
+        let types = found.iter().map(|d| d.datum_type).collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            vec![
+                AsDatumType::Live(0, 1),
+                AsDatumType::Live(0, 2),
+                AsDatumType::Live(1, 3),
+                AsDatumType::Live(1, 4),
+            ]
+        );
+        assert!(found.windows(2).all(|w| w[0].at_ms <= w[1].at_ms));
+        assert!(found[0].latency_ms >= 0.0);
+
+        let trace = bandwidth_trace(&found, 1000);
+        let total: f64 = trace.iter().sum();
+        assert_eq!(total * 1000.0 / 8.0, stream.len() as f64);
+
+        let stats = level_stats(&found);
+        assert_eq!(stats.len(), 2);
+        assert_eq!((stats[0].level, stats[0].frames), (0, 2));
+        assert!(stats[0].kbps > 0.0);
+    }
+
+    #[test]
+    fn test_flow_across_sequence_wrap() {
+        let seg = |seq: u32, payload: &'static [u8]| Segment {
+            client: SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 40000),
+            seq,
+            syn: false,
+            payload,
+        };
+        let mut flow = Flow::new(WireFormat::default());
+        assert!(flow.add(&seg(u32::MAX - 1, b"ab")));
+        // past 2^32 of sequence space, the offsets keep growing
+        assert!(flow.add(&seg(0, b"cd")));
+        assert_eq!((flow.delivered, &flow.buffer[..]), (4, &b"abcd"[..]));
+
+        // a hole that never fills gives up on the stream
+        for i in 0..=MAX_PENDING as u32 {
+            flow.add(&seg(10 + 2 * i, b"ef"));
+        }
+        assert!(flow.broken);
+        assert!(flow.pending.is_empty());
+    }
+}
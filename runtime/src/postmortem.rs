@@ -0,0 +1,94 @@
+//! Last-gasp flushing of metrics when the process panics or exits.
+//!
+//! Components register what they buffer, e.g., the stats snapshot of a
+//! client or the experiment log of a server. `install` adds a panic hook
+//! that flushes all of it before the previous hook runs, and dropping a
+//! `Registration` on a normal exit flushes once more, so post-mortem analysis
+//! isn't missing the last minute before a crash.
+//!
+//! The hook runs on the panicking thread before unwinding, possibly with
+//! locks held: flushers must not block on them (use `try_lock`).
+
+use std::panic;
+use std::sync::{Arc, Mutex, MutexGuard, Once, TryLockError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+type Flusher = Arc<dyn Fn() + Send + Sync>;
+
+static FLUSHERS: Mutex<Vec<(usize, Flusher)>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+static INSTALL: Once = Once::new();
+
+/// Installs the panic hook (once per process), ahead of the existing one.
+pub fn install() {
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            flush_all();
+            previous(info);
+        }));
+    });
+}
+
+/// Registers `flush` until the returned `Registration` is dropped.
+pub fn register<F: Fn() + Send + Sync + 'static>(flush: F) -> Registration {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let flush: Flusher = Arc::new(flush);
+    registry().push((id, flush.clone()));
+    Registration { id, flush }
+}
+
+/// Runs every registered flusher, unless the registry itself is busy.
+pub fn flush_all() {
+    let flushers = match FLUSHERS.try_lock() {
+        Ok(f) => f.iter().map(|(_, f)| f.clone()).collect::<Vec<_>>(),
+        Err(TryLockError::Poisoned(p)) => p.into_inner().iter().map(|(_, f)| f.clone()).collect(),
+        Err(TryLockError::WouldBlock) => return,
+    };
+    for flush in flushers {
+        flush();
+    }
+}
+
+fn registry() -> MutexGuard<'static, Vec<(usize, Flusher)>> {
+    FLUSHERS.lock().unwrap_or_else(|p| p.into_inner())
+}
+
+/// Keeps a flusher registered; flushes one last time when dropped.
+pub struct Registration {
+    id: usize,
+    flush: Flusher,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        registry().retain(|&(id, _)| id != self.id);
+        (self.flush)();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_flush_on_panic_and_exit() {
+        install();
+        let flushed = Arc::new(AtomicUsize::new(0));
+        let counter = flushed.clone();
+        let registration = register(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        assert!(thread::spawn(|| panic!("crash in the field")).join().is_err());
+        // other tests may panic concurrently, so at least once
+        assert!(flushed.load(Ordering::SeqCst) >= 1);
+
+        let before = flushed.load(Ordering::SeqCst);
+        drop(registration);
+        assert_eq!(flushed.load(Ordering::SeqCst), before + 1);
+        flush_all();
+        assert_eq!(flushed.load(Ordering::SeqCst), before + 1);
+    }
+}
@@ -0,0 +1,161 @@
+//! Frame-size prediction, to downgrade ahead of a burst.
+//!
+//! The queue model reacts once frames pile up: a keyframe larger than what
+//! the link drains in an interval is late by the time the monitor sees it.
+//! With `frame_prediction` set, a `FramePredictor` learns from the frames
+//! handed to the send queue
+//!
+//! ```text
+//! the average size of delta frames and of keyframes, per level
+//! the number of frames between keyframes (the keyframe schedule)
+//! the interval between frames
+//! ```
+//!
+//! and predicts the bytes due within `horizon_ms`. When those and the queued
+//! bytes would not drain within the horizon at the estimated bandwidth of
+//! the path (`QueueEstimator::bandwidth`, not the send rate, which only
+//! follows the source while it sends less than the path carries), the
+//! monitor raises `Signal::PredictedBurst`, which downgrades by one level
+//! (at most once per horizon).
+
+use std::cmp;
+
+/// A frame is a keyframe if larger than this many times the average.
+pub(crate) const KEY_FACTOR: f64 = 2.0;
+
+/// Weight of a new sample in the averages.
+const ALPHA: f64 = 1.0 / 8.0;
+
+/// How far ahead the predictor looks.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct PredictionConfig {
+    /// The horizon (ms) of the prediction.
+    pub horizon_ms: u64,
+}
+
+impl Default for PredictionConfig {
+    fn default() -> Self {
+        PredictionConfig { horizon_ms: 500 }
+    }
+}
+
+/// The average frame sizes of a level, once seen.
+#[derive(Debug, Clone, Copy, Default)]
+struct Sizes {
+    delta: Option<f64>,
+    key: Option<f64>,
+}
+
+fn average(avg: Option<f64>, sample: f64) -> Option<f64> {
+    Some(avg.map_or(sample, |avg| avg + ALPHA * (sample - avg)))
+}
+
+/// Predicts the size of upcoming frames from the recent ones.
+#[derive(Debug, Default)]
+pub struct FramePredictor {
+    sizes: Vec<Sizes>,
+    level: usize,
+    /// Frames from one keyframe to the next, once two were seen.
+    key_interval: Option<f64>,
+    /// Frames since the last keyframe, once one was seen.
+    since_key: Option<usize>,
+    gap_ms: Option<f64>,
+    last_ms: Option<u64>,
+}
+
+impl FramePredictor {
+    /// Creates a predictor with no history.
+    pub fn new() -> FramePredictor {
+        FramePredictor::default()
+    }
+
+    /// Learns from a frame of `bytes` at `level`, produced at `now_ms`.
+    pub fn observe(&mut self, level: usize, bytes: usize, now_ms: u64) {
+        if let Some(last) = self.last_ms {
+            self.gap_ms = average(self.gap_ms, now_ms.saturating_sub(last) as f64);
+        }
+        self.last_ms = Some(now_ms);
+        if self.sizes.len() <= level {
+            self.sizes.resize(level + 1, Sizes::default());
+        }
+        self.level = level;
+        let sizes = &mut self.sizes[level];
+        let bytes = bytes as f64;
+        match sizes.delta {
+            Some(avg) if bytes > KEY_FACTOR * avg => {
+                sizes.key = average(sizes.key, bytes);
+                if let Some(since) = self.since_key {
+                    self.key_interval = average(self.key_interval, (since + 1) as f64);
+                }
+                self.since_key = Some(0);
+            }
+            _ => {
+                sizes.delta = average(sizes.delta, bytes);
+                self.since_key = self.since_key.map(|n| n + 1);
+            }
+        }
+    }
+
+    /// The bytes of the frames due within `horizon_ms`, at the level of the
+    /// last frame. Keyframes are expected on schedule once it is known.
+    pub fn predict(&self, horizon_ms: u64) -> usize {
+        let (gap, sizes) = match (self.gap_ms, self.sizes.get(self.level)) {
+            (Some(gap), Some(sizes)) if gap > 0.0 => (gap, sizes),
+            _ => return 0,
+        };
+        let delta = sizes.delta.unwrap_or(0.0);
+        let frames = (horizon_ms as f64 / gap) as usize;
+        let keys = match (self.key_interval, self.since_key) {
+            (Some(interval), Some(since)) => {
+                let interval = cmp::max(interval.round() as usize, 1);
+                let next = cmp::max(interval.saturating_sub(since), 1);
+                if next <= frames {
+                    1 + (frames - next) / interval
+                } else {
+                    0
+                }
+            }
+            _ => 0,
+        };
+        let key = sizes.key.unwrap_or(delta);
+        (keys as f64 * key + (frames - keys) as f64 * delta) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use adaptation::Signal;
+    use decision::QueueEstimator;
+    use estimator::ExponentialSmooth;
+    use Bandwidth;
+
+    #[test]
+    fn test_predicts_keyframe_schedule() {
+        let mut predictor = FramePredictor::new();
+        // a keyframe every 10 frames, one frame every 100 ms
+        for i in 0..30 {
+            let bytes = if i % 10 == 9 { 1000 } else { 100 };
+            predictor.observe(0, bytes, i * 100);
+        }
+        assert_eq!(predictor.predict(500), 500);
+        for i in 30..38 {
+            predictor.observe(0, 100, i * 100);
+        }
+        // the next keyframe is 2 frames away
+        assert_eq!(predictor.predict(500), 1400);
+
+        // sending all it produces, the source limits the rate, not the path
+        let mut queue = QueueEstimator::new(Box::new(ExponentialSmooth::new(0.0)));
+        queue.update(1875, 1875);
+        assert_eq!(queue.bandwidth(), None);
+        assert_eq!(queue.anticipate(100_000, 500), None);
+        // backlogged, the path drains 150 kbps, i.e., 9375 bytes per 500 ms
+        queue.update(1875 + 7000, 1875);
+        assert_eq!(queue.bandwidth(), Some(Bandwidth::from_kbps(150.0)));
+        assert_eq!(queue.anticipate(1400, 500), None);
+        queue.update(1875 + 2000, 1875);
+        assert!(matches!(queue.anticipate(1400, 500), Some(Signal::PredictedBurst(l)) if l > 0.0));
+    }
+}
@@ -1,16 +1,71 @@
 /// A profile stores the list of <bandwidth, accuracy, configuration>. The
 /// simple implementation uses a list and performs binary search for items.
+use bandwidth::Bandwidth;
+use config::{Capabilities, CapabilityCheck, Configurable};
 use csv;
+use error_chain::ChainedError;
+use errors::*;
+use schema::{self, ProfileSchema};
 use serde::de::DeserializeOwned;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::fmt::Debug;
+use std::io;
 use std::path::Path;
+use std::slice;
 
 /// Record is each individual rule in a profile.
 #[derive(Serialize, Deserialize, Clone, Copy, Debug)]
 pub struct Record<C> {
-    pub bandwidth: f64,
+    /// Bandwidth required by the configuration (kbps in profile files).
+    pub bandwidth: Bandwidth,
+
+    /// The configuration.
     pub config: C,
-    _accuracy: f64,
+
+    /// Accuracy of the configuration.
+    pub accuracy: f64,
+}
+
+/// The order of levels in a profile: by bandwidth, then by accuracy, so that
+/// the last of several levels with equal bandwidth is the most accurate.
+fn level_order<C>(a: &Record<C>, b: &Record<C>) -> Ordering {
+    a.bandwidth
+        .bps()
+        .total_cmp(&b.bandwidth.bps())
+        .then_with(|| a.accuracy.total_cmp(&b.accuracy))
+}
+
+impl<C> Record<C> {
+    /// Creates a record of a configuration with its bandwidth and accuracy.
+    pub fn new(bandwidth: Bandwidth, config: C, accuracy: f64) -> Record<C> {
+        Record {
+            bandwidth,
+            config,
+            accuracy,
+        }
+    }
+}
+
+impl<C: Debug> Record<C> {
+    /// Fails unless both the bandwidth and the accuracy are finite numbers,
+    /// which levels need to be ordered.
+    fn check_finite(&self) -> Result<()> {
+        if !self.bandwidth.bps().is_finite() {
+            bail!(ErrorKind::InvalidConfig(format!(
+                "{:?} has bandwidth {}",
+                self.config,
+                self.bandwidth.bps()
+            )));
+        }
+        if !self.accuracy.is_finite() {
+            bail!(ErrorKind::InvalidConfig(format!(
+                "{:?} has accuracy {}",
+                self.config, self.accuracy
+            )));
+        }
+        Ok(())
+    }
 }
 
 const ADJUST_STICKY_MAX: usize = 3;
@@ -19,13 +74,35 @@ const ADJUST_STICKY_MAX: usize = 3;
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct SimpleProfile {
     /// A list of bandwidths
-    levels: Vec<f64>,
+    levels: Vec<Bandwidth>,
 
     /// The current config (serving as cache)
     current: usize,
 
     /// How many times we can stick to current without degrading.
     adjust_sticky_count: usize,
+
+    /// The highest level allowed regardless of bandwidth, e.g., because the
+    /// source cannot encode above it in real time.
+    #[serde(default)]
+    ceiling: Option<usize>,
+
+    /// The highest level the device can sustain while overloaded or
+    /// overheating, lowered by `throttle` and raised by `relax`.
+    #[serde(default)]
+    system_ceiling: Option<usize>,
+
+    /// The highest level viable over the current uplink (see `uplink`).
+    #[serde(default)]
+    link_ceiling: Option<usize>,
+
+    /// The highest bandwidth allowed, e.g., set by an operator.
+    #[serde(default)]
+    bandwidth_cap: Option<Bandwidth>,
+
+    /// Levels the source failed to switch to, skipped until available again.
+    #[serde(default)]
+    unavailable: Vec<usize>,
 }
 
 impl SimpleProfile {
@@ -35,25 +112,163 @@ impl SimpleProfile {
         self.current
     }
 
+    /// The number of levels.
+    pub fn num_levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    /// The rate of each level.
+    pub fn rates(&self) -> &[Bandwidth] {
+        &self.levels
+    }
+
+    /// The highest level currently allowed.
+    fn top(&self) -> usize {
+        let last = self.levels.len() - 1;
+        let capped = self.bandwidth_cap.map(|bw| self.get_level_index(bw));
+        [self.ceiling, self.system_ceiling, self.link_ceiling, capped]
+            .iter()
+            .filter_map(|c| *c)
+            .fold(last, ::std::cmp::min)
+    }
+
+    /// Limits the levels to `ceiling` (or lifts the limit with `None`).
+    /// Returns the new level if the current one had to be lowered.
+    pub fn set_ceiling(&mut self, ceiling: Option<usize>) -> Option<usize> {
+        self.ceiling = ceiling;
+        self.lower_to_top()
+    }
+
+    /// Limits the levels to `ceiling` over the current uplink (or lifts the
+    /// limit with `None`). Returns the new level if the current one had to be
+    /// lowered.
+    pub fn set_link_ceiling(&mut self, ceiling: Option<usize>) -> Option<usize> {
+        self.link_ceiling = ceiling;
+        self.lower_to_top()
+    }
+
+    /// Limits the levels to those within `cap`, or lifts the limit
+    /// with `None`. The lowest level is always allowed. Returns the new level
+    /// if the current one had to be lowered.
+    pub fn set_bandwidth_cap(&mut self, cap: Option<Bandwidth>) -> Option<usize> {
+        self.bandwidth_cap = cap;
+        self.lower_to_top()
+    }
+
+    /// Caps the levels below the current one because the device is
+    /// overloaded. Returns the new level if the current one had to be lowered.
+    pub fn throttle(&mut self) -> Option<usize> {
+        self.system_ceiling = Some(self.current.saturating_sub(1));
+        self.lower_to_top()
+    }
+
+    /// Raises the cap set by `throttle` by one level, lifting it once it
+    /// reaches the top.
+    pub fn relax(&mut self) {
+        self.system_ceiling = match self.system_ceiling {
+            Some(c) if c + 2 < self.levels.len() => Some(c + 1),
+            _ => None,
+        };
+    }
+
+    fn lower_to_top(&mut self) -> Option<usize> {
+        let top = self.top();
+        if self.current > top {
+            self.current = self.nearest_available(top);
+            Some(self.current)
+        } else {
+            None
+        }
+    }
+
+    fn is_available(&self, level: usize) -> bool {
+        !self.unavailable.contains(&level)
+    }
+
+    /// The available level nearest to `level` (capped at the highest
+    /// allowed level), preferring lower ones. If none is available, `level`.
+    fn nearest_available(&self, level: usize) -> usize {
+        let top = self.top();
+        let level = ::std::cmp::min(level, top);
+        (0..level + 1)
+            .rev()
+            .find(|&l| self.is_available(l))
+            .or_else(|| (level + 1..top + 1).find(|&l| self.is_available(l)))
+            .unwrap_or(level)
+    }
+
+    /// The next available level above the current one, if allowed.
+    fn next_available(&self) -> Option<usize> {
+        (self.current + 1..self.top() + 1).find(|&l| self.is_available(l))
+    }
+
+    /// Skips `level` (e.g., the source failed to switch to it) until
+    /// `mark_available`. Returns the new level if the current one had to
+    /// move.
+    pub fn mark_unavailable(&mut self, level: usize) -> Option<usize> {
+        if self.is_available(level) {
+            self.unavailable.push(level);
+        }
+        if self.current == level {
+            self.current = self.nearest_available(level);
+            Some(self.current)
+        } else {
+            None
+        }
+    }
+
+    /// Allows `level` again.
+    pub fn mark_available(&mut self, level: usize) {
+        self.unavailable.retain(|&l| l != level);
+    }
+
+    /// Inserts a level at `index`, keeping the current level and ceilings on
+    /// the same configurations.
+    fn insert_level(&mut self, index: usize, bandwidth: Bandwidth) {
+        self.levels.insert(index, bandwidth);
+        self.reindex(|l| if l >= index { l + 1 } else { l });
+    }
+
+    /// Maps the current level and ceilings to new indices.
+    fn reindex<F: Fn(usize) -> usize>(&mut self, f: F) {
+        self.current = f(self.current);
+        self.ceiling = self.ceiling.map(&f);
+        self.system_ceiling = self.system_ceiling.map(&f);
+        self.link_ceiling = self.link_ceiling.map(&f);
+        self.unavailable = self.unavailable.iter().map(|&l| f(l)).collect();
+    }
+
+    /// Removes the level at `index` (there must be another one). A removed
+    /// current level or ceiling moves to the level below; with no level
+    /// below (the lowest was removed), to the new lowest, i.e., *up* to the
+    /// next configuration. Returns the new current level if it changed
+    /// configuration.
+    fn remove_level(&mut self, index: usize) -> Option<usize> {
+        self.levels.remove(index);
+        self.unavailable.retain(|&l| l != index);
+        let removed_current = self.current == index;
+        self.reindex(|l| if l >= index { l.saturating_sub(1) } else { l });
+        if removed_current {
+            Some(self.current)
+        } else {
+            None
+        }
+    }
+
     /// Finds the index of the configuration that matches (equal or smaller
     /// than) the provided bandwidth.
-    fn get_level_index(&self, bw: f64) -> usize {
-        let pos = (&self.levels).binary_search_by(|v| {
-            v.partial_cmp(&bw).expect("failed to compare bandwidth")
-        });
-        match pos {
-            Ok(i) => i,
-            // If error, it could be the first (only 1 profile) or the last
-            // (fail to find).
-            Err(i) => if i == 0 { 0 } else { i - 1 },
-        }
+    /// Among levels of equal bandwidth, this is the last one, i.e., the most
+    /// accurate (see `level_order`). The lowest level is the fallback.
+    fn get_level_index(&self, bw: Bandwidth) -> usize {
+        let fits = self.levels.partition_point(|v| *v <= bw);
+        fits.saturating_sub(1)
     }
 
     /// Adjusts the profile with a configuration that satisfies the provided
     /// bandwidth, i.e., equal or smaller. Returns a tuple of bandwidth and
     /// configuration.
-    pub fn adjust_level(&mut self, bw: f64) -> Option<usize> {
-        let new_level = self.get_level_index(bw);
+    pub fn adjust_level(&mut self, bw: Bandwidth) -> Option<usize> {
+        let new_level = self.nearest_available(self.get_level_index(bw));
         // Only if new level is more conservative
         if self.current > new_level {
             self.current = new_level;
@@ -73,50 +288,48 @@ impl SimpleProfile {
         }
     }
 
+    /// Moves to `level` (capped at the highest allowed level). Returns the new
+    /// level if it differs from the current one.
+    pub fn set_level(&mut self, level: usize) -> Option<usize> {
+        let level = self.nearest_available(level);
+        if level == self.current {
+            None
+        } else {
+            self.current = level;
+            Some(level)
+        }
+    }
+
     /// Advances to next config. Returns the record if successful; otherwise,
     /// return None (when we cannot advance any more).
     pub fn advance_level(&mut self) -> Option<usize> {
-        if self.current < self.levels.len() - 1 {
-            self.current += 1;
-            Some(self.current)
-        } else {
-            None
-        }
+        let next = self.next_available()?;
+        self.current = next;
+        Some(next)
     }
 
     /// Advances to next config. Returns the record if successful; otherwise,
     /// return None (when we cannot advance any more).
     pub fn decrease_level(&mut self) -> Option<usize> {
-        if self.current > 0 {
-            self.current -= 1;
-            Some(self.current)
-        } else {
-            None
-        }
+        let lower = (0..self.current).rev().find(|&l| self.is_available(l))?;
+        self.current = lower;
+        Some(lower)
     }
 
     /// Finds out the required rate for next configuration.
-    pub fn next_rate(&self) -> Option<f64> {
-        if self.current < self.levels.len() - 1 {
-            Some(self.levels[self.current + 1])
-        } else {
-            None
-        }
+    pub fn next_rate(&self) -> Option<Bandwidth> {
+        self.next_available().map(|l| self.levels[l])
     }
 
     /// Finds out the required delta rate for next configuration.
-    pub fn next_rate_delta(&self) -> Option<f64> {
-        if self.current < self.levels.len() - 1 {
-            trace!("calculating delta for level {}", self.current);
-            Some(self.levels[self.current + 1] - self.levels[self.current])
-        } else {
-            None
-        }
+    pub fn next_rate_delta(&self) -> Option<Bandwidth> {
+        trace!("calculating delta for level {}", self.current);
+        self.next_available().map(|l| self.levels[l] - self.levels[self.current])
     }
 
     /// Am I current at maximum allowed configuration?
     pub fn is_max(&self) -> bool {
-        self.current == self.levels.len() - 1
+        self.next_available().is_none()
     }
 }
 
@@ -130,18 +343,19 @@ pub struct Profile<C> {
     records: Vec<Record<C>>,
 }
 
-impl<C: Copy> Profile<C> {
+impl<C: Clone> Profile<C> {
     /// Returns the initial configuration (we will simply take the first).
     pub fn init_config(&self) -> C {
         self.records
             .first()
             .expect("no configuration in profile")
             .config
+            .clone()
     }
 
     /// Returns n-th configuration.
     pub fn n_th(&self, n: usize) -> C {
-        self.records[n].config
+        self.records[n].config.clone()
     }
 
     /// Returns the last configuration (we will simply take the last).
@@ -151,12 +365,18 @@ impl<C: Copy> Profile<C> {
             .last()
             .expect("no configuration in profile")
             .config
+            .clone()
     }
 
     /// Returns the current configuration
-    #[allow(dead_code)]
-    fn current_config(&self) -> C {
-        self.records[self.simple_profile.current()].config
+    pub fn current_config(&self) -> C {
+        self.current_record().config.clone()
+    }
+
+    /// Returns the record of the current level, without cloning its
+    /// configuration.
+    pub fn current_record(&self) -> &Record<C> {
+        &self.records[self.simple_profile.current()]
     }
 
     /// Returns the current level.
@@ -174,58 +394,210 @@ impl<C> Profile<C> {
             levels: simple,
             current: 0,
             adjust_sticky_count: ADJUST_STICKY_MAX,
+            ceiling: None,
+            system_ceiling: None,
+            link_ceiling: None,
+            bandwidth_cap: None,
+            unavailable: Vec::new(),
         };
         Profile {
             records: vec,
-            simple_profile: simple_profile,
+            simple_profile,
         }
     }
+
+    /// Returns a copy of the config-agnostic `SimpleProfile`.
     pub fn simplify(&self) -> SimpleProfile {
         self.simple_profile.clone()
     }
+
+    /// All records, ordered by level.
+    pub fn records(&self) -> &[Record<C>] {
+        &self.records
+    }
+
+    /// The number of levels.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Returns true if the profile has no level.
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Iterates over the records, ordered by level.
+    pub fn iter(&self) -> slice::Iter<'_, Record<C>> {
+        self.records.iter()
+    }
+
+    /// Moves the accuracy of `level` towards `quality` observed online, by
+    /// `weight` (between 0 and 1). Levels are not reordered, even if levels
+    /// of equal bandwidth end up out of accuracy order. Returns the new
+    /// accuracy, or `None` if there is no such level, or `quality` is not
+    /// between 0 and 1 (or `weight` is NaN) and is ignored.
+    pub fn observe_accuracy(&mut self, level: usize, quality: f64, weight: f64) -> Option<f64> {
+        if !(0.0..=1.0).contains(&quality) || weight.is_nan() {
+            return None;
+        }
+        let weight = weight.clamp(0.0, 1.0);
+        self.records.get_mut(level).map(|r| {
+            r.accuracy += weight * (quality - r.accuracy);
+            r.accuracy
+        })
+    }
 }
 
-impl<C: Debug + Copy> Profile<C> {
+impl<'a, C> IntoIterator for &'a Profile<C> {
+    type Item = &'a Record<C>;
+    type IntoIter = slice::Iter<'a, Record<C>>;
+
+    fn into_iter(self) -> slice::Iter<'a, Record<C>> {
+        self.records.iter()
+    }
+}
+
+impl<C: Configurable + Debug + Clone> Profile<C> {
+    /// The frame rate the configuration of `level` demands, if any.
+    pub fn frame_rate(&self, level: usize) -> Option<f64> {
+        self.records.get(level).and_then(|r| r.config.demand().fps)
+    }
+
+    /// Validates every configuration in the profile, and that levels are
+    /// ordered by bandwidth then accuracy. Returns the first violation (with
+    /// its level) as an error.
+    pub fn validate(&self) -> Result<()> {
+        for (level, pair) in self.records.windows(2).enumerate() {
+            if level_order(&pair[0], &pair[1]) == Ordering::Greater {
+                bail!(ErrorKind::InvalidConfig(
+                    format!("levels {} and {} are out of order", level, level + 1),
+                ));
+            }
+        }
+        for (level, record) in self.records.iter().enumerate() {
+            record.config.validate().chain_err(|| {
+                format!("level {} ({:?}) is invalid", level, record.config)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Inserts `record` at the level matching its bandwidth and accuracy (see
+    /// `level_order`), keeping the current configuration. Returns the
+    /// level of the new record.
+    pub fn insert_record(&mut self, record: Record<C>) -> Result<usize> {
+        record.config.validate().chain_err(|| {
+            format!("{:?} is invalid", record.config)
+        })?;
+        record.check_finite()?;
+        let level = self.records
+            .iter()
+            .position(|r| level_order(r, &record) == Ordering::Greater)
+            .unwrap_or(self.records.len());
+        info!("inserted level {}: {:?}", level, record);
+        self.simple_profile.insert_level(level, record.bandwidth);
+        self.records.insert(level, record);
+        Ok(level)
+    }
+
+    /// Removes the record at `level`. If it was the current one, the profile
+    /// moves to the level below, or up to the next one if it was the lowest
+    /// (check `current_level`, e.g., against a bandwidth cap). The last
+    /// record can't be removed.
+    pub fn remove_level(&mut self, level: usize) -> Result<Record<C>> {
+        if level >= self.records.len() {
+            bail!(ErrorKind::InvalidConfig(format!("no level {}", level)));
+        }
+        if self.records.len() == 1 {
+            bail!(ErrorKind::ProfileIncomplete("cannot remove the only level".into()));
+        }
+        let record = self.records.remove(level);
+        match self.simple_profile.remove_level(level) {
+            // the new lowest level was above the removed one
+            Some(0) if level == 0 => warn!("removed the lowest level, now at the next one up"),
+            Some(current) => info!("removed current level {}, now at {}", level, current),
+            None => {}
+        }
+        Ok(record)
+    }
+
+    /// Checks that the source described by `caps` can produce every level.
+    /// With `CapabilityCheck::Reject`, returns the first level it can't as
+    /// an error; with `Mask`, removes those levels and returns them (as
+    /// numbered before), failing only if none is left.
+    pub fn restrict(&mut self, caps: &Capabilities, check: CapabilityCheck) -> Result<Vec<usize>> {
+        let mut masked = Vec::new();
+        for (level, record) in self.records.iter().enumerate() {
+            if let Err(e) = caps.admit(&record.config.demand()) {
+                if check == CapabilityCheck::Reject {
+                    return Err(e).chain_err(|| {
+                        format!("level {} ({:?}) is beyond the source", level, record.config)
+                    });
+                }
+                warn!("masking level {} ({:?}): {}", level, record.config, e);
+                masked.push(level);
+            }
+        }
+        if masked.len() == self.records.len() {
+            bail!(ErrorKind::ProfileIncomplete("no level is within the source's capabilities".into()));
+        }
+        for &level in masked.iter().rev() {
+            self.remove_level(level)?;
+        }
+        Ok(masked)
+    }
+
+    /// Moves the cached current config to `new_level`, logs the changes and
+    /// returns the new record.
+    fn switch_to(&mut self, prev_level: usize, new_level: usize) -> Record<C> {
+        let prev = &self.records[prev_level].config;
+        let record = &self.records[new_level];
+        info!(
+            "updating to level {}, bandwidth {}, {}",
+            new_level,
+            record.bandwidth,
+            record.config.apply_delta(prev)
+        );
+        record.clone()
+    }
+
     /// Adjusts the profile with a configuration that satisfies the provided
     /// bandwidth, i.e., equal or smaller. Returns a tuple of bandwidth and
     /// configuration.
-    pub fn adjust_config(&mut self, bw: f64) -> Option<Record<C>> {
-        match self.simple_profile.adjust_level(bw) {
-            Some(new_level) => {
-                info!(
-                    "updating to level {}, configuration {:?}",
-                    new_level,
-                    self.records[new_level]
-                );
-                Some(self.records[new_level])
-            }
-            None => None,
-        }
+    pub fn adjust_config(&mut self, bw: Bandwidth) -> Option<Record<C>> {
+        let prev_level = self.simple_profile.current();
+        self.simple_profile
+            .adjust_level(bw)
+            .map(|new_level| self.switch_to(prev_level, new_level))
+    }
+
+    /// Moves to the config at `level`. Returns the record if the level
+    /// changed.
+    pub fn set_config(&mut self, level: usize) -> Option<Record<C>> {
+        let prev_level = self.simple_profile.current();
+        self.simple_profile
+            .set_level(level)
+            .map(|new_level| self.switch_to(prev_level, new_level))
     }
 
     /// Advances to next config. Returns the record if successful; otherwise,
     /// return None (when we cannot advance any more).
     pub fn advance_config(&mut self) -> Option<Record<C>> {
-        match self.simple_profile.advance_level() {
-            Some(new_level) => {
-                info!(
-                    "updating to level {}, configuration {:?}",
-                    new_level,
-                    self.records[new_level]
-                );
-                Some(self.records[new_level])
-            }
-            None => None,
-        }
+        let prev_level = self.simple_profile.current();
+        self.simple_profile
+            .advance_level()
+            .map(|new_level| self.switch_to(prev_level, new_level))
     }
 }
 
-impl<C: DeserializeOwned + Copy + Debug> Profile<C> {
+impl<C: DeserializeOwned + Configurable + Clone + Debug> Profile<C> {
     /// Creates a new `Profile` instance with a path pointing to the profile
     /// file (CSV). The columns in the file needs to match the config type.
-    /// Because this is the loading phase, we bail early (use expect!).
+    /// Because this is the loading phase, we bail early (use expect!), which
+    /// includes rejecting profiles with invalid configurations.
     pub fn new<P: AsRef<Path>>(path: P) -> Profile<C> {
-        let errmsg = format!("no profile file {:?}", path.as_ref());
+        let path = path.as_ref();
+        let errmsg = format!("no profile file {:?}", path);
         let mut rdr = csv::ReaderBuilder::new()
             .has_headers(false)
             .from_path(path)
@@ -235,28 +607,243 @@ impl<C: DeserializeOwned + Copy + Debug> Profile<C> {
             let record: Record<C> = record.expect("failed to parse the record");
             vec.push(record);
         }
+        vec.sort_by(level_order);
 
         let simple = vec.iter().map(|r| r.bandwidth).collect();
-        Profile {
+        let profile = Profile {
             records: vec,
             simple_profile: SimpleProfile {
                 levels: simple,
                 current: 0,
                 adjust_sticky_count: ADJUST_STICKY_MAX,
+                ceiling: None,
+                system_ceiling: None,
+                link_ceiling: None,
+                bandwidth_cap: None,
+                unavailable: Vec::new(),
             },
+        };
+        if let Err(e) = profile.validate() {
+            panic!("invalid profile {:?}: {}", path, e.display_chain());
+        }
+        profile
+    }
+
+    /// Parses a profile from the contents of a profile file, e.g., received
+    /// from the server. Unlike `new`, a bad profile is an error.
+    pub fn from_csv(csv: &str) -> Result<Profile<C>> {
+        let mut records: Vec<Record<C>> = read_rows(csv.as_bytes())?;
+        if records.is_empty() {
+            bail!(ErrorKind::InvalidConfig("empty profile".into()));
+        }
+        for record in &records {
+            record.check_finite()?;
+        }
+        records.sort_by(level_order);
+        let profile = Profile::_with_vec(records);
+        profile.validate()?;
+        Ok(profile)
+    }
+}
+
+impl<C: DeserializeOwned + Configurable + ProfileSchema + Clone + Debug> Profile<C> {
+    /// Like `from_csv`, after checking every row against the columns of the
+    /// config (see `schema`), so that a misaligned file fails rather than
+    /// loading into the wrong fields.
+    pub fn from_csv_checked(csv: &str) -> Result<Profile<C>> {
+        schema::validate::<C>(csv)?;
+        Profile::from_csv(csv)
+    }
+
+    /// Loads the profile file at `path` with `from_csv_checked`.
+    pub fn load_checked<P: AsRef<Path>>(path: P) -> Result<Profile<C>> {
+        Profile::from_csv_checked(&::std::fs::read_to_string(path)?)
+    }
+}
+
+/// `ProfileBuilder` composes a profile from several tables, e.g., a bandwidth
+/// table `(bandwidth, config)` from one measurement pipeline and an accuracy
+/// table `(config, accuracy)` from another. Tables are joined by config when
+/// building; a config that appears twice with different values is a conflict.
+#[derive(Debug)]
+pub struct ProfileBuilder<C> {
+    bandwidth: BTreeMap<C, Bandwidth>,
+    accuracy: BTreeMap<C, f64>,
+}
+
+impl<C: Ord + Clone + Debug> Default for ProfileBuilder<C> {
+    fn default() -> Self {
+        ProfileBuilder {
+            bandwidth: BTreeMap::new(),
+            accuracy: BTreeMap::new(),
+        }
+    }
+}
+
+fn insert_unique<C: Ord + Clone + Debug, V: PartialEq + Copy + ::std::fmt::Display>(
+    table: &mut BTreeMap<C, V>,
+    what: &str,
+    config: C,
+    value: V,
+) -> Result<()> {
+    match table.get(&config) {
+        Some(&prev) if prev != value => {
+            bail!(ErrorKind::ProfileConflict(format!(
+                "{:?} has {} {} and {}",
+                config,
+                what,
+                prev,
+                value
+            )))
+        }
+        Some(_) => {}
+        None => {
+            table.insert(config, value);
+        }
+    }
+    Ok(())
+}
+
+fn read_rows<R: io::Read, T: DeserializeOwned>(rdr: R) -> Result<Vec<T>> {
+    let mut rdr = csv::ReaderBuilder::new().has_headers(false).from_reader(rdr);
+    let mut rows = Vec::new();
+    for row in rdr.deserialize() {
+        rows.push(row?);
+    }
+    Ok(rows)
+}
+
+impl<C: Ord + Clone + Debug> ProfileBuilder<C> {
+    /// Creates an empty builder.
+    pub fn new() -> Self {
+        ProfileBuilder::default()
+    }
+
+    /// Adds the measured bandwidth of a configuration.
+    pub fn add_bandwidth(&mut self, config: C, bandwidth: Bandwidth) -> Result<&mut Self> {
+        insert_unique(&mut self.bandwidth, "bandwidth", config, bandwidth)?;
+        Ok(self)
+    }
+
+    /// Adds the measured accuracy of a configuration.
+    pub fn add_accuracy(&mut self, config: C, accuracy: f64) -> Result<&mut Self> {
+        insert_unique(&mut self.accuracy, "accuracy", config, accuracy)?;
+        Ok(self)
+    }
+
+    /// Adds a complete record (both bandwidth and accuracy).
+    pub fn add_record(&mut self, record: Record<C>) -> Result<&mut Self> {
+        self.add_bandwidth(record.config.clone(), record.bandwidth)?;
+        self.add_accuracy(record.config, record.accuracy)
+    }
+}
+
+impl<C: DeserializeOwned + Ord + Clone + Debug> ProfileBuilder<C> {
+    /// Loads a headerless bandwidth table whose rows are `bandwidth, config`.
+    pub fn bandwidth_csv<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut Self> {
+        let file = ::std::fs::File::open(path)?;
+        self.bandwidth_reader(file)
+    }
+
+    /// Loads a headerless accuracy table whose rows are `config, accuracy`.
+    pub fn accuracy_csv<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut Self> {
+        let file = ::std::fs::File::open(path)?;
+        self.accuracy_reader(file)
+    }
+
+    /// Loads a complete profile file whose rows are `bandwidth, config,
+    /// accuracy` (the format `Profile::new` reads).
+    pub fn profile_csv<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut Self> {
+        let file = ::std::fs::File::open(path)?;
+        for record in read_rows::<_, Record<C>>(file)? {
+            self.add_record(record)?;
+        }
+        Ok(self)
+    }
+
+    fn bandwidth_reader<R: io::Read>(&mut self, rdr: R) -> Result<&mut Self> {
+        for (bandwidth, config) in read_rows::<_, (Bandwidth, C)>(rdr)? {
+            self.add_bandwidth(config, bandwidth)?;
+        }
+        Ok(self)
+    }
+
+    fn accuracy_reader<R: io::Read>(&mut self, rdr: R) -> Result<&mut Self> {
+        for (config, accuracy) in read_rows::<_, (C, f64)>(rdr)? {
+            self.add_accuracy(config, accuracy)?;
+        }
+        Ok(self)
+    }
+}
+
+impl<C: Configurable + Ord + Clone + Debug> ProfileBuilder<C> {
+    /// Joins the tables into a profile sorted by bandwidth. Every config with
+    /// a bandwidth needs an accuracy; accuracy-only configs are ignored since
+    /// we cannot place them in the profile.
+    pub fn build(&self) -> Result<Profile<C>> {
+        let mut records = Vec::with_capacity(self.bandwidth.len());
+        for (config, bandwidth) in &self.bandwidth {
+            let accuracy = match self.accuracy.get(config) {
+                Some(a) => *a,
+                None => {
+                    bail!(ErrorKind::ProfileIncomplete(
+                        format!("no accuracy for {:?}", config),
+                    ))
+                }
+            };
+            records.push(Record {
+                bandwidth: *bandwidth,
+                config: config.clone(),
+                accuracy,
+            });
         }
+        for config in self.accuracy.keys() {
+            if !self.bandwidth.contains_key(config) {
+                warn!("ignoring {:?}: accuracy without bandwidth", config);
+            }
+        }
+        if records.is_empty() {
+            bail!(ErrorKind::ProfileIncomplete("no records".into()));
+        }
+        records.sort_by(level_order);
+        let profile = Profile::_with_vec(records);
+        profile.validate()?;
+        Ok(profile)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use config::{ConfigDelta, Demand};
 
-    #[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+    #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
     struct DummyConfig {
         pub v: usize,
     }
 
+    impl Configurable for DummyConfig {
+        fn validate(&self) -> Result<()> {
+            if self.v > 100 {
+                bail!(ErrorKind::InvalidConfig(format!("v {} > 100", self.v)));
+            }
+            Ok(())
+        }
+
+        fn apply_delta(&self, prev: &Self) -> ConfigDelta {
+            let mut delta = ConfigDelta::new();
+            delta.push("v", prev.v, self.v);
+            delta
+        }
+
+        fn demand(&self) -> Demand {
+            Demand {
+                width: Some(self.v),
+                ..Demand::default()
+            }
+        }
+    }
+
     fn create_profile(i: usize) -> Profile<DummyConfig> {
         let mut vec = Vec::new();
         // Populate sample test data
@@ -264,9 +851,9 @@ mod tests {
         for i in 0..i {
             let c = DummyConfig { v: i };
             let record = Record {
-                bandwidth: i as f64,
+                bandwidth: Bandwidth::from_kbps(i as f64),
                 config: c,
-                _accuracy: 0.0,
+                accuracy: 0.0,
             };
             vec.push(record);
         }
@@ -284,19 +871,124 @@ mod tests {
         assert_eq!(profile.current_config().v, 2);
 
         // cannot adjust to a higher config
-        assert!(profile.adjust_config(4.0).is_none());
+        assert!(profile.adjust_config(Bandwidth::from_kbps(4.0)).is_none());
 
         // can adjust to a higher config
-        assert_eq!(profile.adjust_config(1.5).unwrap().config.v, 1);
+        assert_eq!(profile.adjust_config(Bandwidth::from_kbps(1.5)).unwrap().config.v, 1);
+    }
+
+    #[test]
+    fn test_profile_insert_remove() {
+        let mut profile = create_profile(3);
+        profile.set_config(2);
+        let mut simple = profile.simplify();
+        simple.set_ceiling(Some(2));
+
+        let record = Record::new(Bandwidth::from_kbps(1.5), DummyConfig { v: 9 }, 0.0);
+        assert_eq!(profile.insert_record(record).unwrap(), 2);
+        assert_eq!(profile.current_level(), 3);
+        assert_eq!(profile.current_config().v, 2);
+        assert_eq!(profile.n_th(2).v, 9);
+
+        // levels must be ordered, so non-finite numbers are rejected
+        let bad = [
+            (f64::NAN, 0.0),
+            (f64::INFINITY, 0.0),
+            (1.0, f64::NAN),
+            (1.0, f64::NEG_INFINITY),
+        ];
+        for &(kbps, accuracy) in &bad {
+            let record = Record::new(Bandwidth::from_kbps(kbps), DummyConfig { v: 8 }, accuracy);
+            assert!(profile.insert_record(record).is_err());
+        }
+        assert_eq!(profile.records().len(), 4);
+
+        // removing the current level moves down
+        assert_eq!(profile.remove_level(3).unwrap().config.v, 2);
+        assert_eq!(profile.current_level(), 2);
+        assert_eq!(profile.current_config().v, 9);
+        assert!(profile.remove_level(5).is_err());
+
+        simple.insert_level(0, Bandwidth::from_kbps(0.5));
+        assert_eq!(simple.top(), 3);
+        assert_eq!(simple.remove_level(0), None);
+        assert_eq!(simple.current(), 2);
+
+        // with none below, removing the lowest current level moves up
+        profile.set_config(0);
+        let lowest = profile.current_config().v;
+        profile.remove_level(0).unwrap();
+        assert_eq!(profile.current_level(), 0);
+        assert_eq!(profile.current_config().v, profile.n_th(0).v);
+        assert_ne!(profile.current_config().v, lowest);
+
+        let mut single = create_profile(1);
+        assert!(single.remove_level(0).is_err());
+    }
+
+    #[test]
+    fn test_duplicate_bandwidth() {
+        let records = vec![
+            Record::new(Bandwidth::from_kbps(1.0), DummyConfig { v: 0 }, 0.5),
+            Record::new(Bandwidth::from_kbps(2.0), DummyConfig { v: 1 }, 0.6),
+            Record::new(Bandwidth::from_kbps(2.0), DummyConfig { v: 2 }, 0.8),
+            Record::new(Bandwidth::from_kbps(2.0), DummyConfig { v: 3 }, 0.9),
+            Record::new(Bandwidth::from_kbps(3.0), DummyConfig { v: 4 }, 0.95),
+        ];
+        let profile = Profile::_with_vec(records.clone());
+        assert!(profile.validate().is_ok());
+
+        // the most accurate of equal bandwidths, every time
+        let simple = profile.simplify();
+        for _ in 0..10 {
+            assert_eq!(simple.get_level_index(Bandwidth::from_kbps(2.0)), 3);
+            assert_eq!(simple.get_level_index(Bandwidth::from_kbps(2.5)), 3);
+        }
+        assert_eq!(simple.get_level_index(Bandwidth::from_kbps(0.5)), 0);
+        assert_eq!(simple.get_level_index(Bandwidth::from_kbps(10.0)), 4);
+
+        let mut swapped = records;
+        swapped.swap(1, 3);
+        assert!(Profile::_with_vec(swapped).validate().is_err());
+
+        let mut profile = profile;
+        let record = Record::new(Bandwidth::from_kbps(2.0), DummyConfig { v: 5 }, 0.7);
+        let level = profile.insert_record(record).unwrap();
+        assert_eq!(level, 2);
+        assert!(profile.validate().is_ok());
+    }
+
+    #[test]
+    fn test_profile_records() {
+        let profile = create_profile(3);
+        assert_eq!(profile.len(), 3);
+        assert!(!profile.is_empty());
+        assert_eq!(profile.records()[2].config.v, 2);
+        let bandwidths = profile.iter().map(|r| r.bandwidth.kbps()).collect::<Vec<_>>();
+        assert_eq!(bandwidths, vec![0.0, 1.0, 2.0]);
+        assert_eq!((&profile).into_iter().filter(|r| r.accuracy == 0.0).count(), 3);
+    }
+
+    #[test]
+    fn test_observe_accuracy() {
+        let mut profile = create_profile(3);
+        assert_eq!(profile.observe_accuracy(1, 1.0, 0.5), Some(0.5));
+        assert_eq!(profile.observe_accuracy(1, 1.0, 0.5), Some(0.75));
+        assert_eq!(profile.observe_accuracy(1, 0.0, 2.0), Some(0.0));
+        assert_eq!(profile.observe_accuracy(3, 0.8, 0.5), None);
+        assert_eq!(profile.observe_accuracy(1, f64::NAN, 0.5), None);
+        assert_eq!(profile.observe_accuracy(1, 1.5, 0.5), None);
+        assert_eq!(profile.observe_accuracy(1, 0.5, f64::NAN), None);
+        assert_eq!(profile.records()[0].accuracy, 0.0);
     }
 
     #[test]
     fn test_profile_with_one_record() {
         let mut profile = create_profile(1);
-        assert_eq!(profile.init_config().v, 0);;
+        assert_eq!(profile.init_config().v, 0);
         assert_eq!(profile.last_config().v, 0);
         assert_eq!(profile.current_config().v, 0);
-        assert!(profile.adjust_config(1.5).is_none());
+        assert!(profile.adjust_config(Bandwidth::from_kbps(1.5)).is_none());
     }
 
     #[test]
@@ -308,10 +1000,196 @@ mod tests {
 
         // sticks to current config for ADJUST_STICKY_MAX times
         for _ in 0..ADJUST_STICKY_MAX {
-            assert!(profile.adjust_config(2.1).is_none());
+            assert!(profile.adjust_config(Bandwidth::from_kbps(2.1)).is_none());
             assert_eq!(profile.current_config().v, 2);
         }
 
-        assert_eq!(profile.adjust_config(2.1).unwrap().config.v, 1);
+        assert_eq!(profile.adjust_config(Bandwidth::from_kbps(2.1)).unwrap().config.v, 1);
+    }
+
+    #[test]
+    fn test_simple_profile_ceiling() {
+        let mut simple = create_profile(4).simplify();
+        assert_eq!(simple.set_level(3), Some(3));
+
+        assert_eq!(simple.set_ceiling(Some(1)), Some(1));
+        assert!(simple.is_max());
+        assert_eq!(simple.advance_level(), None);
+        assert_eq!(simple.set_level(3), None);
+
+        assert_eq!(simple.set_ceiling(None), None);
+        assert_eq!(simple.advance_level(), Some(2));
+    }
+
+    #[test]
+    fn test_simple_profile_bandwidth_cap() {
+        let mut simple = create_profile(4).simplify();
+        assert_eq!(simple.set_level(3), Some(3));
+
+        assert_eq!(simple.set_bandwidth_cap(Some(Bandwidth::from_kbps(1.5))), Some(1));
+        assert_eq!(simple.adjust_level(Bandwidth::from_kbps(10.0)), None);
+        assert!(simple.is_max());
+        // the lowest level stays available
+        assert_eq!(simple.set_bandwidth_cap(Some(Bandwidth::from_kbps(0.0))), Some(0));
+
+        assert_eq!(simple.set_bandwidth_cap(None), None);
+        assert_eq!(simple.set_level(3), Some(3));
+    }
+
+    #[test]
+    fn test_simple_profile_unavailable_levels() {
+        let mut simple = create_profile(5).simplify();
+        assert_eq!(simple.set_level(2), Some(2));
+
+        // the nearest lower level takes over
+        assert_eq!(simple.mark_unavailable(2), Some(1));
+        assert_eq!(simple.advance_level(), Some(3));
+        assert_eq!(simple.decrease_level(), Some(1));
+        assert_eq!(simple.set_level(2), None);
+        assert_eq!(simple.next_rate(), Some(Bandwidth::from_kbps(3.0)));
+
+        assert_eq!(simple.mark_unavailable(0), None);
+        assert_eq!(simple.mark_unavailable(1), Some(3));
+        assert_eq!(simple.decrease_level(), None);
+
+        simple.mark_available(2);
+        assert_eq!(simple.decrease_level(), Some(2));
+    }
+
+    #[test]
+    fn test_simple_profile_throttle() {
+        let mut simple = create_profile(4).simplify();
+        assert_eq!(simple.set_level(2), Some(2));
+
+        assert_eq!(simple.throttle(), Some(1));
+        assert_eq!(simple.throttle(), Some(0));
+        assert_eq!(simple.throttle(), None);
+        assert!(simple.is_max());
+
+        simple.relax();
+        assert_eq!(simple.advance_level(), Some(1));
+        assert!(simple.is_max());
+        simple.relax();
+        simple.relax();
+        assert_eq!(simple.set_level(3), Some(3));
+    }
+
+    #[test]
+    fn test_profile_validate() {
+        let mut profile = create_profile(4);
+        assert!(profile.validate().is_ok());
+
+        profile.records[2].config.v = 101;
+        assert!(profile.validate().is_err());
+    }
+
+    #[test]
+    fn test_from_csv_rejects_non_finite() {
+        assert!(Profile::<DummyConfig>::from_csv("1,1,0.5\n2,2,0.6\n").is_ok());
+        assert!(Profile::<DummyConfig>::from_csv("1,1,0.5\nNaN,2,0.6\n").is_err());
+        assert!(Profile::<DummyConfig>::from_csv("1,1,NaN\n2,2,0.6\n").is_err());
+        assert!(Profile::<DummyConfig>::from_csv("inf,1,0.5\n2,2,0.6\n").is_err());
+    }
+
+    #[test]
+    fn test_config_delta_display() {
+        let a = DummyConfig { v: 1 };
+        let b = DummyConfig { v: 3 };
+        assert_eq!(b.apply_delta(&a).to_string(), "v 1→3");
+        assert!(a.apply_delta(&a).is_empty());
+        assert_eq!(a.apply_delta(&a).to_string(), "no change");
+    }
+
+    #[test]
+    fn test_builder_joins_tables() {
+        let bw = "3.0,3\n1.0,1\n2.0,2\n";
+        let acc = "1,0.5\n2,0.7\n3,0.9\n4,1.0\n";
+        let mut builder = ProfileBuilder::<DummyConfig>::new();
+        builder.bandwidth_reader(bw.as_bytes()).unwrap();
+        builder.accuracy_reader(acc.as_bytes()).unwrap();
+        let profile = builder.build().unwrap();
+        assert_eq!(profile.records.len(), 3);
+        assert_eq!(profile.init_config().v, 1);
+        assert_eq!(profile.last_config().v, 3);
+        assert_eq!(profile.records[1].accuracy, 0.7);
+    }
+
+    #[test]
+    fn test_builder_detects_conflicts() {
+        let mut builder = ProfileBuilder::<DummyConfig>::new();
+        builder.add_bandwidth(DummyConfig { v: 1 }, Bandwidth::from_kbps(1.0)).unwrap();
+        // identical duplicates are fine
+        builder.add_bandwidth(DummyConfig { v: 1 }, Bandwidth::from_kbps(1.0)).unwrap();
+        assert!(builder.add_bandwidth(DummyConfig { v: 1 }, Bandwidth::from_kbps(2.0)).is_err());
+
+        // missing accuracy fails the join
+        assert!(builder.build().is_err());
+        builder.add_accuracy(DummyConfig { v: 1 }, 0.5).unwrap();
+        // the rejected bandwidth left the first in place
+        let profile = builder.build().unwrap();
+        assert_eq!(profile.iter().next().unwrap().bandwidth.kbps(), 1.0);
+    }
+
+    #[test]
+    fn test_restrict() {
+        let caps = Capabilities {
+            max_width: Some(2),
+            ..Capabilities::default()
+        };
+        let mut profile = create_profile(5);
+        assert!(profile.restrict(&caps, CapabilityCheck::Reject).is_err());
+        assert_eq!(profile.len(), 5);
+
+        profile.set_config(4);
+        assert_eq!(profile.restrict(&caps, CapabilityCheck::Mask).unwrap(), vec![3, 4]);
+        assert_eq!(profile.len(), 3);
+        assert_eq!(profile.current_config(), DummyConfig { v: 2 });
+        assert!(profile.restrict(&caps, CapabilityCheck::Reject).unwrap().is_empty());
+
+        let none = Capabilities {
+            max_width: Some(0),
+            ..Capabilities::default()
+        };
+        let mut profile = create_profile(5);
+        profile.remove_level(0).unwrap();
+        assert!(profile.restrict(&none, CapabilityCheck::Mask).is_err());
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+    struct CodecConfig {
+        pub codec: String,
+        pub kbps: usize,
+    }
+
+    impl Configurable for CodecConfig {
+        fn validate(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn apply_delta(&self, prev: &Self) -> ConfigDelta {
+            let mut delta = ConfigDelta::new();
+            delta.push("kbps", prev.kbps, self.kbps);
+            delta
+        }
+
+        fn demand(&self) -> Demand {
+            Demand::default()
+        }
+    }
+
+    #[test]
+    fn test_profile_with_non_copy_config() {
+        let csv = "300,vp8,300,0.7\n100,h264,100,0.5\n500,h264,500,0.9\n";
+        let mut profile = Profile::<CodecConfig>::from_csv(csv).unwrap();
+        assert_eq!(profile.init_config().codec, "h264");
+        let record = profile.set_config(1).unwrap();
+        assert_eq!(record.config.codec, "vp8");
+        assert_eq!(profile.current_record().config.kbps, 300);
+
+        let mut builder = ProfileBuilder::new();
+        for record in profile.records() {
+            builder.add_record(record.clone()).unwrap();
+        }
+        assert_eq!(builder.build().unwrap().n_th(2).kbps, 500);
     }
 }
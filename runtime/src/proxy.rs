@@ -0,0 +1,369 @@
+//! Connecting through a SOCKS5 or HTTP CONNECT proxy.
+//!
+//! Some sites only allow egress through a corporate proxy. With `proxy` set,
+//! the client opens its connections (data and control) to the proxy and asks
+//! it for a tunnel to the server; the tunnel then carries the stream as is.
+//! A server given by name is resolved by the proxy, as sites behind one often
+//! can't resolve outside names themselves.
+//!
+//! ```text
+//! proxy = { kind = "socks5", address = "10.0.0.1:1080" }
+//! proxy = { kind = "http", address = "proxy.corp:3128", username = "cam", password = "..." }
+//! ```
+
+use errors::*;
+use futures::{Future, future};
+use std::fmt;
+use std::net::{SocketAddr, ToSocketAddrs};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_io::io::{read_exact, write_all};
+
+/// Longest HTTP response header accepted from a proxy.
+const MAX_HTTP_HEADER: usize = 8 * 1024;
+
+/// The protocol of a proxy.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyKind {
+    /// SOCKS5 (RFC 1928), with username/password authentication (RFC 1929)
+    /// if credentials are set.
+    Socks5,
+
+    /// HTTP `CONNECT`, with basic authentication if credentials are set.
+    Http,
+}
+
+/// A proxy to connect through.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProxyConfig {
+    /// The protocol.
+    pub kind: ProxyKind,
+
+    /// The proxy's `host:port`.
+    pub address: String,
+
+    /// The user to authenticate as, if any.
+    #[serde(default)]
+    pub username: Option<String>,
+
+    /// The user's password.
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+impl ProxyConfig {
+    fn credentials(&self) -> Option<(&str, &str)> {
+        self.username
+            .as_ref()
+            .map(|user| (user.as_str(), self.password.as_ref().map_or("", String::as_str)))
+    }
+}
+
+/// Where a tunnel leads.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// An address.
+    Addr(SocketAddr),
+
+    /// A host name, resolved by the proxy, and a port.
+    Host(String, u16),
+}
+
+impl Target {
+    fn port(&self) -> u16 {
+        match *self {
+            Target::Addr(addr) => addr.port(),
+            Target::Host(_, port) => port,
+        }
+    }
+}
+
+impl From<SocketAddr> for Target {
+    fn from(addr: SocketAddr) -> Target {
+        Target::Addr(addr)
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Target::Addr(addr) => write!(f, "{}", addr),
+            Target::Host(ref host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+type Connecting = Box<dyn Future<Item = TcpStream, Error = Error>>;
+
+fn proxy_error(msg: String) -> Error {
+    Error::from_kind(ErrorKind::Proxy(msg))
+}
+
+/// Connects to `target` through the proxy of `config`.
+pub fn connect(config: &ProxyConfig, target: Target, handle: &Handle) -> Connecting {
+    let proxy = match config.address.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(proxy)) => proxy,
+        _ => {
+            let msg = format!("bad proxy address {:?}", config.address);
+            return Box::new(future::err(proxy_error(msg)));
+        }
+    };
+    let tcp = TcpStream::connect(&proxy, handle).from_err();
+    let config = config.clone();
+    Box::new(tcp.and_then(move |tcp| -> Connecting {
+        info!("connecting to {} through {}", target, proxy);
+        match config.kind {
+            ProxyKind::Socks5 => socks5(tcp, target, config.credentials()),
+            ProxyKind::Http => http_connect(tcp, target, config.credentials()),
+        }
+    }))
+}
+
+/// Asks a SOCKS5 proxy to connect to `target`.
+fn socks5(tcp: TcpStream, target: Target, credentials: Option<(&str, &str)>) -> Connecting {
+    let method = if credentials.is_some() { 0x02 } else { 0x00 };
+    // RFC 1929 gives each a length byte
+    if let Some((user, password)) = credentials {
+        if user.len() > 255 || password.len() > 255 {
+            let msg = "SOCKS5 credentials are limited to 255 bytes each".to_string();
+            return Box::new(future::err(proxy_error(msg)));
+        }
+    }
+    let auth = credentials.map(|(user, password)| {
+        let mut auth = vec![0x01, user.len() as u8];
+        auth.extend_from_slice(user.as_bytes());
+        auth.push(password.len() as u8);
+        auth.extend_from_slice(password.as_bytes());
+        auth
+    });
+    let mut request = vec![0x05, 0x01, 0x00];
+    match target {
+        Target::Addr(SocketAddr::V4(addr)) => {
+            request.push(0x01);
+            request.extend_from_slice(&addr.ip().octets());
+        }
+        Target::Addr(SocketAddr::V6(addr)) => {
+            request.push(0x04);
+            request.extend_from_slice(&addr.ip().octets());
+        }
+        Target::Host(ref host, _) if host.len() > 255 => {
+            let msg = format!("host name too long for SOCKS5: {}", host);
+            return Box::new(future::err(proxy_error(msg)));
+        }
+        Target::Host(ref host, _) => {
+            request.push(0x03);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&target.port().to_be_bytes());
+
+    let greeted = write_all(tcp, vec![0x05, 0x01, method])
+        .and_then(|(tcp, _)| read_exact(tcp, [0; 2]))
+        .from_err()
+        .and_then(move |(tcp, reply)| -> Connecting {
+            if reply[0] != 0x05 || reply[1] != method {
+                let msg = format!("SOCKS5 proxy refused method {}", method);
+                return Box::new(future::err(proxy_error(msg)));
+            }
+            match auth {
+                Some(auth) => {
+                    let authenticated = write_all(tcp, auth)
+                        .and_then(|(tcp, _)| read_exact(tcp, [0; 2]))
+                        .from_err()
+                        .and_then(|(tcp, reply)| match reply[1] {
+                            0x00 => Ok(tcp),
+                            _ => Err(proxy_error("SOCKS5 proxy rejected the credentials".into())),
+                        });
+                    Box::new(authenticated)
+                }
+                None => Box::new(future::ok(tcp)),
+            }
+        });
+    let connected = greeted
+        .and_then(move |tcp| {
+            write_all(tcp, request)
+                .and_then(|(tcp, _)| read_exact(tcp, [0; 5]))
+                .from_err()
+        })
+        .and_then(|(tcp, reply)| {
+            if reply[1] != 0x00 {
+                let msg = format!("SOCKS5 proxy failed to connect (reply {})", reply[1]);
+                bail!(ErrorKind::Proxy(msg));
+            }
+            // the rest of the bound address, which we don't need; its first
+            // byte is part of the reply read
+            let len = match reply[3] {
+                0x01 => 4 - 1 + 2,
+                0x03 => reply[4] as usize + 2,
+                0x04 => 16 - 1 + 2,
+                atyp => bail!(ErrorKind::Proxy(format!("unexpected SOCKS5 address type {}", atyp))),
+            };
+            Ok((tcp, len))
+        })
+        .and_then(|(tcp, len)| read_exact(tcp, vec![0; len]).map(|(tcp, _)| tcp).from_err());
+    Box::new(connected)
+}
+
+/// Asks an HTTP proxy to `CONNECT` to `target`.
+fn http_connect(tcp: TcpStream, target: Target, credentials: Option<(&str, &str)>) -> Connecting {
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+    if let Some((user, password)) = credentials {
+        let token = base64(format!("{}:{}", user, password).as_bytes());
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+    }
+    request.push_str("\r\n");
+
+    // byte by byte, so that nothing after the header is consumed
+    let header = write_all(tcp, request.into_bytes()).from_err().and_then(|(tcp, _)| {
+        future::loop_fn((tcp, Vec::new()), |(tcp, mut header)| {
+            read_exact(tcp, [0; 1]).from_err().and_then(move |(tcp, byte)| {
+                header.push(byte[0]);
+                if header.ends_with(b"\r\n\r\n") {
+                    Ok(future::Loop::Break((tcp, header)))
+                } else if header.len() > MAX_HTTP_HEADER {
+                    Err(proxy_error("HTTP proxy sent an oversized header".into()))
+                } else {
+                    Ok(future::Loop::Continue((tcp, header)))
+                }
+            })
+        })
+    });
+    let connected = header.and_then(|(tcp, header)| {
+        let header = String::from_utf8_lossy(&header);
+        let status = header.lines().next().unwrap_or_default();
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(tcp),
+            _ => bail!(ErrorKind::Proxy(format!("HTTP proxy refused to connect: {}", status))),
+        }
+    });
+    Box::new(connected)
+}
+
+/// Standard base64 with padding, for basic authentication.
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (u32::from(b) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3f] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use tokio_core::reactor::Core;
+
+    /// Runs a proxy that answers one handshake as `reply` says, then writes
+    /// "hello" into the tunnel, and connects through it.
+    fn through<F>(kind: ProxyKind, target: Target, reply: F) -> Result<Vec<u8>>
+    where
+        F: FnOnce(&mut ::std::net::TcpStream) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = ProxyConfig {
+            kind,
+            address: listener.local_addr().unwrap().to_string(),
+            username: Some("cam".into()),
+            password: Some("secret".into()),
+        };
+        let proxy = thread::spawn(move || {
+            let (mut client, _) = listener.accept().unwrap();
+            reply(&mut client);
+            let _ = client.write_all(b"hello");
+        });
+        let mut core = Core::new().unwrap();
+        let tunnel = connect(&config, target, &core.handle())
+            .and_then(|tcp| read_exact(tcp, [0; 5]).from_err())
+            .map(|(_, hello)| hello.to_vec());
+        let result = core.run(tunnel);
+        proxy.join().unwrap();
+        result
+    }
+
+    fn read_n(client: &mut ::std::net::TcpStream, n: usize) -> Vec<u8> {
+        let mut buf = vec![0; n];
+        client.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_connect_through_proxies() {
+        assert_eq!(base64(b"cam:secret"), "Y2FtOnNlY3JldA==");
+        assert_eq!(base64(b"ab"), "YWI=");
+
+        let addr = Target::Addr("192.0.2.1:8889".parse().unwrap());
+        let hello = through(ProxyKind::Socks5, addr.clone(), |client| {
+            assert_eq!(read_n(client, 3), [5, 1, 2]);
+            client.write_all(&[5, 2]).unwrap();
+            assert_eq!(read_n(client, 12), b"\x01\x03cam\x06secret");
+            client.write_all(&[1, 0]).unwrap();
+            assert_eq!(read_n(client, 10), [5, 1, 0, 1, 192, 0, 2, 1, 0x22, 0xb9]);
+            client.write_all(&[5, 0, 0, 1, 10, 0, 0, 1, 0x10, 0x00]).unwrap();
+        });
+        assert_eq!(hello.unwrap(), b"hello");
+
+        // the proxy resolves names, and may report its bound address by name
+        let host = Target::Host("awstream.example".into(), 8889);
+        let hello = through(ProxyKind::Socks5, host.clone(), |client| {
+            assert_eq!(read_n(client, 3), [5, 1, 2]);
+            client.write_all(&[5, 2]).unwrap();
+            let _ = read_n(client, 12);
+            client.write_all(&[1, 0]).unwrap();
+            assert_eq!(read_n(client, 5), [5, 1, 0, 3, 16]);
+            assert_eq!(read_n(client, 18), b"awstream.example\x22\xb9");
+            client.write_all(b"\x05\x00\x00\x03\x05proxy\x10\x00").unwrap();
+        });
+        assert_eq!(hello.unwrap(), b"hello");
+
+        let hello = through(ProxyKind::Http, host, |client| {
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.extend(read_n(client, 1));
+            }
+            let request = String::from_utf8(request).unwrap();
+            assert!(request.starts_with("CONNECT awstream.example:8889 HTTP/1.1\r\n"));
+            assert!(request.contains("Proxy-Authorization: Basic Y2FtOnNlY3JldA==\r\n"));
+            client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").unwrap();
+        });
+        assert_eq!(hello.unwrap(), b"hello");
+
+        let refused = through(ProxyKind::Http, addr.clone(), |client| {
+            let _ = read_n(client, 16);
+            client.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n").unwrap();
+        });
+        match refused {
+            Err(Error(ErrorKind::Proxy(msg), _)) => assert!(msg.contains("407")),
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+
+        // credentials that don't fit their length byte
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = ProxyConfig {
+            kind: ProxyKind::Socks5,
+            address: listener.local_addr().unwrap().to_string(),
+            username: Some("u".repeat(256)),
+            password: None,
+        };
+        let mut core = Core::new().unwrap();
+        match core.run(connect(&config, addr, &core.handle())) {
+            Err(Error(ErrorKind::Proxy(msg), _)) => assert!(msg.contains("255")),
+            r => panic!("unexpected {:?}", r.map(|_| ())),
+        }
+    }
+}
@@ -16,7 +16,7 @@ impl SenderCtl {
     pub fn new(tx: UnboundedSender<AsDatum>, counter: Arc<AtomicIsize>) -> Self {
         SenderCtl {
             inner: tx,
-            counter: counter,
+            counter,
         }
     }
 }
@@ -30,7 +30,7 @@ impl ReceiverCtl {
     pub fn new(rx: UnboundedReceiver<AsDatum>, counter: Arc<AtomicIsize>) -> Self {
         ReceiverCtl {
             inner: rx,
-            counter: counter,
+            counter,
         }
     }
 }
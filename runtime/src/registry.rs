@@ -0,0 +1,622 @@
+//! Sessions shared by several server instances.
+//!
+//! Behind a load balancer, a client connects to any instance of an ingest
+//! tier, but its feedback (quality reports, operator directives through
+//! `admin`) may originate at another one. A `SessionRegistry` tells each
+//! instance which sessions the others serve and carries datums to them:
+//! `SessionStore::send_feedback` falls back to it for sessions it doesn't
+//! have. Deployments with a shared store (e.g., redis) implement it and set
+//! it with `Server::set_registry`; with `registry` set, the instances gossip
+//! instead:
+//!
+//! ```text
+//! hello(node) + attached sessions    on connecting to a peer, and every 10 s
+//! attached(token, node)              a session attached to `node`
+//! detached(token, node)              its connection ended
+//! deliver(token, datum)              feedback for a session of the peer
+//! ```
+//!
+//! Only the seeds in `peers` need to be configured: an instance introduces
+//! itself to every instance that says hello. Messages are bincode, prefixed
+//! with their length (big-endian u32, at most 1 MB), and delivery is best
+//! effort. Each message carries an HMAC-SHA512 tag keyed with the `secret`
+//! all instances share, over a nonce the receiver picks for the connection,
+//! the message's sequence number and the message, so that a host without the
+//! secret can neither announce sessions nor inject feedback, and messages
+//! can't be replayed. An owner that isn't announced again within 30 s, or
+//! whose instance hung up, is forgotten. Only routing is shared; a client
+//! reconnecting to another instance opens a new session there.
+
+use super::AsDatum;
+use super::session::SessionStore;
+use bincode;
+use byteorder::{BigEndian, ByteOrder};
+use errors::*;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha512};
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+type HmacSha512 = Hmac<Sha512>;
+
+/// How long connecting to a peer, or writing to it, may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The longest message a peer may send.
+const MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+/// How often an instance announces its sessions again.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long an owner is trusted without being announced again.
+const OWNER_TTL: Duration = Duration::from_secs(30);
+
+/// The shortest secret accepted, in bytes.
+const MIN_SECRET_LEN: usize = 16;
+
+const NONCE_LEN: usize = 16;
+
+const TAG_LEN: usize = 32;
+
+/// Where the sessions of other instances are.
+pub trait SessionRegistry: Send + Sync {
+    /// Called when session `token` attaches to a connection of this instance.
+    fn attached(&self, token: u64);
+
+    /// Called when its connection ended.
+    fn detached(&self, token: u64);
+
+    /// Sends `datum` to the client of `token` through the instance serving
+    /// it. Returns false if no instance is known to, or it can't be reached.
+    fn relay(&self, token: u64, datum: AsDatum) -> Result<bool>;
+}
+
+/// How instances gossip.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RegistryConfig {
+    /// The address to listen on for peers, e.g., `0.0.0.0:7400`.
+    pub listen: String,
+
+    /// The address peers reach this instance at; defaults to the address
+    /// listened on.
+    #[serde(default)]
+    pub advertise: Option<String>,
+
+    /// Instances to introduce this one to.
+    #[serde(default)]
+    pub peers: Vec<String>,
+
+    /// The secret (at least 16 bytes) all instances authenticate their
+    /// messages with.
+    pub secret: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+enum Message {
+    Hello { node: String },
+    Attached { token: u64, node: String },
+    Detached { token: u64, node: String },
+    Deliver { token: u64, datum: AsDatum },
+}
+
+#[derive(Debug)]
+enum Command {
+    Broadcast(Message),
+    /// Sends to a peer, telling whether it was written.
+    Send(String, Message, Sender<bool>),
+    Introduce(String),
+    Stop,
+}
+
+/// The instance serving each session of the others, and when it last said
+/// so.
+type Owners = Arc<Mutex<HashMap<u64, (String, Instant)>>>;
+
+/// The connections accepted from peers, to shut down on `stop`.
+type Accepted = Arc<Mutex<HashMap<usize, TcpStream>>>;
+
+/// A registry kept by gossiping with the other instances.
+pub struct Gossip {
+    node: String,
+    owners: Owners,
+    outgoing: Mutex<Sender<Command>>,
+    accepted: Accepted,
+    stopped: Arc<AtomicBool>,
+    /// Where to connect to wake up the listener.
+    listening: SocketAddr,
+}
+
+impl ::std::fmt::Debug for Gossip {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("Gossip").field("node", &self.node).finish()
+    }
+}
+
+/// Stops a `Gossip` when dropped.
+#[derive(Debug)]
+pub struct GossipHandle(Arc<Gossip>);
+
+impl Drop for GossipHandle {
+    fn drop(&mut self) {
+        self.0.stop();
+    }
+}
+
+impl Gossip {
+    /// Listens for the peers of `config` and introduces this instance to
+    /// them, routing datums for `sessions`.
+    pub fn start<A>(config: &RegistryConfig, sessions: SessionStore<A>) -> Result<Arc<Gossip>>
+    where
+        A: Clone + Send + 'static,
+    {
+        if config.secret.len() < MIN_SECRET_LEN {
+            let reason = format!("the registry secret needs {} bytes or more", MIN_SECRET_LEN);
+            bail!(ErrorKind::InvalidConfig(reason));
+        }
+        let mac = HmacSha512::new_from_slice(config.secret.as_bytes())
+            .map_err(|_| ErrorKind::InvalidConfig("bad registry secret".into()))?;
+        let listener = TcpListener::bind(&config.listen as &str)?;
+        let mut listening = listener.local_addr()?;
+        if listening.ip().is_unspecified() {
+            listening.set_ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        }
+        let node = match config.advertise {
+            Some(ref advertise) => advertise.clone(),
+            None => listener.local_addr()?.to_string(),
+        };
+        info!("gossiping sessions as {}", node);
+        let (tx, rx) = mpsc::channel();
+        let owners = Owners::default();
+        let accepted = Accepted::default();
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let local = sessions.clone();
+        let sender = Outgoing {
+            node: node.clone(),
+            mac: mac.clone(),
+            conns: HashMap::new(),
+            peers: HashSet::new(),
+        };
+        thread::Builder::new()
+            .name("awstream-gossip-out".into())
+            .spawn(move || sender.run(&rx, &local))?;
+        for peer in &config.peers {
+            tx.send(Command::Introduce(peer.clone()))
+                .map_err(|_| Error::from("gossip stopped"))?;
+        }
+
+        let (incoming, known, receiver) = (tx.clone(), owners.clone(), node.clone());
+        let (open, stop) = (accepted.clone(), stopped.clone());
+        thread::Builder::new()
+            .name("awstream-gossip-in".into())
+            .spawn(move || {
+                for (id, stream) in listener.incoming().enumerate() {
+                    if stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let peer = Peer {
+                        id,
+                        node: receiver.clone(),
+                        mac: mac.clone(),
+                        commands: incoming.clone(),
+                        owners: known.clone(),
+                        accepted: open.clone(),
+                    };
+                    let sessions = sessions.clone();
+                    let served = stream.map_err(Error::from).and_then(|s| {
+                        open.lock()?.insert(id, s.try_clone()?);
+                        thread::Builder::new()
+                            .name("awstream-gossip-peer".into())
+                            .spawn(move || peer.serve(s, &sessions))
+                            .map_err(Error::from)
+                    });
+                    if let Err(e) = served {
+                        warn!("failed to serve a gossip peer: {}", e);
+                    }
+                }
+            })?;
+        Ok(Arc::new(Gossip {
+            node,
+            owners,
+            outgoing: Mutex::new(tx),
+            accepted,
+            stopped,
+            listening,
+        }))
+    }
+
+    /// The address peers reach this instance at.
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+
+    /// Returns a handle that stops the gossip once dropped.
+    pub fn handle(self: &Arc<Self>) -> GossipHandle {
+        GossipHandle(self.clone())
+    }
+
+    /// Stops listening, hangs up on the peers and forgets their sessions.
+    pub fn stop(&self) {
+        if self.stopped.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        if let Ok(tx) = self.outgoing.lock() {
+            let _ = tx.send(Command::Stop);
+        }
+        // wakes the listener up to see it stopped
+        let _ = TcpStream::connect_timeout(&self.listening, CONNECT_TIMEOUT);
+        if let Ok(mut accepted) = self.accepted.lock() {
+            for (_, conn) in accepted.drain() {
+                let _ = conn.shutdown(Shutdown::Both);
+            }
+        }
+        if let Ok(mut owners) = self.owners.lock() {
+            owners.clear();
+        }
+        info!("stopped gossiping sessions as {}", self.node);
+    }
+
+    /// Returns false once stopped.
+    fn send(&self, command: Command) -> bool {
+        if self.stopped.load(Ordering::SeqCst) {
+            return false;
+        }
+        let sent = self.outgoing.lock().map(|tx| tx.send(command).is_ok());
+        let sent = sent.unwrap_or(false);
+        if !sent {
+            warn!("gossip stopped, sessions are no longer shared");
+        }
+        sent
+    }
+
+    /// The instance serving `token`, unless it went quiet.
+    fn owner(&self, token: u64) -> Result<Option<String>> {
+        let mut owners = self.owners.lock()?;
+        match owners.get(&token) {
+            Some(&(ref owner, seen)) if seen.elapsed() < OWNER_TTL => {
+                return Ok(Some(owner.clone()));
+            }
+            Some(_) => {}
+            None => return Ok(None),
+        }
+        owners.remove(&token);
+        Ok(None)
+    }
+}
+
+impl SessionRegistry for Gossip {
+    fn attached(&self, token: u64) {
+        let node = self.node.clone();
+        self.send(Command::Broadcast(Message::Attached { token, node }));
+    }
+
+    fn detached(&self, token: u64) {
+        let node = self.node.clone();
+        self.send(Command::Broadcast(Message::Detached { token, node }));
+    }
+
+    fn relay(&self, token: u64, datum: AsDatum) -> Result<bool> {
+        let owner = match self.owner(token)? {
+            Some(owner) => owner,
+            None => return Ok(false),
+        };
+        let (tx, rx) = mpsc::channel();
+        if !self.send(Command::Send(owner, Message::Deliver { token, datum }, tx)) {
+            return Ok(false);
+        }
+        // connecting and writing each take up to `CONNECT_TIMEOUT`
+        Ok(rx.recv_timeout(CONNECT_TIMEOUT * 3).unwrap_or(false))
+    }
+}
+
+/// Authenticates the messages of one connection (see the module docs).
+struct Auth {
+    mac: HmacSha512,
+    nonce: [u8; NONCE_LEN],
+    seq: u64,
+}
+
+impl Auth {
+    /// The MAC over the next message, `mem`.
+    fn next(&mut self, mem: &[u8]) -> HmacSha512 {
+        let mut mac = self.mac.clone();
+        mac.update(&self.nonce);
+        mac.update(&self.seq.to_be_bytes());
+        mac.update(mem);
+        self.seq += 1;
+        mac
+    }
+}
+
+/// A nonce no other connection uses.
+fn fresh_nonce(node: &str) -> [u8; NONCE_LEN] {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut hasher = Sha512::new();
+    hasher.update(node.as_bytes());
+    hasher.update(since_epoch.as_nanos().to_be_bytes());
+    hasher.update(COUNT.fetch_add(1, Ordering::SeqCst).to_be_bytes());
+    let mut nonce = [0; NONCE_LEN];
+    nonce.copy_from_slice(&hasher.finalize()[..NONCE_LEN]);
+    nonce
+}
+
+/// A connection to a peer, owned by the outgoing thread.
+struct Link {
+    conn: TcpStream,
+    auth: Auth,
+}
+
+/// The connections to the peers, owned by the outgoing thread.
+struct Outgoing {
+    node: String,
+    mac: HmacSha512,
+    conns: HashMap<String, Link>,
+    peers: HashSet<String>,
+}
+
+impl Outgoing {
+    fn run<A: Clone>(mut self, commands: &Receiver<Command>, sessions: &SessionStore<A>) {
+        let mut refresh = Instant::now() + REFRESH_INTERVAL;
+        loop {
+            let wait = refresh.saturating_duration_since(Instant::now());
+            match commands.recv_timeout(wait) {
+                Ok(Command::Broadcast(message)) => {
+                    let peers = self.peers.iter().cloned().collect::<Vec<_>>();
+                    for peer in peers {
+                        self.send(&peer, &message, sessions);
+                    }
+                }
+                Ok(Command::Send(peer, message, sent)) => {
+                    let _ = sent.send(self.send(&peer, &message, sessions));
+                }
+                Ok(Command::Introduce(peer)) => {
+                    if peer != self.node && self.peers.insert(peer.clone()) {
+                        self.connect(&peer, sessions);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    let peers = self.conns.keys().cloned().collect::<Vec<_>>();
+                    for peer in peers {
+                        let announced = match self.conns.get_mut(&peer) {
+                            Some(link) => announce(link, &self.node, sessions),
+                            None => continue,
+                        };
+                        if let Err(e) = announced {
+                            debug!("lost gossip peer {}: {}", peer, e);
+                            self.conns.remove(&peer);
+                        }
+                    }
+                    refresh = Instant::now() + REFRESH_INTERVAL;
+                }
+                Ok(Command::Stop) | Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    /// Returns true if `message` was written to `peer`.
+    fn send<A: Clone>(
+        &mut self,
+        peer: &str,
+        message: &Message,
+        sessions: &SessionStore<A>,
+    ) -> bool {
+        if !self.conns.contains_key(peer) {
+            self.connect(peer, sessions);
+        }
+        let written = match self.conns.get_mut(peer) {
+            Some(link) => write(link, message),
+            None => return false,
+        };
+        if let Err(e) = written {
+            debug!("lost gossip peer {}: {}", peer, e);
+            self.conns.remove(peer);
+            return false;
+        }
+        true
+    }
+
+    /// Connects to `peer` and tells it the sessions of this instance.
+    fn connect<A: Clone>(&mut self, peer: &str, sessions: &SessionStore<A>) {
+        let connected = dial(peer).and_then(|mut conn| {
+            conn.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+            conn.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+            let mut nonce = [0; NONCE_LEN];
+            conn.read_exact(&mut nonce)?;
+            let auth = Auth {
+                mac: self.mac.clone(),
+                nonce,
+                seq: 0,
+            };
+            let mut link = Link { conn, auth };
+            write(&mut link, &Message::Hello { node: self.node.clone() })?;
+            announce(&mut link, &self.node, sessions)?;
+            Ok(link)
+        });
+        match connected {
+            Ok(link) => {
+                self.conns.insert(peer.to_string(), link);
+            }
+            Err(e) => debug!("failed to reach gossip peer {}: {}", peer, e),
+        }
+    }
+}
+
+/// Tells a peer the sessions attached to this instance.
+fn announce<A: Clone>(link: &mut Link, node: &str, sessions: &SessionStore<A>) -> Result<()> {
+    for (session, attached) in sessions.list()? {
+        if attached {
+            let node = node.to_string();
+            write(link, &Message::Attached { token: session.token, node })?;
+        }
+    }
+    Ok(())
+}
+
+fn dial(peer: &str) -> Result<TcpStream> {
+    match peer.to_socket_addrs()?.next() {
+        Some(addr) => Ok(TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?),
+        None => bail!(ErrorKind::InvalidConfig(format!("no address for peer {}", peer))),
+    }
+}
+
+fn write(link: &mut Link, message: &Message) -> Result<()> {
+    let mem = bincode::serialize(message, bincode::Infinite)?;
+    let tag = link.auth.next(&mem).finalize().into_bytes();
+    let mut len = [0; 4];
+    BigEndian::write_u32(&mut len, (TAG_LEN + mem.len()) as u32);
+    link.conn.write_all(&len)?;
+    link.conn.write_all(&tag[..TAG_LEN])?;
+    link.conn.write_all(&mem)?;
+    Ok(())
+}
+
+/// A connection accepted from a peer.
+struct Peer {
+    id: usize,
+    /// This instance.
+    node: String,
+    mac: HmacSha512,
+    commands: Sender<Command>,
+    owners: Owners,
+    accepted: Accepted,
+}
+
+impl Peer {
+    fn serve<A>(self, conn: TcpStream, sessions: &SessionStore<A>) {
+        let mut node = None;
+        if let Err(e) = self.receive(conn, &mut node, sessions) {
+            debug!("gossip peer left: {}", e);
+        }
+        if let Ok(mut accepted) = self.accepted.lock() {
+            accepted.remove(&self.id);
+        }
+        // what the peer announced goes with it
+        if let (Some(node), Ok(mut owners)) = (node, self.owners.lock()) {
+            owners.retain(|_, &mut (ref owner, _)| *owner != node);
+        }
+    }
+
+    /// Applies the messages of a peer until it leaves, noting who it is in
+    /// `node`.
+    fn receive<A>(
+        &self,
+        mut conn: TcpStream,
+        node: &mut Option<String>,
+        sessions: &SessionStore<A>,
+    ) -> Result<()> {
+        let mut auth = Auth {
+            mac: self.mac.clone(),
+            nonce: fresh_nonce(&self.node),
+            seq: 0,
+        };
+        conn.write_all(&auth.nonce)?;
+        loop {
+            let mut len = [0; 4];
+            conn.read_exact(&mut len)?;
+            let len = BigEndian::read_u32(&len) as usize;
+            if !(TAG_LEN..=TAG_LEN + MAX_MESSAGE_LEN).contains(&len) {
+                bail!("gossip message of {} bytes", len);
+            }
+            let mut mem = vec![0; len];
+            conn.read_exact(&mut mem)?;
+            let (tag, mem) = mem.split_at(TAG_LEN);
+            if auth.next(mem).verify_truncated_left(tag).is_err() {
+                bail!("unauthenticated gossip message");
+            }
+            match bincode::deserialize(mem)? {
+                Message::Hello { node: peer } => {
+                    *node = Some(peer.clone());
+                    // introduce this instance back
+                    self.commands
+                        .send(Command::Introduce(peer))
+                        .map_err(|_| Error::from("gossip stopped"))?;
+                }
+                Message::Attached { token, node } => {
+                    self.owners.lock()?.insert(token, (node, Instant::now()));
+                }
+                Message::Detached { token, node } => {
+                    let mut owners = self.owners.lock()?;
+                    if owners.get(&token).map(|o| &o.0) == Some(&node) {
+                        owners.remove(&token);
+                    }
+                }
+                Message::Deliver { token, datum } => {
+                    if !sessions.deliver(token, datum)? {
+                        debug!("feedback relayed for unknown session {:x}", token);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Waits up to 5 s for `f`.
+    fn eventually<F: FnMut() -> bool>(mut f: F) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            if f() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    #[test]
+    fn test_feedback_reaches_session_of_another_instance() {
+        let config = |peers, secret: &str| RegistryConfig {
+            listen: "127.0.0.1:0".into(),
+            advertise: None,
+            peers,
+            secret: secret.into(),
+        };
+        let secret = "correct horse battery staple";
+        let (a, b) = (SessionStore::<()>::new(), SessionStore::<()>::new());
+        let gossip = Gossip::start(&config(vec![], secret), a.clone()).unwrap();
+        a.set_registry(gossip.clone()).unwrap();
+        // only b knows a
+        let (session, _) = b.open(None, ()).unwrap();
+        let seeds = vec![gossip.node().to_string()];
+        let b_gossip = Gossip::start(&config(seeds.clone(), secret), b.clone()).unwrap();
+        b.set_registry(b_gossip.clone()).unwrap();
+
+        let datum = AsDatum::latency_probe();
+        assert!(eventually(|| a.send_feedback(session.token, datum.clone()).unwrap()));
+        assert!(eventually(|| session.outbox.lock().unwrap().len() == 1));
+
+        // and a knows b from then on
+        let (other, _) = b.open(None, ()).unwrap();
+        assert!(eventually(|| a.send_feedback(other.token, datum.clone()).unwrap()));
+        b.detach(other.token).unwrap();
+        assert!(eventually(|| !a.send_feedback(other.token, datum.clone()).unwrap()));
+        assert!(!a.deliver(session.token, datum.clone()).unwrap());
+
+        // an instance without the secret isn't heard
+        let c = SessionStore::<()>::new();
+        let (stranger, _) = c.open(None, ()).unwrap();
+        let c_gossip = Gossip::start(&config(seeds, "not the secret!!"), c.clone());
+        c.set_registry(c_gossip.unwrap()).unwrap();
+        assert!(Gossip::start(&config(vec![], "short"), c.clone()).is_err());
+        // nor is a message too long to be one
+        let mut raw = TcpStream::connect(gossip.node()).unwrap();
+        raw.read_exact(&mut [0; NONCE_LEN]).unwrap();
+        raw.write_all(&[0xff; 4]).unwrap();
+        assert_eq!(raw.read(&mut [0; 1]).unwrap(), 0);
+        assert!(!a.send_feedback(stranger.token, datum.clone()).unwrap());
+
+        // once b stops, its sessions can't be reached
+        drop(b_gossip.handle());
+        assert!(eventually(|| !a.send_feedback(session.token, datum.clone()).unwrap()));
+    }
+}
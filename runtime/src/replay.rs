@@ -0,0 +1,228 @@
+//! Recording and deterministic replay of controller decisions.
+//!
+//! With `record_path` set, the client appends every signal that reaches the
+//! controller (queue congestion with the estimated rate and latency, remote
+//! congestion with the receiver's throughput and latency, empty queue, probe
+//! completion, encoder limits, system load, latency budget violations) to a
+//! CSV file. `replay_decisions`
+//! feeds such a recording to a `Policy` offline, so that a run can be
+//! debugged, or two policies compared decision-for-decision.
+
+use super::Bandwidth;
+use super::adaptation::{self, Decision, Policy, Signal};
+use super::profile::SimpleProfile;
+use csv;
+use errors::*;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::time::Instant;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+enum SignalKind {
+    QueueCongest,
+    QueueEmpty,
+    RemoteCongest,
+    ProbeDone,
+    EncoderLimit,
+    SystemLoad,
+    BudgetViolation,
+    LevelAvailable,
+    MemoryPressure,
+    PredictedBurst,
+    DeadlineMiss,
+}
+
+/// One row in the recording file.
+#[derive(Serialize, Deserialize, Debug)]
+struct Row {
+    t_ms: u64,
+    kind: SignalKind,
+    rate: f64,
+    latency: f64,
+    level: usize,
+}
+
+/// A signal as seen by the controller, with the time (in ms) since the
+/// recording started.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecordedInput {
+    /// Milliseconds since the recording started.
+    pub t_ms: u64,
+
+    /// The signal fed to the policy.
+    pub signal: Signal,
+}
+
+impl From<RecordedInput> for Row {
+    fn from(input: RecordedInput) -> Row {
+        let (kind, rate, latency, level) = match input.signal {
+            Signal::QueueCongest(r, l) => (SignalKind::QueueCongest, r.kbps(), l, 0),
+            Signal::QueueEmpty => (SignalKind::QueueEmpty, 0.0, 0.0, 0),
+            Signal::RemoteCongest(r, l) => (SignalKind::RemoteCongest, r.kbps(), l, 0),
+            Signal::ProbeDone => (SignalKind::ProbeDone, 0.0, 0.0, 0),
+            Signal::EncoderLimit(max) => (SignalKind::EncoderLimit, 0.0, 0.0, max),
+            Signal::SystemLoad(o) => (SignalKind::SystemLoad, 0.0, 0.0, o as usize),
+            Signal::BudgetViolation(l) => (SignalKind::BudgetViolation, 0.0, l, 0),
+            // availability is carried in the rate column
+            Signal::LevelAvailable(l, a) => (SignalKind::LevelAvailable, f64::from(u8::from(a)), 0.0, l),
+            // bytes in use are carried in the level column
+            Signal::MemoryPressure(used) => (SignalKind::MemoryPressure, 0.0, 0.0, used),
+            Signal::PredictedBurst(l) => (SignalKind::PredictedBurst, 0.0, l, 0),
+            Signal::DeadlineMiss(l) => (SignalKind::DeadlineMiss, 0.0, l, 0),
+        };
+        Row {
+            t_ms: input.t_ms,
+            kind,
+            rate,
+            latency,
+            level,
+        }
+    }
+}
+
+impl From<Row> for RecordedInput {
+    fn from(row: Row) -> RecordedInput {
+        let signal = match row.kind {
+            SignalKind::QueueCongest => {
+                Signal::QueueCongest(Bandwidth::from_kbps(row.rate), row.latency)
+            }
+            SignalKind::QueueEmpty => Signal::QueueEmpty,
+            SignalKind::RemoteCongest => {
+                Signal::RemoteCongest(Bandwidth::from_kbps(row.rate), row.latency)
+            }
+            SignalKind::ProbeDone => Signal::ProbeDone,
+            SignalKind::EncoderLimit => Signal::EncoderLimit(row.level),
+            SignalKind::SystemLoad => Signal::SystemLoad(row.level != 0),
+            SignalKind::BudgetViolation => Signal::BudgetViolation(row.latency),
+            SignalKind::LevelAvailable => Signal::LevelAvailable(row.level, row.rate != 0.0),
+            SignalKind::MemoryPressure => Signal::MemoryPressure(row.level),
+            SignalKind::PredictedBurst => Signal::PredictedBurst(row.latency),
+            SignalKind::DeadlineMiss => Signal::DeadlineMiss(row.latency),
+        };
+        RecordedInput {
+            t_ms: row.t_ms,
+            signal,
+        }
+    }
+}
+
+/// Appends controller inputs to a CSV file.
+pub struct Recorder<W: io::Write> {
+    writer: csv::Writer<W>,
+    start: Instant,
+}
+
+impl Recorder<File> {
+    /// Creates (or truncates) the recording at `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Recorder<File>> {
+        Ok(Recorder::new(File::create(path)?))
+    }
+}
+
+impl<W: io::Write> Recorder<W> {
+    /// Creates a recorder writing into `w`.
+    pub fn new(w: W) -> Recorder<W> {
+        Recorder {
+            writer: csv::WriterBuilder::new().has_headers(false).from_writer(w),
+            start: Instant::now(),
+        }
+    }
+
+    /// Records a signal. Each row is flushed so that the recording survives
+    /// the client being killed.
+    pub fn record(&mut self, signal: Signal) -> Result<()> {
+        let elapsed = self.start.elapsed();
+        let t_ms = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+        self.writer.serialize(Row::from(RecordedInput { t_ms, signal }))?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Loads a recording produced by `Recorder`.
+pub fn load_inputs<P: AsRef<Path>>(path: P) -> Result<Vec<RecordedInput>> {
+    read_inputs(File::open(path)?)
+}
+
+fn read_inputs<R: io::Read>(r: R) -> Result<Vec<RecordedInput>> {
+    let mut rdr = csv::ReaderBuilder::new().has_headers(false).from_reader(r);
+    let mut inputs = Vec::new();
+    for row in rdr.deserialize() {
+        let row: Row = row?;
+        inputs.push(row.into());
+    }
+    Ok(inputs)
+}
+
+/// Re-runs `policy` over recorded `inputs`, starting from `profile`, and
+/// returns one decision per input.
+pub fn replay_decisions<P: Policy + ?Sized>(
+    inputs: &[RecordedInput],
+    policy: &mut P,
+    mut profile: SimpleProfile,
+) -> Vec<Decision> {
+    inputs
+        .iter()
+        .map(|input| adaptation::decide(policy, &mut profile, input.signal))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use adaptation::{Action, Adaptation};
+    use profile::{Profile, Record};
+
+    fn simple_profile(n: usize) -> SimpleProfile {
+        let records = (0..n)
+            .map(|i| Record::new(Bandwidth::from_kbps(i as f64 * 100.0), i, 0.0))
+            .collect();
+        Profile::_with_vec(records).simplify()
+    }
+
+    #[test]
+    fn test_record_and_load_roundtrip() {
+        let signals = vec![
+            Signal::QueueEmpty,
+            Signal::QueueCongest(Bandwidth::from_kbps(120.5), 30.0),
+            Signal::RemoteCongest(Bandwidth::from_kbps(80.0), 200.0),
+            Signal::ProbeDone,
+            Signal::EncoderLimit(2),
+            Signal::BudgetViolation(640.0),
+            Signal::LevelAvailable(3, false),
+            Signal::LevelAvailable(3, true),
+        ];
+        let mut recorder = Recorder::new(Vec::new());
+        for s in &signals {
+            recorder.record(*s).unwrap();
+        }
+        let buf = recorder.writer.into_inner().unwrap();
+        let inputs = read_inputs(&buf[..]).unwrap();
+        let loaded = inputs.iter().map(|i| i.signal).collect::<Vec<_>>();
+        assert_eq!(loaded, signals);
+    }
+
+    #[test]
+    fn test_replay_is_deterministic() {
+        let inputs = (0..10)
+            .map(|i| RecordedInput {
+                t_ms: i * 100,
+                signal: if i == 6 {
+                    Signal::QueueCongest(Bandwidth::from_kbps(150.0), 20.0)
+                } else {
+                    Signal::QueueEmpty
+                },
+            })
+            .collect::<Vec<_>>();
+
+        let a = replay_decisions(&inputs, &mut Adaptation::default(), simple_profile(4));
+        let b = replay_decisions(&inputs, &mut Adaptation::default(), simple_profile(4));
+        assert_eq!(a, b);
+        assert_eq!(a.len(), inputs.len());
+
+        // startup climbs one level per empty queue until the top
+        assert_eq!(a[0].action, Action::AdvanceConfig);
+        assert_eq!(a[2].level, 3);
+    }
+}
@@ -0,0 +1,177 @@
+//! Exports an experiment log as the per-client time series used by the
+//! evaluation scripts of the paper.
+//!
+//! Each client gets a CSV with the columns `time` (s since its first frame),
+//! `level`, `throughput` (kbps), `latency` (ms) and `accuracy`, one row per
+//! time bin. Bins without frames keep the previous level with zero
+//! throughput; latency and accuracy are left empty when unknown.
+
+use csv;
+use errors::*;
+use experiment_log::{self, FrameEntry};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// One time bin of a client.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReportRow {
+    /// Start of the bin (s since the client's first frame).
+    pub time: f64,
+
+    /// Level of the last frame delivered by the end of the bin.
+    pub level: usize,
+
+    /// Delivered bitrate (kbps).
+    pub throughput: f64,
+
+    /// Mean latency of the frames in the bin (ms).
+    pub latency: Option<f64>,
+
+    /// Mean ground-truth accuracy of the frames in the bin.
+    pub accuracy: Option<f64>,
+
+    /// Fraction of the bytes in the bin taken for keyframes, when logged.
+    pub key_share: Option<f64>,
+}
+
+/// Bins the entries of each client into `bin_ms` intervals (at least 1 ms).
+pub fn summarize(entries: &[FrameEntry], bin_ms: u64) -> BTreeMap<String, Vec<ReportRow>> {
+    let bin_ms = ::std::cmp::max(bin_ms, 1) as i64;
+    let mut clients: BTreeMap<String, Vec<&FrameEntry>> = BTreeMap::new();
+    for e in entries {
+        clients.entry(e.client.clone()).or_default().push(e);
+    }
+    clients
+        .into_iter()
+        .map(|(client, mut frames)| {
+            frames.sort_by_key(|e| e.time_ms);
+            (client, bin(&frames, bin_ms))
+        })
+        .collect()
+}
+
+fn mean<I: Iterator<Item = f64>>(values: I) -> Option<f64> {
+    let (sum, n) = values.fold((0.0, 0), |(s, n), v| (s + v, n + 1));
+    if n == 0 {
+        None
+    } else {
+        Some(sum / n as f64)
+    }
+}
+
+/// Bins the frames of one client, sorted by time.
+fn bin(frames: &[&FrameEntry], bin_ms: i64) -> Vec<ReportRow> {
+    let start = match frames.first() {
+        Some(e) => e.time_ms,
+        None => return Vec::new(),
+    };
+    let mut rows = Vec::new();
+    let mut level = frames[0].level;
+    let mut rest = frames;
+    while !rest.is_empty() {
+        let index = rows.len() as i64;
+        let end = start + (index + 1) * bin_ms;
+        let n = rest.iter().take_while(|e| e.time_ms < end).count();
+        let (in_bin, after) = rest.split_at(n);
+        rest = after;
+        if let Some(last) = in_bin.last() {
+            level = last.level;
+        }
+        let bytes: usize = in_bin.iter().map(|e| e.bytes).sum();
+        let classified = in_bin.iter().filter(|e| e.key.is_some());
+        let (key_bytes, classified_bytes) = classified.fold((0, 0), |(k, n), e| {
+            (k + if e.key == Some(true) { e.bytes } else { 0 }, n + e.bytes)
+        });
+        rows.push(ReportRow {
+            time: (index * bin_ms) as f64 / 1000.0,
+            level,
+            throughput: bytes as f64 * 8.0 / bin_ms as f64,
+            latency: mean(in_bin.iter().map(|e| e.latency_ms)),
+            accuracy: mean(in_bin.iter().filter_map(|e| e.ground_truth)),
+            key_share: if classified_bytes > 0 {
+                Some(key_bytes as f64 / classified_bytes as f64)
+            } else {
+                None
+            },
+        });
+    }
+    rows
+}
+
+/// Writes `rows` as CSV into `w`.
+pub fn write_rows<W: io::Write>(rows: &[ReportRow], w: W) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(w);
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Converts the experiment log at `log` into one CSV per client in `dir`,
+/// named after the client's address. Returns the paths written.
+pub fn export<P: AsRef<Path>, Q: AsRef<Path>>(log: P, dir: Q, bin_ms: u64) -> Result<Vec<PathBuf>> {
+    let entries = experiment_log::read_entries(log)?;
+    let mut paths = Vec::new();
+    for (client, rows) in summarize(&entries, bin_ms) {
+        let name = client.replace(|c: char| !c.is_ascii_alphanumeric() && c != '.', "_");
+        let path = dir.as_ref().join(format!("{}.csv", name));
+        write_rows(&rows, ::std::fs::File::create(&path)?)?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(client: &str, time_ms: i64, level: usize, bytes: usize) -> FrameEntry {
+        FrameEntry {
+            time_ms,
+            client: client.into(),
+            level,
+            frame_num: 0,
+            bytes,
+            latency_ms: 100.0,
+            ground_truth: Some(0.5 + level as f64 * 0.1),
+            confidence: None,
+            cwnd: None,
+            srtt_ms: None,
+            retransmits: None,
+            key: Some(bytes > 500),
+        }
+    }
+
+    #[test]
+    fn test_summarize() {
+        let entries = vec![
+            entry("a", 1000, 0, 500),
+            entry("b", 1200, 3, 100),
+            entry("a", 1500, 1, 500),
+            entry("a", 3100, 2, 1000),
+        ];
+        let report = summarize(&entries, 1000);
+        assert_eq!(report["b"].len(), 1);
+
+        let a = &report["a"];
+        assert_eq!(a.len(), 3);
+        assert_eq!(a[0].throughput, 8.0);
+        assert_eq!(a[0].level, 1);
+        assert_eq!(a[0].accuracy, Some(0.55));
+        // an outage keeps the level, with no throughput
+        assert_eq!((a[1].time, a[1].level, a[1].throughput), (1.0, 1, 0.0));
+        assert_eq!(a[1].latency, None);
+        assert_eq!(a[2].level, 2);
+        assert_eq!(a[0].key_share, Some(0.0));
+        assert_eq!(a[1].key_share, None);
+        assert_eq!(a[2].key_share, Some(1.0));
+
+        let mut csv = Vec::new();
+        write_rows(a, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.starts_with("time,level,throughput,latency,accuracy,key_share\n"));
+        assert!(csv.contains("\n1.0,1,0.0,,,\n"), "{}", csv);
+    }
+}
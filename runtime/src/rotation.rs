@@ -0,0 +1,220 @@
+//! Rotation of logs on wall-clock boundaries.
+//!
+//! A rotated log `frames.csv` is written as one file per epoch (e.g.,
+//! `frames.20261015T1200Z.0007.csv` for the minute starting at 12:00 UTC,
+//! the log's eighth file). The epoch in progress is written to a `.part`
+//! file and renamed once the epoch is over, so collectors only pick up
+//! complete files.
+
+use chrono::{TimeZone, Utc};
+use csv;
+use errors::*;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+/// How often a log is rotated.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RotationPeriod {
+    /// A file per minute.
+    Minute,
+
+    /// A file per hour.
+    Hour,
+}
+
+impl RotationPeriod {
+    /// The length of the period (ms).
+    pub fn ms(self) -> i64 {
+        match self {
+            RotationPeriod::Minute => 60 * 1000,
+            RotationPeriod::Hour => 60 * 60 * 1000,
+        }
+    }
+
+    /// The start (ms since unix epoch) of the period `t_ms` falls in.
+    pub fn start_ms(self, t_ms: i64) -> i64 {
+        t_ms - t_ms.rem_euclid(self.ms())
+    }
+}
+
+/// The complete file `seq` of a log, holding the epoch starting at
+/// `start_ms`, named after `base`.
+pub fn epoch_path<P: AsRef<Path>>(base: P, start_ms: i64, seq: u64) -> PathBuf {
+    let base = base.as_ref();
+    let stamp = match Utc.timestamp_millis_opt(start_ms).single() {
+        Some(t) => t.format("%Y%m%dT%H%MZ").to_string(),
+        None => start_ms.to_string(),
+    };
+    let stem = base.file_stem().map_or("log".into(), |s| s.to_string_lossy());
+    let name = match base.extension() {
+        Some(ext) => format!("{}.{}.{:04}.{}", stem, stamp, seq, ext.to_string_lossy()),
+        None => format!("{}.{}.{:04}", stem, stamp, seq),
+    };
+    base.with_file_name(name)
+}
+
+/// The file an epoch is written to while in progress.
+pub fn part_path<P: AsRef<Path>>(base: P, start_ms: i64, seq: u64) -> PathBuf {
+    let mut path = epoch_path(base, start_ms, seq).into_os_string();
+    path.push(".part");
+    PathBuf::from(path)
+}
+
+/// Marks file `seq` of the epoch starting at `start_ms` complete, by
+/// renaming its part file (an atomic rename within the directory).
+pub fn complete<P: AsRef<Path>>(base: P, start_ms: i64, seq: u64) -> Result<()> {
+    let base = base.as_ref();
+    fs::rename(part_path(base, start_ms, seq), epoch_path(base, start_ms, seq))?;
+    Ok(())
+}
+
+/// A CSV log written as a file per epoch.
+///
+/// Files are numbered in the order they are opened, skipping numbers taken
+/// by files already on disk (e.g., of an earlier run), so that no file is
+/// ever overwritten. The log never rolls back: rows of an earlier epoch, as
+/// after the clock was set back, go to the file in progress.
+pub struct RotatingCsv {
+    base: PathBuf,
+    period: RotationPeriod,
+    seq: u64,
+    current: Option<(i64, u64, csv::Writer<File>)>,
+}
+
+impl RotatingCsv {
+    /// Creates a log split into a file per `period` next to `base`.
+    pub fn new<P: AsRef<Path>>(base: P, period: RotationPeriod) -> RotatingCsv {
+        RotatingCsv {
+            base: base.as_ref().to_path_buf(),
+            period,
+            seq: 0,
+            current: None,
+        }
+    }
+
+    /// The writer of the epoch of `now_ms`, completing the file of an
+    /// earlier epoch.
+    pub fn writer(&mut self, now_ms: i64) -> Result<&mut csv::Writer<File>> {
+        self.roll(now_ms)?;
+        if self.current.is_none() {
+            let start = self.period.start_ms(now_ms);
+            while epoch_path(&self.base, start, self.seq).exists() ||
+                part_path(&self.base, start, self.seq).exists()
+            {
+                self.seq += 1;
+            }
+            let file = File::create(part_path(&self.base, start, self.seq))?;
+            self.current = Some((start, self.seq, csv::Writer::from_writer(file)));
+            self.seq += 1;
+        }
+        match self.current {
+            Some((_, _, ref mut w)) => Ok(w),
+            None => unreachable!(),
+        }
+    }
+
+    /// Completes the file in progress if its epoch is over at `now_ms`.
+    pub fn roll(&mut self, now_ms: i64) -> Result<()> {
+        match self.current {
+            Some((start, _, _)) if self.period.start_ms(now_ms) > start => self.close(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Flushes the file in progress.
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some((_, _, ref mut w)) = self.current {
+            w.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Completes the file in progress, if any.
+    pub fn close(&mut self) -> Result<()> {
+        if let Some((start, seq, mut w)) = self.current.take() {
+            w.flush()?;
+            complete(&self.base, start, seq)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RotatingCsv {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            warn!("failed to complete {}: {}", self.base.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_epoch_paths() {
+        // 2017-07-14T02:40:00Z
+        let t = 1_500_000_000_000 + 1234;
+        let minute = RotationPeriod::Minute.start_ms(t);
+        assert_eq!(minute, 1_500_000_000_000);
+        assert_eq!(RotationPeriod::Hour.start_ms(t), 1_500_000_000_000 - 40 * 60 * 1000);
+        assert_eq!(
+            epoch_path("/tmp/frames.csv", minute, 7),
+            PathBuf::from("/tmp/frames.20170714T0240Z.0007.csv")
+        );
+        assert_eq!(
+            part_path("frames", minute, 0),
+            PathBuf::from("frames.20170714T0240Z.0000.part")
+        );
+    }
+
+    #[test]
+    #[cfg(any(feature = "server", feature = "tools"))]
+    fn test_rotating_log() {
+        use experiment_log::{read_entries, ExperimentLog, FrameEntry};
+
+        let dir = ::std::env::temp_dir()
+            .join(format!("awstream-rotation-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let base = dir.join("frames.csv");
+        let entry = |time_ms| FrameEntry {
+            time_ms,
+            client: "a".into(),
+            level: 0,
+            frame_num: 0,
+            bytes: 100,
+            latency_ms: 10.0,
+            ground_truth: None,
+            confidence: None,
+            cwnd: None,
+            srtt_ms: None,
+            retransmits: None,
+            key: None,
+        };
+        let (first, second) = (1_500_000_000_000, 1_500_000_060_000);
+
+        let log = ExperimentLog::rotating(&base, RotationPeriod::Minute).unwrap();
+        log.record(&entry(first)).unwrap();
+        log.record(&entry(first + 59_999)).unwrap();
+        assert!(part_path(&base, first, 0).exists());
+        log.record(&entry(second)).unwrap();
+        // the first minute is complete, the second still in progress
+        assert_eq!(read_entries(epoch_path(&base, first, 0)).unwrap().len(), 2);
+        assert!(!part_path(&base, first, 0).exists());
+        assert!(part_path(&base, second, 1).exists());
+        // a frame stamped before the clock was set back stays in it
+        log.record(&entry(first + 10)).unwrap();
+        // and the minute is over once the clock says so
+        log.flush(second + 60_000).unwrap();
+        assert_eq!(read_entries(epoch_path(&base, second, 1)).unwrap().len(), 2);
+
+        // another run does not overwrite the files of the first
+        let log = ExperimentLog::rotating(&base, RotationPeriod::Minute).unwrap();
+        log.record(&entry(first)).unwrap();
+        drop(log);
+        assert_eq!(read_entries(epoch_path(&base, first, 0)).unwrap().len(), 2);
+        assert_eq!(read_entries(epoch_path(&base, first, 1)).unwrap().len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,230 @@
+//! The column layout of profile files, checked against the config type.
+//!
+//! Profile files are headerless: a row is deserialized into
+//! `(bandwidth, config fields..., accuracy)` by position, so a file written
+//! for another layout (a column added, two swapped) may still parse, into the
+//! wrong fields. `profile_schema!` declares the fields of a config type in
+//! the order they appear in its rows,
+//!
+//! ```text
+//! profile_schema!(VideoConfig { width: usize, skip: usize, quant: usize });
+//! ```
+//!
+//! after which `layout` tells the expected columns and `validate` (or
+//! `Profile::load_checked`) checks a file against them before loading,
+//! failing with the line and column that don't fit, e.g., "line 3, column 4
+//! expected usize for `quant`, found \"0.5\"". Rows are read with the
+//! config's `Deserialize`, which takes fields in the order the struct
+//! declares them, so `validate` also fails if the declaration lists them in
+//! another order.
+
+use csv;
+use errors::*;
+use serde::de::{self, DeserializeOwned, Visitor};
+use std::cell::Cell;
+use std::fmt;
+use std::str::FromStr;
+
+/// Declares the columns of a config type in profile files, in order: the
+/// name and type of each field. The declaration fails to compile unless it
+/// names every field of the struct, with its type; `validate` rejects it
+/// unless it names them in the order of the struct.
+#[macro_export]
+macro_rules! profile_schema {
+    ($config:ident { $($field:ident : $ty:ty),* $(,)* }) => {
+        impl $crate::schema::ProfileSchema for $config {
+            fn columns() -> Vec<$crate::schema::Column> {
+                #[allow(dead_code)]
+                fn declares_every_field(config: &$config) {
+                    let $config { $(ref $field),* } = *config;
+                    $(let _: &$ty = $field;)*
+                }
+                vec![$($crate::schema::Column::of::<$ty>(stringify!($field), stringify!($ty))),*]
+            }
+        }
+    };
+}
+
+/// A column of profile files.
+#[derive(Clone, Copy)]
+pub struct Column {
+    /// The field the column is read into.
+    pub name: &'static str,
+
+    /// The type of the field.
+    pub ty: &'static str,
+
+    parses: fn(&str) -> bool,
+}
+
+impl Column {
+    /// A column `name` holding values of `T`, spelled `ty`.
+    pub fn of<T: FromStr>(name: &'static str, ty: &'static str) -> Column {
+        Column {
+            name,
+            ty,
+            parses: |field| field.trim().parse::<T>().is_ok(),
+        }
+    }
+}
+
+impl fmt::Debug for Column {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.name, self.ty)
+    }
+}
+
+/// A config type whose columns in profile files are known, declared with
+/// `profile_schema!`.
+pub trait ProfileSchema {
+    /// The columns of the config, in order.
+    fn columns() -> Vec<Column>;
+}
+
+/// The columns of a profile row of `C`: bandwidth (kbps), the config's, and
+/// accuracy.
+pub fn layout<C: ProfileSchema>() -> Vec<Column> {
+    let mut columns = vec![Column::of::<f64>("bandwidth", "f64")];
+    columns.extend(C::columns());
+    columns.push(Column::of::<f64>("accuracy", "f64"));
+    columns
+}
+
+/// Records the struct a type deserializes from, failing right after.
+struct StructFields<'a>(&'a Cell<Option<(&'static str, &'static [&'static str])>>);
+
+impl<'a, 'de> de::Deserializer<'de> for StructFields<'a> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> ::std::result::Result<V::Value, Self::Error> {
+        Err(de::Error::custom("not a struct"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        _: V,
+    ) -> ::std::result::Result<V::Value, Self::Error> {
+        self.0.set(Some((name, fields)));
+        Err(de::Error::custom("fields recorded"))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes byte_buf option unit
+        unit_struct newtype_struct seq tuple tuple_struct map enum identifier ignored_any
+    }
+}
+
+/// Checks that `profile_schema!` declares the fields of `C` in the order its
+/// `Deserialize` reads them from a row.
+fn check_order<C: ProfileSchema + DeserializeOwned>() -> Result<()> {
+    let recorded = Cell::new(None);
+    let _ = C::deserialize(StructFields(&recorded));
+    let (name, fields) = match recorded.get() {
+        Some(found) => found,
+        None => return Ok(()),
+    };
+    let declared = C::columns().iter().map(|c| c.name).collect::<Vec<_>>();
+    if declared != fields {
+        bail!(ErrorKind::ProfileSchema(format!(
+            "`profile_schema!` declares the fields of {} as {}, rows hold {}",
+            name,
+            declared.join(", "),
+            fields.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// Checks every row of the profile `csv` against the layout of `C`.
+pub fn validate<C: ProfileSchema + DeserializeOwned>(csv: &str) -> Result<()> {
+    check_order::<C>()?;
+    let columns = layout::<C>();
+    let names = || columns.iter().map(|c| c.name).collect::<Vec<_>>().join(", ");
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(csv.as_bytes());
+    for record in rdr.records() {
+        let record = record?;
+        let line = record.position().map_or(0, |p| p.line());
+        if record.len() != columns.len() {
+            bail!(ErrorKind::ProfileSchema(format!(
+                "line {} has {} columns, expected {} ({})",
+                line,
+                record.len(),
+                columns.len(),
+                names()
+            )));
+        }
+        for (j, (field, column)) in record.iter().zip(&columns).enumerate() {
+            if !(column.parses)(field) {
+                bail!(ErrorKind::ProfileSchema(format!(
+                    "line {}, column {} expected {} for `{}`, found {:?}",
+                    line,
+                    j + 1,
+                    column.ty,
+                    column.name,
+                    field.trim()
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "runtime"))]
+mod tests {
+    use super::*;
+    use profile::Profile;
+    use video::VideoConfig;
+
+    #[test]
+    fn test_rejects_misaligned_columns() {
+        let names = layout::<VideoConfig>().iter().map(|c| c.name).collect::<Vec<_>>();
+        assert_eq!(names, vec!["bandwidth", "width", "skip", "quant", "accuracy"]);
+
+        let csv = "100.0,640,0,20,0.5\n200.0,1280,0,20,0.9\n";
+        assert!(validate::<VideoConfig>(csv).is_ok());
+        assert_eq!(Profile::<VideoConfig>::from_csv_checked(csv).unwrap().iter().count(), 2);
+        // quoted as any CSV writer may
+        assert!(validate::<VideoConfig>("\"100.0\",640,0,20,\"0.5\"\n").is_ok());
+
+        // accuracy and quant swapped
+        let swapped = "100.0,640,0,20,0.5\n200.0,1280,0,0.9,20\n";
+        match validate::<VideoConfig>(swapped) {
+            Err(Error(ErrorKind::ProfileSchema(reason), _)) => {
+                assert_eq!(reason, "line 2, column 4 expected usize for `quant`, found \"0.9\"")
+            }
+            other => panic!("unexpected {:?}", other.map_err(|e| e.to_string())),
+        }
+        // a column too many
+        let extra = "100.0,640,480,0,20,0.5\n";
+        match Profile::<VideoConfig>::from_csv_checked(extra) {
+            Err(Error(ErrorKind::ProfileSchema(reason), _)) => assert!(reason.starts_with("line 1 has 6 columns")),
+            _ => panic!("loaded a misaligned profile"),
+        }
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Swapped {
+        skip: usize,
+        width: usize,
+    }
+
+    profile_schema!(Swapped { width: usize, skip: usize });
+
+    #[test]
+    fn test_rejects_declaration_out_of_order() {
+        match validate::<Swapped>("100.0,640,0,0.5\n") {
+            Err(Error(ErrorKind::ProfileSchema(reason), _)) => assert_eq!(
+                reason,
+                "`profile_schema!` declares the fields of Swapped as width, skip, rows hold skip, width"
+            ),
+            other => panic!("unexpected {:?}", other.map_err(|e| e.to_string())),
+        }
+        assert!(check_order::<VideoConfig>().is_ok());
+    }
+}
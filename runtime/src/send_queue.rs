@@ -0,0 +1,365 @@
+//! The client's queue of datums on their way to the socket.
+//!
+//! Frames enter the `SendQueue` as the source produces them and leave as the
+//! socket takes them. When the queue exceeds its capacity, or the memory
+//! budget refuses a frame, its `DropPolicy` chooses the frames that make
+//! room. Datums other than frames (barriers, control datums) are never
+//! dropped; the datums of sub-streams count as frames. Datums leave in order,
+//! or as a `WeightedRoundRobin` picks them among sub-streams.
+
+use super::AsDatum;
+use super::AsDatumType;
+use super::decision::SharedClock;
+use super::drop_policy::DropPolicy;
+use super::memory::Account;
+use super::wrr::WeightedRoundRobin;
+use futures::{Async, Poll, Stream};
+use futures::task::AtomicTask;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Identifies a datum for as long as it is queued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FrameId(u64);
+
+/// Returns true if `datum` carries a frame, which may be dropped.
+fn is_frame(datum: &AsDatum) -> bool {
+    matches!(
+        datum.datum_type(),
+        AsDatumType::Live(..) |
+            AsDatumType::Reference(..) |
+            AsDatumType::Backfill(..) |
+            AsDatumType::Redundant(..) |
+            AsDatumType::Dummy |
+            AsDatumType::Sub(..)
+    )
+}
+
+/// Datums waiting for the socket, oldest first, with since when (ms) they
+/// are queued.
+#[derive(Debug)]
+pub struct SendQueue {
+    entries: VecDeque<(FrameId, AsDatum, u64)>,
+    next_id: u64,
+    bytes: usize,
+    capacity: Option<usize>,
+}
+
+impl SendQueue {
+    /// Creates a queue holding up to `capacity` bytes of frames (unbounded
+    /// without).
+    pub fn new(capacity: Option<usize>) -> SendQueue {
+        SendQueue {
+            entries: VecDeque::new(),
+            next_id: 0,
+            bytes: 0,
+            capacity,
+        }
+    }
+
+    /// The capacity in bytes, if bounded.
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    /// Bytes queued.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// The number of datums queued.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if nothing is queued.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The frames queued, oldest first: the candidates for dropping.
+    pub fn frames(&self) -> impl DoubleEndedIterator<Item = (FrameId, &AsDatum)> {
+        self.entries.iter().filter(|&(_, d, _)| is_frame(d)).map(|(id, d, _)| (*id, d))
+    }
+
+    /// The frames queued ahead of the first control datum, oldest first,
+    /// with since when (ms) they are queued: those that may go before others.
+    pub fn ahead_of_control(&self) -> impl Iterator<Item = (FrameId, &AsDatum, u64)> {
+        self.entries
+            .iter()
+            .take_while(|&(_, d, _)| is_frame(d))
+            .map(|(id, d, since)| (*id, d, *since))
+    }
+
+    /// The id of the oldest datum.
+    pub fn front(&self) -> Option<FrameId> {
+        self.entries.front().map(|&(id, _, _)| id)
+    }
+
+    /// Queues `datum` at `now_ms` and returns its id.
+    pub fn push(&mut self, datum: AsDatum, now_ms: u64) -> FrameId {
+        let id = FrameId(self.next_id);
+        self.next_id += 1;
+        self.bytes += datum.net_len();
+        self.entries.push_back((id, datum, now_ms));
+        id
+    }
+
+    /// Takes the oldest datum.
+    pub fn pop(&mut self) -> Option<AsDatum> {
+        self.pop_timed().map(|(datum, _)| datum)
+    }
+
+    /// Takes the datum of `id`, if still queued.
+    pub fn take(&mut self, id: FrameId) -> Option<AsDatum> {
+        self.take_timed(id).map(|(datum, _)| datum)
+    }
+
+    /// Takes the oldest datum, with since when (ms) it was queued.
+    fn pop_timed(&mut self) -> Option<(AsDatum, u64)> {
+        let (_, datum, since) = self.entries.pop_front()?;
+        self.bytes -= datum.net_len();
+        Some((datum, since))
+    }
+
+    /// Takes the datum of `id`, if still queued, with since when (ms) it was
+    /// queued.
+    fn take_timed(&mut self, id: FrameId) -> Option<(AsDatum, u64)> {
+        let i = self.entries.iter().position(|&(i, _, _)| i == id)?;
+        let (_, datum, since) = self.entries.remove(i)?;
+        self.bytes -= datum.net_len();
+        Some((datum, since))
+    }
+
+    /// Removes the frame of `id`, if still queued.
+    pub fn remove(&mut self, id: FrameId) -> Option<AsDatum> {
+        match self.entries.iter().find(|&&(i, _, _)| i == id) {
+            Some((_, datum, _)) if is_frame(datum) => self.take(id),
+            _ => None,
+        }
+    }
+
+    /// Checks that `bytes` accounts for exactly the datums queued.
+    fn check_accounting(&self) {
+        strict_assert!(
+            self.bytes == self.entries.iter().map(|(_, d, _)| d.net_len()).sum::<usize>(),
+            "send queue counts {} bytes for {} datums",
+            self.bytes,
+            self.entries.len()
+        );
+    }
+
+    /// Bytes of frames beyond the capacity.
+    fn excess(&self) -> usize {
+        self.capacity.map_or(0, |c| self.bytes.saturating_sub(c))
+    }
+}
+
+struct Shared {
+    queue: Mutex<SendQueue>,
+    policy: Arc<Mutex<Box<dyn DropPolicy>>>,
+    account: Option<Account>,
+    clock: SharedClock,
+    task: AtomicTask,
+    sender_done: AtomicBool,
+    receiver_done: AtomicBool,
+}
+
+/// Creates a `SendQueue` of `capacity` bytes whose frames draw from
+/// `account`, making room according to `policy`; datums are timed on
+/// `clock`.
+pub fn channel(
+    capacity: Option<usize>,
+    account: Option<Account>,
+    policy: Arc<Mutex<Box<dyn DropPolicy>>>,
+    clock: SharedClock,
+) -> (QueueSender, QueueReceiver) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(SendQueue::new(capacity)),
+        policy,
+        account,
+        clock,
+        task: AtomicTask::new(),
+        sender_done: AtomicBool::new(false),
+        receiver_done: AtomicBool::new(false),
+    });
+    let receiver = QueueReceiver {
+        shared: shared.clone(),
+        multiplex: None,
+    };
+    (QueueSender { shared }, receiver)
+}
+
+/// The producing end of a `SendQueue`.
+pub struct QueueSender {
+    shared: Arc<Shared>,
+}
+
+impl QueueSender {
+    /// Queues `datum` and returns the frames dropped to make room for it
+    /// (possibly `datum` itself). Once the receiver is gone, gives `datum`
+    /// back.
+    #[allow(clippy::result_large_err)]
+    pub fn send(&self, datum: AsDatum) -> ::std::result::Result<Vec<AsDatum>, AsDatum> {
+        if self.shared.receiver_done.load(Ordering::SeqCst) {
+            return Err(datum);
+        }
+        let mut queue = self.shared.queue.lock().expect("send queue poisoned");
+        let (len, frame) = (datum.net_len(), is_frame(&datum));
+        let id = queue.push(datum, self.shared.clock.now_ms());
+        let mut needed = queue.excess();
+        if let Some(ref account) = self.shared.account {
+            if frame && !account.try_reserve(len) {
+                // freed below
+                account.charge(len);
+                needed = needed.max(len);
+            } else if !frame {
+                account.charge(len);
+            }
+        }
+        let mut dropped = Vec::new();
+        if needed > 0 {
+            let victims = self.shared.policy.lock().expect("drop policy poisoned").select_victims(&queue, needed);
+            dropped.extend(victims.into_iter().filter_map(|v| queue.remove(v)));
+            let freed = dropped.iter().map(AsDatum::net_len).sum::<usize>();
+            if freed < needed {
+                // the policy left the queue over its bounds: the new frame
+                // can't stay
+                dropped.extend(queue.remove(id));
+            }
+        }
+        if let Some(ref account) = self.shared.account {
+            account.release(dropped.iter().map(AsDatum::net_len).sum());
+        }
+        queue.check_accounting();
+        drop(queue);
+        self.shared.task.notify();
+        Ok(dropped)
+    }
+}
+
+impl Drop for QueueSender {
+    fn drop(&mut self) {
+        self.shared.sender_done.store(true, Ordering::SeqCst);
+        self.shared.task.notify();
+    }
+}
+
+/// The consuming end of a `SendQueue`: a stream of its datums in order,
+/// ending once the sender is gone and the queue is empty.
+pub struct QueueReceiver {
+    shared: Arc<Shared>,
+    multiplex: Option<WeightedRoundRobin>,
+}
+
+impl QueueReceiver {
+    /// Takes datums as `wrr` picks them rather than in order.
+    pub fn multiplex(&mut self, wrr: WeightedRoundRobin) {
+        self.multiplex = Some(wrr);
+    }
+
+    /// The clock datums are timed on.
+    pub fn clock(&self) -> SharedClock {
+        self.shared.clock.clone()
+    }
+
+    /// Like `poll`, but yields each datum with since when (ms) it was
+    /// queued.
+    pub fn poll_timed(&mut self) -> Async<Option<(AsDatum, u64)>> {
+        if let Some(timed) = self.pop() {
+            return Async::Ready(Some(timed));
+        }
+        self.shared.task.register();
+        // the sender may have queued or left in between
+        if let Some(timed) = self.pop() {
+            return Async::Ready(Some(timed));
+        }
+        if self.shared.sender_done.load(Ordering::SeqCst) {
+            return Async::Ready(None);
+        }
+        Async::NotReady
+    }
+
+    fn pop(&mut self) -> Option<(AsDatum, u64)> {
+        let mut queue = self.shared.queue.lock().expect("send queue poisoned");
+        let (datum, since) = match self.multiplex {
+            Some(ref mut wrr) => {
+                let id = wrr.pick(&queue, self.shared.clock.now_ms())?;
+                queue.take_timed(id)?
+            }
+            None => queue.pop_timed()?,
+        };
+        drop(queue);
+        if let Some(ref account) = self.shared.account {
+            account.release(datum.net_len());
+        }
+        Some((datum, since))
+    }
+}
+
+impl Stream for QueueReceiver {
+    type Item = AsDatum;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<AsDatum>, ()> {
+        Ok(self.poll_timed().map(|timed| timed.map(|(datum, _)| datum)))
+    }
+}
+
+impl Drop for QueueReceiver {
+    fn drop(&mut self) {
+        self.shared.receiver_done.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use decision::ManualClock;
+    use drop_policy::DropPolicyKind;
+    use memory::{Component, MemoryBudget};
+
+    fn sent(policy: DropPolicyKind) -> (Vec<AsDatumType>, Vec<AsDatumType>) {
+        let budget = MemoryBudget::new(1 << 20);
+        let policy = Arc::new(Mutex::new(policy.into_policy()));
+        let frames = vec![
+            AsDatum::new(0, 0, vec![0; 3000]),
+            AsDatum::new(0, 1, vec![0; 1000]),
+            AsDatum::barrier(1),
+            AsDatum::new(1, 2, vec![0; 1000]).into_backfill(),
+            AsDatum::new(1, 3, vec![0; 1000]),
+        ];
+        // one byte short of all of them
+        let capacity = frames.iter().map(AsDatum::net_len).sum::<usize>() - 1;
+        let account = Some(budget.account(Component::SendQueue));
+        let (tx, rx) = channel(Some(capacity), account, policy, Arc::new(ManualClock::new(0)));
+        let mut dropped = Vec::new();
+        for frame in frames {
+            dropped.extend(tx.send(frame).unwrap().iter().map(AsDatum::datum_type));
+        }
+        drop(tx);
+        assert!(budget.used() > 0);
+        let kept = rx.map(|d| d.datum_type()).wait().collect::<::std::result::Result<Vec<_>, _>>();
+        assert_eq!(budget.used(), 0);
+        (dropped, kept.unwrap())
+    }
+
+    #[test]
+    fn test_drop_policies() {
+        use AsDatumType::*;
+        let (dropped, kept) = sent(DropPolicyKind::TailDrop);
+        assert_eq!(dropped, vec![Live(1, 3)]);
+        assert_eq!(kept, vec![Live(0, 0), Live(0, 1), Barrier(1), Backfill(1, 2)]);
+
+        let (dropped, kept) = sent(DropPolicyKind::OldestFirst);
+        assert_eq!(dropped, vec![Live(0, 0)]);
+        assert_eq!(kept, vec![Live(0, 1), Barrier(1), Backfill(1, 2), Live(1, 3)]);
+
+        let (dropped, _) = sent(DropPolicyKind::LowestPriorityFirst);
+        assert_eq!(dropped, vec![Backfill(1, 2)]);
+
+        let (dropped, _) = sent(DropPolicyKind::KeyframePreserving);
+        assert_eq!(dropped, vec![Live(0, 1)]);
+    }
+}
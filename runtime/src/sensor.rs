@@ -0,0 +1,277 @@
+//! A source of sensor telemetry, adapting by aggregation.
+//!
+//! The levels of a sensor profile are aggregation windows: the lowest level
+//! may send a summary per minute, the highest every sample as it comes (a
+//! window of one sample period). The application pushes samples through a
+//! `SensorSender` from any thread; `SensorBatchSource` folds them into a
+//! `Summary` per window of the current level and sends each summary as a
+//! frame once its window is over.
+
+use super::{Adapt, AsDatum, AsDatumType, Bandwidth, Capabilities, CapabilityCheck};
+use super::config::{ConfigDelta, Configurable};
+use super::profile::{Profile, SimpleProfile};
+use super::source::Source;
+use bincode;
+use errors::*;
+use futures::{Async, Poll, Stream};
+use futures::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded};
+use std::fmt;
+
+/// A level of a sensor profile.
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
+pub struct SensorConfig {
+    /// Length of the aggregation window (ms). Windows no longer than the
+    /// sample period send raw samples.
+    pub window_ms: u64,
+}
+
+profile_schema!(SensorConfig { window_ms: u64 });
+
+impl fmt::Display for SensorConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}ms", self.window_ms)
+    }
+}
+
+impl Configurable for SensorConfig {
+    fn validate(&self) -> Result<()> {
+        if self.window_ms == 0 {
+            bail!(ErrorKind::InvalidConfig("window_ms must be positive".into()));
+        }
+        Ok(())
+    }
+
+    fn apply_delta(&self, prev: &Self) -> ConfigDelta {
+        let mut delta = ConfigDelta::new();
+        delta.push("window_ms", prev.window_ms, self.window_ms);
+        delta
+    }
+}
+
+/// A reading of the sensor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    /// When the sample was taken (ms since unix epoch).
+    pub time_ms: i64,
+
+    /// The reading.
+    pub value: f64,
+}
+
+/// The samples of one window, as sent in a frame.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    /// Start of the window (ms since unix epoch, aligned to the window).
+    pub start_ms: i64,
+
+    /// Length of the window (ms).
+    pub window_ms: u64,
+
+    /// Samples in the window.
+    pub count: usize,
+
+    /// Smallest reading.
+    pub min: f64,
+
+    /// Largest reading.
+    pub max: f64,
+
+    /// Average reading.
+    pub mean: f64,
+}
+
+impl Summary {
+    fn new(start_ms: i64, window_ms: u64, sample: Sample) -> Summary {
+        Summary {
+            start_ms,
+            window_ms,
+            count: 1,
+            min: sample.value,
+            max: sample.value,
+            mean: sample.value,
+        }
+    }
+
+    fn add(&mut self, sample: Sample) {
+        self.count += 1;
+        self.min = self.min.min(sample.value);
+        self.max = self.max.max(sample.value);
+        self.mean += (sample.value - self.mean) / self.count as f64;
+    }
+
+    /// Decodes the summary carried by a frame of a `SensorBatchSource`.
+    pub fn from_datum(datum: &AsDatum) -> Result<Summary> {
+        match datum.datum_type() {
+            AsDatumType::Live(..) | AsDatumType::Backfill(..) => Ok(bincode::deserialize(&datum.mem)?),
+            _ => bail!(ErrorKind::DecodeError),
+        }
+    }
+}
+
+/// Pushes samples into a `SensorBatchSource`. Clones feed the same source;
+/// the source ends once every sender is dropped.
+#[derive(Debug, Clone)]
+pub struct SensorSender {
+    tx: UnboundedSender<Sample>,
+}
+
+impl SensorSender {
+    /// Pushes a reading taken at `time_ms`. Fails if the source is gone.
+    pub fn push(&self, time_ms: i64, value: f64) -> Result<()> {
+        self.tx
+            .unbounded_send(Sample { time_ms, value })
+            .map_err(|_| ErrorKind::SourceData.into())
+    }
+}
+
+/// `SensorBatchSource` aggregates samples over the window of the current
+/// level. A level change closes the window in progress at the next sample.
+pub struct SensorBatchSource {
+    profile: Profile<SensorConfig>,
+    samples: UnboundedReceiver<Sample>,
+    /// The window in progress and the level it is aggregated at.
+    pending: Option<(usize, Summary)>,
+    batches: usize,
+}
+
+impl SensorBatchSource {
+    /// Creates a source for `profile` and the sender feeding it.
+    pub fn new(profile: Profile<SensorConfig>) -> (SensorBatchSource, SensorSender) {
+        let (tx, rx) = unbounded();
+        let source = SensorBatchSource {
+            profile,
+            samples: rx,
+            pending: None,
+            batches: 0,
+        };
+        (source, SensorSender { tx })
+    }
+
+    /// Folds `sample` into its window, returning the window it closes.
+    fn add(&mut self, sample: Sample) -> Result<Option<AsDatum>> {
+        let window_ms = self.profile.current_config().window_ms;
+        let start_ms = sample.time_ms - sample.time_ms.rem_euclid(window_ms as i64);
+        match self.pending {
+            Some((_, ref mut s)) if s.start_ms == start_ms && s.window_ms == window_ms => {
+                s.add(sample);
+                return Ok(None);
+            }
+            _ => {}
+        }
+        let level = self.profile.current_level();
+        let closed = self.pending.replace((level, Summary::new(start_ms, window_ms, sample)));
+        self.emit(closed)
+    }
+
+    fn emit(&mut self, window: Option<(usize, Summary)>) -> Result<Option<AsDatum>> {
+        let (level, summary) = match window {
+            Some(w) => w,
+            None => return Ok(None),
+        };
+        let mem = bincode::serialize(&summary, bincode::Infinite)?;
+        self.batches += 1;
+        Ok(Some(AsDatum::new(level, self.batches, mem)))
+    }
+}
+
+impl Adapt for SensorBatchSource {
+    fn adapt(&mut self, bandwidth: Bandwidth) {
+        self.profile.adjust_config(bandwidth);
+    }
+
+    fn dec_degradation(&mut self) {
+        self.profile.advance_config();
+    }
+
+    fn set_level(&mut self, level: usize) {
+        self.profile.set_config(level);
+    }
+
+    fn period_in_ms(&self) -> u64 {
+        self.profile.current_config().window_ms
+    }
+
+    fn current_level(&self) -> usize {
+        self.profile.current_level()
+    }
+
+    fn simple_profile(&self) -> SimpleProfile {
+        self.profile.simplify()
+    }
+
+    fn observe_accuracy(&mut self, level: usize, quality: f64, weight: f64) {
+        self.profile.observe_accuracy(level, quality, weight);
+    }
+
+    fn restrict(&mut self, caps: &Capabilities, check: CapabilityCheck) -> Result<Vec<usize>> {
+        self.profile.restrict(caps, check)
+    }
+}
+
+impl Source for SensorBatchSource {
+    fn poll_frame(&mut self) -> Poll<Option<AsDatum>, Error> {
+        loop {
+            match self.samples.poll() {
+                Ok(Async::Ready(Some(sample))) => {
+                    if let Some(datum) = self.add(sample)? {
+                        return Ok(Async::Ready(Some(datum)));
+                    }
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                // every sender is gone: flush the last window, then end
+                Ok(Async::Ready(None)) | Err(()) => {
+                    let last = self.pending.take();
+                    return Ok(Async::Ready(self.emit(last)?));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Record;
+    use futures::{executor, stream, Future};
+
+    #[test]
+    fn test_sensor_batches() {
+        let profile = Profile::_with_vec(vec![
+            Record::new(Bandwidth::from_kbps(0.1), SensorConfig { window_ms: 60_000 }, 0.7),
+            Record::new(Bandwidth::from_kbps(10.0), SensorConfig { window_ms: 1000 }, 1.0),
+        ]);
+        let (mut source, sender) = SensorBatchSource::new(profile);
+        assert_eq!(source.period_in_ms(), 60_000);
+        for t in 0..120 {
+            sender.push(t * 1000, t as f64).unwrap();
+        }
+        let mut frames = executor::spawn(stream::poll_fn(move || source.poll_frame()));
+        let first = frames.wait_stream().unwrap().unwrap();
+        let summary = Summary::from_datum(&first).unwrap();
+        assert_eq!(first.datum_type(), AsDatumType::Live(0, 1));
+        assert_eq!((summary.start_ms, summary.count), (0, 60));
+        assert_eq!((summary.min, summary.max, summary.mean), (0.0, 59.0, 29.5));
+
+        // the second minute is flushed once the sender is gone
+        drop(sender);
+        let rest = frames.wait_stream().unwrap().unwrap();
+        assert_eq!(Summary::from_datum(&rest).unwrap().start_ms, 60_000);
+        assert!(frames.wait_stream().is_none());
+
+        // at the raw level every sample is a frame
+        let profile = Profile::_with_vec(vec![
+            Record::new(Bandwidth::from_kbps(0.1), SensorConfig { window_ms: 60_000 }, 0.7),
+            Record::new(Bandwidth::from_kbps(10.0), SensorConfig { window_ms: 1000 }, 1.0),
+        ]);
+        let (mut source, sender) = SensorBatchSource::new(profile);
+        source.set_level(1);
+        for t in 0..3 {
+            sender.push(t * 1000 + 500, 1.0).unwrap();
+        }
+        drop(sender);
+        let raw = stream::poll_fn(move || source.poll_frame()).collect().wait().unwrap();
+        let starts = raw.iter().map(|d| Summary::from_datum(d).unwrap().start_ms).collect::<Vec<_>>();
+        assert_eq!(starts, vec![0, 1000, 2000]);
+    }
+}
@@ -1,21 +1,56 @@
 //! The main entrance for server functionality.
 
-use super::{AsCodec, AsDatum, AsDatumType, ReceiverReport};
+use super::{AsCodec, AsDatum, AsDatumType, Bandwidth, CloseReason, FrameLimits, Hint, QualityReport,
+            StreamInfo, ReceiverReport, WireFormat, restarts_numbering};
+use super::adaptation::{self, Adaptation};
+use super::admin;
+use super::admission::{Admission, AdmissionControl, AdmissionRequest};
 use super::analytics::VideoAnalytics;
 use super::bw_monitor::{BwMonitor, LatencyMonitor};
+use super::catalog::{ClientIdentity, HostedProfile, ProfileCatalog};
+use super::composition::{CompositionTracker, FrameKind};
+use super::congestion::{CongestionSignal, DelayGradient};
+use super::controller::Monitor;
+use super::decision::{Clock, SharedClock, SystemClock};
+use super::delta::DeltaDecoder;
+use super::dictionary::Decompressor;
+use super::estimator::ExponentialSmooth;
+use super::consumer_lag::{ConsumerLagConfig, LagProbe, LagSignal};
+use super::evaluator::{self, AccuracyEvaluator, Sampler};
+use super::experiment_log::{ExperimentLog, FrameEntry};
+use super::grouping::{GroupRequest, StreamGroups};
+use super::integrity::{IntegrityConfig, Sealer, Verdict};
+use super::memory::{Component, MemoryBudget};
+use super::middleware::{ConnectRequest, ConnectionInfo, Layers, Middleware};
+use super::postmortem::{self, Registration};
+use super::registry::{Gossip, GossipHandle, SessionRegistry};
+use super::session::{DEDUP_WINDOW, Session, SessionStore};
+pub use super::session::SessionStats;
 use super::setting::Setting;
+use super::tolerance::{SequenceCheck, ToleranceConfig, Tolerant};
+use super::tradeoff::TradeoffReport;
+use super::socket::{self, FramedRead, ReadLoad, Socket, SocketHandle};
+use super::source::{self, Cancellation, Source, Transition, ZeroPadding};
+use super::tcp_info::TcpInfoProbe;
 use super::utils::StreamingStat;
 use chrono;
 use chrono::{DateTime, TimeZone, Utc};
 use errors::*;
-use futures::{Future, Sink, Stream};
+use futures::{Async, Future, Poll, Sink, Stream};
+use futures::future::{self, Either};
+use futures::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded};
+use futures_cpupool::CpuPool;
 use interval;
-use std::io;
-use std::net::SocketAddr;
-use std::time::Duration;
-use tokio_core::net::{TcpListener, TcpStream};
+use std::net::{self, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio_core::net::{Incoming, TcpListener, TcpStream};
 use tokio_core::reactor::{Core, Handle};
 use tokio_io::AsyncRead;
+#[allow(deprecated)]
+use tokio_io::codec::FramedWrite;
 use tokio_timer;
 
 fn time_diff_in_ms<Tz: TimeZone>(a: DateTime<Tz>, b: DateTime<Tz>) -> f64 {
@@ -30,76 +65,1290 @@ fn time_diff_in_ms<Tz: TimeZone>(a: DateTime<Tz>, b: DateTime<Tz>) -> f64 {
 pub fn server(setting: Setting) {
     let mut core = Core::new().unwrap();
     let handle = core.handle();
-    let addr = ([0, 0, 0, 0], setting.port).into();
-    let listener = TcpListener::bind(&addr, &handle).unwrap();
-
-    // Accept all incoming sockets
-    let server = listener.incoming().for_each(move |(socket, addr)| {
-        let analytics = VideoAnalytics::new(&setting.profile_path, &setting.stat_path);
-        handle_conn(socket, addr, analytics, &handle)
+    let server = Server::bind(setting, &handle).expect("failed to start server");
+    let events = server.incoming_events().for_each(|event| {
+        match event {
+            ServerEvent::Error { addr, error } => error!("client {:?}: {}", addr, error),
+            e => debug!("{:?}", e),
+        }
+        Ok(())
     });
+    core.run(events).unwrap();
+}
+
+/// Datums the server buffers for a connection, across feedback and the
+/// downlink.
+const SEND_CAPACITY: usize = 64;
+
+/// How far apart (ms) the frames of a bundle of a stream group may be
+/// captured, by default: a frame interval at 30 fps.
+const DEFAULT_SYNC_TOLERANCE_MS: u64 = 33;
+
+/// Creates the source a session streams back to its client, if any; called
+/// with the session token on the thread serving the connection.
+pub type DownlinkFactory = Arc<dyn Fn(u64) -> Option<Box<dyn Source>> + Send + Sync>;
+
+/// Events of a running server.
+#[derive(Debug)]
+pub enum ServerEvent {
+    /// A client connected and opened (or resumed) a session.
+    Connected {
+        /// The client.
+        addr: SocketAddr,
+
+        /// The session token.
+        session: u64,
+
+        /// True if the client resumed an existing session.
+        resumed: bool,
+
+        /// The counters of the session, updated as frames arrive.
+        stats: SessionStats,
+    },
+
+    /// A frame was received.
+    Frame {
+        /// The client.
+        addr: SocketAddr,
+
+        /// The session token.
+        session: u64,
+
+        /// Sender-to-receiver latency (ms).
+        latency_ms: f64,
+
+        /// The frame (`AsDatumType::Live`; `Reference` if its payload is in
+        /// a blob store, `Backfill` after an outage, or `Redundant` when
+        /// carried as probe padding), or a datum of one of the client's
+        /// sub-streams (`Sub`). Each frame of a session is reported once:
+        /// later copies are dropped.
+        datum: AsDatum,
+    },
+
+    /// A frame failed to decode and was skipped.
+    DecodeError {
+        /// The client.
+        addr: SocketAddr,
+
+        /// The session token.
+        session: u64,
+
+        /// Why the frame failed to decode.
+        reason: String,
+
+        /// Errors of the connection within the tolerance window, including
+        /// this one. The connection is dropped past the limit.
+        recent: usize,
+    },
+
+    /// A live frame was not numbered above the last one of its session,
+    /// which may be a replay or a client bug.
+    SequenceRegression {
+        /// The client.
+        addr: SocketAddr,
+
+        /// The session token.
+        session: u64,
+
+        /// The last frame number of the session.
+        last: usize,
+
+        /// The number of the frame received.
+        frame_num: usize,
+
+        /// Whether the frame was dropped (`SequenceCheck::Reject`).
+        rejected: bool,
+    },
+
+    /// A sealed frame no longer matched its digest: it was altered (or
+    /// corrupted) after the client sent it.
+    DigestMismatch {
+        /// The client.
+        addr: SocketAddr,
+
+        /// The session token.
+        session: u64,
+
+        /// The frame.
+        datum: AsDatum,
+    },
+
+    /// The client switched levels: every frame after this one is of
+    /// `level`, so decoders can reset before it.
+    Barrier {
+        /// The client.
+        addr: SocketAddr,
+
+        /// The session token.
+        session: u64,
+
+        /// The level of the frames that follow.
+        level: usize,
+    },
+
+    /// The client announced what the stream is (codec, resolution, ...), at
+    /// its start or because it changed. The last one is kept in the
+    /// session's `SessionStats::stream_info`.
+    StreamInfo {
+        /// The client.
+        addr: SocketAddr,
+
+        /// The session token.
+        session: u64,
+
+        /// What the stream is.
+        info: StreamInfo,
+    },
+
+    /// A session was turned down for lack of ingest capacity.
+    AdmissionRejected {
+        /// The client.
+        addr: SocketAddr,
+
+        /// The session token.
+        session: u64,
+
+        /// What the client asked for.
+        request: AdmissionRequest,
+    },
+
+    /// A connection ended; its session can be resumed for a while.
+    Disconnected {
+        /// The client.
+        addr: SocketAddr,
+
+        /// The session token.
+        session: u64,
+
+        /// Time, latency and loss at each level of the session so far.
+        tradeoff: TradeoffReport,
+
+        /// Why the connection was closed, if either end closed it on
+        /// purpose.
+        reason: Option<CloseReason>,
+    },
+
+    /// A congestion report was sent to the client.
+    FeedbackSent {
+        /// The client.
+        addr: SocketAddr,
+
+        /// The report.
+        report: ReceiverReport,
+    },
+
+    /// The read buffers crossed the high watermark of the memory budget;
+    /// connections read no further ahead than they must until it is relieved.
+    MemoryPressure {
+        /// Bytes in use.
+        used: usize,
+
+        /// The limit of the budget.
+        limit: usize,
+    },
+
+    /// An error occurred, on a connection if `addr` is set.
+    Error {
+        /// The client, if the error is specific to one.
+        addr: Option<SocketAddr>,
+
+        /// The error.
+        error: Error,
+    },
+}
+
+#[cfg(feature = "mdns")]
+type Advertisement = ::discovery::Advertisement;
+
+#[cfg(not(feature = "mdns"))]
+type Advertisement = ();
+
+/// Advertises the server on the local network if `advertise` is set. The
+/// returned handle keeps the advertisement alive.
+#[cfg(feature = "mdns")]
+fn advertise(setting: &Setting) -> Option<Advertisement> {
+    let name = setting.advertise.as_ref()?;
+    match ::discovery::advertise(name, setting.port) {
+        Ok(a) => Some(a),
+        Err(e) => {
+            error!("failed to advertise the server: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "mdns"))]
+fn advertise(setting: &Setting) -> Option<Advertisement> {
+    if setting.advertise.is_some() {
+        warn!("`advertise` is set but the `mdns` feature is disabled");
+    }
+    None
+}
+
+/// Server-wide counters, updated lock-free by all connections.
+#[derive(Clone, Default)]
+pub struct ServerStats {
+    inner: Arc<Counters>,
+}
+
+#[derive(Default)]
+struct Counters {
+    connections: AtomicUsize,
+    active: AtomicUsize,
+    frames: AtomicUsize,
+    bytes: AtomicUsize,
+    decode_errors: AtomicUsize,
+    duplicates: AtomicUsize,
+    closed: [AtomicUsize; CloseReason::ALL.len()],
+}
+
+impl ServerStats {
+    /// Connections accepted so far.
+    pub fn connections(&self) -> usize {
+        self.inner.connections.load(Ordering::Relaxed)
+    }
+
+    /// Connections currently open.
+    pub fn active(&self) -> usize {
+        self.inner.active.load(Ordering::Relaxed)
+    }
+
+    /// Frames received so far.
+    pub fn frames(&self) -> usize {
+        self.inner.frames.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of frames received so far.
+    pub fn bytes(&self) -> usize {
+        self.inner.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Frames that failed to decode so far.
+    pub fn decode_errors(&self) -> usize {
+        self.inner.decode_errors.load(Ordering::Relaxed)
+    }
+
+    /// Frames dropped because they were received before.
+    pub fn duplicates(&self) -> usize {
+        self.inner.duplicates.load(Ordering::Relaxed)
+    }
+
+    /// Connections closed (by either end) for `reason` so far.
+    pub fn closed(&self, reason: CloseReason) -> usize {
+        self.inner.closed[reason as usize].load(Ordering::Relaxed)
+    }
+
+    fn add_closed(&self, reason: CloseReason) {
+        self.inner.closed[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
 
-    // Open listener
-    core.run(server).unwrap();
+/// Sends the quality of the analytics of a session (e.g., detector
+/// confidence) back to its client, which may use it to correct the accuracy
+/// of its profile, and hints of what the analytics are interested in. Can be
+/// cloned and used from any thread.
+#[derive(Clone)]
+pub struct QualityFeedback {
+    sessions: SessionStore<VideoAnalytics>,
+}
+
+impl QualityFeedback {
+    /// Sends `report` to the client of `session`. Returns false if the
+    /// session is unknown or expired.
+    pub fn report(&self, session: u64, report: QualityReport) -> Result<bool> {
+        self.sessions.send_feedback(session, AsDatum::quality(report)?)
+    }
+
+    /// Tells the source of `session` what the analytics are interested in
+    /// (see `Source::on_hint`). Returns false if the session is unknown or
+    /// expired.
+    pub fn hint(&self, session: u64, hint: &Hint) -> Result<bool> {
+        self.sessions.send_feedback(session, AsDatum::hint(hint)?)
+    }
+}
+
+/// What connection tasks share, across worker threads.
+#[derive(Clone)]
+struct Shared {
+    sessions: SessionStore<VideoAnalytics>,
+    log: ExperimentLog,
+    events: UnboundedSender<ServerEvent>,
+    stats: ServerStats,
+    profile_path: String,
+    stat_path: String,
+    analytics: bool,
+    delay_gradient_weight: Option<f64>,
+    wire_format: WireFormat,
+    frame_limits: FrameLimits,
+    decode_tolerance: ToleranceConfig,
+    sequence_check: SequenceCheck,
+    downlink: Option<DownlinkFactory>,
+    catalog: Option<ProfileCatalog>,
+    memory: Option<MemoryBudget>,
+    groups: StreamGroups,
+    sealer: Sealer,
+    admission: Option<AdmissionControl>,
+    middleware: Layers,
+    idle_timeout: Option<Duration>,
+    evaluator: Option<(Arc<dyn AccuracyEvaluator>, CpuPool)>,
+    evaluation_sample_every: usize,
+    lag_probe: Option<Arc<dyn LagProbe>>,
+    consumer_lag: ConsumerLagConfig,
+}
+
+/// `Shared` and the reactor of the thread serving a connection.
+#[derive(Clone)]
+struct Context {
+    shared: Shared,
+    handle: Handle,
+}
+
+impl Context {
+    fn emit(&self, event: ServerEvent) {
+        // nobody listening is not an error
+        let _ = self.shared.events.unbounded_send(event);
+    }
+
+    fn accept(&self, socket: TcpStream, addr: SocketAddr) {
+        let analytics = if self.shared.analytics {
+            VideoAnalytics::new(&self.shared.profile_path, &self.shared.stat_path)
+        } else {
+            VideoAnalytics::disabled()
+        };
+        handle_conn(socket, addr, analytics, self.clone());
+    }
 }
 
-/// The main server logic that handles a particular socket.
-fn handle_conn(
-    socket: TcpStream,
+/// A server listening for clients.
+pub struct Server {
+    listener: net::TcpListener,
+    control: Option<net::TcpListener>,
+    admin: Option<net::TcpListener>,
     addr: SocketAddr,
-    analytics: VideoAnalytics,
-    handle: &Handle,
-) -> io::Result<()> {
+    workers: usize,
+    ctx: Context,
+    events: UnboundedReceiver<ServerEvent>,
+    _advertisement: Option<Advertisement>,
+    _gossip: Option<GossipHandle>,
+    _postmortem: Registration,
+}
+
+impl Server {
+    /// Listens on the port of `setting`. Connections are served once
+    /// `incoming_events` is polled: on `handle`, or on `workers` threads
+    /// with a reactor each if set.
+    pub fn bind(setting: Setting, handle: &Handle) -> Result<Server> {
+        let listener = net::TcpListener::bind(("0.0.0.0", setting.port))?;
+        let addr = listener.local_addr()?;
+        let control = match setting.control_port {
+            Some(port) => Some(net::TcpListener::bind(("0.0.0.0", port))?),
+            None => None,
+        };
+        let admin = match setting.admin_port {
+            Some(port) => Some(net::TcpListener::bind(("127.0.0.1", port))?),
+            None => None,
+        };
+        let wire_format = setting.static_wire_format.unwrap_or_default();
+        wire_format.validate()?;
+        let log = match setting.experiment_log {
+            Some(ref path) => match setting.experiment_log_rotation {
+                Some(period) => ExperimentLog::rotating(path, period)?,
+                None => ExperimentLog::create(path)?,
+            },
+            None => ExperimentLog::disabled(),
+        };
+        let flushed = log.clone();
+        let postmortem = postmortem::register(move || {
+            if let Err(e) = flushed.try_flush() {
+                warn!("failed to flush the experiment log: {}", e);
+            }
+        });
+        let sessions = SessionStore::new().with_dedup_window(setting.dedup_window.unwrap_or(DEDUP_WINDOW));
+        let gossip = match setting.registry {
+            Some(ref config) => {
+                let gossip = Gossip::start(config, sessions.clone())?;
+                sessions.set_registry(gossip.clone())?;
+                Some(gossip.handle())
+            }
+            None => None,
+        };
+        let (tx, rx) = unbounded();
+        let memory = setting.memory_budget_mb.map(|mb| MemoryBudget::new(mb * 1024 * 1024));
+        let tolerance = setting.group_sync_tolerance_ms.unwrap_or(DEFAULT_SYNC_TOLERANCE_MS);
+        let groups = StreamGroups::new(tolerance);
+        let groups = match memory {
+            Some(ref budget) => groups.with_budget(budget.account(Component::Group)),
+            None => groups,
+        };
+        let sealer = match setting.frame_integrity {
+            Some(ref config) => Sealer::new(config)?.require_seals(),
+            None => Sealer::new(&IntegrityConfig::default())?,
+        };
+        if let Some(ref budget) = memory {
+            let events = tx.clone();
+            let pressure = budget.subscribe().for_each(move |p| {
+                let event = ServerEvent::MemoryPressure {
+                    used: p.used,
+                    limit: p.limit,
+                };
+                let _ = events.unbounded_send(event);
+                Ok(())
+            });
+            handle.spawn(pressure);
+        }
+        Ok(Server {
+            listener,
+            control,
+            admin,
+            addr,
+            workers: setting.workers.unwrap_or(1),
+            _advertisement: advertise(&setting),
+            _gossip: gossip,
+            _postmortem: postmortem,
+            ctx: Context {
+                shared: Shared {
+                    sessions,
+                    log,
+                    events: tx,
+                    stats: ServerStats::default(),
+                    profile_path: setting.profile_path,
+                    stat_path: setting.stat_path,
+                    analytics: setting.analytics.unwrap_or(true),
+                    delay_gradient_weight: setting.delay_gradient_weight,
+                    wire_format,
+                    frame_limits: setting.frame_limits.unwrap_or_default(),
+                    decode_tolerance: setting.decode_tolerance.unwrap_or_default(),
+                    sequence_check: setting.sequence_check.unwrap_or_default(),
+                    downlink: None,
+                    catalog: setting.profile_catalog.map(ProfileCatalog::new),
+                    memory,
+                    groups,
+                    sealer,
+                    admission: setting
+                        .ingest_budget_kbps
+                        .map(|kbps| AdmissionControl::new(Bandwidth::from_kbps(kbps))),
+                    middleware: Layers::default(),
+                    idle_timeout: setting.idle_timeout_ms.map(Duration::from_millis),
+                    evaluator: None,
+                    evaluation_sample_every: setting
+                        .evaluation_sample_every
+                        .unwrap_or(evaluator::SAMPLE_EVERY),
+                    lag_probe: None,
+                    consumer_lag: setting.consumer_lag.unwrap_or_default(),
+                },
+                handle: handle.clone(),
+            },
+            events: rx,
+        })
+    }
+
+    /// The address the server listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The address the server listens on for control connections, if any.
+    pub fn control_addr(&self) -> Option<SocketAddr> {
+        self.control.as_ref().and_then(|l| l.local_addr().ok())
+    }
+
+    /// The address of the admin endpoint, if any.
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin.as_ref().and_then(|l| l.local_addr().ok())
+    }
+
+    /// Server-wide counters.
+    pub fn stats(&self) -> ServerStats {
+        self.ctx.shared.stats.clone()
+    }
+
+    /// Streams the frames of the source `downlink` creates for a session
+    /// back to its client over the data connection, adapting to the reverse
+    /// path. Sessions for which it returns `None` only receive feedback.
+    /// Applies to the connections accepted after `incoming_events`.
+    pub fn set_downlink<F>(&mut self, downlink: F)
+    where
+        F: Fn(u64) -> Option<Box<dyn Source>> + Send + Sync + 'static,
+    {
+        self.ctx.shared.downlink = Some(Arc::new(downlink));
+    }
+
+    /// Appends a custom stage to the pipeline of each connection (see
+    /// `middleware`). Applies to the connections accepted after
+    /// `incoming_events`.
+    pub fn add_middleware<M: Middleware + 'static>(&mut self, layer: M) {
+        self.ctx.shared.middleware.push(layer);
+    }
+
+    /// Estimates the accuracy of the analytics on the frames delivered with
+    /// `evaluator`, and sends the estimates to the clients (see `evaluator`).
+    /// Applies to the connections accepted after `incoming_events`.
+    pub fn set_evaluator<E: AccuracyEvaluator + 'static>(&mut self, evaluator: E) {
+        self.ctx.shared.evaluator = Some((Arc::new(evaluator), CpuPool::new_num_cpus()));
+    }
+
+    /// Slows the clients down when their downstream consumer falls behind,
+    /// by the lag `probe` tells (see `consumer_lag`). Applies to the
+    /// connections accepted after `incoming_events`.
+    pub fn set_lag_probe<P: LagProbe + 'static>(&mut self, probe: P) {
+        self.ctx.shared.lag_probe = Some(Arc::new(probe));
+    }
+
+    /// Shares the sessions with other server instances through `registry`
+    /// rather than the gossip of `Setting::registry` (see `registry`).
+    pub fn set_registry<R: SessionRegistry + 'static>(&self, registry: R) -> Result<()> {
+        self.ctx.shared.sessions.set_registry(Arc::new(registry))
+    }
+
+    /// The stream groups of clients, to take time-aligned bundles of their
+    /// frames (see `grouping`).
+    pub fn stream_groups(&self) -> StreamGroups {
+        self.ctx.shared.groups.clone()
+    }
+
+    /// A handle to report the quality of the analytics to clients.
+    pub fn quality_feedback(&self) -> QualityFeedback {
+        QualityFeedback { sessions: self.ctx.shared.sessions.clone() }
+    }
+
+    /// Accepts clients and yields what happens to them. Events are buffered
+    /// until polled. The stream never ends; dropping it stops accepting new
+    /// clients (with workers, after the next connection attempt).
+    pub fn incoming_events(self) -> ServerEvents {
+        if let Some(control) = self.control {
+            // control connections are light; they all stay on this reactor
+            let ctx = self.ctx.clone();
+            let addr = control.local_addr().expect("failed to get control address");
+            let listener = TcpListener::from_listener(control, &addr, &ctx.handle)
+                .expect("failed to register control listener");
+            let err_ctx = ctx.clone();
+            let accept = listener
+                .incoming()
+                .for_each(move |(socket, addr)| {
+                    handle_control(socket, addr, ctx.clone());
+                    Ok(())
+                })
+                .map_err(move |e| {
+                    err_ctx.emit(ServerEvent::Error {
+                        addr: None,
+                        error: e.into(),
+                    })
+                });
+            self.ctx.handle.spawn(accept);
+        }
+        if let Some(admin) = self.admin {
+            if let Err(e) = admin::serve(admin, self.ctx.shared.sessions.clone()) {
+                self.ctx.emit(ServerEvent::Error { addr: None, error: e });
+            }
+        }
+        let stop = Arc::new(AtomicBool::new(false));
+        let incoming = if self.workers > 1 {
+            spawn_workers(self.listener, self.workers, &self.ctx.shared, stop.clone());
+            None
+        } else {
+            let listener = TcpListener::from_listener(self.listener, &self.addr, &self.ctx.handle)
+                .expect("failed to register listener");
+            Some(listener.incoming())
+        };
+        ServerEvents {
+            incoming,
+            stop,
+            ctx: self.ctx,
+            events: self.events,
+            _advertisement: self._advertisement,
+            _gossip: self._gossip,
+            _postmortem: self._postmortem,
+        }
+    }
+}
+
+/// Accepts on a dedicated thread and hands connections to `n` worker threads
+/// in turn. Each worker runs its own reactor.
+fn spawn_workers(listener: net::TcpListener, n: usize, shared: &Shared, stop: Arc<AtomicBool>) {
+    let workers = (0..n)
+        .map(|i| {
+            let (tx, rx) = unbounded::<(net::TcpStream, SocketAddr)>();
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name(format!("awstream-worker-{}", i))
+                .spawn(move || {
+                    let mut core = Core::new().expect("failed to create worker reactor");
+                    let ctx = Context {
+                        shared,
+                        handle: core.handle(),
+                    };
+                    let work = rx.for_each(|(stream, addr)| {
+                        match TcpStream::from_stream(stream, &ctx.handle) {
+                            Ok(socket) => ctx.accept(socket, addr),
+                            Err(e) => ctx.emit(ServerEvent::Error {
+                                addr: Some(addr),
+                                error: e.into(),
+                            }),
+                        }
+                        Ok(())
+                    });
+                    let _ = core.run(work);
+                })
+                .expect("failed to spawn worker");
+            tx
+        })
+        .collect::<Vec<_>>();
+
+    let shared = shared.clone();
+    thread::Builder::new()
+        .name("awstream-acceptor".into())
+        .spawn(move || {
+            let emit = |event| {
+                let _ = shared.events.unbounded_send(event);
+            };
+            for (i, stream) in listener.incoming().enumerate() {
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                let accepted = stream.and_then(|s| {
+                    s.set_nonblocking(true)?;
+                    let addr = s.peer_addr()?;
+                    Ok((s, addr))
+                });
+                match accepted {
+                    Ok((s, addr)) => {
+                        if workers[i % n].unbounded_send((s, addr)).is_err() {
+                            emit(ServerEvent::Error {
+                                addr: Some(addr),
+                                error: ErrorKind::DataPlane.into(),
+                            });
+                        }
+                    }
+                    Err(e) => emit(ServerEvent::Error {
+                        addr: None,
+                        error: e.into(),
+                    }),
+                }
+            }
+        })
+        .expect("failed to spawn acceptor");
+}
+
+/// The stream returned by `Server::incoming_events`.
+pub struct ServerEvents {
+    incoming: Option<Incoming>,
+    stop: Arc<AtomicBool>,
+    ctx: Context,
+    events: UnboundedReceiver<ServerEvent>,
+    _advertisement: Option<Advertisement>,
+    _gossip: Option<GossipHandle>,
+    _postmortem: Registration,
+}
+
+impl Stream for ServerEvents {
+    type Item = ServerEvent;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<ServerEvent>, Error> {
+        while let Some(ref mut incoming) = self.incoming {
+            match incoming.poll() {
+                Ok(Async::Ready(Some((socket, addr)))) => self.ctx.accept(socket, addr),
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => break,
+                Err(e) => {
+                    return Ok(Async::Ready(Some(ServerEvent::Error {
+                        addr: None,
+                        error: e.into(),
+                    })))
+                }
+            }
+        }
+        match self.events.poll() {
+            Ok(Async::Ready(Some(e))) => Ok(Async::Ready(Some(e))),
+            // the context holds a sender, so the channel never closes
+            Ok(Async::Ready(None)) | Ok(Async::NotReady) | Err(_) => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl Drop for ServerEvents {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Bytes a connection reads ahead of its handler; the rest is left to TCP
+/// flow control.
+const READ_HIGH_WATERMARK: usize = 1024 * 1024;
+
+/// Below this, a read buffer grown for a large frame is released.
+const READ_LOW_WATERMARK: usize = 64 * 1024;
+
+/// Handles a new socket. The first datum is expected to be the client's
+/// `Hello`, which opens or resumes a session; clients that don't send one get
+/// a fresh session. The server replies with `Welcome` and starts serving.
+fn handle_conn(socket: TcpStream, addr: SocketAddr, analytics: VideoAnalytics, ctx: Context) {
     info!("new connection from {}", addr);
 
-    let transport = socket.framed(AsCodec::default());
-    let (transport_write, transport_read) = transport.split();
-
-    let mut goodput = BwMonitor::new();
-    let mut throughput = BwMonitor::new();
-    let mut latency_mon = LatencyMonitor::new();
-    let mut reporter = Reporter::new(
-        transport_write,
-        goodput.clone(),
-        throughput.clone(),
-        latency_mon.clone(),
-        analytics.clone(),
+    let tcp_info = TcpInfoProbe::new(&socket);
+    let (read_half, write_half) = socket::split(socket);
+    let mut codec = AsCodec::new(ctx.shared.wire_format);
+    codec.set_limits(ctx.shared.frame_limits);
+    let mut transport_read =
+        FramedRead::with_watermarks(read_half, codec, READ_LOW_WATERMARK, READ_HIGH_WATERMARK);
+    if let Some(ref budget) = ctx.shared.memory {
+        transport_read.set_budget(budget.account(Component::Decode));
+    }
+    let probes = Probes {
+        tcp_info,
+        load: transport_read.load(),
+    };
+    // feedback and the downlink share the connection
+    let (socket, out_bytes) = Socket::new(write_half, ctx.shared.wire_format);
+    let (transport_write, shared_socket) = SocketHandle::new(socket, SEND_CAPACITY);
+    ctx.handle.spawn(shared_socket.map_err(move |e| debug!("connection to {} closed: {}", addr, e)));
+
+    let handle = ctx.handle.clone();
+    let err_ctx = ctx.clone();
+    let handshake = transport_read
+        .into_future()
+        .map_err(|(e, _)| e)
+        .and_then(move |(first, rest)| {
+            let (token, identity, request, first) = match first {
+                Some(ref d) => match d.datum_type() {
+                    AsDatumType::Hello(token) => {
+                        (token, d.client_identity()?, d.admission_request()?, None)
+                    }
+                    _ => (None, ClientIdentity::default(), None, first),
+                },
+                None => (None, ClientIdentity::default(), None, None),
+            };
+            // turned down before it holds a session or a grant
+            let request_info = ConnectRequest {
+                addr,
+                resuming: token,
+                identity: identity.clone(),
+            };
+            if let Err(e) = ctx.shared.middleware.on_connect(&request_info) {
+                info!("middleware turned client {} down: {}", addr, e);
+                ctx.shared.stats.add_closed(CloseReason::AuthFailure);
+                let close = transport_write.send(AsDatum::close(CloseReason::AuthFailure)?);
+                let refused = close.then(|_| Err(e));
+                return Ok(Box::new(refused) as Box<dyn Future<Item = (), Error = Error>>);
+            }
+            let (session, resumed) = ctx.shared.sessions.open(token, analytics)?;
+            let admission = match (ctx.shared.admission.as_ref(), request) {
+                (Some(control), Some(request)) => match control.admit(session.token, &request)? {
+                    Admission::Rejected => {
+                        info!("no capacity left for client {}", addr);
+                        ctx.shared.sessions.detach(session.token)?;
+                        ctx.emit(ServerEvent::AdmissionRejected {
+                            addr,
+                            session: session.token,
+                            request,
+                        });
+                        // dropping the connection hangs up once sent
+                        let rejected = AsDatum::admission(Admission::Rejected)?;
+                        let rejected = transport_write.send(rejected).map(|_| ());
+                        return Ok(Box::new(rejected) as Box<dyn Future<Item = (), Error = Error>>);
+                    }
+                    answer => Some(answer),
+                },
+                _ => None,
+            };
+            let conn = Arc::new(ConnectionInfo {
+                addr,
+                session: session.token,
+                identity: identity.clone(),
+            });
+            let stats = &ctx.shared.stats.inner;
+            stats.connections.fetch_add(1, Ordering::Relaxed);
+            stats.active.fetch_add(1, Ordering::Relaxed);
+            if resumed {
+                info!("client {} resumed session {:x}", addr, session.token);
+            }
+            if let Some(ref group) = identity.group {
+                let request = GroupRequest {
+                    host: addr.ip(),
+                    client_id: identity.client_id.clone(),
+                    group: group.clone(),
+                };
+                ctx.shared.groups.join(request, session.token)?;
+            }
+            ctx.emit(ServerEvent::Connected {
+                addr,
+                session: session.token,
+                resumed,
+                stats: session.stats.clone(),
+            });
+            let welcome = match hosted_profile(&ctx.shared, &identity) {
+                Some(profile) => {
+                    info!("handing client {} ({:?}) its profile", addr, identity);
+                    AsDatum::welcome_with_profile(session.token, &profile)?
+                }
+                None => AsDatum::welcome(session.token),
+            };
+            let first = ::futures::stream::iter_ok(first);
+            let answer = admission.map(AsDatum::admission).transpose()?;
+            let replies = ::futures::stream::iter_ok::<_, Error>(answer.into_iter().chain(Some(welcome)));
+            let welcomed = transport_write.send_all(replies).map(|(w, _)| w);
+            Ok(Box::new(welcomed.map(move |w| {
+                let downlink = ctx.shared.downlink.as_ref().and_then(|f| f(session.token));
+                let downlink = downlink.map(|source| {
+                    info!("streaming back to client {}", addr);
+                    spawn_downlink(source, w.clone(), out_bytes, &ctx.handle)
+                });
+                serve(w, first.chain(rest), conn, session, probes, downlink, ctx)
+            })) as Box<dyn Future<Item = (), Error = Error>>)
+        })
+        .flatten()
+        .map_err(move |e| {
+            err_ctx.emit(ServerEvent::Error {
+                addr: Some(addr),
+                error: e,
+            })
+        });
+    handle.spawn(handshake);
+}
+
+/// The profile of the catalog for `identity`, if any. A broken catalog
+/// leaves clients with their local profiles.
+fn hosted_profile(shared: &Shared, identity: &ClientIdentity) -> Option<HostedProfile> {
+    let catalog = shared.catalog.as_ref()?;
+    catalog.lookup(identity).unwrap_or_else(|e| {
+        warn!("no profile for {:?}: {}", identity, e);
+        None
+    })
+}
+
+/// Handles a control connection. The first datum must be `Control` with the
+/// token of an open session; from then on the session's feedback is sent
+/// here, and pings are echoed back.
+fn handle_control(socket: TcpStream, addr: SocketAddr, ctx: Context) {
+    let _ = socket.set_nodelay(true);
+    let (read_half, write_half) = socket.split();
+    #[allow(deprecated)]
+    let transport_write = FramedWrite::new(write_half, AsCodec::default());
+    let (tx, rx) = unbounded();
+    let writer = transport_write
+        .send_all(rx.map_err(|_| Error::from_kind(ErrorKind::ReplyChannel)))
+        .map(|_| ())
+        .map_err(|e| debug!("control connection closed: {}", e));
+    ctx.handle.spawn(writer);
+
+    let sessions = ctx.shared.sessions.clone();
+    let err_ctx = ctx.clone();
+    let work = FramedRead::new(read_half, AsCodec::default())
+        .into_future()
+        .map_err(|(e, _)| e)
+        .and_then(move |(first, rest)| {
+            let token = match first.map(|d| d.datum_type()) {
+                Some(AsDatumType::Control(token)) => token,
+                _ => bail!(ErrorKind::RemotePeer),
+            };
+            let id = match sessions.attach_control(token, tx.clone())? {
+                Some(id) => id,
+                None => bail!(ErrorKind::InvalidConfig(format!("unknown session {:x}", token))),
+            };
+            info!("control connection from {} for session {:x}", addr, token);
+            let echo = rest.for_each(move |datum| {
+                if datum.datum_type() == AsDatumType::LatencyProbe {
+                    // the writer is gone only if the connection is
+                    let _ = tx.unbounded_send(datum);
+                }
+                Ok(())
+            });
+            Ok(echo.then(move |result| {
+                // unless the client has reconnected the control connection
+                sessions.detach_control(token, id)?;
+                result
+            }))
+        })
+        .flatten()
+        .map_err(move |e| {
+            err_ctx.emit(ServerEvent::Error {
+                addr: Some(addr),
+                error: e,
+            })
+        });
+    ctx.handle.spawn(work);
+}
+
+/// Streams `source` into `sink` on `handle` until the returned cancellation
+/// fires. Levels follow the rate at which the socket drains (`consumed`
+/// counts the bytes it wrote): the client sends no feedback on the reverse
+/// path, so only queueing is detected.
+fn spawn_downlink(
+    source: Box<dyn Source>,
+    sink: SocketHandle,
+    consumed: Arc<AtomicUsize>,
+    handle: &Handle,
+) -> Cancellation {
+    let cancel = Cancellation::new();
+    let clock: SharedClock = Arc::new(SystemClock::new());
+    let mut profile = source.simple_profile();
+    let padding = Box::new(ZeroPadding);
+    // the client sends no hints for the downlink
+    let hints = unbounded().1;
+    let ((actions, probing), data, produced) = source::spawn(
+        source,
+        handle,
+        Transition::Immediate,
+        cancel.clone(),
+        padding,
+        clock.clone(),
+        hints,
+        false,
+        None,
     );
+    let data = data.map_err(|_| Error::from_kind(ErrorKind::SourceData));
+    handle.spawn(sink.send_all(data).map(|_| ()).map_err(|e| debug!("downlink stopped: {}", e)));
+
+    let rate = Box::new(ExponentialSmooth::new(0.5));
+    let monitor = Monitor::new(produced, consumed, rate, clock).skip(1);
+    let mut adaptation = Adaptation::default();
+    let running = cancel.clone();
+    let control = monitor
+        .select(probing.map_err(|_| Error::from_kind(ErrorKind::ControlPlane)))
+        .take_while(move |_| Ok(!running.is_cancelled()))
+        .for_each(move |signal| {
+            let decision = adaptation::decide(&mut adaptation, &mut profile, signal);
+            if let Some(command) = decision.command {
+                actions.unbounded_send(command).map_err(
+                    |_| Error::from_kind(ErrorKind::ControlPlane),
+                )?;
+            }
+            Ok(())
+        });
+    handle.spawn(control.map_err(|e| debug!("downlink control stopped: {}", e)));
+    cancel
+}
+
+/// What is sampled every second about a connection.
+struct Probes {
+    /// Its `TCP_INFO`, where supported.
+    tcp_info: Option<TcpInfoProbe>,
+
+    /// How full its reads are, to detect a slow receiver.
+    load: ReadLoad,
+}
+
+/// The main server logic that handles a particular connection of `session`.
+/// The `probes` are sampled every second; a `downlink` is cancelled when the
+/// connection ends.
+fn serve<W, R>(
+    transport_write: W,
+    transport_read: R,
+    conn: Arc<ConnectionInfo>,
+    session: Session<VideoAnalytics>,
+    probes: Probes,
+    downlink: Option<Cancellation>,
+    ctx: Context,
+) where
+    W: Sink<SinkItem = AsDatum, SinkError = Error> + Clone + 'static,
+    R: Stream<Item = AsDatum, Error = Error> + 'static,
+{
+    let (addr, token) = (conn.addr, session.token);
+    // the connection is closed on purpose through the session, or once idle
+    let (closer, close_rx) = unbounded();
+    let closer = Arc::new(closer);
+    *session.closer.lock().expect("session poisoned") = Some(closer.clone());
+    let (session_closer, own_closer) = (session.closer.clone(), closer.clone());
+    let closing_write = transport_write.clone();
+    let activity = Arc::new(Mutex::new(Instant::now()));
+    let idle_since = activity.clone();
+    let idle_timeout = ctx.shared.idle_timeout;
+    let peer_reason = Arc::new(Mutex::new(None));
+    let final_reason = peer_reason.clone();
+    let mut goodput = session.goodput.clone();
+    let mut throughput = session.throughput.clone();
+    let mut latency_mon = session.latency.clone();
+    let analytics = session.analytics.clone();
+    let log = ctx.shared.log.clone();
+    let mut reporter = Reporter::new(transport_write, &session, log.clone(), addr, ctx.shared.events.clone());
+    if let Some(weight) = ctx.shared.delay_gradient_weight {
+        reporter.add_signal(Box::new(DelayGradient::new()), weight);
+    }
+    if let Some(ref probe) = ctx.shared.lag_probe {
+        reporter.set_lag(LagSignal::new(probe.clone(), conn.clone(), ctx.shared.consumer_lag));
+    }
+    let last_frame = session.last_frame.clone();
+    let last_seq = session.last_seq.clone();
+    let frames = session.frames.clone();
+    session.stats.set_expected_rates(analytics.rates().unwrap_or_default());
+    let session_stats = session.stats.clone();
+    let transport_stats = session.stats.clone();
+    let final_stats = session.stats.clone();
+    let info_stats = session.stats.clone();
+    let composition = Arc::new(Mutex::new(CompositionTracker::new()));
+    let interval_composition = composition.clone();
 
     let timer = tokio_timer::Timer::default();
     let (ticks, tick_stopper) = interval::new(timer, Duration::from_millis(1000));
 
     let errmsg = "fail to update statistics";
+    let receiver_limited = reporter.receiver_limited.clone();
 
     let estimate_throughput = ticks.for_each(move |_| {
+        if let Some(timeout) = idle_timeout {
+            if idle_since.lock().expect(errmsg).elapsed() >= timeout {
+                // the connection may be closing already
+                let _ = closer.unbounded_send(CloseReason::Idle);
+            }
+        }
         // in each tick, measure bandwidth
-        goodput.update(1000).expect(&errmsg);
-        throughput.update(1000).expect(&errmsg);
-        latency_mon.update().expect(&errmsg);
+        goodput.update(1000).expect(errmsg);
+        throughput.update(1000).expect(errmsg);
+        latency_mon.update().expect(errmsg);
+        log.flush(Utc::now().timestamp_millis()).expect(errmsg);
         info!(
-            "client {}\tgoodput {} kbps\tthroughput {} kbps\tlatency {:.3} ms\taccuracy {:.4}",
+            concat!(
+                "client {}\tgoodput {}\tthroughput {}\t",
+                "latency {:.3} ms\taccuracy {:.4}\tdelivered {:?}"
+            ),
             addr,
             goodput.rate().unwrap(),
             throughput.rate().unwrap(),
             latency_mon.rate().unwrap(),
-            analytics.accuracy().unwrap()
+            analytics.accuracy().unwrap(),
+            log.delivered_accuracy().unwrap()
         );
+        if let Some(info) = probes.tcp_info.as_ref().and_then(TcpInfoProbe::read) {
+            info!(
+                "client {}\tcwnd {}\tsrtt {:.1} ms\trttvar {:.1} ms\tretransmits {}",
+                addr,
+                info.cwnd,
+                info.srtt_ms,
+                info.rttvar_ms,
+                info.retransmits
+            );
+            transport_stats.set_tcp_info(info);
+        }
+        let composition = interval_composition.lock().expect(errmsg).take();
+        info!("client {}\t{}", addr, composition);
+        transport_stats.set_composition(composition);
+        if let Some(limited) = probes.load.take_limited() {
+            if receiver_limited.swap(limited, Ordering::Relaxed) != limited {
+                if limited {
+                    info!("client {} is limited by the receiver, not the network", addr);
+                } else {
+                    info!("client {} is no longer limited by the receiver", addr);
+                }
+            }
+        }
         Ok(())
     });
 
     // Spawn a new task dedicated to measure bandwidth
-    handle.spawn(estimate_throughput.map_err(|_| ()));
+    ctx.handle.spawn(estimate_throughput.map_err(|_| ()));
 
-    let process_connection = transport_read
-        .for_each(move |as_datum| {
-            let size = as_datum.len() as usize;
-            reporter.throughput.add(size).expect(&errmsg);
+    let handle = ctx.handle.clone();
+    let frame_ctx = ctx.clone();
+    let error_ctx = ctx.clone();
+    let frame_conn = conn.clone();
+    let every = ctx.shared.evaluation_sample_every;
+    let mut sampler = ctx.shared
+        .evaluator
+        .clone()
+        .map(|(evaluator, pool)| Sampler::new(evaluator, pool, every));
+    let on_error = move |e: &Error, recent| {
+        warn!("client {} sent a malformed frame: {}", addr, e);
+        let stats = &error_ctx.shared.stats.inner;
+        stats.decode_errors.fetch_add(1, Ordering::Relaxed);
+        error_ctx.emit(ServerEvent::DecodeError {
+            addr,
+            session: token,
+            reason: e.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(": "),
+            recent,
+        });
+    };
+    // compressed frames are restored, but rates count the bytes on the wire
+    let decoded = ctx.shared.memory.as_ref().map(|budget| budget.account(Component::Decode));
+    let mut decompressor = Decompressor::new(ctx.shared.frame_limits, decoded);
+    let mut delta = DeltaDecoder::default();
+    let transport_read = transport_read
+        .and_then(move |datum| {
+            let size = datum.len();
+            let datum = decompressor.decode(datum)?;
+            delta.decode(datum).map(|datum| datum.map(|datum| (size, datum)))
+        })
+        .filter_map(|restored| restored);
+    let closed = close_rx
+        .into_future()
+        .map_err(|_| Error::from_kind(ErrorKind::ReplyChannel))
+        .and_then(|(reason, _)| match reason {
+            Some(reason) => Either::A(future::err(Error::from_kind(ErrorKind::Closed(reason)))),
+            None => Either::B(future::empty()),
+        });
+    // estimates of the evaluator are sent back as they become ready, in
+    // between the datums of the client
+    let (estimated_tx, estimated_rx) = unbounded();
+    let estimated = estimated_rx
+        .map(Received::Estimate)
+        .map_err(|_| Error::from_kind(ErrorKind::ReplyChannel));
+    let process_connection = Tolerant::new(transport_read, ctx.shared.decode_tolerance, on_error)
+        .map(|(size, datum)| Received::Datum(size, datum))
+        .chain(::futures::stream::once(Ok(Received::End)))
+        .select(estimated)
+        .take_while(|received| Ok(!matches!(*received, Received::End)))
+        .for_each(move |received| {
+            let (size, as_datum) = match received {
+                Received::Datum(size, datum) => (size, datum),
+                Received::Estimate(report) => {
+                    debug!(
+                        "client {} frame {:?} at level {}: accuracy {:.3}",
+                        addr,
+                        report.frame_num,
+                        report.level,
+                        report.quality
+                    );
+                    frame_ctx.shared.sessions.send_feedback(token, AsDatum::quality(report)?)?;
+                    return reporter.flush_outbox();
+                }
+                Received::End => return Ok(()),
+            };
+            *activity.lock()? = Instant::now();
+            reporter.flush_outbox()?;
+            reporter.throughput.add(size).expect(errmsg);
+            let kind = composition.lock()?.observe(&as_datum, size);
+            match frame_ctx.shared.sealer.verify(&as_datum) {
+                Verdict::Unsealed => {}
+                Verdict::Intact => session_stats.add_sealed(false),
+                Verdict::Mismatch => {
+                    warn!("client {} sent {} altered since it was sealed", addr, as_datum);
+                    session_stats.add_sealed(true);
+                    frame_ctx.emit(ServerEvent::DigestMismatch {
+                        addr,
+                        session: token,
+                        datum: as_datum.clone(),
+                    });
+                }
+            }
+            let as_datum = match frame_ctx.shared.middleware.on_datum(&frame_conn, as_datum)? {
+                Some(datum) => datum,
+                None => return Ok(()),
+            };
+            let duplicate = match as_datum.datum_type() {
+                AsDatumType::Live(_, frame_num) |
+                AsDatumType::Reference(_, frame_num) |
+                AsDatumType::Backfill(_, frame_num) |
+                AsDatumType::Redundant(_, frame_num) => !frames.lock()?.insert(None, frame_num),
+                AsDatumType::Sub(stream, seq) => !frames.lock()?.insert(Some(stream), seq),
+                _ => false,
+            };
             match as_datum.datum_type() {
-                AsDatumType::Live(level, frame_num) => {
-                    let size = as_datum.len() as usize;
-                    reporter.goodput.add(size).expect(&errmsg);
-                    reporter.report(level, frame_num, as_datum)?
+                _ if duplicate => {
+                    trace!("client {} sent a duplicate: {}", addr, as_datum);
+                    let stats = &frame_ctx.shared.stats.inner;
+                    stats.duplicates.fetch_add(1, Ordering::Relaxed);
+                    session_stats.add_duplicate();
+                }
+                AsDatumType::Live(level, frame_num) |
+                AsDatumType::Reference(level, frame_num) => {
+                    let check = frame_ctx.shared.sequence_check;
+                    let mut last = last_frame.lock()?;
+                    match *last {
+                        // a new sequence, e.g., the source looped
+                        Some(prev) if restarts_numbering(prev, frame_num) => {
+                            debug!("client {} restarted at frame {} after {}", addr, frame_num, prev);
+                        }
+                        Some(prev) if frame_num <= prev && check != SequenceCheck::Off => {
+                            let rejected = check == SequenceCheck::Reject;
+                            warn!("client {} sent frame {} after {}", addr, frame_num, prev);
+                            session_stats.add_regression(rejected);
+                            frame_ctx.emit(ServerEvent::SequenceRegression {
+                                addr,
+                                session: token,
+                                last: prev,
+                                frame_num,
+                                rejected,
+                            });
+                            if rejected {
+                                return Ok(());
+                            }
+                        }
+                        _ => {}
+                    }
+                    *last = Some(frame_num);
+                    drop(last);
+                    // frame numbers skip on purpose at lower frame rates; the
+                    // sender's numbering (in the frame's trailer) skips only
+                    // what was lost on the way
+                    if let Some(seq) = as_datum.seq() {
+                        let mut last = last_seq.lock()?;
+                        if let Some(prev) = *last {
+                            if seq > prev + 1 {
+                                debug!("client {} lost frames {}..{} on the way", addr, prev + 1, seq);
+                                session_stats.add_lost(seq - prev - 1);
+                            }
+                        }
+                        *last = Some(seq);
+                    }
+                    reporter.goodput.add(size).expect(errmsg);
+                    let latency_ms = reporter.report(level, frame_num, &as_datum, kind)?;
+                    frame_ctx.shared.groups.add(token, &as_datum)?;
+                    let stats = &frame_ctx.shared.stats.inner;
+                    stats.frames.fetch_add(1, Ordering::Relaxed);
+                    stats.bytes.fetch_add(size, Ordering::Relaxed);
+                    frame_ctx.shared.middleware.on_frame(&frame_conn, &as_datum, latency_ms);
+                    let sampled = sampler
+                        .as_mut()
+                        .and_then(|s| s.sample(&frame_conn, level, frame_num, &as_datum));
+                    if let Some(estimate) = sampled {
+                        let tx = estimated_tx.clone();
+                        let sent = estimate.map(move |report| {
+                            if let Some(report) = report {
+                                // unless the connection is gone
+                                let _ = tx.unbounded_send(report);
+                            }
+                        });
+                        frame_ctx.handle.spawn(sent);
+                    }
+                    frame_ctx.emit(ServerEvent::Frame {
+                        addr,
+                        session: token,
+                        latency_ms,
+                        datum: as_datum,
+                    });
+                }
+                AsDatumType::Backfill(..) | AsDatumType::Redundant(..) | AsDatumType::Sub(..) => {
+                    // late or duplicate by design, or of another sub-stream
+                    // (numbered apart), so kept out of the feedback
+                    let latency_ms = time_diff_in_ms(chrono::Utc::now(), as_datum.ts);
+                    trace!("client {} sent {}", addr, as_datum);
+                    let stats = &frame_ctx.shared.stats.inner;
+                    stats.frames.fetch_add(1, Ordering::Relaxed);
+                    stats.bytes.fetch_add(size, Ordering::Relaxed);
+                    frame_ctx.shared.middleware.on_frame(&frame_conn, &as_datum, latency_ms);
+                    frame_ctx.emit(ServerEvent::Frame {
+                        addr,
+                        session: token,
+                        latency_ms,
+                        datum: as_datum,
+                    });
                 }
+                AsDatumType::Barrier(level) => {
+                    debug!("client {} switched to level {}", addr, level);
+                    frame_ctx.emit(ServerEvent::Barrier {
+                        addr,
+                        session: token,
+                        level,
+                    });
+                }
+                AsDatumType::StreamInfo => match StreamInfo::from_mem(&as_datum.mem) {
+                    Ok(info) => {
+                        info!("client {} streams {:?}", addr, info);
+                        info_stats.set_stream_info(info.clone());
+                        frame_ctx.shared.middleware.on_stream_info(&frame_conn, &info);
+                        frame_ctx.emit(ServerEvent::StreamInfo {
+                            addr,
+                            session: token,
+                            info,
+                        });
+                    }
+                    Err(e) => warn!("client {} sent a malformed stream info: {}", addr, e),
+                },
+                AsDatumType::Close => match CloseReason::from_mem(&as_datum.mem) {
+                    Ok(reason) => {
+                        info!("client {} is closing for {}", addr, reason);
+                        *peer_reason.lock()? = Some(reason);
+                    }
+                    Err(e) => warn!("client {} sent a malformed close: {}", addr, e),
+                },
                 AsDatumType::Dummy => {}
                 AsDatumType::LatencyProbe => {
                     let now = chrono::Utc::now();
@@ -110,14 +1359,99 @@ fn handle_conn(
             }
             Ok(())
         })
-        .map_err(|_| ());
+        .select(closed)
+        .map(|_| ())
+        .map_err(|(e, _)| e)
+        .then(move |result| {
+            tick_stopper.send(()).expect("failed to send");
+            if let Some(downlink) = downlink {
+                downlink.cancel();
+            }
+            // this end says why it closes last, or the client did
+            let (result, closing) = match result {
+                Err(Error(ErrorKind::Closed(reason), _)) => (Ok(()), Some(reason)),
+                Err(e) => match *e.kind() {
+                    ErrorKind::TooManyDecodeErrors(_) |
+                    ErrorKind::MetadataTooLarge(..) |
+                    ErrorKind::PayloadTooLarge(..) => (Err(e), Some(CloseReason::ProtocolError)),
+                    _ => (Err(e), None),
+                },
+                Ok(()) => (Ok(()), None),
+            };
+            if let Some(reason) = closing {
+                info!("closing client {} for {}", addr, reason);
+                match AsDatum::close(reason) {
+                    Ok(datum) => {
+                        let sent = closing_write
+                            .send(datum)
+                            .map(|_| ())
+                            .map_err(move |e| debug!("failed to close {}: {}", addr, e));
+                        ctx.handle.spawn(sent);
+                    }
+                    Err(e) => error!("failed to close {}: {}", addr, e),
+                }
+            }
+            let reason = closing.or_else(|| final_reason.lock().ok().and_then(|r| *r));
+            if let Some(reason) = reason {
+                ctx.shared.stats.add_closed(reason);
+            }
+            // unless a resumed connection took the session over already
+            if let Ok(mut closer) = session_closer.lock() {
+                if closer.as_ref().is_some_and(|c| Arc::ptr_eq(c, &own_closer)) {
+                    *closer = None;
+                }
+            }
+            if let Err(e) = result {
+                ctx.emit(ServerEvent::Error {
+                    addr: Some(addr),
+                    error: e,
+                });
+            }
+            ctx.shared.stats.inner.active.fetch_sub(1, Ordering::Relaxed);
+            ctx.shared.middleware.on_disconnect(&conn);
+            if let Err(e) = ctx.shared.sessions.detach(token) {
+                error!("failed to detach session {:x}: {}", token, e);
+            }
+            if let Err(e) = ctx.shared.groups.leave(token) {
+                error!("failed to leave the group of session {:x}: {}", token, e);
+            }
+            if let Some(ref admission) = ctx.shared.admission {
+                if let Err(e) = admission.release(token) {
+                    error!("failed to release the admission of session {:x}: {}", token, e);
+                }
+            }
+            let tradeoff = final_stats.tradeoff();
+            for l in &tradeoff.levels {
+                info!(
+                    "client {}\tlevel {}\t{:.0} ms\tloss {:.3}\tachieved {:?} kbps\texpected {:?} kbps\tlatency p95 {:?} ms",
+                    addr,
+                    l.level,
+                    l.time_ms,
+                    l.loss_rate,
+                    l.achieved_kbps,
+                    l.expected_kbps,
+                    l.latency_p95_ms
+                );
+            }
+            ctx.emit(ServerEvent::Disconnected {
+                addr,
+                session: token,
+                tradeoff,
+                reason,
+            });
+            Ok(())
+        });
 
     // Spawn a new task dedicated to processing the connection
-    handle.spawn(process_connection.and_then(|_| {
-        tick_stopper.send(()).expect("failed to send");
-        Ok(())
-    }));
-    Ok(())
+    handle.spawn(process_connection);
+}
+
+/// What a connection handles: the datums of the client, and the estimates
+/// of the evaluator once ready.
+enum Received {
+    Datum(usize, AsDatum),
+    Estimate(QualityReport),
+    End,
 }
 
 struct Reporter<T: Sink<SinkItem = AsDatum, SinkError = Error>> {
@@ -125,34 +1459,75 @@ struct Reporter<T: Sink<SinkItem = AsDatum, SinkError = Error>> {
     net_latency: StreamingStat,
     app_latency: StreamingStat,
     reporter: T,
+    control: Arc<Mutex<Option<UnboundedSender<AsDatum>>>>,
+    outbox: Arc<Mutex<Vec<AsDatum>>>,
 
     goodput: BwMonitor,
     throughput: BwMonitor,
     latency: LatencyMonitor,
 
     analytics: VideoAnalytics,
+
+    log: ExperimentLog,
+    client: SocketAddr,
+    events: UnboundedSender<ServerEvent>,
+    stats: SessionStats,
+
+    /// Set while frames wait on the application rather than the network,
+    /// and reported to the client so that it doesn't degrade.
+    receiver_limited: Arc<AtomicBool>,
+
+    /// Additional congestion evidence, with weights.
+    signals: Vec<(Box<dyn CongestionSignal>, f64)>,
+
+    /// The lag of the downstream consumer, if probed.
+    lag: Option<LagSignal>,
+
+    /// Timestamps the delay samples fed to `signals`.
+    clock: SystemClock,
 }
 
 impl<T: Sink<SinkItem = AsDatum, SinkError = Error>> Reporter<T> {
     pub fn new(
         reporter: T,
-        goodput: BwMonitor,
-        throughput: BwMonitor,
-        latency: LatencyMonitor,
-        analytics: VideoAnalytics,
+        session: &Session<VideoAnalytics>,
+        log: ExperimentLog,
+        client: SocketAddr,
+        events: UnboundedSender<ServerEvent>,
     ) -> Self {
         Reporter {
             last_report_time: chrono::Utc::now(),
-            net_latency: StreamingStat::new(::std::f64::INFINITY, 10),
-            app_latency: StreamingStat::new(::std::f64::INFINITY, 10),
-            reporter: reporter,
-            goodput: goodput,
-            throughput: throughput,
-            latency: latency,
-            analytics: analytics,
+            net_latency: StreamingStat::new(f64::INFINITY, 10),
+            app_latency: StreamingStat::new(f64::INFINITY, 10),
+            reporter,
+            control: session.control.clone(),
+            outbox: session.outbox.clone(),
+            goodput: session.goodput.clone(),
+            throughput: session.throughput.clone(),
+            latency: session.latency.clone(),
+            analytics: session.analytics.clone(),
+            log,
+            client,
+            events,
+            stats: session.stats.clone(),
+            receiver_limited: Arc::default(),
+            signals: Vec::new(),
+            lag: None,
+            clock: SystemClock::new(),
         }
     }
 
+    /// Also sends feedback when `weight` times the level of `signal` reaches
+    /// 1.
+    pub fn add_signal(&mut self, signal: Box<dyn CongestionSignal>, weight: f64) {
+        self.signals.push((signal, weight));
+    }
+
+    /// Also sends feedback when the consumer's lag holds the session back.
+    pub fn set_lag(&mut self, lag: LagSignal) {
+        self.lag = Some(lag);
+    }
+
     pub fn update_app_latency(&mut self, latency: f64) {
         self.app_latency.add(latency);
     }
@@ -162,19 +1537,36 @@ impl<T: Sink<SinkItem = AsDatum, SinkError = Error>> Reporter<T> {
     }
 
     pub fn update_latency(&mut self, latency: f64) {
-        self.latency.add(latency).expect(
-            &"failed to update latency",
-        );
+        self.latency.add(latency).expect("failed to update latency");
     }
 
-    /// report is called whenever we receive a new datum
-    pub fn report(&mut self, level: usize, frame_num: usize, datum: AsDatum) -> Result<()> {
+    /// report is called whenever we receive a new datum, of `kind`. Returns
+    /// the latency of the datum.
+    pub fn report(&mut self, level: usize, frame_num: usize, datum: &AsDatum, kind: FrameKind) -> Result<f64> {
         let ts = datum.ts;
         let now = chrono::Utc::now();
         let latency = time_diff_in_ms(now, ts);
         self.update_latency(latency);
         self.update_app_latency(latency);
         self.analytics.add(frame_num, level)?;
+        self.stats.add_frame(level, datum.net_len(), latency);
+        let mut entry = FrameEntry {
+            time_ms: now.timestamp_millis(),
+            client: self.client.to_string(),
+            level,
+            frame_num,
+            bytes: datum.net_len(),
+            latency_ms: latency,
+            ground_truth: None,
+            confidence: None,
+            cwnd: None,
+            srtt_ms: None,
+            retransmits: None,
+            key: Some(kind == FrameKind::Key),
+        };
+        entry.annotate(datum.annotation());
+        entry.transport(self.stats.tcp_info());
+        self.log.record(&entry)?;
         trace!(
             "level: {}, latency: {:.1}, size: {}",
             level,
@@ -182,21 +1574,62 @@ impl<T: Sink<SinkItem = AsDatum, SinkError = Error>> Reporter<T> {
             datum.len()
         );
 
-        if self.latency_is_high(latency, &datum) {
+        let at_ms = self.clock.now_ms();
+        for &mut (ref mut signal, _) in &mut self.signals {
+            signal.on_delay(latency, at_ms);
+        }
+        let signalled = self.signals.iter().any(|&(ref s, w)| w * s.level() >= 1.0);
+        if let Some(ref mut lag) = self.lag {
+            lag.update(at_ms);
+        }
+        let lagging = self.lag.as_ref().is_some_and(|lag| lag.pressure() >= 1.0);
+
+        if signalled || lagging || self.latency_is_high(latency, datum) {
             let time_since_last_report = time_diff_in_ms(now, self.last_report_time);
             if time_since_last_report > 500.0 {
                 self.last_report_time = now;
-                let report = ReceiverReport::new(
-                    latency,
-                    self.goodput.rate().unwrap(),
-                    self.throughput.rate().unwrap(),
-                );
+                let throughput = self.throughput.rate().unwrap();
+                let throughput = match self.lag {
+                    Some(ref lag) => lag.limit(throughput),
+                    None => throughput,
+                };
+                // degrading does help a consumer falling behind
+                let receiver_limited = self.receiver_limited.load(Ordering::Relaxed) && !lagging;
+                let report = ReceiverReport::new(latency, self.goodput.rate().unwrap(), throughput)
+                    .with_receiver_limited(receiver_limited);
                 trace!("report {:?}", report);
                 let datum = AsDatum::ack(report)?;
-                self.reporter.start_send(datum)?;
-                self.reporter.poll_complete()?;
+                self.send(datum)?;
+                let _ = self.events.unbounded_send(ServerEvent::FeedbackSent {
+                    addr: self.client,
+                    report,
+                });
             }
         }
+        Ok(latency)
+    }
+
+    /// Sends feedback over the control connection if the session has one,
+    /// and over the data connection otherwise.
+    fn send(&mut self, datum: AsDatum) -> Result<()> {
+        let datum = match *self.control.lock()? {
+            Some(ref tx) => match tx.unbounded_send(datum) {
+                Ok(()) => return Ok(()),
+                Err(e) => e.into_inner(),
+            },
+            None => datum,
+        };
+        self.reporter.start_send(datum)?;
+        self.reporter.poll_complete()?;
+        Ok(())
+    }
+
+    /// Sends the feedback queued for the data connection.
+    fn flush_outbox(&mut self) -> Result<()> {
+        let queued = ::std::mem::take(&mut *self.outbox.lock()?);
+        for datum in queued {
+            self.send(datum)?;
+        }
         Ok(())
     }
 
@@ -204,7 +1637,7 @@ impl<T: Sink<SinkItem = AsDatum, SinkError = Error>> Reporter<T> {
     fn latency_is_high(&self, current_latency: f64, datum: &AsDatum) -> bool {
         // Build a latency model: expected = min_net + size / rate + noise
         let net_delay = self.net_latency.min();
-        let tx_delay = datum.len() as f64 / self.goodput.rate().unwrap();
+        let tx_delay = self.goodput.rate().unwrap().transfer_ms(datum.len());
         let ideal = net_delay + tx_delay;
 
         let expected = match ideal as u64 {
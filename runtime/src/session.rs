@@ -0,0 +1,629 @@
+//! Server-side sessions that survive reconnects.
+//!
+//! A client opens a session with `Hello(None)` and receives a resumption
+//! token in `Welcome`. After a reconnect it presents the token with
+//! `Hello(Some(token))`, and the new connection is re-associated with the
+//! existing session (monitors, analytics, last frame number) instead of
+//! starting an anonymous one.
+
+use super::{AsDatum, Bandwidth, CloseReason, StreamInfo, restarts_numbering};
+use super::bw_monitor::{BwMonitor, LatencyMonitor};
+use super::composition::Composition;
+use super::registry::SessionRegistry;
+use super::tcp_info::TcpInfo;
+use super::tradeoff::{Tradeoff, TradeoffReport};
+use errors::*;
+use futures::sync::mpsc::UnboundedSender;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Detached sessions are forgotten after this long.
+const SESSION_TTL: Duration = Duration::from_secs(60);
+
+/// Frames remembered per session to detect duplicates, by default.
+pub const DEDUP_WINDOW: usize = 1024;
+
+/// Feedback held per session without a connection to send it over; beyond
+/// this, the oldest is dropped.
+const OUTBOX_LIMIT: usize = 256;
+
+/// Live frames whose latency is kept for percentiles.
+const LATENCY_WINDOW: usize = 1000;
+
+/// The frame numbers received most recently, to drop frames that arrive
+/// twice (e.g., a backfilled frame that made it before the outage, or a
+/// redundant copy of a frame that was not lost). Numbers are kept per stream:
+/// the live frames (`None`) and each sub-stream (`Some(id)`), numbered apart.
+/// A stream restarting its numbering (e.g., a looping source) starts afresh.
+#[derive(Debug)]
+pub struct FrameWindow {
+    capacity: usize,
+    seen: HashSet<(Option<u32>, usize)>,
+    order: VecDeque<(Option<u32>, usize)>,
+    /// The number last recorded of each stream.
+    last: HashMap<Option<u32>, usize>,
+}
+
+impl FrameWindow {
+    /// Remembers the last `capacity` frames; 0 remembers none.
+    pub fn new(capacity: usize) -> FrameWindow {
+        FrameWindow {
+            capacity,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+            last: HashMap::new(),
+        }
+    }
+
+    /// Records `seq` of `stream`. Returns false if it is within the window
+    /// already.
+    pub fn insert(&mut self, stream: Option<u32>, seq: usize) -> bool {
+        if self.capacity == 0 {
+            return true;
+        }
+        if let Some(last) = self.last.insert(stream, seq) {
+            if restarts_numbering(last, seq) {
+                self.seen.retain(|&(s, _)| s != stream);
+                self.order.retain(|&(s, _)| s != stream);
+            }
+        }
+        if !self.seen.insert((stream, seq)) {
+            return false;
+        }
+        self.order.push_back((stream, seq));
+        if self.order.len() > self.capacity {
+            if let Some(old) = self.order.pop_front() {
+                self.seen.remove(&old);
+            }
+        }
+        true
+    }
+}
+
+/// Counters of a session, across its connections.
+#[derive(Debug, Clone, Default)]
+pub struct SessionStats {
+    inner: Arc<SessionCounters>,
+}
+
+#[derive(Debug, Default)]
+struct SessionCounters {
+    duplicates: AtomicUsize,
+    regressions: AtomicUsize,
+    rejected: AtomicUsize,
+    sealed: AtomicUsize,
+    digest_mismatches: AtomicUsize,
+    tcp_info: Mutex<Option<TcpInfo>>,
+    composition: Mutex<Option<Composition>>,
+    recent: Mutex<Recent>,
+    tradeoff: Mutex<Tradeoff>,
+    expected_rates: Mutex<Vec<Bandwidth>>,
+    stream_info: Mutex<Option<StreamInfo>>,
+}
+
+/// The last level and latencies of a session.
+#[derive(Debug, Default)]
+struct Recent {
+    level: Option<usize>,
+    latencies: VecDeque<f64>,
+}
+
+impl SessionStats {
+    /// Frames dropped because they were received before.
+    pub fn duplicates(&self) -> usize {
+        self.inner.duplicates.load(Ordering::Relaxed)
+    }
+
+    /// Live frames numbered at or below the last one.
+    pub fn regressions(&self) -> usize {
+        self.inner.regressions.load(Ordering::Relaxed)
+    }
+
+    /// Regressions that were dropped.
+    pub fn rejected(&self) -> usize {
+        self.inner.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Sealed frames verified (see `integrity`).
+    pub fn sealed(&self) -> usize {
+        self.inner.sealed.load(Ordering::Relaxed)
+    }
+
+    /// Sealed frames whose content no longer matched their digest.
+    pub fn digest_mismatches(&self) -> usize {
+        self.inner.digest_mismatches.load(Ordering::Relaxed)
+    }
+
+    /// The last `TCP_INFO` sample of the session's connection, if sampled.
+    pub fn tcp_info(&self) -> Option<TcpInfo> {
+        *self.inner.tcp_info.lock().expect("session stats poisoned")
+    }
+
+    /// Stores a `TCP_INFO` sample.
+    pub fn set_tcp_info(&self, info: TcpInfo) {
+        *self.inner.tcp_info.lock().expect("session stats poisoned") = Some(info);
+    }
+
+    /// The bitrate composition of the last full interval, if any.
+    pub fn composition(&self) -> Option<Composition> {
+        *self.inner.composition.lock().expect("session stats poisoned")
+    }
+
+    /// Stores the composition of an interval.
+    pub fn set_composition(&self, composition: Composition) {
+        *self.inner.composition.lock().expect("session stats poisoned") = Some(composition);
+    }
+
+    /// What the client last announced the stream is, if it did.
+    pub fn stream_info(&self) -> Option<StreamInfo> {
+        self.inner.stream_info.lock().expect("session stats poisoned").clone()
+    }
+
+    /// Stores what the client announced the stream is.
+    pub fn set_stream_info(&self, info: StreamInfo) {
+        *self.inner.stream_info.lock().expect("session stats poisoned") = Some(info);
+    }
+
+    /// The level of the last live frame, if any.
+    pub fn level(&self) -> Option<usize> {
+        self.inner.recent.lock().expect("session stats poisoned").level
+    }
+
+    /// The `p`-th percentile (0 to 100) of the latency of the last
+    /// `LATENCY_WINDOW` live frames, if any.
+    pub fn latency_percentile(&self, p: f64) -> Option<f64> {
+        let recent = self.inner.recent.lock().expect("session stats poisoned");
+        let mut sorted = recent.latencies.iter().cloned().collect::<Vec<_>>();
+        if sorted.is_empty() {
+            return None;
+        }
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let rank = (p.clamp(0.0, 100.0) / 100.0 * (sorted.len() - 1) as f64).round();
+        Some(sorted[rank as usize])
+    }
+
+    /// Records a live frame of `level` and `bytes` delivered after
+    /// `latency_ms`.
+    pub fn add_frame(&self, level: usize, bytes: usize, latency_ms: f64) {
+        let mut recent = self.inner.recent.lock().expect("session stats poisoned");
+        recent.level = Some(level);
+        if recent.latencies.len() == LATENCY_WINDOW {
+            recent.latencies.pop_front();
+        }
+        recent.latencies.push_back(latency_ms);
+        drop(recent);
+        let mut tradeoff = self.inner.tradeoff.lock().expect("session stats poisoned");
+        tradeoff.add_frame(level, bytes, latency_ms);
+    }
+
+    /// Counts `frames` the client sent but that never arrived (by
+    /// `AsDatum::seq`), at the level of the last frame.
+    pub fn add_lost(&self, frames: usize) {
+        self.inner.tradeoff.lock().expect("session stats poisoned").add_lost(frames);
+    }
+
+    /// Sets the rates the profile expects of each level, for `tradeoff`.
+    pub fn set_expected_rates(&self, rates: Vec<Bandwidth>) {
+        *self.inner.expected_rates.lock().expect("session stats poisoned") = rates;
+    }
+
+    /// Time, latency and loss at each level so far.
+    pub fn tradeoff(&self) -> TradeoffReport {
+        let expected = self.inner.expected_rates.lock().expect("session stats poisoned");
+        let tradeoff = self.inner.tradeoff.lock().expect("session stats poisoned");
+        tradeoff.report(&expected)
+    }
+
+    /// Counts a duplicate frame.
+    pub fn add_duplicate(&self) {
+        self.inner.duplicates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a regression, dropped if `rejected`.
+    pub fn add_regression(&self, rejected: bool) {
+        self.inner.regressions.fetch_add(1, Ordering::Relaxed);
+        if rejected {
+            self.inner.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a sealed frame, whose digest matched unless `mismatch`.
+    pub fn add_sealed(&self, mismatch: bool) {
+        self.inner.sealed.fetch_add(1, Ordering::Relaxed);
+        if mismatch {
+            self.inner.digest_mismatches.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The state a session keeps across connections. `A` is the analytics
+/// (`VideoAnalytics` on the server).
+#[derive(Clone)]
+pub struct Session<A> {
+    /// The resumption token.
+    pub token: u64,
+
+    /// Goodput of the session.
+    pub goodput: BwMonitor,
+
+    /// Throughput of the session.
+    pub throughput: BwMonitor,
+
+    /// Latency of the session.
+    pub latency: LatencyMonitor,
+
+    /// Analytics of the session.
+    pub analytics: A,
+
+    /// The last frame number received, shared by all connections.
+    pub last_frame: Arc<Mutex<Option<usize>>>,
+
+    /// The sender's number (`AsDatum::seq`) of the last live frame received,
+    /// shared by all connections.
+    pub last_seq: Arc<Mutex<Option<usize>>>,
+
+    /// The control connection of the session, if the client opened one.
+    pub control: Arc<Mutex<Option<UnboundedSender<AsDatum>>>>,
+
+    /// Counts the control connections attached, identifying the current one.
+    control_id: Arc<AtomicUsize>,
+
+    /// Feedback waiting to be sent over the data connection, for sessions
+    /// without a control connection.
+    pub outbox: Arc<Mutex<Vec<AsDatum>>>,
+
+    /// Closes the connection attached to the session, if any.
+    pub closer: Arc<Mutex<Option<Arc<UnboundedSender<CloseReason>>>>>,
+
+    /// The frames received recently, shared by all connections.
+    pub frames: Arc<Mutex<FrameWindow>>,
+
+    /// Counters of the session.
+    pub stats: SessionStats,
+}
+
+struct Entry<A> {
+    session: Session<A>,
+    attached: bool,
+    detached_at: Instant,
+}
+
+/// All sessions of the server.
+pub struct SessionStore<A> {
+    inner: Arc<Mutex<HashMap<u64, Entry<A>>>>,
+    dedup_window: usize,
+    registry: Arc<Mutex<Option<Arc<dyn SessionRegistry>>>>,
+}
+
+impl<A> Clone for SessionStore<A> {
+    fn clone(&self) -> SessionStore<A> {
+        SessionStore {
+            inner: self.inner.clone(),
+            dedup_window: self.dedup_window,
+            registry: self.registry.clone(),
+        }
+    }
+}
+
+impl<A: Clone> Default for SessionStore<A> {
+    fn default() -> SessionStore<A> {
+        SessionStore::new()
+    }
+}
+
+impl<A: Clone> SessionStore<A> {
+    /// Creates an empty store.
+    pub fn new() -> SessionStore<A> {
+        SessionStore {
+            inner: Arc::new(Mutex::new(HashMap::new())),
+            dedup_window: DEDUP_WINDOW,
+            registry: Arc::default(),
+        }
+    }
+
+    /// Remembers the last `frames` frames of each session to detect
+    /// duplicates (0 disables the detection).
+    pub fn with_dedup_window(mut self, frames: usize) -> SessionStore<A> {
+        self.dedup_window = frames;
+        self
+    }
+
+    /// Resumes the session of `token` if it is known and not attached to
+    /// another connection; otherwise opens a new session with `analytics`.
+    /// Returns the session and whether it was resumed.
+    pub fn open(&self, token: Option<u64>, analytics: A) -> Result<(Session<A>, bool)> {
+        let (session, resumed) = self.open_local(token, analytics)?;
+        if let Some(registry) = self.registry()? {
+            registry.attached(session.token);
+        }
+        Ok((session, resumed))
+    }
+
+    fn open_local(&self, token: Option<u64>, analytics: A) -> Result<(Session<A>, bool)> {
+        let mut sessions = self.inner.lock()?;
+        let now = Instant::now();
+        sessions.retain(|_, e| e.attached || now.duration_since(e.detached_at) < SESSION_TTL);
+
+        if let Some(token) = token {
+            match sessions.get_mut(&token) {
+                Some(ref mut e) if !e.attached => {
+                    e.attached = true;
+                    return Ok((e.session.clone(), true));
+                }
+                Some(_) => warn!("session {:x} is still attached, opening a new one", token),
+                None => info!("unknown or expired session {:x}", token),
+            }
+        }
+
+        let mut token = new_token();
+        while sessions.contains_key(&token) {
+            token = new_token();
+        }
+        let session = Session {
+            token,
+            goodput: BwMonitor::new(),
+            throughput: BwMonitor::new(),
+            latency: LatencyMonitor::new(),
+            analytics,
+            last_frame: Arc::new(Mutex::new(None)),
+            last_seq: Arc::new(Mutex::new(None)),
+            control: Arc::new(Mutex::new(None)),
+            control_id: Arc::new(AtomicUsize::new(0)),
+            outbox: Arc::new(Mutex::new(Vec::new())),
+            closer: Arc::new(Mutex::new(None)),
+            frames: Arc::new(Mutex::new(FrameWindow::new(self.dedup_window))),
+            stats: SessionStats::default(),
+        };
+        sessions.insert(
+            token,
+            Entry {
+                session: session.clone(),
+                attached: true,
+                detached_at: now,
+            },
+        );
+        Ok((session, false))
+    }
+
+    /// Marks the session as detached (its connection ended) so that it can be
+    /// resumed within `SESSION_TTL`.
+    pub fn detach(&self, token: u64) -> Result<()> {
+        if let Some(e) = self.inner.lock()?.get_mut(&token) {
+            e.attached = false;
+            e.detached_at = Instant::now();
+        }
+        if let Some(registry) = self.registry()? {
+            registry.detached(token);
+        }
+        Ok(())
+    }
+
+    /// Routes the feedback of session `token` through `control`, replacing
+    /// the control connection before, if any. Returns the id to
+    /// `detach_control` with, or `None` if the session is unknown.
+    pub fn attach_control(&self, token: u64, control: UnboundedSender<AsDatum>) -> Result<Option<usize>> {
+        match self.inner.lock()?.get(&token) {
+            Some(e) => {
+                let mut attached = e.session.control.lock()?;
+                *attached = Some(control);
+                Ok(Some(e.session.control_id.fetch_add(1, Ordering::SeqCst) + 1))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Routes the feedback of session `token` back through the data
+    /// connection, unless the control connection `id` was replaced since.
+    pub fn detach_control(&self, token: u64, id: usize) -> Result<()> {
+        if let Some(e) = self.inner.lock()?.get(&token) {
+            let mut attached = e.session.control.lock()?;
+            if e.session.control_id.load(Ordering::SeqCst) == id {
+                *attached = None;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<A> SessionStore<A> {
+    /// Shares the sessions with other server instances through `registry`
+    /// (see `registry`). Applies to all clones of the store.
+    pub fn set_registry(&self, registry: Arc<dyn SessionRegistry>) -> Result<()> {
+        *self.registry.lock()? = Some(registry);
+        Ok(())
+    }
+
+    fn registry(&self) -> Result<Option<Arc<dyn SessionRegistry>>> {
+        Ok(self.registry.lock()?.clone())
+    }
+
+    /// All sessions, with whether a connection is attached to each.
+    pub fn list(&self) -> Result<Vec<(Session<A>, bool)>>
+    where
+        A: Clone,
+    {
+        let sessions = self.inner.lock()?;
+        Ok(sessions.values().map(|e| (e.session.clone(), e.attached)).collect())
+    }
+
+    /// Sends `datum` to the client of session `token`: over its control
+    /// connection if it has one, and otherwise over its data connection
+    /// along with the next feedback. Sessions of other server instances are
+    /// reached through the registry, if any. Returns false if the session is
+    /// unknown.
+    pub fn send_feedback(&self, token: u64, datum: AsDatum) -> Result<bool> {
+        if self.deliver(token, datum.clone())? {
+            return Ok(true);
+        }
+        match self.registry()? {
+            Some(registry) => registry.relay(token, datum),
+            None => Ok(false),
+        }
+    }
+
+    /// Closes the connection of session `token` for `reason`. Returns false
+    /// if the session is unknown or no connection is attached to it.
+    pub fn close(&self, token: u64, reason: CloseReason) -> Result<bool> {
+        let sessions = self.inner.lock()?;
+        let closer = match sessions.get(&token) {
+            Some(e) if e.attached => e.session.closer.lock()?,
+            _ => return Ok(false),
+        };
+        Ok(closer.as_ref().is_some_and(|tx| tx.unbounded_send(reason).is_ok()))
+    }
+
+    /// Like `send_feedback`, for the sessions of this instance only.
+    pub fn deliver(&self, token: u64, datum: AsDatum) -> Result<bool> {
+        let sessions = self.inner.lock()?;
+        let session = match sessions.get(&token) {
+            Some(e) => &e.session,
+            None => return Ok(false),
+        };
+        let datum = match *session.control.lock()? {
+            Some(ref tx) => match tx.unbounded_send(datum) {
+                Ok(()) => return Ok(true),
+                Err(e) => e.into_inner(),
+            },
+            None => datum,
+        };
+        let mut outbox = session.outbox.lock()?;
+        if outbox.len() >= OUTBOX_LIMIT {
+            // the oldest feedback is the stalest
+            let dropped = outbox.remove(0);
+            debug!("session {:x} dropped queued feedback: {}", token, dropped);
+        }
+        outbox.push(datum);
+        Ok(true)
+    }
+}
+
+/// Returns an unpredictable token (`RandomState` is randomly keyed).
+fn new_token() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(t) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u64(t.as_secs());
+        hasher.write_u32(t.subsec_nanos());
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Stream;
+
+    #[test]
+    fn test_resume_session() {
+        let store = SessionStore::new();
+        let (first, resumed) = store.open(None, ()).unwrap();
+        assert!(!resumed);
+        *first.last_frame.lock().unwrap() = Some(42);
+
+        // still attached: a second connection cannot steal the session
+        let (other, resumed) = store.open(Some(first.token), ()).unwrap();
+        assert!(!resumed);
+        assert!(other.token != first.token);
+
+        store.detach(first.token).unwrap();
+        let (again, resumed) = store.open(Some(first.token), ()).unwrap();
+        assert!(resumed);
+        assert_eq!(*again.last_frame.lock().unwrap(), Some(42));
+    }
+
+    #[test]
+    fn test_control_channel() {
+        let store = SessionStore::new();
+        let (session, _) = store.open(None, ()).unwrap();
+        let (tx, _rx) = ::futures::sync::mpsc::unbounded();
+        assert_eq!(store.attach_control(session.token + 1, tx.clone()).unwrap(), None);
+        let first = store.attach_control(session.token, tx.clone()).unwrap().unwrap();
+        assert!(session.control.lock().unwrap().is_some());
+        // a reconnect replaces the control connection before the old one closes
+        let second = store.attach_control(session.token, tx).unwrap().unwrap();
+        store.detach_control(session.token, first).unwrap();
+        assert!(session.control.lock().unwrap().is_some());
+        store.detach_control(session.token, second).unwrap();
+        assert!(session.control.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_session_stats() {
+        let store = SessionStore::new();
+        let (session, _) = store.open(None, ()).unwrap();
+        let stats = session.stats.clone();
+        session.stats.add_duplicate();
+        session.stats.add_regression(false);
+        session.stats.add_regression(true);
+        assert_eq!((stats.duplicates(), stats.regressions(), stats.rejected()), (1, 2, 1));
+        session.stats.add_sealed(false);
+        session.stats.add_sealed(true);
+        assert_eq!((stats.sealed(), stats.digest_mismatches()), (2, 1));
+
+        assert_eq!(stats.latency_percentile(50.0), None);
+        for i in 0..=100 {
+            session.stats.add_frame(i % 3, 100, i as f64);
+        }
+        assert_eq!(stats.level(), Some(1));
+        assert_eq!(stats.latency_percentile(50.0), Some(50.0));
+        assert_eq!(stats.latency_percentile(99.0), Some(99.0));
+        assert_eq!(store.list().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_frame_window() {
+        let mut window = FrameWindow::new(2);
+        assert!(window.insert(None, 1));
+        assert!(window.insert(None, 2));
+        assert!(!window.insert(None, 1));
+        assert!(window.insert(None, 3));
+        // 1 fell out of the window
+        assert!(window.insert(None, 1));
+        assert!(!window.insert(None, 3));
+
+        let mut off = FrameWindow::new(0);
+        assert!(off.insert(None, 1));
+        assert!(off.insert(None, 1));
+
+        // sub-streams are numbered apart
+        let mut window = FrameWindow::new(DEDUP_WINDOW);
+        assert!(window.insert(None, 1));
+        assert!(window.insert(Some(7), 1));
+        assert!(!window.insert(Some(7), 1));
+
+        // a looping source sends its frames again
+        for frame_num in 2..100 {
+            assert!(window.insert(None, frame_num));
+        }
+        assert!(window.insert(None, 1));
+        assert!(window.insert(None, 2));
+        assert!(!window.insert(None, 2));
+        assert!(!window.insert(Some(7), 1));
+    }
+
+    #[test]
+    fn test_send_feedback() {
+        let store = SessionStore::new();
+        let (session, _) = store.open(None, ()).unwrap();
+        assert!(!store.send_feedback(session.token + 1, AsDatum::latency_probe()).unwrap());
+
+        // without a control connection, feedback waits for the data connection
+        assert!(store.send_feedback(session.token, AsDatum::latency_probe()).unwrap());
+        assert_eq!(session.outbox.lock().unwrap().len(), 1);
+        // within bounds
+        for _ in 0..OUTBOX_LIMIT {
+            assert!(store.send_feedback(session.token, AsDatum::latency_probe()).unwrap());
+        }
+        assert_eq!(session.outbox.lock().unwrap().len(), OUTBOX_LIMIT);
+        session.outbox.lock().unwrap().truncate(1);
+
+        let (tx, rx) = ::futures::sync::mpsc::unbounded();
+        store.attach_control(session.token, tx).unwrap();
+        assert!(store.send_feedback(session.token, AsDatum::latency_probe()).unwrap());
+        assert_eq!(session.outbox.lock().unwrap().len(), 1);
+        assert!(rx.wait().next().is_some());
+    }
+}
@@ -1,5 +1,29 @@
 //! A flexible client/server runtime setting in TOML.
 
+use super::{CapabilityCheck, CounterMode, FrameLimits, WireFormat};
+use super::barrier::BarrierPolicy;
+use super::codel::CoDelConfig;
+use super::collector::CollectorConfig;
+use super::drop_policy::DropPolicyKind;
+use super::congestion::BudgetConfig;
+#[cfg(feature = "server")]
+use super::consumer_lag::ConsumerLagConfig;
+use super::deadline::DeadlineConfig;
+use super::delta::DeltaConfig;
+use super::dictionary::CompressionConfig;
+use super::external::ExternalPolicyConfig;
+use super::frame_rate::FrameRateEnforcement;
+use super::heartbeat::HeartbeatConfig;
+use super::integrity::IntegrityConfig;
+use super::prediction::PredictionConfig;
+use super::proxy::ProxyConfig;
+#[cfg(feature = "server")]
+use super::registry::RegistryConfig;
+use super::rotation::RotationPeriod;
+use super::tolerance::{SequenceCheck, ToleranceConfig};
+use super::uplink::UplinkProfiles;
+use super::watchdog::WatchdogConfig;
+use super::wrr::MultiplexConfig;
 use std::fs::File;
 use std::io::Read;
 use std::io::Result;
@@ -8,7 +32,8 @@ use toml;
 /// The runtime setting.
 #[derive(Deserialize)]
 pub struct Setting {
-    /// Server's IP address.
+    /// Server's IP address or host name, or `auto` to discover one on the
+    /// local network (requires the `mdns` feature).
     pub server: String,
 
     /// Data connection port.
@@ -22,6 +47,350 @@ pub struct Setting {
 
     /// Path to stat (per frame stat).
     pub stat_path: String,
+
+    /// If set, the client records all controller inputs to this file so that
+    /// its decisions can be replayed offline (see `replay`).
+    #[serde(default)]
+    pub record_path: Option<String>,
+
+    /// If set, the source steps through intermediate levels (one every
+    /// `transition_step_ms`) instead of jumping straight to a new level.
+    #[serde(default)]
+    pub transition_step_ms: Option<u64>,
+
+    /// If set, the server logs every delivered frame (with its accuracy
+    /// annotation) to this CSV file.
+    #[serde(default)]
+    pub experiment_log: Option<String>,
+
+    /// If set, the experiment log is split into a file per `minute` or
+    /// `hour`, each renamed into place once complete (see `rotation`).
+    #[serde(default)]
+    pub experiment_log_rotation: Option<RotationPeriod>,
+
+    /// If set, the server advertises itself on the local network under this
+    /// name (requires the `mdns` feature).
+    #[serde(default)]
+    pub advertise: Option<String>,
+
+    /// Number of server threads serving connections, each with its own
+    /// reactor. Defaults to a single thread.
+    #[serde(default)]
+    pub workers: Option<usize>,
+
+    /// If set, the client sets TCP_NODELAY and instead delays flushes by up
+    /// to this many microseconds to coalesce small frames.
+    #[serde(default)]
+    pub coalesce_us: Option<u64>,
+
+    /// When `server` resolves to several addresses, the client races
+    /// connections to them, starting one every this many milliseconds
+    /// (250 by default, see `happy_eyeballs`).
+    #[serde(default)]
+    pub connect_stagger_ms: Option<u64>,
+
+    /// If set, the client sends heartbeats on the data connection, and
+    /// reserves them this share (0 to 1) of the bytes sent when the send
+    /// queue is saturated (see `spool::Scheduler`).
+    #[serde(default)]
+    pub control_reserve: Option<f64>,
+
+    /// If set, the client spaces its heartbeats (and control connection
+    /// pings) within these bounds by how stable the link is, rather than
+    /// every second (see `heartbeat`).
+    #[serde(default)]
+    pub heartbeat: Option<HeartbeatConfig>,
+
+    /// If set, the client tears down and rebuilds its pipeline when a stage
+    /// makes no progress for a while (see `watchdog`).
+    #[serde(default)]
+    pub watchdog: Option<WatchdogConfig>,
+
+    /// How the client counts the bytes it sends: `per_write` (the default)
+    /// or `batched`, once per flush, for less contention at high frame
+    /// rates.
+    #[serde(default)]
+    pub counter_mode: Option<CounterMode>,
+
+    /// If set, the server also listens on this port for control connections,
+    /// and the client opens one per session, so that feedback and pings are
+    /// never queued behind media.
+    #[serde(default)]
+    pub control_port: Option<u16>,
+
+    /// If set, the client keeps frames it fails to deliver during an outage
+    /// and, after reconnecting, backfills every n-th of them at low priority.
+    #[serde(default)]
+    pub backfill_stride: Option<usize>,
+
+    /// If set, levels are capped while the CPU is busier than this fraction
+    /// (0 to 1) of the time (Linux only).
+    #[serde(default)]
+    pub cpu_limit: Option<f64>,
+
+    /// If set, levels are capped while the hottest thermal zone is above this
+    /// temperature (Celsius, Linux only).
+    #[serde(default)]
+    pub thermal_limit_c: Option<f64>,
+
+    /// If set, the client streams with the profile or level ceiling set for
+    /// the type of its uplink to the server (see `uplink`), e.g.,
+    /// `uplinks = { cellular = { max_level = 2 } }`.
+    #[serde(default)]
+    pub uplinks: Option<UplinkProfiles>,
+
+    /// If set, probe padding re-sends up to this many recent frames instead
+    /// of zeros.
+    #[serde(default)]
+    pub padding_frames: Option<usize>,
+
+    /// If true, naturally large frames (keyframes, scene changes) count
+    /// towards probe padding, and only the shortfall is padded.
+    #[serde(default)]
+    pub burst_probing: Option<bool>,
+
+    /// If set, the client estimates its outgoing rate as this quantile (0 to
+    /// 1, e.g., 0.25) of recent delivery rates instead of their average.
+    #[serde(default)]
+    pub throughput_quantile: Option<f64>,
+
+    /// If set, the server also sends congestion feedback when the one-way
+    /// delay trends upward. The weight scales the detector's sensitivity
+    /// (1 is the default threshold, higher reacts earlier).
+    #[serde(default)]
+    pub delay_gradient_weight: Option<f64>,
+
+    /// With a lag probe set, how the lag of the downstream consumer weighs
+    /// against the network's signals (see `consumer_lag`), e.g.,
+    /// `consumer_lag = { target_ms = 2000.0, weight = 0.5 }`.
+    #[cfg(feature = "server")]
+    #[serde(default)]
+    pub consumer_lag: Option<ConsumerLagConfig>,
+
+    /// If set, frames at `blob_levels` are uploaded to this directory and
+    /// streamed as references.
+    #[serde(default)]
+    pub blob_dir: Option<String>,
+
+    /// The levels whose payloads go to `blob_dir` (e.g., the lowest levels
+    /// of a profile that streams metadata only).
+    #[serde(default)]
+    pub blob_levels: Option<Vec<usize>>,
+
+    /// The frame header layout, if not the default. It is not negotiated:
+    /// both ends must be deployed with the same one (see `wire`).
+    #[serde(default)]
+    pub static_wire_format: Option<WireFormat>,
+
+    /// Caps on the metadata and payload of the frames the client sends; the
+    /// server restores compressed payloads within them (see `dictionary`).
+    #[serde(default)]
+    pub frame_limits: Option<FrameLimits>,
+
+    /// Whether the server scores delivered frames against `profile_path`
+    /// and `stat_path` (default true).
+    #[serde(default)]
+    pub analytics: Option<bool>,
+
+    /// How many malformed frames the server tolerates per connection.
+    #[serde(default)]
+    pub decode_tolerance: Option<ToleranceConfig>,
+
+    /// If set, the server serves its admin endpoint (see `admin`) on this
+    /// port on localhost.
+    #[serde(default)]
+    pub admin_port: Option<u16>,
+
+    /// If set, the server shares its sessions with other instances by
+    /// gossiping with them, so that feedback reaches a client whichever
+    /// instance it connected to (see `registry`).
+    #[cfg(feature = "server")]
+    #[serde(default)]
+    pub registry: Option<RegistryConfig>,
+
+    /// If set, the client streams its live statistics to viewers (e.g.,
+    /// `awstream-top`) connecting to this port on localhost.
+    #[serde(default)]
+    pub stats_port: Option<u16>,
+
+    /// If set, the client appends its statistics every second to this CSV
+    /// file (see `stats`).
+    #[serde(default)]
+    pub stats_log: Option<String>,
+
+    /// If set, the statistics log is split into a file per `minute` or
+    /// `hour`, each renamed into place once complete (see `rotation`).
+    #[serde(default)]
+    pub stats_log_rotation: Option<RotationPeriod>,
+
+    /// If set, the client ships its statistics to a remote collector over a
+    /// secondary stream (see `collector`), e.g.,
+    /// `stats_collector = { address = "collector:8900" }`.
+    #[serde(default)]
+    pub stats_collector: Option<CollectorConfig>,
+
+    /// If set, the client moves the accuracy of its profile towards the
+    /// quality reported by the server's analytics, with this weight per
+    /// report (between 0 and 1).
+    #[serde(default)]
+    pub accuracy_feedback: Option<f64>,
+
+    /// How many recent frames of a session the server remembers to drop
+    /// duplicates (default `session::DEDUP_WINDOW`, 0 disables).
+    #[serde(default)]
+    pub dedup_window: Option<usize>,
+
+    /// If set, the server closes connections (for `idle`) after this many
+    /// ms without a datum.
+    #[serde(default)]
+    pub idle_timeout_ms: Option<u64>,
+
+    /// With an accuracy evaluator set, the server evaluates one in this many
+    /// frames delivered of each session (see `evaluator`).
+    #[serde(default)]
+    pub evaluation_sample_every: Option<usize>,
+
+    /// What the server does with live frames that are not numbered above the
+    /// last one of their session (default `flag`).
+    #[serde(default)]
+    pub sequence_check: Option<SequenceCheck>,
+
+    /// If set, the client drops live frames from the head of its send queue
+    /// while they wait longer than a target.
+    #[serde(default)]
+    pub codel: Option<CoDelConfig>,
+
+    /// If set, the client drops live frames that would reach the server
+    /// later than a deadline after capture, and downgrades; best effort, as
+    /// bytes already in the socket's buffers are not covered (see
+    /// `deadline`).
+    #[serde(default)]
+    pub latency_deadline: Option<DeadlineConfig>,
+
+    /// How the source's frames share the connection with the sub-streams
+    /// added with `Client::add_sub_stream` (see `wrr`).
+    #[serde(default)]
+    pub multiplex: Option<MultiplexConfig>,
+
+    /// What the client does at startup with profile levels beyond the
+    /// source's capabilities (default `reject`).
+    #[serde(default)]
+    pub capability_check: Option<CapabilityCheck>,
+
+    /// If set, the client passes live frames at no more than the frame rate
+    /// of their level, and `drop`s or `delay`s the excess (see
+    /// `frame_rate`). By default, sources are trusted to honor it.
+    #[serde(default)]
+    pub frame_rate_enforcement: Option<FrameRateEnforcement>,
+
+    /// If set, the client downgrades whenever the latency it observes (in
+    /// the receiver's reports and its own queue) stays over this budget,
+    /// regardless of throughput.
+    #[serde(default)]
+    pub latency_budget: Option<BudgetConfig>,
+
+    /// If set, the client predicts the size of upcoming frames (e.g., the
+    /// next keyframe) and downgrades ahead of a burst the link can't drain
+    /// (see `prediction`).
+    #[serde(default)]
+    pub frame_prediction: Option<PredictionConfig>,
+
+    /// If set, the client's levels are decided by an external agent (see
+    /// `ExternalPolicy`), e.g., `external_policy = { socket = "/tmp/agent" }`.
+    #[serde(default)]
+    pub external_policy: Option<ExternalPolicyConfig>,
+
+    /// If set, a barrier separates the frames of two levels on the wire, and
+    /// frames of the old level still queued are flushed or dropped.
+    #[serde(default)]
+    pub barrier: Option<BarrierPolicy>,
+
+    /// The client's identity, for the server to pick its profile (see
+    /// `catalog`).
+    #[serde(default)]
+    pub client_id: Option<String>,
+
+    /// The client's stream type, for the server to pick its profile when it
+    /// has none for the client itself.
+    #[serde(default)]
+    pub stream_type: Option<String>,
+
+    /// The group of the client's streams whose frames the server aligns in
+    /// time, e.g., one per camera of a rig (see `grouping`).
+    #[serde(default)]
+    pub stream_group: Option<String>,
+
+    /// How far apart (ms) the frames of a bundle of a stream group may be
+    /// captured (33 by default).
+    #[serde(default)]
+    pub group_sync_tolerance_ms: Option<u64>,
+
+    /// If set, the server admits sessions only within this aggregate ingest
+    /// rate (kbps), capping or rejecting those whose starting level doesn't
+    /// fit (see `admission`).
+    #[serde(default)]
+    pub ingest_budget_kbps: Option<f64>,
+
+    /// If set, the server hands the profiles in this directory to clients at
+    /// handshake (see `catalog`).
+    #[serde(default)]
+    pub profile_catalog: Option<String>,
+
+    /// If set, the client only applies profiles signed by one of these
+    /// Ed25519 public keys (hex), whether hosted by the server or read from
+    /// `profile_path` (see `signature`).
+    #[serde(default)]
+    pub profile_keys: Option<Vec<String>>,
+
+    /// If set, the client writes its last statistics to this file when it
+    /// panics or exits (see `postmortem`).
+    #[serde(default)]
+    pub stats_snapshot: Option<String>,
+
+    /// If set, the client records the last level it held steadily to this
+    /// file and starts the next run from it (see `warm_start`).
+    #[serde(default)]
+    pub warm_start_path: Option<String>,
+
+    /// If set, the buffers of the client (send queue, spool, barrier) or of
+    /// the server (read buffers) hold at most this many MB together, and
+    /// drop frames under pressure (see `memory`).
+    #[serde(default)]
+    pub memory_budget_mb: Option<usize>,
+
+    /// If set, the client's send queue holds at most this many KB, and
+    /// frames are dropped to make room according to `drop_policy`.
+    #[serde(default)]
+    pub send_queue_kb: Option<usize>,
+
+    /// Which frames the client's send queue drops when full or refused by
+    /// the memory budget: `tail_drop` (the default), `oldest_first`,
+    /// `lowest_priority_first` or `keyframe_preserving`.
+    #[serde(default)]
+    pub drop_policy: Option<DropPolicyKind>,
+
+    /// If set, the client compresses live frames with a dictionary trained
+    /// on the first frames of each connection (requires the `compression`
+    /// feature on both ends, see `dictionary`).
+    #[serde(default)]
+    pub compression: Option<CompressionConfig>,
+
+    /// If set, the client sends the changes of each live frame from the
+    /// previous one of its level, with a full frame now and then and after
+    /// losses (see `delta`). Applied before `compression`.
+    #[serde(default)]
+    pub delta: Option<DeltaConfig>,
+
+    /// If set, the client seals each live frame with a digest of its content
+    /// and the server checks it, keyed if `key` is set on both ends (see
+    /// `integrity`).
+    #[serde(default)]
+    pub frame_integrity: Option<IntegrityConfig>,
+
+    /// If set, the client connects to the server through this SOCKS5 or
+    /// HTTP CONNECT proxy (see `proxy`).
+    #[serde(default)]
+    pub proxy: Option<ProxyConfig>,
 }
 
 impl Setting {
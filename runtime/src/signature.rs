@@ -0,0 +1,229 @@
+//! Ed25519 signatures over profiles.
+//!
+//! A profile fetched from the server or read from shared storage can be
+//! signed offline. With `profile_keys` set, the client applies a profile only
+//! if its signature verifies with one of the keys, so whoever controls the
+//! file server can't push, e.g., a profile that saturates a metered link.
+//! Signatures are detached, next to the profile (`profile.csv.sig`), or
+//! carried along with a hosted profile, as `<version> <hex signature>`.
+//!
+//! A signature covers the profile's name, its version and its body:
+//!
+//! ```text
+//! len(name) (u64, BE) | name | version (u64, BE) | body
+//! ```
+//!
+//! so that a profile signed for one stream can't be served to another, and
+//! the client refuses a version older than one it already applied (a
+//! rollback to a profile since replaced). Verification is `ed25519-dalek`'s
+//! (strict, RFC 8032), behind the `signing` feature.
+
+#[cfg(feature = "signing")]
+use ed25519_dalek::{Signature, VerifyingKey};
+use errors::*;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// The highest version applied so far, per profile name.
+pub type ProfileVersions = Arc<Mutex<HashMap<String, u64>>>;
+
+/// Returns where the detached signature of the file at `path` is kept.
+pub fn signature_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let mut name = path.as_ref().as_os_str().to_owned();
+    name.push(".sig");
+    PathBuf::from(name)
+}
+
+/// The message a signature covers (see the module docs).
+fn signed_message(name: &str, version: u64, body: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(16 + name.len() + body.len());
+    message.extend_from_slice(&(name.len() as u64).to_be_bytes());
+    message.extend_from_slice(name.as_bytes());
+    message.extend_from_slice(&version.to_be_bytes());
+    message.extend_from_slice(body);
+    message
+}
+
+/// Signs version `version` of the profile `name`, in the format of `.sig`
+/// files, with the Ed25519 secret key `secret`.
+#[cfg(feature = "signing")]
+pub fn sign(secret: &[u8; 32], name: &str, version: u64, body: &[u8]) -> String {
+    use ed25519_dalek::{Signer, SigningKey};
+    let signature = SigningKey::from_bytes(secret).sign(&signed_message(name, version, body));
+    let hex: String = signature.to_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{} {}", version, hex)
+}
+
+/// An Ed25519 public key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PublicKey([u8; 32]);
+
+impl PublicKey {
+    /// Parses a key from its 64 hex digits.
+    pub fn from_hex(hex: &str) -> Result<PublicKey> {
+        let bytes = from_hex(hex)?;
+        if bytes.len() != 32 {
+            bail!(ErrorKind::BadSignature(format!("a key has 32 bytes, not {}", bytes.len())));
+        }
+        let mut key = [0; 32];
+        key.copy_from_slice(&bytes);
+        Ok(PublicKey(key))
+    }
+
+    /// Returns true if `signature` (64 bytes) is this key's over `message`.
+    #[cfg(feature = "signing")]
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match (VerifyingKey::from_bytes(&self.0), Signature::from_slice(signature)) {
+            (Ok(key), Ok(signature)) => key.verify_strict(message, &signature).is_ok(),
+            _ => false,
+        }
+    }
+}
+
+/// The keys trusted to sign profiles.
+#[derive(Debug, Clone)]
+pub struct ProfileVerifier {
+    #[cfg_attr(not(feature = "signing"), allow(dead_code))]
+    keys: Vec<PublicKey>,
+    versions: ProfileVersions,
+}
+
+impl ProfileVerifier {
+    /// Trusts the keys given in hex, refusing versions older than those in
+    /// `versions`.
+    pub fn new(hex_keys: &[String], versions: ProfileVersions) -> Result<ProfileVerifier> {
+        if cfg!(not(feature = "signing")) {
+            let reason = "verifying profiles needs the `signing` feature";
+            bail!(ErrorKind::InvalidConfig(reason.into()));
+        }
+        if hex_keys.is_empty() {
+            bail!(ErrorKind::InvalidConfig("no key to verify profiles with".into()));
+        }
+        let keys = hex_keys
+            .iter()
+            .map(|k| PublicKey::from_hex(k))
+            .collect::<Result<_>>()?;
+        Ok(ProfileVerifier { keys, versions })
+    }
+
+    /// Checks that `signature` over the profile `name` is from a trusted key
+    /// and not older than the version last applied; returns its version, to
+    /// `record` once the profile is applied.
+    pub fn verify(&self, name: &str, profile: &[u8], signature: Option<&str>) -> Result<u64> {
+        let (version, signature) = match signature.map(parse_signature) {
+            Some(parsed) => parsed?,
+            None => bail!(ErrorKind::BadSignature("the profile is not signed".into())),
+        };
+        if !self.trusted(&signed_message(name, version, profile), &signature) {
+            let reason = format!("no trusted key signed {} (v{})", name, version);
+            bail!(ErrorKind::BadSignature(reason));
+        }
+        check_version(&*self.versions.lock()?, name, version)?;
+        Ok(version)
+    }
+
+    /// Records `version` of the profile `name` as applied, so that older
+    /// ones are refused from now on.
+    pub fn record(&self, name: &str, version: u64) -> Result<()> {
+        let mut versions = self.versions.lock()?;
+        check_version(&versions, name, version)?;
+        versions.insert(name.to_string(), version);
+        Ok(())
+    }
+
+    /// Checks the file at `path`, named by its stem, against its detached
+    /// signature; returns its name and version.
+    pub fn verify_file<P: AsRef<Path>>(&self, path: P) -> Result<(String, u64)> {
+        let path = path.as_ref();
+        let profile = fs::read(path)?;
+        let signature = fs::read_to_string(signature_path(path)).ok();
+        let name = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+        let version = self.verify(&name, &profile, signature.as_deref())
+            .chain_err(|| format!("profile {:?}", path))?;
+        Ok((name, version))
+    }
+
+    #[cfg(feature = "signing")]
+    fn trusted(&self, message: &[u8], signature: &[u8]) -> bool {
+        self.keys.iter().any(|k| k.verify(message, signature))
+    }
+
+    #[cfg(not(feature = "signing"))]
+    fn trusted(&self, _message: &[u8], _signature: &[u8]) -> bool {
+        false
+    }
+}
+
+/// Refuses `version` of the profile `name` if older than the one applied.
+fn check_version(versions: &HashMap<String, u64>, name: &str, version: u64) -> Result<()> {
+    match versions.get(name) {
+        Some(&applied) if version < applied => {
+            let reason = format!("{} v{} is older than v{}", name, version, applied);
+            bail!(ErrorKind::BadSignature(reason))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Splits `<version> <hex>` into the version and the signature's bytes.
+fn parse_signature(text: &str) -> Result<(u64, Vec<u8>)> {
+    let mut fields = text.split_whitespace();
+    match (fields.next().map(str::parse), fields.next(), fields.next()) {
+        (Some(Ok(version)), Some(hex), None) => Ok((version, from_hex(hex)?)),
+        _ => bail!(ErrorKind::BadSignature("expected `<version> <hex signature>`".into())),
+    }
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    let hex = hex.trim();
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        bail!(ErrorKind::BadSignature("malformed hex".into()));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| ErrorKind::BadSignature("malformed hex".into()).into())
+        })
+        .collect()
+}
+
+#[cfg(all(test, feature = "signing"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_signature() {
+        // RFC 8032, section 7.1, test 1
+        let secret = from_hex("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60");
+        let mut seed = [0; 32];
+        seed.copy_from_slice(&secret.unwrap());
+        let key = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a".to_string();
+        let versions = ProfileVersions::default();
+        let verifier = ProfileVerifier::new(&[key], versions.clone()).unwrap();
+
+        let v2 = sign(&seed, "streams/traffic", 2, b"1,500");
+        assert_eq!(verifier.verify("streams/traffic", b"1,500", Some(&v2)).unwrap(), 2);
+        // recorded only once applied
+        assert!(versions.lock().unwrap().is_empty());
+        verifier.record("streams/traffic", 2).unwrap();
+        assert_eq!(versions.lock().unwrap()["streams/traffic"], 2);
+
+        // altered, signed for another stream, unsigned
+        assert!(verifier.verify("streams/traffic", b"1,900", Some(&v2)).is_err());
+        assert!(verifier.verify("streams/parking", b"1,500", Some(&v2)).is_err());
+        assert!(verifier.verify("streams/traffic", b"1,500", None).is_err());
+        // a validly signed rollback
+        let v1 = sign(&seed, "streams/traffic", 1, b"1,900");
+        assert!(verifier.verify("streams/traffic", b"1,900", Some(&v1)).is_err());
+        let parking = sign(&seed, "streams/parking", 1, b"1,900");
+        assert!(verifier.verify("streams/parking", b"1,900", Some(&parking)).is_ok());
+        assert!(verifier.record("streams/traffic", 1).is_err());
+
+        let other = "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c".to_string();
+        let verifier = ProfileVerifier::new(&[other], ProfileVersions::default()).unwrap();
+        assert!(verifier.verify("streams/traffic", b"1,500", Some(&v2)).is_err());
+    }
+}
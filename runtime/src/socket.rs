@@ -1,26 +1,113 @@
 //! Socket implements `Sink` trait that can keep track of the delivered bytes
 //! for bandwidth estimation.
+//!
+//! The transport only uses the portable socket API of `std` and tokio (no raw
+//! file descriptors), so it builds and runs on Windows as well.
 
 use errors::*;
-use super::{AsCodec, AsDatum};
+use super::{AsCodec, AsDatum, CloseReason, FrameLimits, WireFormat};
+use super::memory::Account;
+use super::ticker::shared_timer;
 use bytes::BytesMut;
-use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream, future};
+use futures::sync::mpsc;
+use std::collections::{BTreeMap, VecDeque};
 use std::{fmt, io};
-use std::io::Write;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
 use tokio_core::net::TcpStream;
-use tokio_io::AsyncRead;
+use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_io::codec::{Decoder, Encoder};
-use tokio_io::io::WriteHalf;
+use tokio_timer::{Sleep, Timer};
+
+/// One half of a `TcpStream`, sharing it with the other half. Unlike the
+/// halves of `tokio_io::io::split`, shutting down a half shuts down the
+/// write side of the connection, so that the peer sees EOF.
+#[derive(Debug, Clone)]
+pub struct TcpHalf(Arc<TcpStream>);
+
+/// Splits `tcp` into a read half and a write half.
+pub fn split(tcp: TcpStream) -> (TcpHalf, TcpHalf) {
+    let tcp = Arc::new(tcp);
+    (TcpHalf(tcp.clone()), TcpHalf(tcp))
+}
+
+impl Read for TcpHalf {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self.0).read(buf)
+    }
+}
+
+impl Write for TcpHalf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self.0).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.0).flush()
+    }
+}
+
+impl AsyncRead for TcpHalf {}
+
+impl AsyncWrite for TcpHalf {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.0.shutdown(Shutdown::Write)?;
+        Ok(Async::Ready(()))
+    }
+}
+
+/// Callbacks on the send path of a `Socket`, e.g., to trace frames or export
+/// metrics. All of them do nothing by default; a socket without hooks skips
+/// them entirely.
+pub trait SocketHooks: Send + Sync {
+    /// `datum` is about to be encoded into the send buffer.
+    fn on_frame_enqueued(&self, _datum: &AsDatum) {}
+
+    /// `n` bytes were written to the connection.
+    fn on_bytes_written(&self, _n: usize) {}
+
+    /// `datum` was dropped because the peer closed the connection.
+    fn on_drop(&self, _datum: &AsDatum) {}
+
+    /// A datum was pushed back because `buffered` bytes still wait to be
+    /// written.
+    fn on_backpressure(&self, _buffered: usize) {}
+}
+
+impl fmt::Debug for dyn SocketHooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SocketHooks")
+    }
+}
+
+/// How a `Socket` updates its counter of bytes sent.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CounterMode {
+    /// After every write (the default).
+    #[default]
+    PerWrite,
+
+    /// Once per flush of the socket, with relaxed ordering: fewer atomic
+    /// operations at high frame rates. The bytes of a flush are counted
+    /// before it returns, so windowed estimates are as accurate.
+    Batched,
+}
 
 /// `Socket` manages sending data over the network with encoder `AsCodec`. When
 /// sending, it updates a counter of `AtomicUsize` so that other monitors can
 /// learn the throughput.
+///
+/// It writes to any `AsyncWrite`, e.g., a TLS stream, a Unix socket or an
+/// in-memory pipe; by default, the write half of a `TcpStream`.
 #[derive(Debug)]
-pub struct Socket {
-    /// The write half of a `TcpStream`, which implements `Sink` interface.
-    net: WriteHalf<TcpStream>,
+pub struct Socket<W = TcpHalf> {
+    /// The transport, whose shutdown closes the connection.
+    net: W,
 
     /// Encoder that teach us how to encode.
     encoder: AsCodec,
@@ -28,60 +115,118 @@ pub struct Socket {
     /// Counter keeps track of bytes sent.
     bytes: Arc<AtomicUsize>,
 
+    /// How `bytes` is updated, and what is yet to be added to it.
+    counter_mode: CounterMode,
+    uncounted: usize,
+
     /// Internal socket buffer.
     buffer: BytesMut,
+
+    /// If set, small writes are delayed to be coalesced.
+    coalesce: Option<Coalesce>,
+
+    /// Set once the peer closed the connection.
+    poison: Poison,
+
+    /// Instrumentation of the send path, if any.
+    hooks: Option<Arc<dyn SocketHooks>>,
+}
+
+/// A handle to mark a `Socket` as poisoned once its peer closed the
+/// connection: new datums are dropped instead of being pushed into a dead
+/// connection, while what is already buffered is still flushed by `close`.
+#[derive(Debug, Clone, Default)]
+pub struct Poison {
+    inner: Arc<AtomicBool>,
+}
+
+impl Poison {
+    /// Marks the socket as poisoned.
+    pub fn poison(&self) {
+        self.inner.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns true once poisoned.
+    pub fn is_poisoned(&self) -> bool {
+        self.inner.load(Ordering::SeqCst)
+    }
+}
+
+/// Bounded delay of flushes, so that small frames are written together with
+/// fewer syscalls (like Nagle's algorithm, but under our control).
+struct Coalesce {
+    delay: Duration,
+    timer: Timer,
+    pending: Option<Sleep>,
+}
+
+impl fmt::Debug for Coalesce {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Coalesce")
+            .field("delay", &self.delay)
+            .field("pending", &self.pending.is_some())
+            .finish()
+    }
 }
 
-impl Socket {
+impl<W: AsyncWrite> Socket<W> {
     /// Send buffer size.
     const INITIAL_CAPACITY: usize = 16 * 1_024;
 
     /// Triggers `poll_complete` if buffered item exceeds the boundary.
-    const BACKPRESSURE_BOUNDARY: usize = Socket::INITIAL_CAPACITY;
+    const BACKPRESSURE_BOUNDARY: usize = Self::INITIAL_CAPACITY;
 
-    /// Creates a new Socket by taking owner ship of the write half of
-    /// TcpStream. Also we return a copy of the counter.
-    pub fn new(tcp: WriteHalf<TcpStream>) -> (Socket, Arc<AtomicUsize>) {
+    /// Creates a new Socket by taking owner ship of the transport `net`
+    /// (e.g., the write half of a TcpStream), framing with `format`. Also we
+    /// return a copy of the counter.
+    pub fn new(net: W, format: WireFormat) -> (Socket<W>, Arc<AtomicUsize>) {
         let counter = Arc::new(AtomicUsize::new(0));
         let socket = Socket {
-            net: tcp,
-            encoder: AsCodec::default(),
+            net,
+            encoder: AsCodec::new(format),
             bytes: counter.clone(),
-            buffer: BytesMut::with_capacity(Socket::INITIAL_CAPACITY),
+            counter_mode: CounterMode::default(),
+            uncounted: 0,
+            buffer: BytesMut::with_capacity(Self::INITIAL_CAPACITY),
+            coalesce: None,
+            poison: Poison::default(),
+            hooks: None,
         };
         (socket, counter)
     }
-}
 
-impl Sink for Socket {
-    type SinkItem = AsDatum;
-    type SinkError = Error;
+    /// Fails to send datums beyond `limits`.
+    pub fn set_frame_limits(&mut self, limits: FrameLimits) {
+        self.encoder.set_limits(limits);
+    }
 
-    fn start_send(&mut self, item: AsDatum) -> StartSend<AsDatum, Error> {
-        // If the buffer is already over 8KiB, then attempt to flush it. If
-        // after flushing it's *still* over 8KiB, then apply backpressure
-        // (reject the send).
-        if self.buffer.len() >= Socket::BACKPRESSURE_BOUNDARY {
-            self.poll_complete()?;
+    /// Updates the counter of bytes sent as `mode` says.
+    pub fn set_counter_mode(&mut self, mode: CounterMode) {
+        self.counter_mode = mode;
+    }
 
-            if self.buffer.len() >= Socket::BACKPRESSURE_BOUNDARY {
-                return Ok(AsyncSink::NotReady(item));
+    /// Counts `n` bytes written.
+    fn count(&mut self, n: usize) {
+        match self.counter_mode {
+            CounterMode::PerWrite => {
+                self.bytes.fetch_add(n, Ordering::SeqCst);
             }
+            CounterMode::Batched => self.uncounted += n,
         }
-
-        self.encoder.encode(item, &mut self.buffer)?;
-
-        Ok(AsyncSink::Ready)
     }
 
-    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+    /// Writes the buffer out, as far as the transport takes it.
+    fn write_buffer(&mut self) -> Poll<(), Error> {
         trace!("flushing socket");
         while !self.buffer.is_empty() {
             trace!("writing; remaining={}", self.buffer.len());
 
             let n = try_nb!(self.net.write(&self.buffer));
 
-            self.bytes.fetch_add(n, Ordering::SeqCst);
+            self.count(n);
+            if let Some(ref h) = self.hooks {
+                h.on_bytes_written(n);
+            }
             info!("complete sending item with size {}", n);
 
             if n == 0 {
@@ -100,34 +245,414 @@ impl Sink for Socket {
         try_nb!(self.net.flush());
 
         trace!("socket packet flushed");
-        return Ok(Async::Ready(()));
+        Ok(Async::Ready(()))
+    }
+
+    /// A handle to poison this socket from other tasks.
+    pub fn poison(&self) -> Poison {
+        self.poison.clone()
+    }
+
+    /// Calls `hooks` on the send path (`None` removes them).
+    pub fn set_hooks(&mut self, hooks: Option<Arc<dyn SocketHooks>>) {
+        self.hooks = hooks;
+    }
+
+    /// Delays flushing by up to `delay` (rounded up to 1 ms) after the first
+    /// buffered frame, unless the buffer fills up. Meant to be used with
+    /// TCP_NODELAY set, so that batching is explicit. `None` flushes
+    /// immediately (the default).
+    pub fn set_coalescing(&mut self, delay: Option<Duration>) {
+        self.coalesce = delay.map(|delay| {
+            Coalesce {
+                delay,
+                timer: shared_timer(),
+                pending: None,
+            }
+        });
+    }
+
+    /// Returns true while a coalescing delay holds back the buffered data.
+    fn hold_back(&mut self) -> Result<bool> {
+        let c = match self.coalesce {
+            Some(ref mut c) => c,
+            None => return Ok(false),
+        };
+        if self.buffer.is_empty() || self.buffer.len() >= Self::BACKPRESSURE_BOUNDARY {
+            c.pending = None;
+            return Ok(false);
+        }
+        if c.pending.is_none() {
+            c.pending = Some(c.timer.sleep(c.delay));
+        }
+        let expired = match c.pending {
+            Some(ref mut sleep) => sleep.poll()?.is_ready(),
+            None => unreachable!(),
+        };
+        if expired {
+            c.pending = None;
+        }
+        Ok(!expired)
+    }
+}
+
+impl<W: AsyncWrite> Sink for Socket<W> {
+    type SinkItem = AsDatum;
+    type SinkError = Error;
+
+    fn start_send(&mut self, item: AsDatum) -> StartSend<AsDatum, Error> {
+        if self.poison.is_poisoned() {
+            debug!("peer closed, dropping {}", item);
+            if let Some(ref h) = self.hooks {
+                h.on_drop(&item);
+            }
+            return Ok(AsyncSink::Ready);
+        }
+
+        // If the buffer is already over 8KiB, then attempt to flush it. If
+        // after flushing it's *still* over 8KiB, then apply backpressure
+        // (reject the send).
+        if self.buffer.len() >= Self::BACKPRESSURE_BOUNDARY {
+            self.poll_complete()?;
+
+            if self.buffer.len() >= Self::BACKPRESSURE_BOUNDARY {
+                if let Some(ref h) = self.hooks {
+                    h.on_backpressure(self.buffer.len());
+                }
+                return Ok(AsyncSink::NotReady(item));
+            }
+        }
+
+        if let Some(ref h) = self.hooks {
+            h.on_frame_enqueued(&item);
+        }
+        self.encoder.encode(item, &mut self.buffer)?;
+
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        if self.hold_back()? {
+            return Ok(Async::NotReady);
+        }
+        let written = self.write_buffer();
+        if self.uncounted > 0 {
+            self.bytes.fetch_add(self.uncounted, Ordering::Relaxed);
+            self.uncounted = 0;
+        }
+        written
+    }
+
+    /// Flushes, then shuts down the write side of the connection.
+    fn close(&mut self) -> Poll<(), Error> {
+        try_ready!(self.poll_complete());
+        try_ready!(self.net.shutdown());
+        Ok(Async::Ready(()))
+    }
+}
+
+/// A cloneable handle to send into a `Socket` shared by several tasks. Each
+/// clone is a producer of its own: the `SharedSocket` driving the socket
+/// takes one datum from each producer in turn, so that a busy producer does
+/// not starve the others, and all producers share its `capacity`.
+#[derive(Debug)]
+pub struct SocketHandle {
+    id: usize,
+    ids: Arc<AtomicUsize>,
+    tx: mpsc::Sender<(usize, AsDatum)>,
+}
+
+impl SocketHandle {
+    /// Shares `socket`, buffering up to `capacity` datums (at least 1) before
+    /// pushing back on the producers. The returned future drives the socket
+    /// and closes it once all handles are dropped and everything is sent.
+    pub fn new<W: AsyncWrite>(socket: Socket<W>, capacity: usize) -> (SocketHandle, SharedSocket<W>) {
+        let capacity = ::std::cmp::max(capacity, 1);
+        let (tx, rx) = mpsc::channel(capacity);
+        let handle = SocketHandle {
+            id: 0,
+            ids: Arc::new(AtomicUsize::new(1)),
+            tx,
+        };
+        let shared = SharedSocket {
+            socket,
+            rx,
+            rx_done: false,
+            queues: BTreeMap::new(),
+            next: 0,
+            buffered: 0,
+            capacity,
+            pending: None,
+        };
+        (handle, shared)
+    }
+}
+
+impl SocketHandle {
+    /// Sends a `Close` datum saying why the connection closes, and drops this
+    /// handle. The connection closes once the other handles are dropped too:
+    /// the `Close` is last if they send nothing more.
+    pub fn close(self, reason: CloseReason) -> Box<dyn Future<Item = (), Error = Error>> {
+        let sent = future::result(AsDatum::close(reason)).and_then(move |datum| self.send(datum));
+        Box::new(sent.map(|_| ()))
+    }
+}
+
+impl Clone for SocketHandle {
+    /// A new producer, interleaved fairly with the others.
+    fn clone(&self) -> SocketHandle {
+        SocketHandle {
+            id: self.ids.fetch_add(1, Ordering::SeqCst),
+            ids: self.ids.clone(),
+            tx: self.tx.clone(),
+        }
+    }
+}
+
+impl Sink for SocketHandle {
+    type SinkItem = AsDatum;
+    type SinkError = Error;
+
+    fn start_send(&mut self, item: AsDatum) -> StartSend<AsDatum, Error> {
+        match self.tx.start_send((self.id, item)) {
+            Ok(AsyncSink::Ready) => Ok(AsyncSink::Ready),
+            Ok(AsyncSink::NotReady((_, item))) => Ok(AsyncSink::NotReady(item)),
+            Err(_) => Err(Error::from_kind(ErrorKind::DataPlane)),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Error> {
+        self.tx.poll_complete().map_err(|_| Error::from_kind(ErrorKind::DataPlane))
+    }
+}
+
+/// Drives a `Socket` shared through `SocketHandle`s, round-robin over the
+/// producers that have datums queued.
+#[derive(Debug)]
+pub struct SharedSocket<W = TcpHalf> {
+    socket: Socket<W>,
+    rx: mpsc::Receiver<(usize, AsDatum)>,
+    rx_done: bool,
+    queues: BTreeMap<usize, VecDeque<AsDatum>>,
+    next: usize,
+    buffered: usize,
+    capacity: usize,
+    pending: Option<AsDatum>,
+}
+
+impl<W> SharedSocket<W> {
+    /// Moves datums from the channel to the queues of their producers, until
+    /// the queues hold `capacity` datums.
+    fn fill(&mut self) {
+        while !self.rx_done && self.buffered < self.capacity {
+            match self.rx.poll() {
+                Ok(Async::Ready(Some((id, datum)))) => {
+                    self.queues.entry(id).or_default().push_back(datum);
+                    self.buffered += 1;
+                }
+                Ok(Async::Ready(None)) | Err(()) => self.rx_done = true,
+                Ok(Async::NotReady) => break,
+            }
+        }
+    }
+
+    /// Takes the next datum of the first producer at or after `next`.
+    fn next_datum(&mut self) -> Option<AsDatum> {
+        let id = self.queues
+            .range(self.next..)
+            .chain(self.queues.range(..self.next))
+            .map(|(id, _)| *id)
+            .next()?;
+        let (datum, empty) = {
+            let queue = self.queues.get_mut(&id).expect("queue exists");
+            (queue.pop_front(), queue.is_empty())
+        };
+        if empty {
+            self.queues.remove(&id);
+        }
+        self.next = id + 1;
+        self.buffered -= 1;
+        datum
+    }
+}
+
+impl<W: AsyncWrite> Future for SharedSocket<W> {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        loop {
+            self.fill();
+            let datum = match self.pending.take().or_else(|| self.next_datum()) {
+                Some(d) => d,
+                None if self.rx_done => return self.socket.close(),
+                None => {
+                    try_ready!(self.socket.poll_complete());
+                    return Ok(Async::NotReady);
+                }
+            };
+            if let AsyncSink::NotReady(d) = self.socket.start_send(datum)? {
+                self.pending = Some(d);
+                try_ready!(self.socket.poll_complete());
+            }
+        }
     }
 }
 
 /// A `Stream` of messages decoded from an `AsyncRead`.
+///
+/// Reads stop at the high watermark: bytes beyond it stay in the kernel's
+/// receive buffer, so a slow consumer pushes back on the sender through TCP
+/// flow control instead of growing this buffer. A buffer that grew to hold a
+/// large frame is released once it drains below the low watermark.
 pub struct FramedRead<T, D> {
+    load: ReadLoad,
     inner: T,
     decoder: D,
     eof: bool,
     is_readable: bool,
     buffer: BytesMut,
+    low: usize,
+    high: usize,
+    account: Option<Account>,
 }
 
 const READ_CAPACITY: usize = 8 * 1024;
 
+/// A decoder that knows how much of the frame in progress is still to
+/// come, so that reads past the high watermark take just the rest of it.
+pub trait Remaining {
+    /// Bytes missing from the frame being decoded, with `buffered` bytes of
+    /// it read.
+    fn remaining(&self, buffered: usize) -> usize;
+}
+
+/// Reads per window below which `ReadLoad` doesn't judge the receiver.
+const MIN_READS: usize = 8;
+
+/// Fraction of full reads above which the receiver is the bottleneck.
+const LIMITED_FRACTION: f64 = 0.9;
+
+/// How full the reads of a `FramedRead` are. When nearly every read fills
+/// all the room below the high watermark, the socket is always readable and
+/// frames wait on the application rather than on the network: the receiver
+/// is the bottleneck. Clones share the counts.
+#[derive(Debug, Clone, Default)]
+pub struct ReadLoad {
+    inner: Arc<Mutex<(usize, usize)>>,
+}
+
+impl ReadLoad {
+    fn record(&self, full: bool) {
+        let mut counts = self.inner.lock().expect("read load poisoned");
+        counts.0 += 1;
+        if full {
+            counts.1 += 1;
+        }
+    }
+
+    /// Returns whether the reads since the last call were nearly all full,
+    /// and starts a new window. With too few reads to judge, returns
+    /// `None`.
+    pub fn take_limited(&self) -> Option<bool> {
+        let (reads, full) = ::std::mem::take(&mut *self.inner.lock().expect("read load poisoned"));
+        if reads < MIN_READS {
+            return None;
+        }
+        Some(full as f64 >= reads as f64 * LIMITED_FRACTION)
+    }
+}
+
 impl<T, D> FramedRead<T, D>
 where
     T: AsyncRead,
-    D: Decoder,
+    D: Decoder + Remaining,
 {
-    /// Creates a new `FramedRead` with the given `decoder`.
+    /// Creates a new `FramedRead` with the given `decoder`, reading as much
+    /// as is available.
     pub fn new(inner: T, decoder: D) -> FramedRead<T, D> {
+        FramedRead::with_watermarks(inner, decoder, READ_CAPACITY, usize::MAX)
+    }
+
+    /// Creates a new `FramedRead` that buffers at most `high` bytes ahead of
+    /// decoding (plus the frame being assembled, if larger), and shrinks its
+    /// buffer when it drains below `low`.
+    pub fn with_watermarks(inner: T, decoder: D, low: usize, high: usize) -> FramedRead<T, D> {
         FramedRead {
-            inner: inner,
-            decoder: decoder,
+            load: ReadLoad::default(),
+            inner,
+            decoder,
             eof: false,
             is_readable: false,
             buffer: BytesMut::with_capacity(READ_CAPACITY),
+            low,
+            high: ::std::cmp::max(high, 1),
+            account: None,
+        }
+    }
+
+    /// Charges the read buffer to a memory budget; under pressure, reads no
+    /// further ahead than the low watermark.
+    pub fn set_budget(&mut self, account: Account) {
+        account.resize(self.buffer.capacity());
+        self.account = Some(account);
+    }
+
+    /// How full the reads are, to detect a slow receiver. Only tracked with
+    /// a high watermark.
+    pub fn load(&self) -> ReadLoad {
+        self.load.clone()
+    }
+
+    /// Reads at most up to the high watermark, or past it, the rest of the
+    /// frame in progress (at least one byte), so that a frame larger than
+    /// the watermark still arrives in a few reads.
+    fn read_some(&mut self) -> Poll<usize, io::Error> {
+        let read = self.read_within();
+        if let Some(ref account) = self.account {
+            account.resize(self.buffer.capacity());
+        }
+        read
+    }
+
+    fn read_within(&mut self) -> Poll<usize, io::Error> {
+        let high = match self.account {
+            Some(ref account) if account.under_pressure() => {
+                ::std::cmp::min(self.high, self.low.max(1))
+            }
+            _ => self.high,
+        };
+        let len = self.buffer.len();
+        if len <= self.low && self.buffer.capacity() > high {
+            // release the memory of an earlier large frame
+            let mut fresh = BytesMut::with_capacity(READ_CAPACITY);
+            fresh.extend_from_slice(&self.buffer);
+            self.buffer = fresh;
+        }
+        if high == usize::MAX {
+            self.buffer.reserve(1);
+            return AsyncRead::read_buf(&mut self.inner, &mut self.buffer);
+        }
+
+        let pending = ::std::cmp::max(self.decoder.remaining(len), 1);
+        let room = ::std::cmp::max(high.saturating_sub(len), pending);
+        let capacity = ::std::cmp::max(READ_CAPACITY, self.buffer.capacity() - len);
+        let room = ::std::cmp::min(room, ::std::cmp::max(capacity, pending));
+        self.buffer.resize(len + room, 0);
+        match self.inner.read(&mut self.buffer[len..]) {
+            Ok(n) => {
+                self.buffer.truncate(len + n);
+                self.load.record(n == room);
+                Ok(Async::Ready(n))
+            }
+            Err(e) => {
+                self.buffer.truncate(len);
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    Ok(Async::NotReady)
+                } else {
+                    Err(e)
+                }
+            }
         }
     }
 }
@@ -135,7 +660,7 @@ where
 impl<T, D> Stream for FramedRead<T, D>
 where
     T: AsyncRead,
-    D: Decoder,
+    D: Decoder + Remaining,
 {
     type Item = D::Item;
     type Error = D::Error;
@@ -167,13 +692,11 @@ where
 
             assert!(!self.eof);
 
-            // Otherwise, try to read more data and try again. Make sure we've
-            // got room for at least one byte to read to ensure that we don't
-            // get a spurious 0 that looks like EOF
-            self.buffer.reserve(1);
+            // Otherwise, try to read more data and try again. `read_some`
+            // makes room for at least one byte to read to ensure that we
+            // don't get a spurious 0 that looks like EOF
             trace!("before read_buf");
-            // if 0 == try_ready!(tokio_io::AsyncRead::read_buf(&mut self.inner, &mut self.buffer)) {
-            if 0 == try_ready!(self.inner.read_buf(&mut self.buffer)) {
+            if 0 == try_ready!(self.read_some()) {
                 self.eof = true;
             }
             trace!("after read_buf");
@@ -198,3 +721,290 @@ where
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use AsDatumType;
+    use futures::{future, stream};
+    use std::net::TcpListener;
+    use std::time::Instant;
+    use tokio_core::reactor::Core;
+
+    /// A reader that records the largest read requested, and counts them.
+    struct Recording {
+        data: io::Cursor<Vec<u8>>,
+        max_read: usize,
+        reads: usize,
+    }
+
+    impl Recording {
+        fn new(data: Vec<u8>) -> Recording {
+            Recording {
+                data: io::Cursor::new(data),
+                max_read: 0,
+                reads: 0,
+            }
+        }
+    }
+
+    impl Read for Recording {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.max_read = ::std::cmp::max(self.max_read, buf.len());
+            self.reads += 1;
+            self.data.read(buf)
+        }
+    }
+
+    impl AsyncRead for Recording {}
+
+    #[test]
+    fn test_watermarks_bound_reads() {
+        let mut data = BytesMut::new();
+        let mut codec = AsCodec::default();
+        for i in 0..20 {
+            codec.encode(AsDatum::new(0, i, vec![0; 1000]), &mut data).unwrap();
+        }
+        let reader = Recording::new(data.to_vec());
+        let mut framed = FramedRead::with_watermarks(reader, AsCodec::default(), 512, 4096);
+        let frames = framed.by_ref().wait().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(frames.len(), 20);
+        assert!(framed.inner.max_read <= 4096);
+    }
+
+    #[test]
+    fn test_large_frame_past_the_watermark() {
+        let mut data = BytesMut::new();
+        AsCodec::default().encode(AsDatum::new(0, 0, vec![0; 1 << 20]), &mut data).unwrap();
+        let reader = Recording::new(data.to_vec());
+        let mut framed = FramedRead::with_watermarks(reader, AsCodec::default(), 512, 4096);
+        let frames = framed.by_ref().wait().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(frames[0].mem.len(), 1 << 20);
+        // the rest of the frame at once, not a byte per read
+        assert!(framed.inner.reads <= 4);
+    }
+
+    /// A reader that returns at most `chunk` bytes per read.
+    struct Trickle {
+        data: io::Cursor<Vec<u8>>,
+        chunk: usize,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = ::std::cmp::min(buf.len(), self.chunk);
+            self.data.read(&mut buf[..n])
+        }
+    }
+
+    impl AsyncRead for Trickle {}
+
+    #[test]
+    fn test_read_load_detects_slow_receiver() {
+        let mut data = BytesMut::new();
+        let mut codec = AsCodec::default();
+        for i in 0..100 {
+            codec.encode(AsDatum::new(0, i, vec![0; 1000]), &mut data).unwrap();
+        }
+        let load_of = |chunk| {
+            let reader = Trickle {
+                data: io::Cursor::new(data.to_vec()),
+                chunk,
+            };
+            let mut framed = FramedRead::with_watermarks(reader, AsCodec::default(), 512, 4096);
+            let load = framed.load();
+            assert!(load.take_limited().is_none());
+            let frames = framed.by_ref().take(90).wait().count();
+            assert_eq!(frames, 90);
+            load.take_limited()
+        };
+        // backed-up data fills every read; arriving data doesn't
+        assert_eq!(load_of(usize::MAX), Some(true));
+        assert_eq!(load_of(100), Some(false));
+    }
+
+    #[test]
+    fn test_socket_over_memory() {
+        let (socket, bytes) = Socket::new(io::Cursor::new(Vec::new()), WireFormat::default());
+        let frames = (0..3).map(|i| AsDatum::new(0, i, vec![0; 100])).collect::<Vec<_>>();
+        let (socket, _) = socket.send_all(stream::iter_ok::<_, Error>(frames)).wait().unwrap();
+        let sent = socket.net.into_inner();
+        assert_eq!(bytes.load(Ordering::SeqCst), sent.len());
+
+        let received = FramedRead::new(io::Cursor::new(sent), AsCodec::default())
+            .map(|d| d.datum_type())
+            .collect()
+            .wait()
+            .unwrap();
+        let live = AsDatumType::Live;
+        assert_eq!(received, vec![live(0, 0), live(0, 1), live(0, 2)]);
+    }
+
+    /// A writer that takes at most `chunk` bytes per write and blocks every
+    /// other write.
+    struct Choppy {
+        written: Vec<u8>,
+        chunk: usize,
+        blocked: bool,
+    }
+
+    impl Write for Choppy {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.blocked = !self.blocked;
+            if !self.blocked {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = ::std::cmp::min(buf.len(), self.chunk);
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncWrite for Choppy {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn test_batched_counter_is_current_after_each_flush() {
+        let w = Choppy {
+            written: Vec::new(),
+            chunk: 100,
+            blocked: false,
+        };
+        let (mut socket, bytes) = Socket::new(w, WireFormat::default());
+        socket.set_counter_mode(CounterMode::Batched);
+        for i in 0..5 {
+            socket.start_send(AsDatum::new(0, i, vec![0; 150])).unwrap();
+        }
+        let mut polls = 0;
+        while socket.poll_complete().unwrap().is_not_ready() {
+            assert_eq!(bytes.load(Ordering::SeqCst), socket.net.written.len());
+            polls += 1;
+        }
+        assert!(polls > 1);
+        assert_eq!(bytes.load(Ordering::SeqCst), socket.net.written.len());
+        assert!(socket.buffer.is_empty());
+    }
+
+    #[test]
+    fn test_poisoned_socket_flushes_and_closes() {
+        let mut core = Core::new().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tcp = core.run(TcpStream::connect(&addr, &core.handle())).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        let (_, w) = split(tcp);
+        let (socket, bytes) = Socket::new(w, WireFormat::default());
+
+        let socket = core.run(socket.send(AsDatum::latency_probe())).unwrap();
+        let sent = bytes.load(Ordering::SeqCst);
+        assert!(sent > 0);
+
+        socket.poison().poison();
+        let mut socket = core.run(socket.send(AsDatum::new(0, 0, vec![0; 100]))).unwrap();
+        core.run(future::poll_fn(|| socket.close())).unwrap();
+        assert_eq!(bytes.load(Ordering::SeqCst), sent);
+
+        // the peer reads what was sent before, then EOF
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).unwrap();
+        assert_eq!(received.len(), sent);
+    }
+
+    #[test]
+    fn test_shared_socket_interleaves() {
+        let mut core = Core::new().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tcp = core.run(TcpStream::connect(&addr, &core.handle())).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        let (_, w) = split(tcp);
+        let (socket, _) = Socket::new(w, WireFormat::default());
+
+        let (a, shared) = SocketHandle::new(socket, 16);
+        let b = a.clone();
+        // a queues all its datums before b, yet doesn't go first
+        let frames = |level, n| {
+            stream::iter_ok::<_, Error>((0..n).map(move |i| AsDatum::new(level, i, vec![0; 10])))
+        };
+        let a = core.run(a.send_all(frames(0, 4))).unwrap().0;
+        let b = core.run(b.send_all(frames(1, 2))).unwrap().0;
+        drop((a, b));
+        core.run(shared).unwrap();
+
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).unwrap();
+        let mut buf = BytesMut::from(received);
+        let mut codec = AsCodec::default();
+        let mut order = Vec::new();
+        while let Some(datum) = codec.decode(&mut buf).unwrap() {
+            order.push(datum.datum_type());
+        }
+        let live = AsDatumType::Live;
+        let expected = vec![live(0, 0), live(1, 0), live(0, 1), live(1, 1), live(0, 2), live(0, 3)];
+        assert_eq!(order, expected);
+    }
+
+    /// Counts the calls of each hook.
+    #[derive(Default)]
+    struct Counting {
+        enqueued: AtomicUsize,
+        written: AtomicUsize,
+        dropped: AtomicUsize,
+    }
+
+    impl SocketHooks for Counting {
+        fn on_frame_enqueued(&self, _datum: &AsDatum) {
+            self.enqueued.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_bytes_written(&self, n: usize) {
+            self.written.fetch_add(n, Ordering::SeqCst);
+        }
+
+        fn on_drop(&self, _datum: &AsDatum) {
+            self.dropped.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_hooks() {
+        let mut core = Core::new().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tcp = core.run(TcpStream::connect(&addr, &core.handle())).unwrap();
+        let (_, w) = split(tcp);
+        let (mut socket, bytes) = Socket::new(w, WireFormat::default());
+        let hooks = Arc::new(Counting::default());
+        socket.set_hooks(Some(hooks.clone()));
+
+        let socket = core.run(socket.send(AsDatum::latency_probe())).unwrap();
+        socket.poison().poison();
+        core.run(socket.send(AsDatum::latency_probe())).unwrap();
+        assert_eq!(hooks.enqueued.load(Ordering::SeqCst), 1);
+        assert_eq!(hooks.written.load(Ordering::SeqCst), bytes.load(Ordering::SeqCst));
+        assert_eq!(hooks.dropped.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_coalescing_delays_small_writes() {
+        let mut core = Core::new().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tcp = core.run(TcpStream::connect(&addr, &core.handle())).unwrap();
+        let (_, w) = split(tcp);
+        let (mut socket, bytes) = Socket::new(w, WireFormat::default());
+        socket.set_coalescing(Some(Duration::from_millis(50)));
+
+        let start = Instant::now();
+        core.run(socket.send(AsDatum::latency_probe())).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        assert!(bytes.load(Ordering::SeqCst) > 0);
+    }
+}
@@ -1,23 +1,592 @@
-use super::{Adapt, AdaptAction, AsDatum, Experiment};
+//! Sources produce the frames to stream. A `Source` is polled by the runtime
+//! from the reactor thread; the driver spawned by `spawn` applies level
+//! changes, interleaves probes and accounts produced bytes for the monitor.
+
+use super::{Adapt, AdaptAction, Annotation, AsDatum, AsDatumType, Bandwidth, Capabilities,
+            CapabilityCheck, Experiment, Hint, StreamInfo, restarts_numbering};
 use super::adaptation::Signal;
-use super::queue::ReceiverCtl;
+use super::decision::{SharedClock, SystemClock};
+use super::frame_rate::{FrameRateEnforcement, FrameRateGate};
+use super::profile::SimpleProfile;
+use super::queue::{ReceiverCtl, SenderCtl};
 use super::queue::queue;
-use futures::Stream;
+use super::ticker::Ticker;
+use errors::*;
+use futures::{Async, Future, Poll, Stream};
 use futures::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use futures::task::AtomicTask;
+use futures_cpupool::{CpuFuture, CpuPool};
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::time::Duration;
 use tokio_core::reactor::Handle;
-use tokio_timer;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 type SourceCtrl = (UnboundedSender<AdaptAction>, UnboundedReceiver<Signal>);
 type SourceData = ReceiverCtl;
 type SourceStat = Arc<AtomicUsize>;
 
-pub type Source = (SourceCtrl, SourceData, SourceStat);
+pub type SourceHandles = (SourceCtrl, SourceData, SourceStat);
+
+/// An asynchronous source of frames.
+///
+/// `poll_frame` is called on the reactor thread and must not block. Sources
+/// backed by hardware capture or IPC should register interest and return
+/// `Async::NotReady` (like any `Stream`), or be wrapped in `BlockingSource`.
+pub trait Source: Adapt {
+    /// Attempts to produce the next frame. `Ready(None)` ends the stream.
+    fn poll_frame(&mut self) -> Poll<Option<AsDatum>, Error>;
+
+    /// The highest level this source can produce in real time, if limited
+    /// (e.g., by the CPU cost of encoding). Checked whenever the source is
+    /// polled; changes are forwarded to the controller.
+    fn encoder_limit(&self) -> Option<usize> {
+        None
+    }
+
+    /// What this source can produce, checked against every level of the
+    /// profile at startup (see `Setting::capability_check`). Unlimited by
+    /// default.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    /// Called with what the receiver is interested in (e.g., to crop to its
+    /// regions of interest), whenever it changes. Ignored by default.
+    fn on_hint(&mut self, _hint: &Hint) {}
+}
+
+impl<A: Adapt + ?Sized> Adapt for Box<A> {
+    fn adapt(&mut self, bandwidth: Bandwidth) {
+        (**self).adapt(bandwidth)
+    }
+
+    fn dec_degradation(&mut self) {
+        (**self).dec_degradation()
+    }
+
+    fn set_level(&mut self, level: usize) {
+        (**self).set_level(level)
+    }
+
+    fn try_set_level(&mut self, level: usize) -> Result<()> {
+        (**self).try_set_level(level)
+    }
+
+    fn period_in_ms(&self) -> u64 {
+        (**self).period_in_ms()
+    }
+
+    fn current_level(&self) -> usize {
+        (**self).current_level()
+    }
+
+    fn simple_profile(&self) -> SimpleProfile {
+        (**self).simple_profile()
+    }
+
+    fn observe_accuracy(&mut self, level: usize, quality: f64, weight: f64) {
+        (**self).observe_accuracy(level, quality, weight)
+    }
+
+    fn frame_rate(&self, level: usize) -> Option<f64> {
+        (**self).frame_rate(level)
+    }
+
+    fn restrict(&mut self, caps: &Capabilities, check: CapabilityCheck) -> Result<Vec<usize>> {
+        (**self).restrict(caps, check)
+    }
+
+    fn replace_profile(&mut self, csv: &str) -> Result<()> {
+        (**self).replace_profile(csv)
+    }
+
+    fn stream_info(&self) -> Option<StreamInfo> {
+        (**self).stream_info()
+    }
+}
+
+impl<S: Source + ?Sized> Source for Box<S> {
+    fn poll_frame(&mut self) -> Poll<Option<AsDatum>, Error> {
+        (**self).poll_frame()
+    }
+
+    fn encoder_limit(&self) -> Option<usize> {
+        (**self).encoder_limit()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        (**self).capabilities()
+    }
+
+    fn on_hint(&mut self, hint: &Hint) {
+        (**self).on_hint(hint)
+    }
+}
+
+/// Decides what probe padding carries. Probing only needs the bytes to
+/// occupy the link, so instead of zeros they can carry data that is useful to
+/// the receiver.
+pub trait PaddingPolicy {
+    /// Observes each live frame before it is sent.
+    fn observe(&mut self, _frame: &AsDatum) {}
+
+    /// Returns datums totalling about `size` bytes of padding.
+    fn pad(&mut self, size: usize) -> Vec<AsDatum>;
+}
+
+/// Pads with zeros (the default).
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ZeroPadding;
+
+impl PaddingPolicy for ZeroPadding {
+    fn pad(&mut self, size: usize) -> Vec<AsDatum> {
+        vec![AsDatum::bw_probe(size)]
+    }
+}
+
+/// Pads with redundant copies of recent frames (newest first, each at most
+/// once), and zeros for what they don't fill.
+#[derive(Debug)]
+pub struct RecentFrames {
+    frames: ::std::collections::VecDeque<AsDatum>,
+    capacity: usize,
+}
+
+impl RecentFrames {
+    /// Keeps up to `capacity` recent frames for padding.
+    pub fn new(capacity: usize) -> RecentFrames {
+        RecentFrames {
+            frames: ::std::collections::VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+}
+
+impl PaddingPolicy for RecentFrames {
+    fn observe(&mut self, frame: &AsDatum) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.frames.len() == self.capacity {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(frame.clone());
+    }
+
+    fn pad(&mut self, size: usize) -> Vec<AsDatum> {
+        let mut left = size;
+        let mut padding = Vec::new();
+        let mut i = self.frames.len();
+        while i > 0 {
+            i -= 1;
+            if self.frames[i].net_len() <= left {
+                let frame = self.frames.remove(i).expect("index in range");
+                left -= frame.net_len();
+                padding.push(frame.into_redundant());
+            }
+        }
+        if left > 0 {
+            padding.push(AsDatum::bw_probe(left));
+        }
+        padding
+    }
+}
+
+/// Weight of a new frame in the average frame size of `NaturalBursts`.
+const BURST_ALPHA: f64 = 1.0 / 16.0;
+
+/// Counts naturally large frames (keyframes, scene changes) as probes: the
+/// bytes by which frames exceeded the average size since the last tick are
+/// taken off the padding, and only the shortfall is padded by `fallback`.
+/// Whether the bursts inflated the queue is measured as for any probe, so the
+/// upgrade still waits for the probe to pass, with less synthetic padding.
+pub struct NaturalBursts {
+    fallback: Box<dyn PaddingPolicy>,
+    level: Option<usize>,
+    average: f64,
+    excess: usize,
+}
+
+impl NaturalBursts {
+    /// Pads what bursts don't cover with `fallback`.
+    pub fn new(fallback: Box<dyn PaddingPolicy>) -> NaturalBursts {
+        NaturalBursts {
+            fallback,
+            level: None,
+            average: 0.0,
+            excess: 0,
+        }
+    }
+}
+
+impl PaddingPolicy for NaturalBursts {
+    fn observe(&mut self, frame: &AsDatum) {
+        self.fallback.observe(frame);
+        let level = match frame.datum_type() {
+            AsDatumType::Live(level, _) => level,
+            _ => return,
+        };
+        let len = frame.net_len() as f64;
+        // frame sizes of another level say nothing about this one
+        if self.level != Some(level) {
+            self.level = Some(level);
+            self.average = len;
+            return;
+        }
+        if len > self.average {
+            self.excess += (len - self.average) as usize;
+        }
+        self.average += BURST_ALPHA * (len - self.average);
+    }
 
-pub struct TimerSource;
+    fn pad(&mut self, size: usize) -> Vec<AsDatum> {
+        let covered = ::std::cmp::min(size, self.excess);
+        // a burst only stands in for the tick it was sent in
+        self.excess = 0;
+        if covered > 0 {
+            debug!("burst covers {} of {} bytes of padding", covered, size);
+        }
+        if covered == size {
+            return Vec::new();
+        }
+        self.fallback.pad(size - covered)
+    }
+}
+
+/// Cooperative cancellation shared between the runtime and a source. The
+/// runtime stops polling a cancelled source; long-running sources can check
+/// `is_cancelled` to abandon work early.
+#[derive(Clone, Default)]
+pub struct Cancellation {
+    inner: Arc<CancelInner>,
+}
+
+#[derive(Default)]
+struct CancelInner {
+    cancelled: AtomicBool,
+    task: AtomicTask,
+    parent: Option<Arc<CancelInner>>,
+}
+
+impl Cancellation {
+    /// Creates a token that is not cancelled.
+    pub fn new() -> Cancellation {
+        Cancellation::default()
+    }
+
+    /// Cancels and wakes up the task polling the source.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        self.inner.task.notify();
+    }
+
+    /// A token cancelled with this one, which can also be cancelled on its
+    /// own, e.g., for one attempt of a task this one cancels.
+    pub fn child(&self) -> Cancellation {
+        Cancellation {
+            inner: Arc::new(CancelInner {
+                parent: Some(self.inner.clone()),
+                ..CancelInner::default()
+            }),
+        }
+    }
+
+    /// Returns true once `cancel` has been called (on this token or the one
+    /// it is a child of).
+    pub fn is_cancelled(&self) -> bool {
+        let mut inner = Some(&self.inner);
+        while let Some(i) = inner {
+            if i.cancelled.load(Ordering::SeqCst) {
+                return true;
+            }
+            inner = i.parent.as_ref();
+        }
+        false
+    }
+
+    /// Like `is_cancelled`, but also registers the current task to be woken
+    /// up on cancellation. Must be called from within a task.
+    pub fn poll_cancelled(&self) -> bool {
+        let mut inner = Some(&self.inner);
+        while let Some(i) = inner {
+            i.task.register();
+            inner = i.parent.as_ref();
+        }
+        self.is_cancelled()
+    }
+}
+
+/// `Paced` turns an `Experiment` into a `Source` by asking for a datum every
+/// `period_in_ms`. The datum is a zero-filled payload of the reported size.
+pub struct Paced<E> {
+    inner: E,
+    timer: Ticker,
+}
+
+impl<E: Adapt> Paced<E> {
+    /// Wraps `inner`, ticking at its period.
+    pub fn new(inner: E) -> Paced<E> {
+        Paced::with_clock(inner, Arc::new(SystemClock::new()))
+    }
+
+    /// Wraps `inner`, ticking at its period of `clock`.
+    pub fn with_clock(inner: E, clock: SharedClock) -> Paced<E> {
+        let timer = Ticker::new(clock, Duration::from_millis(inner.period_in_ms()));
+        Paced { inner, timer }
+    }
+}
+
+impl<E: Adapt> Adapt for Paced<E> {
+    fn adapt(&mut self, bandwidth: Bandwidth) {
+        self.inner.adapt(bandwidth)
+    }
+
+    fn dec_degradation(&mut self) {
+        self.inner.dec_degradation()
+    }
+
+    fn set_level(&mut self, level: usize) {
+        self.inner.set_level(level)
+    }
+
+    fn try_set_level(&mut self, level: usize) -> Result<()> {
+        self.inner.try_set_level(level)
+    }
+
+    fn period_in_ms(&self) -> u64 {
+        self.inner.period_in_ms()
+    }
+
+    fn current_level(&self) -> usize {
+        self.inner.current_level()
+    }
+
+    fn simple_profile(&self) -> SimpleProfile {
+        self.inner.simple_profile()
+    }
+
+    fn observe_accuracy(&mut self, level: usize, quality: f64, weight: f64) {
+        self.inner.observe_accuracy(level, quality, weight)
+    }
+
+    fn frame_rate(&self, level: usize) -> Option<f64> {
+        self.inner.frame_rate(level)
+    }
+
+    fn restrict(&mut self, caps: &Capabilities, check: CapabilityCheck) -> Result<Vec<usize>> {
+        self.inner.restrict(caps, check)
+    }
+
+    fn replace_profile(&mut self, csv: &str) -> Result<()> {
+        self.inner.replace_profile(csv)
+    }
+
+    fn stream_info(&self) -> Option<StreamInfo> {
+        self.inner.stream_info()
+    }
+}
+
+impl<E: Adapt + Experiment> Source for Paced<E> {
+    fn poll_frame(&mut self) -> Poll<Option<AsDatum>, Error> {
+        loop {
+            if try_ready!(self.timer.poll()).is_none() {
+                return Ok(Async::Ready(None));
+            }
+            let (size, frame_num) = self.inner.next_datum();
+            if size == 0 {
+                continue;
+            }
+            let level = self.inner.current_level();
+            let mut datum = AsDatum::new(level, frame_num, vec![0; size]);
+            if let Some(a) = self.inner.annotation(frame_num) {
+                datum = datum.with_annotation(a);
+            }
+            return Ok(Async::Ready(Some(datum)));
+        }
+    }
+}
+
+/// Level changes requested while a `BlockingSource` is busy producing.
+enum Deferred {
+    Rate(Bandwidth),
+    DecDegradation,
+    Level(usize),
+    Accuracy(usize, f64, f64),
+}
+
+/// A datum produced on the pool.
+struct Produced {
+    level: usize,
+    frame_num: usize,
+    size: usize,
+    annotation: Option<Annotation>,
+}
+
+/// `BlockingSource` runs an `Experiment` whose `next_datum` blocks (e.g., it
+/// waits on a capture device) on a thread pool so that the reactor thread
+/// stays responsive. Level changes are deferred and applied on the pool
+/// right before the next datum is produced.
+pub struct BlockingSource<E> {
+    inner: Arc<Mutex<E>>,
+    deferred: Arc<Mutex<Vec<Deferred>>>,
+    pool: CpuPool,
+    pending: Option<CpuFuture<Option<Produced>, Error>>,
+    cancel: Cancellation,
+    period: u64,
+    level: usize,
+    /// The frame rate of each level, read whenever the profile changes.
+    rates: Vec<Option<f64>>,
+}
+
+/// The frame rate of each level of `source`.
+fn frame_rates<A: Adapt>(source: &A) -> Vec<Option<f64>> {
+    (0..source.simple_profile().num_levels()).map(|l| source.frame_rate(l)).collect()
+}
+
+impl<E: Adapt + Experiment + Send + 'static> BlockingSource<E> {
+    /// Wraps `inner`, running it on `pool` until `cancel` fires.
+    pub fn new(inner: E, pool: CpuPool, cancel: Cancellation) -> BlockingSource<E> {
+        BlockingSource {
+            period: inner.period_in_ms(),
+            level: inner.current_level(),
+            rates: frame_rates(&inner),
+            inner: Arc::new(Mutex::new(inner)),
+            deferred: Arc::new(Mutex::new(Vec::new())),
+            pool,
+            pending: None,
+            cancel,
+        }
+    }
+
+    fn defer(&self, d: Deferred) {
+        self.deferred.lock().expect("blocking source poisoned").push(d);
+    }
+
+    fn produce(&self) -> CpuFuture<Option<Produced>, Error> {
+        let inner = self.inner.clone();
+        let deferred = self.deferred.clone();
+        let cancel = self.cancel.clone();
+        self.pool.spawn_fn(move || {
+            if cancel.is_cancelled() {
+                return Ok(None);
+            }
+            let mut source = inner.lock()?;
+            for d in deferred.lock()?.drain(..) {
+                match d {
+                    Deferred::Rate(bw) => source.adapt(bw),
+                    Deferred::DecDegradation => source.dec_degradation(),
+                    Deferred::Level(l) => source.set_level(l),
+                    Deferred::Accuracy(l, q, w) => source.observe_accuracy(l, q, w),
+                }
+            }
+            let (size, frame_num) = source.next_datum();
+            Ok(Some(Produced {
+                level: source.current_level(),
+                frame_num,
+                size,
+                annotation: source.annotation(frame_num),
+            }))
+        })
+    }
+}
+
+impl<E: Adapt + Experiment + Send + 'static> Adapt for BlockingSource<E> {
+    fn adapt(&mut self, bandwidth: Bandwidth) {
+        self.defer(Deferred::Rate(bandwidth))
+    }
+
+    fn dec_degradation(&mut self) {
+        self.defer(Deferred::DecDegradation)
+    }
+
+    fn set_level(&mut self, level: usize) {
+        self.level = level;
+        self.defer(Deferred::Level(level))
+    }
+
+    fn period_in_ms(&self) -> u64 {
+        self.period
+    }
+
+    /// The level of the last produced datum, or the last requested level.
+    fn current_level(&self) -> usize {
+        self.level
+    }
+
+    /// Locks the inner source; only meant to be used at startup.
+    fn simple_profile(&self) -> SimpleProfile {
+        self.inner
+            .lock()
+            .expect("blocking source poisoned")
+            .simple_profile()
+    }
+
+    fn observe_accuracy(&mut self, level: usize, quality: f64, weight: f64) {
+        self.defer(Deferred::Accuracy(level, quality, weight))
+    }
+
+    /// As of the last profile set through this source.
+    fn frame_rate(&self, level: usize) -> Option<f64> {
+        self.rates.get(level).copied().flatten()
+    }
+
+    /// Locks the inner source; only meant to be used at startup.
+    fn restrict(&mut self, caps: &Capabilities, check: CapabilityCheck) -> Result<Vec<usize>> {
+        let mut inner = self.inner.lock()?;
+        let masked = inner.restrict(caps, check)?;
+        self.level = inner.current_level();
+        self.rates = frame_rates(&*inner);
+        Ok(masked)
+    }
+
+    /// Locks the inner source; only meant to be used at startup.
+    fn replace_profile(&mut self, csv: &str) -> Result<()> {
+        let mut inner = self.inner.lock()?;
+        inner.replace_profile(csv)?;
+        self.level = inner.current_level();
+        self.rates = frame_rates(&*inner);
+        Ok(())
+    }
+
+    /// Unknown while the inner source is producing, rather than block.
+    fn stream_info(&self) -> Option<StreamInfo> {
+        self.inner.try_lock().ok()?.stream_info()
+    }
+}
+
+impl<E: Adapt + Experiment + Send + 'static> Source for BlockingSource<E> {
+    fn poll_frame(&mut self) -> Poll<Option<AsDatum>, Error> {
+        loop {
+            if self.pending.is_none() {
+                if self.cancel.is_cancelled() {
+                    return Ok(Async::Ready(None));
+                }
+                self.pending = Some(self.produce());
+            }
+            let produced = match self.pending.as_mut() {
+                Some(p) => try_ready!(p.poll()),
+                None => unreachable!(),
+            };
+            self.pending = None;
+            let produced = match produced {
+                Some(p) => p,
+                None => return Ok(Async::Ready(None)),
+            };
+            self.level = produced.level;
+            if produced.size == 0 {
+                continue;
+            }
+            let mut datum = AsDatum::new(produced.level, produced.frame_num, vec![0; produced.size]);
+            if let Some(a) = produced.annotation {
+                datum = datum.with_annotation(a);
+            }
+            return Ok(Async::Ready(Some(datum)));
+        }
+    }
+}
 
 /// `ProbeTracker` controls the probing behavior. The core function is `next`
 /// that returns an `Option<AsDatum>`, it is either a probe datum, or indicates
@@ -26,16 +595,16 @@ pub struct TimerSource;
 /// Probing is evenly spaced in each tick within a second. So complication of
 /// this data type is due to the calculation of a proper rate. See `start_probe`
 /// for details.
-struct ProbeTracker {
+pub(crate) struct ProbeTracker {
     /// We need to know the tick_period to calculate how large each probe packet
     /// is for a even distribution.
     pub tick_period: u64,
 
     /// The target probe bandwidth.
-    pub target_in_kbps: f64,
+    pub target: Bandwidth,
 
     /// The target pace, i.e. packet size for each tick. This is derived from
-    /// `target_in_kbps`.
+    /// `target`.
     pub target_pace: usize,
 
     /// The pace, i.e. the current packet size for each tick.
@@ -48,20 +617,20 @@ struct ProbeTracker {
 const NUM_PROBE_REQUIRED: usize = 3;
 
 impl ProbeTracker {
-    fn new(tick_period: u64) -> ProbeTracker {
+    pub fn new(tick_period: u64) -> ProbeTracker {
         ProbeTracker {
-            tick_period: tick_period,
-            target_in_kbps: 0.0,
+            tick_period,
+            target: Bandwidth::ZERO,
             target_pace: 0,
             delta: 0,
             pace: 0,
         }
     }
 
-    pub fn start_probe(&mut self, additional_kbps: f64) {
-        self.target_in_kbps = additional_kbps;
+    pub fn start_probe(&mut self, additional: Bandwidth) {
+        self.target = additional;
 
-        let bytes_per_sec = self.target_in_kbps * 1000.0 / 8.0;
+        let bytes_per_sec = self.target.bytes_per_sec();
         let ticks_per_sec = 1000.0 / self.tick_period as f64;
         let size_per_tick = bytes_per_sec / ticks_per_sec;
         self.target_pace = size_per_tick as usize;
@@ -73,7 +642,7 @@ impl ProbeTracker {
     /// Probing is the additive increase phase (as AIMD in TCP).
     pub fn inc_pace(&mut self) -> bool {
         if self.pace < self.target_pace {
-            self.pace = self.pace + self.delta;
+            self.pace += self.delta;
             true
         } else {
             false
@@ -81,115 +650,647 @@ impl ProbeTracker {
     }
 
     pub fn stop_probe(&mut self) {
-        self.target_in_kbps = 0.0;
+        self.target = Bandwidth::ZERO;
         self.target_pace = 0;
         self.pace = 0;
         self.delta = 0;
     }
 
-    fn next(&self) -> Option<AsDatum> {
+    /// The size of the padding due this tick, if probing.
+    pub fn next(&self) -> Option<usize> {
         if self.target_pace > 0 {
-            Some(AsDatum::bw_probe(self.pace))
+            Some(self.pace)
         } else {
             None
         }
     }
 }
 
-enum Incoming {
-    Timer,
-    Adapt(AdaptAction),
-}
+/// How the source moves to a new target level.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transition {
+    /// Jumps straight to the target level.
+    Immediate,
 
-impl TimerSource {
-    pub fn spawn<As>(mut source: As, handle: Handle) -> Source
-    where
-        As: Adapt + Experiment + 'static,
-    {
-        let timer_tick = source.period_in_ms();
-        let timer = tokio_timer::wheel()
-            .tick_duration(Duration::from_millis(1))
-            .build()
-            .interval(Duration::from_millis(timer_tick))
-            .map_err(|_e| ())
-            .map(|_e| Incoming::Timer);
+    /// Moves one level at a time (both down and up), at most once per step
+    /// interval. Avoids jarring quality jumps for human viewers.
+    Gradual(Duration),
+}
 
-        let (adapt_tx, adapt_rx) = unbounded();
-        let adapter = adapt_rx.map(|level| Incoming::Adapt(level));
+impl Transition {
+    /// `Gradual` with the given step (in ms), or `Immediate` if not set.
+    pub fn from_step_ms(step_ms: Option<u64>) -> Transition {
+        match step_ms {
+            Some(ms) => Transition::Gradual(Duration::from_millis(ms)),
+            None => Transition::Immediate,
+        }
+    }
+}
 
-        let (data_tx, data_rx) = queue();
-        let counter = Arc::new(AtomicUsize::new(0));
-        let counter_clone = counter.clone();
+/// `LevelTransition` tracks an ongoing move towards a target level.
+struct LevelTransition {
+    mode: Transition,
+    target: Option<usize>,
+    last_step: Option<u64>,
+}
 
-        let mut prober = ProbeTracker::new(timer_tick);
-        let (probe_tx, probe_rx) = unbounded();
+impl LevelTransition {
+    fn new(mode: Transition) -> LevelTransition {
+        LevelTransition {
+            mode,
+            target: None,
+            last_step: None,
+        }
+    }
 
-        let mut ticks = 0;
-        let one_second_ticks = 1000 / timer_tick;
+    fn set_target(&mut self, target: usize) {
+        self.target = Some(target);
+    }
 
-        let work = timer.select(adapter).for_each(
-            move |incoming| match incoming {
-                Incoming::Timer => {
-                    ticks += 1;
+    /// Gives up on the current target.
+    fn cancel(&mut self) {
+        self.target = None;
+    }
 
-                    // when one sec, send probe_rtt
-                    if ticks == one_second_ticks {
-                        let p = AsDatum::latency_probe();
-                        counter_clone.fetch_add(p.net_len(), Ordering::SeqCst);
-                        data_tx.send(p).map(|_| ()).map_err(|_| ()).expect(
-                            "failed to send probing latency packet",
-                        );
-                        ticks = 0;
+    /// Returns the level to move to at `now_ms`, if any.
+    fn next_step(&mut self, current: usize, now_ms: u64) -> Option<usize> {
+        let target = self.target?;
+        if target == current {
+            self.target = None;
+            return None;
+        }
+        let next = match self.mode {
+            Transition::Immediate => target,
+            Transition::Gradual(step) => {
+                if let Some(last) = self.last_step {
+                    if now_ms < last + step.as_millis() as u64 {
+                        return None;
                     }
+                }
+                if target > current {
+                    current + 1
+                } else {
+                    current - 1
+                }
+            }
+        };
+        self.last_step = Some(now_ms);
+        if next == target {
+            self.target = None;
+        }
+        Some(next)
+    }
+}
 
-                    let (size, frame_num) = source.next_datum();
-                    if size == 0 {
-                        return Ok(());
-                    }
+/// A rejected level is retried after this long, doubling per rejection.
+const LEVEL_RETRY_MS: u64 = 30 * 1000;
 
-                    if let Some(p) = prober.next() {
-                        counter_clone.fetch_add(p.net_len(), Ordering::SeqCst);
-                        data_tx.send(p).map(|_| ()).map_err(|_| ()).expect(
-                            "failed to send probing packet",
-                        );
-                    }
+/// At most this many doublings of `LEVEL_RETRY_MS`.
+const LEVEL_RETRY_DOUBLINGS: u32 = 4;
+
+/// Levels the source failed to switch to, and when to retry them.
+#[derive(Debug, Default)]
+struct Rejections {
+    /// Per level, the rejections so far and the pending retry, if any.
+    levels: HashMap<usize, (u32, Option<u64>)>,
+}
 
-                    let level = source.current_level();
-                    let data_to_send = AsDatum::new(level, frame_num, vec![0; size]);
-                    // info!("add new, level: {}, size: {}", level, size);
-                    let send_ts = SystemTime::now().duration_since(UNIX_EPOCH).expect("").as_millis();
-                    info!("send frame frame_no: {} size: {} ts: {:?} level: {}", frame_num, size, send_ts, level);
-                    counter_clone.fetch_add(data_to_send.net_len(), Ordering::SeqCst);
-                    data_tx.send(data_to_send).map(|_| ()).map_err(|_| ())
+impl Rejections {
+    /// Records that `level` was rejected at `now_ms`.
+    fn reject(&mut self, level: usize, now_ms: u64) {
+        let entry = self.levels.entry(level).or_insert((0, None));
+        let backoff = LEVEL_RETRY_MS << cmp::min(entry.0, LEVEL_RETRY_DOUBLINGS);
+        entry.0 += 1;
+        entry.1 = Some(now_ms + backoff);
+    }
+
+    /// Returns the levels due for a retry at `now_ms`.
+    fn due(&mut self, now_ms: u64) -> Vec<usize> {
+        let mut due = Vec::new();
+        for (&level, entry) in &mut self.levels {
+            match entry.1 {
+                Some(at) if at <= now_ms => {
+                    entry.1 = None;
+                    due.push(level);
                 }
-                Incoming::Adapt(AdaptAction::ToRate(rate)) => {
-                    prober.stop_probe();
-                    source.adapt(rate);
-                    Ok(())
+                _ => {}
+            }
+        }
+        due.sort_unstable();
+        due
+    }
+}
+
+/// `Driver` polls a `Source` and feeds the data plane.
+struct Driver<S> {
+    source: S,
+    adapt_rx: UnboundedReceiver<AdaptAction>,
+    hints: UnboundedReceiver<Hint>,
+    probe_tx: UnboundedSender<Signal>,
+    data_tx: SenderCtl,
+    produced: Arc<AtomicUsize>,
+    prober: ProbeTracker,
+    padding: Box<dyn PaddingPolicy>,
+    transition: LevelTransition,
+    clock: SharedClock,
+    latency_timer: Ticker,
+    cancel: Cancellation,
+    encoder_limit: Option<usize>,
+    rejections: Rejections,
+    /// Whether a barrier precedes the first frame of each new level.
+    barriers: bool,
+    last_level: Option<usize>,
+    /// The number of the last live frame, and the levels of the profile.
+    last_frame: Option<usize>,
+    /// Live frames sent so far, numbering the next (`AsDatum::seq`).
+    sent_frames: usize,
+    num_levels: usize,
+    /// Passes live frames at the rate of their level, if enforced.
+    frame_rate: Option<FrameRateGate>,
+    /// What the server was last told the stream is.
+    announced: Option<StreamInfo>,
+}
+
+/// Interval between two latency probes.
+const LATENCY_PROBE_INTERVAL: u64 = 1000;
+
+impl<S: Source> Driver<S> {
+    fn send(&self, datum: AsDatum) -> Result<()> {
+        self.produced.fetch_add(datum.net_len(), Ordering::SeqCst);
+        self.data_tx.send(datum)
+    }
+
+    fn signal(&self, signal: Signal) -> Result<()> {
+        self.probe_tx
+            .unbounded_send(signal)
+            .map_err(|_| Error::from_kind(ErrorKind::ControlPlane))
+    }
+
+    fn react(&mut self, action: AdaptAction) -> Result<()> {
+        match action {
+            AdaptAction::ToRate(rate) => {
+                self.prober.stop_probe();
+                self.source.adapt(rate);
+            }
+            AdaptAction::DecreaseDegradation => {
+                self.prober.stop_probe();
+                self.source.dec_degradation();
+            }
+            AdaptAction::ToLevel(level) => {
+                self.prober.stop_probe();
+                self.transition.set_target(level);
+            }
+            AdaptAction::StartProbe(target) => {
+                self.prober.start_probe(target);
+            }
+            AdaptAction::IncreaseProbePace => {
+                if !self.prober.inc_pace() {
+                    self.signal(Signal::ProbeDone)?;
+                }
+            }
+            AdaptAction::StopProbe => {
+                self.prober.stop_probe();
+            }
+            AdaptAction::ObserveAccuracy(level, quality, weight) => {
+                self.source.observe_accuracy(level, quality, weight);
+            }
+        }
+        Ok(())
+    }
+
+    fn on_frame(&mut self, frame: AsDatum) -> Result<()> {
+        if let Some(size) = self.prober.next() {
+            for p in self.padding.pad(size) {
+                self.send(p)?;
+            }
+        }
+        if let AsDatumType::Live(..) | AsDatumType::Reference(..) = frame.datum_type() {
+            if let Some(info) = self.source.stream_info() {
+                if self.announced.as_ref() != Some(&info) {
+                    info!("stream info {:?}", info);
+                    self.send(AsDatum::stream_info(&info)?)?;
+                    self.announced = Some(info);
                 }
-                Incoming::Adapt(AdaptAction::DecreaseDegradation) => {
-                    prober.stop_probe();
-                    source.dec_degradation();
-                    Ok(())
+            }
+        }
+        let frame = match frame.datum_type() {
+            AsDatumType::Live(..) | AsDatumType::Reference(..) => {
+                self.sent_frames += 1;
+                frame.with_seq(self.sent_frames - 1)
+            }
+            _ => frame,
+        };
+        if let AsDatumType::Live(level, _) | AsDatumType::Reference(level, _) = frame.datum_type() {
+            if self.barriers && self.last_level.is_some_and(|l| l != level) {
+                self.send(AsDatum::barrier(level))?;
+            }
+            self.last_level = Some(level);
+        }
+        // references stand for offloaded frames (see `blob`)
+        if let AsDatumType::Live(level, frame_num) | AsDatumType::Reference(level, frame_num) = frame.datum_type() {
+            strict_assert!(
+                self.last_frame
+                    .is_none_or(|last| frame_num > last || restarts_numbering(last, frame_num)),
+                "frame {} (level {}) after frame {:?}",
+                frame_num,
+                level,
+                self.last_frame
+            );
+            strict_assert!(level < self.num_levels, "frame {} at level {} of {}", frame_num, level, self.num_levels);
+            self.last_frame = Some(frame_num);
+            // padding replays media, never a reference to it
+            if let AsDatumType::Live(..) = frame.datum_type() {
+                self.padding.observe(&frame);
+            }
+            let send_ts = SystemTime::now().duration_since(UNIX_EPOCH).expect("").as_millis();
+            info!(
+                "send frame frame_no: {} size: {} ts: {:?} level: {}",
+                frame_num,
+                frame.mem.len(),
+                send_ts,
+                level
+            );
+        }
+        self.send(frame)
+    }
+
+    fn poll_inner(&mut self) -> Poll<(), Error> {
+        if self.cancel.poll_cancelled() {
+            info!("source cancelled");
+            return Ok(Async::Ready(()));
+        }
+
+        while let Async::Ready(Some(action)) = self.adapt_rx.poll().map_err(|_| {
+            Error::from_kind(ErrorKind::ControlPlane)
+        })?
+        {
+            self.react(action)?;
+        }
+
+        while let Async::Ready(Some(hint)) = self.hints.poll().map_err(|_| {
+            Error::from_kind(ErrorKind::ControlPlane)
+        })?
+        {
+            self.source.on_hint(&hint);
+        }
+
+        let limit = self.source.encoder_limit();
+        if limit != self.encoder_limit {
+            self.encoder_limit = limit;
+            // a lifted limit is reported as no limit at all
+            let max = limit.unwrap_or(usize::MAX);
+            self.signal(Signal::EncoderLimit(max))?;
+        }
+
+        let now = self.clock.now_ms();
+        let current = self.source.current_level();
+        if let Some(l) = self.transition.next_step(current, now) {
+            if let Err(e) = self.source.try_set_level(l) {
+                // the controller picks another level
+                warn!("source rejected level {}: {}", l, e);
+                self.transition.cancel();
+                self.rejections.reject(l, now);
+                self.signal(Signal::LevelAvailable(l, false))?;
+            }
+        }
+        for l in self.rejections.due(now) {
+            self.signal(Signal::LevelAvailable(l, true))?;
+        }
+
+        while let Async::Ready(Some(_)) = self.latency_timer.poll()? {
+            self.send(AsDatum::latency_probe())?;
+        }
+
+        loop {
+            if let Some(ref mut gate) = self.frame_rate {
+                if let Async::Ready(Some(frame)) = gate.poll_held()? {
+                    self.on_frame(frame)?;
                 }
-                Incoming::Adapt(AdaptAction::StartProbe(target_in_kbps)) => {
-                    prober.start_probe(target_in_kbps);
-                    Ok(())
+            }
+            match self.source.poll_frame()? {
+                Async::Ready(Some(frame)) => {
+                    let frame = match self.frame_rate {
+                        Some(ref mut gate) => {
+                            let fps = match frame.datum_type() {
+                                AsDatumType::Live(level, _) | AsDatumType::Reference(level, _) => {
+                                    self.source.frame_rate(level)
+                                }
+                                _ => None,
+                            };
+                            gate.offer(frame, fps)
+                        }
+                        None => Some(frame),
+                    };
+                    if let Some(frame) = frame {
+                        self.on_frame(frame)?;
+                    }
                 }
-                Incoming::Adapt(AdaptAction::IncreaseProbePace) => {
-                    if !prober.inc_pace() {
-                        probe_tx.unbounded_send(Signal::ProbeDone).unwrap();
+                Async::Ready(None) => {
+                    if let Some(frame) = self.frame_rate.as_mut().and_then(FrameRateGate::take_held) {
+                        self.on_frame(frame)?;
                     }
-                    Ok(())
+                    return Ok(Async::Ready(()));
                 }
-                Incoming::Adapt(AdaptAction::StopProbe) => {
-                    prober.stop_probe();
-                    Ok(())
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+impl<S: Source> Future for Driver<S> {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        self.poll_inner().map_err(|e| error!("source stopped: {}", e))
+    }
+}
+
+/// Spawns a task on `handle` that drives `source` until it ends or `cancel`
+/// fires, probing with `padding` and timing with `clock`, and passing on `hints`. With
+/// `barriers`, a `Barrier` datum precedes the first frame of each new level. With
+/// `frame_rate`, live frames beyond the rate of their level are discarded or delayed.
+/// Returns the control channels, the data queue and the counter of produced bytes.
+#[allow(clippy::too_many_arguments)]
+pub fn spawn<S>(
+    source: S,
+    handle: &Handle,
+    transition: Transition,
+    cancel: Cancellation,
+    padding: Box<dyn PaddingPolicy>,
+    clock: SharedClock,
+    hints: UnboundedReceiver<Hint>,
+    barriers: bool,
+    frame_rate: Option<FrameRateEnforcement>,
+) -> SourceHandles
+where
+    S: Source + 'static,
+{
+    let (adapt_tx, adapt_rx) = unbounded();
+    let (probe_tx, probe_rx) = unbounded();
+    let (data_tx, data_rx) = queue();
+    let counter = Arc::new(AtomicUsize::new(0));
+
+    let latency_timer = Ticker::new(clock.clone(), Duration::from_millis(LATENCY_PROBE_INTERVAL));
+    let num_levels = source.simple_profile().num_levels();
+    let frame_rate = frame_rate.map(|mode| FrameRateGate::new(mode, clock.clone()));
+
+    let driver = Driver {
+        prober: ProbeTracker::new(source.period_in_ms()),
+        num_levels,
+        padding,
+        source,
+        adapt_rx,
+        hints,
+        probe_tx,
+        data_tx,
+        produced: counter.clone(),
+        transition: LevelTransition::new(transition),
+        clock,
+        latency_timer,
+        cancel,
+        encoder_limit: None,
+        rejections: Rejections::default(),
+        barriers,
+        last_level: None,
+        last_frame: None,
+        sent_frames: 0,
+        frame_rate,
+        announced: None,
+    };
+    handle.spawn(driver);
+
+    ((adapt_tx, probe_rx), data_rx, counter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use decision::ManualClock;
+    use futures::future;
+    use profile::{Profile, Record};
+    use tokio_core::reactor::Core;
+
+    struct Counting {
+        level: usize,
+        frame: usize,
+    }
+
+    impl Adapt for Counting {
+        fn adapt(&mut self, _bandwidth: Bandwidth) {}
+        fn dec_degradation(&mut self) {}
+        fn set_level(&mut self, level: usize) {
+            self.level = level;
+        }
+        fn period_in_ms(&self) -> u64 {
+            10
+        }
+        fn current_level(&self) -> usize {
+            self.level
+        }
+        fn simple_profile(&self) -> SimpleProfile {
+            Profile::_with_vec(vec![Record::new(Bandwidth::from_kbps(1.0), (), 0.0)]).simplify()
+        }
+    }
+
+    impl Experiment for Counting {
+        fn next_datum(&mut self) -> (usize, usize) {
+            self.frame += 1;
+            (100, self.frame)
+        }
+    }
+
+    #[test]
+    fn test_blocking_source_runs_on_pool() {
+        let cancel = Cancellation::new();
+        let counting = Counting { level: 0, frame: 0 };
+        let mut src = BlockingSource::new(counting, CpuPool::new(1), cancel.clone());
+
+        let first = future::poll_fn(|| src.poll_frame()).wait().unwrap().unwrap();
+        assert_eq!(first.datum_type(), AsDatumType::Live(0, 1));
+
+        // level changes are applied before the next datum
+        src.set_level(2);
+        let second = future::poll_fn(|| src.poll_frame()).wait().unwrap().unwrap();
+        assert_eq!(second.datum_type(), AsDatumType::Live(2, 2));
+
+        cancel.cancel();
+        assert!(future::poll_fn(|| src.poll_frame()).wait().unwrap().is_none());
+    }
+
+    /// Produces live frames at `levels`, announcing one resolution per two
+    /// levels.
+    struct Scripted {
+        levels: Vec<usize>,
+        sent: usize,
+    }
+
+    impl Adapt for Scripted {
+        fn adapt(&mut self, _bandwidth: Bandwidth) {}
+        fn dec_degradation(&mut self) {}
+        fn set_level(&mut self, _level: usize) {}
+        fn period_in_ms(&self) -> u64 {
+            10
+        }
+        fn current_level(&self) -> usize {
+            self.levels[self.sent.saturating_sub(1)]
+        }
+        fn simple_profile(&self) -> SimpleProfile {
+            let records = (0..3).map(|l| Record::new(Bandwidth::from_kbps(l as f64 + 1.0), (), 0.0)).collect();
+            Profile::_with_vec(records).simplify()
+        }
+        fn stream_info(&self) -> Option<StreamInfo> {
+            Some(StreamInfo {
+                resolution: Some(format!("{}w", 320 << (self.current_level() / 2))),
+                ..StreamInfo::default()
+            })
+        }
+    }
+
+    impl Source for Scripted {
+        fn poll_frame(&mut self) -> Poll<Option<AsDatum>, Error> {
+            match self.levels.get(self.sent) {
+                Some(&level) => {
+                    self.sent += 1;
+                    Ok(Async::Ready(Some(AsDatum::new(level, self.sent, vec![0; 10]))))
                 }
-            },
+                None => Ok(Async::Ready(None)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_announces_stream_info_on_change() {
+        let mut core = Core::new().unwrap();
+        let source = Scripted {
+            levels: vec![0, 0, 1, 2, 2],
+            sent: 0,
+        };
+        let (_hints_tx, hints) = unbounded();
+        let clock = Arc::new(SystemClock::new());
+        let padding = Box::new(ZeroPadding);
+        let (_, data, _) = spawn(source, &core.handle(), Transition::Immediate, Cancellation::new(), padding, clock, hints, false, None);
+        let sent = core.run(data.collect()).unwrap();
+        let sent = sent
+            .iter()
+            .filter(|d| d.datum_type() != AsDatumType::LatencyProbe)
+            .map(|d| match d.datum_type() {
+                AsDatumType::StreamInfo => StreamInfo::from_mem(&d.mem).unwrap().resolution.unwrap(),
+                t => format!("{:?}", t),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sent,
+            vec!["320w", "Live(0, 1)", "Live(0, 2)", "Live(1, 3)", "640w", "Live(2, 4)", "Live(2, 5)"]
         );
-        handle.spawn(work);
+    }
+
+    #[test]
+    fn test_paced_follows_clock() {
+        let clock = ManualClock::new(0);
+        let counting = Counting { level: 0, frame: 0 };
+        let mut paced = Paced::with_clock(counting, Arc::new(clock.clone()));
+        let mut poll = || future::lazy(|| Ok::<_, ()>(paced.poll_frame().unwrap())).wait().unwrap();
+        assert!(poll().is_not_ready());
+
+        // one frame per period of 10 ms
+        clock.advance(25);
+        let frames = (0..3).map(|_| poll()).collect::<Vec<_>>();
+        assert!(frames[0].is_ready() && frames[1].is_ready());
+        assert!(frames[2].is_not_ready());
+    }
+
+    #[test]
+    fn test_gradual_transition_steps_one_level_per_interval() {
+        let step = 100;
+        let mut t = LevelTransition::new(Transition::from_step_ms(Some(step)));
+        let start = 5000;
+
+        t.set_target(1);
+        assert_eq!(t.next_step(4, start), Some(3));
+        assert_eq!(t.next_step(3, start + step / 2), None);
+        assert_eq!(t.next_step(3, start + step), Some(2));
+        assert_eq!(t.next_step(2, start + step * 2), Some(1));
+        assert_eq!(t.next_step(1, start + step * 3), None);
+
+        // ramp-up is symmetric
+        t.set_target(3);
+        assert_eq!(t.next_step(1, start + step * 4), Some(2));
+        assert_eq!(t.next_step(2, start + step * 5), Some(3));
+        assert_eq!(t.next_step(3, start + step * 6), None);
+    }
+
+    #[test]
+    fn test_rejected_levels_are_retried() {
+        let mut rejections = Rejections::default();
+        rejections.reject(3, 0);
+        assert!(rejections.due(LEVEL_RETRY_MS - 1).is_empty());
+        assert_eq!(rejections.due(LEVEL_RETRY_MS), vec![3]);
+        assert!(rejections.due(LEVEL_RETRY_MS * 10).is_empty());
+
+        // rejected again, it is retried later
+        rejections.reject(3, 0);
+        assert!(rejections.due(LEVEL_RETRY_MS).is_empty());
+        assert_eq!(rejections.due(LEVEL_RETRY_MS * 2), vec![3]);
+    }
+
+    #[test]
+    fn test_immediate_transition_jumps() {
+        let mut t = LevelTransition::new(Transition::Immediate);
+        t.set_target(0);
+        assert_eq!(t.next_step(4, 0), Some(0));
+        assert_eq!(t.next_step(0, 0), None);
+    }
+
+    #[test]
+    fn test_recent_frames_padding() {
+        let mut padding = RecentFrames::new(2);
+        for i in 0..3 {
+            padding.observe(&AsDatum::new(0, i, vec![0; 100]));
+        }
+        let size = AsDatum::new(0, 0, vec![0; 100]).net_len();
+
+        // the newest frame fits, the rest is zeros
+        let sent = padding.pad(size + 50);
+        assert_eq!(sent[0].datum_type(), AsDatumType::Redundant(0, 2));
+        assert_eq!(sent[1].datum_type(), AsDatumType::Dummy);
+        assert_eq!(sent[1].mem.len(), 50);
+
+        // frames are re-sent only once
+        let sent = padding.pad(size * 4);
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].datum_type(), AsDatumType::Redundant(0, 1));
+        assert!(padding.pad(size).iter().all(|d| d.datum_type() == AsDatumType::Dummy));
+    }
+
+    #[test]
+    fn test_natural_bursts() {
+        // a fixed timestamp, as the encoded length of the current one varies
+        fn frame(level: usize, num: usize, data: Vec<u8>) -> AsDatum {
+            let mut datum = AsDatum::new(level, num, data);
+            datum.ts = Utc.timestamp_opt(0, 0).unwrap();
+            datum
+        }
+
+        let mut padding = NaturalBursts::new(Box::new(ZeroPadding));
+        for i in 0..10 {
+            padding.observe(&frame(0, i, vec![0; 100]));
+        }
+        assert_eq!(padding.pad(40)[0].mem.len(), 40);
+
+        // a keyframe covers the padding of its tick
+        padding.observe(&frame(0, 10, vec![0; 300]));
+        assert!(padding.pad(150).is_empty());
+        assert_eq!(padding.pad(150)[0].mem.len(), 150);
+
+        // and partly covers larger probes
+        padding.observe(&frame(0, 11, vec![0; 200]));
+        let sent = padding.pad(500);
+        assert!(sent[0].mem.len() > 300 && sent[0].mem.len() < 500);
 
-        ((adapt_tx, probe_rx), data_rx, counter.clone())
+        // a level switch is not a burst
+        padding.observe(&frame(1, 12, vec![0; 1000]));
+        assert_eq!(padding.pad(40)[0].mem.len(), 40);
     }
 }
@@ -0,0 +1,358 @@
+//! Backfill of frames generated during an outage.
+//!
+//! Live frames that can't be delivered because the connection is gone are
+//! kept in a bounded `Spool`. After reconnecting, a `Scheduler` sends them
+//! (optionally downsampled) as `Backfill` datums at low priority: a live
+//! frame always goes first, and at most one backfill frame follows each live
+//! frame.
+//!
+//! The `Scheduler` can also carry control datums (e.g., heartbeats) with a
+//! reserved share of the bytes sent: they go ahead of queued frames as long
+//! as they stay within their share, so that signaling gets through when the
+//! data queue is saturated, which is when adaptation needs it most. When no
+//! frame is ready, control datums go regardless.
+
+use super::{AsDatum, AsDatumType};
+use super::memory::Account;
+use futures::{Async, Poll, Stream};
+use futures::sync::mpsc::UnboundedReceiver;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Bounded store of undelivered live frames, shared across reconnects. When
+/// full, the oldest frames are dropped.
+#[derive(Clone)]
+pub struct Spool {
+    inner: Arc<Mutex<VecDeque<AsDatum>>>,
+    capacity: usize,
+    account: Option<Account>,
+}
+
+impl Spool {
+    /// Creates a spool keeping up to `capacity` frames.
+    pub fn new(capacity: usize) -> Spool {
+        Spool {
+            inner: Arc::new(Mutex::new(VecDeque::new())),
+            capacity,
+            account: None,
+        }
+    }
+
+    /// Creates a spool keeping up to `capacity` frames within a memory
+    /// budget: under pressure, it sheds its oldest frames and takes no more.
+    pub fn with_budget(capacity: usize, account: Account) -> Spool {
+        Spool {
+            account: Some(account),
+            ..Spool::new(capacity)
+        }
+    }
+
+    /// Keeps `datum` for backfill if it is a live frame.
+    pub fn push(&self, datum: AsDatum) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let AsDatumType::Live(_, _) = datum.datum_type() {
+            let mut frames = self.inner.lock().expect("spool poisoned");
+            if frames.len() == self.capacity {
+                self.forget(frames.pop_front());
+            }
+            if let Some(ref account) = self.account {
+                let len = datum.net_len();
+                // shedding frames for nothing would only lose them
+                if !account.could_reserve(len) {
+                    debug!("no memory to spool {}", datum);
+                    return;
+                }
+                while !account.try_reserve(len) {
+                    if frames.is_empty() {
+                        debug!("no memory to spool {}", datum);
+                        return;
+                    }
+                    self.forget(frames.pop_front());
+                }
+            }
+            frames.push_back(datum);
+            strict_assert!(
+                frames.len() <= self.capacity,
+                "spool holds {} of {} frames",
+                frames.len(),
+                self.capacity
+            );
+        }
+    }
+
+    /// Gives back the memory of a frame leaving the spool.
+    fn forget(&self, datum: Option<AsDatum>) {
+        if let (Some(account), Some(datum)) = (self.account.as_ref(), datum) {
+            account.release(datum.net_len());
+        }
+    }
+
+    /// The number of frames kept.
+    pub fn len(&self) -> usize {
+        self.inner.lock().expect("spool poisoned").len()
+    }
+
+    /// Returns true if no frame is kept.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Empties the spool and returns every `stride`-th frame, in order and
+    /// marked as backfill.
+    pub fn take(&self, stride: usize) -> VecDeque<AsDatum> {
+        let frames = ::std::mem::take(&mut *self.inner.lock().expect("spool poisoned"));
+        if let Some(ref account) = self.account {
+            account.release(frames.iter().map(AsDatum::net_len).sum());
+        }
+        frames
+            .into_iter()
+            .step_by(stride.max(1))
+            .map(AsDatum::into_backfill)
+            .collect()
+    }
+}
+
+/// Bytes of control datums a burst of frames can earn ahead of time.
+const MAX_CONTROL_CREDIT: f64 = 64.0 * 1024.0;
+
+/// Interleaves backfill frames into a live stream at low priority, and
+/// control datums at high priority within their reserve.
+pub struct Scheduler<S> {
+    live: S,
+    backfill: VecDeque<AsDatum>,
+    owed: bool,
+    control: Option<Reserved>,
+}
+
+/// Control datums and their share of the bytes sent.
+struct Reserved {
+    rx: UnboundedReceiver<AsDatum>,
+    pending: Option<AsDatum>,
+    /// Control bytes earned per byte of frames sent.
+    rate: f64,
+    /// Control bytes that may go ahead of frames now.
+    credit: f64,
+}
+
+impl Reserved {
+    /// The next control datum, if one is waiting.
+    fn peek(&mut self) -> Option<&AsDatum> {
+        if self.pending.is_none() {
+            if let Ok(Async::Ready(Some(datum))) = self.rx.poll() {
+                self.pending = Some(datum);
+            }
+        }
+        self.pending.as_ref()
+    }
+
+    fn take(&mut self) -> Option<AsDatum> {
+        let datum = self.pending.take()?;
+        self.credit = (self.credit - datum.net_len() as f64).max(-MAX_CONTROL_CREDIT);
+        Some(datum)
+    }
+
+    fn earn(&mut self, datum: &AsDatum) {
+        self.credit = (self.credit + datum.net_len() as f64 * self.rate).min(MAX_CONTROL_CREDIT);
+    }
+}
+
+impl<S: Stream<Item = AsDatum>> Scheduler<S> {
+    /// Sends `backfill` in the gaps of `live`.
+    pub fn new(live: S, backfill: VecDeque<AsDatum>) -> Scheduler<S> {
+        if !backfill.is_empty() {
+            info!("backfilling {} frames", backfill.len());
+        }
+        Scheduler {
+            live,
+            backfill,
+            owed: false,
+            control: None,
+        }
+    }
+
+    /// Also sends the datums of `control`, reserving them `reserve` (0 to 1)
+    /// of the bytes sent while frames are waiting.
+    pub fn set_control(&mut self, control: UnboundedReceiver<AsDatum>, reserve: f64) {
+        let reserve = reserve.clamp(0.0, 0.9);
+        self.control = Some(Reserved {
+            rx: control,
+            pending: None,
+            rate: reserve / (1.0 - reserve),
+            credit: 0.0,
+        });
+    }
+
+    /// Sends a frame, crediting the control reserve.
+    fn frame(&mut self, datum: AsDatum) -> Poll<Option<AsDatum>, S::Error> {
+        if let Some(ref mut control) = self.control {
+            control.earn(&datum);
+        }
+        Ok(Async::Ready(Some(datum)))
+    }
+}
+
+impl<S: Stream<Item = AsDatum>> Stream for Scheduler<S> {
+    type Item = AsDatum;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<AsDatum>, S::Error> {
+        if let Some(ref mut control) = self.control {
+            if control.peek().is_some() && control.credit >= 0.0 {
+                return Ok(Async::Ready(control.take()));
+            }
+        }
+        match self.live.poll()? {
+            Async::Ready(Some(datum)) => {
+                self.owed = true;
+                self.frame(datum)
+            }
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => {
+                // nothing is waiting for the link
+                if let Some(datum) = self.control.as_mut().and_then(Reserved::take) {
+                    return Ok(Async::Ready(Some(datum)));
+                }
+                if !self.owed {
+                    return Ok(Async::NotReady);
+                }
+                match self.backfill.pop_front() {
+                    Some(datum) => {
+                        self.owed = false;
+                        self.frame(datum)
+                    }
+                    None => Ok(Async::NotReady),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{Future, future};
+    use futures::sync::mpsc::unbounded;
+
+    /// Polls `s` once, within a task.
+    fn poll_now<S: Stream>(s: &mut S) -> Poll<Option<S::Item>, S::Error> {
+        future::poll_fn(|| Ok::<_, ()>(Async::Ready(s.poll()))).wait().unwrap()
+    }
+
+    #[test]
+    fn test_backfill_interleaves_after_live() {
+        let spool = Spool::new(3);
+        for i in 0..5 {
+            spool.push(AsDatum::new(0, i, vec![]));
+        }
+        spool.push(AsDatum::latency_probe());
+        assert_eq!(spool.len(), 3);
+
+        let backfill = spool.take(2);
+        assert!(spool.is_empty());
+        let types = backfill.iter().map(|d| d.datum_type()).collect::<Vec<_>>();
+        assert_eq!(types, vec![AsDatumType::Backfill(0, 2), AsDatumType::Backfill(0, 4)]);
+
+        let (tx, rx) = unbounded();
+        let mut scheduler = Scheduler::new(rx, backfill);
+
+        // nothing live yet: backfill waits
+        assert!(poll_now(&mut scheduler).unwrap().is_not_ready());
+        tx.unbounded_send(AsDatum::new(1, 10, vec![])).unwrap();
+        tx.unbounded_send(AsDatum::new(1, 11, vec![])).unwrap();
+        let mut sent = Vec::new();
+        while let Async::Ready(Some(d)) = poll_now(&mut scheduler).unwrap() {
+            sent.push(d.datum_type());
+        }
+        assert_eq!(
+            sent,
+            vec![
+                AsDatumType::Live(1, 10),
+                AsDatumType::Live(1, 11),
+                AsDatumType::Backfill(0, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_control_reserve_under_saturation() {
+        let (tx, rx) = unbounded();
+        let (control_tx, control_rx) = unbounded();
+        let mut scheduler = Scheduler::new(rx, VecDeque::new());
+        scheduler.set_control(control_rx, 0.2);
+
+        // a saturated queue: 100 frames of 1 KB and 10 heartbeats waiting
+        for i in 0..100 {
+            tx.unbounded_send(AsDatum::new(0, i, vec![0; 1000])).unwrap();
+        }
+        let probe = AsDatum::latency_probe();
+        for _ in 0..10 {
+            control_tx.unbounded_send(probe.clone()).unwrap();
+        }
+        let mut sent = Vec::new();
+        for _ in 0..30 {
+            match poll_now(&mut scheduler).unwrap() {
+                Async::Ready(Some(d)) => sent.push(d.datum_type()),
+                r => panic!("unexpected {:?}", r),
+            }
+        }
+        // all heartbeats go long before the queue drains: the first at once,
+        // then each as soon as a frame earned its bytes
+        let probes = sent.iter().filter(|&&t| t == AsDatumType::LatencyProbe).count();
+        assert_eq!(probes, 10);
+        assert_eq!(sent[0], AsDatumType::LatencyProbe);
+        assert_eq!(sent[1], AsDatumType::Live(0, 0));
+
+        // a flood of control datums can't starve the frames
+        for _ in 0..1000 {
+            control_tx.unbounded_send(AsDatum::with_type(AsDatumType::Hint, vec![0; 1000])).unwrap();
+        }
+        let mut frames = 0;
+        for _ in 0..50 {
+            if let Async::Ready(Some(d)) = poll_now(&mut scheduler).unwrap() {
+                if let AsDatumType::Live(..) = d.datum_type() {
+                    frames += 1;
+                }
+            }
+        }
+        assert!(frames >= 35, "{} frames", frames);
+
+        // once the frames are sent, control goes regardless
+        let mut hints = 50 - frames;
+        while let Async::Ready(Some(d)) = poll_now(&mut scheduler).unwrap() {
+            if d.datum_type() == AsDatumType::Hint {
+                hints += 1;
+            }
+        }
+        assert_eq!(hints, 1000);
+    }
+
+    #[test]
+    fn test_spool_sheds_only_when_it_helps() {
+        use memory::{Component, MemoryBudget};
+
+        let frame = |i| AsDatum::new(0, i, vec![0; 100]);
+        let len = frame(0).net_len();
+        let budget = MemoryBudget::new(1000);
+        let spool = Spool::with_budget(10, budget.account(Component::Spool));
+        spool.push(frame(0));
+        spool.push(frame(1));
+
+        // the rest of the pipeline put the budget under pressure, above the
+        // low watermark even without the spool: shedding can't help
+        let queue = budget.account(Component::SendQueue);
+        queue.charge(1000 - 2 * len - len / 2);
+        assert!(budget.under_pressure());
+        spool.push(frame(2));
+        assert_eq!(spool.len(), 2);
+        // nor can it for a frame larger than the budget
+        queue.release(1000);
+        spool.push(AsDatum::new(0, 3, vec![0; 1000]));
+        assert_eq!(spool.len(), 2);
+
+        spool.push(frame(4));
+        assert_eq!(spool.len(), 3);
+        assert_eq!(budget.used_by(Component::Spool), 3 * len);
+    }
+}
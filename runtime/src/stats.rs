@@ -0,0 +1,280 @@
+//! Live statistics of a client, for dashboards such as `awstream-top`.
+//!
+//! With `stats_port` set, the client listens on localhost and streams a
+//! `StatsSnapshot` per second to every viewer, as CSV rows after a header.
+//! Viewers on other machines reach it through an SSH tunnel. With
+//! `stats_log` set, the client also appends the snapshots to a CSV file,
+//! rotated like the experiment log if `stats_log_rotation` is set.
+
+use super::CloseReason;
+use chrono::Utc;
+use csv;
+use errors::*;
+use rotation::{RotatingCsv, RotationPeriod};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// How often viewers are sent a snapshot.
+pub const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The state of a client at one instant.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct StatsSnapshot {
+    /// When the snapshot was taken (ms since unix epoch).
+    pub time_ms: i64,
+
+    /// The level the controller last chose, once streaming.
+    pub level: Option<usize>,
+
+    /// Estimated outgoing rate (kbps).
+    pub throughput_kbps: f64,
+
+    /// Time the queued bytes take to drain (ms).
+    pub queue_delay_ms: f64,
+
+    /// Frames dropped from the queue since the client started.
+    pub drops: usize,
+
+    /// Smoothed RTT of the data connection (ms), with `tcp-info`.
+    pub rtt_ms: Option<f64>,
+
+    /// Why the server last closed the connection on purpose, if it did.
+    pub close_reason: Option<CloseReason>,
+
+    /// Pipelines the watchdog restarted since the client started.
+    pub restarts: usize,
+}
+
+/// A shared handle to the live statistics. Clones update the same snapshot.
+#[derive(Debug, Clone, Default)]
+pub struct ClientStats {
+    inner: Arc<Mutex<StatsSnapshot>>,
+}
+
+impl ClientStats {
+    /// Creates empty statistics.
+    pub fn new() -> ClientStats {
+        ClientStats::default()
+    }
+
+    /// The current statistics.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let mut snapshot = *self.lock();
+        snapshot.time_ms = Utc::now().timestamp_millis();
+        snapshot
+    }
+
+    /// Records the level in use.
+    pub fn set_level(&self, level: usize) {
+        self.lock().level = Some(level);
+    }
+
+    /// Records the outgoing rate (kbps) and queueing delay (ms).
+    pub fn set_queue(&self, throughput_kbps: f64, queue_delay_ms: f64) {
+        let mut s = self.lock();
+        s.throughput_kbps = throughput_kbps;
+        s.queue_delay_ms = queue_delay_ms;
+    }
+
+    /// Counts a frame dropped from the queue.
+    pub fn add_drop(&self) {
+        self.lock().drops += 1;
+    }
+
+    /// Records why the server closed the connection.
+    pub fn set_close_reason(&self, reason: CloseReason) {
+        self.lock().close_reason = Some(reason);
+    }
+
+    /// Counts a pipeline restarted by the watchdog.
+    pub fn add_restart(&self) {
+        self.lock().restarts += 1;
+    }
+
+    /// Records the RTT (ms) of the data connection.
+    pub fn set_rtt(&self, rtt_ms: f64) {
+        self.lock().rtt_ms = Some(rtt_ms);
+    }
+
+    /// Writes the current statistics to `path`, as a CSV header and row,
+    /// replacing the file at once. Gives up rather than block if the
+    /// statistics are being updated (e.g., from a panic hook), returning
+    /// false.
+    pub fn write_snapshot<P: AsRef<Path>>(&self, path: P) -> Result<bool> {
+        let mut snapshot = match self.inner.try_lock() {
+            Ok(s) => *s,
+            Err(TryLockError::Poisoned(p)) => *p.into_inner(),
+            Err(TryLockError::WouldBlock) => return Ok(false),
+        };
+        snapshot.time_ms = Utc::now().timestamp_millis();
+        let path = path.as_ref();
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        {
+            let mut writer = csv::Writer::from_path(&partial)?;
+            writer.serialize(snapshot)?;
+            writer.flush()?;
+        }
+        fs::rename(&partial, path)?;
+        Ok(true)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, StatsSnapshot> {
+        self.inner.lock().expect("client stats poisoned")
+    }
+}
+
+/// Streams `stats` to viewers connecting to `addr`, from a background
+/// thread (and one per viewer). Returns the address listened on.
+pub fn serve<A: ToSocketAddrs>(stats: ClientStats, addr: A) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local = listener.local_addr()?;
+    info!("serving stats on {}", local);
+    thread::spawn(move || {
+        for viewer in listener.incoming() {
+            match viewer {
+                Ok(viewer) => {
+                    let stats = stats.clone();
+                    thread::spawn(move || feed(&stats, viewer));
+                }
+                Err(e) => warn!("failed to accept stats viewer: {}", e),
+            }
+        }
+    });
+    Ok(local)
+}
+
+/// Sends a snapshot every `STATS_INTERVAL` until the viewer leaves.
+fn feed(stats: &ClientStats, viewer: TcpStream) {
+    let mut writer = csv::Writer::from_writer(viewer);
+    loop {
+        if let Err(e) = writer.serialize(stats.snapshot()) {
+            debug!("stats viewer left: {}", e);
+            return;
+        }
+        if let Err(e) = writer.flush() {
+            debug!("stats viewer left: {}", e);
+            return;
+        }
+        thread::sleep(STATS_INTERVAL);
+    }
+}
+
+/// Where logged snapshots go.
+enum LogFile {
+    Single(csv::Writer<File>),
+    Rotating(RotatingCsv),
+}
+
+impl LogFile {
+    fn write(&mut self, snapshot: &StatsSnapshot) -> Result<()> {
+        let w = match *self {
+            LogFile::Single(ref mut w) => w,
+            LogFile::Rotating(ref mut r) => r.writer(snapshot.time_ms)?,
+        };
+        w.serialize(snapshot)?;
+        w.flush()?;
+        Ok(())
+    }
+}
+
+/// Stops logging statistics when dropped, completing the file in progress.
+pub struct StatsLog {
+    stopped: Arc<AtomicBool>,
+}
+
+impl Drop for StatsLog {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Appends a snapshot of `stats` every `STATS_INTERVAL` to `path`, or to a
+/// file per `rotation` period next to it (see `rotation`), from a background
+/// thread.
+pub fn log<P: AsRef<Path>>(
+    stats: ClientStats,
+    path: P,
+    rotation: Option<RotationPeriod>,
+) -> Result<StatsLog> {
+    let mut file = match rotation {
+        Some(period) => LogFile::Rotating(RotatingCsv::new(path, period)),
+        None => LogFile::Single(csv::Writer::from_path(path)?),
+    };
+    let stopped = Arc::new(AtomicBool::new(false));
+    let stop = stopped.clone();
+    thread::spawn(move || {
+        while !stop.load(Ordering::SeqCst) {
+            if let Err(e) = file.write(&stats.snapshot()) {
+                warn!("failed to log stats: {}", e);
+                return;
+            }
+            thread::sleep(STATS_INTERVAL);
+        }
+    });
+    Ok(StatsLog { stopped })
+}
+
+/// Connects to the stats of a client at `addr`, yielding its snapshots as
+/// they arrive.
+pub fn watch<A: ToSocketAddrs>(addr: A) -> Result<Box<dyn Iterator<Item = Result<StatsSnapshot>>>> {
+    let stream = TcpStream::connect(addr)?;
+    let reader = csv::Reader::from_reader(BufReader::new(stream));
+    Ok(Box::new(reader.into_deserialize().map(|r| r.map_err(Error::from))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watch_stats() {
+        let stats = ClientStats::new();
+        stats.set_level(3);
+        stats.set_queue(800.0, 12.5);
+        stats.add_drop();
+        let addr = serve(stats.clone(), "127.0.0.1:0").unwrap();
+
+        let mut snapshots = watch(addr).unwrap();
+        let first = snapshots.next().unwrap().unwrap();
+        assert_eq!(first.level, Some(3));
+        assert_eq!((first.throughput_kbps, first.queue_delay_ms), (800.0, 12.5));
+        assert_eq!((first.drops, first.rtt_ms), (1, None));
+
+        stats.set_rtt(40.0);
+        let next = snapshots.next().unwrap().unwrap();
+        assert_eq!(next.rtt_ms, Some(40.0));
+        assert!(next.time_ms >= first.time_ms);
+    }
+
+    #[test]
+    fn test_rotating_stats_log() {
+        let dir = ::std::env::temp_dir()
+            .join(format!("awstream-stats-log-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let base = dir.join("stats.csv");
+        let stats = ClientStats::new();
+        stats.set_level(2);
+        let log = log(stats, &base, Some(RotationPeriod::Hour)).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        drop(log);
+        // the thread completes the hour in progress once it sees the stop
+        thread::sleep(STATS_INTERVAL + Duration::from_millis(200));
+        let mut files = fs::read_dir(&dir).unwrap().map(|f| f.unwrap().path()).collect::<Vec<_>>();
+        files.sort();
+        assert!(files.iter().all(|f| f.extension().unwrap() == "csv"), "{:?}", files);
+        let rows = csv::Reader::from_path(&files[0])
+            .unwrap()
+            .into_deserialize::<StatsSnapshot>()
+            .collect::<::std::result::Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(rows[0].level, Some(2));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
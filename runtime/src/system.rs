@@ -0,0 +1,235 @@
+//! Monitors the health of the edge device (CPU load and the temperature of
+//! its thermal zones) and asks the controller to cap the level when it is
+//! overloaded or overheating, independent of bandwidth.
+//!
+//! Both are read from procfs and sysfs, i.e., on Linux only. Elsewhere (e.g.,
+//! on Windows capture boxes) they are unknown and never exceed the limits.
+
+use adaptation::Signal;
+use errors::*;
+use futures::{Async, Poll, Stream};
+#[cfg(target_os = "linux")]
+use std::fs;
+use std::time::{Duration, Instant};
+use tokio_timer::{self, Interval};
+
+/// How often the device is sampled.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Load and heat lag behind a level change, so a throttled device is given
+/// this long to cool down before being throttled again.
+const HOLD: Duration = Duration::from_secs(10);
+
+/// How long the device must stay healthy before a throttled level is raised
+/// by one.
+const RECOVERY: Duration = Duration::from_secs(30);
+
+/// Thresholds above which the device counts as overloaded.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    /// Fraction (0 to 1) of CPU time spent busy.
+    pub cpu: Option<f64>,
+
+    /// Temperature of the hottest thermal zone, in degrees Celsius.
+    pub temp_c: Option<f64>,
+}
+
+impl Limits {
+    fn exceeded(&self, cpu: Option<f64>, temp_c: Option<f64>) -> bool {
+        let over = |value: Option<f64>, limit: Option<f64>| match (value, limit) {
+            (Some(v), Some(l)) => v > l,
+            _ => false,
+        };
+        over(cpu, self.cpu) || over(temp_c, self.temp_c)
+    }
+}
+
+/// Cumulative CPU times (in jiffies) from `/proc/stat`.
+#[derive(Debug, Clone, Copy)]
+struct CpuTimes {
+    busy: u64,
+    total: u64,
+}
+
+#[cfg(target_os = "linux")]
+fn read_cpu_times() -> Option<CpuTimes> {
+    let stat = fs::read_to_string("/proc/stat").ok()?;
+    let fields = stat
+        .lines()
+        .next()?
+        .split_whitespace()
+        .skip(1)
+        .map(|f| f.parse::<u64>())
+        .collect::<::std::result::Result<Vec<_>, _>>()
+        .ok()?;
+    // user nice system idle iowait irq softirq steal ...
+    let idle = fields.get(3)? + fields.get(4).unwrap_or(&0);
+    let total = fields.iter().take(8).sum::<u64>();
+    Some(CpuTimes {
+        busy: total - idle,
+        total,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_cpu_times() -> Option<CpuTimes> {
+    None
+}
+
+/// The temperature of the hottest thermal zone, if any can be read.
+#[cfg(target_os = "linux")]
+fn read_temp_c() -> Option<f64> {
+    fs::read_dir("/sys/class/thermal")
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("thermal_zone"))
+        .filter_map(|entry| fs::read_to_string(entry.path().join("temp")).ok())
+        .filter_map(|t| t.trim().parse::<f64>().ok())
+        .map(|millis| millis / 1000.0)
+        .fold(None, |max: Option<f64>, t| Some(max.map_or(t, |m| m.max(t))))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_temp_c() -> Option<f64> {
+    None
+}
+
+/// Turns overload samples into `SystemLoad` signals: throttle at most once
+/// per `HOLD`, and relax one level per `RECOVERY` of health until the cap is
+/// lifted.
+struct Governor {
+    levels: usize,
+    last_throttle: Option<Instant>,
+    healthy_since: Option<Instant>,
+    pending_relax: usize,
+}
+
+impl Governor {
+    fn new(levels: usize) -> Governor {
+        Governor {
+            levels,
+            last_throttle: None,
+            healthy_since: None,
+            pending_relax: 0,
+        }
+    }
+
+    fn update(&mut self, overloaded: bool, now: Instant) -> Option<Signal> {
+        if overloaded {
+            self.healthy_since = None;
+            if self.last_throttle.is_some_and(|t| now.duration_since(t) < HOLD) {
+                return None;
+            }
+            self.last_throttle = Some(now);
+            // the cap is at most this many levels below the top
+            self.pending_relax = self.levels.saturating_sub(1);
+            return Some(Signal::SystemLoad(true));
+        }
+        if self.pending_relax == 0 {
+            return None;
+        }
+        let since = *self.healthy_since.get_or_insert(now);
+        if now.duration_since(since) < RECOVERY {
+            return None;
+        }
+        self.healthy_since = Some(now);
+        self.pending_relax -= 1;
+        Some(Signal::SystemLoad(false))
+    }
+}
+
+/// A stream of `SystemLoad` signals for a profile of `levels` levels.
+pub struct SystemMonitor {
+    timer: Interval,
+    limits: Limits,
+    last_cpu: Option<CpuTimes>,
+    governor: Governor,
+}
+
+impl SystemMonitor {
+    /// Samples the device every second against `limits`.
+    pub fn new(limits: Limits, levels: usize) -> SystemMonitor {
+        if !cfg!(target_os = "linux") {
+            warn!("device load and temperature are only monitored on Linux");
+        }
+        let timer = tokio_timer::wheel()
+            .tick_duration(Duration::from_millis(50))
+            .build()
+            .interval(SAMPLE_INTERVAL);
+        SystemMonitor {
+            timer,
+            limits,
+            last_cpu: read_cpu_times(),
+            governor: Governor::new(levels),
+        }
+    }
+
+    fn sample(&mut self) -> Option<Signal> {
+        let now = read_cpu_times();
+        let cpu = match (self.last_cpu, now) {
+            (Some(a), Some(b)) if b.total > a.total => {
+                Some((b.busy - a.busy) as f64 / (b.total - a.total) as f64)
+            }
+            _ => None,
+        };
+        self.last_cpu = now;
+        let temp_c = if self.limits.temp_c.is_some() {
+            read_temp_c()
+        } else {
+            None
+        };
+        let overloaded = self.limits.exceeded(cpu, temp_c);
+        if overloaded {
+            warn!("device overloaded: cpu {:?}, temperature {:?}", cpu, temp_c);
+        }
+        self.governor.update(overloaded, Instant::now())
+    }
+}
+
+impl Stream for SystemMonitor {
+    type Item = Signal;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Signal>, Error> {
+        while try_ready!(self.timer.poll()).is_some() {
+            if let Some(signal) = self.sample() {
+                return Ok(Async::Ready(Some(signal)));
+            }
+        }
+        Ok(Async::Ready(None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_governor_holds_and_recovers() {
+        let start = Instant::now();
+        let at = |s: u64| start + Duration::from_secs(s);
+        let mut governor = Governor::new(3);
+
+        assert_eq!(governor.update(false, at(0)), None);
+        assert_eq!(governor.update(true, at(1)), Some(Signal::SystemLoad(true)));
+        assert_eq!(governor.update(true, at(5)), None);
+        assert_eq!(governor.update(true, at(11)), Some(Signal::SystemLoad(true)));
+
+        assert_eq!(governor.update(false, at(12)), None);
+        assert_eq!(governor.update(false, at(42)), Some(Signal::SystemLoad(false)));
+        assert_eq!(governor.update(false, at(72)), Some(Signal::SystemLoad(false)));
+        // the cap is lifted
+        assert_eq!(governor.update(false, at(200)), None);
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits {
+            cpu: Some(0.9),
+            temp_c: None,
+        };
+        assert!(limits.exceeded(Some(0.95), Some(90.0)));
+        assert!(!limits.exceeded(Some(0.5), Some(90.0)));
+        assert!(!limits.exceeded(None, None));
+    }
+}
@@ -0,0 +1,110 @@
+//! Transport-layer state of a connection, read from `TCP_INFO`.
+//!
+//! Sampled once per second on both ends, so that adaptation decisions can be
+//! correlated with the congestion window, RTT and retransmissions of the TCP
+//! connection underneath. Only available on Linux with the `tcp-info`
+//! feature; elsewhere `TcpInfoProbe::new` returns `None`.
+
+use tokio_core::net::TcpStream;
+
+/// A sample of the state of a TCP connection.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct TcpInfo {
+    /// Congestion window (segments).
+    pub cwnd: u32,
+
+    /// Smoothed round-trip time (ms).
+    pub srtt_ms: f64,
+
+    /// Round-trip time variation (ms).
+    pub rttvar_ms: f64,
+
+    /// Segments retransmitted since the connection opened.
+    pub retransmits: u32,
+}
+
+/// Reads `TCP_INFO` of a connection, for as long as the probe lives (it
+/// holds a duplicate of the socket).
+#[derive(Debug)]
+pub struct TcpInfoProbe {
+    #[cfg(all(feature = "tcp-info", target_os = "linux"))]
+    socket: ::std::net::TcpStream,
+}
+
+#[cfg(all(feature = "tcp-info", target_os = "linux"))]
+impl TcpInfoProbe {
+    /// Creates a probe of `tcp`.
+    pub fn new(tcp: &TcpStream) -> Option<TcpInfoProbe> {
+        use std::os::unix::io::{AsRawFd, FromRawFd};
+        let fd = unsafe { ::libc::dup(tcp.as_raw_fd()) };
+        if fd < 0 {
+            warn!("failed to duplicate socket for TCP_INFO");
+            return None;
+        }
+        let socket = unsafe { ::std::net::TcpStream::from_raw_fd(fd) };
+        Some(TcpInfoProbe { socket })
+    }
+
+    /// Reads the current state, if the kernel reports it.
+    pub fn read(&self) -> Option<TcpInfo> {
+        use std::mem;
+        use std::os::unix::io::AsRawFd;
+        let mut info: ::libc::tcp_info = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<::libc::tcp_info>() as ::libc::socklen_t;
+        let ret = unsafe {
+            ::libc::getsockopt(
+                self.socket.as_raw_fd(),
+                ::libc::IPPROTO_TCP,
+                ::libc::TCP_INFO,
+                &mut info as *mut _ as *mut ::libc::c_void,
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            debug!("TCP_INFO failed: {}", ::std::io::Error::last_os_error());
+            return None;
+        }
+        Some(TcpInfo {
+            cwnd: info.tcpi_snd_cwnd,
+            srtt_ms: f64::from(info.tcpi_rtt) / 1000.0,
+            rttvar_ms: f64::from(info.tcpi_rttvar) / 1000.0,
+            retransmits: info.tcpi_total_retrans,
+        })
+    }
+}
+
+#[cfg(not(all(feature = "tcp-info", target_os = "linux")))]
+impl TcpInfoProbe {
+    /// Creates a probe of `tcp`; unsupported in this build.
+    pub fn new(_tcp: &TcpStream) -> Option<TcpInfoProbe> {
+        None
+    }
+
+    /// Reads the current state, if the kernel reports it.
+    pub fn read(&self) -> Option<TcpInfo> {
+        None
+    }
+}
+
+#[cfg(all(test, feature = "tcp-info", target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::net;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn test_reads_tcp_info() {
+        let core = Core::new().unwrap();
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let _server = listener.accept().unwrap();
+        let tcp = TcpStream::from_stream(client, &core.handle()).unwrap();
+
+        let probe = TcpInfoProbe::new(&tcp).unwrap();
+        drop(tcp);
+        // the probe keeps its own handle of the socket
+        let info = probe.read().unwrap();
+        assert!(info.cwnd > 0);
+        assert_eq!(info.retransmits, 0);
+    }
+}
@@ -0,0 +1,130 @@
+//! Periodic ticks read from a `decision::Clock`.
+//!
+//! Pacers, probes and heartbeats tick through `Ticker` rather than a
+//! `tokio_timer::Interval`. The timer only wakes the task up; whether a tick
+//! is due is decided by the clock, so tests can drive a `Ticker` with a
+//! `ManualClock` instead of sleeping.
+//!
+//! Every timer wheel runs a thread of its own, so tickers (and coalescing
+//! sockets) share the one of `shared_timer` rather than building one each.
+
+use super::decision::{SharedClock, SystemClock};
+use errors::*;
+use futures::{Async, Future, Poll, Stream};
+use std::cmp;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio_timer::{self, Sleep, Timer};
+
+/// The longest single sleep, well within the range of the timer wheel.
+const MAX_SLEEP_MS: u64 = 1000;
+
+/// The timer wheel of the process, at a 1 ms resolution, started on first
+/// use.
+pub fn shared_timer() -> Timer {
+    static TIMER: OnceLock<Timer> = OnceLock::new();
+    TIMER
+        .get_or_init(|| tokio_timer::wheel().tick_duration(Duration::from_millis(1)).build())
+        .clone()
+}
+
+/// A stream of ticks every `period`, yielding the time (ms) each was due.
+/// Like `tokio_timer::Interval`, late ticks are caught up on rather than
+/// skipped.
+pub struct Ticker {
+    clock: SharedClock,
+    period_ms: u64,
+    next_ms: u64,
+    timer: Timer,
+    sleep: Option<Sleep>,
+}
+
+impl Ticker {
+    /// Ticks every `period` of `clock`, first one period from now.
+    pub fn new(clock: SharedClock, period: Duration) -> Ticker {
+        let period_ms = cmp::max(period.as_millis() as u64, 1);
+        let next_ms = clock.now_ms() + period_ms;
+        Ticker {
+            clock,
+            period_ms,
+            next_ms,
+            timer: shared_timer(),
+            sleep: None,
+        }
+    }
+
+    /// Ticks every `period` from the last tick on (or from now, if that is
+    /// past).
+    pub fn set_period(&mut self, period: Duration) {
+        let period_ms = cmp::max(period.as_millis() as u64, 1);
+        let last_ms = self.next_ms - self.period_ms;
+        self.next_ms = cmp::max(last_ms + period_ms, self.clock.now_ms());
+        self.period_ms = period_ms;
+        self.sleep = None;
+    }
+
+    /// Ticks every `period` of the system clock.
+    pub fn system(period: Duration) -> Ticker {
+        Ticker::new(Arc::new(SystemClock::new()), period)
+    }
+}
+
+impl Stream for Ticker {
+    type Item = u64;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<u64>, Error> {
+        loop {
+            let now = self.clock.now_ms();
+            if now >= self.next_ms {
+                let due = self.next_ms;
+                self.next_ms += self.period_ms;
+                self.sleep = None;
+                return Ok(Async::Ready(Some(due)));
+            }
+            let wait = cmp::min(self.next_ms - now, MAX_SLEEP_MS);
+            let timer = &self.timer;
+            let sleep = self.sleep
+                .get_or_insert_with(|| timer.sleep(Duration::from_millis(wait)));
+            if sleep.poll()?.is_not_ready() {
+                return Ok(Async::NotReady);
+            }
+            // woken up; the clock may still be behind the timer
+            self.sleep = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use decision::ManualClock;
+    use futures::future;
+
+    fn poll(ticker: &mut Ticker) -> Async<Option<u64>> {
+        future::lazy(|| Ok::<_, ()>(ticker.poll().unwrap())).wait().unwrap()
+    }
+
+    #[test]
+    fn test_ticker_follows_clock() {
+        let clock = ManualClock::new(1000);
+        let mut ticker = Ticker::new(Arc::new(clock.clone()), Duration::from_millis(100));
+        assert_eq!(poll(&mut ticker), Async::NotReady);
+
+        clock.advance(100);
+        assert_eq!(poll(&mut ticker), Async::Ready(Some(1100)));
+        assert_eq!(poll(&mut ticker), Async::NotReady);
+
+        // late ticks are caught up on
+        clock.advance(250);
+        assert_eq!(poll(&mut ticker), Async::Ready(Some(1200)));
+        assert_eq!(poll(&mut ticker), Async::Ready(Some(1300)));
+        assert_eq!(poll(&mut ticker), Async::NotReady);
+
+        // a second ticker sleeps on the same wheel
+        let mut other = Ticker::new(Arc::new(clock.clone()), Duration::from_millis(10));
+        assert_eq!(poll(&mut other), Async::NotReady);
+        clock.advance(10);
+        assert_eq!(poll(&mut other), Async::Ready(Some(1360)));
+    }
+}
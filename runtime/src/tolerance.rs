@@ -0,0 +1,162 @@
+//! Tolerance of malformed frames.
+//!
+//! A frame that fails to decode is skipped (the codec has consumed it), so a
+//! connection can survive an occasional corrupt frame. `Tolerant` counts
+//! these errors and gives up on the connection once too many happen within a
+//! window.
+//!
+//! `SequenceCheck` is the policy for live frames whose number does not
+//! increase, which may be a replay or a client bug. A number going back to
+//! the start of the stream is a new sequence, not a regression.
+
+use errors::*;
+use futures::{Poll, Stream};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How many malformed frames a connection may send.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct ToleranceConfig {
+    /// The connection is dropped when more errors than this happen within
+    /// `window_ms`.
+    pub max_errors: usize,
+
+    /// The window (ms).
+    pub window_ms: u64,
+}
+
+impl Default for ToleranceConfig {
+    fn default() -> Self {
+        ToleranceConfig {
+            max_errors: 5,
+            window_ms: 10_000,
+        }
+    }
+}
+
+/// What the server does with a live frame numbered at or below the last
+/// one of its session, unless it restarts the numbering (a source looping
+/// over its input starts a new sequence).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SequenceCheck {
+    /// Accepts it without checking.
+    Off,
+
+    /// Accepts it, and reports the regression (the default).
+    #[default]
+    Flag,
+
+    /// Drops it, and reports the regression.
+    Reject,
+}
+
+/// Skips the frames of `inner` that fail to decode, reporting each to
+/// `on_error` with the number of errors within the window. Ends with
+/// `TooManyDecodeErrors` once the limit is exceeded; other errors are passed
+/// through.
+pub struct Tolerant<S, F> {
+    inner: S,
+    config: ToleranceConfig,
+    recent: VecDeque<Instant>,
+    total: usize,
+    on_error: F,
+}
+
+impl<S, F> Tolerant<S, F>
+where
+    S: Stream<Error = Error>,
+    F: FnMut(&Error, usize),
+{
+    /// Wraps `inner`.
+    pub fn new(inner: S, config: ToleranceConfig, on_error: F) -> Tolerant<S, F> {
+        Tolerant {
+            inner,
+            config,
+            recent: VecDeque::new(),
+            total: 0,
+            on_error,
+        }
+    }
+
+    /// The number of frames that failed to decode so far.
+    pub fn errors(&self) -> usize {
+        self.total
+    }
+
+    /// Records an error at `now`; returns the errors within the window.
+    fn record(&mut self, now: Instant) -> usize {
+        let window = Duration::from_millis(self.config.window_ms);
+        while self.recent.front().is_some_and(|t| now.duration_since(*t) > window) {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(now);
+        self.total += 1;
+        self.recent.len()
+    }
+}
+
+impl<S, F> Stream for Tolerant<S, F>
+where
+    S: Stream<Error = Error>,
+    F: FnMut(&Error, usize),
+{
+    type Item = S::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, Error> {
+        loop {
+            match self.inner.poll() {
+                Err(Error(ErrorKind::DecodeError, state)) => {
+                    let e = Error(ErrorKind::DecodeError, state);
+                    let recent = self.record(Instant::now());
+                    (self.on_error)(&e, recent);
+                    if recent > self.config.max_errors {
+                        bail!(ErrorKind::TooManyDecodeErrors(recent));
+                    }
+                }
+                other => return other,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+
+    #[test]
+    fn test_tolerate_decode_errors() {
+        let frames = vec![
+            Ok(1),
+            Err(Error::from_kind(ErrorKind::DecodeError)),
+            Ok(2),
+            Err(Error::from_kind(ErrorKind::DecodeError)),
+            Err(Error::from_kind(ErrorKind::DecodeError)),
+            Ok(3),
+        ];
+        let config = ToleranceConfig {
+            max_errors: 2,
+            window_ms: 60_000,
+        };
+        let mut reported = Vec::new();
+        let got = Tolerant::new(stream::iter_result(frames), config, |_, n| reported.push(n))
+            .wait()
+            .collect::<Vec<_>>();
+        assert_eq!(got[0].as_ref().ok(), Some(&1));
+        assert_eq!(got[1].as_ref().ok(), Some(&2));
+        match got[2] {
+            Err(Error(ErrorKind::TooManyDecodeErrors(3), _)) => {}
+            ref r => panic!("unexpected {:?}", r),
+        }
+        assert_eq!(reported, vec![1, 2, 3]);
+
+        // other errors pass through
+        let frames = vec![Err::<usize, _>(Error::from_kind(ErrorKind::RemotePeer))];
+        let mut tolerant = Tolerant::new(stream::iter_result(frames), config, |_, _| {});
+        assert!(tolerant.by_ref().wait().next().unwrap().is_err());
+        assert_eq!(tolerant.errors(), 0);
+    }
+}
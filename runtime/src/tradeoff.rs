@@ -0,0 +1,220 @@
+//! How each level of a session fared on the network.
+//!
+//! A `Tradeoff` attributes the time between live frames, their bytes, their
+//! latency and the frames lost before them to the level being streamed.
+//! Its `TradeoffReport` puts the rate achieved at each level next to the rate
+//! the profile expects of it: a level that stays well below its expected rate,
+//! or that sees high latency or loss, was not sustained by the network and
+//! hints that the profile overestimates what it needs (or the network).
+
+use super::Bandwidth;
+use csv;
+use errors::*;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// Longer gaps between frames (e.g., an outage) count as this much time.
+const MAX_GAP: Duration = Duration::from_secs(1);
+
+/// Latency samples kept per level for percentiles: the most recent.
+const SAMPLES_PER_LEVEL: usize = 1000;
+
+#[derive(Debug, Default, Clone)]
+struct Tally {
+    time: Duration,
+    frames: usize,
+    lost: usize,
+    bytes: usize,
+    latency_sum: f64,
+    latencies: Vec<f64>,
+}
+
+/// Accumulates the frames of a session, by level.
+#[derive(Debug, Default, Clone)]
+pub struct Tradeoff {
+    levels: BTreeMap<usize, Tally>,
+    last: Option<(usize, Instant)>,
+}
+
+impl Tradeoff {
+    /// Creates an empty tradeoff.
+    pub fn new() -> Tradeoff {
+        Tradeoff::default()
+    }
+
+    /// Records a live frame of `level` with `bytes`, received now after
+    /// `latency_ms`.
+    pub fn add_frame(&mut self, level: usize, bytes: usize, latency_ms: f64) {
+        self.add_frame_at(level, bytes, latency_ms, Instant::now());
+    }
+
+    /// Records a live frame received at `now`. The time since the previous
+    /// frame is spent at the previous frame's level.
+    pub fn add_frame_at(&mut self, level: usize, bytes: usize, latency_ms: f64, now: Instant) {
+        if let Some((prev, at)) = self.last {
+            let gap = now.saturating_duration_since(at).min(MAX_GAP);
+            self.levels.entry(prev).or_default().time += gap;
+        }
+        self.last = Some((level, now));
+        let tally = self.levels.entry(level).or_default();
+        tally.frames += 1;
+        tally.bytes += bytes;
+        tally.latency_sum += latency_ms;
+        if tally.latencies.len() < SAMPLES_PER_LEVEL {
+            tally.latencies.push(latency_ms);
+        } else {
+            // a ring of the most recent samples: replace the oldest
+            let i = (tally.frames - 1) % SAMPLES_PER_LEVEL;
+            tally.latencies[i] = latency_ms;
+        }
+    }
+
+    /// Counts `frames` lost while streaming the level of the last frame.
+    pub fn add_lost(&mut self, frames: usize) {
+        if let Some((level, _)) = self.last {
+            self.levels.entry(level).or_default().lost += frames;
+        }
+    }
+
+    /// Summarizes each level against `expected`, the rates of the profile
+    /// indexed by level (empty if unknown).
+    pub fn report(&self, expected: &[Bandwidth]) -> TradeoffReport {
+        let levels = self
+            .levels
+            .iter()
+            .map(|(&level, tally)| {
+                let time_ms = tally.time.as_secs_f64() * 1000.0;
+                let achieved_kbps = if time_ms > 0.0 {
+                    Some(tally.bytes as f64 * 8.0 / time_ms)
+                } else {
+                    None
+                };
+                let expected_kbps = expected.get(level).map(|bw| bw.kbps());
+                let mut sorted = tally.latencies.clone();
+                sorted.sort_by(|a, b| a.total_cmp(b));
+                let p95 = if sorted.is_empty() {
+                    None
+                } else {
+                    Some(sorted[((sorted.len() - 1) as f64 * 0.95).round() as usize])
+                };
+                let sent = tally.frames + tally.lost;
+                LevelSummary {
+                    level,
+                    time_ms,
+                    frames: tally.frames,
+                    lost: tally.lost,
+                    loss_rate: if sent > 0 { tally.lost as f64 / sent as f64 } else { 0.0 },
+                    achieved_kbps,
+                    expected_kbps,
+                    ratio: match (achieved_kbps, expected_kbps) {
+                        (Some(a), Some(e)) if e > 0.0 => Some(a / e),
+                        _ => None,
+                    },
+                    latency_mean_ms: if tally.frames > 0 {
+                        Some(tally.latency_sum / tally.frames as f64)
+                    } else {
+                        None
+                    },
+                    latency_p95_ms: p95,
+                }
+            })
+            .collect();
+        TradeoffReport { levels }
+    }
+}
+
+/// How one level fared.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LevelSummary {
+    /// The level.
+    pub level: usize,
+
+    /// Time spent streaming it.
+    pub time_ms: f64,
+
+    /// Frames received.
+    pub frames: usize,
+
+    /// Frames the client sent at this level that never arrived (by the
+    /// sender's numbering, not frame numbers that skip on purpose).
+    pub lost: usize,
+
+    /// `lost` over the frames sent.
+    pub loss_rate: f64,
+
+    /// The rate of the frames received, if any time was spent.
+    pub achieved_kbps: Option<f64>,
+
+    /// The rate the profile expects, if known.
+    pub expected_kbps: Option<f64>,
+
+    /// `achieved_kbps` over `expected_kbps`: well below 1, the network did
+    /// not sustain the level.
+    pub ratio: Option<f64>,
+
+    /// Mean latency of the frames.
+    pub latency_mean_ms: Option<f64>,
+
+    /// 95th percentile of the latency of the most recent frames.
+    pub latency_p95_ms: Option<f64>,
+}
+
+/// Time, latency and loss at each level of a session.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TradeoffReport {
+    /// Levels that received frames, in order.
+    pub levels: Vec<LevelSummary>,
+}
+
+impl TradeoffReport {
+    /// The report as CSV, one row per level.
+    pub fn to_csv(&self) -> Result<String> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        for level in &self.levels {
+            writer.serialize(level)?;
+        }
+        let bytes = writer.into_inner().map_err(|e| e.into_error())?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tradeoff_by_level() {
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        let mut tradeoff = Tradeoff::new();
+        // level 1 for 1 s at 8 kbps (1000 bytes) with a lost frame
+        tradeoff.add_frame_at(1, 500, 20.0, ms(0));
+        tradeoff.add_frame_at(1, 500, 40.0, ms(500));
+        tradeoff.add_lost(1);
+        // level 2 after an outage, which counts as `MAX_GAP`
+        tradeoff.add_frame_at(2, 2000, 100.0, ms(1000));
+        tradeoff.add_frame_at(2, 2000, 300.0, ms(60_000));
+        tradeoff.add_frame_at(0, 100, 10.0, ms(60_500));
+
+        let expected = [Bandwidth::from_kbps(4.0), Bandwidth::from_kbps(8.0), Bandwidth::from_kbps(64.0)];
+        let report = tradeoff.report(&expected);
+        let levels = report.levels.iter().map(|l| l.level).collect::<Vec<_>>();
+        assert_eq!(levels, vec![0, 1, 2]);
+
+        let one = &report.levels[1];
+        assert_eq!((one.time_ms, one.frames, one.lost), (1000.0, 2, 1));
+        assert!((one.loss_rate - 1.0 / 3.0).abs() < 1e-9);
+        assert_eq!((one.achieved_kbps, one.ratio), (Some(8.0), Some(1.0)));
+        assert_eq!((one.latency_mean_ms, one.latency_p95_ms), (Some(30.0), Some(40.0)));
+
+        let two = &report.levels[2];
+        assert_eq!(two.time_ms, 1500.0);
+        assert!(two.ratio.unwrap() < 0.5);
+        // no time spent at the last level yet
+        assert_eq!((report.levels[0].achieved_kbps, report.levels[0].ratio), (None, None));
+
+        let csv = report.to_csv().unwrap();
+        assert!(csv.starts_with("level,time_ms,frames,lost,loss_rate,achieved_kbps"));
+        assert_eq!(csv.lines().count(), 4);
+    }
+}
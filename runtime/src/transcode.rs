@@ -0,0 +1,543 @@
+//! Transcoding for sources that cannot adapt themselves.
+//!
+//! Fixed-bitrate IP cameras always deliver the same stream. `Transcoder`
+//! wraps such a source and re-encodes every frame to the parameters of the
+//! current level with a `Reencode` backend (`FfmpegReencoder` with the
+//! `ffmpeg` feature). Re-encoding runs on a thread pool; its duration is
+//! tracked per level so that the controller can avoid levels this machine
+//! cannot encode in real time (see `Source::encoder_limit`).
+
+use super::{Adapt, Annotation, AsDatum, AsDatumType, Bandwidth, Capabilities, CapabilityCheck,
+            Hint, StreamInfo};
+use super::config::Configurable;
+use super::profile::{Profile, SimpleProfile};
+use super::schema::ProfileSchema;
+use super::source::Source;
+use errors::*;
+use futures::{Async, Future, Poll};
+use futures_cpupool::{CpuFuture, CpuPool};
+use serde::de::DeserializeOwned;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "ffmpeg")]
+pub use self::ffmpeg_backend::FfmpegReencoder;
+
+/// Encoding may use at most this share of the frame period.
+const MAX_UTILIZATION: f64 = 0.9;
+
+/// Weight of the newest sample in the moving average of encoding time.
+const EWMA_WEIGHT: f64 = 0.2;
+
+/// Log a CPU report every this many encoded frames.
+const REPORT_EVERY: usize = 300;
+
+/// A backend re-encoding one frame to a configuration.
+pub trait Reencode<C> {
+    /// Re-encodes `frame` (raw or high-bitrate) to `config`. An empty output
+    /// drops the frame (e.g., to reduce the frame rate).
+    fn reencode(&mut self, frame: &[u8], frame_num: usize, config: C) -> Result<Vec<u8>>;
+}
+
+/// CPU usage of encoding at one level.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CpuReport {
+    /// The level.
+    pub level: usize,
+
+    /// Average time to encode a frame (ms).
+    pub encode_ms: f64,
+
+    /// Encoding time as a share of the frame period; above 1.0 the level
+    /// cannot be sustained in real time.
+    pub utilization: f64,
+}
+
+/// `EncodeCost` keeps a moving average of the encoding time per level.
+#[derive(Debug, Clone)]
+pub struct EncodeCost {
+    period_ms: f64,
+    cost_ms: Vec<Option<f64>>,
+}
+
+impl EncodeCost {
+    /// Creates an empty tracker for frames due every `period_in_ms`.
+    pub fn new(period_in_ms: u64) -> EncodeCost {
+        EncodeCost {
+            period_ms: period_in_ms as f64,
+            cost_ms: Vec::new(),
+        }
+    }
+
+    /// Accounts one frame encoded at `level` in `elapsed`.
+    pub fn record(&mut self, level: usize, elapsed: Duration) {
+        let ms = elapsed.as_secs() as f64 * 1000.0 + f64::from(elapsed.subsec_nanos()) / 1e6;
+        if self.cost_ms.len() <= level {
+            self.cost_ms.resize(level + 1, None);
+        }
+        let cost = &mut self.cost_ms[level];
+        *cost = Some(match *cost {
+            Some(avg) => (1.0 - EWMA_WEIGHT) * avg + EWMA_WEIGHT * ms,
+            None => ms,
+        });
+    }
+
+    /// Encoding time at `level` as a share of the frame period, if measured.
+    pub fn utilization(&self, level: usize) -> Option<f64> {
+        self.cost_ms
+            .get(level)
+            .and_then(|c| *c)
+            .map(|ms| ms / self.period_ms)
+    }
+
+    /// The level right below the lowest level measured too expensive, or
+    /// `None` if every measured level fits in real time.
+    pub fn max_realtime_level(&self) -> Option<usize> {
+        (0..self.cost_ms.len())
+            .position(|l| self.utilization(l).is_some_and(|u| u > MAX_UTILIZATION))
+            .map(|l| l.saturating_sub(1))
+    }
+
+    /// Reports every measured level.
+    pub fn report(&self) -> Vec<CpuReport> {
+        (0..self.cost_ms.len())
+            .filter_map(|level| {
+                self.cost_ms[level].map(|encode_ms| {
+                    CpuReport {
+                        level,
+                        encode_ms,
+                        utilization: encode_ms / self.period_ms,
+                    }
+                })
+            })
+            .collect()
+    }
+}
+
+/// A frame re-encoded on the pool.
+struct Encoded {
+    level: usize,
+    frame_num: usize,
+    mem: Vec<u8>,
+    elapsed: Duration,
+    annotation: Option<Annotation>,
+}
+
+/// `Transcoder` re-encodes the frames of a non-adaptive source to the
+/// current level of `profile`. The level of the inner source is ignored.
+pub struct Transcoder<S, R, C> {
+    inner: S,
+    backend: Arc<Mutex<R>>,
+    profile: Profile<C>,
+    pool: CpuPool,
+    pending: Option<CpuFuture<Encoded, Error>>,
+    cost: EncodeCost,
+    encoded: usize,
+}
+
+impl<S, R, C> Transcoder<S, R, C>
+where
+    S: Source,
+    R: Reencode<C> + Send + 'static,
+    C: Configurable + Clone + Debug + Send + 'static,
+{
+    /// Wraps `inner`, re-encoding with `backend` on `pool`.
+    pub fn new(inner: S, backend: R, profile: Profile<C>, pool: CpuPool) -> Transcoder<S, R, C> {
+        Transcoder {
+            cost: EncodeCost::new(inner.period_in_ms()),
+            inner,
+            backend: Arc::new(Mutex::new(backend)),
+            profile,
+            pool,
+            pending: None,
+            encoded: 0,
+        }
+    }
+
+    /// CPU usage of the levels encoded so far.
+    pub fn cpu_report(&self) -> Vec<CpuReport> {
+        self.cost.report()
+    }
+
+    fn encode(&self, frame: AsDatum, frame_num: usize) -> CpuFuture<Encoded, Error> {
+        let backend = self.backend.clone();
+        let level = self.profile.current_level();
+        let config = self.profile.current_config();
+        let annotation = frame.annotation();
+        self.pool.spawn_fn(move || {
+            let mut backend = backend.lock()?;
+            let start = Instant::now();
+            let mem = backend.reencode(&frame.mem, frame_num, config)?;
+            Ok(Encoded {
+                level,
+                frame_num,
+                mem,
+                elapsed: start.elapsed(),
+                annotation,
+            })
+        })
+    }
+}
+
+impl<S, R, C> Adapt for Transcoder<S, R, C>
+where
+    S: Source,
+    R: Reencode<C> + Send + 'static,
+    C: Configurable + ProfileSchema + DeserializeOwned + Clone + Debug + Send + 'static,
+{
+    fn adapt(&mut self, bandwidth: Bandwidth) {
+        self.profile.adjust_config(bandwidth);
+    }
+
+    fn dec_degradation(&mut self) {
+        self.profile.advance_config();
+    }
+
+    fn set_level(&mut self, level: usize) {
+        self.profile.set_config(level);
+    }
+
+    /// Any level of the profile can be re-encoded to.
+    fn try_set_level(&mut self, level: usize) -> Result<()> {
+        if level >= self.profile.len() {
+            bail!(ErrorKind::InvalidConfig(format!("no level {} in the profile", level)));
+        }
+        self.profile.set_config(level);
+        Ok(())
+    }
+
+    fn period_in_ms(&self) -> u64 {
+        self.inner.period_in_ms()
+    }
+
+    fn current_level(&self) -> usize {
+        self.profile.current_level()
+    }
+
+    fn simple_profile(&self) -> SimpleProfile {
+        self.profile.simplify()
+    }
+
+    fn observe_accuracy(&mut self, level: usize, quality: f64, weight: f64) {
+        self.profile.observe_accuracy(level, quality, weight);
+    }
+
+    fn frame_rate(&self, level: usize) -> Option<f64> {
+        self.profile.frame_rate(level)
+    }
+
+    fn restrict(&mut self, caps: &Capabilities, check: CapabilityCheck) -> Result<Vec<usize>> {
+        self.profile.restrict(caps, check)
+    }
+
+    /// The encoding costs measured are of the levels replaced, so they start
+    /// over.
+    fn replace_profile(&mut self, csv: &str) -> Result<()> {
+        self.profile = Profile::from_csv_checked(csv)?;
+        self.cost = EncodeCost::new(self.inner.period_in_ms());
+        Ok(())
+    }
+
+    fn stream_info(&self) -> Option<StreamInfo> {
+        self.inner.stream_info()
+    }
+}
+
+impl<S, R, C> Source for Transcoder<S, R, C>
+where
+    S: Source,
+    R: Reencode<C> + Send + 'static,
+    C: Configurable + ProfileSchema + DeserializeOwned + Clone + Debug + Send + 'static,
+{
+    fn poll_frame(&mut self) -> Poll<Option<AsDatum>, Error> {
+        loop {
+            if self.pending.is_none() {
+                let frame = match try_ready!(self.inner.poll_frame()) {
+                    Some(f) => f,
+                    None => return Ok(Async::Ready(None)),
+                };
+                let frame_num = match frame.datum_type() {
+                    AsDatumType::Live(_, n) => n,
+                    _ => continue,
+                };
+                self.pending = Some(self.encode(frame, frame_num));
+            }
+            let encoded = match self.pending.as_mut() {
+                Some(p) => try_ready!(p.poll()),
+                None => unreachable!(),
+            };
+            self.pending = None;
+
+            self.cost.record(encoded.level, encoded.elapsed);
+            self.encoded += 1;
+            if self.encoded.is_multiple_of(REPORT_EVERY) {
+                for r in self.cost.report() {
+                    info!(
+                        "encode level {}: {:.1} ms/frame, cpu {:.0}%",
+                        r.level,
+                        r.encode_ms,
+                        r.utilization * 100.0
+                    );
+                }
+            }
+
+            if encoded.mem.is_empty() {
+                continue;
+            }
+            let mut datum = AsDatum::new(encoded.level, encoded.frame_num, encoded.mem);
+            if let Some(a) = encoded.annotation {
+                datum = datum.with_annotation(a);
+            }
+            return Ok(Async::Ready(Some(datum)));
+        }
+    }
+
+    fn encoder_limit(&self) -> Option<usize> {
+        self.cost.max_realtime_level()
+    }
+
+    /// Re-encoding can't produce more than the frames it is given.
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn on_hint(&mut self, hint: &Hint) {
+        self.inner.on_hint(hint)
+    }
+}
+
+#[cfg(feature = "ffmpeg")]
+mod ffmpeg_backend {
+    use super::Reencode;
+    use super::super::video::VideoConfig;
+    use errors::*;
+    use ffmpeg;
+    use ffmpeg::codec;
+    use ffmpeg::encoder::video::Encoder;
+    use ffmpeg::format::Pixel;
+    use ffmpeg::software::scaling;
+    use ffmpeg::util::frame::Video as Frame;
+
+    fn backend(e: ffmpeg::Error) -> Error {
+        Error::from_kind(ErrorKind::SourceBackend(e.to_string()))
+    }
+
+    /// Decodes the camera's stream (`input`, e.g., H.264) and re-encodes it
+    /// with libx264 to the width, frame rate and quantizer of a `VideoConfig`.
+    pub struct FfmpegReencoder {
+        decoder: ffmpeg::decoder::Video,
+        encoder: Option<(VideoConfig, u32, Encoder)>,
+        scaler: Option<scaling::Context>,
+    }
+
+    // The raw codec contexts are only ever used by one thread at a time.
+    unsafe impl Send for FfmpegReencoder {}
+
+    impl FfmpegReencoder {
+        /// Creates a re-encoder for an input stream of codec `input`.
+        pub fn new(input: codec::Id) -> Result<FfmpegReencoder> {
+            ffmpeg::init().map_err(backend)?;
+            let codec = ffmpeg::decoder::find(input).ok_or_else(|| {
+                Error::from_kind(ErrorKind::SourceBackend(format!("no decoder for {:?}", input)))
+            })?;
+            let decoder = codec::context::Context::new_with_codec(codec)
+                .decoder()
+                .video()
+                .map_err(backend)?;
+            Ok(FfmpegReencoder {
+                decoder,
+                encoder: None,
+                scaler: None,
+            })
+        }
+
+        /// Opens an encoder for `config` at `height`, unless the current one
+        /// already matches.
+        fn ensure_encoder(&mut self, config: VideoConfig, height: u32) -> Result<()> {
+            let reopen = match self.encoder {
+                Some((c, h, _)) => c != config || h != height,
+                None => true,
+            };
+            if reopen {
+                let codec = ffmpeg::encoder::find_by_name("libx264").ok_or_else(|| {
+                    Error::from_kind(ErrorKind::SourceBackend("libx264 not available".into()))
+                })?;
+                let mut ctx = codec::context::Context::new_with_codec(codec)
+                    .encoder()
+                    .video()
+                    .map_err(backend)?;
+                ctx.set_width(config.width as u32);
+                ctx.set_height(height);
+                ctx.set_format(Pixel::YUV420P);
+                // one frame in `skip + 1` of the original, i.e., `config.fps()`
+                ctx.set_time_base(((config.skip + 1) as i32, VideoConfig::FPS as i32));
+                let mut opts = ffmpeg::Dictionary::new();
+                opts.set("preset", "ultrafast");
+                opts.set("tune", "zerolatency");
+                opts.set("qp", &config.quant.to_string());
+                let encoder = ctx.open_with(opts).map_err(backend)?;
+                self.encoder = Some((config, height, encoder));
+                self.scaler = None;
+            }
+            Ok(())
+        }
+    }
+
+    /// Whether `frame_num` (from 1) is dropped when keeping one frame in
+    /// `skip + 1`, as the profiles were measured.
+    fn skipped(frame_num: usize, skip: usize) -> bool {
+        frame_num.wrapping_sub(1) % (skip + 1) != 0
+    }
+
+    impl Reencode<VideoConfig> for FfmpegReencoder {
+        fn reencode(&mut self, frame: &[u8], frame_num: usize, config: VideoConfig) -> Result<Vec<u8>> {
+            self.decoder
+                .send_packet(&ffmpeg::Packet::copy(frame))
+                .map_err(backend)?;
+            let mut out = Vec::new();
+            let mut decoded = Frame::empty();
+            while self.decoder.receive_frame(&mut decoded).is_ok() {
+                if skipped(frame_num, config.skip) {
+                    continue;
+                }
+                let (w, h) = (decoded.width(), decoded.height());
+                let height = (h as usize * config.width / w as usize) as u32 & !1;
+                self.ensure_encoder(config, height)?;
+                let mut scaled = Frame::empty();
+                if self.scaler.is_none() {
+                    self.scaler = Some(
+                        scaling::Context::get(
+                            decoded.format(),
+                            w,
+                            h,
+                            Pixel::YUV420P,
+                            config.width as u32,
+                            height,
+                            scaling::Flags::BILINEAR,
+                        ).map_err(backend)?,
+                    );
+                }
+                if let Some(ref mut scaler) = self.scaler {
+                    scaler.run(&decoded, &mut scaled).map_err(backend)?;
+                }
+                scaled.set_pts(Some(frame_num as i64));
+                let encoder = match self.encoder {
+                    Some((_, _, ref mut e)) => e,
+                    None => unreachable!(),
+                };
+                encoder.send_frame(&scaled).map_err(backend)?;
+                let mut packet = ffmpeg::Packet::empty();
+                while encoder.receive_packet(&mut packet).is_ok() {
+                    if let Some(data) = packet.data() {
+                        out.extend_from_slice(data);
+                    }
+                }
+            }
+            Ok(out)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use profile::Record;
+
+    #[test]
+    fn test_max_realtime_level() {
+        let mut cost = EncodeCost::new(100);
+        assert_eq!(cost.max_realtime_level(), None);
+
+        cost.record(0, Duration::from_millis(20));
+        cost.record(1, Duration::from_millis(50));
+        assert_eq!(cost.max_realtime_level(), None);
+
+        cost.record(3, Duration::from_millis(150));
+        assert_eq!(cost.max_realtime_level(), Some(2));
+        assert_eq!(cost.utilization(3), Some(1.5));
+        assert_eq!(cost.report().len(), 3);
+    }
+
+    /// Emits a 100-byte frame per poll.
+    struct Camera {
+        frame: usize,
+    }
+
+    impl Adapt for Camera {
+        fn adapt(&mut self, _bandwidth: Bandwidth) {}
+        fn dec_degradation(&mut self) {}
+        fn set_level(&mut self, _level: usize) {}
+        fn period_in_ms(&self) -> u64 {
+            10
+        }
+        fn current_level(&self) -> usize {
+            0
+        }
+        fn simple_profile(&self) -> SimpleProfile {
+            Profile::_with_vec(vec![Record::new(Bandwidth::from_kbps(1.0), (), 0.0)]).simplify()
+        }
+    }
+
+    impl Source for Camera {
+        fn poll_frame(&mut self) -> Poll<Option<AsDatum>, Error> {
+            self.frame += 1;
+            Ok(Async::Ready(Some(AsDatum::new(0, self.frame, vec![0; 100]))))
+        }
+    }
+
+    #[derive(Deserialize, Debug, Clone, Copy)]
+    struct Divisor {
+        by: usize,
+    }
+
+    profile_schema!(Divisor { by: usize });
+
+    impl Configurable for Divisor {
+        fn validate(&self) -> Result<()> {
+            Ok(())
+        }
+        fn apply_delta(&self, _prev: &Self) -> ::config::ConfigDelta {
+            ::config::ConfigDelta::new()
+        }
+    }
+
+    /// Shrinks each frame by the divisor of the config.
+    struct Shrink;
+
+    impl Reencode<Divisor> for Shrink {
+        fn reencode(&mut self, frame: &[u8], _n: usize, config: Divisor) -> Result<Vec<u8>> {
+            Ok(frame[..frame.len() / config.by].to_vec())
+        }
+    }
+
+    #[test]
+    fn test_transcoder_follows_level() {
+        let records = vec![
+            Record::new(Bandwidth::from_kbps(10.0), Divisor { by: 4 }, 0.0),
+            Record::new(Bandwidth::from_kbps(20.0), Divisor { by: 1 }, 0.0),
+        ];
+        let profile = Profile::_with_vec(records);
+        let mut t = Transcoder::new(Camera { frame: 0 }, Shrink, profile, CpuPool::new(1));
+
+        let first = future::poll_fn(|| t.poll_frame()).wait().unwrap().unwrap();
+        assert_eq!(first.datum_type(), AsDatumType::Live(0, 1));
+        assert_eq!(first.mem.len(), 25);
+
+        t.set_level(1);
+        let second = future::poll_fn(|| t.poll_frame()).wait().unwrap().unwrap();
+        assert_eq!(second.datum_type(), AsDatumType::Live(1, 2));
+        assert_eq!(second.mem.len(), 100);
+        assert_eq!(t.cpu_report().len(), 2);
+
+        assert!(t.try_set_level(2).is_err());
+        assert_eq!(t.current_level(), 1);
+        t.replace_profile("10.0,2,0.0\n20.0,1,0.0\n30.0,1,0.5\n").unwrap();
+        assert_eq!(t.simple_profile().num_levels(), 3);
+        assert!(t.cpu_report().is_empty());
+        t.try_set_level(2).unwrap();
+        let third = future::poll_fn(|| t.poll_frame()).wait().unwrap().unwrap();
+        assert_eq!(third.datum_type(), AsDatumType::Live(2, 3));
+    }
+}
@@ -0,0 +1,241 @@
+//! Separate profiles per network interface type.
+//!
+//! The same device may stream over ethernet, wifi or a cellular modem, each
+//! with its own rates, jitter and cost. With `uplinks` set, the client finds
+//! the interface of its route to the server, classifies it, and uses what is
+//! configured for that type:
+//!
+//! ```text
+//! profile_path   the profile to stream with (a profile hosted by the server
+//!                still takes precedence)
+//! max_level      the highest level to stream at
+//! ```
+//!
+//! e.g., `uplinks = { cellular = { max_level = 2 } }`. The route is checked
+//! again every few seconds: a new ceiling applies right away, while a new
+//! profile rebuilds the source and reconnects, resuming the session.
+//!
+//! Routes and interfaces are read from procfs and sysfs, i.e., on Linux only.
+//! Elsewhere the uplink is unknown and nothing is applied.
+
+use decision::SharedClock;
+use errors::*;
+use futures::{Async, Poll, Stream};
+#[cfg(target_os = "linux")]
+use std::fs;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+use ticker::Ticker;
+
+/// How often the route to the server is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The type of an uplink.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Uplink {
+    /// A wired link.
+    Ethernet,
+
+    /// A wireless LAN.
+    Wifi,
+
+    /// A cellular modem.
+    Cellular,
+}
+
+impl fmt::Display for Uplink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Uplink::Ethernet => write!(f, "ethernet"),
+            Uplink::Wifi => write!(f, "wifi"),
+            Uplink::Cellular => write!(f, "cellular"),
+        }
+    }
+}
+
+/// What applies over one type of uplink.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct UplinkConfig {
+    /// The profile to stream with, instead of `profile_path`.
+    pub profile_path: Option<String>,
+
+    /// The highest level to stream at.
+    pub max_level: Option<usize>,
+}
+
+/// What applies over each type of uplink; unset types use the defaults.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct UplinkProfiles {
+    /// Over ethernet.
+    pub ethernet: Option<UplinkConfig>,
+
+    /// Over wifi.
+    pub wifi: Option<UplinkConfig>,
+
+    /// Over a cellular modem.
+    pub cellular: Option<UplinkConfig>,
+}
+
+impl UplinkProfiles {
+    /// What applies over `uplink`, if anything.
+    pub fn get(&self, uplink: Option<Uplink>) -> Option<&UplinkConfig> {
+        match uplink? {
+            Uplink::Ethernet => self.ethernet.as_ref(),
+            Uplink::Wifi => self.wifi.as_ref(),
+            Uplink::Cellular => self.cellular.as_ref(),
+        }
+    }
+}
+
+/// The interface of the most specific route to `dest` in `table`, formatted
+/// as `/proc/net/route` (addresses in hex, in host byte order).
+fn route_v4(table: &str, dest: Ipv4Addr) -> Option<String> {
+    let dest = u32::from_le_bytes(dest.octets());
+    let hex = |f: &str| u32::from_str_radix(f, 16).ok();
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let (iface, net, metric, mask) = (fields.first()?, hex(fields.get(1)?)?, fields.get(6)?, hex(fields.get(7)?)?);
+            if dest & mask != net {
+                return None;
+            }
+            Some((mask.count_ones(), metric.parse::<u32>().ok()?, iface.to_string()))
+        })
+        .max_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)))
+        .map(|(_, _, iface)| iface)
+}
+
+/// Like `route_v4`, for `/proc/net/ipv6_route`.
+fn route_v6(table: &str, dest: Ipv6Addr) -> Option<String> {
+    let dest = u128::from_be_bytes(dest.octets());
+    table
+        .lines()
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let net = u128::from_str_radix(fields.first()?, 16).ok()?;
+            let len = u32::from_str_radix(fields.get(1)?, 16).ok()?;
+            let metric = u32::from_str_radix(fields.get(5)?, 16).ok()?;
+            let mask = u128::MAX.checked_shl(128 - len).unwrap_or(0);
+            if dest & mask != net & mask {
+                return None;
+            }
+            Some((len, metric, fields.get(9)?.to_string()))
+        })
+        .max_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)))
+        .map(|(_, _, iface)| iface)
+}
+
+/// The type of the interface `name`, from its `DEVTYPE` in sysfs, whether
+/// it is `wireless`, or else the usual naming schemes.
+fn classify(name: &str, devtype: Option<&str>, wireless: bool) -> Option<Uplink> {
+    match devtype {
+        Some("wlan") => return Some(Uplink::Wifi),
+        Some("wwan") => return Some(Uplink::Cellular),
+        _ => {}
+    }
+    if wireless {
+        return Some(Uplink::Wifi);
+    }
+    let is = |prefixes: &[&str]| prefixes.iter().any(|p| name.starts_with(p));
+    if is(&["wwan", "wwp", "rmnet", "ccmni", "ppp"]) {
+        Some(Uplink::Cellular)
+    } else if is(&["wl"]) {
+        Some(Uplink::Wifi)
+    } else if is(&["eth", "en", "em"]) {
+        Some(Uplink::Ethernet)
+    } else {
+        None
+    }
+}
+
+/// The type of the uplink the route to `server` goes through, if known.
+#[cfg(target_os = "linux")]
+pub fn detect(server: IpAddr) -> Option<Uplink> {
+    let iface = match server {
+        IpAddr::V4(ip) => route_v4(&fs::read_to_string("/proc/net/route").ok()?, ip)?,
+        IpAddr::V6(ip) => route_v6(&fs::read_to_string("/proc/net/ipv6_route").ok()?, ip)?,
+    };
+    let sys = format!("/sys/class/net/{}", iface);
+    let uevent = fs::read_to_string(format!("{}/uevent", sys)).unwrap_or_default();
+    let devtype = uevent.lines().find_map(|line| line.strip_prefix("DEVTYPE="));
+    let wireless = fs::metadata(format!("{}/wireless", sys)).is_ok();
+    classify(&iface, devtype, wireless)
+}
+
+/// The type of the uplink the route to `server` goes through, if known.
+#[cfg(not(target_os = "linux"))]
+pub fn detect(_server: IpAddr) -> Option<Uplink> {
+    None
+}
+
+/// A stream of the uplinks the route to the server moves to.
+pub struct UplinkMonitor {
+    server: IpAddr,
+    current: Option<Uplink>,
+    ticker: Ticker,
+}
+
+impl UplinkMonitor {
+    /// Checks the route to `server` every few seconds of `clock`, starting
+    /// from `current`.
+    pub fn new(server: IpAddr, current: Option<Uplink>, clock: SharedClock) -> UplinkMonitor {
+        UplinkMonitor {
+            server,
+            current,
+            ticker: Ticker::new(clock, CHECK_INTERVAL),
+        }
+    }
+}
+
+impl Stream for UplinkMonitor {
+    type Item = Uplink;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Uplink>, Error> {
+        while try_ready!(self.ticker.poll()).is_some() {
+            // a route briefly missing (e.g., during a handover) is no change
+            match detect(self.server) {
+                Some(uplink) if Some(uplink) != self.current => {
+                    self.current = Some(uplink);
+                    return Ok(Async::Ready(Some(uplink)));
+                }
+                _ => {}
+            }
+        }
+        Ok(Async::Ready(None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routes_and_classifies_uplink() {
+        let table = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+                     wlan0\t00000000\t0101A8C0\t0003\t0\t0\t600\t00000000\t0\t0\t0\n\
+                     eth0\t00000000\t01000A0A\t0003\t0\t0\t100\t00000000\t0\t0\t0\n\
+                     wwan0\t0000000A\t00000000\t0001\t0\t0\t0\t000000FF\t0\t0\t0\n";
+        // the default route with the lowest metric, unless a narrower one
+        // matches
+        assert_eq!(route_v4(table, Ipv4Addr::new(93, 184, 216, 34)), Some("eth0".into()));
+        assert_eq!(route_v4(table, Ipv4Addr::new(10, 1, 2, 3)), Some("wwan0".into()));
+        let table = "00000000000000000000000000000000 00 00000000000000000000000000000000 00 fe800000000000000000000000000001 00000400 00000001 00000000 00000003 wlp2s0\n\
+                     20010db8000000000000000000000000 20 00000000000000000000000000000000 00 00000000000000000000000000000000 00000100 00000001 00000000 00000001 enp3s0\n";
+        assert_eq!(route_v6(table, "2001:db8::1".parse().unwrap()), Some("enp3s0".into()));
+        assert_eq!(route_v6(table, "2606:2800::1".parse().unwrap()), Some("wlp2s0".into()));
+
+        assert_eq!(classify("wlp2s0", None, false), Some(Uplink::Wifi));
+        assert_eq!(classify("usb0", Some("wwan"), false), Some(Uplink::Cellular));
+        assert_eq!(classify("eth1", None, true), Some(Uplink::Wifi));
+        assert_eq!(classify("rmnet_data0", None, false), Some(Uplink::Cellular));
+        assert_eq!(classify("enp3s0", None, false), Some(Uplink::Ethernet));
+        assert_eq!(classify("lo", None, false), None);
+    }
+}
@@ -1,27 +1,5 @@
 //! Utility structures and functions.
 
-pub struct ExponentialSmooth {
-    val: f64,
-    alpha: f64,
-}
-
-impl ExponentialSmooth {
-    pub fn new(alpha: f64) -> Self {
-        ExponentialSmooth {
-            val: 0.0,
-            alpha: alpha,
-        }
-    }
-
-    pub fn add(&mut self, sample: f64) {
-        self.val = self.val * self.alpha + sample * (1.0 - self.alpha);
-    }
-
-    pub fn val(&self) -> f64 {
-        self.val
-    }
-}
-
 pub struct StreamingStat {
     buffer: Vec<f64>,
     pos: usize,
@@ -30,7 +8,7 @@ pub struct StreamingStat {
 
 impl StreamingStat {
     pub fn new(init: f64, size: usize) -> Self {
-        assert!(init != ::std::f64::NAN);
+        assert!(!init.is_nan());
         assert!(size > 0);
         StreamingStat {
             pos: 0,
@@ -40,7 +18,7 @@ impl StreamingStat {
     }
 
     pub fn add(&mut self, sample: f64) {
-        assert!(sample != ::std::f64::NAN);
+        assert!(!sample.is_nan());
         self.buffer[self.pos] = sample;
         self.pos += 1;
         if self.pos == self.capacity {
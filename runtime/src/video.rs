@@ -1,5 +1,7 @@
-use super::Adapt;
+use super::{Adapt, Bandwidth, Capabilities, CapabilityCheck, StreamInfo};
 use super::Experiment;
+use super::config::{ConfigDelta, Configurable, Demand};
+use super::errors::*;
 use super::profile::{Profile, SimpleProfile};
 use csv;
 use std::collections::BTreeMap;
@@ -13,18 +15,76 @@ pub struct VideoConfig {
     pub quant: usize,
 }
 
+profile_schema!(VideoConfig { width: usize, skip: usize, quant: usize });
+
 impl ::std::fmt::Display for VideoConfig {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         write!(f, "{}x{}x{}", self.width, self.skip, self.quant)
     }
 }
 
+impl VideoConfig {
+    /// Frames per second of the original video.
+    pub(crate) const FPS: usize = 30;
+
+    /// Largest quantization parameter accepted by H.264.
+    const MAX_QUANT: usize = 51;
+
+    /// Frames per second sent at this config: one frame in `skip + 1` of the
+    /// original video.
+    pub fn fps(&self) -> f64 {
+        VideoConfig::FPS as f64 / (self.skip + 1) as f64
+    }
+}
+
+impl Configurable for VideoConfig {
+    fn validate(&self) -> Result<()> {
+        if self.width == 0 {
+            bail!(ErrorKind::InvalidConfig("width must be positive".into()));
+        }
+        if self.skip >= VideoConfig::FPS {
+            bail!(ErrorKind::InvalidConfig(format!(
+                "skip {} leaves less than a frame per second of {} fps",
+                self.skip,
+                VideoConfig::FPS
+            )));
+        }
+        if self.quant > VideoConfig::MAX_QUANT {
+            bail!(ErrorKind::InvalidConfig(format!(
+                "quant {} exceeds {}",
+                self.quant,
+                VideoConfig::MAX_QUANT
+            )));
+        }
+        Ok(())
+    }
+
+    fn apply_delta(&self, prev: &Self) -> ConfigDelta {
+        let mut delta = ConfigDelta::new();
+        delta.push("width", prev.width, self.width);
+        delta.push("skip", prev.skip, self.skip);
+        delta.push("quant", prev.quant, self.quant);
+        delta
+    }
+
+    fn demand(&self) -> Demand {
+        Demand {
+            width: Some(self.width),
+            height: None,
+            fps: Some(self.fps()),
+            format: Some("h264".into()),
+        }
+    }
+}
+
 pub struct VideoSource {
     map: BTreeMap<(VideoConfig, usize), usize>,
     frame: usize,
     num: usize,
     config: VideoConfig,
     profile: Profile<VideoConfig>,
+    /// The name of the profile file, or `hosted` once replaced.
+    profile_id: String,
 }
 
 impl VideoSource {
@@ -46,23 +106,27 @@ impl VideoSource {
             num = ::std::cmp::max(num, record.1);
         }
 
+        let profile_id = profile.as_ref().file_stem().map(|s| s.to_string_lossy().into_owned());
         let p = Profile::new(profile);
         let init = p.init_config();
         VideoSource {
-            map: map,
+            map,
             frame: 1,
-            num: num,
+            num,
             config: init,
             profile: p,
+            profile_id: profile_id.unwrap_or_default(),
         }
     }
 
     pub fn next_frame(&mut self) -> (usize, usize) {
-        let frame_size = self.map.get(&(self.config, self.frame)).expect(&format!(
-            "Source file corrupted. Failed to find frame size for {}@{}",
-            self.config,
-            self.frame
-        ));
+        let frame_size = self.map.get(&(self.config, self.frame)).unwrap_or_else(|| {
+            panic!(
+                "Source file corrupted. Failed to find frame size for {}@{}",
+                self.config,
+                self.frame
+            )
+        });
         let frame_num = self.frame;
         self.frame += 1;
         if self.frame >= self.num {
@@ -73,10 +137,9 @@ impl VideoSource {
 }
 
 impl Adapt for VideoSource {
-    fn adapt(&mut self, bw: f64) {
-        match self.profile.adjust_config(bw) {
-            Some(c) => self.config = c.config,
-            None => {}
+    fn adapt(&mut self, bw: Bandwidth) {
+        if let Some(c) = self.profile.adjust_config(bw) {
+            self.config = c.config
         }
     }
 
@@ -85,9 +148,14 @@ impl Adapt for VideoSource {
     }
 
     fn dec_degradation(&mut self) {
-        match self.profile.advance_config() {
-            Some(c) => self.config = c.config,
-            None => {}
+        if let Some(c) = self.profile.advance_config() {
+            self.config = c.config
+        }
+    }
+
+    fn set_level(&mut self, level: usize) {
+        if let Some(c) = self.profile.set_config(level) {
+            self.config = c.config
         }
     }
 
@@ -98,6 +166,43 @@ impl Adapt for VideoSource {
     fn period_in_ms(&self) -> u64 {
         33
     }
+
+    fn observe_accuracy(&mut self, level: usize, quality: f64, weight: f64) {
+        if let Some(a) = self.profile.observe_accuracy(level, quality, weight) {
+            debug!("accuracy of level {} is now {:.4}", level, a);
+        }
+    }
+
+    fn frame_rate(&self, level: usize) -> Option<f64> {
+        self.profile.frame_rate(level)
+    }
+
+    fn restrict(&mut self, caps: &Capabilities, check: CapabilityCheck) -> Result<Vec<usize>> {
+        let masked = self.profile.restrict(caps, check)?;
+        self.config = self.profile.current_config();
+        Ok(masked)
+    }
+
+    /// Rejects profiles with configs missing from the source file.
+    fn replace_profile(&mut self, csv: &str) -> Result<()> {
+        let profile = Profile::from_csv_checked(csv)?;
+        if let Some(r) = profile.iter().find(|r| !self.map.contains_key(&(r.config, 1))) {
+            bail!(ErrorKind::InvalidConfig(format!("no frames for {}", r.config)));
+        }
+        self.profile = profile;
+        self.config = self.profile.init_config();
+        self.profile_id = "hosted".into();
+        Ok(())
+    }
+
+    fn stream_info(&self) -> Option<StreamInfo> {
+        Some(StreamInfo {
+            codec: Some("h264".into()),
+            resolution: Some(format!("{}w@{}fps", self.config.width, self.config.fps())),
+            profile_id: Some(self.profile_id.clone()),
+            ..StreamInfo::default()
+        })
+    }
 }
 
 impl Experiment for VideoSource {
@@ -105,3 +210,18 @@ impl Experiment for VideoSource {
         self.next_frame()
     }
 }
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+    use evaluation::{all_configurations, skip_to_fps};
+
+    #[test]
+    fn test_demand_keeps_one_frame_in_skip_plus_one() {
+        for c in all_configurations() {
+            let config = VideoConfig { width: c.width, skip: c.skip, quant: c.quant };
+            assert_eq!(config.demand().fps, Some(skip_to_fps(c.skip) as f64), "skip {}", c.skip);
+        }
+        assert_eq!(VideoConfig { width: 640, skip: 2, quant: 20 }.fps(), 10.0);
+    }
+}
@@ -0,0 +1,193 @@
+//! The last level a client held steadily, kept across restarts.
+//!
+//! A client starts at the bottom of its profile and climbs as its estimates
+//! allow, which takes a while on a fast link. With `warm_start_path` set, it
+//! records the level it held for `STABLE_MS` and the throughput it measured
+//! meanwhile in a small state file, and the next run starts from there:
+//!
+//! ```text
+//! level = 3
+//! num_levels = 5
+//! throughput_kbps = 2480.5
+//! time_ms = 1760520000000
+//! ```
+//!
+//! A record older than `MAX_AGE_MS`, or of a profile with another number of
+//! levels, is ignored. The next run starts no higher than the level its
+//! measured throughput carried (allowing for `THROUGHPUT_TOLERANCE`), and
+//! adaptation takes over from there.
+
+use super::Bandwidth;
+use chrono::Utc;
+use errors::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml;
+
+/// How long (ms) a level must be held before it is recorded.
+pub const STABLE_MS: u64 = 10_000;
+
+/// How old (ms) a record may be to start from.
+pub const MAX_AGE_MS: i64 = 24 * 3600 * 1000;
+
+/// How far (as a fraction of its rate) the throughput measured may fall
+/// short of a level that still counts as carried. The throughput at a level
+/// hovers around its rate, often just below it.
+pub const THROUGHPUT_TOLERANCE: f64 = 0.1;
+
+/// A level held steadily, as recorded.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct LastKnownGood {
+    /// The level.
+    pub level: usize,
+
+    /// The number of levels of the profile.
+    pub num_levels: usize,
+
+    /// The throughput measured at that level (kbps).
+    pub throughput_kbps: f64,
+
+    /// When it was recorded (ms since unix epoch).
+    pub time_ms: i64,
+}
+
+impl LastKnownGood {
+    /// Reads the record of `path`, if any and recent enough.
+    pub fn load<P: AsRef<Path>>(path: P) -> Option<LastKnownGood> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).ok()?;
+        let record: LastKnownGood = match toml::from_str(&contents) {
+            Ok(record) => record,
+            Err(e) => {
+                warn!("ignoring {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        let age = Utc::now().timestamp_millis() - record.time_ms;
+        if age > MAX_AGE_MS {
+            info!("ignoring the level recorded {} s ago", age / 1000);
+            return None;
+        }
+        Some(record)
+    }
+
+    /// Writes the record to `path`, replacing the file at once.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        let contents = toml::to_string(self).chain_err(|| ErrorKind::InvalidConfig("unserializable level".into()))?;
+        fs::write(&partial, contents)?;
+        fs::rename(&partial, path)?;
+        Ok(())
+    }
+
+    /// The level to start at with a profile of `rates`: the recorded one,
+    /// lowered to what the measured throughput carries, within
+    /// `THROUGHPUT_TOLERANCE`. `None` if the profile changed.
+    pub fn start_level(&self, rates: &[Bandwidth]) -> Option<usize> {
+        if rates.len() != self.num_levels || rates.is_empty() {
+            return None;
+        }
+        let carried = |rate: Bandwidth| rate.kbps() * (1.0 - THROUGHPUT_TOLERANCE) <= self.throughput_kbps;
+        let level = self.level.min(rates.len() - 1);
+        Some((0..=level).rev().find(|&l| carried(rates[l])).unwrap_or(0))
+    }
+}
+
+/// Records the levels a client holds steadily.
+#[derive(Debug)]
+pub struct WarmStart {
+    path: PathBuf,
+    num_levels: usize,
+    /// The level held, and since when (ms of the client's clock).
+    held: Option<(usize, u64)>,
+    saved: Option<usize>,
+}
+
+impl WarmStart {
+    /// Records the levels of a profile of `num_levels` to `path`.
+    pub fn new<P: Into<PathBuf>>(path: P, num_levels: usize) -> WarmStart {
+        WarmStart {
+            path: path.into(),
+            num_levels,
+            held: None,
+            saved: None,
+        }
+    }
+
+    /// Observes `level` at `now_ms` with the current throughput, writing the
+    /// record once the level has been held for `STABLE_MS`. Returns true if
+    /// it wrote.
+    pub fn observe(&mut self, level: usize, throughput: Bandwidth, now_ms: u64) -> bool {
+        let since = match self.held {
+            Some((held, since)) if held == level => since,
+            _ => {
+                self.held = Some((level, now_ms));
+                now_ms
+            }
+        };
+        if now_ms < since + STABLE_MS || self.saved == Some(level) {
+            return false;
+        }
+        let record = LastKnownGood {
+            level,
+            num_levels: self.num_levels,
+            throughput_kbps: throughput.kbps(),
+            time_ms: Utc::now().timestamp_millis(),
+        };
+        match record.save(&self.path) {
+            Ok(()) => {
+                debug!("recorded level {} as last known good", level);
+                self.saved = Some(level);
+                true
+            }
+            Err(e) => {
+                warn!("failed to record the level: {}", e);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_and_start_from_stable_level() {
+        let path = ::std::env::temp_dir().join(format!("awstream-warm-{}.toml", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+        assert!(LastKnownGood::load(&path).is_none());
+
+        let mut warm = WarmStart::new(&path, 4);
+        let bw = Bandwidth::from_kbps(1000.0);
+        assert!(!warm.observe(1, bw, 0));
+        // level 2 is left before it is stable
+        assert!(!warm.observe(2, bw, 5_000));
+        assert!(!warm.observe(3, bw, 14_000));
+        assert!(warm.observe(3, bw, 24_000));
+        assert!(!warm.observe(3, bw, 30_000));
+
+        let record = LastKnownGood::load(&path).unwrap();
+        assert_eq!((record.level, record.num_levels, record.throughput_kbps), (3, 4, 1000.0));
+        let rates = [100.0, 400.0, 900.0, 1500.0].iter().map(|&r| Bandwidth::from_kbps(r)).collect::<Vec<_>>();
+        // level 3 didn't fit the throughput measured
+        assert_eq!(record.start_level(&rates), Some(2));
+        assert_eq!(record.start_level(&rates[..3]), None);
+        // measured just below the rate of the level held
+        let held = LastKnownGood {
+            throughput_kbps: 1420.0,
+            ..record
+        };
+        assert_eq!(held.start_level(&rates), Some(3));
+
+        let stale = LastKnownGood {
+            time_ms: record.time_ms - MAX_AGE_MS - 1,
+            ..record
+        };
+        stale.save(&path).unwrap();
+        assert!(LastKnownGood::load(&path).is_none());
+        fs::remove_file(&path).unwrap();
+    }
+}
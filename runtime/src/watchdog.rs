@@ -0,0 +1,213 @@
+//! Restarts a client pipeline that makes no progress.
+//!
+//! A stage that deadlocks (a capture pipeline that stopped producing, a
+//! socket that stopped draining) otherwise leaves a field device streaming
+//! nothing until someone restarts it. With `watchdog` set, the client checks
+//! every second how long it has been since the pipeline progressed:
+//!
+//! ```text
+//! no frame produced for stall_ms                               -> source
+//! frames produced but none sent for stall_ms, while the server
+//! reported no congestion                                       -> socket
+//! ```
+//!
+//! A wedged pipeline is reported as a `WatchdogEvent` and torn down: the run
+//! fails with `ErrorKind::Wedged`, and `Client::run` (or `Client::supervise`)
+//! rebuilds the source and reconnects, resuming the session.
+
+use super::{AsDatum, AsDatumType};
+use decision::SharedClock;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// When the watchdog gives up on a pipeline.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct WatchdogConfig {
+    /// How long (ms) a stage may make no progress.
+    pub stall_ms: u64,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig { stall_ms: 10_000 }
+    }
+}
+
+/// A stage of the client's pipeline.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// The source, up to the send queue.
+    Source,
+
+    /// The send queue, down to the connection.
+    Socket,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Stage::Source => write!(f, "source"),
+            Stage::Socket => write!(f, "socket"),
+        }
+    }
+}
+
+/// A pipeline found wedged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchdogEvent {
+    /// The stage making no progress.
+    pub stage: Stage,
+
+    /// How long (ms) it made none.
+    pub stalled_ms: u64,
+
+    /// Frames produced by the source during the run.
+    pub produced: usize,
+
+    /// Frames taken by the connection during the run.
+    pub sent: usize,
+}
+
+/// Counts the frames passing through the pipeline, leaving out what the
+/// source sends while its frames stalled (padding, probes, stream info).
+/// Clones share counts.
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    produced: Arc<AtomicUsize>,
+    sent: Arc<AtomicUsize>,
+}
+
+impl Progress {
+    /// Counts `datum`, produced by the source, if a frame.
+    pub fn add_produced(&self, datum: &AsDatum) {
+        if is_frame(datum) {
+            self.produced.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts `datum`, taken by the connection, if a frame.
+    pub fn add_sent(&self, datum: &AsDatum) {
+        if is_frame(datum) {
+            self.sent.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn read(&self) -> (usize, usize) {
+        (self.produced.load(Ordering::Relaxed), self.sent.load(Ordering::Relaxed))
+    }
+}
+
+fn is_frame(datum: &AsDatum) -> bool {
+    matches!(datum.datum_type(), AsDatumType::Live(..) | AsDatumType::Reference(..))
+}
+
+/// Tells a wedged pipeline from a slow one.
+pub struct Watchdog {
+    config: WatchdogConfig,
+    progress: Progress,
+    clock: SharedClock,
+    /// The counts last seen, and when each last moved (ms).
+    produced: (usize, u64),
+    sent: (usize, u64),
+    congested_ms: Option<u64>,
+}
+
+impl Watchdog {
+    /// Watches the counts of `progress`, from now on.
+    pub fn new(config: WatchdogConfig, progress: Progress, clock: SharedClock) -> Watchdog {
+        let now = clock.now_ms();
+        let (produced, sent) = progress.read();
+        Watchdog {
+            config,
+            progress,
+            clock,
+            produced: (produced, now),
+            sent: (sent, now),
+            congested_ms: None,
+        }
+    }
+
+    /// Notes that the server reported congestion: the path is slow, but
+    /// alive.
+    pub fn observe_congestion(&mut self) {
+        self.congested_ms = Some(self.clock.now_ms());
+    }
+
+    /// Returns the stage making no progress, if any.
+    pub fn check(&mut self) -> Option<WatchdogEvent> {
+        let now = self.clock.now_ms();
+        let (produced, sent) = self.progress.read();
+        if produced != self.produced.0 {
+            self.produced = (produced, now);
+        }
+        if sent != self.sent.0 {
+            self.sent = (sent, now);
+        }
+        let stall = self.config.stall_ms;
+        let event = |stage, since| WatchdogEvent {
+            stage,
+            stalled_ms: now - since,
+            produced,
+            sent,
+        };
+        if now - self.produced.1 >= stall {
+            return Some(event(Stage::Source, self.produced.1));
+        }
+        let waiting = produced > sent;
+        let congested = self.congested_ms.is_some_and(|at| now - at < stall);
+        if waiting && !congested && now - self.sent.1 >= stall {
+            return Some(event(Stage::Socket, self.sent.1));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use decision::ManualClock;
+
+    #[test]
+    fn test_detects_wedged_stage() {
+        let clock = ManualClock::new(0);
+        let progress = Progress::default();
+        let config = WatchdogConfig { stall_ms: 1000 };
+        let frame = AsDatum::new(1, 0, vec![0; 100]);
+        let mut watchdog = Watchdog::new(config, progress.clone(), Arc::new(clock.clone()));
+        for _ in 0..5 {
+            clock.advance(500);
+            progress.add_produced(&frame);
+            progress.add_sent(&frame);
+            assert_eq!(watchdog.check(), None);
+        }
+
+        // the connection stops taking frames while the server says the path
+        // is congested: slow, not wedged
+        for _ in 0..3 {
+            clock.advance(500);
+            progress.add_produced(&frame);
+            watchdog.observe_congestion();
+            assert_eq!(watchdog.check(), None);
+        }
+        clock.advance(1000);
+        progress.add_produced(&frame);
+        let wedged = watchdog.check().unwrap();
+        assert_eq!((wedged.stage, wedged.stalled_ms, wedged.produced, wedged.sent), (Stage::Socket, 2500, 9, 5));
+
+        // nor does the source produce any more frames, only probes and
+        // padding
+        let mut watchdog = Watchdog::new(config, progress.clone(), Arc::new(clock.clone()));
+        clock.advance(999);
+        let padding = [AsDatum::latency_probe(), AsDatum::bw_probe(1000), frame.into_redundant()];
+        for datum in &padding {
+            progress.add_produced(datum);
+            progress.add_sent(datum);
+        }
+        assert_eq!(watchdog.check(), None);
+        clock.advance(1);
+        assert_eq!(watchdog.check().map(|e| e.stage), Some(Stage::Source));
+    }
+}
@@ -0,0 +1,389 @@
+//! Layout of the frame header on the wire.
+//!
+//! Every datum is preceded by its length. By default the length is a
+//! big-endian `u64`, but senders we cannot change (e.g., embedded C code)
+//! may use a narrower or little-endian length, followed by a flags byte.
+//!
+//! The layout is static configuration, not negotiated: such a sender can't
+//! take part in a negotiation, so both ends are deployed with the same
+//! `WireFormat` (`static_wire_format` in the setting), which applies from
+//! the first byte of every connection, the handshake included. A server
+//! configured for a device's layout serves only senders framing alike;
+//! others fail to decode their first datum.
+//!
+//! With a flags byte, each frame carries its `FrameFlags`. Every flag has a
+//! bit of its own, and bits unknown to the reader are ignored, so that new
+//! wire features can be added without breaking older receivers. Likewise,
+//! fields a datum gained since (its annotation, the sender's numbering)
+//! travel in a trailer after it (`FrameFlags::TRAILER`), leaving the datum
+//! as older receivers read it.
+//!
+//! `FrameLimits` caps what a sender may put into a frame, so that a bloated
+//! annotation fails loudly instead of inflating every frame.
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use errors::*;
+use super::{AsDatum, AsDatumType};
+
+bitflags! {
+    /// Properties of a frame, carried in the flags byte of its header.
+    /// Bit 7 is reserved.
+    #[derive(Default)]
+    pub struct FrameFlags: u8 {
+        /// The frame may be discarded on the way without harm.
+        const DROPPABLE = 0b0000_0001;
+
+        /// The payload is padding (probes), not media.
+        const PADDED = 0b0000_0010;
+
+        /// The datum runs the session (handshake, feedback, probes) rather
+        /// than carrying a frame.
+        const CONTROL = 0b0000_0100;
+
+        /// The payload is compressed.
+        const COMPRESSED = 0b0000_1000;
+
+        /// The payload is encrypted.
+        const ENCRYPTED = 0b0001_0000;
+
+        /// The payload is one fragment of a larger frame.
+        const FRAGMENTED = 0b0010_0000;
+
+        /// A trailer (the annotation, the sender's numbering) follows the
+        /// serialized datum.
+        const TRAILER = 0b0100_0000;
+    }
+}
+
+impl FrameFlags {
+    /// The flags implied by the type of a datum.
+    pub fn of(t: AsDatumType) -> FrameFlags {
+        match t {
+            AsDatumType::Live(..) |
+            AsDatumType::Reference(..) |
+            AsDatumType::Sub(..) |
+            AsDatumType::Raw => FrameFlags::empty(),
+            AsDatumType::Backfill(..) => FrameFlags::DROPPABLE,
+            AsDatumType::Redundant(..) | AsDatumType::Dummy => FrameFlags::DROPPABLE | FrameFlags::PADDED,
+            AsDatumType::LatencyProbe |
+            AsDatumType::ReceiverCongest |
+            AsDatumType::Hello(_) |
+            AsDatumType::Welcome(_) |
+            AsDatumType::Control(_) |
+            AsDatumType::Quality |
+            AsDatumType::Hint |
+            AsDatumType::Directive |
+            AsDatumType::Barrier(_) |
+            AsDatumType::Dictionary |
+            AsDatumType::Admission |
+            AsDatumType::Delta |
+            AsDatumType::Close |
+            AsDatumType::Stats |
+            AsDatumType::StreamInfo => FrameFlags::CONTROL,
+        }
+    }
+}
+
+/// The frame header layout.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct WireFormat {
+    /// Width of the length field in bytes: 2, 4 or 8.
+    pub length_bytes: usize,
+
+    /// Whether the length field is little-endian.
+    pub little_endian: bool,
+
+    /// Whether a flags byte (`FrameFlags`) follows the length; the length
+    /// does not count it.
+    pub flags: bool,
+}
+
+impl Default for WireFormat {
+    fn default() -> Self {
+        WireFormat {
+            length_bytes: 8,
+            little_endian: false,
+            flags: false,
+        }
+    }
+}
+
+impl WireFormat {
+    /// Checks that the length width is supported.
+    pub fn validate(&self) -> Result<()> {
+        match self.length_bytes {
+            2 | 4 | 8 => Ok(()),
+            n => bail!(ErrorKind::InvalidConfig(format!("unsupported length width {}", n))),
+        }
+    }
+
+    /// The size of the header in bytes.
+    pub fn header_len(&self) -> usize {
+        self.length_bytes + if self.flags { 1 } else { 0 }
+    }
+
+    /// The largest payload the length field can describe.
+    pub fn max_len(&self) -> u64 {
+        match self.length_bytes {
+            2 => u64::from(u16::MAX),
+            4 => u64::from(u32::MAX),
+            _ => u64::MAX,
+        }
+    }
+
+    /// Writes the header of a `len`-byte payload with `flags` into `buf`,
+    /// which must hold `header_len` bytes. Without a flags byte, the flags
+    /// are not sent.
+    pub fn write_header(&self, len: u64, flags: FrameFlags, buf: &mut [u8]) -> Result<()> {
+        if len > self.max_len() {
+            bail!(ErrorKind::EncodeError);
+        }
+        let field = &mut buf[..self.length_bytes];
+        match (self.length_bytes, self.little_endian) {
+            (2, false) => BigEndian::write_u16(field, len as u16),
+            (2, true) => LittleEndian::write_u16(field, len as u16),
+            (4, false) => BigEndian::write_u32(field, len as u32),
+            (4, true) => LittleEndian::write_u32(field, len as u32),
+            (_, false) => BigEndian::write_u64(field, len),
+            (_, true) => LittleEndian::write_u64(field, len),
+        }
+        if self.flags {
+            buf[self.length_bytes] = flags.bits();
+        }
+        Ok(())
+    }
+
+    /// Reads the payload length from a header of `header_len` bytes.
+    pub fn read_header(&self, buf: &[u8]) -> u64 {
+        let field = &buf[..self.length_bytes];
+        match (self.length_bytes, self.little_endian) {
+            (2, false) => u64::from(BigEndian::read_u16(field)),
+            (2, true) => u64::from(LittleEndian::read_u16(field)),
+            (4, false) => u64::from(BigEndian::read_u32(field)),
+            (4, true) => u64::from(LittleEndian::read_u32(field)),
+            (_, false) => BigEndian::read_u64(field),
+            (_, true) => LittleEndian::read_u64(field),
+        }
+    }
+
+    /// Reads the flags from a header of `header_len` bytes, ignoring unknown
+    /// bits (empty without a flags byte).
+    pub fn read_flags(&self, buf: &[u8]) -> FrameFlags {
+        if self.flags {
+            FrameFlags::from_bits_truncate(buf[self.length_bytes])
+        } else {
+            FrameFlags::empty()
+        }
+    }
+}
+
+/// What `FrameLimits::max_frame_len` allows of the metadata when uncapped.
+const MAX_METADATA: usize = 64 * 1024;
+
+/// What `FrameLimits::max_frame_len` allows of the payload when uncapped.
+const MAX_PAYLOAD: usize = 64 << 20;
+
+/// Caps on the size of encoded datums; unset caps are not enforced.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct FrameLimits {
+    /// Largest serialized metadata (type, timestamp, annotation) in bytes.
+    pub max_metadata: Option<usize>,
+
+    /// Largest payload in bytes.
+    pub max_payload: Option<usize>,
+}
+
+impl FrameLimits {
+    /// Checks `datum` against the caps.
+    pub fn check(&self, datum: &AsDatum) -> Result<()> {
+        let metadata = datum.metadata_len();
+        match self.max_metadata {
+            Some(max) if metadata > max => bail!(ErrorKind::MetadataTooLarge(metadata, max)),
+            _ => {}
+        }
+        let payload = datum.mem.len();
+        match self.max_payload {
+            Some(max) if payload > max => bail!(ErrorKind::PayloadTooLarge(payload, max)),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// The longest frame a reader accepts, whatever a peer announces: the
+    /// caps of the metadata and of the payload, or bounds of their own where
+    /// uncapped.
+    pub fn max_frame_len(&self) -> u64 {
+        let metadata = self.max_metadata.unwrap_or(MAX_METADATA);
+        let payload = self.max_payload.unwrap_or(MAX_PAYLOAD);
+        metadata.saturating_add(payload) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {Annotation, AsCodec, AsDatum, AsDatumType};
+    use bytes::BytesMut;
+    use chrono::{TimeZone, Utc};
+    use tokio_io::codec::{Decoder, Encoder};
+
+    /// The layout of the embedded sender: 4-byte little-endian length and a
+    /// flags byte.
+    const DEVICE: WireFormat = WireFormat {
+        length_bytes: 4,
+        little_endian: true,
+        flags: true,
+    };
+
+    /// A stream as the device frames it: two latency probes stamped
+    /// 2017-01-01T00:00:00Z, with flags 0x80 (a reserved bit). Written after
+    /// the device's framing rather than by this crate, until the TCP payload
+    /// of a `tcpdump -w` of the device takes its place. Frozen: changes to
+    /// the layout must keep decoding it, never regenerate it.
+    const DEVICE_TRACE: &[u8] = include_bytes!("../testdata/device-probes.bin");
+
+    #[test]
+    fn test_decode_device_trace() {
+        let mut codec = AsCodec::new(DEVICE);
+        let mut buf = BytesMut::new();
+        let (head, tail) = DEVICE_TRACE.split_at(60);
+
+        buf.extend_from_slice(head);
+        let first = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(first.datum_type(), AsDatumType::LatencyProbe);
+        assert_eq!(first.ts, Utc.with_ymd_and_hms(2017, 1, 1, 0, 0, 0).unwrap());
+        // the device's reserved bit is ignored
+        assert_eq!(first.flags(), FrameFlags::CONTROL);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        buf.extend_from_slice(tail);
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        assert!(buf.is_empty());
+
+        // nor does it decode in another layout
+        let mut buf = BytesMut::from(DEVICE_TRACE);
+        assert!(AsCodec::default().decode(&mut buf).map(|d| d.is_none()).unwrap_or(true));
+    }
+
+    #[test]
+    fn test_encode_device_trace() {
+        let mut datum = AsDatum::latency_probe();
+        datum.ts = Utc.with_ymd_and_hms(2017, 1, 1, 0, 0, 0).unwrap();
+        datum.update_len();
+        let mut buf = BytesMut::new();
+        AsCodec::new(DEVICE).encode(datum, &mut buf).unwrap();
+
+        // identical to the device, except for the flags
+        let mut expected = DEVICE_TRACE[..buf.len()].to_vec();
+        expected[4] = FrameFlags::CONTROL.bits();
+        assert_eq!(&buf[..], &expected[..]);
+    }
+
+    #[test]
+    fn test_frame_flags_round_trip() {
+        // every flag has a bit of its own
+        let flags = [
+            FrameFlags::DROPPABLE,
+            FrameFlags::PADDED,
+            FrameFlags::CONTROL,
+            FrameFlags::COMPRESSED,
+            FrameFlags::ENCRYPTED,
+            FrameFlags::FRAGMENTED,
+            FrameFlags::TRAILER,
+        ];
+        for (i, a) in flags.iter().enumerate() {
+            assert_eq!(a.bits().count_ones(), 1);
+            assert!(flags[i + 1..].iter().all(|b| !a.intersects(*b)));
+        }
+        assert_eq!(FrameFlags::all().bits(), 0b0111_1111);
+
+        // every combination survives the header and the codec
+        let mut codec = AsCodec::new(DEVICE);
+        let mut buf = BytesMut::new();
+        let mut header = [0; 5];
+        for bits in 0..=FrameFlags::all().bits() {
+            let flags = FrameFlags::from_bits(bits).unwrap();
+            DEVICE.write_header(42, flags, &mut header).unwrap();
+            assert_eq!((DEVICE.read_header(&header), DEVICE.read_flags(&header)), (42, flags));
+            let mut datum = AsDatum::new(0, usize::from(bits), vec![]).with_flags(flags);
+            // the trailer's flag goes with the trailer
+            if flags.contains(FrameFlags::TRAILER) {
+                datum = datum.with_seq(7);
+            }
+            codec.encode(datum.clone(), &mut buf).unwrap();
+            let decoded = codec.decode(&mut buf).unwrap().unwrap();
+            assert_eq!((decoded.flags(), decoded.seq()), (flags, datum.seq()));
+        }
+
+        // unknown bits are ignored
+        for byte in 0..=u8::MAX {
+            header[4] = byte;
+            assert_eq!(DEVICE.read_flags(&header).bits(), byte & 0b0111_1111);
+        }
+
+        // without a flags byte, only those implied by the type remain
+        let mut plain = AsCodec::default();
+        let probe = AsDatum::bw_probe(10).with_flags(FrameFlags::COMPRESSED);
+        plain.encode(probe, &mut buf).unwrap();
+        let probe = plain.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(probe.flags(), FrameFlags::DROPPABLE | FrameFlags::PADDED);
+    }
+
+    #[test]
+    fn test_narrow_length() {
+        let narrow = WireFormat {
+            length_bytes: 2,
+            ..WireFormat::default()
+        };
+        let mut buf = BytesMut::new();
+        let mut codec = AsCodec::new(narrow);
+        codec.encode(AsDatum::new(0, 0, vec![1; 100]), &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().mem, vec![1; 100]);
+        assert!(codec.encode(AsDatum::new(0, 0, vec![0; 70_000]), &mut buf).is_err());
+
+        let odd = WireFormat {
+            length_bytes: 3,
+            ..WireFormat::default()
+        };
+        assert!(odd.validate().is_err());
+    }
+
+    #[test]
+    fn test_frame_limits() {
+        let mut codec = AsCodec::default();
+        codec.set_limits(FrameLimits {
+            max_metadata: Some(128),
+            max_payload: Some(100),
+        });
+        let mut buf = BytesMut::new();
+        codec.encode(AsDatum::new(0, 0, vec![0; 100]), &mut buf).unwrap();
+        match codec.encode(AsDatum::new(0, 1, vec![0; 101]), &mut buf) {
+            Err(Error(ErrorKind::PayloadTooLarge(101, 100), _)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        let bloated = AsDatum::new(0, 2, vec![]).with_annotation(Annotation::GroundTruth(0.5));
+        assert!(bloated.metadata_len() <= 128);
+        let mut tight = AsCodec::default();
+        tight.set_limits(FrameLimits {
+            max_metadata: Some(bloated.metadata_len() - 1),
+            max_payload: None,
+        });
+        match tight.encode(bloated, &mut buf) {
+            Err(Error(ErrorKind::MetadataTooLarge(..), _)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+
+        // a reader takes no frame longer than the caps allow
+        buf.clear();
+        AsCodec::default().encode(AsDatum::new(0, 3, vec![0; 200]), &mut buf).unwrap();
+        match codec.decode(&mut buf) {
+            Err(Error(ErrorKind::PayloadTooLarge(..), _)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        let mut huge = BytesMut::new();
+        huge.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(AsCodec::default().decode(&mut huge).is_err());
+    }
+}
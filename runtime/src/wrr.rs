@@ -0,0 +1,311 @@
+//! Weighted round-robin across sub-streams sharing the connection.
+//!
+//! Besides the source's frames, an application may send sub-streams of its
+//! own (e.g., telemetry) over the same socket (see `Client::add_sub_stream`).
+//! Their datums (`AsDatumType::Sub`, numbered per sub-stream) go through the
+//! same stages as frames (sealing, delta coding, compression) into the send
+//! queue, within its capacity and the memory budget. Taken in arrival order,
+//! a high-bitrate video stream would keep them waiting behind its frames.
+//! `WeightedRoundRobin` takes datums out of the queue sharing the bytes sent
+//! instead, in proportion to the weight of each sub-stream (deficit round
+//! robin), and protects against starvation: a datum queued for longer than
+//! `max_wait_ms` goes next, whatever the weights.
+//!
+//! ```text
+//! weights 8 (video), 1 (telemetry)  =>  ~8 bytes of video per telemetry byte
+//! ```
+//!
+//! Datums overtake one another, but never a control datum (e.g., the
+//! `Dictionary` of compression) nor are overtaken by one: the server decodes
+//! each datum in the state it was coded in.
+
+use super::{AsDatum, AsDatumType};
+use super::send_queue::{FrameId, SendQueue};
+use futures::{Async, Poll, Stream, stream};
+use futures::sync::mpsc::{Receiver, Sender, channel};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Bytes a sub-stream may send per round and unit of weight.
+const QUANTUM: usize = 1500;
+
+/// Datums a sub-stream holds before its sender waits for the client to take
+/// them.
+const SUB_STREAM_BUFFER: usize = 64;
+
+/// The stream id of the source's frames.
+const PRIMARY: u32 = 0;
+
+/// How the source's frames share the connection with other sub-streams.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct MultiplexConfig {
+    /// The weight of the source's frames.
+    pub weight: u32,
+
+    /// How long (ms) a datum may wait before it goes regardless of weights.
+    pub max_wait_ms: u64,
+}
+
+impl Default for MultiplexConfig {
+    fn default() -> Self {
+        MultiplexConfig {
+            weight: 1,
+            max_wait_ms: 200,
+        }
+    }
+}
+
+/// An application's sub-stream, kept across runs: its payloads wait in a
+/// bounded channel until the client takes them.
+#[derive(Clone)]
+pub struct SubStream {
+    id: u32,
+    weight: u32,
+    payloads: Arc<Mutex<Receiver<Vec<u8>>>>,
+    next_seq: Arc<AtomicUsize>,
+}
+
+impl SubStream {
+    /// Creates sub-stream `id` (not 0, the source's) of `weight`, and the
+    /// sender of its payloads.
+    pub fn new(id: u32, weight: u32) -> (SubStream, Sender<Vec<u8>>) {
+        assert_ne!(id, PRIMARY, "sub-stream 0 is the source's");
+        let (tx, rx) = channel(SUB_STREAM_BUFFER);
+        let sub = SubStream {
+            id,
+            weight,
+            payloads: Arc::new(Mutex::new(rx)),
+            next_seq: Arc::default(),
+        };
+        (sub, tx)
+    }
+
+    /// The datums of the payloads sent, numbered in order, with their bytes
+    /// counted in `produced`.
+    pub fn datums(&self, produced: Arc<AtomicUsize>) -> impl Stream<Item = AsDatum, Error = ()> {
+        let (id, payloads, next_seq) = (self.id, self.payloads.clone(), self.next_seq.clone());
+        stream::poll_fn(move || {
+            // a run that panicked holding the lock left the channel intact
+            let payload = payloads.lock().unwrap_or_else(PoisonError::into_inner).poll();
+            match try_ready!(payload) {
+                Some(mem) => {
+                    let seq = next_seq.fetch_add(1, Ordering::SeqCst);
+                    let datum = AsDatum::sub_stream(id, seq, mem);
+                    produced.fetch_add(datum.net_len(), Ordering::SeqCst);
+                    Ok(Async::Ready(Some(datum)))
+                }
+                None => Ok(Async::Ready(None)),
+            }
+        })
+    }
+}
+
+/// The source's datums interleaved with those of sub-streams as they come,
+/// ending with the source's.
+pub struct Inputs<S> {
+    primary: S,
+    subs: Vec<Box<dyn Stream<Item = AsDatum, Error = ()>>>,
+}
+
+impl<S> Inputs<S> {
+    /// Takes the datums of `primary`, then those of `subs`.
+    pub fn new(primary: S, subs: Vec<Box<dyn Stream<Item = AsDatum, Error = ()>>>) -> Inputs<S> {
+        Inputs { primary, subs }
+    }
+}
+
+impl<S: Stream<Item = AsDatum, Error = ()>> Stream for Inputs<S> {
+    type Item = AsDatum;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<AsDatum>, ()> {
+        if let Async::Ready(datum) = self.primary.poll()? {
+            return Ok(Async::Ready(datum));
+        }
+        for sub in &mut self.subs {
+            if let Async::Ready(Some(datum)) = sub.poll()? {
+                return Ok(Async::Ready(Some(datum)));
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// A sub-stream's place in the rounds.
+#[derive(Debug)]
+struct Lane {
+    stream: u32,
+    weight: u32,
+    deficit: usize,
+}
+
+/// The next datum of a lane: its id, size and since when (ms) it is queued.
+type Head = (FrameId, usize, u64);
+
+/// Chooses the datums leaving a `SendQueue` by weight (see
+/// `QueueReceiver::multiplex`).
+#[derive(Debug)]
+pub struct WeightedRoundRobin {
+    lanes: Vec<Lane>,
+    current: usize,
+    max_wait_ms: u64,
+}
+
+impl WeightedRoundRobin {
+    /// Sends the source's datums with the weight of `config`.
+    pub fn new(config: MultiplexConfig) -> WeightedRoundRobin {
+        let mut wrr = WeightedRoundRobin {
+            lanes: Vec::new(),
+            current: 0,
+            max_wait_ms: config.max_wait_ms,
+        };
+        wrr.add(PRIMARY, config.weight);
+        wrr
+    }
+
+    /// Also sends the datums of sub-stream `stream`, with `weight` (at least
+    /// 1).
+    pub fn add(&mut self, stream: u32, weight: u32) {
+        self.lanes.push(Lane {
+            stream,
+            weight: weight.max(1),
+            deficit: 0,
+        });
+    }
+
+    /// Adds the sub-streams `subs`.
+    pub fn with_sub_streams(mut self, subs: &[SubStream]) -> WeightedRoundRobin {
+        for sub in subs {
+            self.add(sub.id, sub.weight);
+        }
+        self
+    }
+
+    /// The lane of `datum`: that of its sub-stream, or the source's.
+    fn lane(&self, datum: &AsDatum) -> usize {
+        match datum.datum_type() {
+            AsDatumType::Sub(stream, _) => {
+                self.lanes.iter().position(|l| l.stream == stream).unwrap_or(0)
+            }
+            _ => 0,
+        }
+    }
+
+    /// Chooses the datum of `queue` to send next, at `now_ms`.
+    pub fn pick(&mut self, queue: &SendQueue, now_ms: u64) -> Option<FrameId> {
+        let mut heads: Vec<Option<Head>> = vec![None; self.lanes.len()];
+        for (id, datum, since) in queue.ahead_of_control() {
+            let lane = self.lane(datum);
+            if heads[lane].is_none() {
+                heads[lane] = Some((id, datum.net_len(), since));
+            }
+        }
+        if heads.iter().all(Option::is_none) {
+            // a control datum, if any, goes on its own
+            return queue.front();
+        }
+        let starved = heads
+            .iter()
+            .enumerate()
+            .filter_map(|(i, h)| h.map(|(_, _, since)| (since, i)))
+            .filter(|&(since, _)| now_ms >= since + self.max_wait_ms)
+            .min();
+        if let Some((_, i)) = starved {
+            debug!("sub-stream {} waited over {} ms", self.lanes[i].stream, self.max_wait_ms);
+            return heads[i].map(|head| self.take(i, head));
+        }
+        // some lane has a datum, and each visit adds to its deficit
+        loop {
+            let i = self.current;
+            match heads[i] {
+                Some(head) if self.lanes[i].deficit >= head.1 => return Some(self.take(i, head)),
+                Some(_) => self.lanes[i].deficit += QUANTUM * self.lanes[i].weight as usize,
+                // an idle lane banks no credit
+                None => self.lanes[i].deficit = 0,
+            }
+            self.current = (self.current + 1) % self.lanes.len();
+        }
+    }
+
+    fn take(&mut self, lane: usize, (id, len, _): Head) -> FrameId {
+        let lane = &mut self.lanes[lane];
+        lane.deficit = lane.deficit.saturating_sub(len);
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use decision::ManualClock;
+    use drop_policy::DropPolicyKind;
+    use futures::{Future, future};
+    use send_queue::{self, QueueReceiver};
+
+    /// Polls `rx` once, within a task.
+    fn next(rx: &mut QueueReceiver) -> Option<AsDatumType> {
+        match future::poll_fn(|| Ok::<_, ()>(Async::Ready(rx.poll()))).wait().unwrap() {
+            Ok(Async::Ready(Some(datum))) => Some(datum.datum_type()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_shares_by_weight_without_starvation() {
+        let clock = ManualClock::new(0);
+        let policy = Arc::new(Mutex::new(DropPolicyKind::TailDrop.into_policy()));
+        let (tx, mut rx) = send_queue::channel(None, None, policy.clone(), Arc::new(clock.clone()));
+        let config = MultiplexConfig {
+            weight: 4,
+            max_wait_ms: 100,
+        };
+        rx.multiplex(WeightedRoundRobin::new(config).with_sub_streams(&[SubStream::new(7, 1).0]));
+        for i in 0..100 {
+            tx.send(AsDatum::new(1, i, vec![0; 1000])).unwrap();
+            tx.send(AsDatum::sub_stream(7, i, vec![0; 1000])).unwrap();
+        }
+        let sent = (0..100).filter_map(|_| next(&mut rx)).collect::<Vec<_>>();
+        let telemetry = sent.iter().filter(|t| matches!(t, AsDatumType::Sub(7, _))).count();
+        assert!((18..=22).contains(&telemetry), "{} of 100", telemetry);
+
+        // a datum queued too long goes next, whatever the weights
+        let (tx, mut rx) = send_queue::channel(None, None, policy, Arc::new(clock.clone()));
+        let heavy = MultiplexConfig { weight: 100, ..config };
+        rx.multiplex(WeightedRoundRobin::new(heavy).with_sub_streams(&[SubStream::new(7, 1).0]));
+        tx.send(AsDatum::sub_stream(7, 0, vec![0; 100_000])).unwrap();
+        clock.advance(100);
+        for i in 0..10 {
+            tx.send(AsDatum::new(1, i, vec![0; 10_000])).unwrap();
+        }
+        assert_eq!(next(&mut rx), Some(AsDatumType::Sub(7, 0)));
+
+        // nothing passes a control datum, in either direction
+        tx.send(AsDatum::sub_stream(7, 1, vec![0; 100])).unwrap();
+        tx.send(AsDatum::delta()).unwrap();
+        tx.send(AsDatum::sub_stream(7, 2, vec![0; 100])).unwrap();
+        clock.advance(100);
+        let sent = (0..13).filter_map(|_| next(&mut rx)).collect::<Vec<_>>();
+        let at = |t| sent.iter().position(|&s| s == t).unwrap();
+        assert!(at(AsDatumType::Sub(7, 1)) < at(AsDatumType::Delta));
+        assert!(at(AsDatumType::Live(1, 9)) < at(AsDatumType::Delta));
+        assert!(at(AsDatumType::Delta) < at(AsDatumType::Sub(7, 2)));
+
+        // the sender leaving ends the stream
+        drop(tx);
+        assert_eq!(next(&mut rx), None);
+    }
+
+    #[test]
+    fn test_sub_stream_datums() {
+        let (sub, mut tx) = SubStream::new(3, 1);
+        let produced = Arc::new(AtomicUsize::new(0));
+        tx.try_send(vec![1; 10]).unwrap();
+        tx.try_send(vec![2; 10]).unwrap();
+        drop(tx);
+        let datums = sub.datums(produced.clone()).collect().wait().unwrap();
+        let types = datums.iter().map(AsDatum::datum_type).collect::<Vec<_>>();
+        assert_eq!(types, vec![AsDatumType::Sub(3, 0), AsDatumType::Sub(3, 1)]);
+        assert_eq!(produced.load(Ordering::SeqCst), datums.iter().map(AsDatum::net_len).sum());
+    }
+}